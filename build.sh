@@ -1,3 +1,3 @@
 cargo build --manifest-path daemon/Cargo.toml --release
 cargo build --manifest-path ctl/Cargo.toml --release
-
+cargo build --manifest-path helper/Cargo.toml --release
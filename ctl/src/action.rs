@@ -1,11 +1,50 @@
-use rensen_lib::logging::Trap; 
+use rensen_lib::logging::{Trap, log_host_trap};
 use rensen_lib::config::*;
-use rensen_lib::traits::{YamlFile, JsonFile, Rsync};
+use rensen_lib::traits::{YamlFile, JsonFile, Rsync, Restore};
 use rensen_lib::backup::rsync::Sftp;
 use rensen_lib::record::Record;
+use rensen_lib::chunks::ChunkStore;
 use rensen_lib::compiler::Compiler;
+use rensen_lib::unpacked::UnpackCache;
+use rensen_lib::compact::snapshot_time;
+use rensen_lib::report::Report;
+use rensen_lib::units::Units;
+use rensen_lib::exit::ExitCode;
+use rensen_lib::lock::HostLock;
+use rensen_lib::schedule::{host_schedule, is_due, preview};
+use rensen_lib::quota::{check_quota, inode_usage, disk_usage};
+use rensen_lib::notify::alert;
+use rensen_lib::ledger::TransferLedger;
+use rensen_lib::verify::{mark_for_refetch, verify_host};
+use rensen_lib::index::snapshot_files;
+use rensen_lib::snapshot::Snapshot;
+use rensen_lib::keys;
+use rensen_lib::calendar::{windows, to_ical, DEFAULT_CALENDAR_DAYS};
+use rensen_lib::simulate::{simulate, peak_concurrency, Simulated};
+use rensen_lib::progress::{render_bar, Progress};
+use rensen_lib::codec::Codec;
+use rensen_lib::audit::{admit_manual_run, AuditEntry, AuditLog};
+use rensen_lib::inventory::{discover_all, plan, enroll, Enrollment};
+use rensen_lib::plan::{Plan, Step, plan_compaction, plan_gc, plan_prune};
+use rensen_lib::replica::cross_check;
+use rensen_lib::helper::{Helper, HELPER_PROTOCOL, DEFAULT_HELPER_PATH};
+use rensen_lib::seed::{export_seed, import_seed, parse_rate};
+use rensen_lib::runbook::{restore_plan, resolve_snapshot, RestoreStep};
+use rensen_lib::mirror::{flush_mirrors, mirror_snapshot, mirrors, MirrorStatus};
+use rensen_lib::migrate::{migrate, Layout};
+use rensen_lib::repository::{RepositoryFormat, FORMAT, OLDEST_FORMAT};
+use rensen_lib::breaker::Breaker;
+use rensen_lib::annotate::Annotations;
+use rensen_lib::retire::{Retirement, parse_until};
+use rensen_lib::forecast::{forecast, Forecast};
+use rensen_lib::histogram::SIZE_BOUNDS;
+use rensen_lib::advise::{advise, Thresholds};
+use rensen_lib::status::{HostStatus, OVERDUE_GRACE};
+use rensen_lib::history::{History, ExportFormat, trend, export_csv, export_parquet};
 
 use console::Style;
+use chrono::Local;
+use clap::{Args, Subcommand, ValueEnum};
 
 use crate::utils::*;
 use std::path::PathBuf; use std::fs;
@@ -14,74 +53,337 @@ use std::path::PathBuf; use std::fs;
 pub enum ViewSubject {
     Snapshots,
     Config,
+    Histogram,
 }
 
-#[derive(PartialEq)]
-enum BackupMethod {
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum BackupMethod {
+    #[value(alias = "f")]
     Full,
+    #[value(name = "inc", aliases = ["incremental", "i"])]
     Incremental
 }
 
-#[derive(PartialEq)]
+/// Operands of the actions that take them as they come, see print_help
+#[derive(Debug, Clone, Default, PartialEq, Args)]
+pub struct Operands {
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub operands: Vec<String>,
+}
+
+/// The actions, as subcommands of `rensen` or typed into its shell
+#[derive(Debug, PartialEq, Subcommand)]
 pub enum ActionType {
-    AddHost,    // 1 arg
-    DeleteHost, // 1 arg
-    ModifyHost, // 1 arg
-    RunBackup,  // 2 arg
-    Compile,    // 1 arg
-    ListHosts,  // 2 arg
-    View,       // 2 arg
-
-    Clear,      // 0 arg
-    Help,       // 0 arg
-    Exit,       // 0 arg
+    /// Enters the host-adding interface
+    #[command(name = "add", visible_alias = "a")]
+    AddHost(Operands),
+    /// Deletes the config of host
+    #[command(name = "del", visible_alias = "d")]
+    DeleteHost(Operands),
+    /// Enters the modification interface of host
+    #[command(name = "mod", visible_alias = "m")]
+    ModifyHost(Operands),
+    /// Runs a backup of host, or with --due of every host that is due
+    #[command(name = "backup", visible_aliases = ["run", "r"])]
+    RunBackup {
+        #[arg(required_unless_present = "due")]
+        hostname: Option<String>,
+        #[arg(value_enum, ignore_case = true, default_value = "inc")]
+        method: BackupMethod,
+        /// Runs an incremental backup of every host that is due, then exits
+        #[arg(long, conflicts_with = "hostname")]
+        due: bool,
+    },
+    /// Starts the compilation interface of host
+    #[command(name = "comp", visible_aliases = ["c", "compile"])]
+    Compile(Operands),
+    /// Lists all hosts
+    #[command(name = "list", visible_alias = "l")]
+    ListHosts,
+    /// Views the snapshots, histogram or config of host
+    #[command(name = "view", visible_alias = "v")]
+    View(Operands),
+    /// Prints a report of all hosts
+    #[command(name = "report", visible_alias = "rp")]
+    Report(Operands),
+    /// Lists the latest runs of host, or exports those of all hosts
+    #[command(name = "history", visible_alias = "hi")]
+    History(Operands),
+    /// Lists bytes transferred per host and month
+    #[command(name = "stats", visible_alias = "st")]
+    Stats(Operands),
+    /// Verifies the snapshots of host
+    #[command(name = "verify", visible_alias = "vf")]
+    Verify {
+        hostname: String,
+        /// Share of the snapshots to check, those verified the longest ago first
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(1..=100))]
+        percent: u8,
+        /// Backs up the damaged files that are still current again
+        #[arg(long)]
+        refetch: bool,
+    },
+    /// Enrolls hosts from the inventory sources
+    #[command(name = "discover", visible_alias = "di")]
+    Discover(Operands),
+    /// Compacts old snapshot records of host
+    #[command(name = "compact", visible_alias = "cp")]
+    Compact(Operands),
+    /// Removes leftovers from the backups of host
+    #[command(name = "gc")]
+    Gc(Operands),
+    /// Removes snapshots of host past their retention, or forecasts the repository size
+    #[command(name = "prune", visible_alias = "pr")]
+    Prune(Operands),
+    /// Cross-checks snapshots against the replica
+    #[command(name = "replica", visible_alias = "rc")]
+    Replica(Operands),
+    /// Shows or deploys the rensen-helper on host
+    #[command(name = "helper", visible_alias = "he")]
+    Helper(Operands),
+    /// Seeds the first backup of host via removable media
+    #[command(name = "seed", visible_alias = "se")]
+    Seed(Operands),
+    /// Prints or runs the restore runbook of host
+    #[command(name = "restore", visible_alias = "rs")]
+    Restore {
+        hostname: String,
+        /// A snapshot, `latest`, or the point in time to restore, YYYY-MM-DD or YYYY-MM-DD-HH-MM-SS
+        snapshot: String,
+        /// Only prints the steps, the default
+        #[arg(long, conflicts_with = "execute")]
+        plan: bool,
+        /// Carries out the steps over ssh
+        #[arg(long)]
+        execute: bool,
+        /// Compares every restored file on host with the snapshot at the end
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Catches up and shows the mirrors of host
+    #[command(name = "mirror", visible_alias = "mi")]
+    Mirror(Operands),
+    /// Previews, exports or simulates the upcoming backups
+    #[command(name = "schedule", visible_alias = "sc")]
+    Schedule(Operands),
+    /// Resumes the backups of host after its breaker tripped
+    #[command(name = "reset")]
+    Reset(Operands),
+    /// Attaches a note to the next or latest snapshot of host
+    #[command(name = "annotate", visible_alias = "an")]
+    Annotate(Operands),
+    /// Retires host, keeping its snapshots
+    #[command(name = "host", visible_alias = "ho")]
+    Host(Operands),
+    /// Generates, rotates and revokes the ssh keys of host
+    #[command(name = "keys", visible_alias = "ke")]
+    Keys(Operands),
+    /// Suggests excludes for large, churning, incompressible files
+    #[command(name = "advise", visible_alias = "ad")]
+    Advise(Operands),
+    /// Shows the latest run of each source and whether it is overdue
+    #[command(name = "status")]
+    Status {
+        /// Only this host, every host otherwise
+        hostname: Option<String>,
+        /// Lists only the overdue sources, and fails while there are any
+        #[arg(long)]
+        overdue: bool,
+    },
+    /// Moves the snapshots of host to another storage layout
+    #[command(name = "migrate")]
+    Migrate(Operands),
+    /// Shows or upgrades the format of the repository
+    #[command(name = "format")]
+    Format(Operands),
+
+    /// Clears the screen of the shell
+    #[command(name = "clear")]
+    Clear,
+    /// Shows the help of all actions, or of one
+    #[command(name = "help", visible_aliases = ["h", "?"])]
+    Help(Operands),
+    /// Quits the shell
+    #[command(name = "exit", visible_aliases = ["q", "quit"])]
+    Exit,
+}
+
+impl ActionType {
+    /// What the actions that take their operands as they come were given
+    pub fn operands(&self) -> &[String] {
+        match self {
+            ActionType::AddHost(operands)
+            | ActionType::DeleteHost(operands)
+            | ActionType::ModifyHost(operands)
+            | ActionType::Compile(operands)
+            | ActionType::View(operands)
+            | ActionType::Report(operands)
+            | ActionType::History(operands)
+            | ActionType::Stats(operands)
+            | ActionType::Discover(operands)
+            | ActionType::Compact(operands)
+            | ActionType::Gc(operands)
+            | ActionType::Prune(operands)
+            | ActionType::Replica(operands)
+            | ActionType::Helper(operands)
+            | ActionType::Seed(operands)
+            | ActionType::Mirror(operands)
+            | ActionType::Schedule(operands)
+            | ActionType::Reset(operands)
+            | ActionType::Annotate(operands)
+            | ActionType::Host(operands)
+            | ActionType::Keys(operands)
+            | ActionType::Advise(operands)
+            | ActionType::Migrate(operands)
+            | ActionType::Format(operands)
+            | ActionType::Help(operands) => &operands.operands,
+            _ => &[],
+        }
+    }
 }
 
 pub struct Action {
     pub action_type: ActionType,
-    pub operands: Vec<String>,
     pub global_config: GlobalConfig,
+    pub raw: bool, // `--raw`: print unformatted sizes, durations and timestamps
 }
 
 impl Action {
-    pub fn execute(&self) -> Result<(), Trap> {
+    fn units(&self) -> Result<Units, Trap> {
+        Units::new(&self.global_config, self.raw)
+    }
+
+    fn operands(&self) -> &[String] {
+        self.action_type.operands()
+    }
+
+    /// Runs the action. `Ok` carries the exit code for actions that can
+    /// complete with warnings, e.g. a backup that had to skip files.
+    pub fn execute(&self) -> Result<ExitCode, Trap> {
 
+        // Read-only replicas may list, view, compile and report, nothing else
         match self.action_type {
-            ActionType::AddHost    => {
+            ActionType::AddHost(_)       => self.global_config.ensure_writable("add hosts")?,
+            ActionType::DeleteHost(_)    => self.global_config.ensure_writable("delete hosts")?,
+            ActionType::ModifyHost(_)    => self.global_config.ensure_writable("modify hosts")?,
+            ActionType::RunBackup { .. } => self.global_config.ensure_writable("run backups")?,
+            ActionType::Mirror(_)        => self.global_config.ensure_writable("mirror snapshots")?,
+            ActionType::Reset(_)         => self.global_config.ensure_writable("reset hosts")?,
+            ActionType::Annotate(_)      => self.global_config.ensure_writable("annotate snapshots")?,
+            ActionType::Host(_)          => self.global_config.ensure_writable("retire hosts")?,
+            ActionType::Keys(_)          => self.global_config.ensure_writable("change keys")?,
+            ActionType::Migrate(_)       => self.global_config.ensure_writable("migrate snapshots")?,
+            _ => (),
+        }
+
+        match &self.action_type {
+            ActionType::AddHost(_)    => {
                 self.add_host()?;
             },
-            ActionType::DeleteHost => {
+            ActionType::DeleteHost(_) => {
                 self.del_host()?;
             },
-            ActionType::ModifyHost => {
+            ActionType::ModifyHost(_) => {
                 self.mod_host()?;
             },
-            ActionType::RunBackup  => {
-                self.run_backup()?;
+            ActionType::RunBackup { due: true, .. } => {
+                return self.run_due();
+            },
+            ActionType::RunBackup { hostname, method, .. } => {
+                return self.run_backup(hostname.as_deref().unwrap_or_default(), *method);
             },
-            ActionType::Compile    => {
-                self.compile_snapshot()?;
+            ActionType::Compile(_)    => {
+                return self.compile_snapshot();
             },
-            ActionType::ListHosts  => {
+            ActionType::ListHosts     => {
                 self.list()?;
             },
-            ActionType::View       => {
+            ActionType::View(_)       => {
                 self.view()?;
             }
-            ActionType::Help       => {
+            ActionType::Report(_)     => {
+                self.report()?;
+            }
+            ActionType::History(_)    => {
+                self.history()?;
+            }
+            ActionType::Stats(_)      => {
+                self.stats()?;
+            }
+            ActionType::Verify { hostname, percent, refetch } => {
+                self.verify(hostname, *percent, *refetch)?;
+            }
+            ActionType::Discover(_)   => {
+                self.discover()?;
+            }
+            ActionType::Compact(_)    => {
+                self.maintain("Compacting", |host_config| plan_compaction(&self.global_config, host_config, Local::now().timestamp()))?;
+            }
+            ActionType::Gc(_)         => {
+                self.maintain("Removing", |host_config| plan_gc(&self.global_config, host_config))?;
+            }
+            ActionType::Prune(_) if self.operands().iter().any(|operand| operand == "--simulate") => {
+                self.simulate_prune()?;
+            }
+            ActionType::Prune(_)      => {
+                self.maintain("Pruning", |host_config| plan_prune(&self.global_config, host_config, Local::now().timestamp()))?;
+            }
+            ActionType::Replica(_)    => {
+                self.check_replica()?;
+            }
+            ActionType::Helper(_)     => {
+                self.helper()?;
+            }
+            ActionType::Seed(_)       => {
+                return self.seed();
+            }
+            ActionType::Restore { hostname, snapshot, execute, verify, .. } => {
+                return self.restore(hostname, snapshot, *execute, *verify);
+            }
+            ActionType::Mirror(_)     => {
+                self.mirror()?;
+            }
+            ActionType::Schedule(_)   => {
+                self.schedule()?;
+            }
+            ActionType::Reset(_)      => {
+                self.reset()?;
+            }
+            ActionType::Annotate(_)   => {
+                self.annotate()?;
+            }
+            ActionType::Host(_)       => {
+                self.host()?;
+            }
+            ActionType::Keys(_)       => {
+                self.keys()?;
+            }
+            ActionType::Advise(_)     => {
+                self.advise()?;
+            }
+            ActionType::Status { hostname, overdue } => {
+                self.status(hostname.as_deref(), *overdue)?;
+            }
+            ActionType::Migrate(_)    => {
+                self.migrate()?;
+            }
+            ActionType::Format(_)     => {
+                self.format()?;
+            }
+            ActionType::Help(_)       => {
                 self.print_help();
             }
 
-            _ => (),
+            ActionType::Clear | ActionType::Exit => (),
         }
 
-        Ok(())
+        Ok(ExitCode::Success)
     }
 
     /* add action */
 
     fn add_host(&self) -> Result<(), Trap> {
-        if self.operands.len() != 1 {
+        if self.operands().len() != 1 {
             return Err(
                 Trap::InvalidInput(
                     String::from("Invalid arguments for action. Use `help` for more details")
@@ -90,14 +392,14 @@ impl Action {
         }
 
         let hosts = &self.global_config.hosts;
-        let hostname = &self.operands[0];
+        let hostname = &self.operands()[0];
 
-        let mut settings: Settings = Settings::deserialize_yaml(&hosts)
+        let mut settings: Settings = Settings::deserialize_yaml(hosts)
             .map_err(|err| Trap::Deserialize(format!("Could not deserialize settings: {}", err)))?;
 
         // checking if the hostname is taken
         for host in settings.hosts.iter() {
-            if hostname.to_owned() == host.hostname {
+            if *hostname == host.hostname {
                 return Err(Trap::InvalidInput(format!("Hostname `{}` already in use!", hostname)));
             }
         }
@@ -158,7 +460,7 @@ impl Action {
 
         settings.hosts.push(Host { hostname: hostname.clone(), config: host_config  });
 
-        let _ = settings.serialize_yaml(hosts)
+        settings.serialize_yaml(hosts)
             .map_err(|err| Trap::Serialize(format!("Could not serialize yaml: {}", err)))?;
 
         Ok(())
@@ -167,7 +469,7 @@ impl Action {
     /* del action */
 
     fn del_host(&self) -> Result<(), Trap> {
-        if self.operands.len() != 1 {
+        if self.operands().len() != 1 {
             return Err(
                 Trap::InvalidInput(
                     String::from("Invalid arguments for action. Use `help` for more details")
@@ -175,23 +477,23 @@ impl Action {
             );
         }
         
-        let hostname = &self.operands[0];
+        let hostname = &self.operands()[0];
         let hosts = &self.global_config.hosts;
 
         // Global host-settings for rensen
-        let mut settings: Settings = Settings::deserialize_yaml(&hosts)
+        let mut settings: Settings = Settings::deserialize_yaml(hosts)
             .map_err(|err| Trap::Deserialize(format!("Could not deserialize settings: {}", err)))?;
         
         // Removing host from the settings by extractin it's index
         for (i, host) in settings.hosts.iter().enumerate() {
-            if host.hostname.to_owned() == hostname.to_owned() {
+            if host.hostname == *hostname {
                 settings.hosts.remove(i);
                 break;
             }
         }
 
         // Writing it back to the file
-        settings.serialize_yaml(&hosts)
+        settings.serialize_yaml(hosts)
             .map_err(|err| Trap::Serialize(format!("Could not serialize settings: {}", err)))?;
 
         println!("Deleted `{}`", hostname);
@@ -203,14 +505,14 @@ impl Action {
 
     fn mod_host(&self) -> Result<(), Trap> {
         let hosts = &self.global_config.hosts;
-        let hostname = &self.operands[0];
+        let hostname = &self.operands()[0];
         let style = Style::new();
 
-        let mut settings: Settings = Settings::deserialize_yaml(&hosts)
+        let mut settings: Settings = Settings::deserialize_yaml(hosts)
             .map_err(|err| Trap::Deserialize(format!("Could not deserialize settings: {}", err)))?;
 
         // Gettings the host_config
-        let host_config = match settings.associated_config(&hostname) {
+        let host_config = match settings.associated_config(hostname) {
             Some(config) => config,
             None => return Err(Trap::InvalidInput(format!("hostname `{}` was not found", hostname)))
         };
@@ -248,16 +550,16 @@ impl Action {
         let cron_schedule = get_input("backupping schedule (Cron expression): ")
             .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?.trim().to_string();
 
-        let new_host_config: HostConfig = HostConfig::from(
-            match user.len() {
+        let new_host_config: HostConfig = HostConfig {
+            user: match user.len() {
                 0 => host_config.user.to_owned(),
                 _ => user
             },
-            match identifier.len() {
+            identifier: match identifier.len() {
                 0 => host_config.identifier.to_owned(),
                 _ => identifier
             },
-            match port.len() {
+            port: Some(match port.len() {
                 0 => host_config.port.unwrap_or(22).to_owned(),
                 _ => {
                     if port.trim().is_empty() {
@@ -274,31 +576,38 @@ impl Action {
                         }
                     }
                 }
-            },
-            match key.len() {
+            }),
+            key: Some(match key.len() {
                 0 => host_config.key.unwrap_or("".into()).to_owned(),
                 _ => PathBuf::from(&key), 
-            },
-            match source.len() {
+            }),
+            source: match source.len() {
                 0 => host_config.source.to_owned(),
                 _ => PathBuf::from(&source), 
             },
-            match destination.len() {
+            destination: match destination.len() {
                 0 => host_config.destination.to_owned(),
                 _ => PathBuf::from(&destination), 
             },
-            match cron_schedule.len() {
-                0 => host_config.cron_schedule.unwrap_or(String::from("0 0 * * *")).to_owned(),
-                _ => cron_schedule
-            }
-        );
+            // A new expression replaces an `interval` or `run_at` the host had
+            cron_schedule: match cron_schedule.len() {
+                0 if host_config.interval.is_some() || host_config.run_at.is_some() => None,
+                0 => Some(host_config.cron_schedule.unwrap_or(String::from("0 0 * * *")).to_owned()),
+                _ => Some(cron_schedule.clone())
+            },
+            interval: host_config.interval.filter(|_| cron_schedule.is_empty()),
+            run_at: host_config.run_at.filter(|_| cron_schedule.is_empty()),
+
+            // Settings which are not prompted for are kept as they are
+            ..host_config
+        };
 
         println!("{}", style.clone().bold().apply_to("New config:"));
         println!("{}", new_host_config);
 
         // Gets the index of host.hostname == hostname.to_owned()
         for (i, host) in settings.hosts.iter().enumerate() {
-            if host.hostname == hostname.to_owned() {
+            if host.hostname == *hostname {
                 settings.hosts.remove(i);
                 break;
             }
@@ -314,8 +623,8 @@ impl Action {
 
     /* compile action */
 
-    fn compile_snapshot(&self) -> Result<(), Trap> {
-        if self.operands.len() != 1 {
+    fn compile_snapshot(&self) -> Result<ExitCode, Trap> {
+        if self.operands().len() != 1 {
             return Err(
                 Trap::InvalidInput(
                     String::from("Invalid arguments for action. Use `help` for more details")
@@ -324,12 +633,12 @@ impl Action {
         }
 
         let hosts = &self.global_config.hosts;
-        let hostname = &self.operands[0];
+        let hostname = &self.operands()[0];
 
         let settings: Settings = Settings::deserialize_yaml(hosts)
             .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
 
-        let host_config = match settings.associated_config(&hostname) {
+        let host_config = match settings.associated_config(hostname) {
             Some(config) => config,
             None => return Err(Trap::InvalidInput(format!("hostname `{}` is not found", hostname)))
         };
@@ -342,18 +651,33 @@ impl Action {
             snapshot = String::from("record");
         }
 
-        let snapshot_record_path = self.global_config.backups
-            .join(host_config.identifier)
+        let snapshot_record_path = host_config.root(&self.global_config)
             .join(".records")
             .join(format!("{}.json", snapshot.trim()));
 
         /* Compiling snapshot */
         let mut compiler = Compiler::from(&snapshot_record_path)?;
-        compiler.compile(&self.global_config.snapshots)?;
+        compiler.checksums = host_config.checksums.unwrap_or_default();
+        compiler.chunks = Some(ChunkStore::new(&self.global_config));
+        compiler.cache = UnpackCache::new(&self.global_config);
+        compiler.special_files = host_config.special_files.unwrap_or_default();
+        compiler.owner_map = host_config.owner_map.clone().unwrap_or_default();
+        if self.global_config.is_read_only() {
+            compiler.scratch = Some(self.global_config.snapshots.join(".unpack"));
+        }
+        let report = compiler.compile(&self.global_config.snapshots);
         let _ = compiler.cleanup();
+        let report = report?;
 
+        for skipped in report.skipped.iter() {
+            println!("Could not restore {:?}", skipped);
+        }
+        for mismatch in report.mismatches.iter() {
+            println!("Checksum mismatch: {}", mismatch);
+        }
+        println!("{} files ({}) compiled to {:?}", report.files, self.units()?.bytes(report.bytes), report.destination);
 
-        Ok(())
+        Ok(report.exit_code())
     }
 
     /* List action */
@@ -377,7 +701,7 @@ impl Action {
 
     fn view(&self) -> Result<(), Trap> {
 
-        if self.operands.len() != 2 {
+        if self.operands().len() < 2 || self.operands().len() > 3 {
             return Err(
                 Trap::InvalidInput(
                     String::from("Invalid arguments for action. Use `help` for more details")
@@ -386,15 +710,17 @@ impl Action {
         }
 
         // checking the list method, either listing `snapshots` or `config`
-        let list_method = match self.operands[1].to_lowercase().as_str() {
+        let list_method = match self.operands()[1].to_lowercase().as_str() {
             "snapshots" | "s" | "snap" => ViewSubject::Snapshots,
             "config"    | "c" | "conf" => ViewSubject::Config,
-            _ => return Err(Trap::InvalidInput(format!("List Method: `{}` is not recognized in this action", self.operands[0])))
+            "histogram" | "h" | "hist" => ViewSubject::Histogram,
+            _ => return Err(Trap::InvalidInput(format!("List Method: `{}` is not recognized in this action", self.operands()[0])))
         };
 
         match list_method {
             ViewSubject::Snapshots => self.view_snapshots()?,
             ViewSubject::Config    => self.view_config()?,
+            ViewSubject::Histogram => self.view_histogram()?,
         }
 
         Ok(())
@@ -402,7 +728,7 @@ impl Action {
 
     // Cats config for host
     fn view_config(&self) -> Result<(), Trap> {
-        if self.operands.len() != 2 {
+        if self.operands().len() != 2 {
             return Err(
                 Trap::InvalidInput(
                     String::from("Invalid arguments for action. Use `help` for more details")
@@ -411,14 +737,14 @@ impl Action {
         }
 
         let hosts = &self.global_config.hosts;
-        let hostname = &self.operands[0];
+        let hostname = &self.operands()[0];
 
         // Gettings the Settings
         let settings: Settings = Settings::deserialize_yaml(hosts)
             .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
 
         // Extracting the config for associated hostname
-        let host_config = match settings.associated_config(&hostname) {
+        let host_config = match settings.associated_config(hostname) {
             Some(config) => config,
             None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
         };
@@ -432,7 +758,7 @@ impl Action {
 
     // Lists all snapshots/backups taken of host
     fn view_snapshots(&self) -> Result<(), Trap> {
-        if self.operands.len() != 2 {
+        if self.operands().len() < 2 || self.operands().len() > 3 {
             return Err(
                 Trap::InvalidInput(
                     String::from("Invalid arguments for action. Use `help` for more details")
@@ -441,89 +767,326 @@ impl Action {
         }
 
         let hosts = &self.global_config.hosts;
-        let hostname = &self.operands[0];
+        let hostname = &self.operands()[0];
 
         // Gettings the Settings
         let settings: Settings = Settings::deserialize_yaml(hosts)
             .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
 
         // Extracting the config for associated hostname
-        let host_config = match settings.associated_config(&hostname) {
+        let host_config = match settings.associated_config(hostname) {
             Some(config) => config,
             None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
         };
 
-        let dir_path = self.global_config.backups
-            .join(host_config.identifier)
-            .join(".records");
+        if let Some(snapshot) = self.operands().get(2) {
+            return self.view_snapshot_files(&host_config, snapshot);
+        }
+
+        let snapshots = Snapshot::list(&self.global_config, &host_config)?;
+        let units = self.units()?;
+        let annotations = Annotations::load(&self.global_config, &host_config)?;
+        let style = console::Style::new();
+        println!("{}", style.clone().bold().apply_to(format!("{}: ", hostname).as_str()));
 
-        /* Reading directory contentens and formatting outputs */
-        let entries = match fs::read_dir(&dir_path) {
-            Ok(entries) => entries,
-            Err(err) => return Err(Trap::FS(
-                format!("Could not read directory at: `{:?}`: {}", dir_path, err)))
+        // What each snapshot holds in the chunk store alone, and shares with others
+        let store = ChunkStore::new(&self.global_config);
+        let counts = match host_config.dedup.unwrap_or(false) {
+            true => Some(ChunkStore::reference_counts(&self.global_config)),
+            false => None,
         };
 
-        let mut entries_sorted_by_date: Vec<_> = entries
-            .map(|entry| {
-                entry.map(|e| {
-                    e.metadata().and_then(|m| m.modified()).map(|t| (e, t))
-                })
-            })
-            .filter_map(|entry| entry.ok())
-            .collect();
+        for snapshot in snapshots {
+            let compacted = match snapshot.compacted {
+                true => " (compacted)",
+                false => "",
+            };
+            let codec = match snapshot.codec {
+                Some(Codec::Gzip) | None => String::new(),
+                Some(codec) => format!(" ({:?})", codec).to_lowercase(),
+            };
+
+            let share = counts.as_ref()
+                .filter(|_| !snapshot.compacted)
+                .and_then(|counts| Record::deserialize_json(&snapshot.record_path()).ok().map(|record| store.share(counts, &record)))
+                .map(|share| format!(" (unique {}, shared {})", units.bytes(share.unique), units.bytes(share.shared)))
+                .unwrap_or_default();
+
+            println!("->  {} {}{}{}{}", style.clone().bold().blue().apply_to(&snapshot.name), units.bytes(snapshot.size), share, codec, compacted);
+            for annotation in annotations.of(&snapshot.name) {
+                println!("      {} ({})", annotation.text, units.timestamp(annotation.time));
+            }
+        }
+        for annotation in annotations.pending.iter() {
+            println!("    next: {} ({})", annotation.text, units.timestamp(annotation.time));
+        }
+        println!();
+
+        Ok(())
+    }
 
-        entries_sorted_by_date.sort_by(|a, b| a.as_ref().unwrap().1.cmp(&b.as_ref().unwrap().1));
+    // Lists the files of one snapshot of host
+    fn view_snapshot_files(&self, host_config: &HostConfig, snapshot: &str) -> Result<(), Trap> {
+        if snapshot_time(snapshot).is_none() {
+            return Err(Trap::InvalidInput(format!("`{}` is not a snapshot, e.g. 2024-05-15-08-10-30", snapshot)));
+        }
 
+        let units = self.units()?;
         let style = console::Style::new();
-        println!("{}", style.clone().bold().apply_to(format!("{}: ", hostname).as_str()));
+        let files = snapshot_files(&self.global_config, host_config, snapshot)?;
 
-        for entry in entries_sorted_by_date {
-            let entry = entry.unwrap().0;
-            let record = Record::deserialize_json(&entry.path())
-                .map_err(|err| Trap::Deserialize(format!("Could not deserialize record, size unavailable: {}", err)))?;
+        for file in files.iter() {
+            println!("{:>10}  {}  {}", units.bytes(file.size), style.clone().dim().apply_to(&file.snapshot), file.path.display());
+        }
+        println!("{} files", files.len());
 
-            let mem_size: MemoryUsage = format_bytes(record.size);
-            if let Some(file_stem) = entry.path().file_stem() {
+        Ok(())
+    }
 
-                // Filtering out the record.json file
-                if file_stem != "record" {
-                    println!("->  {} {} {}", style.clone().bold().blue().apply_to(file_stem.to_str().unwrap()), mem_size.amount, mem_size.unit);
-                }
-            }
+    // What the files of a snapshot of host are, by extension and size, the latest one by default
+    fn view_histogram(&self) -> Result<(), Trap> {
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[0];
+
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Hostname `{}` was not found", hostname)))
+        };
 
+        let snapshots = Snapshot::list(&self.global_config, &host_config)?;
+        let snapshot = match self.operands().get(2) {
+            Some(name) => snapshots.iter().find(|snapshot| &snapshot.name == name)
+                .ok_or(Trap::InvalidInput(format!("`{}` has no snapshot `{}`", hostname, name)))?,
+            None => snapshots.last()
+                .ok_or(Trap::Missing(format!("`{}` has no snapshots yet", hostname)))?,
+        };
+
+        let record_path = snapshot.record_path();
+        let record = Record::deserialize_json(&record_path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+        let histogram = record.histogram
+            .ok_or(Trap::Missing(format!("Snapshot `{}` was taken before histograms were kept", snapshot.name)))?;
+
+        let units = self.units()?;
+        let style = console::Style::new();
+        println!("{}", style.clone().bold().apply_to(format!("{} {}: ", hostname, snapshot.name)));
+
+        let total: u64 = histogram.sizes.iter().map(|bucket| bucket.bytes).sum();
+        let share = |bytes: u64| match total {
+            0 => 0.0,
+            total => bytes as f64 * 100.0 / total as f64,
+        };
+
+        println!("by extension:");
+        for (extension, bucket) in histogram.ranked() {
+            let extension = match extension {
+                "" => String::from("(none)"),
+                "other" => String::from("other"),
+                extension => format!(".{}", extension),
+            };
+            println!("  {:<12} {:>10} {:>5.1}%  {} files", extension, units.bytes(bucket.bytes), share(bucket.bytes), bucket.files);
+        }
+
+        println!("by size:");
+        for (index, bucket) in histogram.sizes.iter().enumerate() {
+            let range = match (index.checked_sub(1).map(|below| SIZE_BOUNDS[below]), SIZE_BOUNDS.get(index)) {
+                (_, Some(bound)) => format!("< {}", units.bytes(*bound)),
+                (Some(bound), None) => format!(">= {}", units.bytes(bound)),
+                (None, None) => String::new(),
+            };
+            println!("  {:<12} {:>10} {:>5.1}%  {} files", range, units.bytes(bucket.bytes), share(bucket.bytes), bucket.files);
         }
-        println!();
 
         Ok(())
     }
 
-    /* run action */
+    /* report action */
 
-    fn run_backup(&self) -> Result<(), Trap> {
-        if self.operands.len() != 2 {
-            return Err(
-                    Trap::InvalidInput(
-                    String::from("Invalid arguments for action. Use `help` for more details")
-                )
-            );
+    // Prints an overview of all hosts, using `templates.report` for the layout if configured
+    fn report(&self) -> Result<(), Trap> {
+        let settings: Settings = Settings::deserialize_yaml(&self.global_config.hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", &self.global_config.hosts, err)))?;
+
+        let report = Report::collect(&self.global_config, &settings)?;
+        if let Some(rendered) = report.render(&self.global_config)? {
+            print!("{}", rendered);
+            return Ok(());
+        }
+
+        let units = self.units()?;
+        let style = console::Style::new();
+        println!("{}", style.clone().bold().apply_to(format!("Report ({}):", report.generated)));
+
+        for host in report.hosts {
+            println!("->  {}", style.clone().bold().blue().apply_to(&host.hostname));
+
+            match &host.sla {
+                Some(sla) => {
+                    let last = match host.sla_last_hit {
+                        Some(true) => style.clone().green().apply_to("hit").to_string(),
+                        Some(false) => style.clone().red().apply_to("missed").to_string(),
+                        None => String::from("none"),
+                    };
+
+                    println!("    sla {}: {} hit, {} missed (last: {})", sla, host.sla_hits, host.sla_misses, last);
+                },
+                None => println!("    sla: none"),
+            }
+
+            match &host.last_run {
+                Some(run) if run.success => println!("    last run: {}", style.clone().green().apply_to("OK")),
+                Some(run) => println!("    last run: {} {}", style.clone().red().apply_to("FAILED"), run.error.as_deref().unwrap_or("")),
+                None => println!("    last run: none"),
+            }
+
+            if let Some(usage) = host.last_run.as_ref().and_then(|run| run.source) {
+                println!("    source: {:.1}% free of {}", usage.free_percent(), units.bytes(usage.total));
+            }
+
+            if let Some(inodes) = host.last_run.as_ref().and_then(|run| run.destination_inodes) {
+                println!("    destination: {} inodes", inodes);
+            }
+
+            for key in host.stale_keys.iter() {
+                println!("    {} key {:?}: {} days old, {}", key.kind, key.path, key.days, style.clone().yellow().apply_to("due for rotation"));
+            }
+        }
+
+        // Archives take few inodes, but the destination may be shared
+        if let Ok(usage) = inode_usage(&self.global_config.backups) {
+            println!("destination: {:.1}% of {} inodes free", usage.free_percent(), usage.total);
         }
 
-        let hostname = &self.operands[0];
+        Ok(())
+    }
+
+    /* run action */
+
+    fn run_backup(&self, hostname: &str, backup_method: BackupMethod) -> Result<ExitCode, Trap> {
         let hosts = &self.global_config.hosts;
 
         // Opening the settings file for all hosts
         let settings: Settings = Settings::deserialize_yaml(hosts)
             .map_err(|err| Trap::FS(format!("Could not deserialize {:?}: {}", hosts, err)))?;
 
-        let host_config = match settings.associated_config(&hostname) {
+        let host_config = match settings.associated_config(hostname) {
             Some(config) => config,
             None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
         };
 
+        // Started by hand or by automation, unlike `run --due`
+        let detail = match backup_method {
+            BackupMethod::Full => "full",
+            BackupMethod::Incremental => "incremental",
+        };
+        admit_manual_run(&self.global_config, hostname, detail, Local::now().timestamp())?;
+
+        self.backup_host(hostname, &host_config, backup_method)
+    }
+
+    /// Evaluates the schedules of all hosts once and backs up those that are due,
+    /// meaning a run was scheduled since their last successful backup.
+    /// Meant for systemd timers or cron in place of rensend.
+    fn run_due(&self) -> Result<ExitCode, Trap> {
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::FS(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let history = History::new(&self.global_config);
+        let now = Local::now();
+
+        let mut due: Vec<&Host> = Vec::new();
+        for host in settings.hosts.iter().filter(|host| host.hostname != "dummy") {
+            if Retirement::load(&self.global_config, &host.config)?.is_some() {
+                continue;
+            }
+
+            let breaker = Breaker::load(&self.global_config, &host.config)?;
+            if breaker.holds(&self.global_config, now.timestamp()) {
+                println!("`{}` is held back after {} failed runs, use `reset` to resume", host.hostname, breaker.failures);
+                continue;
+            }
+
+            let schedule = host_schedule(host)?;
+            if is_due(&schedule, history.last_success(&host.hostname)?, &now) {
+                due.push(host);
+            }
+        }
+
+        if due.is_empty() {
+            println!("No hosts are due");
+            return Ok(ExitCode::Success);
+        }
+
+        // Same order as rensend, critical hosts first
+        due.sort_by_key(|host| !host.config.is_critical());
+
+        let mut codes: Vec<ExitCode> = Vec::new();
+        for host in due {
+            let result = check_quota(&self.global_config, host)
+                .and_then(|_| self.backup_host(&host.hostname, &host.config, BackupMethod::Incremental));
+
+            match result {
+                Ok(code) => codes.push(code),
+                Err(err) => {
+                    alert(&self.global_config, &host.hostname, &err);
+                    codes.push(ExitCode::from(&err));
+                }
+            }
+        }
+
+        Ok(ExitCode::combine(codes))
+    }
+
+    // Backs up every source of a host, each into its own snapshot chain, or
+    // just the one picked with `host:name`
+    fn backup_host(&self, hostname: &str, host_config: &HostConfig, backup_method: BackupMethod) -> Result<ExitCode, Trap> {
+        let namespaces = match host_config.namespace {
+            Some(_) => vec![host_config.clone()],
+            None => host_config.namespaces()?,
+        };
+
+        // Held until the end of the run, keeps rensend off this host meanwhile
+        let _lock = HostLock::acquire(&self.global_config, host_config)?;
+
+        let hostname = hostname.split(':').next().unwrap_or(hostname);
+        let mut codes: Vec<ExitCode> = Vec::new();
+        for namespace in namespaces.iter() {
+            let name = match &namespace.namespace {
+                Some(name) => format!("{}:{}", hostname, name),
+                None => hostname.to_string(),
+            };
+
+            match self.backup_namespace(&name, namespace, backup_method) {
+                Ok(code) => codes.push(code),
+                Err(err) if namespaces.len() == 1 => {
+                    self.record_breaker(hostname, host_config, false);
+                    return Err(err);
+                },
+                // The other sources are still backed up
+                Err(err) => {
+                    println!("{}: {}", name, err);
+                    alert(&self.global_config, &name, &err);
+                    codes.push(ExitCode::from(&err));
+                },
+            }
+        }
+
+        self.record_breaker(hostname, host_config, codes.iter().all(|code| !code.is_failure()));
+        Ok(ExitCode::combine(codes))
+    }
+
+    fn record_breaker(&self, hostname: &str, host_config: &HostConfig, success: bool) {
+        if let Err(err) = Breaker::record(&self.global_config, host_config, hostname, success, Local::now().timestamp()) {
+            log_host_trap(&self.global_config, hostname, &err);
+        }
+    }
+
+    fn backup_namespace(&self, hostname: &str, host_config: &HostConfig, backup_method: BackupMethod) -> Result<ExitCode, Trap> {
         // Formatting the path to where the record for that specific machine would be stored.
-        let record_path = self.global_config.backups
-            .join(&host_config.identifier)
+        let record_path = host_config.root(&self.global_config)
             .join(".records")
             .join("record.json");
 
@@ -531,62 +1094,1386 @@ impl Action {
             .map_err(|err| Trap::Deserialize(format!("Could not deserialize record: {}", err)))?;
 
 
-        let mut sftp = Sftp::new(&host_config, &self.global_config, record, false);
-
-        // Check if second arguement is `full` or is `inc`.
-        // Running manual backup based on that.
-        let backup_method = match self.operands[1].to_lowercase().as_str() {
-            "full" => BackupMethod::Full,
-            "inc"  => BackupMethod::Incremental,
-            _ => return Err(Trap::InvalidInput(format!("Not a regognozed backup method")))
-        };
+        let mut sftp = Sftp::new(host_config, &self.global_config, record, false);
 
         if backup_method == BackupMethod::Incremental {
             sftp.incremental = true;
-            sftp.backup()?;
-            return Ok(())
         }
 
-        sftp.backup()?; 
+        // A bar instead of a line per file, unless the output goes elsewhere
+        let units = self.units()?;
+        let term = console::Term::stderr();
+        if term.is_term() {
+            let term = term.clone();
+            sftp.progress = Some(Box::new(move |progress: &Progress| {
+                let width = term.size().1 as usize;
+                let bar: String = render_bar(progress, 30, |bytes| units.bytes(bytes)).chars().take(width.saturating_sub(1)).collect();
+                let _ = term.clear_line();
+                let _ = term.write_str(&bar);
+            }));
+        }
+
+        let started = Local::now().timestamp();
+        let result = sftp.backup();
+        if term.is_term() {
+            let _ = term.clear_line();
+        }
+
+        let outcome = sftp.outcome(hostname, started, Local::now().timestamp(), &result);
+        History::record(&self.global_config, &outcome);
+        HostStatus::record(&self.global_config, host_config, &outcome);
+
+        let report = result?;
+        println!("{}: {} files ({}) transferred, {} deleted, {} skipped",
+            report.snapshot, report.files, units.bytes(report.bytes), report.deleted, report.skipped.len());
+        for notice in report.notices.iter() {
+            println!("notice: {}", notice);
+        }
+        for warning in report.warnings.iter() {
+            println!("warning: {}", warning);
+        }
+
+        Ok(report.exit_code())
+    }
+
+    /* history action */
+
+    // Lists the latest runs of a host along with their trends
+    fn history(&self) -> Result<(), Trap> {
+        if self.operands().is_empty() {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        if self.operands()[0] == "export" {
+            return self.export_history();
+        }
+
+        let last = match get_flag(self.operands(), "--last") {
+            Some(last) => last.parse::<usize>()
+                .map_err(|err| Trap::InvalidInput(format!("Invalid value for --last: {}", err)))?,
+            None => 30,
+        };
+
+        let hostname = &self.operands()[0];
+        let runs = History::new(&self.global_config).for_host(hostname, last)?;
+
+        let units = self.units()?;
+        let style = console::Style::new();
+        println!("{}", style.clone().bold().apply_to(format!("{}: ", hostname).as_str()));
+
+        for run in runs.iter() {
+            let status = match run.success {
+                true  => style.clone().green().apply_to("OK"),
+                false => style.clone().red().apply_to("FAILED"),
+            };
+
+            println!("->  {} {:>11} {:>6} files {:>10} transferred {:>10} total  {}",
+                style.clone().bold().blue().apply_to(units.timestamp(run.started)), units.duration(run.duration()),
+                run.files, units.bytes(run.bytes), units.bytes(run.size), status);
+            for notice in run.notices.iter() {
+                println!("    {}", notice);
+            }
+        }
+
+        let durations: Vec<f64> = runs.iter().map(|run| run.duration() as f64).collect();
+        let sizes: Vec<f64> = runs.iter().map(|run| run.size as f64).collect();
+
+        println!();
+        match trend(&durations) {
+            Some(trend) => println!("duration trend: {:+.1}%", trend),
+            None => println!("duration trend: not enough runs"),
+        }
+        match trend(&sizes) {
+            Some(trend) => println!("size trend:     {:+.1}%", trend),
+            None => println!("size trend:     not enough runs"),
+        }
+
+        Ok(())
+    }
+
+    // Exports the history of all hosts for use in spreadsheets etc.
+    fn export_history(&self) -> Result<(), Trap> {
+        let format = ExportFormat::from(get_flag(self.operands(), "--format").map(|f| f.as_str()).unwrap_or("csv"))?;
+
+        let since = get_flag(self.operands(), "--since")
+            .map(|date| parse_date(date))
+            .transpose()
+            .map_err(Trap::InvalidInput)?;
+        let until = get_flag(self.operands(), "--until")
+            .map(|date| parse_date(date))
+            .transpose()
+            .map_err(Trap::InvalidInput)?;
+
+        let runs = History::new(&self.global_config).range(since, until)?;
+        let output = get_flag(self.operands(), "--output").map(PathBuf::from);
+
+        match (format, output) {
+            (ExportFormat::Csv, Some(output)) => {
+                let mut file = fs::File::create(&output)
+                    .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", output, err)))?;
+                export_csv(&runs, &mut file)
+                    .map_err(|err| Trap::FS(format!("Could not write {:?}: {}", output, err)))?;
+            },
+            (ExportFormat::Csv, None) => {
+                export_csv(&runs, &mut std::io::stdout())
+                    .map_err(|err| Trap::STD(format!("Could not write to stdout: {}", err)))?;
+            },
+            (ExportFormat::Parquet, Some(output)) => export_parquet(&runs, &output)?,
+            (ExportFormat::Parquet, None) => {
+                return Err(Trap::InvalidInput(String::from("Parquet exports need an --output file")));
+            },
+        }
 
         Ok(())
     }
 
-    /* help action */
+    /* stats action */
 
-    pub fn print_help(&self) {
-        let style = Style::new();
+    // Bytes transferred per host and month, from the transfer ledger
+    fn stats(&self) -> Result<(), Trap> {
+        let ledger_path = TransferLedger::path(&self.global_config);
+        let ledger = TransferLedger::deserialize_json(&ledger_path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read ledger at {:?}: {}", ledger_path, err)))?;
 
-        if self.operands.len() > 0 {
-            match self.operands[0].to_lowercase().as_str() {
-                "add" => {
-                    println!("a, add <hostname>     Enters host-adding interface.");
-                    println!(
-                        "Enters the host-adding interface where you are able to specify information about\nthen host which is going to be backupped.\n\n{} Remember to have a ssh-key generated in the path you specify, and also have thepublic key on the host machine.",
-                    style.bold().red().apply_to("Note:"));
-                },
-                "del" => {
-                    println!("d, del <hostname>     Deletes host config.");
-                    println!("Deletes the specified host's config from the configuration file located at probably in /etc/rensen or has specified path in /etc/rensen/rensen_config");
-                },
-                "mod"     => {
-                    println!("m, mod <hostname>     Enters modification interface.");
-                    println!("Allows you to modify a config for a host that already exists instead of readding it.");
-                },
-                "run"     => {
-                    println!("r, run <hostname> <inc, full>   Runs backup for host based on what is specified in config."); 
-                    println!("Runs the rensen backup system, either incremental or full backups. Backupped files will be stored\nat path specified in /etc/rensen/rensen_config.yml\n");
-                    println!("\nAliases:\nincremental, inc, i\nfull, f");
-                },
-                "list"    => {
-                    println!("l, list    lists out all hosts.");
+        let units = self.units()?;
+        let style = console::Style::new();
 
-                },
-                "view"    => {
-                    println!("v, view <hostname> <snapshots, config>     views snapshots taken of host.");
-                    println!("\nsnapshots: \nThis checks the snapshots/backups taken of the host at the location specified in /etc/rensen/rensen_config.yml");
-                    println!("\nconfig: \nEchos out the deserialized format of the config file, stored at location specified in /etc/rensen/rensne_config.yml");
-                    println!("\nAliases: \nsnapshots, snap, s\nconfig, conf, c"); 
+        // Every month of a single host, followed by its latest runs
+        if let Some(hostname) = self.operands().first().filter(|operand| !operand.starts_with("--")) {
+            println!("{}", style.clone().bold().apply_to(format!("{}: ", hostname).as_str()));
+            for (month, transfer) in ledger.for_host(hostname) {
+                println!("->  {} {:>10} {:>6} files {:>4} runs", style.clone().bold().blue().apply_to(month),
+                    units.bytes(transfer.bytes), transfer.files, transfer.runs);
+            }
+
+            let last = match get_flag(self.operands(), "--last") {
+                Some(last) => last.parse::<usize>()
+                    .map_err(|err| Trap::InvalidInput(format!("Invalid value for --last: {}", err)))?,
+                None => 10,
+            };
+
+            println!();
+            for run in History::new(&self.global_config).for_host(hostname, last)? {
+                let usage = match &run.usage {
+                    Some(usage) => format!("  cpu {:.1}s, rss {}, read {}, written {}", usage.cpu_seconds(),
+                        units.bytes(usage.max_rss), units.bytes(usage.read_bytes), units.bytes(usage.write_bytes)),
+                    None => String::new(),
+                };
+                println!("->  {} {:>10} {:>6} files{}", style.clone().bold().blue().apply_to(units.timestamp(run.started)),
+                    units.bytes(run.bytes), run.files, usage);
+
+                // Where the time went, the slowest phase stands out
+                if let Some(phases) = &run.phases {
+                    let (slowest, _) = phases.slowest();
+                    let named: Vec<String> = phases.named().iter()
+                        .map(|(phase, ms)| {
+                            let took = format!("{} {}", phase, units.duration((*ms / 1000) as i64));
+                            match *phase == slowest {
+                                true => style.clone().bold().apply_to(took).to_string(),
+                                false => took,
+                            }
+                        })
+                        .collect();
+                    println!("    {} ({})", named.join(", "), units.duration(run.duration()));
+                }
+            }
+
+            return Ok(());
+        }
+
+        // Every host during one month
+        let month = match get_flag(self.operands(), "--month") {
+            Some(month) => month.clone(),
+            None => TransferLedger::month(Local::now().timestamp()),
+        };
+
+        println!("{}", style.clone().bold().apply_to(format!("{}: ", month).as_str()));
+        let hosts = ledger.months.get(&month).cloned().unwrap_or_default();
+        for (hostname, transfer) in hosts.iter() {
+            println!("->  {} {:>10} {:>6} files {:>4} runs", style.clone().bold().blue().apply_to(hostname),
+                units.bytes(transfer.bytes), transfer.files, transfer.runs);
+        }
+
+        let total: u64 = hosts.values().map(|transfer| transfer.bytes).sum();
+        println!("\ntotal: {}", units.bytes(total));
+
+        Ok(())
+    }
+
+    /* verify action */
+
+    // Checks the archives of a host's snapshots against their records, and
+    // with `--refetch` backs up what is damaged again
+    fn verify(&self, hostname: &str, percent: u8, refetch: bool) -> Result<(), Trap> {
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        let style = console::Style::new();
+        let results = verify_host(&self.global_config, &host_config, percent, Local::now().timestamp())?;
+        for result in results.iter() {
+            let status = match result.is_ok() {
+                true  => style.clone().green().apply_to("OK"),
+                false => style.clone().red().apply_to("FAILED"),
+            };
+
+            println!("->  {} {:>6} files  {}", style.clone().bold().blue().apply_to(&result.snapshot), result.files, status);
+            for problem in result.problems.iter() {
+                println!("    {}", problem);
+            }
+        }
+
+        let failed = results.iter().filter(|result| !result.is_ok()).count();
+        if failed > 0 && refetch {
+            let dropped = mark_for_refetch(&self.global_config, &host_config, &results)?;
+            println!("\nFetching {} damaged files again", dropped.len());
+            for source in dropped.iter() {
+                println!("    {}", source.display());
+            }
+
+            if !dropped.is_empty() {
+                self.backup_host(hostname, &host_config, BackupMethod::Incremental)?;
+            }
+        }
+
+        if failed > 0 {
+            return Err(Trap::Verify(format!("{} of {} snapshots of `{}` failed verification", failed, results.len(), hostname)));
+        }
+
+        Ok(())
+    }
+
+    /* discover action */
+
+    // Lists the hosts the inventory sources would add or change, and with
+    // `--apply` writes them to the host settings
+    fn discover(&self) -> Result<(), Trap> {
+        let inventory = match &self.global_config.inventory {
+            Some(inventory) => inventory,
+            None => return Err(Trap::Config(String::from("No `inventory` configured in /etc/rensen/rensen_config.yml"))),
+        };
+
+        let apply = self.operands().iter().any(|operand| operand == "--apply");
+        if apply {
+            self.global_config.ensure_writable("enroll hosts")?;
+        }
+
+        let hosts = &self.global_config.hosts;
+        let mut settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let (discovered, errors) = discover_all(inventory);
+        for err in errors.iter() {
+            println!("{}", err);
+        }
+
+        let enrollments = plan(&settings, &inventory.template, &discovered)?;
+        let style = console::Style::new();
+        for enrollment in enrollments.iter() {
+            match enrollment {
+                Enrollment::Add(host) => {
+                    println!("{} {}", style.clone().green().apply_to("+"), style.clone().bold().apply_to(&host.hostname));
+                    println!("{}\n", host.config);
+                },
+                Enrollment::Update { hostname, before, after } => {
+                    println!("{} {}", style.clone().yellow().apply_to("~"), style.clone().bold().apply_to(hostname));
+                    println!("addr: {} -> {}\nuser: {} -> {}\nport: {} -> {}\n",
+                        before.identifier, after.identifier,
+                        before.user, after.user,
+                        before.port.unwrap_or(22), after.port.unwrap_or(22),
+                    );
+                },
+            }
+        }
+
+        if enrollments.is_empty() {
+            println!("All {} discovered hosts are enrolled", discovered.len());
+        }
+        else if apply {
+            let count = enrollments.len();
+            enroll(&mut settings, enrollments);
+            settings.serialize_yaml(hosts)
+                .map_err(|err| Trap::Serialize(format!("Could not serialize yaml: {}", err)))?;
+            println!("Enrolled {} hosts, restart rensend to schedule them", count);
+        }
+        else {
+            println!("Run `discover --apply` to enroll these hosts");
+        }
+
+        match errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /* compact, gc and prune actions */
+
+    // Plans a maintenance operation for a host, or one of its sources with
+    // `host:name`, prints every step and carries them out unless `--dry-run`
+    // is given. Without a name every source of the host is planned for.
+    fn maintain<F>(&self, doing: &str, planner: F) -> Result<(), Trap>
+    where F: Fn(&HostConfig) -> Result<Plan, Trap> {
+        if self.operands().is_empty() {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[0];
+        let dry_run = self.operands().iter().any(|operand| operand == "--dry-run");
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        if !dry_run {
+            self.global_config.ensure_writable("modify the repository")?;
+        }
+
+        let namespaces = match host_config.namespace {
+            Some(_) => vec![host_config],
+            None => host_config.namespaces()?,
+        };
+
+        let mut plan = Plan::default();
+        for namespace in namespaces.iter() {
+            let planned = planner(namespace)?;
+            plan.now = planned.now;
+            plan.steps.extend(planned.steps);
+        }
+
+        let units = self.units()?;
+        for step in plan.steps.iter() {
+            match step {
+                Step::Compact { record, bytes_before, bytes_after } => {
+                    println!("compact  {}  {} -> {}", record.display(), units.bytes(*bytes_before), units.bytes(*bytes_after));
+                },
+                Step::Remove { path, bytes, reason } => {
+                    println!("remove   {}  {} ({})", path.display(), units.bytes(*bytes), reason);
+                },
+            }
+        }
+
+        if plan.is_empty() {
+            println!("Nothing to do for `{}`", hostname);
+            return Ok(());
+        }
+
+        if dry_run {
+            println!("Would free {} in {} steps (dry run)", units.bytes(plan.freed()), plan.steps.len());
+            return Ok(());
+        }
+
+        // Not while a backup of the host writes its records
+        let _lock = HostLock::acquire(&self.global_config, &namespaces[0])?;
+        println!("{}... ", doing);
+        let report = plan.execute(&self.global_config)?;
+        println!("Freed {}: {} records compacted, {} paths removed", units.bytes(report.freed), report.compacted, report.removed);
+
+        Ok(())
+    }
+
+    /* helper action */
+
+    // Shows the helper on a host, deploying it first with `--deploy`
+    fn helper(&self) -> Result<(), Trap> {
+        if self.operands().is_empty() {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[0];
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        let mut sftp = Sftp::new(&host_config, &self.global_config, Record::new(), false);
+        sftp.connect()?;
+        sftp.auth()?;
+        let sess = sftp.sess.as_ref().unwrap();
+
+        if self.operands().iter().any(|operand| operand == "--deploy") {
+            let local = self.global_config.helper_path.clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_HELPER_PATH));
+            Helper::deploy(sess, &local)?;
+            println!("Deployed {:?} to `{}`", local, hostname);
+        }
+
+        match Helper::probe(sess) {
+            Some(helper) => println!("rensen-helper {} (protocol {}) on `{}`", helper.version, helper.protocol, hostname),
+            None => println!("No rensen-helper speaking protocol {} on `{}`, backups use pure sftp", HELPER_PROTOCOL, hostname),
+        }
+
+        if !host_config.helper.unwrap_or(false) {
+            println!("`helper` is not enabled for `{}`, backups do not use it", hostname);
+        }
+
+        Ok(())
+    }
+
+    /* mirror action */
+
+    // Catches the mirrors of one or all hosts up and prints how each destination is doing
+    fn mirror(&self) -> Result<(), Trap> {
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let selected: Vec<&Host> = match self.operands().first() {
+            Some(hostname) => match settings.hosts.iter().find(|host| host.hostname == *hostname) {
+                Some(host) => vec![host],
+                None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname))),
+            },
+            None => settings.hosts.iter().filter(|host| !mirrors(&self.global_config, &host.config).is_empty()).collect(),
+        };
+
+        let style = console::Style::new();
+        let units = self.units()?;
+        let mut failed = 0;
+        for host in selected {
+            let errors = flush_mirrors(&self.global_config, &host.config, Local::now().timestamp())?;
+            let status = MirrorStatus::load(&self.global_config, &host.config)?;
+            println!("->  {}", style.clone().bold().blue().apply_to(&host.hostname));
+
+            for mirror in mirrors(&self.global_config, &host.config) {
+                let state = status.destinations.get(&mirror.label()).cloned().unwrap_or_default();
+                let (text, color) = match (&state.last_error, state.pending.is_empty()) {
+                    (Some(_), _) => ("FAILING", style.clone().red()),
+                    (None, false) => ("PENDING", style.clone().yellow()),
+                    (None, true) => ("OK", style.clone().green()),
+                };
+                let last = state.last_success.map(|time| units.timestamp(time)).unwrap_or_else(|| String::from("never"));
+                println!("    {} {}: last {} at {}, {} pending",
+                    color.apply_to(text), mirror.label(), state.last_snapshot.as_deref().unwrap_or("-"), last, state.pending.len());
+                if let Some(error) = &state.last_error {
+                    println!("    {}", error);
+                }
+            }
+
+            for err in errors.iter() {
+                alert(&self.global_config, &host.hostname, err);
+            }
+            failed += errors.len();
+        }
+
+        if failed > 0 {
+            return Err(Trap::Mirror(format!("{} destinations could not be caught up", failed)));
+        }
+
+        Ok(())
+    }
+
+    /* restore action */
+
+    // Prints the restore runbook of a host for a snapshot, or runs it with `--execute`
+    fn restore(&self, hostname: &str, at: &str, execute: bool, verify: bool) -> Result<ExitCode, Trap> {
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        // A snapshot, `latest` (the live record) or the point in time to restore
+        let snapshot = resolve_snapshot(&self.global_config, &host_config, at)?;
+        if snapshot != at && snapshot != "record" {
+            println!("Restoring snapshot {}, the last one as of {}", snapshot, at);
+        }
+        let units = self.units()?;
+        for annotation in Annotations::load(&self.global_config, &host_config)?.of(&snapshot) {
+            println!("{}: {} ({})", snapshot, annotation.text, units.timestamp(annotation.time));
+        }
+
+        let mut steps = restore_plan(&host_config, &snapshot)?;
+        if verify {
+            if host_config.encrypt_key.is_some() {
+                return Err(Trap::InvalidInput(format!("`{}` has `encrypt_key`, its restores cannot be verified", hostname)));
+            }
+            steps.push(RestoreStep::Verify);
+        }
+
+        if !execute {
+            for (i, step) in steps.iter().enumerate() {
+                println!("{:>3}. {}", i + 1, step);
+            }
+            return Ok(ExitCode::Success);
+        }
+
+        let mut sftp = Sftp::new(&host_config, &self.global_config, Record::new(), false);
+        sftp.connect()?;
+        sftp.auth()?;
+
+        let report = sftp.restore(&steps)?;
+        println!("Restored {} files to `{}`", report.uploaded, hostname);
+
+        if let Some(verified) = report.verified {
+            for path in report.mismatched.iter() {
+                println!("mismatch: {:?}", path);
+            }
+            for path in report.missing.iter() {
+                println!("missing:  {:?}", path);
+            }
+            match report.exit_code() {
+                ExitCode::Success => println!("PASS: all {} restored files match snapshot {}", verified, snapshot),
+                _ => println!("FAIL: {} of {} restored files differ from snapshot {}, {} are missing",
+                    report.mismatched.len(), verified, snapshot, report.missing.len()),
+            }
+        }
+
+        Ok(report.exit_code())
+    }
+
+    /* seed action */
+
+    // Exports the initial full backup of a host to removable media at the
+    // source site, or imports it at the backup server as its first snapshot
+    fn seed(&self) -> Result<ExitCode, Trap> {
+        if self.operands().len() < 3 {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[1];
+        let media = PathBuf::from(&self.operands()[2]);
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host = match settings.hosts.iter().find(|host| host.hostname == *hostname) {
+            Some(host) => host,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname))),
+        };
+
+        let report = match self.operands()[0].as_str() {
+            "export" => {
+                let limit = match get_flag(self.operands(), "--limit") {
+                    Some(rate) => Some(parse_rate(rate)?),
+                    None => None,
+                };
+                export_seed(host, &media, limit)?
+            },
+            "import" => {
+                let _lock = HostLock::acquire(&self.global_config, &host.config)?;
+                import_seed(&self.global_config, host, &media)?
+            },
+            other => return Err(Trap::InvalidInput(format!("Unknown seed step `{}`, expected `export` or `import`", other))),
+        };
+
+        let units = self.units()?;
+        println!("{}: {} files ({}) copied, {} already there from an earlier run, {} skipped",
+            report.snapshot, report.files, units.bytes(report.bytes), report.resumed, report.skipped.len());
+        for path in report.skipped.iter() {
+            println!("skipped: {:?}", path);
+        }
+
+        Ok(report.exit_code())
+    }
+
+    /* prune --simulate */
+
+    // Projects the size of the repository of one or all hosts over the next
+    // months, with what their retention will prune by then, see forecast
+    fn simulate_prune(&self) -> Result<(), Trap> {
+        let all = self.operands().iter().any(|operand| operand == "--all");
+        if !all && self.operands().first().is_none_or(|operand| operand.starts_with("--")) {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let months = match get_flag(self.operands(), "--months") {
+            Some(months) => months.parse::<u32>()
+                .map_err(|err| Trap::InvalidInput(format!("Invalid number of months `{}`: {}", months, err)))?,
+            None => 12,
+        };
+
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let configs: Vec<(String, HostConfig)> = match all {
+            true => settings.hosts.iter().map(|host| (host.hostname.clone(), host.config.clone())).collect(),
+            false => {
+                let hostname = &self.operands()[0];
+                match settings.associated_config(hostname) {
+                    Some(config) => vec![(hostname.clone(), config)],
+                    None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname))),
+                }
+            },
+        };
+
+        let now = Local::now().timestamp();
+        let units = self.units()?;
+        let mut fleet = Forecast { months: vec![0; months as usize], ..Default::default() };
+        for (hostname, host_config) in configs.iter() {
+            let namespaces = match host_config.namespace {
+                Some(_) => vec![host_config.clone()],
+                None => host_config.namespaces()?,
+            };
+
+            let mut host = Forecast { months: vec![0; months as usize], ..Default::default() };
+            for namespace in namespaces.iter() {
+                let projected = forecast(&self.global_config, namespace, now, months);
+                host.current += projected.current;
+                host.snapshot_bytes += projected.snapshot_bytes;
+                for (total, bytes) in host.months.iter_mut().zip(projected.months) {
+                    *total += bytes;
+                }
+            }
+
+            let last = host.months.last().copied().unwrap_or(host.current);
+            println!("{:<24} now {:>10}  in {} months {:>10}  ({} per snapshot)",
+                hostname, units.bytes(host.current), months, units.bytes(last), units.bytes(host.snapshot_bytes));
+
+            fleet.current += host.current;
+            for (total, bytes) in fleet.months.iter_mut().zip(host.months) {
+                *total += bytes;
+            }
+        }
+
+        println!();
+        for (i, bytes) in fleet.months.iter().enumerate() {
+            println!("month {:>3}  {:>10}", i + 1, units.bytes(*bytes));
+        }
+
+        // Against what is left at the destination, what is there now stays
+        let disk = disk_usage(&self.global_config.backups)?;
+        match fleet.months.iter().position(|bytes| bytes.saturating_sub(fleet.current) > disk.free) {
+            Some(month) => println!("The destination ({} free) runs out of space in month {}", units.bytes(disk.free), month + 1),
+            None => println!("The destination ({} free) lasts the next {} months", units.bytes(disk.free), months),
+        }
+
+        Ok(())
+    }
+
+    /* replica action */
+
+    // Cross-checks the snapshots of one or all hosts against the replica,
+    // alerting on every host that diverges
+    fn check_replica(&self) -> Result<(), Trap> {
+        let replica = match &self.global_config.replica {
+            Some(replica) => replica,
+            None => return Err(Trap::Config(String::from("No `replica` configured in /etc/rensen/rensen_config.yml"))),
+        };
+
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let selected: Vec<&Host> = match self.operands().first() {
+            Some(hostname) => match settings.hosts.iter().find(|host| host.hostname == *hostname) {
+                Some(host) => vec![host],
+                None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname))),
+            },
+            None => settings.hosts.iter().collect(),
+        };
+
+        let style = console::Style::new();
+        let mut diverged = 0;
+        for host in selected {
+            let mut divergences = Vec::new();
+            for host_config in host.config.namespaces()? {
+                divergences.extend(cross_check(&self.global_config, &host_config, replica, Local::now().timestamp())?);
+            }
+            let status = match divergences.is_empty() {
+                true  => style.clone().green().apply_to("OK"),
+                false => style.clone().red().apply_to("DIVERGED"),
+            };
+            println!("->  {} {}", style.clone().bold().blue().apply_to(&host.hostname), status);
+
+            for divergence in divergences.iter() {
+                println!("    {}", divergence);
+            }
+
+            if !divergences.is_empty() {
+                diverged += 1;
+                let details: Vec<String> = divergences.iter().map(|divergence| divergence.to_string()).collect();
+                alert(&self.global_config, &host.hostname, &Trap::Replica(format!(
+                    "Replica at {:?} diverges from the primary: {}", replica, details.join("; ")
+                )));
+            }
+        }
+
+        if diverged > 0 {
+            return Err(Trap::Replica(format!("{} hosts diverge from the replica", diverged)));
+        }
+
+        Ok(())
+    }
+
+    /* reset action */
+
+    // Resumes the scheduled backups of a host held back after failing
+    fn reset(&self) -> Result<(), Trap> {
+        if self.operands().len() != 1 {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[0];
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        let breaker = Breaker::load(&self.global_config, &host_config)?;
+        Breaker::reset(&self.global_config, &host_config)?;
+        match breaker.tripped {
+            Some(_) => println!("Resumed `{}` after {} failed runs", hostname, breaker.failures),
+            None => println!("`{}` was not held back", hostname),
+        }
+
+        Ok(())
+    }
+
+    /* annotate action */
+
+    // Attaches a note from e.g. a deploy pipeline to the next snapshot of a
+    // host, or with `--latest` to its latest one
+    fn annotate(&self) -> Result<(), Trap> {
+        let text: Vec<&str> = self.operands().iter().skip(1)
+            .filter(|operand| *operand != "--latest")
+            .map(|operand| operand.as_str())
+            .collect();
+        if text.is_empty() {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[0];
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        let latest = self.operands().iter().any(|operand| operand == "--latest");
+        match Annotations::annotate(&self.global_config, &host_config, &text.join(" "), latest, Local::now().timestamp())? {
+            Some(snapshot) => println!("Annotated snapshot {} of `{}`", snapshot, hostname),
+            None => println!("Annotation kept for the next snapshot of `{}`", hostname),
+        }
+
+        Ok(())
+    }
+
+    /* host action */
+
+    // Retires a host that is gone: no more backups, and its snapshots kept
+    // until `--keep-until` (or forever) rather than pruned
+    fn host(&self) -> Result<(), Trap> {
+        let hostname = match (self.operands().first().map(String::as_str), self.operands().get(1)) {
+            (Some("retire"), Some(hostname)) if !hostname.starts_with("--") => hostname,
+            _ => return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            ),
+        };
+
+        let until = match get_flag(self.operands(), "--keep-until") {
+            Some(date) => Some(parse_until(date)?),
+            None => None,
+        };
+
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        let units = self.units()?;
+        let retirement = Retirement::retire(&self.global_config, &host_config, hostname, until, Local::now().timestamp())?;
+        println!("Retired `{}` as of {}, it is no longer backed up", hostname, units.timestamp(retirement.retired));
+        match retirement.until {
+            Some(until) => println!("Its snapshots are kept until {}, then pruned", units.timestamp(until)),
+            None => println!("Its snapshots are kept forever"),
+        }
+
+        Ok(())
+    }
+
+    /* keys action */
+
+    // Generates, rotates and prunes the dedicated ssh keys of a host, keeping
+    // `key` and `previous_key` in its config in step
+    fn keys(&self) -> Result<(), Trap> {
+        let (command, hostname) = match (self.operands().first().map(String::as_str), self.operands().get(1)) {
+            (Some(command @ ("init" | "rotate" | "prune")), Some(hostname)) if !hostname.starts_with("--") => (command, hostname),
+            _ => return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            ),
+        };
+
+        let hosts = &self.global_config.hosts;
+        let mut settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let host = match settings.hosts.iter_mut().find(|host| host.hostname == *hostname) {
+            Some(host) => host,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        let now = Local::now().timestamp();
+        let detail = match command {
+            "init" => {
+                let password = match self.operands().iter().any(|operand| operand == "--install") {
+                    true => {
+                        print!("Password of {}@{} (used once, not stored): ", host.config.user, host.config.identifier);
+                        let _ = std::io::Write::flush(&mut std::io::stdout());
+                        Some(console::Term::stdout().read_secure_line()
+                            .map_err(|err| Trap::ReadInput(format!("Could not read input: {}", err)))?)
+                    },
+                    false => None,
+                };
+
+                let key = keys::init(&self.global_config, &host.config, hostname, password.as_deref(), now)?;
+                match password {
+                    Some(_) => println!("Generated {:?} and installed it on `{}`", key, hostname),
+                    None => println!("Generated {:?}, add {:?}.pub to ~/.ssh/authorized_keys of {} on `{}`", key, key, host.config.user, hostname),
+                }
+
+                host.config.key = Some(key.clone());
+                format!("{:?}", key)
+            },
+            "rotate" => {
+                let key = keys::rotate(&self.global_config, &host.config, hostname, now)?;
+                println!("Rotated `{}` to {:?}, the previous key stays authorized until `keys prune {}`", hostname, key, hostname);
+
+                host.config.previous_key = host.config.key.replace(key.clone());
+                format!("{:?}", key)
+            },
+            _ => {
+                let previous = keys::prune(&self.global_config, &host.config)?;
+                println!("Revoked {:?} on `{}`", previous, hostname);
+
+                host.config.previous_key = None;
+                format!("{:?}", previous)
+            },
+        };
+
+        settings.serialize_yaml(hosts)
+            .map_err(|err| Trap::Serialize(format!("Could not serialize settings: {}", err)))?;
+        AuditLog::new(&self.global_config).append(&AuditEntry::new(hostname, &format!("keys {}", command), &detail, now))?;
+
+        Ok(())
+    }
+
+    /* schedule action */
+
+    // Prints the next fire times of the cron_schedule of host (or all hosts),
+    // exports them as an iCal feed or simulates how they play out
+    fn schedule(&self) -> Result<(), Trap> {
+        match self.operands().first().map(String::as_str) {
+            Some("preview") => (),
+            Some("ical") => return self.schedule_ical(),
+            Some("simulate") => return self.schedule_simulate(),
+            _ => return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            ),
+        }
+
+        let next = match get_flag(self.operands(), "--next") {
+            Some(next) => next.parse::<usize>()
+                .map_err(|err| Trap::InvalidInput(format!("Invalid value for --next: {}", err)))?,
+            None => 10,
+        };
+
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let selected: Vec<&Host> = match self.operands().get(1).filter(|operand| !operand.starts_with("--")) {
+            Some(hostname) => match settings.hosts.iter().find(|host| host.hostname == *hostname) {
+                Some(host) => vec![host],
+                None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname))),
+            },
+            None => settings.hosts.iter().filter(|host| host.hostname != "dummy").collect(),
+        };
+
+        let style = console::Style::new();
+        let units = self.units()?;
+        let now = Local::now();
+        for host in selected {
+            let schedule = host_schedule(host)?;
+            println!("->  {} `{}`", style.clone().bold().blue().apply_to(&host.hostname), schedule);
+
+            for time in preview(&schedule, &now, next) {
+                println!("    {}", units.datetime(&time));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the backup and maintenance windows of the next days as iCal,
+    // to stdout unless --output is given
+    fn schedule_ical(&self) -> Result<(), Trap> {
+        let days = match get_flag(self.operands(), "--days") {
+            Some(days) => days.parse::<i64>()
+                .map_err(|err| Trap::InvalidInput(format!("Invalid value for --days: {}", err)))?,
+            None => DEFAULT_CALENDAR_DAYS,
+        };
+
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let selected: Vec<&Host> = match self.operands().get(1).filter(|operand| !operand.starts_with("--")) {
+            Some(hostname) => match settings.hosts.iter().find(|host| host.hostname == *hostname) {
+                Some(host) => vec![host],
+                None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname))),
+            },
+            None => settings.hosts.iter().filter(|host| host.hostname != "dummy").collect(),
+        };
+
+        let runs = History::new(&self.global_config).load()?;
+        let now = Local::now();
+        let ical = to_ical(&windows(&self.global_config, &selected, &runs, &now, days)?, &now);
+
+        match get_flag(self.operands(), "--output") {
+            Some(output) => fs::write(output, ical)
+                .map_err(|err| Trap::FS(format!("Could not write {:?}: {}", output, err)))?,
+            None => print!("{}", ical),
+        }
+
+        Ok(())
+    }
+
+    // Runs the scheduling of rensend over the next days without backing up
+    // anything and prints when each backup would start and finish. All
+    // hosts are simulated, as they share the slots, but only host is shown.
+    fn schedule_simulate(&self) -> Result<(), Trap> {
+        let days = match get_flag(self.operands(), "--days") {
+            Some(days) => days.parse::<i64>()
+                .map_err(|err| Trap::InvalidInput(format!("Invalid value for --days: {}", err)))?,
+            None => 7,
+        };
+
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let shown = self.operands().get(1).filter(|operand| !operand.starts_with("--"));
+        if let Some(hostname) = shown {
+            if !settings.hosts.iter().any(|host| host.hostname == *hostname) {
+                return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)));
+            }
+        }
+
+        let fleet: Vec<&Host> = settings.hosts.iter().filter(|host| host.hostname != "dummy").collect();
+        let runs = History::new(&self.global_config).load()?;
+        let simulated = simulate(&self.global_config, &fleet, &runs, &Local::now(), days)?;
+
+        let style = console::Style::new();
+        let units = self.units()?;
+        let (mut ran, mut skipped, mut longest_wait) = (0, 0, 0);
+        for run in simulated.iter().filter(|run| shown.is_none_or(|hostname| run.hostname == *hostname)) {
+            let hostname = style.clone().bold().blue().apply_to(&run.hostname);
+            match &run.outcome {
+                Simulated::Ran { started, finished } => {
+                    let wait = (*started - run.due).num_seconds();
+                    ran += 1;
+                    longest_wait = longest_wait.max(wait);
+                    match wait {
+                        0 => println!("{}  {}  started, done ~{}", units.datetime(&run.due), hostname, units.datetime(finished)),
+                        _ => println!("{}  {}  started {} (waited {}), done ~{}", units.datetime(&run.due), hostname, units.datetime(started), units.duration(wait), units.datetime(finished)),
+                    }
+                },
+                Simulated::Skipped(reason) => {
+                    skipped += 1;
+                    println!("{}  {}  {}", units.datetime(&run.due), hostname, style.clone().yellow().apply_to(format!("skipped: {}", reason)));
+                },
+                Simulated::Queued => println!("{}  {}  still queued at the end", units.datetime(&run.due), hostname),
+            }
+        }
+
+        println!(
+            "\n{} backups over {} days, {} skipped, longest wait {}, at most {} running at once",
+            ran, days, skipped, units.duration(longest_wait), peak_concurrency(&simulated)
+        );
+        Ok(())
+    }
+
+    /* advise action */
+
+    // Suggests excludes for what the latest runs of host fetched every time,
+    // in bulk, and could hardly compress
+    /// Where each source of every host stands after its latest run, as kept
+    /// in its status file, and whether it is overdue
+    fn status(&self, hostname: Option<&str>, only_overdue: bool) -> Result<(), Trap> {
+        let hosts = &self.global_config.hosts;
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+
+        let selected: Vec<&Host> = match hostname {
+            Some(hostname) => match settings.hosts.iter().find(|host| host.hostname == *hostname) {
+                Some(host) => vec![host],
+                None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname))),
+            },
+            None => settings.hosts.iter().filter(|host| host.hostname != "dummy").collect(),
+        };
+
+        let units = self.units()?;
+        let style = console::Style::new();
+        let now = Local::now().timestamp();
+        let mut overdue = 0;
+        for host in selected {
+            if Retirement::load(&self.global_config, &host.config)?.is_some() {
+                continue;
+            }
+
+            // Past the timeout of a run that was due, it can no longer finish in time
+            let schedule = host_schedule(host)?;
+            let grace = host.config.timeout.map(|timeout| timeout as i64).unwrap_or(OVERDUE_GRACE);
+            for namespace in host.config.namespaces()? {
+                let name = match &namespace.namespace {
+                    Some(name) => format!("{}:{}", host.hostname, name),
+                    None => host.hostname.clone(),
+                };
+                let status = HostStatus::load(&self.global_config, &namespace)?;
+                let late = status.overdue(&schedule, grace, now);
+                if late.is_some() {
+                    overdue += 1;
+                }
+                else if only_overdue {
+                    continue;
+                }
+
+                let state = match (late, &status.last_error) {
+                    (Some(Some(due)), _) => style.clone().red().apply_to(format!("OVERDUE since {}", units.timestamp(due))),
+                    (Some(None), _) => style.clone().red().apply_to(String::from("OVERDUE, never backed up")),
+                    (None, Some(_)) => style.clone().yellow().apply_to(format!("FAILED {} time(s)", status.failures)),
+                    (None, None) => style.clone().green().apply_to(String::from("OK")),
+                };
+                let success = status.last_success.map(|time| units.timestamp(time)).unwrap_or_else(|| String::from("never"));
+                let attempt = status.last_attempt.map(|time| units.timestamp(time)).unwrap_or_else(|| String::from("never"));
+                println!("->  {}  {}", style.clone().bold().blue().apply_to(&name), state);
+                println!("    last success {}, last attempt {}, {} in {}", success, attempt, units.bytes(status.bytes), units.duration(status.duration));
+                if let Some(error) = &status.last_error {
+                    println!("    {}", error);
+                }
+            }
+        }
+
+        if overdue > 0 {
+            return Err(Trap::Sla(format!("{} source(s) are overdue", overdue)));
+        }
+        Ok(())
+    }
+
+    fn advise(&self) -> Result<(), Trap> {
+        if self.operands().is_empty() || self.operands()[0].starts_with("--") {
+            return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            );
+        }
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[0];
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        let mut thresholds = Thresholds::default();
+        if let Some(size) = get_flag(self.operands(), "--min-size") {
+            thresholds.min_bytes = size.parse::<u64>()
+                .map_err(|err| Trap::InvalidInput(format!("Invalid size `{}` in MiB: {}", size, err)))? * 1024 * 1024;
+        }
+
+        let advice = advise(&self.global_config, &host_config, &thresholds)?;
+        let units = self.units()?;
+        let style = console::Style::new();
+        if advice.suggestions.is_empty() {
+            println!("Nothing in the last {} runs of `{}` is worth excluding", advice.runs, hostname);
+            return Ok(());
+        }
+
+        println!("{}", style.clone().bold().apply_to(format!("Exclude candidates of `{}`, from its last {} runs:", hostname, advice.runs)));
+        for suggestion in advice.suggestions.iter() {
+            let ratio = match suggestion.ratio {
+                Some(ratio) => format!("compresses to {:.0}%", ratio * 100.0),
+                None => String::from("compression unknown"),
+            };
+            println!("->  {}  {} per run, fetched in {} of {} runs, {} file(s), {}",
+                style.clone().bold().blue().apply_to(&suggestion.pattern), units.bytes(suggestion.bytes_per_run),
+                suggestion.runs, advice.runs, suggestion.files, ratio);
+        }
+
+        println!("\nReview them, then paste what should go under the host in hosts.yml:\n");
+        println!("    exclude:");
+        for suggestion in advice.suggestions.iter() {
+            println!("      - \"{}\"", suggestion.pattern.replace('\\', "\\\\").replace('"', "\\\""));
+        }
+
+        Ok(())
+    }
+
+    /* migrate action */
+
+    // Moves the file contents of every snapshot of a host between archives
+    // and the chunk store, after its `dedup` was changed
+    fn migrate(&self) -> Result<(), Trap> {
+        let to = match get_flag(self.operands(), "--to") {
+            Some(layout) if !self.operands().is_empty() && !self.operands()[0].starts_with("--") => Layout::from(layout)?,
+            _ => return Err(
+                Trap::InvalidInput(
+                    String::from("Invalid arguments for action. Use `help` for more details")
+                )
+            ),
+        };
+
+        let hosts = &self.global_config.hosts;
+        let hostname = &self.operands()[0];
+        let settings: Settings = Settings::deserialize_yaml(hosts)
+            .map_err(|err| Trap::Deserialize(format!("Could not deserialize {:?}: {}", hosts, err)))?;
+        let host_config = match settings.associated_config(hostname) {
+            Some(config) => config,
+            None => return Err(Trap::InvalidInput(format!("Host does not exist: `{}`", hostname)))
+        };
+
+        // Not while a backup of the host writes its records
+        let namespaces = host_config.namespaces()?;
+        let _lock = HostLock::acquire(&self.global_config, &namespaces[0])?;
+        let now = Local::now().timestamp();
+        for namespace in namespaces.iter() {
+            let report = migrate(&self.global_config, namespace, to, |snapshot| println!("Migrating {}...", snapshot))?;
+            if report.resumed > 0 {
+                println!("Resumed after {} snapshot(s) an earlier migration was done with", report.resumed);
+            }
+            println!("Migrated {} snapshot(s) of `{}`, {} file(s) moved", report.migrated.len(), namespace.repo_path().display(), report.files);
+
+            // The mirrors hold the archives as they were
+            for snapshot in report.migrated.iter() {
+                for err in mirror_snapshot(&self.global_config, namespace, snapshot, now) {
+                    println!("warning: {}", err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /* format action */
+
+    /// Shows the format of the repository, or with --upgrade moves it on to
+    /// the one this build writes
+    fn format(&self) -> Result<(), Trap> {
+        if self.operands().iter().any(|operand| operand == "--upgrade") {
+            let from = RepositoryFormat::upgrade(&self.global_config)?;
+            match from == FORMAT {
+                true => println!("The repository is in format {} already", FORMAT),
+                false => println!("Upgraded the repository from format {} to {}", from, FORMAT),
+            }
+            return Ok(());
+        }
+
+        let marker = RepositoryFormat::load(&self.global_config)?;
+        println!("Repository format: {}", marker.format);
+        if let Some(version) = marker.written_by {
+            println!("Written by: rensen {}", version);
+        }
+        println!("This rensen reads formats {} to {} and writes format {}", OLDEST_FORMAT, FORMAT, FORMAT);
+        Ok(())
+    }
+
+    /* help action */
+
+    pub fn print_help(&self) {
+        let style = Style::new();
+
+        if !self.operands().is_empty() {
+            match self.operands()[0].to_lowercase().as_str() {
+                "add" => {
+                    println!("a, add <hostname>     Enters host-adding interface.");
+                    println!(
+                        "Enters the host-adding interface where you are able to specify information about\nthen host which is going to be backupped.\n\n{} Remember to have a ssh-key generated in the path you specify, and also have thepublic key on the host machine.",
+                    style.bold().red().apply_to("Note:"));
+                },
+                "del" => {
+                    println!("d, del <hostname>     Deletes host config.");
+                    println!("Deletes the specified host's config from the configuration file located at probably in /etc/rensen or has specified path in /etc/rensen/rensen_config");
+                },
+                "mod"     => {
+                    println!("m, mod <hostname>     Enters modification interface.");
+                    println!("Allows you to modify a config for a host that already exists instead of readding it.");
+                },
+                "run" | "backup" => {
+                    println!("backup, run, r <hostname> [inc, full]   Runs backup for host based on what is specified in config (default inc).");
+                    println!("Runs the rensen backup system, either incremental or full backups. Backupped files will be stored\nat path specified in /etc/rensen/rensen_config.yml\n");
+                    println!("\nAliases:\nincremental, inc, i\nfull, f");
+                    println!("\nbackup, run, r --due    Runs an incremental backup of every host that is due, then exits.");
+                    println!("A host is due when its cron_schedule had a run since its last successful backup. Meant to be run\nfrom a systemd timer or cron instead of keeping rensend running, e.g. `rensen run --due`.");
+                    println!("\nA host with `sources` backs up each of them into its own snapshot chain in the same run.\nUse <hostname>:<name> with run, view, comp and prune to get at the chain of one source.");
+                },
+                "list"    => {
+                    println!("l, list    lists out all hosts.");
+
+                },
+                "view"    => {
+                    println!("v, view <hostname> <snapshots, config, histogram>     views snapshots taken of host.");
+                    println!("`histogram [<snapshot>]` shows what the files the snapshot fetched are by extension and size,\nthe latest snapshot by default, to tell what a host grows by.");
+                    println!("\nsnapshots: \nThis checks the snapshots/backups taken of the host at the location specified in /etc/rensen/rensen_config.yml");
+                    println!("Given a snapshot as well, e.g. `view myserver snapshots 2024-05-15-08-10-30`, lists the files in it.\nWith `warm_cache` set these are read from the index built after each backup.");
+                    println!("Snapshots of hosts with `dedup` show how much of the chunk store they hold alone, freed once they\nare pruned, and how much they share with other snapshots of any host.");
+                    println!("\nconfig: \nEchos out the deserialized format of the config file, stored at location specified in /etc/rensen/rensne_config.yml");
+                    println!("\nAliases: \nsnapshots, snap, s\nconfig, conf, c"); 
+                },
+                "status"  => {
+                    println!("status [<hostname>] [--overdue]     Shows where each source of every host (or of host) stands.");
+                    println!("Lists when each source last succeeded and was last attempted, what its latest run transferred\nand how long it took, and the error it failed with. A source is overdue once a scheduled run since\nits last success is more than an hour past, or the `timeout` of its host. --overdue lists only those,\nand the command fails while there are any, for monitoring.");
+                },
+                "report"  => {
+                    println!("rp, report    Prints a report of all hosts.");
+                    println!("Shows how each host is doing against its SLA (`sla: HH:MM` in the host config), as recorded by rensend,\nalong with the outcome of its last run. The layout can be replaced with `templates.report` in the global config.");
+                },
+                "history" => {
+                    println!("hi, history <hostname> [--last N]     Lists the latest runs of host.");
+                    println!("Shows duration, transferred and total size of the last N runs (default 30) along with\nhow duration and size are trending, comparing the older half of the runs to the newer half.");
+                    println!("\nhi, history export [--format csv|parquet] [--since YYYY-MM-DD] [--until YYYY-MM-DD] [--output <path>]");
+                    println!("Exports the runs of all hosts. CSV is written to stdout unless --output is given.");
+                },
+                "stats"   => {
+                    println!("st, stats [--month YYYY-MM]     Lists how much each host transferred during a month.");
+                    println!("Defaults to the current month. Meant for charging back teams that share the backup destination.");
+                    println!("\nst, stats <hostname> [--last N]");
+                    println!("Lists what the host transferred per month, followed by its last N runs (default 10),\nwith the CPU time, peak memory and disk I/O each took on the backup server.");
+                },
+                "compact" => {
+                    println!("cp, compact <hostname> [--dry-run]     Compacts the snapshot records of host older than `record_retention` days.");
+                    println!("Compacted records keep their size and file counts, but can no longer be compiled.\nWith --dry-run every record that would be rewritten is listed with its size before and after, and nothing is changed.");
+                },
+                "gc" => {
+                    println!("gc <hostname> [--dry-run]              Removes leftovers nothing refers to from the backups of host.");
+                    println!("These are unpacked snapshot directories next to their archive, e.g. after an interrupted compile,\nand index files of snapshots that are gone. With --dry-run they are only listed, with their sizes.");
+                },
+                "prune" => {
+                    println!("pr, prune <hostname> [--dry-run]       Removes the snapshots of host past its `retention` days and `keep` policy.");
+                    println!("pr, prune <hostname, --all> --simulate [--months N]  Projects the repository size over the next N (default 12) months.");
+                    println!("Every source of the host is pruned by its own retention, use <hostname>:<name> for just one.\nThe newest snapshot is always kept, as is every snapshot the live record still has files in.\nWith --dry-run the snapshots are only listed, with their sizes. --simulate assumes snapshots keep coming\nat the pace and size they have so far, prunes them the same way and compares the growth with the free space\nat the destination.");
+                },
+                "reset" => {
+                    println!("reset <hostname>                       Resumes the scheduled backups of host after its breaker tripped.");
+                    println!("With `trip_after` in the global config, a host failing that many runs in a row is held back by\nrensend and `run --due`, with an alert of its own, until it is reset, a manual run succeeds,\nor `trip_cooldown` hours have passed.");
+                },
+                "advise" => {
+                    println!("ad, advise <hostname> [--min-size MiB]  Suggests excludes from the last runs of host.");
+                    println!("Files of a directory and extension, or single files, that were fetched in most of the last 10 runs,\n64 MiB or more (--min-size) per run on average, and compress to 90% or more of their size. Each comes\nwith a ready-to-paste pattern for `exclude`, nothing is changed.");
+                },
+                "migrate" => {
+                    println!("migrate <hostname> --to <tar, chunked>  Moves the file contents of every snapshot of host to layout.");
+                    println!("tar keeps them in the archive of each snapshot, chunked in the chunk store as `dedup` does. Set or unset\n`dedup` of host first. Archives are rewritten one snapshot at a time, and an interrupted migration\npicks up at the snapshot it stopped at when run again. The rewritten snapshots are mirrored again.");
+                },
+                "format" => {
+                    println!("format [--upgrade]                     Shows the format of the repository, or upgrades it.");
+                    println!("Every rensen refuses to write a repository in another format than its own, and to start on one\nnewer than it reads. Upgrade once every rensen reading the repository, e.g. a standby rensend, runs\na version that reads the new format.");
+                },
+                "annotate" => {
+                    println!("an, annotate <hostname> <text> [--latest]  Attaches a note to the next snapshot of host.");
+                    println!("For deploy pipelines and other tools to mark events, e.g. `rensen annotate web01 deployed v2.3.1`.\nThe note is attached to the next snapshot taken of host, or with --latest to its latest one, and shown\nalongside it by `view <hostname> snapshots` and `restore`.");
+                },
+                "host" => {
+                    println!("ho, host retire <hostname> [--keep-until YYYY-MM-DD]  Retires host once it is decommissioned.");
+                    println!("Retired hosts are skipped by rensend and `run --due`, manual backups, compaction and annotations\nof them are refused, and prune keeps all of their snapshots, or with --keep-until removes all of\nthem after that day. The host stays in the settings so its snapshots can still be listed, verified\nand restored. Retiring is recorded in the audit log, and running it again moves --keep-until.");
+                },
+                "keys" => {
+                    println!("ke, keys init <hostname> [--install]   Generates a dedicated ed25519 key for host and sets it as its `key`.");
+                    println!("ke, keys rotate <hostname>             Replaces the key of host with a new one.");
+                    println!("ke, keys prune <hostname>              Revokes the key the last rotation replaced.");
+                    println!("Keys are kept at `keys` in the global config (default /etc/rensen/keys), one file per key.\nWith --install the password of the host's user is asked for once to add the key to its authorized_keys.\nRotating authorizes the new key over the current one and checks it logs in, the old one stays authorized\nas `previous_key` so nothing still using it breaks, until it is pruned. Every change is recorded in the audit log.");
+                },
+                "schedule" => {
+                    println!("sc, schedule preview [<hostname>] [--next N]  Prints the next N (default 10) backups of host (or all hosts).");
+                    println!("These are the times rensend and `run --due` go by for the host's `cron_schedule`, `interval` or `run_at`,\nshown in the configured `timezone`. Use it to check a new expression does what was intended.");
+                    println!("\nsc, schedule ical [<hostname>] [--days N] [--output <path>]  Exports the windows of the next N (default 14) days as iCal.");
+                    println!("One event per scheduled backup of host (or all hosts), lasting as long as its recent runs took,\nand one per `maintenance` window, for ops calendars and change management. Written to stdout unless --output is given.");
+                    println!("\nsc, schedule simulate [<hostname>] [--days N]  Plays the next N (default 7) days of scheduling.");
+                    println!("Applies the breakers, the overlap rule, the fair queue and `max_concurrent_backups` to all hosts, each\nbackup taking as long as its recent runs, and prints when the backups of host (or all hosts) would start\nand finish. Nothing is backed up. Use it to check a schedule change does not pile up backups.");
+                },
+                "replica" => {
+                    println!("rc, replica [<hostname>]               Cross-checks the snapshots of host (or all hosts) against the replica.");
+                    println!("Hashes the archives and records of every snapshot on both sides of `replica` in the global config,\nand alerts on anything missing, different or only on the replica. Archives are sealed with their hash\nthe first time they are checked, so changes to them on the primary are caught too.");
+                },
+                "mirror" => {
+                    println!("mi, mirror [<hostname>]                Catches up the mirrors of host (or all hosts) and shows their status.");
+                    println!("Each backup copies its snapshot to the `mirrors` of the host as well: other local volumes (`path`),\nor anything a `command` run per file can put it on, like S3. Mirrors with `defer: true` are left for\nrensend to catch up once the run is done, or for this action. Failed copies are retried here too.");
+                },
+                "restore" => {
+                    println!("rs, restore <hostname> <snapshot> [--plan, --execute] [--verify]  Restores a snapshot (or `latest`) onto host.");
+                    println!("The snapshot can also be a point in time, YYYY-MM-DD or YYYY-MM-DD-HH-MM-SS, restoring the last snapshot taken by then.");
+                    println!("Follows the runbook under `restore` in the host's config: the paths of `order` are restored first, in that order,\nthen the rest of the source, with each `post` command run once its `after` path is restored (or at the end).\nFiles go back to `target` on the host (default: `source`). --plan, the default, only prints the steps,\n--execute carries them out over ssh and stops at the first step that fails.\n--verify adds a last step hashing every restored file on the host and comparing it with the snapshot, with a\nPASS or FAIL at the end and a non-zero exit code on failure.");
+                },
+                "seed" => {
+                    println!("se, seed export <hostname> <media> [--limit R]  Copies the source of host onto removable media.");
+                    println!("se, seed import <hostname> <media>              Imports it as the first snapshot of host.");
+                    println!("For hosts whose first full backup would take too long over the network. Run the export at the source site,\nwhere `source` is a local path, with --limit capping the read rate (e.g. 20M per second). An interrupted\nexport or import picks up where it left off. Once imported, the first run over the network is incremental.");
+                },
+                "helper" => {
+                    println!("he, helper <hostname> [--deploy]       Shows the rensen-helper on host.");
+                    println!("Hosts with `helper: true` get the binary at `helper_path` (default /usr/lib/rensen/rensen-helper)\ndeployed to ~/.rensen/rensen-helper whenever it is missing or speaks another protocol, and fall back to\npure sftp when that fails. With --deploy the binary is uploaded right away, replacing what is there.");
+                },
+                "discover" => {
+                    println!("di, discover [--apply]                 Lists hosts found in the inventory sources that are not enrolled yet.");
+                    println!("Sources (Ansible inventories, JSON/CSV files or URLs, DNS SRV records) are set under `inventory` in the global config.\nNew hosts get their config from `inventory.template`, known ones only have their addr, user and port updated.\nNothing is written until the changes are reviewed and applied with --apply. Hosts are never removed.");
+                },
+                "verify"  => {
+                    println!("vf, verify <hostname> [--percent N] [--refetch]  Verifies the snapshots of host.");
+                    println!("Reads every archive and checks it holds the files its record lists, with the sizes and hashes they were\nfetched with. With --percent only that share of the snapshots is checked, those verified the longest ago first.\nWith --refetch, files that are missing or damaged and still current are backed up again by an incremental run.");
+                    println!("rensend does this on its own for hosts with `verify_schedule` (cron) set, checking `verify_percent`\n(default 10) of the snapshots each time.");
                 },
                 "compile" => {
                     println!("c, comp <hostname>     Starts compilation interface.");
@@ -600,15 +2487,43 @@ impl Action {
 
         println!("h, ?, help                             Show this info.");
         println!("q, quit, exit                          Quit ctl.");
-        println!("clear                                  Clear screen.\n");
+        println!("clear                                  Clear screen.");
+        println!("--raw                                  Can be added to any action to print raw sizes and UTC timestamps.");
+        println!("--help                                 Can be added to any action to show its arguments.\n");
 
         println!("a, add <hostname>                      Enter host-adding interface.");
         println!("d, del <hostname>                      Deletes host config.");
         println!("m, mod <hostname>                      Enter modification interface.");
-        println!("r, run <hostname> <inc, full>          Run backup for host machine.");
+        println!("backup, r, run <hostname> [inc, full] Run backup for host machine.");
+        println!("backup, r, run --due                   Run backups of all hosts that are due.");
         println!("l, list                                Lists all hosts on system.");
-        println!("v, view <hostname> <snapshots, config> views snapshots taken of host or echos config file.");
+        println!("v, view <hostname> <snapshots [<snapshot>], histogram [<snapshot>], config> views snapshots taken of host, what they fetched or echos config file.");
         println!("c, comp <hostname>                     Start compilation interface.");
+        println!("rp, report                             Prints a report of all hosts.");
+        println!("status [<hostname>] [--overdue]        Shows the latest run of each source of host and whether it is overdue.");
+        println!("hi, history <hostname> [--last N]      Lists the latest runs of host.");
+        println!("st, stats [<hostname>] [--month M]     Lists bytes transferred per host and month.");
+        println!("vf, verify <hostname> [--percent N] [--refetch] Verifies the snapshots of host.");
+        println!("di, discover [--apply]                 Enrolls hosts from the inventory sources.");
+        println!("cp, compact <hostname> [--dry-run]     Compacts old snapshot records of host.");
+        println!("gc <hostname> [--dry-run]              Removes leftovers from the backups of host.");
+        println!("pr, prune <hostname> [--dry-run]       Removes snapshots of host past their retention.");
+        println!("pr, prune --all --simulate [--months N] Forecasts the size of the repository.");
+        println!("rc, replica [<hostname>]               Cross-checks snapshots against the replica.");
+        println!("he, helper <hostname> [--deploy]       Shows or deploys the rensen-helper on host.");
+        println!("se, seed <export, import> <hostname> <media> Seeds the first backup of host via removable media.");
+        println!("rs, restore <hostname> <snapshot> [--execute] [--verify] Prints or runs the restore runbook of host.");
+        println!("mi, mirror [<hostname>]                Catches up and shows the mirrors of host.");
+        println!("sc, schedule preview [<hostname>] [--next N] Prints the upcoming backups of host.");
+        println!("sc, schedule ical [<hostname>] [--days N] Exports the upcoming backup windows as iCal.");
+        println!("sc, schedule simulate [<hostname>] [--days N] Simulates the scheduling of the next days.");
+        println!("reset <hostname>                       Resumes the backups of host after its breaker tripped.");
+        println!("ad, advise <hostname> [--min-size MiB]    Suggests excludes for large, churning, incompressible files.");
+        println!("an, annotate <hostname> <text> [--latest] Attaches a note to the next or latest snapshot of host.");
+        println!("ho, host retire <hostname> [--keep-until YYYY-MM-DD] Retires host, keeping its snapshots.");
+        println!("ke, keys <init, rotate, prune> <hostname> Generates, rotates and revokes the ssh keys of host.");
+        println!("migrate <hostname> --to <tar, chunked>  Moves the snapshots of host to another storage layout.");
+        println!("format [--upgrade]                     Shows or upgrades the format of the repository.");
     }
 }
 
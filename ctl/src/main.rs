@@ -1,12 +1,17 @@
 // std/other
 use std::io;
+use std::iter;
+use std::process;
 use console::{Term, Style};
 use std::path::PathBuf;
+use clap::Parser;
 
 // rensen-lib
 use rensen_lib::logging::*;
 use rensen_lib::config::GlobalConfig;
 use rensen_lib::traits::YamlFile;
+use rensen_lib::exit::ExitCode;
+use rensen_lib::repository::RepositoryFormat;
 
 // Action
 pub mod action;
@@ -15,6 +20,34 @@ use action::*;
 pub mod utils;
 use utils::*;
 
+/// Backups of hosts over ssh. Runs the interactive shell unless given an
+/// action, e.g. `rensen history myserver`.
+#[derive(Parser)]
+#[command(name = "rensen", version, disable_help_subcommand = true)]
+struct Cli {
+    /// Print raw sizes and UTC timestamps, with any action
+    #[arg(long, global = true)]
+    raw: bool,
+
+    #[command(subcommand)]
+    action: Option<ActionType>,
+}
+
+impl Cli {
+    /// Parses `input` like the arguments of `rensen`. `--raw` may come
+    /// anywhere, the actions taking their operands as they come never see it.
+    fn parse(input: &[String]) -> Result<Self, clap::Error> {
+        let raw = input.iter().any(|operand| operand == "--raw");
+        let args = input.iter()
+            .filter(|operand| *operand != "--raw")
+            .enumerate()
+            .map(|(i, arg)| if i == 0 { arg.to_lowercase() } else { arg.clone() });
+
+        let cli = Cli::try_parse_from(iter::once(String::from("rensen")).chain(args))?;
+        Ok(Cli { raw: raw || cli.raw, ..cli })
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Ctl {
     pub global_config: GlobalConfig,
@@ -54,12 +87,11 @@ impl Ctl {
             let input_vec: Vec<String> = input.split_whitespace().map(String::from).collect();
 
             // Checking the first index, what type of action it predicts.
-            let action = match self.parse_action_type(&input_vec) {
-                Some(action) => {
-                    action
-                }
-                None => {
-                    println!("`{}` is not a recognized action!", input_vec[0]);
+            let action = match Cli::parse(&input_vec).map(|cli| self.action(cli)) {
+                Ok(Some(action)) => action,
+                Ok(None) => continue,
+                Err(err) => {
+                    let _ = err.print();
                     continue;
                 }
             };
@@ -74,7 +106,8 @@ impl Ctl {
             }
 
             // execute the action of commnad given
-            let _ = match action.execute() {
+            match action.execute() {
+                Ok(ExitCode::Warnings) => println!("Completed with warnings"),
                 Ok(_) => (),
                 Err(e) => {
                     log_trap(&self.global_config, &e);
@@ -86,27 +119,10 @@ impl Ctl {
         Ok(())
     }
 
-    fn parse_action_type(&self, input: &Vec<String>) -> Option<Action> {
-        if input.is_empty() {
-            return None;
-        }
-
-        let action_type = match input[0].to_lowercase().as_str() {
-            "a" | "add"           => ActionType::AddHost,
-            "d" | "del"           => ActionType::DeleteHost,
-            "l" | "list"          => ActionType::ListHosts,
-            "v" | "view"          => ActionType::View,
-            "m" | "mod"           => ActionType::ModifyHost,
-            "r" | "run"           => ActionType::RunBackup,
-            "c" | "comp"          => ActionType::Compile,
-            "clear"               => ActionType::Clear,
-            "h" | "?" | "help"    => ActionType::Help,
-            "q" | "quit" | "exit" => ActionType::Exit,
-            _ => return None,
-        };
-
+    /// The action `cli` names, if any
+    fn action(&self, cli: Cli) -> Option<Action> {
         let global_config = self.global_config.clone();
-        Some(Action { global_config, action_type, operands: input.iter().skip(1).cloned().collect() })
+        cli.action.map(|action_type| Action { global_config, action_type, raw: cli.raw })
     }
 
     pub fn clear_screen(&self) {
@@ -114,23 +130,86 @@ impl Ctl {
     }
 }
 
-fn main() -> std::io::Result<()> {
+fn main() -> process::ExitCode {
+    if let Some(code) = rensen_lib::sandbox::serve_if_requested() {
+        return code;
+    }
+
+    // Running a single action when given as arguments, e.g. `rensen history myserver`.
+    // Parsed first, so --help works without a config.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = match Cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(err) => {
+            let _ = err.print();
+            return match err.use_stderr() {
+                true => ExitCode::Config.into(),
+                false => ExitCode::Success.into(), // --help or --version
+            };
+        }
+    };
+
     let global_config_path = PathBuf::from("/etc/rensen/rensen_config.yml");
     let mut ctl = Ctl { 
         global_config: match GlobalConfig::deserialize_yaml(&global_config_path) {
             Ok(v) => v,
             Err(err) => {
                 println!("{}", err);
-                return Ok(());
+                return ExitCode::Config.into();
             }
         },
 
         term: Term::stdout()
 
     };
+    rensen_lib::sandbox::enable(ctl.global_config.sandbox.unwrap_or(false));
+    if let Err(err) = rensen_lib::ownership::apply(&ctl.global_config) {
+        println!("{}", err);
+        return ExitCode::from(&err).into();
+    }
+
+    // Nothing is read from a repository of a format this build does not know
+    if let Err(err) = RepositoryFormat::check(&ctl.global_config) {
+        println!("{}", err);
+        return ExitCode::from(&err).into();
+    }
+
+    if let Some(action) = ctl.action(cli) {
+        let code = match action.execute() {
+            Ok(code) => code,
+            Err(err) => {
+                log_trap(&ctl.global_config, &err);
+                println!("{:?}", err);
+                ExitCode::from(&err)
+            }
+        };
+
+        return code.into();
+    }
 
     ctl.clear_screen();
-    let _ = ctl.start();
+    if ctl.start().is_err() {
+        return ExitCode::Failure.into();
+    }
+
+    ExitCode::Success.into()
+}
 
-    Ok(())
+#[test]
+fn test_parse() {
+    use clap::CommandFactory;
+    Cli::command().debug_assert();
+
+    let parse = |input: &str| Cli::parse(&input.split_whitespace().map(String::from).collect::<Vec<_>>());
+    let cli = parse("Run web01 full --raw").unwrap();
+    assert!(cli.raw);
+    assert!(matches!(cli.action, Some(ActionType::RunBackup { hostname: Some(_), method: BackupMethod::Full, due: false })));
+    assert!(matches!(parse("backup --due").unwrap().action, Some(ActionType::RunBackup { hostname: None, due: true, .. })));
+    assert!(parse("backup").is_err());
+
+    // Left to the action as they come
+    let cli = parse("history web01 --last 5").unwrap();
+    assert_eq!(cli.action.unwrap().operands(), ["web01", "--last", "5"]);
+    assert!(matches!(parse("? view").unwrap().action, Some(ActionType::Help(_))));
+    assert!(parse("nonsense").is_err());
 }
@@ -1,6 +1,4 @@
 use std::io::{self, Write, BufRead};
-use std::fmt;
-use std::path::PathBuf;
 
 pub fn get_input(prompt: &str) -> Result<String, io::Error> {
     print!("{}", prompt);
@@ -12,68 +10,20 @@ pub fn get_input(prompt: &str) -> Result<String, io::Error> {
     Ok(buffer)
 }
 
-#[derive(PartialEq, Debug)]
-pub enum ByteUnit {
-    B,
-    Kib,
-    Mib,
-    Gib,
-    Tib
+/// Returns the value following `flag` in the operands, e.g. `--last 30`
+pub fn get_flag<'a>(operands: &'a [String], flag: &str) -> Option<&'a String> {
+    operands.iter()
+        .position(|operand| operand == flag)
+        .and_then(|i| operands.get(i + 1))
 }
 
-impl ByteUnit {
-    fn from_u32(value: u32) -> ByteUnit {
-        match value {
-            0 => ByteUnit::B,
-            1 => ByteUnit::Kib,
-            2 => ByteUnit::Mib,
-            3 => ByteUnit::Gib,
-            4 => ByteUnit::Tib,
-            _ => ByteUnit::B,
-        }
-    }
-}
-
-impl fmt::Display for ByteUnit {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ByteUnit::B   => write!(f, "B"),
-            ByteUnit::Kib => write!(f, "Kib"),
-            ByteUnit::Mib => write!(f, "MiB"),
-            ByteUnit::Gib => write!(f, "Gib"),
-            ByteUnit::Tib => write!(f, "Tib"),
-        }
-    }
-}
-
-#[derive(PartialEq, Debug)]
-pub struct MemoryUsage {
-    pub amount: f32,
-    pub unit: ByteUnit
-}
-
-pub fn format_bytes(bytes: u64) -> MemoryUsage { 
-    let mut current_amount = bytes;
-    let mut unit = 0;
-    let mut result: MemoryUsage = MemoryUsage { amount: 0.0, unit: ByteUnit::B };
-
-    while current_amount > 1024 {
-        current_amount /= 1024;
-        unit += 1;
-
-        if current_amount < 1024 {
-            break;
-        }
-    }
-    
-    result.amount = current_amount as f32;
-    result.unit = ByteUnit::from_u32(unit);
-    return result;
-}
+/// Parses a `YYYY-MM-DD` date into unix seconds at local midnight
+pub fn parse_date(date: &str) -> Result<i64, String> {
+    let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|err| format!("Invalid date `{}`, expected YYYY-MM-DD: {}", date, err))?;
 
-#[cfg(test)]
-#[test]
-fn test_format_bytes() {
-    let bytes: u64 = 900012000;
-    assert_eq!(format_bytes(bytes), MemoryUsage { amount: 0.0, unit: ByteUnit::B });
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|time| time.and_local_timezone(chrono::Local).earliest())
+        .map(|time| time.timestamp())
+        .ok_or(format!("Invalid local date `{}`", date))
 }
@@ -0,0 +1,227 @@
+use rensen_lib::audit::{AuditEntry, AuditLog};
+use rensen_lib::cancel::{Cancel, CANCEL_GRACE};
+use rensen_lib::config::GlobalConfig;
+use rensen_lib::logging::*;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+/// What the control endpoint can be asked to do to a host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Backup, // queue a backup, like one that is due
+    Cancel, // take a queued backup out of the queue, or cancel a running one
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Backup => "backup",
+            Command::Cancel => "cancel",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Command::Backup, Command::Cancel].into_iter().find(|command| command.name() == name)
+    }
+}
+
+/// A command of an authenticated user, for the scheduler to carry out. It
+/// replies with the HTTP status and message the caller is answered with.
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    pub hostname: String,
+    pub user: String,
+    pub reply: oneshot::Sender<(u16, String)>,
+}
+
+/// The control endpoint: who may use it, and the running backups it can
+/// cancel
+#[derive(Debug)]
+pub struct Control {
+    global_config: Arc<GlobalConfig>, // as rensend started, `control` is not reloaded
+    requests: mpsc::UnboundedSender<Request>,
+    runs: Mutex<HashMap<String, Run>>, // by host
+    next_run: Mutex<u64>,
+}
+
+/// The cancels of the sources of a running backup
+#[derive(Debug, Default)]
+struct Run {
+    id: u64,
+    cancels: Vec<Cancel>,
+    cancelled: Option<String>, // why, for sources that start after
+}
+
+/// Keeps a backup cancellable until dropped
+pub struct Running {
+    control: Arc<Control>,
+    hostname: String,
+    id: u64,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut runs = self.control.runs.lock().unwrap();
+        if runs.get(&self.hostname).is_some_and(|run| run.id == self.id) {
+            runs.remove(&self.hostname);
+        }
+    }
+}
+
+impl Control {
+    pub fn new(global_config: Arc<GlobalConfig>) -> (Arc<Self>, mpsc::UnboundedReceiver<Request>) {
+        let (requests, received) = mpsc::unbounded_channel();
+        let control = Control { global_config, requests, runs: Mutex::new(HashMap::new()), next_run: Mutex::new(0) };
+        (Arc::new(control), received)
+    }
+
+    /// Makes the backup of `hostname` that is about to start cancellable
+    pub fn start(self: &Arc<Self>, hostname: &str) -> Running {
+        let id = {
+            let mut next_run = self.next_run.lock().unwrap();
+            *next_run += 1;
+            *next_run
+        };
+        self.runs.lock().unwrap().insert(hostname.to_string(), Run { id, ..Default::default() });
+        Running { control: Arc::clone(self), hostname: hostname.to_string(), id }
+    }
+
+    /// Adds `cancel` of a source of the running backup of `hostname`. One
+    /// attached after the backup was cancelled is cancelled right away.
+    pub fn attach(&self, hostname: &str, cancel: &Cancel) {
+        let mut runs = self.runs.lock().unwrap();
+        let Some(run) = runs.get_mut(hostname) else { return };
+        if let Some(reason) = &run.cancelled {
+            cancel.cancel(reason);
+        }
+        run.cancels.push(cancel.clone());
+    }
+
+    /// Cancels the running backup of `hostname`, false if there is none. One
+    /// still going CANCEL_GRACE later has its connection shut.
+    pub fn cancel(self: &Arc<Self>, hostname: &str, reason: &str) -> bool {
+        let id = {
+            let mut runs = self.runs.lock().unwrap();
+            let Some(run) = runs.get_mut(hostname) else { return false };
+            run.cancelled = Some(reason.to_string());
+            run.cancels.iter().for_each(|cancel| cancel.cancel(reason));
+            run.id
+        };
+
+        let (control, hostname) = (Arc::clone(self), hostname.to_string());
+        thread::spawn(move || {
+            thread::sleep(CANCEL_GRACE);
+            if let Some(run) = control.runs.lock().unwrap().get(&hostname).filter(|run| run.id == id) {
+                run.cancels.iter().for_each(Cancel::sever);
+            }
+        });
+        true
+    }
+
+    /// Answers the caller sending `authorization` asking for `command` of
+    /// `hostname`, with the HTTP status and a message. What the credentials
+    /// were refused for is only logged.
+    pub async fn handle(&self, command: Command, hostname: &str, authorization: Option<&str>) -> (u16, String) {
+        let Some(config) = self.global_config.control.clone() else {
+            return (404, String::from("No control endpoint is configured"));
+        };
+        let Some(authorization) = authorization.filter(|authorization| !authorization.is_empty()).map(String::from) else {
+            return (401, String::from("Credentials required"));
+        };
+
+        // PAM and the identity provider both take their time
+        let user = match tokio::task::spawn_blocking(move || config.authenticate(&authorization)).await {
+            Ok(Ok(user)) => user,
+            Ok(Err(trap)) => {
+                log_host_trap(&self.global_config, hostname, &trap);
+                return match trap {
+                    Trap::Auth(_) => (401, String::from("Authentication failed")),
+                    _ => (500, String::from("The control endpoint is misconfigured")),
+                };
+            },
+            Err(err) => return (500, format!("Could not authenticate: {}", err)),
+        };
+
+        let (reply, answer) = oneshot::channel();
+        let request = Request { command, hostname: hostname.to_string(), user, reply };
+        if self.requests.send(request).is_err() {
+            return (503, String::from("rensend is not scheduling backups"));
+        }
+        answer.await.unwrap_or((503, String::from("rensend did not answer")))
+    }
+
+    /// Audits `command` of a host by `user`
+    pub fn audit(&self, command: Command, hostname: &str, user: &str, now: i64) {
+        let entry = AuditEntry { user: user.to_string(), ..AuditEntry::new(hostname, command.name(), "over the control endpoint", now) };
+        if let Err(err) = AuditLog::new(&self.global_config).append(&entry) {
+            log_host_trap(&self.global_config, hostname, &err);
+        }
+    }
+}
+
+/// The control query of the status socket the front-end forwards `command`
+/// of `hostname` with
+pub fn query(command: Command, hostname: &str, authorization: Option<&str>) -> String {
+    format!("{} {} {} {}", crate::frontend::CONTROL_QUERY, command.name(), hostname, authorization.unwrap_or_default())
+}
+
+/// The command, host and authorization of a control query
+pub fn parse_query(query: &str) -> Option<(Command, &str, Option<&str>)> {
+    let mut parts = query.strip_prefix(crate::frontend::CONTROL_QUERY)?.strip_prefix(' ')?.splitn(3, ' ');
+    let command = Command::from_name(parts.next()?)?;
+    let hostname = parts.next().filter(|hostname| is_hostname(hostname))?;
+    Some((command, hostname, parts.next().filter(|authorization| !authorization.is_empty())))
+}
+
+/// Whether `hostname` can be a host of hosts.yml, as taken from a path. The
+/// sources of a host are backed up and cancelled with it, so `host:source`
+/// is not one.
+pub fn is_hostname(hostname: &str) -> bool {
+    !hostname.is_empty() && hostname.chars().all(|char| char.is_ascii_alphanumeric() || "-_.".contains(char))
+}
+
+/// Why a backup cancelled by `user` ended, as its trap says
+pub fn cancel_reason(hostname: &str, user: &str) -> String {
+    format!("The backup of `{}` was cancelled by `{}`", hostname, user)
+}
+
+#[tokio::test]
+async fn test_control() {
+    let (control, mut requests) = Control::new(Arc::new(GlobalConfig::default()));
+
+    // Sources that start after the backup was cancelled do not get to run
+    let running = control.start("web01");
+    let first = Cancel::new();
+    control.attach("web01", &first);
+    assert!(control.cancel("web01", "cancelled by `alice`"));
+    assert!(matches!(first.trap(), Some(Trap::Cancelled(reason)) if reason == "cancelled by `alice`"));
+    let second = Cancel::new();
+    control.attach("web01", &second);
+    assert!(second.is_cancelled());
+
+    // Once it is over there is nothing to cancel, whatever a later run left
+    let later = control.start("web01");
+    drop(running);
+    assert!(control.runs.lock().unwrap().get("web01").unwrap().cancelled.is_none());
+    drop(later);
+    assert!(!control.cancel("web01", "cancelled by `alice`"));
+
+    // Nobody gets through without a `control` config or credentials
+    assert_eq!(control.handle(Command::Backup, "web01", Some("Basic YWxpY2U6czNjcmV0")).await.0, 404);
+    let global_config = GlobalConfig { control: Some(Default::default()), ..Default::default() };
+    let (control, _) = Control::new(Arc::new(global_config));
+    assert_eq!(control.handle(Command::Cancel, "web01", None).await.0, 401);
+    assert_eq!(control.handle(Command::Cancel, "web01", Some("Bearer abc")).await, (401, String::from("Authentication failed")));
+    assert!(requests.try_recv().is_err());
+
+    assert_eq!(parse_query(&query(Command::Cancel, "web01", Some("Bearer abc def"))), Some((Command::Cancel, "web01", Some("Bearer abc def"))));
+    assert_eq!(parse_query(&query(Command::Backup, "db01.example.com", None)), Some((Command::Backup, "db01.example.com", None)));
+    assert_eq!(parse_query(&query(Command::Backup, "db01:etc", None)), None);
+    assert_eq!(parse_query("control reload web01"), None);
+    assert_eq!(parse_query("control backup ../etc Bearer abc"), None);
+    assert_eq!(parse_query("controlbackup web01"), None);
+}
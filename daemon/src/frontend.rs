@@ -0,0 +1,165 @@
+use rensen_lib::config::GlobalConfig;
+use rensen_lib::logging::*;
+use rensen_lib::utils::user_ids;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+use tokio::time::{timeout, Duration};
+
+use crate::metrics::Metrics;
+use crate::control::{parse_query, Control};
+
+pub const DEFAULT_STATUS_SOCKET: &str = "/run/rensen/status.sock";
+
+/// What the front-end can ask for. Anything else on the socket is hung up
+/// on, and control queries are carried out for the credentials they were
+/// sent with only, so the front-end can not make rensend do anything on its
+/// own.
+pub const METRICS_QUERY: &str = "metrics";
+pub const PROGRESS_QUERY: &str = "progress"; // the backups running, see Metrics::render_progress
+pub const CONTROL_QUERY: &str = "control";   // followed by the command, host and authorization, see control::query
+
+/// Bytes of a query read at most, room for the bearer tokens of control queries
+const MAX_QUERY: u64 = 16 * 1024;
+
+pub fn status_socket(global_config: &GlobalConfig) -> PathBuf {
+    global_config.status_socket.clone().unwrap_or(PathBuf::from(DEFAULT_STATUS_SOCKET))
+}
+
+/// Answers the front-end on `socket_path` until rensend stops. Only `gid`,
+/// the group of the front-end, can connect. Control queries are answered
+/// with the HTTP status and message of Control::handle.
+pub async fn serve_status(global_config: Arc<GlobalConfig>, socket_path: PathBuf, gid: u32, metrics: Arc<Metrics>, control: Arc<Control>) -> Result<(), Trap> {
+    let listener = bind_socket(&socket_path, gid)
+        .map_err(|err| Trap::Config(format!("Could not listen on {:?} for the front-end: {}", socket_path, err)))?;
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log_trap(&global_config, &Trap::STD(format!("Could not accept a front-end connection: {}", err)));
+                continue;
+            }
+        };
+
+        let (metrics, control) = (Arc::clone(&metrics), Arc::clone(&control));
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut query = String::new();
+            let read = timeout(Duration::from_secs(5), BufReader::new(reader.take(MAX_QUERY)).read_line(&mut query)).await;
+            let answer = match (read, query.trim_end()) {
+                (Ok(Ok(_)), METRICS_QUERY) => metrics.render(),
+                (Ok(Ok(_)), PROGRESS_QUERY) => metrics.render_progress(),
+                (Ok(Ok(_)), query) => match parse_query(query) {
+                    Some((command, hostname, authorization)) => {
+                        let (status, message) = control.handle(command, hostname, authorization).await;
+                        format!("{} {}", status, message)
+                    },
+                    None => return,
+                },
+                _ => return,
+            };
+            let _ = writer.write_all(answer.as_bytes()).await;
+        });
+    }
+}
+
+fn bind_socket(socket_path: &Path, gid: u32) -> std::io::Result<UnixListener> {
+    if let Some(parent) = socket_path.parent() {
+        if !parent.exists() {
+            // The front-end has to get through it, whatever `umask` and `group` make of it
+            fs::create_dir_all(parent)?;
+            std::os::unix::fs::chown(parent, None, Some(gid))?;
+            fs::set_permissions(parent, fs::Permissions::from_mode(0o750))?;
+        }
+    }
+
+    // Left behind by the rensend before
+    let _ = fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+    std::os::unix::fs::chown(socket_path, None, Some(gid))?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+/// Asks rensend over `socket_path`, from the front-end
+pub async fn ask(socket_path: &Path, query: &str) -> Result<String, Trap> {
+    let exchange = async {
+        let mut stream = UnixStream::connect(socket_path).await?;
+        stream.write_all(format!("{}\n", query).as_bytes()).await?;
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).await?;
+        Ok::<String, std::io::Error>(answer)
+    };
+
+    match timeout(Duration::from_secs(10), exchange).await {
+        Ok(Ok(answer)) if !answer.is_empty() => Ok(answer),
+        Ok(Ok(_)) => Err(Trap::STD(format!("rensend did not answer `{}` on {:?}", query, socket_path))),
+        Ok(Err(err)) => Err(Trap::STD(format!("Could not ask rensend on {:?}: {}", socket_path, err))),
+        Err(_) => Err(Trap::STD(format!("rensend did not answer on {:?} in time", socket_path))),
+    }
+}
+
+/// Starts the front-end serving /metrics at `bind` as `frontend_user`, with
+/// nothing of rensend but the status socket: no environment, no config and
+/// none of the keys, which that user can not read.
+pub fn spawn_frontend(global_config: &GlobalConfig, user: &str, bind: &str) -> Result<(Child, u32), Trap> {
+    let (uid, gid) = user_ids(user)
+        .ok_or(Trap::Config(format!("Unknown frontend_user `{}`", user)))?;
+    if uid == 0 {
+        return Err(Trap::Config(String::from("frontend_user must not be root")));
+    }
+
+    let exe = std::env::current_exe()
+        .map_err(|err| Trap::STD(format!("Could not find the rensend binary: {}", err)))?;
+    let child = Command::new(exe)
+        .arg("--frontend")
+        .arg(status_socket(global_config))
+        .arg(bind)
+        .env_clear()
+        .current_dir("/")
+        .uid(uid)
+        .gid(gid)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| Trap::STD(format!("Could not start the front-end as `{}`: {}", user, err)))?;
+
+    Ok((child, gid))
+}
+
+#[tokio::test]
+async fn test_status_socket() {
+    let root = std::env::temp_dir().join("rensen_test_frontend");
+    let _ = fs::remove_dir_all(&root);
+    let socket_path = root.join("status.sock");
+    let global_config = Arc::new(GlobalConfig { status_socket: Some(socket_path.clone()), ..Default::default() });
+    assert_eq!(status_socket(&GlobalConfig::default()), PathBuf::from(DEFAULT_STATUS_SOCKET));
+    let (control, _requests) = Control::new(Arc::clone(&global_config));
+
+    let metrics = Arc::new(Metrics::default());
+    metrics.set_queue(3, 1);
+    let gid = fs::metadata(std::env::temp_dir()).map(|metadata| std::os::unix::fs::MetadataExt::gid(&metadata)).unwrap();
+    tokio::spawn(serve_status(Arc::clone(&global_config), socket_path.clone(), gid, Arc::clone(&metrics), control));
+    while !socket_path.exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Only the metrics and progress are handed out
+    assert!(ask(&socket_path, METRICS_QUERY).await.unwrap().contains("rensen_queue_depth 3\n"));
+    assert!(ask(&socket_path, PROGRESS_QUERY).await.unwrap().starts_with("host\tfiles_done"));
+    assert!(ask(&socket_path, "reload").await.is_err());
+
+    // Control queries are answered, if only to say there is no control endpoint
+    let query = crate::control::query(crate::control::Command::Backup, "web01", Some("Bearer abc"));
+    assert_eq!(ask(&socket_path, &query).await.unwrap(), "404 No control endpoint is configured");
+    assert!(ask(&socket_path, "control reload web01").await.is_err());
+    assert_eq!(fs::metadata(&socket_path).unwrap().permissions().mode() & 0o777, 0o660);
+    let _ = fs::remove_dir_all(&root);
+}
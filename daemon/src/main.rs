@@ -1,66 +1,128 @@
 use rensen_lib::config::*;
 use rensen_lib::traits::*;
 use rensen_lib::logging::*;
+use rensen_lib::exit::ExitCode;
 
 pub mod scheduler;
 pub mod utils;
 pub mod tasks;
+pub mod metrics;
+pub mod records;
+pub mod frontend;
+pub mod control;
 
 use crate::scheduler::*;
+use crate::metrics::Source;
 
-use cron::Schedule;
 use std::sync::Arc;
 use std::path::PathBuf;
-use std::str::FromStr;
+use std::process;
 use tokio::sync::Mutex;
 
-/// Gets all cron schedules from host configs and places them into a vector with associated
-/// hostname (WSchedule)
-fn parse_schedules(global_config: &GlobalConfig, settings: &Settings) -> Result<Vec<Arc<WSchedule>>, Trap> {
-    let mut schedules: Vec<Arc<WSchedule>> = Vec::new();
-    for host in settings.hosts.iter() {
-        if host.hostname == "dummy" { continue }; // Skip dummy host
-        if let Some(cron_schedule) = &host.config.cron_schedule {
-            println!("Cron: {}", cron_schedule);
-
-            // Parse cron expression and push to vector which will await its time for exec
-            match Schedule::from_str(cron_schedule) {
-                Ok(schedule) => {
-                    let host_schedule = Arc::new(WSchedule { host: host.clone().into(), schedule });
-                    println!("host_schedule: {:?}", host_schedule);
-                    schedules.push(host_schedule);
-                },
-                Err(err) => {
-                    log_trap(&global_config, &Trap::InvalidInput(format!("Invalid Cron Expression for `{}`: {}", host.hostname, err)));
-                }
-            }
-        } else {
-            // Defaults cron to midnight every day if parsing fails
-            log_trap(&global_config, &Trap::Missing(format!("Missing cron_schedule for `{}`: Defaulting to `0 0 * * *`", &host.hostname)));
-            let host_schedule = Arc::new(WSchedule {
-                host: host.clone().into(),
-                schedule: Schedule::from_str("0 0 0 * *").unwrap(),
-            });
-
-            schedules.push(host_schedule);
-            continue;
-        }
+#[tokio::main]
+async fn main() -> process::ExitCode {
+    if let Some(code) = rensen_lib::sandbox::serve_if_requested() {
+        return code;
     }
 
-    Ok(schedules)
-}
+    // The unprivileged front-end, started by rensend itself for `frontend_user`
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--frontend") {
+        return run_frontend(&args[2..]).await;
+    }
 
-#[tokio::main]
-async fn main() -> Result<(), Trap> {
     let global_config_path = PathBuf::from("/etc/rensen/rensen_config.yml");
-    let global_config: GlobalConfig = GlobalConfig::deserialize_yaml(&global_config_path)
-        .map_err(|err| Trap::FS(format!("Could not deserialize Global Config: {}", err)))?;
+    let global_config: GlobalConfig = match GlobalConfig::deserialize_yaml(&global_config_path) {
+        Ok(global_config) => global_config,
+        Err(err) => {
+            eprintln!("{}", Trap::Config(format!("Could not deserialize Global Config: {}", err)));
+            return ExitCode::Config.into();
+        }
+    };
 
-    let settings = Settings::deserialize_yaml(&global_config.hosts)
-        .map_err(|err| Trap::FS(format!("Could not deserialize Settings @ {:?}: {}", global_config.hosts, err)))?;
+    // Nothing is ever scheduled against a read-only replica
+    if let Err(trap) = global_config.ensure_writable("start rensend") {
+        log_trap(&global_config, &trap);
+        eprintln!("{}", trap);
+        return ExitCode::from(&trap).into();
+    }
+    rensen_lib::sandbox::enable(global_config.sandbox.unwrap_or(false));
+    if let Err(trap) = rensen_lib::ownership::apply(&global_config) {
+        eprintln!("{}", trap);
+        return ExitCode::from(&trap).into();
+    }
 
-    let schedules = parse_schedules(&global_config, &settings)?;
-    let backup_scheduler = Arc::new(Mutex::new(Scheduler::from(Arc::new(global_config.clone()), settings, schedules)));
+    let settings = match Settings::deserialize_yaml(&global_config.hosts) {
+        Ok(settings) => settings,
+        Err(err) => {
+            let trap = Trap::Config(format!("Could not deserialize Settings @ {:?}: {}", global_config.hosts, err));
+            log_trap(&global_config, &trap);
+            return ExitCode::from(&trap).into();
+        }
+    };
+
+    let schedules = match parse_schedules(&global_config, &settings) {
+        Ok(schedules) => schedules,
+        Err(trap) => {
+            log_trap(&global_config, &trap);
+            return ExitCode::from(&trap).into();
+        }
+    };
+    let scheduler = Scheduler::from(Arc::new(global_config.clone()), settings, schedules, global_config_path.clone());
+
+    /* ------- */
+    /* Metrics */
+    /* ------- */
+
+    // Only ever a side line, rensend keeps backing up if it can not listen
+    if let Some(bind) = global_config.metrics_bind.clone() {
+        let metrics_global_config = Arc::new(global_config.clone());
+        let metrics = Arc::clone(&scheduler.metrics);
+        let control = Arc::clone(&scheduler.control);
+        match global_config.frontend_user.as_deref() {
+            None => {
+                tokio::spawn(async move {
+                    if let Err(err) = metrics::serve(Some(Arc::clone(&metrics_global_config)), bind, Source::Local(metrics, control)).await {
+                        log_trap(&metrics_global_config, &err);
+                    }
+                });
+            },
+            // Served by a process of its own that can not read the keys,
+            // rensend only answers it over the status socket
+            Some(user) => match frontend::spawn_frontend(&global_config, user, &bind) {
+                Ok((mut child, gid)) => {
+                    let socket_path = frontend::status_socket(&global_config);
+                    let status_global_config = Arc::clone(&metrics_global_config);
+                    tokio::spawn(async move {
+                        if let Err(err) = frontend::serve_status(Arc::clone(&status_global_config), socket_path, gid, metrics, control).await {
+                            log_trap(&status_global_config, &err);
+                        }
+                    });
+                    tokio::spawn(async move {
+                        let trap = match child.wait().await {
+                            Ok(status) => Trap::STD(format!("The front-end exited: {}", status)),
+                            Err(err) => Trap::STD(format!("Could not wait for the front-end: {}", err)),
+                        };
+                        log_trap(&metrics_global_config, &trap);
+                    });
+                },
+                Err(trap) => log_trap(&global_config, &trap),
+            },
+        }
+    }
+
+    /* ------- */
+    /* Records */
+    /* ------- */
+
+    // Read while the scheduler already runs, a host due before its record
+    // is preloaded reads it itself
+    let records = Arc::clone(&scheduler.records);
+    let records_global_config = Arc::clone(&scheduler.global_config);
+    let hosts = scheduler.settings.hosts.clone();
+    tokio::task::spawn_blocking(move || records.preload(&records_global_config, &hosts));
+
+    let backup_scheduler = Arc::new(Mutex::new(scheduler));
 
     /* --------- */
     /* Scheduler */
@@ -97,18 +159,41 @@ async fn main() -> Result<(), Trap> {
         }
     });
 
-    // Finishing tasks
+    // Both loops run forever, so getting here always means something broke
+    // and systemd should restart us.
     if let Err(err) = tokio::try_join!(scheduler_task, task_executor) {
         eprintln!("Error occurred while running tasks: {:?}", err);
     }
 
-    Ok(())
+    ExitCode::Failure.into()
+}
+
+/// Serves /metrics at the address in `args`, asking rensend over the
+/// status socket in `args` for them
+async fn run_frontend(args: &[String]) -> process::ExitCode {
+    let (socket_path, bind) = match args {
+        [socket_path, bind] => (PathBuf::from(socket_path), bind.clone()),
+        _ => {
+            eprintln!("Usage: rensend --frontend <status socket> <bind>");
+            return ExitCode::Config.into();
+        }
+    };
+
+    if let Err(trap) = metrics::serve(None, bind, Source::Worker(socket_path)).await {
+        eprintln!("{}", trap);
+        return ExitCode::from(&trap).into();
+    }
+
+    ExitCode::Failure.into()
 }
 
 #[cfg(test)]
 #[test]
 fn test_cron() {
+    use std::str::FromStr;
+    use cron::Schedule;
+
     let cron_str = "* 0 0 * * *";
-    let schedule = Schedule::from_str(cron_str).unwrap();
+    let _schedule = Schedule::from_str(cron_str).unwrap();
 }
 
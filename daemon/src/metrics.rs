@@ -0,0 +1,349 @@
+use rensen_lib::config::GlobalConfig;
+use rensen_lib::history::RunOutcome;
+use rensen_lib::logging::*;
+use rensen_lib::progress::Progress;
+use rensen_lib::phases::Phases;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration};
+
+use crate::frontend::{ask, METRICS_QUERY, PROGRESS_QUERY};
+use crate::control::{self, Command, Control};
+
+/// What rensend did for one host since it started
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostMetrics {
+    pub started: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub bytes: u64,
+    pub last_duration: Option<i64>, // seconds the last run took
+    pub last_success: Option<i64>,  // unix seconds, from the history as well
+    pub last_phases: Option<Phases>,  // of the last run
+}
+
+/// Counters and gauges served at /metrics, for alerting on stale backups
+#[derive(Debug, Default)]
+pub struct Metrics {
+    hosts: Mutex<BTreeMap<String, HostMetrics>>,
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    record_hits: AtomicU64,
+    record_misses: AtomicU64,
+    records_cached: AtomicUsize,
+    progress: Mutex<BTreeMap<String, Progress>>, // of the backups running, by host
+}
+
+impl Metrics {
+    /// Takes the last successes of the hosts from `outcomes`, so a restart
+    /// does not make every host look like it was never backed up
+    pub fn seed(&self, outcomes: &[RunOutcome]) {
+        let mut hosts = self.hosts.lock().unwrap();
+        for outcome in outcomes.iter().filter(|outcome| outcome.success) {
+            let host = hosts.entry(outcome.hostname.clone()).or_default();
+            host.last_success = host.last_success.max(Some(outcome.started));
+        }
+    }
+
+    pub fn started(&self, hostname: &str) {
+        self.hosts.lock().unwrap().entry(hostname.to_string()).or_default().started += 1;
+    }
+
+    pub fn finished(&self, outcome: &RunOutcome) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.entry(outcome.hostname.clone()).or_default();
+        match outcome.success {
+            true => {
+                host.succeeded += 1;
+                host.last_success = Some(outcome.started);
+            },
+            false => host.failed += 1,
+        }
+        host.bytes += outcome.bytes;
+        host.last_duration = Some(outcome.duration());
+        host.last_phases = outcome.phases;
+        self.progress.lock().unwrap().remove(&outcome.hostname);
+    }
+
+    pub fn set_progress(&self, hostname: &str, progress: &Progress) {
+        self.progress.lock().unwrap().insert(hostname.to_string(), progress.clone());
+    }
+
+    /// The backups running and how far they got, as tab separated values,
+    /// one per line. Totals not known are `-`.
+    pub fn render_progress(&self) -> String {
+        let unknown = |total: Option<u64>| total.map(|total| total.to_string()).unwrap_or(String::from("-"));
+        let mut out = String::from("host\tfiles_done\tfiles_total\tbytes_done\tbytes_total\tcurrent\n");
+        for (hostname, progress) in self.progress.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}", hostname, progress.files_done, unknown(progress.files_total),
+                progress.bytes_done, unknown(progress.bytes_total), progress.current.display());
+        }
+        out
+    }
+
+    pub fn set_queue(&self, queued: usize, running: usize) {
+        self.queued.store(queued, Ordering::Relaxed);
+        self.running.store(running, Ordering::Relaxed);
+    }
+
+    /// Notes whether a record was found in the record cache
+    pub fn record_lookup(&self, hit: bool) {
+        match hit {
+            true => self.record_hits.fetch_add(1, Ordering::Relaxed),
+            false => self.record_misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn set_records_cached(&self, cached: usize) {
+        self.records_cached.store(cached, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text exposition of everything
+    pub fn render(&self) -> String {
+        let hosts = self.hosts.lock().unwrap();
+        let mut out = String::new();
+
+        let families: [Family; 6] = [
+            ("rensen_backups_started_total", "counter", "Backups started since rensend started", |host| Some(host.started as i64)),
+            ("rensen_backups_succeeded_total", "counter", "Backups that succeeded since rensend started", |host| Some(host.succeeded as i64)),
+            ("rensen_backups_failed_total", "counter", "Backups that failed since rensend started", |host| Some(host.failed as i64)),
+            ("rensen_bytes_transferred_total", "counter", "Bytes transferred by backups since rensend started", |host| Some(host.bytes as i64)),
+            ("rensen_backup_duration_seconds", "gauge", "How long the last backup took", |host| host.last_duration),
+            ("rensen_last_success_timestamp_seconds", "gauge", "When the last successful backup started", |host| host.last_success),
+        ];
+        for (name, kind, help, value) in families {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (hostname, host) in hosts.iter() {
+                if let Some(value) = value(host) {
+                    let _ = writeln!(out, "{}{{host=\"{}\"}} {}", name, escape(hostname), value);
+                }
+            }
+        }
+
+        let _ = writeln!(out, "# HELP rensen_backup_phase_seconds How long each phase of the last backup took\n# TYPE rensen_backup_phase_seconds gauge");
+        for (hostname, host) in hosts.iter() {
+            for (phase, ms) in host.last_phases.iter().flat_map(|phases| phases.named()) {
+                let _ = writeln!(out, "rensen_backup_phase_seconds{{host=\"{}\",phase=\"{}\"}} {:.3}", escape(hostname), phase, ms as f64 / 1000.0);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP rensen_queue_depth Backups due and waiting for a slot\n# TYPE rensen_queue_depth gauge");
+        let _ = writeln!(out, "rensen_queue_depth {}", self.queued.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP rensen_backups_running Backups running right now\n# TYPE rensen_backups_running gauge");
+        let _ = writeln!(out, "rensen_backups_running {}", self.running.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP rensen_record_cache_hits_total Records taken from the cache instead of being read\n# TYPE rensen_record_cache_hits_total counter");
+        let _ = writeln!(out, "rensen_record_cache_hits_total {}", self.record_hits.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP rensen_record_cache_misses_total Records read from disk because they were not cached or had changed\n# TYPE rensen_record_cache_misses_total counter");
+        let _ = writeln!(out, "rensen_record_cache_misses_total {}", self.record_misses.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP rensen_records_cached Records held parsed in the cache\n# TYPE rensen_records_cached gauge");
+        let _ = writeln!(out, "rensen_records_cached {}", self.records_cached.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Name, type, help and value per host of a metric
+type Family = (&'static str, &'static str, &'static str, fn(&HostMetrics) -> Option<i64>);
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// What a request is for
+#[derive(Debug, PartialEq)]
+enum Route {
+    Query(&'static str),      // of the status socket, GET /metrics or /progress
+    Control(Command, String), // POST /backup/<host> or /cancel/<host>
+}
+
+/// What the request starting with `request_line` is for, and if nothing
+/// what to answer instead
+fn route(request_line: &str) -> Result<Route, (u16, String)> {
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => Ok(Route::Query(METRICS_QUERY)),
+        (Some("GET"), Some("/progress")) => Ok(Route::Query(PROGRESS_QUERY)),
+        (Some("GET"), Some(_)) => Err((404, String::from("Not found, try /metrics\n"))),
+        (Some("POST"), Some(path)) => match path.strip_prefix('/').and_then(|path| path.split_once('/')) {
+            Some((command, hostname)) if control::is_hostname(hostname) => match Command::from_name(command) {
+                Some(command) => Ok(Route::Control(command, hostname.to_string())),
+                None => Err((404, String::from("Not found, try /backup/<host> or /cancel/<host>\n"))),
+            },
+            _ => Err((404, String::from("Not found, try /backup/<host> or /cancel/<host>\n"))),
+        },
+        _ => Err((405, String::new())),
+    }
+}
+
+/// The value of header `name` of `request`
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Response to `request`
+async fn respond(request: &str, metrics: &Metrics, control: &Control) -> String {
+    match route(request.lines().next().unwrap_or_default()) {
+        Ok(Route::Query(METRICS_QUERY)) => http(200, &metrics.render()),
+        Ok(Route::Query(_)) => http(200, &metrics.render_progress()),
+        Ok(Route::Control(command, hostname)) => {
+            let (status, message) = control.handle(command, &hostname, header(request, "Authorization")).await;
+            http(status, &format!("{}\n", message))
+        },
+        Err((status, body)) => http(status, &body),
+    }
+}
+
+fn http(status: u16, body: &str) -> String {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    // Both Basic credentials for PAM and bearer tokens are taken
+    let challenge = match status {
+        401 => "WWW-Authenticate: Basic realm=\"rensen\"\r\nWWW-Authenticate: Bearer realm=\"rensen\"\r\n",
+        _ => "",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, challenge, body.len(), body
+    )
+}
+
+/// Where the metrics served come from, and where control requests go
+#[derive(Debug, Clone)]
+pub enum Source {
+    Local(Arc<Metrics>, Arc<Control>), // rensend serves them itself
+    Worker(PathBuf),                   // the front-end asks rensend over its status socket
+}
+
+impl Source {
+    async fn response(&self, request: &str) -> String {
+        let socket_path = match self {
+            Source::Local(metrics, control) => return respond(request, metrics, control).await,
+            Source::Worker(socket_path) => socket_path,
+        };
+
+        // The credentials are passed on, it is rensend that checks them
+        let (query, control) = match route(request.lines().next().unwrap_or_default()) {
+            Ok(Route::Query(query)) => (query.to_string(), false),
+            Ok(Route::Control(command, hostname)) => (control::query(command, &hostname, header(request, "Authorization")), true),
+            Err((status, body)) => return http(status, &body),
+        };
+        match (ask(socket_path, &query).await, control) {
+            (Ok(rendered), false) => http(200, &rendered),
+            (Ok(answer), true) => match answer.split_once(' ').and_then(|(status, message)| Some((status.parse::<u16>().ok()?, message))) {
+                Some((status, message)) => http(status, &format!("{}\n", message)),
+                None => http(502, "rensend answered something else\n"),
+            },
+            (Err(err), _) => http(503, &format!("{}\n", err)),
+        }
+    }
+}
+
+/// Serves the metrics of `source` over HTTP at `metrics_bind` until rensend
+/// stops. The front-end has no config to log with, it logs to stderr.
+pub async fn serve(global_config: Option<Arc<GlobalConfig>>, bind: String, source: Source) -> Result<(), Trap> {
+    let listener = TcpListener::bind(&bind).await
+        .map_err(|err| Trap::Config(format!("Could not listen on `{}` for metrics: {}", bind, err)))?;
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                let trap = Trap::STD(format!("Could not accept a metrics connection: {}", err));
+                match &global_config {
+                    Some(global_config) => log_trap(global_config, &trap),
+                    None => eprintln!("{}", trap),
+                }
+                continue;
+            }
+        };
+
+        let source = source.clone();
+        tokio::spawn(async move {
+            // A scraper that never sends its request does not hold on to a task.
+            // The request is read in one go, with room for a bearer token.
+            let mut request = vec![0u8; 16 * 1024];
+            let read = match timeout(Duration::from_secs(5), stream.read(&mut request)).await {
+                Ok(Ok(read)) => read,
+                _ => return,
+            };
+
+            let request = String::from_utf8_lossy(&request[..read]);
+            let response = source.response(&request).await;
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let metrics = Metrics::default();
+    metrics.seed(&[
+        RunOutcome { hostname: String::from("web01"), started: 100, success: true, ..Default::default() },
+        RunOutcome { hostname: String::from("db01"), started: 50, success: false, ..Default::default() },
+    ]);
+
+    metrics.started("web01");
+    let phases = Phases { connect_ms: 1500, transfer_ms: 50000, ..Default::default() };
+    metrics.finished(&RunOutcome { hostname: String::from("web01"), started: 200, finished: 260, success: false, bytes: 1024, phases: Some(phases), ..Default::default() });
+    metrics.set_queue(2, 1);
+    metrics.record_lookup(false);
+    metrics.record_lookup(true);
+    metrics.set_records_cached(1);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("# TYPE rensen_backups_started_total counter\nrensen_backups_started_total{host=\"web01\"} 1\n"));
+    assert!(rendered.contains("rensen_backups_failed_total{host=\"web01\"} 1\n"));
+    assert!(rendered.contains("rensen_bytes_transferred_total{host=\"web01\"} 1024\n"));
+    assert!(rendered.contains("rensen_backup_duration_seconds{host=\"web01\"} 60\n"));
+    assert!(rendered.contains("rensen_last_success_timestamp_seconds{host=\"web01\"} 100\n"));
+    assert!(rendered.contains("rensen_backup_phase_seconds{host=\"web01\",phase=\"connect\"} 1.500\n"));
+    assert!(rendered.contains("rensen_backup_phase_seconds{host=\"web01\",phase=\"transfer\"} 50.000\n"));
+    assert!(!rendered.contains("db01"));
+    assert!(rendered.contains("rensen_queue_depth 2\n") && rendered.contains("rensen_backups_running 1\n"));
+    assert!(rendered.contains("rensen_record_cache_hits_total 1\n") && rendered.ends_with("rensen_records_cached 1\n"));
+
+    // Only the backups still running
+    let progress = Progress { files_done: 3, files_total: Some(10), bytes_done: 512, current: PathBuf::from("/srv/a"), ..Default::default() };
+    metrics.set_progress("db01", &progress);
+    metrics.set_progress("web01", &progress);
+    metrics.finished(&RunOutcome { hostname: String::from("web01"), ..Default::default() });
+    assert_eq!(metrics.render_progress(), "host\tfiles_done\tfiles_total\tbytes_done\tbytes_total\tcurrent\ndb01\t3\t10\t512\t-\t/srv/a\n");
+
+    let (control, _requests) = Control::new(Arc::new(GlobalConfig::default()));
+    assert!(respond("GET /metrics HTTP/1.1", &metrics, &control).await.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(respond("GET /progress HTTP/1.1", &metrics, &control).await.ends_with("db01\t3\t10\t512\t-\t/srv/a\n"));
+    assert!(respond("GET / HTTP/1.1", &metrics, &control).await.starts_with("HTTP/1.1 404"));
+    assert!(respond("POST /backup/web01 HTTP/1.1\r\nAuthorization: Bearer abc\r\n\r\n", &metrics, &control).await.ends_with("No control endpoint is configured\n"));
+    assert_eq!(escape("a\"b"), "a\\\"b");
+
+    // Backups are started and cancelled by POST, along with the credentials
+    assert_eq!(route("POST /cancel/db01 HTTP/1.1"), Ok(Route::Control(Command::Cancel, String::from("db01"))));
+    assert!(matches!(route("POST /cancel/db01:etc HTTP/1.1"), Err((404, _))));
+    assert!(matches!(route("POST /reload/web01 HTTP/1.1"), Err((404, _))));
+    assert!(matches!(route("POST /backup/../etc HTTP/1.1"), Err((404, _))));
+    assert!(matches!(route("DELETE /backup/web01 HTTP/1.1"), Err((405, _))));
+    let request = "POST /backup/web01 HTTP/1.1\r\nHost: backup\r\nauthorization:  Basic YWxpY2U6czNjcmV0\r\n\r\nAuthorization: body";
+    assert_eq!(header(request, "Authorization"), Some("Basic YWxpY2U6czNjcmV0"));
+    assert_eq!(header("GET /metrics HTTP/1.1\r\n\r\n", "Authorization"), None);
+    assert!(http(401, "").contains("\r\nWWW-Authenticate: Bearer realm=\"rensen\"\r\n"));
+}
@@ -0,0 +1,187 @@
+use rensen_lib::config::{GlobalConfig, Host, HostConfig};
+use rensen_lib::logging::*;
+use rensen_lib::record::Record;
+use rensen_lib::traits::JsonFile;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::metrics::Metrics;
+
+/// Records kept by default, large fleets set `record_cache` higher
+pub const DEFAULT_RECORD_CACHE: usize = 64;
+
+/// Size and mtime of a record file, a record is only taken from the cache
+/// while its file still has the ones it was read with
+type Stamp = (u64, Option<SystemTime>);
+
+fn stamp(path: &Path) -> io::Result<Stamp> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+#[derive(Debug)]
+struct Cached {
+    stamp: Stamp,
+    record: Record,
+    used: u64, // lookup it was last handed out at
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<PathBuf, Cached>,
+    lookups: u64,
+}
+
+/// Records of the hosts, kept parsed between runs so a run does not start
+/// by reading its whole record.json again. Anything that rewrites a record
+/// behind rensend's back, rensen-ctl or a restore, changes its stamp and
+/// has it read again. Past `capacity` the least recently used are dropped.
+#[derive(Debug)]
+pub struct RecordCache {
+    capacity: usize,
+    state: Mutex<State>,
+    metrics: Arc<Metrics>,
+}
+
+impl RecordCache {
+    pub fn new(global_config: &GlobalConfig, metrics: Arc<Metrics>) -> Self {
+        RecordCache {
+            capacity: global_config.record_cache.unwrap_or(DEFAULT_RECORD_CACHE),
+            state: Mutex::new(State::default()),
+            metrics,
+        }
+    }
+
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("record.json")
+    }
+
+    /// The record at `path`, from the cache if it has not changed since
+    pub fn get(&self, path: &Path) -> io::Result<Record> {
+        let stamp = stamp(path)?;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.lookups += 1;
+            let lookups = state.lookups;
+            if let Some(cached) = state.entries.get_mut(path).filter(|cached| cached.stamp == stamp) {
+                cached.used = lookups;
+                self.metrics.record_lookup(true);
+                return Ok(cached.record.clone());
+            }
+        }
+
+        // Parsed without holding the lock, other hosts' runs carry on meanwhile
+        let record = Record::deserialize_json(path)?;
+        self.metrics.record_lookup(false);
+        self.insert(path, stamp, record.clone());
+        Ok(record)
+    }
+
+    /// Keeps `record` as what a run just wrote to `path`
+    pub fn put(&self, path: &Path, record: Record) {
+        if let Ok(stamp) = stamp(path) {
+            self.insert(path, stamp, record);
+        }
+    }
+
+    /// Reads the records of `hosts` ahead of their runs, as many as fit.
+    /// Hosts never backed up have none yet, and are left out.
+    pub fn preload(&self, global_config: &GlobalConfig, hosts: &[Host]) {
+        let paths = hosts.iter()
+            .flat_map(|host| host.config.namespaces().unwrap_or_default())
+            .map(|host_config| RecordCache::path(global_config, &host_config))
+            .take(self.capacity);
+
+        for path in paths {
+            let stamp = match stamp(&path) {
+                Ok(stamp) => stamp,
+                Err(_) => continue,
+            };
+            if self.state.lock().unwrap().entries.get(&path).is_some_and(|cached| cached.stamp == stamp) {
+                continue;
+            }
+
+            match Record::deserialize_json(&path) {
+                Ok(record) => self.insert(&path, stamp, record),
+                Err(err) => log_trap(global_config, &Trap::Deserialize(format!("Could not preload record {:?}: {}", path, err))),
+            }
+        }
+    }
+
+    fn insert(&self, path: &Path, stamp: Stamp, record: Record) {
+        let mut state = self.state.lock().unwrap();
+        state.lookups += 1;
+        let used = state.lookups;
+        state.entries.insert(path.to_path_buf(), Cached { stamp, record, used });
+
+        while state.entries.len() > self.capacity {
+            let oldest = state.entries.iter()
+                .min_by_key(|(_, cached)| cached.used)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(oldest) => state.entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        self.metrics.set_records_cached(state.entries.len());
+    }
+}
+
+#[test]
+fn test_record_cache() {
+    use rensen_lib::snapshot::FileEntry;
+
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_record_cache"), record_cache: Some(2), ..Default::default() };
+    let _ = fs::remove_dir_all(&global_config.backups);
+    let hosts: Vec<Host> = ["a", "b", "c"].iter().map(|name| Host {
+        hostname: name.to_string(),
+        config: HostConfig { identifier: name.to_string(), ..Default::default() },
+    }).collect();
+    for host in hosts.iter() {
+        let path = RecordCache::path(&global_config, &host.config);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        Record::new().serialize_json(&path).unwrap();
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let cache = RecordCache::new(&global_config, Arc::clone(&metrics));
+    let path = |host: &Host| RecordCache::path(&global_config, &host.config);
+
+    // Only as many as fit are preloaded, and found without a read
+    cache.preload(&global_config, &hosts);
+    cache.get(&path(&hosts[0])).unwrap();
+    cache.get(&path(&hosts[1])).unwrap();
+    assert!(metrics.render().contains("rensen_record_cache_hits_total 2\n"));
+    assert!(metrics.render().contains("rensen_record_cache_misses_total 0\n"));
+
+    // The least recently used makes room
+    cache.get(&path(&hosts[2])).unwrap();
+    cache.get(&path(&hosts[1])).unwrap();
+    assert!(metrics.render().contains("rensen_record_cache_hits_total 3\n"));
+    cache.get(&path(&hosts[0])).unwrap();
+    assert!(metrics.render().contains("rensen_record_cache_misses_total 2\n"));
+    assert!(metrics.render().contains("rensen_records_cached 2\n"));
+
+    // A record written elsewhere is read again
+    let mut record = Record::new();
+    record.snapshot.entries.insert(PathBuf::from("/etc/hosts"), FileEntry::from(PathBuf::from("/etc/hosts"), PathBuf::new(), 1, 12));
+    record.serialize_json(&path(&hosts[1])).unwrap();
+    assert_eq!(cache.get(&path(&hosts[1])).unwrap().snapshot.entries.len(), 1);
+    assert!(metrics.render().contains("rensen_record_cache_misses_total 3\n"));
+
+    // What a run wrote is kept as it is
+    record.size = 12;
+    record.serialize_json(&path(&hosts[1])).unwrap();
+    cache.put(&path(&hosts[1]), record);
+    assert_eq!(cache.get(&path(&hosts[1])).unwrap().size, 12);
+    assert!(metrics.render().contains("rensen_record_cache_hits_total 4\n"));
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
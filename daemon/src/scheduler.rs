@@ -1,32 +1,237 @@
 use rensen_lib::config::*;
 use rensen_lib::logging::*;
+use rensen_lib::notify::alert;
+use rensen_lib::quota::check_quota;
+use rensen_lib::units::Units;
+use rensen_lib::history::History;
+use rensen_lib::breaker::Breaker;
+use rensen_lib::retire::Retirement;
+use rensen_lib::schedule::{host_schedule, verify_schedule, HostSchedule, DEFAULT_CRON};
+use rensen_lib::drift::ConfigFingerprint;
+use rensen_lib::traits::YamlFile;
+use rensen_lib::queue::FairQueue;
+use rensen_lib::audit::admit_control_run;
 
-use chrono::{Local, Timelike, SecondsFormat};
-use cron::Schedule;
+use chrono::{Local, Timelike};
 use tokio::time::{interval, Duration};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 
 use crate::utils::*;
 use crate::tasks::*;
+use crate::metrics::Metrics;
+use crate::records::RecordCache;
+use crate::control::{cancel_reason, Command, Control, Request};
 
 // Struct for holding the host data with it's associate schedul
-// Wrapper for cron::Schedule
+// Wrapper for HostSchedule
 #[derive(Debug)]
 pub struct WSchedule {
     pub host: Arc<Host>, 
-    pub schedule: Schedule,
+    pub schedule: HostSchedule,
+    pub kind: TaskKind,
+}
+
+/// Expected run time of a host's backups in seconds, from its latest runs
+fn expected_duration(global_config: &GlobalConfig, hostname: &str) -> u64 {
+    let runs = History::new(global_config).for_host(hostname, 10).unwrap_or_default();
+    rensen_lib::queue::expected_duration(&runs, hostname)
+}
+
+/// What a schedule triggers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskKind {
+    Backup,
+    Verify,
+}
+
+/// Gets all schedules from host configs and places them into a vector with associated
+/// hostname (WSchedule)
+pub fn parse_schedules(global_config: &GlobalConfig, settings: &Settings) -> Result<Vec<Arc<WSchedule>>, Trap> {
+    let mut schedules: Vec<Arc<WSchedule>> = Vec::new();
+    for host in settings.hosts.iter() {
+        if host.hostname == "dummy" { continue }; // Skip dummy host
+        let config = &host.config;
+        if config.cron_schedule.is_none() && config.interval.is_none() && config.run_at.is_none() {
+            log_host_trap(global_config, &host.hostname, &Trap::Missing(format!("Missing cron_schedule for `{}`: Defaulting to `{}`", &host.hostname, DEFAULT_CRON)));
+        }
+
+        // Parse the schedule and push to vector which will await its time for exec
+        match host_schedule(host) {
+            Ok(schedule) => {
+                let wschedule = Arc::new(WSchedule { host: host.clone().into(), schedule, kind: TaskKind::Backup });
+                println!("host_schedule: {:?}", wschedule);
+                schedules.push(wschedule);
+            },
+            Err(err) => log_trap(global_config, &err),
+        }
+
+        // Scrubbing on its own schedule, so it can be staggered across hosts
+        match verify_schedule(host) {
+            Ok(Some(schedule)) => schedules.push(Arc::new(WSchedule { host: host.clone().into(), schedule: HostSchedule::Cron(Box::new(schedule)), kind: TaskKind::Verify })),
+            Ok(None) => (),
+            Err(err) => log_trap(global_config, &err),
+        }
+    }
+
+    Ok(schedules)
+}
+
+/// What a reload changes about `old`, one line per schedule
+pub fn diff_schedules(old: &[Arc<WSchedule>], new: &[Arc<WSchedule>]) -> Vec<String> {
+    let find = |schedules: &[Arc<WSchedule>], schedule: &WSchedule| schedules.iter()
+        .find(|other| other.host.hostname == schedule.host.hostname && other.kind == schedule.kind)
+        .cloned();
+
+    let mut changes = Vec::new();
+    for schedule in new {
+        match find(old, schedule) {
+            None => changes.push(format!("added {:?} of `{}` at `{}`", schedule.kind, schedule.host.hostname, schedule.schedule)),
+            Some(previous) if previous.schedule.to_string() != schedule.schedule.to_string()
+                || ConfigFingerprint::of(&previous.host.config).hash != ConfigFingerprint::of(&schedule.host.config).hash => {
+                changes.push(format!("updated {:?} of `{}` at `{}`", schedule.kind, schedule.host.hostname, schedule.schedule));
+            },
+            Some(_) => (),
+        }
+    }
+    for schedule in old.iter().filter(|schedule| find(new, schedule).is_none()) {
+        changes.push(format!("removed {:?} of `{}`", schedule.kind, schedule.host.hostname));
+    }
+
+    changes
+}
+
+/// The schedule of the `maintenance` window, None without one or if it does
+/// not parse, which is logged
+fn maintenance_schedule(global_config: &GlobalConfig) -> Option<HostSchedule> {
+    let maintenance = global_config.maintenance.as_ref()?;
+    maintenance.parse_schedule()
+        .map(|schedule| HostSchedule::Cron(Box::new(schedule)))
+        .map_err(|trap| log_trap(global_config, &trap))
+        .ok()
+}
+
+/// How long `backup_task` waits before its next attempt
+fn retry_delay(backup_task: &BackupTask) -> Duration {
+    let spread = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.subsec_nanos() as f64 / 1e9).unwrap_or(0.5);
+    backup_task.host.config.retry.as_ref()
+        .map(|retry| retry.delay(backup_task.attempt, spread))
+        .unwrap_or_default()
+}
+
+/// How long a host stays deferred for lack of space before it is alerted
+/// on again
+const DEFER_ALERT_INTERVAL: i64 = 60 * 60;
+
+/// The backup schedules of the `deferred` hosts, which are tried again on
+/// every tick until there is room for them. Hosts no longer configured
+/// drop out.
+fn deferred_schedules<'a>(schedules: &'a [Arc<WSchedule>], deferred: &mut HashMap<String, i64>) -> Vec<&'a Arc<WSchedule>> {
+    let backups: Vec<&Arc<WSchedule>> = schedules.iter()
+        .filter(|schedule| schedule.kind == TaskKind::Backup && deferred.contains_key(&schedule.host.hostname))
+        .collect();
+    deferred.retain(|hostname, _| backups.iter().any(|schedule| schedule.host.hostname == *hostname));
+    backups
+}
+
+/// The backup schedule of host `hostname`, which backs up all its sources
+fn backup_schedule<'a>(schedules: &'a [Arc<WSchedule>], hostname: &str) -> Option<&'a Arc<WSchedule>> {
+    schedules.iter().find(|schedule| schedule.kind == TaskKind::Backup && schedule.host.hostname == hostname)
+}
+
+/// When the global config at `path` and the hosts.yml it points to were last
+/// modified, to notice edits without a SIGHUP
+fn modified(path: &Path, global_config: &GlobalConfig) -> Vec<Option<SystemTime>> {
+    [path, global_config.hosts.as_path()].iter()
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
 }
 
 pub struct Scheduler {
     pub global_config: Arc<GlobalConfig>, 
     pub settings: Settings,
     pub schedules: Vec<Arc<WSchedule>>,
-    queue: Arc<Mutex<TaskQueue<BackupTask>>>
+    pub config_path: PathBuf, // reloaded from on SIGHUP or when it or hosts.yml change
+    pub metrics: Arc<Metrics>,
+    pub records: Arc<RecordCache>,
+    pub control: Arc<Control>,
+    requests: Option<mpsc::UnboundedReceiver<Request>>, // of the control endpoint, taken by run_scheduler
+    maintenance: Option<HostSchedule>,
+    maintaining: Arc<AtomicBool>, // while a maintenance window runs
+    queue: Arc<Mutex<TaskQueue<BackupTask>>>,
+    modified: Vec<Option<SystemTime>>,
 }
 
 impl Scheduler {
-    pub fn from(global_config: Arc<GlobalConfig>, settings: Settings, schedules: Vec<Arc<WSchedule>>) -> Self {
-        Scheduler { global_config, settings, schedules, queue: Arc::new(Mutex::new(TaskQueue::new())) }
+    pub fn from(global_config: Arc<GlobalConfig>, settings: Settings, schedules: Vec<Arc<WSchedule>>, config_path: PathBuf) -> Self {
+        let modified = modified(&config_path, &global_config);
+        let metrics = Arc::new(Metrics::default());
+        if let Ok(outcomes) = History::new(&global_config).load() {
+            metrics.seed(&outcomes);
+        }
+        let records = Arc::new(RecordCache::new(&global_config, Arc::clone(&metrics)));
+        let maintenance = maintenance_schedule(&global_config);
+        let (control, requests) = Control::new(Arc::clone(&global_config));
+
+        Scheduler {
+            global_config, settings, schedules, config_path, metrics, records, control, maintenance,
+            requests: Some(requests),
+            maintaining: Arc::new(AtomicBool::new(false)),
+            queue: Arc::new(Mutex::new(TaskQueue::new())),
+            modified,
+        }
+    }
+
+    /// Re-reads the global config and hosts.yml and swaps in their
+    /// schedules. Backups already queued or running carry on with the
+    /// config they started with. A config that does not parse is logged and
+    /// the running one kept.
+    fn reload(&mut self) {
+        self.modified = modified(&self.config_path, &self.global_config);
+        let global_config = match GlobalConfig::deserialize_yaml(&self.config_path) {
+            Ok(global_config) => global_config,
+            Err(err) => {
+                log_trap(&self.global_config, &Trap::Config(format!("Could not reload {:?}, keeping the running config: {}", self.config_path, err)));
+                return;
+            }
+        };
+        if let Err(trap) = global_config.ensure_writable("keep scheduling backups") {
+            log_trap(&self.global_config, &trap);
+            return;
+        }
+
+        let settings = match Settings::deserialize_yaml(&global_config.hosts) {
+            Ok(settings) => settings,
+            Err(err) => {
+                log_trap(&self.global_config, &Trap::Config(format!("Could not reload {:?}, keeping the running config: {}", global_config.hosts, err)));
+                return;
+            }
+        };
+        let schedules = match parse_schedules(&global_config, &settings) {
+            Ok(schedules) => schedules,
+            Err(trap) => {
+                log_trap(&self.global_config, &trap);
+                return;
+            }
+        };
+
+        let changes = diff_schedules(&self.schedules, &schedules);
+        println!("Reloaded {:?}, {} schedules changed", self.config_path, changes.len());
+        for change in changes {
+            println!("  {}", change);
+        }
+
+        self.modified = modified(&self.config_path, &global_config);
+        self.maintenance = maintenance_schedule(&global_config);
+        self.global_config = Arc::new(global_config);
+        self.settings = settings;
+        self.schedules = schedules;
     }
 
     pub async fn run_executor(&mut self) -> Result<(), Trap> {
@@ -36,14 +241,12 @@ impl Scheduler {
         loop {
             interval.tick().await;
 
-            if let Some(task) = self.queue.lock().unwrap().peek() {
-                let _ = task.run();
-
-                self.queue.lock().unwrap().popf();
+            if let Some(task) = self.get_next_task() {
+                if let Err(err) = task.run() {
+                    log_host_trap(&task.global_config, &task.host.hostname, &err);
+                }
             }
         }
-
-        Ok(())
     }
 
     // Locks mutex and returns first available task if Some()
@@ -53,19 +256,21 @@ impl Scheduler {
     }
 
     /// Checking according to the hosts's schedule if it is time to backup at this moment.
-    fn should_run(&self, now: &chrono::DateTime<Local>, host_schedule: &WSchedule) -> bool {
+    fn should_run(&self, now: &chrono::DateTime<Local>, schedule: &HostSchedule) -> bool {
         let current_time = now
         .with_second(0).unwrap()
         .with_nanosecond(0).unwrap();
 
-        let mut upcoming_times = host_schedule.schedule.upcoming(Local).take(1);
+        // From the start of the minute, so an interval or run_at on it is not already behind
+        let mut upcoming_times = schedule.after(&(current_time - chrono::Duration::seconds(1))).take(1);
 
         if let Some(scheduled_time) = upcoming_times.next() {
+            let units = Units::new(&self.global_config, false).unwrap_or_default();
             println!(
                 "Current time: {} (h: {}, m: {}, s: {}), Scheduled time: {} (h: {}, m: {}, s: {})",
-                current_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                units.datetime(&current_time),
                 current_time.hour(), current_time.minute(), current_time.second(),
-                scheduled_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                units.datetime(&scheduled_time),
                 scheduled_time.hour(), scheduled_time.minute(), scheduled_time.second()
             );
 
@@ -80,32 +285,297 @@ impl Scheduler {
     /// Will wait 60 seconds between each check
     pub async fn run_scheduler(&mut self) -> Result<(), Trap> {
         let mut interval = interval(Duration::from_secs(60));
+        let mut hangup = signal(SignalKind::hangup())
+            .map_err(|err| Trap::Scheduler(format!("Could not listen for SIGHUP: {}", err)))?;
+        let mut requests = self.requests.take()
+            .ok_or(Trap::Scheduler(String::from("The scheduler is running already")))?;
+        let mut queue: FairQueue<BackupTask> = FairQueue::new();
+        let mut running: HashSet<String> = HashSet::new();
+        let mut retrying: HashSet<String> = HashSet::new();
+        let mut withdrawn: HashSet<String> = HashSet::new(); // cancelled while waiting to be retried
+        let mut deferred: HashMap<String, i64> = HashMap::new(); // hostname, when it was last alerted on
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel::<(String, bool)>();
+        let (retry_tx, mut retry_rx) = mpsc::unbounded_channel::<BackupTask>();
 
-        
         loop {
+            let mut requested: Option<Request> = None;
+
+            // Checking every interval if it's time, and freeing the slots of
+            // finished backups as they come in
+            let ticked = tokio::select! {
+                _ = interval.tick() => true,
+                Some((hostname, retry)) = done_rx.recv() => {
+                    running.remove(&hostname);
+                    if retry {
+                        retrying.insert(hostname);
+                    }
+                    false
+                }
+                // Back in the queue once its backoff is over, like one that is due
+                Some(backup_task) = retry_rx.recv() => {
+                    let hostname = backup_task.host.hostname.clone();
+                    retrying.remove(&hostname);
+                    if !withdrawn.remove(&hostname) {
+                        let cost = expected_duration(&self.global_config, &hostname);
+                        let critical = backup_task.host.config.is_critical();
+                        queue.push(&hostname, cost, critical, backup_task);
+                    }
+                    false
+                }
+                Some(request) = requests.recv() => {
+                    requested = Some(request);
+                    false
+                }
+                Some(_) = hangup.recv() => {
+                    self.reload();
+                    false
+                }
+            };
+
+            // Edits are picked up on the next tick without a SIGHUP as well
+            if ticked && modified(&self.config_path, &self.global_config) != self.modified {
+                self.reload();
+            }
+            let slots = self.global_config.max_concurrent_backups.unwrap_or(usize::MAX).max(1);
 
-            // Checking every interval if it's time
-            interval.tick().await;
             let now = Local::now();
 
-            for schedule in self.schedules.iter() {
-                if self.should_run(&now, &schedule) {
+            // A window still running when the next opens carries on alone
+            let window = self.maintenance.as_ref().filter(|schedule| ticked && self.should_run(&now, schedule));
+            if window.is_some() && !self.maintaining.swap(true, Ordering::SeqCst) {
+                let maintenance_task = MaintenanceTask { global_config: Arc::clone(&self.global_config), hosts: self.settings.hosts.clone() };
+                let maintaining = Arc::clone(&self.maintaining);
+                tokio::task::spawn_blocking(move || {
+                    if let Err(err) = maintenance_task.run() {
+                        log_trap(&maintenance_task.global_config, &err);
+                    }
+                    maintaining.store(false, Ordering::SeqCst);
+                });
+            }
 
-                    let global_config_clone = Arc::clone(&self.global_config);
-                    let host = Arc::clone(&schedule.host); 
-                    let backup_task = BackupTask { global_config: global_config_clone, host };
+            let mut due: Vec<&Arc<WSchedule>> = match ticked {
+                true => self.schedules.iter()
+                    .filter(|schedule| self.should_run(&now, &schedule.schedule))
+                    .collect(),
+                false => Vec::new(),
+            };
+            if ticked {
+                for schedule in deferred_schedules(&self.schedules, &mut deferred) {
+                    if !due.iter().any(|due| Arc::ptr_eq(due, schedule)) {
+                        due.push(schedule);
+                    }
+                }
+            }
 
-                    // self.queue.lock().unwrap().pushb(backup_task);
+            // Over the control endpoint a backup is started like one that is
+            // due, and a queued or running one is cancelled
+            if let Some(request) = requested {
+                let hostname = &request.hostname;
+                let busy = running.contains(hostname) || queue.contains(hostname) || retrying.contains(hostname);
+                let answer = match request.command {
+                    Command::Backup => match backup_schedule(&self.schedules, hostname) {
+                        None => (404, format!("No host `{}`", hostname)),
+                        Some(_) if busy => (409, format!("`{}` is queued, running or waiting to be retried already", hostname)),
+                        Some(schedule) => match admit_control_run(&self.global_config, hostname, &request.user, "over the control endpoint", now.timestamp()) {
+                            Ok(()) => {
+                                log_event(&self.global_config, Level::Info, Some(hostname), None, &format!("Backup requested by `{}`", request.user));
+                                if !due.iter().any(|due| Arc::ptr_eq(due, schedule)) {
+                                    due.push(schedule);
+                                }
+                                (202, format!("Backup of `{}` requested", hostname))
+                            },
+                            Err(trap @ Trap::RateLimit(_)) => (429, trap.to_string()),
+                            Err(trap) => (500, trap.to_string()),
+                        },
+                    },
+                    Command::Cancel => {
+                        let waiting = queue.remove(hostname).is_some() || (retrying.remove(hostname) && withdrawn.insert(hostname.clone()));
+                        match waiting || self.control.cancel(hostname, &cancel_reason(hostname, &request.user)) {
+                            true => {
+                                self.control.audit(Command::Cancel, hostname, &request.user, now.timestamp());
+                                log_event(&self.global_config, Level::Warn, Some(hostname), None, &format!("Backup cancelled by `{}`", request.user));
+                                (202, format!("Backup of `{}` cancelled", hostname))
+                            },
+                            false => (409, format!("No backup of `{}` is queued or running", hostname)),
+                        }
+                    },
+                };
+                let _ = request.reply.send(answer);
+            }
 
-                    // Spawning new thread as it's time for backupping
-                    tokio::spawn(async move {
-                        if let Err(err) = backup_task.run().await {
-                            log_trap(&backup_task.global_config, &err); 
+            for schedule in due {
+                if schedule.kind == TaskKind::Verify {
+                    let hostname = &schedule.host.hostname;
+                    match Retirement::load(&self.global_config, &schedule.host.config) {
+                        Ok(Some(_)) => continue,
+                        Ok(None) => (),
+                        Err(err) => log_trap(&self.global_config, &err),
+                    }
+                    if running.contains(hostname) || queue.contains(hostname) {
+                        log_host_trap(&self.global_config, hostname, &Trap::Scheduler(format!("`{}` is due for verification while a backup of it is queued or running, skipping", hostname)));
+                        continue;
+                    }
+
+                    // Off the async workers like backups, it reads whole archives
+                    let verify_task = VerifyTask { global_config: Arc::clone(&self.global_config), host: Arc::clone(&schedule.host) };
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(err) = verify_task.run() {
+                            log_host_trap(&verify_task.global_config, &verify_task.host.hostname, &err);
                         }
                     });
+                    continue;
                 }
+
+                // Skipped for anything else, a host is no longer deferred
+                let alerted = deferred.remove(&schedule.host.hostname);
+
+                // Retired hosts are kept in the settings for their snapshots only
+                match Retirement::load(&self.global_config, &schedule.host.config) {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => (),
+                    Err(err) => log_trap(&self.global_config, &err),
+                }
+
+                // Tripped hosts stay held back until reset or their cool-down passed
+                match Breaker::load(&self.global_config, &schedule.host.config) {
+                    Ok(breaker) if breaker.holds(&self.global_config, now.timestamp()) => {
+                        log_host_trap(&self.global_config, &schedule.host.hostname, &Trap::Breaker(format!("`{}` is held back after {} failed runs, skipping", schedule.host.hostname, breaker.failures)));
+                        continue;
+                    },
+                    Ok(_) => (),
+                    Err(err) => log_trap(&self.global_config, &err),
+                }
+
+                // Kept until there is room again, alerted on once an hour meanwhile
+                if let Err(trap) = check_quota(&self.global_config, &schedule.host) {
+                    let alerted = match alerted.filter(|alerted| now.timestamp() - alerted < DEFER_ALERT_INTERVAL) {
+                        Some(alerted) => alerted,
+                        None => {
+                            let (global_config, hostname) = (Arc::clone(&self.global_config), schedule.host.hostname.clone());
+                            tokio::task::spawn_blocking(move || alert(&global_config, &hostname, &trap));
+                            now.timestamp()
+                        },
+                    };
+                    deferred.insert(schedule.host.hostname.clone(), alerted);
+                    continue;
+                }
+
+                let hostname = &schedule.host.hostname;
+                if running.contains(hostname) || queue.contains(hostname) || retrying.contains(hostname) {
+                    log_host_trap(&self.global_config, hostname, &Trap::Scheduler(format!("`{}` is due while its last backup is still queued, running or waiting to be retried, skipping", hostname)));
+                    continue;
+                }
+
+                let global_config_clone = Arc::clone(&self.global_config);
+                let host = Arc::clone(&schedule.host); 
+                let backup_task = BackupTask {
+                    global_config: global_config_clone, host, metrics: Arc::clone(&self.metrics), records: Arc::clone(&self.records),
+                    control: Arc::clone(&self.control), attempt: 0,
+                };
+
+                // Critical hosts are started first, so they get what is left
+                // of the destination before anything else.
+                let cost = expected_duration(&self.global_config, hostname);
+                queue.push(hostname, cost, schedule.host.config.is_critical(), backup_task);
+            }
+
+            // Starting as many as there are slots for
+            while running.len() < slots {
+                let Some((hostname, backup_task)) = queue.pop() else { break };
+                running.insert(hostname.clone());
+
+                // Off the async workers, so the slots are not capped at their
+                // number and a long backup never holds up the ticks
+                let done = done_tx.clone();
+                let retry_tx = retry_tx.clone();
+                let runtime = tokio::runtime::Handle::current();
+                let cancellable = self.control.start(&hostname);
+                tokio::task::spawn_blocking(move || {
+                    let result = backup_task.run();
+                    drop(cancellable);
+                    let retry = backup_task.will_retry(&result);
+                    if let Err(err) = &result {
+                        log_host_trap(&backup_task.global_config, &backup_task.host.hostname, err);
+                    }
+
+                    // Waited out without holding the slot
+                    if retry {
+                        let delay = retry_delay(&backup_task);
+                        log_event(&backup_task.global_config, Level::Warn, Some(&hostname), None, &format!(
+                            "Attempt {} failed, retrying in {}s", backup_task.attempt + 1, delay.as_secs()));
+                        let next = backup_task.retry();
+                        runtime.spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            let _ = retry_tx.send(next);
+                        });
+                    }
+                    let _ = done.send((hostname, retry));
+                });
             }
+            self.metrics.set_queue(queue.len(), running.len());
         }
     }
 }
 
+
+#[test]
+fn test_diff_schedules() {
+    use std::str::FromStr;
+    use cron::Schedule;
+
+    let wschedule = |hostname: &str, cron: &str, kind: TaskKind, compress: Option<bool>| Arc::new(WSchedule {
+        host: Arc::new(Host { hostname: hostname.to_string(), config: HostConfig { compress, ..Default::default() } }),
+        schedule: HostSchedule::Cron(Box::new(Schedule::from_str(cron).unwrap())),
+        kind,
+    });
+
+    let old = vec![
+        wschedule("web", "0 0 3 * * *", TaskKind::Backup, None),
+        wschedule("db", "0 0 4 * * *", TaskKind::Backup, None),
+        wschedule("db", "0 0 5 * * 7", TaskKind::Verify, None),
+        wschedule("gone", "0 0 6 * * *", TaskKind::Backup, None),
+    ];
+    let new = vec![
+        wschedule("web", "0 0 3 * * *", TaskKind::Backup, None),
+        wschedule("db", "0 30 4 * * *", TaskKind::Backup, None),
+        wschedule("db", "0 0 5 * * 7", TaskKind::Verify, Some(true)),
+        wschedule("new", "0 0 2 * * *", TaskKind::Backup, None),
+    ];
+
+    assert!(diff_schedules(&old, &old).is_empty());
+    assert_eq!(diff_schedules(&old, &new), vec![
+        String::from("updated Backup of `db` at `0 30 4 * * *`"),
+        String::from("updated Verify of `db` at `0 0 5 * * 7`"),
+        String::from("added Backup of `new` at `0 0 2 * * *`"),
+        String::from("removed Backup of `gone`"),
+    ]);
+}
+
+#[test]
+fn test_deferred_schedules() {
+    use std::str::FromStr;
+    use cron::Schedule;
+
+    let wschedule = |hostname: &str, kind: TaskKind| Arc::new(WSchedule {
+        host: Arc::new(Host { hostname: hostname.to_string(), config: HostConfig::default() }),
+        schedule: HostSchedule::Cron(Box::new(Schedule::from_str("0 0 4 * * *").unwrap())),
+        kind,
+    });
+    let schedules = vec![
+        wschedule("web", TaskKind::Backup),
+        wschedule("db", TaskKind::Backup),
+        wschedule("db", TaskKind::Verify),
+    ];
+
+    // Deferred hosts come back with their backup schedule until they are gone
+    let mut deferred = HashMap::from([(String::from("db"), 0), (String::from("gone"), 0)]);
+    let due = deferred_schedules(&schedules, &mut deferred);
+    assert_eq!(due.len(), 1);
+    assert!(Arc::ptr_eq(due[0], &schedules[1]));
+    assert_eq!(deferred.keys().collect::<Vec<_>>(), vec!["db"]);
+
+    // A backup asked for is the host's, not one of its sources
+    assert!(Arc::ptr_eq(backup_schedule(&schedules, "db").unwrap(), &schedules[1]));
+    assert!(backup_schedule(&schedules, "db:etc").is_none());
+    assert!(backup_schedule(&schedules, "gone").is_none());
+}
@@ -2,39 +2,287 @@ use rensen_lib::backup::rsync::Sftp;
 use rensen_lib::config::*;
 use rensen_lib::traits::*;
 use rensen_lib::logging::*;
-use rensen_lib::record::*;
+use rensen_lib::sla::*;
+use rensen_lib::history::History;
+use rensen_lib::status::HostStatus;
+use rensen_lib::lock::HostLock;
+use rensen_lib::verify::{verify_host, DEFAULT_VERIFY_PERCENT};
+use rensen_lib::notify::alert;
+use rensen_lib::results::BackupReport;
+use rensen_lib::mirror::{flush_mirrors, mirrors};
+use rensen_lib::breaker::Breaker;
+use rensen_lib::maintenance::run_maintenance;
+use rensen_lib::progress::Progress;
+use rensen_lib::cancel::Cancel;
+
+use crate::metrics::Metrics;
+use crate::control::Control;
+use crate::records::RecordCache;
+
+use chrono::Local;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 // Struct for running the actual backup task
 #[derive(Debug)]
 pub struct BackupTask {
     pub global_config: Arc<GlobalConfig>, 
     pub host: Arc<Host>, 
+    pub metrics: Arc<Metrics>,
+    pub records: Arc<RecordCache>,
+    pub control: Arc<Control>, // cancels the run when asked to
+    pub attempt: u32, // retries so far, see `retry`
 }
 
 impl BackupTask {
 
-    /// Performs backup task using the rensen sftp-backup lib
-    pub async fn run(&self) -> Result<(), Trap> {
+    /// Performs backup task using the rensen sftp-backup lib, once for every
+    /// source of the host. Returns the report of the host's `source`, or the
+    /// first error once all of them have run. Blocks on ssh throughout, so
+    /// the scheduler runs it on a thread of its own.
+    pub fn run(&self) -> Result<BackupReport, Trap> {
+        let namespaces = self.host.config.namespaces()?;
+        let _lock = HostLock::acquire(&self.global_config, &self.host.config)?;
+
+        // Past the host's timeout the run is cancelled, whichever source it
+        // is at, which frees its slot, and so it is when asked to over the
+        // control endpoint
+        let cancel = Cancel::new();
+        self.control.attach(&self.host.hostname, &cancel);
+        let timeout = self.host.config.timeout.map(Duration::from_secs);
+        let results = run_sources(&self.host.hostname, &namespaces, &cancel, timeout, |hostname, host_config| {
+            let result = self.run_namespace(hostname, host_config, &cancel);
+            if let Err(err) = &result {
+                if host_config.namespace.is_some() {
+                    alert(&self.global_config, hostname, err);
+                }
+            }
+            result
+        });
+
+        let success = results.iter().all(|result| result.is_ok());
+        let mut results = results.into_iter();
+        let first = results.next().unwrap_or(Err(Trap::Config(String::from("No sources to back up"))));
+        let result = match results.find_map(|result| result.err()) {
+            Some(err) if first.is_ok() => Err(err),
+            _ => first,
+        };
+
+        // Only the last attempt counts towards the breaker, and a backup
+        // somebody cancelled not at all
+        if !self.will_retry(&result) && !matches!(result, Err(Trap::Cancelled(_))) {
+            if let Err(err) = Breaker::record(&self.global_config, &self.host.config, &self.host.hostname, success, Local::now().timestamp()) {
+                log_host_trap(&self.global_config, &self.host.hostname, &err);
+            }
+        }
+
+        result
+    }
 
-        let hostname = &self.host.hostname;
+    /// Whether the scheduler runs the host again after `result`
+    pub fn will_retry(&self, result: &Result<BackupReport, Trap>) -> bool {
+        match (&self.host.config.retry, result) {
+            (Some(retry), Err(err)) => retry.retries(err, self.attempt),
+            _ => false,
+        }
+    }
+
+    /// The task of the next attempt
+    pub fn retry(&self) -> Self {
+        BackupTask {
+            global_config: Arc::clone(&self.global_config),
+            host: Arc::clone(&self.host),
+            metrics: Arc::clone(&self.metrics),
+            records: Arc::clone(&self.records),
+            control: Arc::clone(&self.control),
+            attempt: self.attempt + 1,
+        }
+    }
+
+    fn run_namespace(&self, hostname: &str, host_config: &HostConfig, cancel: &Cancel) -> Result<BackupReport, Trap> {
         let inc = true;
+
+        let record_path = RecordCache::path(&self.global_config, host_config);
+        let record = self.records.get(&record_path)
+            .map_err(|err| Trap::FS(format!("Could not read record for host `{}`: {}", hostname, err)))?;
+
+        let mut sftp = Sftp::new(host_config, &self.global_config, record, inc);
+        sftp.incremental = inc;
+
+        // For the status socket, until the run is finished
+        let (metrics, progress_host) = (Arc::clone(&self.metrics), hostname.to_string());
+        sftp.progress = Some(Box::new(move |progress: &Progress| metrics.set_progress(&progress_host, progress)));
+
+        // The ledger is kept per host, so only the host's `source` is held to it
+        let sla = match host_config.sla.as_ref().filter(|_| host_config.namespace.is_none()) {
+            Some(sla) => Some((deadline_after(Local::now(), sla)?, SlaLedger::path(&self.global_config, host_config))),
+            None => None,
+        };
+
+        let mut ledger = SlaLedger::default();
+        if let Some((deadline, ledger_path)) = &sla {
+            ledger = SlaLedger::deserialize_json(ledger_path)
+                .map_err(|err| Trap::Deserialize(format!("Could not read SLA ledger for host `{}`: {}", hostname, err)))?;
+
+            sftp.sla = Some(SlaWatch::new(*deadline, ledger.expected_bytes(), host_config.sla_escalate.unwrap_or(false)));
+        }
+
+        sftp.cancel = Some(cancel.clone());
+
+        let started = Local::now();
+        log_event(&self.global_config, Level::Info, Some(hostname), None, "Backup started");
+        self.metrics.started(hostname);
+        let result = sftp.backup();
+        if let Ok(report) = &result {
+            log_event(&self.global_config, Level::Info, Some(hostname), None, &format!(
+                "Backup {} finished in {}s, {} bytes in {} files", report.snapshot, Local::now().timestamp() - started.timestamp(), report.bytes, report.files));
+        }
+
+        // Recording whether the run made it before its deadline, unless it
+        // was cancelled by hand and so never had the chance
+        if let Some((deadline, ledger_path)) = sla.as_ref().filter(|_| !matches!(result, Err(Trap::Cancelled(_)))) {
+            let finished = Local::now();
+            let hit = result.is_ok() && finished <= *deadline;
+            ledger.entries.push(SlaEntry {
+                started: started.timestamp(),
+                deadline: deadline.timestamp(),
+                finished: finished.timestamp(),
+                bytes: sftp.bytes_transferred.get(),
+                hit,
+            });
+
+            if let Err(err) = ledger.serialize_json(ledger_path) {
+                log_host_trap(&self.global_config, hostname, &Trap::Serialize(format!("Could not write SLA ledger for host `{}`: {}", hostname, err)));
+            }
+
+            if !hit {
+                log_host_trap(&self.global_config, hostname, &Trap::Sla(format!("`{}` missed its deadline at {}", hostname, deadline.format("%H:%M"))));
+            }
+        }
+
+        let outcome = sftp.outcome(hostname, started.timestamp(), Local::now().timestamp(), &result);
+        History::record(&self.global_config, &outcome);
+        HostStatus::record(&self.global_config, host_config, &outcome);
+        self.metrics.finished(&outcome);
+
+        // What the run wrote is what the next one starts from
+        if result.is_ok() {
+            self.records.put(&record_path, std::mem::take(&mut sftp.record));
+        }
+
+        // Deferred mirrors, and what failed to mirror before, once the run is on record
+        if result.is_ok() && mirrors(&self.global_config, host_config).iter().any(|mirror| mirror.is_deferred()) {
+            match flush_mirrors(&self.global_config, host_config, Local::now().timestamp()) {
+                Ok(errors) => errors.iter().for_each(|err| alert(&self.global_config, hostname, err)),
+                Err(err) => log_host_trap(&self.global_config, hostname, &err),
+            }
+        }
+
+        result
+    }
+}
+
+/// Runs `run` for every source of host `hostname` in turn, all under the one
+/// `timeout`. Once `cancel` is cancelled the sources still to come fail with
+/// its trap without running.
+fn run_sources<T>(hostname: &str, namespaces: &[HostConfig], cancel: &Cancel, timeout: Option<Duration>, mut run: impl FnMut(&str, &HostConfig) -> Result<T, Trap>) -> Vec<Result<T, Trap>> {
+    let _deadline = timeout.map(|timeout| cancel.after(
+        timeout, format!("`{}` ran past its timeout of {}s and was cancelled", hostname, timeout.as_secs())));
+
+    namespaces.iter().map(|host_config| {
+        let hostname = match &host_config.namespace {
+            Some(name) => format!("{}:{}", hostname, name),
+            None => hostname.to_string(),
+        };
+        match cancel.trap() {
+            Some(trap) => Err(trap),
+            None => run(&hostname, host_config),
+        }
+    }).collect()
+}
+
+// Struct for scrubbing part of a host's snapshots
+#[derive(Debug)]
+pub struct VerifyTask {
+    pub global_config: Arc<GlobalConfig>,
+    pub host: Arc<Host>,
+}
+
+impl VerifyTask {
+
+    /// Verifies `verify_percent` of the host's snapshots, alerting on problems.
+    /// Reads and hashes whole archives, so the scheduler runs it on a thread
+    /// of its own.
+    pub fn run(&self) -> Result<(), Trap> {
         let host_config = &self.host.config;
+        let _lock = HostLock::acquire(&self.global_config, host_config)?;
+        let percent = host_config.verify_percent.unwrap_or(DEFAULT_VERIFY_PERCENT);
 
-        let record_path = host_config.destination
-            .join(&host_config.identifier)
-            .join(".records")
-            .join("record.json");
+        for result in verify_host(&self.global_config, host_config, percent, Local::now().timestamp())? {
+            if !result.is_ok() {
+                alert(&self.global_config, &self.host.hostname, &Trap::Verify(format!(
+                    "Snapshot {} failed verification: {}", result.snapshot, result.problems.join("; ")
+                )));
+            }
+        }
 
-        let record = Record::deserialize_json(&record_path)
-            .map_err(|err| Trap::FS(format!("Could not read record for host `{}`: {}", hostname, err)))?;
+        Ok(())
+    }
+}
 
-        let mut sftp = Sftp::new(&host_config, &self.global_config, record, inc);
+// Struct for the nightly verification of the snapshots of all hosts
+#[derive(Debug)]
+pub struct MaintenanceTask {
+    pub global_config: Arc<GlobalConfig>,
+    pub hosts: Vec<Host>,
+}
 
-        sftp.incremental = inc;
-        sftp.backup()?;
+impl MaintenanceTask {
 
+    /// Verifies as many snapshots as the `maintenance` window has room for,
+    /// alerting on problems. Reads archives throughout, so the scheduler runs
+    /// it on a thread of its own.
+    pub fn run(&self) -> Result<(), Trap> {
+        let Some(config) = &self.global_config.maintenance else { return Ok(()) };
+
+        log_event(&self.global_config, Level::Info, None, None, "Maintenance window opened");
+        let report = run_maintenance(&self.global_config, &self.hosts, config, Local::now().timestamp())?;
+        for (hostname, result) in report.verified.iter().filter(|(_, result)| !result.is_ok()) {
+            alert(&self.global_config, hostname, &Trap::Verify(format!(
+                "Snapshot {} failed verification: {}", result.snapshot, result.problems.join("; ")
+            )));
+        }
+        for warning in report.warnings.iter() {
+            log_event(&self.global_config, Level::Warn, None, None, warning);
+        }
+
+        log_event(&self.global_config, Level::Info, None, None, &format!(
+            "Maintenance window closed, {} snapshots verified ({} bytes read), {} left for the next one", report.verified.len(), report.bytes, report.left));
         Ok(())
     }
 }
+
+#[test]
+fn test_run_sources() {
+    use std::thread;
+
+    // Each source takes longer than half the timeout, together they run past it
+    let namespaces = [None, Some("etc"), Some("srv")].map(|namespace| HostConfig { namespace: namespace.map(String::from), ..Default::default() });
+    let cancel = Cancel::new();
+    let mut ran = Vec::new();
+    let results = run_sources("db", &namespaces, &cancel, Some(Duration::from_millis(300)), |hostname, _| {
+        ran.push(hostname.to_string());
+        for _ in 0..20 {
+            if let Some(trap) = cancel.trap() {
+                return Err(trap);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    });
+    assert!(results[0].is_ok());
+    assert!(matches!(&results[1], Err(Trap::Timeout(reason)) if reason.starts_with("`db` ran past its timeout")));
+    assert!(matches!(results[2], Err(Trap::Timeout(_))));
+    assert_eq!(ran, vec!["db", "db:etc"]);
+}
@@ -5,6 +5,12 @@ pub struct TaskQueue<T> {
     pub tasks: VecDeque<T>,
 }
 
+impl<T> Default for TaskQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> TaskQueue<T> {
     pub fn new() -> Self {
         TaskQueue {
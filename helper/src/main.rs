@@ -0,0 +1,151 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use sha3::{Digest, Sha3_256};
+
+/// Bumped whenever a command or its output changes, rensen redeploys on mismatch
+const PROTOCOL: u32 = 3;
+
+fn sha3(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha3_256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Writes the sha3-256 of every `size` bytes of `path`, one per line
+fn blocks(path: &Path, size: u64, out: &mut impl Write) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    loop {
+        let mut hasher = Sha3_256::new();
+        if io::copy(&mut (&mut file).take(size), &mut hasher)? == 0 {
+            return Ok(());
+        }
+
+        let hash: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+        writeln!(out, "{}", hash)?;
+    }
+}
+
+/// Writes `mtime size path` for every file below `dir`, depth first
+fn tree(dir: &Path, out: &mut impl Write, failed: &mut bool) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("{}: {}", dir.display(), err);
+            *failed = true;
+            return Ok(());
+        },
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                *failed = true;
+                continue;
+            },
+        };
+
+        if metadata.is_dir() {
+            tree(&path, out, failed)?;
+        } else if metadata.is_file() {
+            let mtime = metadata.modified().ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map(|mtime| mtime.as_secs())
+                .unwrap_or(0);
+            writeln!(out, "{} {} {}", mtime, metadata.len(), path.display())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `link dev:ino path` for every file below `dir` with more than
+/// one link, and `device rdev path` for every device node, depth first
+fn special(dir: &Path, out: &mut impl Write, failed: &mut bool) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("{}: {}", dir.display(), err);
+            *failed = true;
+            return Ok(());
+        },
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                *failed = true;
+                continue;
+            },
+        };
+
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            special(&path, out, failed)?;
+        } else if file_type.is_file() && metadata.nlink() > 1 {
+            writeln!(out, "link {}:{} {}", metadata.dev(), metadata.ino(), path.display())?;
+        } else if file_type.is_char_device() || file_type.is_block_device() {
+            writeln!(out, "device {} {}", metadata.rdev(), path.display())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut failed = false;
+
+    let written = match args.first().map(String::as_str) {
+        Some("version") => writeln!(out, "rensen-helper {} {}", PROTOCOL, env!("CARGO_PKG_VERSION")),
+
+        // `hash path` per file, like sha3sum
+        Some("sha3") => args[1..].iter().try_for_each(|path| match sha3(Path::new(path)) {
+            Ok(hash) => writeln!(out, "{}  {}", hash, path),
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                failed = true;
+                Ok(())
+            },
+        }),
+
+        Some("tree") if args.len() == 2 => tree(Path::new(&args[1]), &mut out, &mut failed),
+
+        Some("special") if args.len() == 2 => special(Path::new(&args[1]), &mut out, &mut failed),
+
+        Some("blocks") if args.len() == 3 && args[1].parse::<u64>().is_ok_and(|size| size > 0) => {
+            blocks(Path::new(&args[2]), args[1].parse().unwrap(), &mut out)
+        },
+
+        _ => {
+            eprintln!("usage: rensen-helper version | sha3 <path>... | tree <dir> | special <dir> | blocks <size> <path>");
+            process::exit(2);
+        },
+    };
+
+    if written.and_then(|_| out.flush()).is_err() || failed {
+        process::exit(1);
+    }
+}
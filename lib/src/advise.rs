@@ -0,0 +1,278 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use tar::Archive;
+
+use crate::chunks::ChunkStore;
+use crate::codec::decoder;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::record::{snapshot_archive, Record};
+use crate::snapshot::{FileEntry, Snapshot};
+use crate::traits::JsonFile;
+
+/// Runs looked back on, the latest snapshots after the first, full one
+pub const ADVISE_RUNS: usize = 10;
+
+/// Fewer runs tell too little about what changes every time
+pub const MIN_RUNS: usize = 3;
+
+/// Bytes of each file compressed to tell how well it compresses
+const SAMPLE: u64 = 1024 * 1024;
+
+/// Files sampled per suggestion
+const SAMPLES: usize = 3;
+
+/// What makes files worth excluding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    pub min_bytes: u64,  // fetched per run, on average
+    pub min_churn: f64,  // share of runs they were fetched in
+    pub max_ratio: f64,  // compressed size over the original size of their samples, at least
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds { min_bytes: 64 * 1024 * 1024, min_churn: 0.8, max_ratio: 0.9 }
+    }
+}
+
+/// Files a host keeps fetching, huge and compressing poorly, and the
+/// exclude pattern that would leave them out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub pattern: String,     // for `exclude`, anchored at the source
+    pub files: u64,          // distinct paths it matched in the runs looked at
+    pub bytes_per_run: u64,  // fetched, on average over all runs looked at
+    pub runs: usize,         // it was fetched in
+    pub ratio: Option<f64>,  // of the samples, none when they could not be read or are encrypted
+}
+
+/// Advice for `host_config`, most bytes per run first, and the runs it was
+/// drawn from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Advice {
+    pub runs: usize,
+    pub suggestions: Vec<Suggestion>,
+}
+
+/// Where the fetched files of one pattern went
+#[derive(Default)]
+struct Group {
+    paths: BTreeMap<PathBuf, FileEntry>, // by source path, as fetched last
+    bytes: u64,
+    runs: usize,
+}
+
+/// Suggests excludes from the records of the latest runs of
+/// `host_config`: files of a directory and extension, or else single
+/// files, that were fetched in most runs, many bytes at a time, and barely
+/// compress. Compacted snapshots are passed over.
+pub fn advise(global_config: &GlobalConfig, host_config: &HostConfig, thresholds: &Thresholds) -> Result<Advice, Trap> {
+    let listed = Snapshot::list(global_config, host_config)?;
+    let recent: Vec<_> = listed.iter().skip(1).filter(|snapshot| !snapshot.compacted).rev().take(ADVISE_RUNS).collect();
+    if recent.len() < MIN_RUNS {
+        return Err(Trap::Missing(format!("`{}` has {} run(s) after its first to look at, advice needs {}", host_config.identifier, recent.len(), MIN_RUNS)));
+    }
+
+    // Newest first, so the entry kept per path is its latest one
+    let mut groups: HashMap<String, Group> = HashMap::new();
+    for snapshot in recent.iter() {
+        let record_path = snapshot.record_path();
+        let record = Record::deserialize_json(&record_path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+
+        let mut fetched: HashMap<String, u64> = HashMap::new();
+        for (path, entry) in record.snapshot.entries.iter() {
+            if entry.snapshot_path.file_name().is_none_or(|name| name.to_string_lossy() != snapshot.name) {
+                continue;
+            }
+            let relative = path.strip_prefix(&host_config.source).unwrap_or(path);
+            let pattern = pattern_of(relative);
+            *fetched.entry(pattern.clone()).or_default() += entry.size;
+            groups.entry(pattern).or_default().paths.entry(path.clone()).or_insert_with(|| entry.clone());
+        }
+        for (pattern, bytes) in fetched {
+            let group = groups.get_mut(&pattern).unwrap();
+            group.bytes += bytes;
+            group.runs += 1;
+        }
+    }
+
+    let runs = recent.len();
+    let wanted: Vec<(String, Group)> = groups.into_iter()
+        .filter(|(_, group)| group.runs as f64 >= thresholds.min_churn * runs as f64 && group.bytes / runs as u64 >= thresholds.min_bytes)
+        .collect();
+
+    // What is encrypted on the host compresses poorly however it started out
+    let encrypted = host_config.encrypt_key.is_some();
+    let samples: Vec<&FileEntry> = wanted.iter()
+        .flat_map(|(_, group)| group.paths.values().take(SAMPLES))
+        .collect();
+    let sampled = match encrypted {
+        true => HashMap::new(),
+        false => sample(global_config, &samples),
+    };
+
+    let mut suggestions = Vec::new();
+    for (pattern, group) in wanted {
+        let (original, compressed) = group.paths.values().take(SAMPLES)
+            .filter_map(|entry| sampled.get(&entry.file_path))
+            .fold((0, 0), |(original, compressed), (size, packed)| (original + size, compressed + packed));
+        let ratio = match original {
+            0 => None,
+            original => Some(compressed as f64 / original as f64),
+        };
+        if ratio.is_some_and(|ratio| ratio < thresholds.max_ratio) {
+            continue;
+        }
+
+        suggestions.push(Suggestion {
+            pattern: match group.paths.len() {
+                1 => format!("/{}", escape(group.paths.keys().next().unwrap().strip_prefix(&host_config.source).unwrap_or(Path::new(&pattern)))),
+                _ => pattern,
+            },
+            files: group.paths.len() as u64,
+            bytes_per_run: group.bytes / runs as u64,
+            runs: group.runs,
+            ratio,
+        });
+    }
+
+    suggestions.sort_by(|a, b| b.bytes_per_run.cmp(&a.bytes_per_run).then_with(|| a.pattern.cmp(&b.pattern)));
+    Ok(Advice { runs, suggestions })
+}
+
+/// The pattern `relative` is counted under: the files of its directory with
+/// its extension, or itself without an extension
+fn pattern_of(relative: &Path) -> String {
+    match (relative.parent(), relative.extension()) {
+        (Some(parent), Some(extension)) if !parent.as_os_str().is_empty() => format!("/{}/*.{}", escape(parent), escape(Path::new(extension))),
+        (_, Some(extension)) => format!("/*.{}", escape(Path::new(extension))),
+        (_, None) => format!("/{}", escape(relative)),
+    }
+}
+
+/// `path` with the characters globs treat specially matched by `?`
+fn escape(path: &Path) -> String {
+    path.to_string_lossy().chars()
+        .map(|c| if matches!(c, '*' | '?' | '[') { '?' } else { c })
+        .collect()
+}
+
+/// Bytes `data` deflates to, quickly
+fn deflated(data: &[u8]) -> u64 {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    let _ = encoder.write_all(data);
+    encoder.finish().map(|packed| packed.len() as u64).unwrap_or(data.len() as u64)
+}
+
+/// The sampled bytes and what they deflate to of each of `entries` that
+/// can be read, by file path. Archives are read once for all their members.
+fn sample(global_config: &GlobalConfig, entries: &[&FileEntry]) -> HashMap<PathBuf, (u64, u64)> {
+    let mut sampled = HashMap::new();
+    let mut keep = |entry: &FileEntry, data: &[u8]| {
+        if !data.is_empty() {
+            sampled.insert(entry.file_path.clone(), (data.len() as u64, deflated(data)));
+        }
+    };
+
+    let mut archived: BTreeMap<PathBuf, Vec<&FileEntry>> = BTreeMap::new();
+    for entry in entries {
+        let mut data = Vec::new();
+        if let Some(first) = entry.chunks.as_ref().and_then(|chunks| chunks.first()) {
+            if ChunkStore::new(global_config).read(first, &mut data).is_ok() {
+                data.truncate(SAMPLE as usize);
+                keep(entry, &data);
+            }
+        }
+        else if entry.file_path.is_file() {
+            if File::open(&entry.file_path).and_then(|file| file.take(SAMPLE).read_to_end(&mut data)).is_ok() {
+                keep(entry, &data);
+            }
+        }
+        else {
+            archived.entry(entry.snapshot_path.clone()).or_default().push(entry);
+        }
+    }
+
+    for (snapshot_path, entries) in archived {
+        let members: HashMap<PathBuf, &FileEntry> = entries.iter()
+            .filter_map(|entry| Some((entry.file_path.strip_prefix(&snapshot_path).ok()?.to_path_buf(), *entry)))
+            .collect();
+        let _ = (|| -> io::Result<()> {
+            let (archive, codec) = snapshot_archive(&snapshot_path);
+            let mut archive = Archive::new(decoder(codec, File::open(archive)?)?);
+            for member in archive.entries()? {
+                let member = member?;
+                if let Some(entry) = members.get(member.path()?.as_ref()) {
+                    let mut data = Vec::new();
+                    member.take(SAMPLE).read_to_end(&mut data)?;
+                    keep(entry, &data);
+                }
+            }
+            Ok(())
+        })();
+    }
+
+    sampled
+}
+
+#[test]
+fn test_advise() {
+    use std::fs;
+
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_advise"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("db"), source: PathBuf::from("/srv"), ..Default::default() };
+    let root = host_config.root(&global_config);
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(root.join(".records")).unwrap();
+
+    // Noise that will not compress, and text that will
+    let mut state: u32 = 7;
+    let noise: Vec<u8> = (0..4096).map(|_| { state ^= state << 13; state ^= state >> 17; state ^= state << 5; state as u8 }).collect();
+    let text = b"the same line again\n".repeat(200);
+
+    let names = ["2024-05-01-00-00-00", "2024-05-02-00-00-00", "2024-05-03-00-00-00", "2024-05-04-00-00-00"];
+    let mut record = Record::new();
+    for (run, name) in names.iter().enumerate() {
+        let snapshot_root = root.join(name);
+        let mut fetch = |path: &str, data: &[u8]| {
+            let file_path = snapshot_root.join(path);
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            fs::write(&file_path, data).unwrap();
+            record.snapshot.entries.insert(Path::new("/srv").join(path), FileEntry::from(file_path, snapshot_root.clone(), run as u64, data.len() as u64));
+        };
+
+        // A new capture every run, a disk image changing every run, a log
+        // and a text file that compress, and one fetched only at first
+        fetch(&format!("captures/{}.pcap", run), &noise);
+        fetch("vm/disk", &noise);
+        fetch("log/app.log", &text);
+        if run == 0 {
+            fetch("once.bin", &noise);
+        }
+        record.serialize_json(&root.join(".records").join(format!("{}.json", name))).unwrap();
+    }
+
+    let thresholds = Thresholds { min_bytes: 1024, ..Default::default() };
+    let advice = advise(&global_config, &host_config, &thresholds).unwrap();
+    assert_eq!(advice.runs, 3);
+    let patterns: Vec<&str> = advice.suggestions.iter().map(|suggestion| suggestion.pattern.as_str()).collect();
+    assert_eq!(patterns, vec!["/captures/*.pcap", "/vm/disk"]);
+    assert_eq!((advice.suggestions[0].files, advice.suggestions[0].bytes_per_run, advice.suggestions[0].runs), (3, 4096, 3));
+    assert!(advice.suggestions[1].ratio.unwrap() > 0.9);
+
+    // Encrypted contents are not judged by how they compress
+    let encrypted = HostConfig { encrypt_key: Some(PathBuf::from("/etc/key")), ..host_config.clone() };
+    let advice = advise(&global_config, &encrypted, &thresholds).unwrap();
+    assert_eq!(advice.suggestions.len(), 3);
+    assert!(advice.suggestions.iter().all(|suggestion| suggestion.ratio.is_none()));
+
+    assert_eq!(pattern_of(Path::new("a[1]/b.c")), "/a?1]/*.c");
+    assert!(advise(&global_config, &HostConfig { identifier: String::from("none"), ..Default::default() }, &thresholds).is_err());
+}
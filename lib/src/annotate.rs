@@ -0,0 +1,144 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::retire::Retirement;
+use crate::traits::JsonFile;
+use crate::verify::snapshots;
+
+/// A note from outside about what happened to a host, e.g. a deploy
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub time: i64, // unix seconds it was made at
+    pub text: String,
+}
+
+/// Annotations of the snapshots of a host, and those waiting for its next
+/// snapshot. Stored at $backups/$identifier/.records/annotations.json
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Annotations {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<Annotation>,
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, Vec<Annotation>>,
+}
+
+impl Annotations {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("annotations.json")
+    }
+
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Self, Trap> {
+        let path = Self::path(global_config, host_config);
+        Annotations::deserialize_json(&path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))
+    }
+
+    fn save(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        let path = Self::path(global_config, host_config);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+
+        self.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+    }
+
+    /// The annotations of `snapshot`, oldest first
+    pub fn of(&self, snapshot: &str) -> &[Annotation] {
+        self.snapshots.get(snapshot).map(|annotations| annotations.as_slice()).unwrap_or(&[])
+    }
+
+    /// Annotates the latest snapshot of `host_config` with `text`, or with
+    /// `latest` unset the next one it takes. Returns the snapshot annotated,
+    /// None while it is pending.
+    pub fn annotate(global_config: &GlobalConfig, host_config: &HostConfig, text: &str, latest: bool, now: i64) -> Result<Option<String>, Trap> {
+        global_config.ensure_writable("annotate snapshots")?;
+        Retirement::ensure_active(global_config, host_config, "annotate snapshots")?;
+        let mut annotations = Annotations::load(global_config, host_config)?;
+        let annotation = Annotation { time: now, text: text.to_string() };
+
+        let snapshot = match latest {
+            true => {
+                let snapshot = snapshots(global_config, host_config).pop()
+                    .ok_or(Trap::InvalidInput(format!("`{}` has no snapshots to annotate yet", host_config.identifier)))?;
+                annotations.snapshots.entry(snapshot.clone()).or_default().push(annotation);
+                Some(snapshot)
+            },
+            false => {
+                annotations.pending.push(annotation);
+                None
+            },
+        };
+
+        annotations.save(global_config, host_config)?;
+        Ok(snapshot)
+    }
+
+    /// Hands the pending annotations of `host_config` to `snapshot`, once it
+    /// has been taken. Returns them.
+    pub fn attach_pending(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str) -> Result<Vec<Annotation>, Trap> {
+        let mut annotations = Annotations::load(global_config, host_config)?;
+        if annotations.pending.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let pending = std::mem::take(&mut annotations.pending);
+        annotations.snapshots.entry(snapshot.to_string()).or_default().extend(pending.iter().cloned());
+        annotations.save(global_config, host_config)?;
+        Ok(pending)
+    }
+}
+
+impl JsonFile for Annotations {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(Annotations::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let annotations: Annotations = serde_json::from_str(&contents)?;
+        Ok(annotations)
+    }
+}
+
+#[test]
+fn test_annotations() {
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_annotate"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let records = global_config.backups.join("host").join(".records");
+    let _ = fs::remove_dir_all(&global_config.backups);
+
+    // Nothing to attach a note about the latest snapshot to yet
+    assert!(Annotations::annotate(&global_config, &host_config, "deployed v2.3.0", true, 1).is_err());
+    assert_eq!(Annotations::annotate(&global_config, &host_config, "deployed v2.3.1", false, 2).unwrap(), None);
+
+    fs::write(records.join("2024-05-01-14-05-00.json"), "{}").unwrap();
+    let attached = Annotations::attach_pending(&global_config, &host_config, "2024-05-01-14-05-00").unwrap();
+    assert_eq!(attached, vec![Annotation { time: 2, text: String::from("deployed v2.3.1") }]);
+    assert!(Annotations::attach_pending(&global_config, &host_config, "2024-05-02-14-05-00").unwrap().is_empty());
+
+    let latest = Annotations::annotate(&global_config, &host_config, "rolled back", true, 3).unwrap();
+    assert_eq!(latest.as_deref(), Some("2024-05-01-14-05-00"));
+    let annotations = Annotations::load(&global_config, &host_config).unwrap();
+    assert!(annotations.pending.is_empty());
+    assert_eq!(annotations.of("2024-05-01-14-05-00").len(), 2);
+    assert!(annotations.of("2024-05-02-14-05-00").is_empty());
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
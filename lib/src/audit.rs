@@ -0,0 +1,141 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::GlobalConfig;
+use crate::logging::Trap;
+
+/// Something done by hand to a host or its snapshots, e.g. retiring it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: i64,        // unix seconds
+    pub user: String,     // who ran it, from $SUDO_USER or $USER
+    pub hostname: String,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+impl AuditEntry {
+    pub fn new(hostname: &str, action: &str, detail: &str, time: i64) -> Self {
+        let user = std::env::var("SUDO_USER")
+            .or_else(|_| std::env::var("USER"))
+            .unwrap_or_else(|_| String::from("unknown"));
+
+        Self { time, user, hostname: hostname.to_string(), action: action.to_string(), detail: detail.to_string() }
+    }
+}
+
+/// Action of the backups started by hand that count towards `manual_runs`
+const MANUAL_RUN: &str = "run";
+
+/// Append-only log of what was done to the hosts by hand, one JSON object
+/// per line. Unlike the history it is never about runs.
+pub struct AuditLog {
+    pub path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(global_config: &GlobalConfig) -> Self {
+        let path = match &global_config.audit {
+            Some(path) => path.clone(),
+            None => global_config.log
+                .parent()
+                .unwrap_or(Path::new("/etc/rensen"))
+                .join("audit.jsonl"),
+        };
+
+        Self { path }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), Trap> {
+        let line = serde_json::to_string(entry)
+            .map_err(|err| Trap::Serialize(format!("Could not serialize audit entry: {}", err)))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| Trap::FS(format!("Could not open audit log at {:?}: {}", self.path, err)))?;
+
+        writeln!(file, "{}", line)
+            .map_err(|err| Trap::FS(format!("Could not write to audit log at {:?}: {}", self.path, err)))
+    }
+
+    /// Every entry of `hostname`, oldest first, skipping lines which can not
+    /// be parsed
+    pub fn for_host(&self, hostname: &str) -> Result<Vec<AuditEntry>, Trap> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| Trap::FS(format!("Could not read audit log: {}", err)))?;
+            match serde_json::from_str::<AuditEntry>(&line) {
+                Ok(entry) if entry.hostname == hostname => entries.push(entry),
+                _ => (),
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Audits a backup of `hostname` started by hand, as `detail` along with the
+/// address the caller came from over ssh. With `manual_runs` in the global
+/// config, one started when that many others were within the hour before
+/// `now` is refused, and audited as such, so a misbehaving automation can
+/// not pile up duplicate runs.
+pub fn admit_manual_run(global_config: &GlobalConfig, hostname: &str, detail: &str, now: i64) -> Result<(), Trap> {
+    let detail = match std::env::var("SSH_CONNECTION") {
+        Ok(connection) => format!("{}, from {}", detail, connection.split(' ').next().unwrap_or_default()),
+        Err(_) => detail.to_string(),
+    };
+    admit(global_config, AuditEntry::new(hostname, MANUAL_RUN, &detail, now))
+}
+
+/// `admit_manual_run` of a backup `user` started through the control
+/// endpoint of rensend
+pub fn admit_control_run(global_config: &GlobalConfig, hostname: &str, user: &str, detail: &str, now: i64) -> Result<(), Trap> {
+    admit(global_config, AuditEntry { user: user.to_string(), ..AuditEntry::new(hostname, MANUAL_RUN, detail, now) })
+}
+
+fn admit(global_config: &GlobalConfig, entry: AuditEntry) -> Result<(), Trap> {
+    let log = AuditLog::new(global_config);
+    if let Some(limit) = global_config.manual_runs {
+        let recent = log.for_host(&entry.hostname)?.iter()
+            .filter(|other| other.action == MANUAL_RUN && other.time > entry.time - 60 * 60)
+            .count();
+        if recent >= limit as usize {
+            log.append(&AuditEntry { action: String::from("run refused"), ..entry.clone() })?;
+            return Err(Trap::RateLimit(format!("`{}` was backed up by hand {} times within the hour, `manual_runs` allows {}", entry.hostname, recent, limit)));
+        }
+    }
+
+    log.append(&entry)
+}
+
+#[test]
+fn test_admit_manual_run() {
+    let log = std::env::temp_dir().join("rensen_test_audit").join("rensen.log");
+    let global_config = GlobalConfig { log: log.clone(), manual_runs: Some(2), ..Default::default() };
+    let _ = std::fs::remove_dir_all(log.parent().unwrap());
+    std::fs::create_dir_all(log.parent().unwrap()).unwrap();
+
+    // The third within the hour is refused, the hour after they are let through again
+    assert!(admit_manual_run(&global_config, "web", "incremental", 1000).is_ok());
+    assert!(admit_manual_run(&global_config, "web", "full", 2000).is_ok());
+    assert!(matches!(admit_manual_run(&global_config, "web", "incremental", 3000), Err(Trap::RateLimit(_))));
+    assert!(admit_manual_run(&global_config, "db", "incremental", 3000).is_ok());
+    assert!(admit_manual_run(&global_config, "web", "incremental", 1000 + 60 * 60).is_ok());
+    assert!(matches!(admit_control_run(&global_config, "web", "alice", "over the control endpoint", 1001 + 60 * 60), Err(Trap::RateLimit(_))));
+
+    let entries = AuditLog::new(&global_config).for_host("web").unwrap();
+    let actions: Vec<&str> = entries.iter().map(|entry| entry.action.as_str()).collect();
+    assert_eq!(actions, vec!["run", "run", "run refused", "run", "run refused"]);
+    assert!(entries[1].detail.starts_with("full"));
+    assert_eq!(entries[4].user, "alice");
+}
@@ -0,0 +1,448 @@
+use serde::{Serialize, Deserialize};
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+use crate::logging::Trap;
+use crate::webdav::escape;
+
+/// Who may start and cancel backups through the control endpoint of
+/// rensend, served at `metrics_bind`, e.g.
+///
+/// control:
+///   providers:
+///     - pam:
+///         service: rensen
+///     - oidc:
+///         introspection_url: https://sso.example.com/realms/ops/protocol/openid-connect/token/introspect
+///         client_id: rensen
+///         client_secret_file: /etc/rensen/oidc_secret
+///         audience: rensen
+///   allow: [alice, bob]
+///
+/// Basic credentials are checked against PAM, bearer tokens against the
+/// identity provider, by the providers in order until one accepts them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ControlConfig {
+    pub providers: Vec<AuthProvider>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<String>>, // users let in, default: anyone a provider accepts
+}
+
+/// One way of telling who is calling, set one of them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthProvider {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pam: Option<PamConfig>,   // user and password of Basic credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<OidcConfig>, // bearer tokens of an OpenID Connect provider
+}
+
+/// PAM_SERVICE rensend authenticates as, its stack is /etc/pam.d/rensen
+pub const DEFAULT_PAM_SERVICE: &str = "rensen";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PamConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,  // default: rensen
+}
+
+/// Claim of the introspected token naming the user
+pub const DEFAULT_USERNAME_CLAIM: &str = "username";
+
+/// Bearer tokens are handed to the identity provider's token introspection
+/// endpoint (RFC 7662), as the client rensend is registered as
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OidcConfig {
+    pub introspection_url: String,
+    pub client_id: String,
+    pub client_secret_file: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,   // `iss` the token must have, default: any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>, // one of the token's `aud`, default: any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username_claim: Option<String>, // e.g. `preferred_username` or `email`, default: username
+}
+
+/// What a caller sent in its `Authorization` header
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Of the value of an `Authorization` header, None if it is neither
+    /// Basic nor Bearer or does not decode
+    pub fn parse(authorization: &str) -> Option<Self> {
+        let (scheme, value) = authorization.trim().split_once(' ')?;
+        let value = value.trim();
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => {
+                let decoded = String::from_utf8(decode_base64(value)?).ok()?;
+                let (user, password) = decoded.split_once(':')?;
+                Some(Credentials::Basic { user: user.to_string(), password: password.to_string() })
+            },
+            "bearer" if !value.is_empty() => Some(Credentials::Bearer(value.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Tells who `credentials` belong to
+pub trait Authenticator {
+    /// The user, None if the credentials are not of the kind the provider
+    /// checks. Credentials it refuses are a Trap::Auth.
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<String>, Trap>;
+}
+
+impl AuthProvider {
+    pub fn authenticator(&self) -> Result<Box<dyn Authenticator>, Trap> {
+        match (&self.pam, &self.oidc) {
+            (Some(pam), None) => Ok(Box::new(Pam { service: pam.service.clone().unwrap_or(String::from(DEFAULT_PAM_SERVICE)) })),
+            (None, Some(oidc)) => Ok(Box::new(Oidc::new(oidc))),
+            _ => Err(Trap::Config(String::from("Every control provider needs exactly one of `pam` and `oidc`"))),
+        }
+    }
+}
+
+impl ControlConfig {
+    /// The user the `Authorization` header `authorization` authenticates,
+    /// if `allow` lets them in
+    pub fn authenticate(&self, authorization: &str) -> Result<String, Trap> {
+        let credentials = Credentials::parse(authorization)
+            .ok_or(Trap::Auth(String::from("Expected Basic or Bearer credentials")))?;
+        let authenticators = self.providers.iter()
+            .map(|provider| provider.authenticator())
+            .collect::<Result<Vec<_>, Trap>>()?;
+        self.admit(&credentials, &authenticators)
+    }
+
+    fn admit(&self, credentials: &Credentials, authenticators: &[Box<dyn Authenticator>]) -> Result<String, Trap> {
+        let mut refused = None;
+        for authenticator in authenticators {
+            match authenticator.authenticate(credentials) {
+                Ok(Some(user)) => return match &self.allow {
+                    Some(allow) if !allow.contains(&user) => Err(Trap::Auth(format!("`{}` is not allowed to control rensend", user))),
+                    _ => Ok(user),
+                },
+                Ok(None) => (),
+                Err(err) => refused = Some(err),
+            }
+        }
+
+        Err(refused.unwrap_or(Trap::Auth(String::from("No control provider takes these credentials"))))
+    }
+}
+
+/// Checks user and password against a PAM stack, along with whether the
+/// account may be used at all. Needs rensend to run as root for pam_unix.
+pub struct Pam {
+    pub service: String,
+}
+
+impl Authenticator for Pam {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<String>, Trap> {
+        let Credentials::Basic { user, password } = credentials else { return Ok(None) };
+        let refused = |reason: &str| Trap::Auth(format!("PAM `{}` refused `{}`: {}", self.service, user, reason));
+
+        let library = libpam().map_err(|err| Trap::Auth(err.clone()))?;
+        let (Ok(service), Ok(c_user), Ok(c_password)) = (CString::new(self.service.as_str()), CString::new(user.as_str()), CString::new(password.as_str())) else {
+            return Err(refused("credentials hold a NUL"));
+        };
+
+        let conversation = PamConv { conv: converse, appdata_ptr: c_password.as_ptr() as *mut c_void };
+        let mut handle = std::ptr::null_mut();
+        let status = unsafe { (library.start)(service.as_ptr(), c_user.as_ptr(), &conversation, &mut handle) };
+        if status != PAM_SUCCESS {
+            return Err(refused(&format!("could not start, status {}", status)));
+        }
+
+        let mut status = unsafe { (library.authenticate)(handle, PAM_DISALLOW_NULL_AUTHTOK) };
+        if status == PAM_SUCCESS {
+            status = unsafe { (library.acct_mgmt)(handle, PAM_DISALLOW_NULL_AUTHTOK) };
+        }
+        unsafe { (library.end)(handle, status) };
+
+        match status {
+            PAM_SUCCESS => Ok(Some(user.clone())),
+            _ => Err(refused(&format!("status {}", status))),
+        }
+    }
+}
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type Conversation = unsafe extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: Conversation,
+    appdata_ptr: *mut c_void,
+}
+
+/// The functions of libpam rensend calls, looked up once. It is loaded at
+/// run time so rensend starts on systems without PAM.
+struct LibPam {
+    start: PamStart,
+    authenticate: PamCall,
+    acct_mgmt: PamCall,
+    end: PamCall,
+}
+
+type PamStart = unsafe extern "C" fn(*const c_char, *const c_char, *const PamConv, *mut *mut c_void) -> c_int;
+type PamCall = unsafe extern "C" fn(*mut c_void, c_int) -> c_int; // of the handle and flags or status
+
+fn libpam() -> Result<&'static LibPam, &'static String> {
+    static LIBPAM: OnceLock<Result<LibPam, String>> = OnceLock::new();
+    LIBPAM.get_or_init(|| unsafe {
+        let library = libc::dlopen(c"libpam.so.0".as_ptr(), libc::RTLD_NOW);
+        if library.is_null() {
+            return Err(String::from("Could not load libpam.so.0"));
+        }
+
+        let symbol = |name: &std::ffi::CStr| {
+            let symbol = libc::dlsym(library, name.as_ptr());
+            match symbol.is_null() {
+                true => Err(format!("libpam.so.0 has no {:?}", name)),
+                false => Ok(symbol),
+            }
+        };
+        Ok(LibPam {
+            start: std::mem::transmute::<*mut c_void, PamStart>(symbol(c"pam_start")?),
+            authenticate: std::mem::transmute::<*mut c_void, PamCall>(symbol(c"pam_authenticate")?),
+            acct_mgmt: std::mem::transmute::<*mut c_void, PamCall>(symbol(c"pam_acct_mgmt")?),
+            end: std::mem::transmute::<*mut c_void, PamCall>(symbol(c"pam_end")?),
+        })
+    }).as_ref()
+}
+
+/// Answers every prompt of the PAM stack with the password in `appdata`.
+/// The answers are freed by PAM, so they are allocated with malloc.
+unsafe extern "C" fn converse(count: c_int, messages: *mut *const PamMessage, responses: *mut *mut PamResponse, appdata: *mut c_void) -> c_int {
+    unsafe {
+        let answers = libc::calloc(count.max(1) as usize, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if answers.is_null() {
+            return PAM_BUF_ERR;
+        }
+
+        for index in 0..count.max(0) as usize {
+            let message = *messages.add(index);
+            if matches!((*message).msg_style, PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON) {
+                (*answers.add(index)).resp = libc::strdup(appdata as *const c_char);
+            }
+        }
+        *responses = answers;
+        PAM_SUCCESS
+    }
+}
+
+/// Checks bearer tokens with the identity provider, spoken to through curl
+pub struct Oidc {
+    pub config: OidcConfig,
+    pub curl: PathBuf, // default: `curl` from $PATH
+}
+
+/// The parts of an introspection response rensend looks at
+#[derive(Debug, Default, Deserialize)]
+struct Introspection {
+    #[serde(default)]
+    active: bool,
+    #[serde(default)]
+    iss: Option<String>,
+    #[serde(default)]
+    aud: Option<Audience>,
+    #[serde(flatten)]
+    claims: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Oidc {
+    pub fn new(config: &OidcConfig) -> Self {
+        Self { config: config.clone(), curl: PathBuf::from("curl") }
+    }
+
+    fn introspect(&self, token: &str) -> Result<Introspection, Trap> {
+        let url = &self.config.introspection_url;
+        let secret = fs::read_to_string(&self.config.client_secret_file)
+            .map_err(|err| Trap::Auth(format!("Could not read client secret file {:?}: {}", self.config.client_secret_file, err)))?;
+
+        // The client secret and token go in on stdin, never on the command line
+        let mut child = Command::new(&self.curl)
+            .args(["-sS", "-K", "-", "--max-time", "10", "-w", "\n%{http_code}"])
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| Trap::Auth(format!("Could not run {:?}: {}", self.curl, err)))?;
+        let request = format!(
+            "user = \"{}:{}\"\ndata-urlencode = \"token={}\"\ndata-urlencode = \"token_type_hint=access_token\"\n",
+            escape(&self.config.client_id), escape(secret.trim_end_matches(['\r', '\n'])), escape(token),
+        );
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(request.as_bytes());
+        }
+
+        let output = child.wait_with_output()
+            .map_err(|err| Trap::Auth(format!("Could not run {:?}: {}", self.curl, err)))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (body, status) = stdout.rsplit_once('\n').unwrap_or(("", &stdout));
+        match status.trim() {
+            "200" => serde_json::from_str(body)
+                .map_err(|err| Trap::Auth(format!("Could not parse the introspection of {}: {}", url, err))),
+            "000" | "" => Err(Trap::Auth(format!("{} got no answer: {}", url, String::from_utf8_lossy(&output.stderr).trim()))),
+            status => Err(Trap::Auth(format!("{} answered {}", url, status))),
+        }
+    }
+}
+
+impl Authenticator for Oidc {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<String>, Trap> {
+        let Credentials::Bearer(token) = credentials else { return Ok(None) };
+        let introspection = self.introspect(token)?;
+
+        if !introspection.active {
+            return Err(Trap::Auth(String::from("The token is not active")));
+        }
+        if let Some(issuer) = &self.config.issuer {
+            if introspection.iss.as_ref() != Some(issuer) {
+                return Err(Trap::Auth(format!("The token was not issued by {}", issuer)));
+            }
+        }
+        if let Some(audience) = &self.config.audience {
+            let audiences = match &introspection.aud {
+                Some(Audience::One(aud)) => vec![aud.clone()],
+                Some(Audience::Many(auds)) => auds.clone(),
+                None => Vec::new(),
+            };
+            if !audiences.contains(audience) {
+                return Err(Trap::Auth(format!("The token is not meant for {}", audience)));
+            }
+        }
+
+        let claim = self.config.username_claim.as_deref().unwrap_or(DEFAULT_USERNAME_CLAIM);
+        match introspection.claims.get(claim).and_then(|user| user.as_str()) {
+            Some(user) if !user.is_empty() => Ok(Some(user.to_string())),
+            _ => Err(Trap::Auth(format!("The token has no `{}` claim", claim))),
+        }
+    }
+}
+
+/// Standard base64, padded or not, None if `encoded` is not
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut bits, mut held) = (0u32, 0);
+    for byte in encoded.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        held += 6;
+        if held >= 8 {
+            held -= 8;
+            decoded.push((bits >> held) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[test]
+fn test_credentials() {
+    assert_eq!(decode_base64("YWxpY2U6czNjcmV0"), Some(b"alice:s3cret".to_vec()));
+    assert_eq!(decode_base64("YQ=="), Some(b"a".to_vec()));
+    assert_eq!(decode_base64("a b"), None);
+
+    assert_eq!(Credentials::parse("Basic YWxpY2U6czNjcmV0"), Some(Credentials::Basic { user: String::from("alice"), password: String::from("s3cret") }));
+    assert_eq!(Credentials::parse("bearer eyJ.abc.def"), Some(Credentials::Bearer(String::from("eyJ.abc.def"))));
+    assert_eq!(Credentials::parse("Basic YWxpY2U"), None);
+    assert_eq!(Credentials::parse("Digest username=alice"), None);
+    assert_eq!(Credentials::parse("Bearer "), None);
+}
+
+#[test]
+fn test_oidc() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = std::env::temp_dir().join("rensen_test_oidc");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("secret"), "hunter2\n").unwrap();
+
+    // Stands in for curl, introspecting the tokens `good`, `other` and `stale`
+    let curl = root.join("curl");
+    fs::write(&curl, format!(
+        "#!/bin/sh\nrequest=$(cat)\necho \"$request\" > {request:?}\ncase \"$request\" in\n\
+         *token=good*) printf '{{\"active\":true,\"iss\":\"https://sso\",\"aud\":[\"rensen\",\"web\"],\"username\":\"alice\"}}\\n200' ;;\n\
+         *token=other*) printf '{{\"active\":true,\"iss\":\"https://sso\",\"aud\":\"web\",\"username\":\"bob\"}}\\n200' ;;\n\
+         *token=stale*) printf '{{\"active\":false}}\\n200' ;;\n\
+         *) printf 'denied\\n401' ;;\nesac\n",
+        request = root.join("request"),
+    )).unwrap();
+    fs::set_permissions(&curl, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = OidcConfig {
+        introspection_url: String::from("https://sso/introspect"),
+        client_id: String::from("rensen"),
+        client_secret_file: root.join("secret"),
+        issuer: Some(String::from("https://sso")),
+        audience: Some(String::from("rensen")),
+        ..Default::default()
+    };
+    let oidc = Oidc { config: config.clone(), curl: curl.clone() };
+    let bearer = |token: &str| Credentials::Bearer(token.to_string());
+
+    assert_eq!(oidc.authenticate(&bearer("good")).unwrap(), Some(String::from("alice")));
+    let request = fs::read_to_string(root.join("request")).unwrap();
+    assert!(request.starts_with("user = \"rensen:hunter2\"\ndata-urlencode = \"token=good\"\n"));
+    assert!(matches!(oidc.authenticate(&bearer("other")), Err(Trap::Auth(msg)) if msg == "The token is not meant for rensen"));
+    assert!(matches!(oidc.authenticate(&bearer("stale")), Err(Trap::Auth(msg)) if msg == "The token is not active"));
+    assert!(matches!(oidc.authenticate(&bearer("forged")), Err(Trap::Auth(msg)) if msg.ends_with("answered 401")));
+
+    // Basic credentials are left to the other providers
+    let basic = Credentials::Basic { user: String::from("alice"), password: String::from("s3cret") };
+    assert_eq!(oidc.authenticate(&basic).unwrap(), None);
+    let oidc = Oidc { config: OidcConfig { username_claim: Some(String::from("email")), ..config.clone() }, curl: curl.clone() };
+    assert!(matches!(oidc.authenticate(&bearer("good")), Err(Trap::Auth(msg)) if msg == "The token has no `email` claim"));
+
+    // Only the allowed users get in, and not without a provider for their credentials
+    let control = ControlConfig { allow: Some(vec![String::from("bob")]), ..Default::default() };
+    let authenticators: Vec<Box<dyn Authenticator>> = vec![Box::new(oidc), Box::new(Oidc { config: OidcConfig { audience: None, ..config }, curl })];
+    assert_eq!(control.admit(&bearer("other"), &authenticators).unwrap(), "bob");
+    assert!(matches!(control.admit(&bearer("good"), &authenticators), Err(Trap::Auth(msg)) if msg == "`alice` is not allowed to control rensend"));
+    assert!(matches!(control.admit(&basic, &authenticators), Err(Trap::Auth(msg)) if msg == "No control provider takes these credentials"));
+    assert!(control.authenticate("Token abc").is_err());
+    assert!(AuthProvider::default().authenticator().is_err());
+    let _ = fs::remove_dir_all(&root);
+}
@@ -1,20 +1,65 @@
 pub mod rsync {
     use std::fs;
-    use std::io::{self, stdout, Write, Read};
-    use std::net::TcpStream;
+    use std::io::{self, Write, Read, Seek, SeekFrom};
+    use std::net::{TcpStream, SocketAddr, ToSocketAddrs};
+    use std::sync::mpsc;
+    use std::thread;
     use ssh2::{Session, FileStat};
-    use std::time::SystemTime;
+    use std::time::{SystemTime, Duration, Instant};
     use std::path::{Path, PathBuf}; 
     use std::ffi::OsStr;
     use console::Style;
     use std::rc::Rc;
+    use std::cell::{Cell, RefCell};
+    use std::collections::{BTreeSet, HashMap};
+    use fxhash::FxHashMap;
 
     use crate::traits::*;
-    use crate::logging::Trap;
+    use crate::logging::{Trap, log_host_trap};
     use crate::config::*;
-    use crate::utils::{make_tar_gz, set_metadata, get_datetime, get_file_sz};
+    use crate::utils::{write_tar_gz, ArchiveOptions, set_metadata, get_datetime, hash_contents, sample_digest, digest_reader};
     use crate::record::Record;
-    use crate::snapshot::{PathPair, FileEntry, Snapshot};
+    use crate::snapshot::{PathPair, FileEntry};
+    use crate::sla::SlaWatch;
+    use crate::history::RunOutcome;
+    use crate::results::BackupReport;
+    use crate::bootable;
+    use crate::quota::{DiskUsage, parse_df, source_nearly_full, inode_usage, count_inodes, inodes_nearly_exhausted};
+    use crate::notify::alert;
+    use crate::quiesce::freeze_all;
+    use crate::hooks::{run_hook, run_finalize_hook, FinalizeStats, Finalizer, HookFailure};
+    use crate::filter::Filter;
+    use crate::compact::compact_records;
+    use crate::plan::{plan_prune, plan_gc, Step};
+    use crate::journal::Journal;
+    use crate::index::warm_index;
+    use crate::helper::{Helper, quote};
+    use crate::keys::key_passphrase;
+    use crate::credentials::{KeyStamp, KeyStamps};
+    use crate::retire::Retirement;
+    use crate::store::LocalStore;
+    use crate::resume::{Partial, RESUME_CHECKPOINT};
+    use crate::rsyncd::RsyncdConfig;
+    use crate::incremental::{compare, plan_transfer, ChangeDetection, Verdict};
+    use crate::elevate::{is_elevated, list_command, read_command, read_digest, parse_list};
+    use crate::delta::{basis, block_hashes, reconstruct, DELTA_BLOCK};
+    use crate::preflight::{self, check_command, parse_problems};
+    use crate::progress::{Progress, ProgressSink, PROGRESS_BYTES};
+    use crate::usage::ResourceUsage;
+    use crate::phases::{Phases, ms_since};
+    use crate::histogram::Histogram;
+    use crate::remote::RemoteOs;
+    use crate::cancel::Cancel;
+    use crate::chunks::ChunkStore;
+    use crate::checksum::{verify_manifest_by, Algorithm, ChecksumLog};
+    use crate::mirror::mirror_snapshot;
+    use crate::drift::ConfigFingerprint;
+    use crate::encrypt::fetch_encrypted;
+    use crate::state::{capture_state, STATE_DIR};
+    use crate::annotate::Annotations;
+    use crate::special::{link_targets, SpecialEntry, SpecialKind};
+    use crate::sparse::SparseWriter;
+    use crate::listing::{stat_command, hash_command, parse_listing, write_listing, LISTING_FILE};
 
     pub struct Sftp<'a> {
         
@@ -25,8 +70,33 @@ pub mod rsync {
         pub sess: Option<Session>,
         pub incremental: bool,
         pub debug: bool,
+        pub sla: Option<SlaWatch>,
+        pub bytes_transferred: Cell<u64>,
+        pub files_transferred: Cell<u64>,
+        pub skipped: RefCell<Vec<PathBuf>>,
+        pub source_usage: Option<DiskUsage>,
+        pub helper: Option<Helper>,
+        pub notices: Vec<String>,
+        pub destination_inodes: Option<u64>,
+        pub store: Box<dyn Store>,     // where archives go, default: a LocalStore at `backups`
+        pub finalize: Option<Finalizer>,               // vetoes the snapshot with Err, like `finalize_cmd`
+        pub progress: Option<Box<dyn ProgressSink>>, // told as files are fetched, takes the place of the line per file
+        pub cancel: Option<Cancel>,                  // set from another thread to stop the run, see Cancel
 
         /* Private */
+        warnings: Vec<String>,
+        journal: RefCell<Option<Journal>>,
+        partial: RefCell<Option<Partial>>,
+        listed: Option<BTreeSet<PathBuf>>, // what rsyncd listed, for sources with no sftp to stat on
+        elevated: RefCell<BTreeSet<PathBuf>>, // what sudo listed below the `sudo` paths
+        specials: RefCell<FxHashMap<PathBuf, SpecialEntry>>, // symlinks and the like found so far, by source path
+        links: HashMap<PathBuf, PathBuf>,  // further hard links below the source, to the one of each file that is fetched
+        devices: HashMap<PathBuf, u64>,    // device numbers of the device nodes below the source
+        files_seen: Cell<u64>,             // fetched or skipped so far, for `progress`
+        expected: (Option<u64>, Option<u64>), // files and bytes this run is likely to go through
+        filter: Filter,                    // `exclude` and `include` of the host
+        usage: Option<ResourceUsage>,      // counters of the thread when the run started
+        phases: Cell<Phases>,              // of this run so far
         host_root_path: Option<PathBuf>,
         snapshot_root_path: Option<PathBuf>,
         complete_destination: Option<PathBuf>,
@@ -42,7 +112,32 @@ pub mod rsync {
                 sess: None,
                 incremental: false,
                 debug,
-
+                sla: None,
+                bytes_transferred: Cell::new(0),
+                files_transferred: Cell::new(0),
+                skipped: RefCell::new(Vec::new()),
+                source_usage: None,
+                helper: None,
+                notices: Vec::new(),
+                destination_inodes: None,
+                store: Box::new(LocalStore::new(&global_config.backups)),
+                finalize: None,
+                progress: None,
+                cancel: None,
+
+                warnings: Vec::new(),
+                journal: RefCell::new(None),
+                partial: RefCell::new(None),
+                listed: None,
+                elevated: RefCell::new(BTreeSet::new()),
+                specials: RefCell::new(FxHashMap::default()),
+                links: HashMap::new(),
+                devices: HashMap::new(),
+                files_seen: Cell::new(0),
+                expected: (None, None),
+                filter: Filter::new(host_config),
+                usage: None,
+                phases: Cell::new(Phases::default()),
                 host_root_path: None,
                 snapshot_root_path: None,
                 complete_destination: None,
@@ -51,7 +146,7 @@ pub mod rsync {
         }
 
         pub fn debug(&self, s: &str) -> Result<(), Trap> {
-            if self.debug == true {
+            if self.debug {
                 print!("{}", s);
                 io::stdout()
                     .flush()
@@ -63,6 +158,825 @@ pub mod rsync {
             Ok(())
         }
 
+        /// Archives the unpacked `snapshot_path` into the store as its .tar.gz,
+        /// or what the codec of the host names it, then removes it
+        fn archive(&self, snapshot_path: &Path) -> Result<(), Trap> {
+            let options = ArchiveOptions::for_host(self.global_config, self.host_config);
+            let key = snapshot_path.strip_prefix(&self.global_config.backups)
+                .map(|path| options.codec.archive(path))
+                .map_err(|_| Trap::FS(format!("Snapshot {:?} is not below {:?}", snapshot_path, self.global_config.backups)))?;
+
+            let mut writer = self.store.put(&key)?;
+            write_tar_gz(snapshot_path, &mut writer, &options)
+                .and_then(|_| writer.flush())
+                .map_err(|err| Trap::FS(format!("Could not archive {:?}: {}", snapshot_path, err)))?;
+            drop(writer);
+
+            self.store.finalize(&key)?;
+            let _ = fs::remove_dir_all(snapshot_path);
+            Ok(())
+        }
+
+        /// Summarizes a finished run for the history
+        pub fn outcome(&self, hostname: &str, started: i64, finished: i64, result: &Result<BackupReport, Trap>) -> RunOutcome {
+            RunOutcome {
+                hostname: hostname.to_string(),
+                started,
+                finished,
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|err| err.to_string()),
+                bytes: self.bytes_transferred.get(),
+                files: self.files_transferred.get(),
+                size: self.record.size,
+                source: self.source_usage,
+                notices: self.notices.clone(),
+                destination_inodes: self.destination_inodes,
+                usage: self.usage.and_then(|start| Some(ResourceUsage::thread()?.since(&start))),
+                phases: Some(self.phases.get()).filter(|phases| *phases != Phases::default()),
+            }
+        }
+
+        /// Runs `command` on the host and returns what it wrote to stdout
+        pub fn exec(&self, command: &str) -> Result<String, Trap> {
+            let sess = self.sess.as_ref().ok_or(Trap::Session(String::from("Session unavailable")))?;
+            let mut channel = sess.channel_session().map_err(|err| {
+                Trap::Channel(format!("Could not open channel: {}", err))
+            })?;
+
+            channel.exec(command).map_err(|err| {
+                Trap::Channel(format!("Could not execute `{}`: {}", command, err))
+            })?;
+
+            let mut output = String::new();
+            channel.read_to_string(&mut output).map_err(|err| {
+                Trap::Channel(format!("Could not read output of `{}`: {}", command, err))
+            })?;
+            let _ = channel.wait_close();
+
+            match channel.exit_status() {
+                Ok(0) => Ok(output),
+                Ok(status) => Err(Trap::Channel(format!("`{}` exited with status {}", command, status))),
+                Err(err) => Err(Trap::Channel(format!("Could not get exit status of `{}`: {}", command, err))),
+            }
+        }
+
+        /// Authenticates with the password of the host's user instead of the
+        /// key, for installing a key on hosts that do not have one yet
+        pub fn auth_password(&mut self, password: &str) -> Result<(), Trap> {
+            let session = self.sess.as_ref().ok_or(Trap::Auth(String::from("Sessions is None")))?;
+            session.userauth_password(&self.host_config.user, password)
+                .map_err(|err| Trap::Auth(format!("Could not authenticate with password: {}", err)))
+        }
+
+        /// Usage of the filesystem the host's source lives on, through `df`
+        pub fn remote_disk_usage(&self) -> Result<DiskUsage, Trap> {
+            let command = format!("df -Pk '{}'", self.host_config.source.display().to_string().replace('\'', "'\\''"));
+            let output = self.exec(&command)?;
+            parse_df(&output).ok_or(Trap::Source(format!("Could not parse output of `{}`", command)))
+        }
+
+        /// Finds or deploys the helper on hosts with `helper` set. Without one
+        /// the backup carries on over pure sftp.
+        fn negotiate_helper(&mut self) {
+            let sess = match self.sess.as_ref() {
+                Some(sess) => sess,
+                None => return,
+            };
+
+            match Helper::negotiate(self.global_config, self.host_config, sess) {
+                Ok(helper) => {
+                    if let Some(helper) = &helper {
+                        let _ = self.debug(&format!("Using rensen-helper {}\n", helper.version));
+                    }
+                    self.helper = helper;
+                },
+                Err(err) => {
+                    log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                    self.warnings.push(format!("{}, falling back to sftp", err));
+                },
+            }
+        }
+
+        /// Reads a (small) file from the host in full
+        fn read_remote(&self, remote_file: &Path) -> Result<String, Trap> {
+            let sftp = self.sess.as_ref().ok_or(Trap::FS(String::from("Session unavailable")))?.sftp().map_err(|err| {
+                Trap::Session(format!("Could not init SFTP session: {}", err))
+            })?;
+
+            let mut contents = String::new();
+            let mut file = sftp.open(remote_file).map_err(|err| {
+                Trap::Checksum(format!("Could not open {:?} on `{}`: {}", remote_file, self.host_config.identifier, err))
+            })?;
+            file.read_to_string(&mut contents).map_err(|err| {
+                Trap::Checksum(format!("Could not read {:?} on `{}`: {}", remote_file, self.host_config.identifier, err))
+            })?;
+
+            Ok(contents)
+        }
+
+        /// Checks the files fetched by this run against the host's `checksums`
+        /// manifests, as they are on the source. Mismatches are alerted on and
+        /// kept in the checksum log, files not fetched were checked before.
+        fn check_checksums(&mut self) {
+            let manifests = match &self.host_config.checksums {
+                Some(manifests) if !manifests.is_empty() => manifests,
+                _ => return,
+            };
+
+            // What was fetched is encrypted, it hashes to something else entirely
+            if self.host_config.encrypt_key.is_some() {
+                self.notices.push(String::from("Checksums are not checked for hosts with `encrypt_key`"));
+                return;
+            }
+
+            let snapshot_root = self.snapshot_root_path.clone().unwrap();
+            let entries = &self.record.snapshot.entries;
+            let store = ChunkStore::new(self.global_config);
+
+            // Files of `dedup` hosts are emptied into the chunk store, their
+            // SHA3-256 is in the record and anything else is read from their chunks
+            let digest = |file: &Path, algorithm: Algorithm| {
+                let entry = entries.get(file).filter(|entry| entry.snapshot_path == snapshot_root)?;
+                match (&entry.chunks, &entry.hash) {
+                    (Some(_), Some(hash)) if algorithm == Algorithm::Sha3_256 => Some(Some(hash.clone())),
+                    (Some(chunks), _) => Some(algorithm.digest_with(|mut hasher| {
+                        chunks.iter().try_for_each(|chunk| store.read(chunk, &mut hasher).map(|_| ()).map_err(|err| io::Error::other(err.to_string())))
+                    }).ok()),
+                    (None, _) => Some(algorithm.digest(&entry.file_path).ok()),
+                }
+            };
+
+            let mut mismatches = Vec::new();
+            for manifest in manifests {
+                match self.read_remote(manifest) {
+                    Ok(contents) => mismatches.extend(verify_manifest_by(manifest, &contents, false, digest)),
+                    Err(err) => {
+                        log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                        self.warnings.push(err.to_string());
+                    },
+                }
+            }
+
+            if mismatches.is_empty() {
+                return;
+            }
+
+            let details: Vec<String> = mismatches.iter().map(|mismatch| mismatch.to_string()).collect();
+            let trap = Trap::Checksum(format!(
+                "{} files of `{}` do not match their checksums: {}",
+                mismatches.len(), self.host_config.identifier, details.join("; ")
+            ));
+            alert(self.global_config, &self.host_config.identifier, &trap);
+            self.warnings.push(trap.to_string());
+
+            let snapshot = snapshot_root.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            if let Err(err) = ChecksumLog::record(self.global_config, self.host_config, &snapshot, &mismatches) {
+                log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                self.warnings.push(err.to_string());
+            }
+        }
+
+        /// Captures packages, services, crontabs and firewall rules of hosts
+        /// with `system_state` into the snapshot, for rebuilding them from
+        /// scratch. What the host lacks is noted, the files are what matter.
+        fn capture_system_state(&mut self) {
+            if !self.host_config.system_state.unwrap_or(false) {
+                return;
+            }
+
+            let _ = self.debug("Capturing system state... ");
+            let directory = self.snapshot_root_path.clone().unwrap().join(STATE_DIR);
+            match capture_state(|command| self.exec(command), &directory) {
+                Ok((_, missing)) => self.notices.extend(missing),
+                Err(err) => {
+                    log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                    self.warnings.push(err.to_string());
+                },
+            }
+            let _ = self.debug("Done\n");
+        }
+
+        /// Notes the settings edited since the previous snapshot, and stamps
+        /// the current ones into the record the snapshot is written with
+        fn check_config_drift(&mut self) {
+            let current = ConfigFingerprint::of(self.host_config);
+            if let Some(previous) = &self.record.config {
+                let changes = previous.changes(&current);
+                if !changes.is_empty() {
+                    self.notices.push(format!("Config changed since the last snapshot: {}", changes.join(", ")));
+                }
+            }
+
+            self.record.config = Some(current);
+        }
+
+        /// Records the inodes the host takes up at the destination, and alerts
+        /// if the destination is running out of them
+        fn check_destination_inodes(&mut self) {
+            let held = count_inodes(&self.global_config.backups.join(&self.host_config.identifier));
+            self.destination_inodes = Some(held);
+
+            let usage = match inode_usage(&self.global_config.backups) {
+                Ok(usage) => usage,
+                Err(err) => {
+                    log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                    return;
+                }
+            };
+
+            let unpacked = plan_gc(self.global_config, self.host_config)
+                .map(|plan| plan.steps.iter().filter(|step| matches!(step, Step::Remove { reason: "unpacked, archive exists", .. })).count())
+                .unwrap_or(0);
+
+            if let Some(trap) = inodes_nearly_exhausted(self.global_config, &self.host_config.identifier, &usage, held, unpacked) {
+                alert(self.global_config, &self.host_config.identifier, &trap);
+                self.warnings.push(trap.to_string());
+            }
+        }
+
+        /// Lists the files below `source` with their size, mode, owner, mtime
+        /// and hash into the snapshot, for `metadata_only` sources whose
+        /// contents are kept elsewhere. Nothing is fetched.
+        fn list_remote_directory(&mut self, source: &Path) -> Result<(), Trap> {
+            let _ = self.debug("Listing files on host... ");
+            let stats = self.exec(&stat_command(source))?;
+            let hashes = self.exec(&hash_command(source))?;
+            let mut files = parse_listing(&stats, &hashes);
+            files.retain(|file| !self.filter.excludes_within(Path::new(&file.path).strip_prefix(source).unwrap_or(Path::new(&file.path))));
+
+            let snapshot_root = self.snapshot_root_path.clone().unwrap();
+            fs::create_dir_all(&snapshot_root).map_err(|err| {
+                Trap::FS(format!("Could not create directory {:?}: {}", snapshot_root, err))
+            })?;
+            write_listing(&files, &snapshot_root.join(LISTING_FILE))?;
+            let _ = self.debug("Done\n");
+
+            let unhashed = files.iter().filter(|file| file.sha256.is_none()).count();
+            self.notices.push(format!(
+                "Listed {} files ({} bytes) of {:?} without their contents, {} could not be hashed",
+                files.len(), files.iter().map(|file| file.size).sum::<u64>(), source, unhashed
+            ));
+
+            Ok(())
+        }
+
+        /// Like copy_remote_file, with the contents encrypted on the host by
+        /// `openssl enc` before they are sent, see `encrypt_key`
+        fn copy_remote_file_encrypted(&self, key: &Path, source: &Path, destination: &Path) -> Result<(), Trap> {
+            let mut file = fs::File::create(destination).map_err(|err| {
+                Trap::FS(format!("Could not create file: {}\nCheck permissions!", err))
+            })?;
+
+            if self.progress.is_none() {
+                print!("{} {}@{}:{:?} (encrypted) ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), self.host_config.user, self.host_config.identifier, source);
+            }
+            let start = Instant::now();
+            let size = fetch_encrypted(self.sess.as_ref().unwrap(), key, source, &mut file)?;
+            self.add_phase(start, |phases| &mut phases.transfer_ms);
+            if self.progress.is_none() {
+                println!("Done");
+            }
+
+            self.bytes_transferred.set(self.bytes_transferred.get() + size);
+            self.files_transferred.set(self.files_transferred.get() + 1);
+
+            if let Some(watch) = &self.sla {
+                watch.check(self.global_config, &self.host_config.identifier, self.bytes_transferred.get());
+            }
+
+            let stat = self.remote_filestat(source)?;
+            let mtime = stat.mtime.unwrap_or(0);
+            let (uid, gid) = (stat.uid, stat.gid);
+            let _ = set_metadata(&mut file, stat);
+
+            let mut entry = FileEntry { uid, gid, ..FileEntry::from(destination.to_path_buf(), self.snapshot_root_path.clone().unwrap(), mtime, size) };
+            entry.hash = Some(hash_contents(&entry.file_path)?);
+            self.dedup(&mut entry)?;
+            if let Some(journal) = self.journal.borrow_mut().as_mut() {
+                journal.append(source, &entry)?;
+            }
+
+            Ok(())
+        }
+
+        /// Copies `source`, a file or a directory below one of the `sudo` paths,
+        /// listed by `find` and read by `cat` through `sudo -n` on exec
+        /// channels, since sftp runs as the user and can not get at it.
+        fn copy_elevated(&self, source: &Path, destination: &Path) -> Result<(), Trap> {
+            if self.host_config.encrypt_key.is_some() {
+                return Err(Trap::Config(String::from("`sudo` paths can not be fetched with `encrypt_key`")));
+            }
+
+            for (path, stat) in parse_list(&self.exec(&list_command(source))?) {
+                self.cancelled()?;
+                let relative = path.strip_prefix(&self.host_config.source).unwrap_or(&path);
+                if self.filter.excludes_within(relative) {
+                    continue;
+                }
+                self.elevated.borrow_mut().insert(path.clone());
+
+                let file_destination = match path.strip_prefix(source) {
+                    Ok(below) if !below.as_os_str().is_empty() => destination.join(below),
+                    _ => destination.to_path_buf(),
+                };
+                if let Some(parent) = file_destination.parent() {
+                    fs::create_dir_all(parent).map_err(|err| {
+                        Trap::FS(format!("Could not create directory: {}", err))
+                    })?;
+                }
+
+                self.files_seen.set(self.files_seen.get() + 1);
+                self.report_progress(&path);
+                if self.incremental {
+                    // Compared like files read over sftp, hashed through sudo as well
+                    let (mtime, size) = (stat.mtime.unwrap_or(u64::MAX), stat.size.unwrap_or(0));
+                    let recorded = self.record.snapshot.entries.get(&path);
+                    let verdict = compare(self.host_config.change_detection(), recorded, mtime, size, || self.elevated_digest(&path, size));
+                    if verdict != Verdict::Fetch {
+                        let chowned = recorded.is_some_and(|entry| (entry.uid, entry.gid) != (stat.uid, stat.gid));
+                        if verdict == Verdict::Touch || chowned {
+                            let entry = FileEntry { mtime, uid: stat.uid, gid: stat.gid, ..recorded.unwrap().clone() };
+                            if let Some(journal) = self.journal.borrow_mut().as_mut() {
+                                journal.append(&path, &entry)?;
+                            }
+                        }
+                        if self.progress.is_none() {
+                            println!("{} {}@{}:{:?}", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Skipping")), self.host_config.user, self.host_config.identifier, path);
+                        }
+                        continue;
+                    }
+                }
+
+                if let Err(err) = self.copy_elevated_file(&path, &file_destination, stat) {
+                    self.cancelled()?;
+                    self.skipped.borrow_mut().push(path.clone());
+                    println!("{} Could not receive file through sudo: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Copies the file at `source` as listed by copy_elevated. Not resumed
+        /// when cut off, the channel can not start at an offset.
+        fn copy_elevated_file(&self, source: &Path, destination: &Path, stat: FileStat) -> Result<(), Trap> {
+            let command = read_command(source);
+            let mut channel = self.sess.as_ref().unwrap().channel_session().map_err(|err| {
+                Trap::Channel(format!("Could not open channel: {}", err))
+            })?;
+            channel.exec(&command).map_err(|err| {
+                Trap::Channel(format!("Could not execute `{}`: {}", command, err))
+            })?;
+
+            let mut file = fs::File::create(destination).map_err(|err| {
+                Trap::FS(format!("Could not create file: {}\nCheck permissions!", err))
+            })?;
+
+            if self.progress.is_none() {
+                print!("{} {}@{}:{:?} (sudo) ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), self.host_config.user, self.host_config.identifier, source);
+            }
+            let size = self.receive(&mut channel, &mut file, source, destination, &stat, 0)?;
+            let _ = channel.wait_close();
+            match channel.exit_status() {
+                Ok(0) => (),
+                Ok(status) => return Err(Trap::Channel(format!("`{}` exited with status {}", command, status))),
+                Err(err) => return Err(Trap::Channel(format!("Could not get exit status of `{}`: {}", command, err))),
+            }
+            if self.progress.is_none() {
+                println!("Done");
+            }
+            self.files_transferred.set(self.files_transferred.get() + 1);
+
+            if let Some(watch) = &self.sla {
+                watch.check(self.global_config, &self.host_config.identifier, self.bytes_transferred.get());
+            }
+
+            let mtime = stat.mtime.unwrap_or(0);
+            let (uid, gid) = (stat.uid, stat.gid);
+            let _ = set_metadata(&mut file, stat);
+
+            let mut entry = FileEntry { uid, gid, ..FileEntry::from(destination.to_path_buf(), self.snapshot_root_path.clone().unwrap(), mtime, size) };
+            entry.hash = Some(hash_contents(&entry.file_path)?);
+            if self.host_config.change_detection() == ChangeDetection::Sampled {
+                entry.sample = fs::File::open(&entry.file_path).and_then(|mut file| sample_digest(&mut file, size)).ok();
+            }
+            self.dedup(&mut entry)?;
+            if let Some(journal) = self.journal.borrow_mut().as_mut() {
+                journal.append(source, &entry)?;
+            }
+
+            Ok(())
+        }
+
+        /// The hash of `source`, `size` bytes on the host that sftp can not
+        /// read, that `change_detection` compares with the record. Read
+        /// through sudo like the file itself, None if that failed.
+        fn elevated_digest(&self, source: &Path, size: u64) -> Option<String> {
+            let mut channel = self.sess.as_ref()?.channel_session().ok()?;
+            channel.exec(&read_command(source)).ok()?;
+            let digest = read_digest(self.host_config.change_detection(), &mut channel, size).ok();
+            let _ = channel.wait_close();
+            digest.filter(|_| channel.exit_status().is_ok_and(|status| status == 0))
+        }
+
+        /// Adds the time since `start` to one of the phases of this run
+        fn add_phase(&self, start: Instant, phase: fn(&mut Phases) -> &mut u64) {
+            let mut phases = self.phases.get();
+            *phase(&mut phases) += ms_since(start);
+            self.phases.set(phases);
+        }
+
+        /// Fetches only the blocks of `source` that changed since the copy the
+        /// record has, for files of at least `delta_min_size` on hosts with the
+        /// helper. None if that does not apply, or the delta did not add up to
+        /// the file on the host, which is then fetched whole.
+        fn fetch_delta(&self, source: &Path, destination: &Path, stat: &FileStat) -> Option<(fs::File, u64)> {
+            let (helper, min_size) = (self.helper.as_ref()?, self.host_config.delta_min_size?);
+            let remote_size = stat.size.unwrap_or(0);
+            if remote_size < min_size * 1024 * 1024 {
+                return None;
+            }
+
+            let previous = self.record.snapshot.entries.get(source)?;
+            let scratch = destination.with_extension("rensen-basis");
+            let result = self.fetch_delta_from(helper, source, destination, &scratch, previous, remote_size);
+            let _ = fs::remove_file(&scratch);
+
+            match result {
+                Ok(result) => Some(result),
+                Err(err) => {
+                    let _ = self.debug(&format!("Fetching all of {:?}, the delta did not work out: {}\n", source, err));
+                    None
+                },
+            }
+        }
+
+        fn fetch_delta_from(&self, helper: &Helper, source: &Path, destination: &Path, scratch: &Path, previous: &FileEntry, remote_size: u64) -> Result<(fs::File, u64), Trap> {
+            let sess = self.sess.as_ref().unwrap();
+            let mut basis = basis(self.global_config, previous, scratch)
+                .ok_or(Trap::FS(format!("No copy of {:?} from before at hand", source)))?;
+            let local = block_hashes(&mut basis, DELTA_BLOCK)
+                .map_err(|err| Trap::FS(format!("Could not hash the copy of {:?} from before: {}", source, err)))?;
+            let remote = helper.blocks(sess, source, DELTA_BLOCK)?;
+
+            let sftp = sess.sftp().map_err(|err| {
+                Trap::Session(format!("Could not init SFTP session: {}", err))
+            })?;
+            let mut remote_file = sftp.open(source).map_err(|err| {
+                Trap::Copy(format!("Could not open remote file: {}", err))
+            })?;
+            let fetch = |offset: u64, len: u64| {
+                remote_file.seek(SeekFrom::Start(offset))?;
+                let mut block = Vec::with_capacity(len as usize);
+                (&mut remote_file).take(len).read_to_end(&mut block)?;
+                Ok(block)
+            };
+
+            let mut file = fs::File::create(destination).map_err(|err| {
+                Trap::FS(format!("Could not create file: {}\nCheck permissions!", err))
+            })?;
+            let start = Instant::now();
+            let fetched = reconstruct(&mut basis, &local, &remote, DELTA_BLOCK, remote_size, fetch, &mut file)
+                .map_err(|err| Trap::Copy(format!("Could not fetch the changed blocks: {}", err)))?;
+            self.add_phase(start, |phases| &mut phases.transfer_ms);
+            self.bytes_transferred.set(self.bytes_transferred.get() + fetched);
+            self.report_progress(source);
+
+            // The file may have changed between hashing and fetching its blocks
+            let expected = helper.sha3(sess, &[source.to_path_buf()])?;
+            if expected.first().map(|(_, hash)| hash) != Some(&hash_contents(destination)?) {
+                return Err(Trap::Copy(String::from("The file changed while its blocks were fetched")));
+            }
+            if self.progress.is_none() {
+                print!("{} {}@{}:{:?} (delta, {} of {} bytes) ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), self.host_config.user, self.host_config.identifier, source, fetched, remote_size);
+            }
+
+            Ok((file, remote_size))
+        }
+
+        /// Reads the rest of a remote file from `reader` into `file`, which
+        /// holds the first `offset` bytes of it already. Large files are
+        /// checkpointed as they come in, so a transfer that is cut off can be
+        /// resumed. Returns the size of the file.
+        fn receive(&self, reader: &mut dyn Read, file: &mut fs::File, source: &Path, destination: &Path, stat: &FileStat, offset: u64) -> Result<u64, Trap> {
+            let (mtime, remote_size) = (stat.mtime.unwrap_or(0), stat.size.unwrap_or(0));
+            let mut size = offset;
+            let mut checkpoint = (offset / RESUME_CHECKPOINT + 1) * RESUME_CHECKPOINT;
+            let mut buffer = [0; 4096];
+            let start = Instant::now();
+            // Blocks of zeros, e.g. of VM disks, stay holes in the snapshot
+            let mut writer = SparseWriter::new(file)
+                .map_err(|err| Trap::FS(format!("Could not write to file: {}", err)))?;
+            loop {
+                self.cancelled()?;
+                match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        writer.write_all(&buffer[..n]).map_err(|err| {
+                            Trap::FS(format!("Could not write to file: {}", err))
+                        })?;
+                        self.bytes_transferred.set(self.bytes_transferred.get() + n as u64);
+                        if (size + n as u64) / PROGRESS_BYTES > size / PROGRESS_BYTES {
+                            self.report_progress(source);
+                        }
+                        size += n as u64;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        return Err(Trap::Channel(format!("Could not read from channel: {}", err)));
+                    }
+                }
+
+                if size >= checkpoint && size < remote_size {
+                    writer.flush()
+                        .map_err(|err| Trap::FS(format!("Could not write to file: {}", err)))?;
+                    Partial::checkpoint(self.global_config, self.host_config, source, destination, mtime, remote_size, size)?;
+                    checkpoint = (size / RESUME_CHECKPOINT + 1) * RESUME_CHECKPOINT;
+                }
+            }
+            writer.finish()
+                .map_err(|err| Trap::FS(format!("Could not write to file: {}", err)))?;
+
+            if size >= RESUME_CHECKPOINT {
+                Partial::clear(self.global_config, self.host_config)?;
+            }
+
+            self.add_phase(start, |phases| &mut phases.transfer_ms);
+            Ok(size)
+        }
+
+        /// Moves the contents of the file of `entry`, just fetched, into the
+        /// chunk store for hosts with `dedup`. The file is left empty in the
+        /// snapshot, keeping its place and mode.
+        fn dedup(&self, entry: &mut FileEntry) -> Result<(), Trap> {
+            if !self.host_config.dedup.unwrap_or(false) {
+                return Ok(());
+            }
+
+            let chunks = ChunkStore::new(self.global_config).store_file(&entry.file_path)?;
+            fs::OpenOptions::new().write(true).open(&entry.file_path)
+                .and_then(|file| file.set_len(0))
+                .map_err(|err| Trap::FS(format!("Could not empty {:?}: {}", entry.file_path, err)))?;
+            entry.chunks = Some(chunks);
+            Ok(())
+        }
+
+        /// Runs a `pre_backup_cmd` or `post_backup_cmd` on the host. With
+        /// `hook_failure: continue` a failure only becomes a warning.
+        fn run_hook(&mut self, command: Option<&str>) -> Result<(), Trap> {
+            let Some(command) = command else { return Ok(()) };
+
+            self.debug(&format!("Running `{}`... ", command))?;
+            match run_hook(self.sess.as_ref().unwrap(), command, self.host_config.hook_timeout()) {
+                Ok(()) => self.debug("Done\n"),
+                Err(err) if self.host_config.hook_failure.unwrap_or_default() == HookFailure::Continue => {
+                    alert(self.global_config, &self.host_config.identifier, &err);
+                    self.warnings.push(err.to_string());
+                    Ok(())
+                },
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Checks that the paths the run reads are there and readable by the
+        /// user, all of them before anything is transferred
+        fn preflight(&self) -> Result<(), Trap> {
+            let paths = preflight::paths(self.host_config);
+            let output = match self.exec(&check_command(&paths)) {
+                Ok(output) => output,
+                // Hosts without a shell find out file by file
+                Err(err) => {
+                    let _ = self.debug(&format!("Skipping the preflight: {}\n", err));
+                    return Ok(());
+                },
+            };
+
+            let problems = parse_problems(&output, &paths);
+            match problems.is_empty() {
+                true => Ok(()),
+                false => Err(preflight::report(self.host_config, &problems)),
+            }
+        }
+
+        /// Errors with the settings of a host behind rsyncd that need a shell
+        /// on it, which rsyncd does not give
+        fn check_rsyncd_options(&self) -> Result<(), Trap> {
+            let host_config = self.host_config;
+            let unsupported: Vec<&str> = [
+                ("quiesce", host_config.quiesce.is_some()),
+                ("pre_backup_cmd", host_config.pre_backup_cmd.is_some()),
+                ("post_backup_cmd", host_config.post_backup_cmd.is_some()),
+                ("system_state", host_config.system_state.unwrap_or(false)),
+                ("checksums", host_config.checksums.is_some()),
+                ("encrypt_key", host_config.encrypt_key.is_some()),
+                ("sudo", host_config.sudo.is_some()),
+                ("metadata_only", host_config.metadata_only.unwrap_or(false)),
+                ("helper", host_config.helper.unwrap_or(false)),
+            ].into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect();
+
+            match unsupported.is_empty() {
+                true => Ok(()),
+                false => Err(Trap::Config(format!("`{}` is served by rsyncd, which has no shell for {}", host_config.identifier, unsupported.join(", ")))),
+            }
+        }
+
+        /// Errors with the settings of a Windows host that need a POSIX shell
+        /// or sudo on it. Hooks and `command` quiesce run in its own shell.
+        fn check_windows_options(&self) -> Result<(), Trap> {
+            let host_config = self.host_config;
+            let unsupported: Vec<&str> = [
+                ("quiesce", host_config.quiesce.iter().flatten().any(|quiesce| quiesce.method != "command")),
+                ("system_state", host_config.system_state.unwrap_or(false)),
+                ("system_backup", host_config.system_backup.unwrap_or(false)),
+                ("encrypt_key", host_config.encrypt_key.is_some()),
+                ("sudo", host_config.sudo.is_some()),
+                ("metadata_only", host_config.metadata_only.unwrap_or(false)),
+                ("helper", host_config.helper.unwrap_or(false)),
+            ].into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect();
+
+            match unsupported.is_empty() {
+                true => Ok(()),
+                false => Err(Trap::Config(format!("`{}` runs Windows, which has no POSIX shell for {}", host_config.identifier, unsupported.join(", ")))),
+            }
+        }
+
+        /// Errors once the run was cancelled
+        fn cancelled(&self) -> Result<(), Trap> {
+            match self.cancel.as_ref().and_then(|cancel| cancel.trap()) {
+                Some(trap) => Err(trap),
+                None => Ok(()),
+            }
+        }
+
+        /// Tells `progress` the run is at `current`
+        fn report_progress(&self, current: &Path) {
+            if let Some(sink) = &self.progress {
+                sink.progress(&Progress {
+                    files_done: self.files_seen.get(),
+                    files_total: self.expected.0,
+                    bytes_done: self.bytes_transferred.get(),
+                    bytes_total: self.expected.1,
+                    current: current.to_path_buf(),
+                });
+            }
+        }
+
+        /// Asks `finalize_cmd` and the finalize callback whether the snapshot
+        /// is committed. Vetoed, what this run fetched is dropped along with
+        /// its journal, the record stays as it was and the run fails.
+        fn finalize(&mut self, deleted: u64) -> Result<(), Trap> {
+            if self.host_config.finalize_cmd.is_none() && self.finalize.is_none() {
+                return Ok(());
+            }
+
+            let path = self.snapshot_root_path.clone().unwrap();
+            let stats = FinalizeStats {
+                identifier: self.host_config.identifier.clone(),
+                snapshot: path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+                path: path.clone(),
+                incremental: self.incremental,
+                files: self.files_transferred.get(),
+                bytes: self.bytes_transferred.get(),
+                deleted,
+                size: self.record.size,
+                skipped: self.skipped.borrow().len() as u64,
+            };
+
+            let mut vetoed = match &self.host_config.finalize_cmd {
+                Some(command) => run_finalize_hook(command, &stats, self.host_config.hook_timeout()).err(),
+                None => None,
+            };
+            if let (None, Some(finalize)) = (&vetoed, &self.finalize) {
+                vetoed = finalize(&stats).err()
+                    .map(|reason| Trap::Hook(format!("The snapshot was vetoed: {}", reason)));
+            }
+
+            match vetoed {
+                Some(err) => self.discard(err),
+                None => Ok(()),
+            }
+        }
+
+        /// Errors with the artifacts a restore needs to boot that the
+        /// snapshot of a host with `system_backup` lacks, and discards it
+        fn check_bootable(&mut self) -> Result<(), Trap> {
+            if !self.host_config.system_backup.unwrap_or(false) || self.host_config.namespace.is_some() {
+                return Ok(());
+            }
+
+            let missing = bootable::missing(self.host_config, &self.record.snapshot);
+            match missing.is_empty() {
+                true => Ok(()),
+                false => self.discard(bootable::report(self.host_config, &missing)),
+            }
+        }
+
+        /// Removes the snapshot of this run and its journal, and ends the run
+        /// with `err`
+        fn discard(&mut self, err: Trap) -> Result<(), Trap> {
+            let path = self.snapshot_root_path.clone().unwrap();
+            self.debug(&format!("Discarding {:?}\n", path))?;
+            self.journal.replace(None);
+            Journal::remove(&Journal::path(self.host_root_path.as_ref().unwrap()))?;
+            let _ = fs::remove_dir_all(&path);
+            self.partial.replace(None);
+            let _ = Partial::clear(self.global_config, self.host_config);
+            Err(err)
+        }
+
+        /// Like copy_remote_directory for the source of a host behind rsyncd,
+        /// in one rsync run for everything changed since the record
+        fn copy_from_rsyncd(&mut self, rsyncd: &RsyncdConfig) -> Result<(), Trap> {
+            let source = self.host_config.source.clone();
+            let destination = self.complete_destination.clone().unwrap();
+            fs::create_dir_all(&destination).map_err(|err| {
+                Trap::FS(format!("Could not create directory: {}", err))
+            })?;
+
+            let listing = rsyncd.list(self.global_config, self.host_config)?;
+            let plan = match self.incremental {
+                true => plan_transfer(&self.record, self.host_config, &listing),
+                false => plan_transfer(&Record::new(), self.host_config, &listing),
+            };
+            let wanted: Vec<PathBuf> = plan.fetch.iter().map(|file| file.path.clone()).collect();
+            self.listed = Some(plan.fetch.iter().chain(&plan.skip).map(|file| source.join(&file.path)).collect());
+            self.expected = (Some((plan.fetch.len() + plan.skip.len()) as u64), Some(plan.fetch_size()));
+            self.files_seen.set(plan.skip.len() as u64);
+
+            print!("{} {} of {} files from {} ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), wanted.len(), plan.fetch.len() + plan.skip.len(), rsyncd.url(self.host_config));
+            let start = Instant::now();
+            let received = rsyncd.fetch(self.global_config, self.host_config, &wanted, &destination, self.cancel.as_ref());
+            self.cancelled()?;
+            let received = received?;
+            self.add_phase(start, |phases| &mut phases.transfer_ms);
+            println!("Done");
+
+            for file in received.iter() {
+                self.bytes_transferred.set(self.bytes_transferred.get() + file.size);
+                self.files_transferred.set(self.files_transferred.get() + 1);
+                self.files_seen.set(self.files_seen.get() + 1);
+                self.report_progress(&source.join(&file.path));
+                let mut entry = FileEntry::from(destination.join(&file.path), self.snapshot_root_path.clone().unwrap(), file.mtime, file.size);
+                entry.hash = Some(hash_contents(&entry.file_path)?);
+                self.dedup(&mut entry)?;
+                if let Some(journal) = self.journal.borrow_mut().as_mut() {
+                    journal.append(&source.join(&file.path), &entry)?;
+                }
+            }
+
+            // Unreadable, or gone since they were listed
+            let fetched: BTreeSet<&PathBuf> = received.iter().map(|file| &file.path).collect();
+            self.skipped.borrow_mut().extend(wanted.iter().filter(|path| !fetched.contains(path)).map(|path| source.join(path)));
+
+            if let Some(watch) = &self.sla {
+                watch.check(self.global_config, &self.host_config.identifier, self.bytes_transferred.get());
+            }
+
+            Ok(())
+        }
+
+        /// Records the usage at the source and alerts if it is nearly full.
+        /// Hosts without `df` are backed up all the same.
+        fn check_source_usage(&mut self) {
+            let usage = match self.remote_disk_usage() {
+                Ok(usage) => usage,
+                Err(err) => {
+                    log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                    return;
+                }
+            };
+
+            self.source_usage = Some(usage);
+            if source_nearly_full(self.global_config, &usage) {
+                let trap = Trap::Source(format!(
+                    "Only {:.1}% free at {:?} on `{}`",
+                    usage.free_percent(), self.host_config.source, self.host_config.identifier
+                ));
+                alert(self.global_config, &self.host_config.identifier, &trap);
+                self.warnings.push(trap.to_string());
+            }
+        }
+
+        /// Notes when the `encrypt_key` on the host last changed, for
+        /// `key_rotation`. A key that can not be looked at is left to the copy.
+        fn stamp_encrypt_key(&self) {
+            let key = match &self.host_config.encrypt_key {
+                Some(key) => key,
+                None => return,
+            };
+
+            let changed = self.exec(&format!("stat -c %Y {}", quote(key)))
+                .and_then(|output| output.trim().parse::<i64>()
+                    .map_err(|err| Trap::Channel(format!("Unexpected output of stat: {}", err))));
+            let stamp = changed.and_then(|changed| {
+                let stamp = KeyStamp { path: key.clone(), changed, checked: chrono::Local::now().timestamp() };
+                KeyStamps { encrypt_key: Some(stamp) }.save(self.global_config, self.host_config)
+            });
+            if let Err(err) = stamp {
+                log_host_trap(self.global_config, &self.host_config.identifier, &err);
+            }
+        }
+
         /// Returns last_modified_time from metadata in secs (as u64)
         pub fn local_file_mtime(&self, local_file: &Path) -> Result<u64, Trap> {
             let local_metadata = fs::metadata(local_file).map_err(|err| {
@@ -96,6 +1010,70 @@ pub mod rsync {
             Ok(self.remote_filestat(remote_file)?.mtime.unwrap_or(u64::MAX))
         }
 
+        /// Asks the helper which files below `source` are hard links of one
+        /// another, and the numbers of its device nodes, neither of which
+        /// sftp tells. Without it hard links are fetched as separate files.
+        fn list_special(&mut self, source: &Path) {
+            let (Some(helper), Some(sess)) = (&self.helper, &self.sess) else { return };
+            match helper.special(sess, source) {
+                Ok(special) => {
+                    self.links = link_targets(&special.links, |path| {
+                        !self.filter.excludes_within(path.strip_prefix(source).unwrap_or(path))
+                    });
+                    self.devices = special.devices;
+                },
+                Err(err) => {
+                    let _ = self.debug(&format!("Fetching hard links as separate files: {}\n", err));
+                },
+            }
+        }
+
+        /// Records the symlink, further hard link or special file `source`
+        /// instead of fetching it
+        fn record_special(&self, source: &Path, destination: &Path, kind: SpecialKind, stat: &FileStat) {
+            let target = match kind {
+                SpecialKind::Symlink => match self.sess.as_ref().unwrap().sftp().and_then(|sftp| sftp.readlink(source)) {
+                    Ok(target) => Some(target),
+                    Err(err) => {
+                        self.skipped.borrow_mut().push(source.to_path_buf());
+                        println!("{} Could not read symlink: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
+                        return;
+                    },
+                },
+                SpecialKind::Hardlink => self.links.get(source).cloned(),
+                _ => None,
+            };
+
+            self.specials.borrow_mut().insert(source.to_path_buf(), SpecialEntry {
+                file_path: destination.to_path_buf(),
+                snapshot_path: self.snapshot_root_path.clone().unwrap_or_default(),
+                kind,
+                target,
+                mode: stat.perm.unwrap_or(0) & 0o7777,
+                uid: stat.uid,
+                gid: stat.gid,
+                mtime: stat.mtime.unwrap_or(0),
+                rdev: self.devices.get(source).copied(),
+            });
+        }
+
+        /// The hash of `source`, `size` bytes on the host, that `change_detection`
+        /// compares with the record. The helper hashes whole files on the host,
+        /// without it they are read over sftp. None if it could not be read.
+        fn remote_digest(&self, source: &Path, size: u64) -> Option<String> {
+            let sess = self.sess.as_ref()?;
+            let detection = self.host_config.change_detection();
+            if let (ChangeDetection::Full, Some(helper)) = (detection, &self.helper) {
+                return helper.sha3(sess, &[source.to_path_buf()]).ok()?.into_iter().next().map(|(_, hash)| hash);
+            }
+
+            let sftp = sess.sftp().ok()?;
+            let mut remote = sftp.open(source).ok()?;
+            match detection {
+                ChangeDetection::Sampled => sample_digest(&mut remote, size).ok(),
+                _ => digest_reader(&mut remote).ok(),
+            }
+        }
         /// Iterating the keys in entries and checking if they are remotly
         /// accessable still. If not, they are assumed to be deleted from the source,
         /// and therefore marked as deleted.
@@ -103,7 +1081,18 @@ pub mod rsync {
             let keys: Vec<_> = self.record.snapshot.entries.keys().cloned().collect();
 
             for entry in keys {
-                if let Err(_) = self.remote_file_mtime(&entry) {
+                // Excluded since it was backed up counts as gone as well
+                let relative = entry.strip_prefix(&self.host_config.source).unwrap_or(&entry);
+                let gone = self.filter.excludes_within(relative) || match &self.listed {
+                    Some(listed) => !listed.contains(&entry),
+                    // Not below a path sudo could not list, which would mark all of it
+                    None if is_elevated(self.host_config, &entry) => {
+                        !self.elevated.borrow().contains(&entry) && !self.skipped.borrow().iter().any(|skipped| entry.starts_with(skipped))
+                    },
+                    None => self.remote_file_mtime(&entry).is_err(),
+                };
+
+                if gone {
                     let pair = PathPair::from(
                         entry.to_path_buf(),
                         self.record.snapshot.path(&entry)
@@ -119,55 +1108,38 @@ pub mod rsync {
             Ok(())
         }
 
-        pub fn update_entries(&mut self, dir_path: &PathBuf /*, snapshot: &mut Snapshot */) -> Result<(), Trap> {
-            
-            // let snapshot_root_path = Rc::from(self.snapshot_root_path.clone().unwrap());
-            let snapshot_root_path = self.snapshot_root_path.clone().unwrap();
-
-            if let Ok(entries) = fs::read_dir(dir_path) {
-                for entry in entries {
-                    let entry = match entry {
-                        Ok(entry) => entry,
-                        Err(_) => continue,
-                    };
-
-                    let current_path = entry.path();
-
-                    if current_path.is_dir() {
-                        self.update_entries(&current_path)?;
-                    } else {
+        /// Adds entries of copied files to the record, unmarking those which
+        /// were marked as deleted by a previous backup (they got readded).
+        fn merge_entries(&mut self, entries: Vec<(PathBuf, FileEntry)>) {
+            for (source, entry) in entries {
+                let pathpair = PathPair::from(source.clone(), entry.file_path.clone());
+                if self.record.snapshot.is_deleted(&pathpair) {
+                    self.record.snapshot.undelete(&pathpair);
+                }
 
-                        // TODO: MULTITHREADING
-                        let source = self.into_source(&current_path)?; 
-                        let mtime = self.local_file_mtime(&current_path)?; 
-                        let size = get_file_sz(&current_path);
-                        let _ = self.debug(format!("{} {:?}... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Recording")), &current_path).as_str());
+                self.record.snapshot.entries.insert(source, entry);
+            }
+        }
 
-                        // If the pathpair is already marked as deleted from a previous backup
-                        // (it got readded), will unmark it as deleted. Not checking mtime here
-                        // as it is not relevant.
+        /// Merges the journal of this run into the record.
+        pub fn update_record(&mut self) -> Result<(), Trap> {
+            if let Some(journal) = self.journal.get_mut() {
+                journal.flush()?;
+            }
 
-                        let pathpair = PathPair::from(source.clone(), current_path.clone());
-                        if self.record.snapshot.is_deleted(&pathpair) {
-                            self.record.snapshot.undelete(&pathpair);
-                        }
+            let journal_path = Journal::path(self.host_root_path.as_ref().unwrap());
+            self.merge_entries(Journal::replay(&journal_path)?);
+            self.update_deleted_entries()?;
 
-                        self.record.snapshot.entries.insert(source, FileEntry { file_path: current_path, snapshot_path: snapshot_root_path.clone(), mtime, size});
-                        let _ = self.debug("Done\n");
-                    }
+            // Walked over sftp, what it found takes the place of what the run before found
+            if self.host_config.rsyncd.is_none() && !self.host_config.metadata_only.unwrap_or(false) {
+                let specials = self.specials.take();
+                for source in specials.keys() {
+                    self.record.snapshot.entries.remove(source);
                 }
+                self.record.snapshot.specials = specials;
             }
 
-            Ok(())
-        }
-
-        /// base_path: path to dir the files were copied to.
-        pub fn update_record(&mut self, base_path: &PathBuf) -> Result<(), Trap> {
-            // let mut snapshot = Snapshot::new();
-
-            let _ = self.update_entries(base_path)?;
-            let _ = self.update_deleted_entries()?;
-
             // Count up total size
             let mut total_size = 0;
             for key in self.record.snapshot.entries.keys() {
@@ -181,9 +1153,35 @@ pub mod rsync {
             Ok(())
         }
 
+        /// Picks up the journal of a run that died before its record was
+        /// written. What it copied is archived and kept, so it is not fetched again.
+        fn recover_journal(&mut self, record_dir_path: &Path) -> Result<(), Trap> {
+            let journal_path = Journal::path(self.host_root_path.as_ref().unwrap());
+            let entries = Journal::replay(&journal_path)?;
+            if entries.is_empty() {
+                return Journal::remove(&journal_path);
+            }
+
+            let snapshot_paths: BTreeSet<PathBuf> = entries.iter().map(|(_, entry)| entry.snapshot_path.clone()).collect();
+            for snapshot_path in snapshot_paths {
+                // Its record was never written, it was archived with the codec of the host
+                let archive = self.host_config.compression.unwrap_or_default().archive(&snapshot_path);
+                if snapshot_path.exists() && !archive.exists() {
+                    self.archive(&snapshot_path)?;
+                }
+            }
+
+            println!("Recovered {} files from an interrupted run", entries.len());
+            self.merge_entries(entries);
+            self.record.serialize_json(&record_dir_path.join("record.json"))
+                .map_err(|err| Trap::Serialize(format!("Could not write record: {}", err)))?;
+
+            Journal::remove(&journal_path)
+        }
+
         /// Takes in a local_path, and returns it's remote path equvelent according to 'self'
-        fn into_source(&self, current_path: &Path) -> Result<PathBuf, Trap> {
-            let mut result = PathBuf::from(self.host_config.source.clone());
+        fn to_source(&self, current_path: &Path) -> Result<PathBuf, Trap> {
+            let mut result = self.host_config.source.clone();
             let current_path_components = current_path.components().collect::<Vec<_>>(); 
 
             // Extracting the common prefix between current_path and self.host_config.dest_path
@@ -233,41 +1231,54 @@ pub mod rsync {
         /// ...
         ///
         ///
-        fn backup(&mut self) -> Result<(), Trap> {
+        fn backup(&mut self) -> Result<BackupReport, Trap> {
+            self.usage = ResourceUsage::thread();
+            self.global_config.ensure_writable(&format!("back up `{}`", self.host_config.identifier))?;
+            Retirement::ensure_active(self.global_config, self.host_config, &format!("back up `{}`", self.host_config.identifier))?;
+            self.check_config_drift();
+
+            // Hosts behind rsyncd have no shell, what needs one is refused up front
+            if self.host_config.rsyncd.is_some() {
+                self.check_rsyncd_options()?;
+            }
+            else {
+                let unix = self.host_config.remote_os() == RemoteOs::Unix;
+                if !unix {
+                    self.check_windows_options()?;
+                }
+
+                let start = Instant::now();
+                self.debug("Connecting to host... ")?;
+                self.connect()?;
+                self.debug("Done\n")?;
 
-            let _ = self.debug("Connecting to host... ")?;
-            self.connect()?;
-            let _ = self.debug("Done\n")?;
+                self.debug("Authenticating... ")?;
+                self.auth()?;
+                self.debug("Done\n")?;
 
-            let _ = self.debug("Authenticating... ")?;
-            self.auth()?;
-            let _ = self.debug("Done\n")?;
+                // These run POSIX shell commands on the host
+                if unix {
+                    self.preflight()?;
+                    self.negotiate_helper();
+                    self.check_source_usage();
+                }
+                self.stamp_encrypt_key();
+                if self.host_config.encrypt_key.is_some() && self.host_config.change_detection.is_some_and(|detection| detection != ChangeDetection::Mtime) {
+                    self.notices.push(String::from("Changes are told by mtime for hosts with `encrypt_key`"));
+                }
+                self.add_phase(start, |phases| &mut phases.connect_ms);
+            }
 
             let datetime = get_datetime();
             let source = &self.host_config.source;
 
-            // $HOME/destination/$identifier
-            self.host_root_path = Some(self.global_config.backups
-                .join(&self.host_config.identifier));
+            // $HOME/destination/$identifier, or below it for a further source
+            self.host_root_path = Some(self.host_config.root(self.global_config));
 
             // $HOME/destination/$identifier/$datetime
             self.snapshot_root_path = Some(self.host_root_path.clone().unwrap()
                 .join(datetime));
 
-            // $HOME/destination/$identifier/$datetime/dir_name
-            self.complete_destination = if let Some(stem) = &self.host_config.source.file_stem() {
-                Some(self.snapshot_root_path.clone().unwrap().join(stem))
-            } else {
-                Some(self.snapshot_root_path.clone().unwrap().join(format!("{}", self.host_config.identifier)))
-            };
-
-            // Start backup
-            self.copy_remote_directory(&source, &self.complete_destination.clone().unwrap())?;
-
-            let _ = self.debug("Updating records\n")?;
-            self.update_record(&mut self.snapshot_root_path.clone().unwrap())?;
-            let _ = self.debug("Done\n")?;
-
             // $HOME/destination/$identifier/.records
             let record_dir_path = self.host_root_path.clone().unwrap()
                 .join(".records");
@@ -277,32 +1288,171 @@ pub mod rsync {
                     Trap::FS(format!("Could not create directory: {}", err))
                 })?; }
 
-            // Serializeing records
-            let _ = self.debug("Writing records... ")?;
-            let _ = self.record.serialize_json(&record_dir_path.join("record.json"));
-            let _ = self.debug("Done\n");
+            // Before the interrupted run's snapshot is archived with the file it was cut off in
+            match Partial::recover(self.global_config, self.host_config) {
+                Ok(partial) => *self.partial.get_mut() = partial,
+                Err(err) => log_host_trap(self.global_config, &self.host_config.identifier, &err),
+            }
+            self.recover_journal(&record_dir_path)?;
+            *self.journal.get_mut() = Some(Journal::open(&Journal::path(self.host_root_path.as_ref().unwrap()))?);
+
+            // $HOME/destination/$identifier/$datetime/dir_name
+            self.complete_destination = if let Some(stem) = &self.host_config.source.file_stem() {
+                Some(self.snapshot_root_path.clone().unwrap().join(stem))
+            } else {
+                Some(self.snapshot_root_path.clone().unwrap().join(&self.host_config.identifier))
+            };
+
+            // As many files as last time, until a listing says otherwise
+            if !self.record.snapshot.entries.is_empty() {
+                self.expected = (Some(self.record.snapshot.entries.len() as u64), None);
+            }
+
+            // Start backup, with whatever needs to be consistent frozen meanwhile
+            let transferred_before = self.phases.get().transfer_ms;
+            let copy_ms = match self.host_config.rsyncd.clone() {
+                Some(rsyncd) => {
+                    let start = Instant::now();
+                    self.copy_from_rsyncd(&rsyncd)?;
+                    ms_since(start)
+                },
+                None => {
+                    self.run_hook(self.host_config.pre_backup_cmd.as_deref())?;
+                    let quiesce = self.host_config.quiesce.as_deref().unwrap_or(&[]);
+                    let mut frozen = freeze_all(self.sess.as_ref().unwrap(), quiesce, source)?;
+                    self.list_special(source);
+
+                    let start = Instant::now();
+                    let copied = match self.host_config.metadata_only.unwrap_or(false) {
+                        true => self.list_remote_directory(source),
+                        false => self.copy_remote_directory(source, &self.complete_destination.clone().unwrap()),
+                    };
+                    let copy_ms = ms_since(start);
+                    if let Err(err) = frozen.thaw(self.sess.as_ref().unwrap()) {
+                        alert(self.global_config, &self.host_config.identifier, &err);
+                        self.warnings.push(err.to_string());
+                    }
+                    let hooked = self.run_hook(self.host_config.post_backup_cmd.as_deref());
+                    copied?;
+                    hooked?;
+                    copy_ms
+                },
+            };
+            let mut phases = self.phases.get();
+            phases.traverse_ms += copy_ms.saturating_sub(phases.transfer_ms - transferred_before);
+            self.phases.set(phases);
+            self.capture_system_state();
+
+            let recording = Instant::now();
+            self.debug("Updating records\n")?;
+            let deleted_before = self.record.snapshot.deleted_entries.len();
+            self.update_record()?;
+            let deleted = self.record.snapshot.deleted_entries.len().saturating_sub(deleted_before) as u64;
+            self.add_phase(recording, |phases| &mut phases.record_ms);
+            self.debug("Done\n")?;
+
+            // Before archiving, while what was fetched is still unpacked
+            self.check_checksums();
+            self.check_bootable()?;
+            self.finalize(deleted)?;
+
+            // Serializeing records, once, the snapshot's record is a copy of it
+            let recording = Instant::now();
+            self.record.codec = Some(self.host_config.compression.unwrap_or_default());
+            if let Some(snapshot_root) = &self.snapshot_root_path {
+                self.record.histogram = Some(Histogram::of(&self.record.snapshot, snapshot_root));
+            }
+            self.debug("Writing records... ")?;
+            let record_path = record_dir_path.join("record.json");
+            // Failing here keeps the journal, the next run merges it again
+            self.record.serialize_json(&record_path)
+                .map_err(|err| Trap::Serialize(format!("Could not write record {:?}: {}", record_path, err)))?;
+            self.debug("Done\n")?;
+            self.add_phase(recording, |phases| &mut phases.record_ms);
 
             let snapshot_root_path_binding = self.snapshot_root_path.clone().unwrap();
             let snapshot_root_file_stem = match snapshot_root_path_binding.file_name() {
                 Some(stem) => stem,
-                _ => &OsStr::new("broken")
+                _ => OsStr::new("broken")
             };
 
-            let _ = self.record.serialize_json(&record_dir_path.join(
+            let snapshot_record_path = record_dir_path.join(
                 format!("{}.json", snapshot_root_file_stem.to_str().unwrap_or("broken"))
-            ));
+            );
+            fs::copy(&record_path, &snapshot_record_path)
+                .map_err(|err| Trap::FS(format!("Could not copy record to {:?}: {}", snapshot_record_path, err)))?;
+
+            // Notes made for this run meanwhile, e.g. by a deploy pipeline
+            match Annotations::attach_pending(self.global_config, self.host_config, &snapshot_root_file_stem.to_string_lossy()) {
+                Ok(attached) => self.notices.extend(attached.into_iter().map(|annotation| format!("Annotated: {}", annotation.text))),
+                Err(err) => {
+                    log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                    self.warnings.push(err.to_string());
+                },
+            }
+
+            // Dropping per-file detail from records past `record_retention`
+            if let Err(err) = compact_records(self.global_config, self.host_config, chrono::Local::now().timestamp()) {
+                log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                self.warnings.push(err.to_string());
+            }
+
+            // Expiring snapshots past the `retention` of this source
+            let pruned = plan_prune(self.global_config, self.host_config, chrono::Local::now().timestamp())
+                .and_then(|plan| plan.execute(self.global_config));
+            if let Err(err) = pruned {
+                log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                self.warnings.push(err.to_string());
+            }
 
             // Compressing and archive
-            let archive_compress_dest: &str = snapshot_root_path_binding.to_str().unwrap();
+            let archiving = Instant::now();
+            if let Err(err) = self.archive(&snapshot_root_path_binding) {
+                self.warnings.push(err.to_string());
+            }
+            self.add_phase(archiving, |phases| &mut phases.archive_ms);
 
-            let _ = make_tar_gz(
-                self.snapshot_root_path.clone().unwrap(),
-                format!("{}.tar.gz", archive_compress_dest)
-            );
+            // Copies to the other destinations, the snapshot is safe on the primary already
+            let snapshot = snapshot_root_file_stem.to_string_lossy().into_owned();
+            for err in mirror_snapshot(self.global_config, self.host_config, &snapshot, chrono::Local::now().timestamp()) {
+                alert(self.global_config, &self.host_config.identifier, &err);
+                self.warnings.push(err.to_string());
+            }
 
-            let _ = self.debug("Status: OK\n")?;
-            
-            Ok(())
+            self.check_destination_inodes();
+
+            // Pre-building the index interactive commands read from
+            if self.global_config.warm_cache.unwrap_or(false) {
+                if let Err(err) = warm_index(self.global_config, self.host_config) {
+                    log_host_trap(self.global_config, &self.host_config.identifier, &err);
+                    self.warnings.push(err.to_string());
+                }
+            }
+
+            // Everything in the journal is in the record by now
+            self.journal.replace(None);
+            Journal::remove(&Journal::path(self.host_root_path.as_ref().unwrap()))?;
+
+            // A partial file not resumed from is gone from the host or changed
+            self.partial.replace(None);
+            if let Err(err) = Partial::clear(self.global_config, self.host_config) {
+                log_host_trap(self.global_config, &self.host_config.identifier, &err);
+            }
+
+            self.debug("Status: OK\n")?;
+
+            Ok(BackupReport {
+                snapshot,
+                incremental: self.incremental,
+                files: self.files_transferred.get(),
+                bytes: self.bytes_transferred.get(),
+                deleted,
+                size: self.record.size,
+                skipped: self.skipped.borrow().clone(),
+                warnings: std::mem::take(&mut self.warnings),
+                source: self.source_usage,
+                notices: self.notices.clone(),
+            })
         }
 
         fn auth(&mut self) -> Result<(), Trap> {
@@ -318,7 +1468,19 @@ pub mod rsync {
             // Authenticate session (private key --> public key)
             match self.sess.as_ref() {
                 Some(session) => {
-                    if let Err(err) = session.userauth_pubkey_file(&self.host_config.user, None, private_key_path, None) {
+                    // Whatever identity the agent offers first, falling back to the key file
+                    if self.host_config.agent.unwrap_or(false) {
+                        match session.userauth_agent(&self.host_config.user) {
+                            Ok(()) => return Ok(()),
+                            Err(err) if self.host_config.key.is_none() => {
+                                return Err(Trap::Auth(format!("Could not authenticate with ssh-agent: {}", err)));
+                            },
+                            Err(_) => (),
+                        }
+                    }
+
+                    let passphrase = key_passphrase(self.host_config)?;
+                    if let Err(err) = session.userauth_pubkey_file(&self.host_config.user, None, private_key_path, passphrase.as_deref()) {
                         return Err(Trap::Auth(
                                 format!("Could not Authenticate session: {}\nMake sur ethe ssh-key is at hosts specified key-path", err)
                                 )
@@ -337,10 +1499,22 @@ pub mod rsync {
             let identifier = &self.host_config.identifier;
             let port = self.host_config.port.unwrap_or(22);
 
-            // Connect to SSH server
-            let tcp = TcpStream::connect(format!("{}:{}", identifier, port)).map_err(|err| {
-                Trap::Connect(format!("Could not connect to host: {}\nHost unreachable!", err))
+            let connect_timeout = self.host_config.connect_timeout(self.global_config);
+            let addrs = resolve(identifier, port, self.host_config.dns_timeout(self.global_config))?;
 
+            // Connect to SSH server, trying each resolved address in turn
+            let mut last_err = None;
+            let mut tcp = None;
+            for addr in addrs {
+                match TcpStream::connect_timeout(&addr, connect_timeout) {
+                    Ok(stream) => { tcp = Some(stream); break; },
+                    Err(err) => last_err = Some(err),
+                }
+            }
+
+            let tcp = tcp.ok_or_else(|| {
+                let err = last_err.map(|err| err.to_string()).unwrap_or_else(|| String::from("no addresses"));
+                Trap::Connect(format!("Could not connect to host: {}\nHost unreachable!", err))
             })?;
 
             // Create SSH session
@@ -349,11 +1523,21 @@ pub mod rsync {
 
             })?;
 
-            // Perform SSH handshake
+            // Pays off for compressible data over slow links, only costs CPU on a LAN.
+            // Negotiated during the handshake, servers without zlib get none.
+            sess.set_compress(self.host_config.compress.unwrap_or(false));
+
+            if let Some(cancel) = &self.cancel {
+                cancel.attach_stream(&tcp);
+            }
+
+            // Perform SSH handshake, bounded by the connect timeout as well
             sess.set_tcp_stream(tcp);
+            sess.set_timeout(connect_timeout.as_millis() as u32);
             sess.handshake().map_err(|err| {
                 Trap::Handshake(format!("Could not perform SSH handshake: {}", err))
             })?;
+            sess.set_timeout(0);
 
             self.sess = Some(sess);
             Ok(())
@@ -369,6 +1553,10 @@ pub mod rsync {
 
                 })?;
             }
+
+            if is_elevated(self.host_config, source) {
+                return self.copy_elevated(source, destination);
+            }
             
             let dir_entries = self.sess.as_ref().unwrap().sftp().map_err(|err| {
                 Trap::Copy(format!("Could not init SFTP: {}", err))
@@ -380,6 +1568,7 @@ pub mod rsync {
             })?;
 
             for (entry, stat) in dir_entries {
+                self.cancelled()?;
                 let entryname = match entry.file_name() {
                     Some(entryname) => {
                         entryname 
@@ -393,16 +1582,39 @@ pub mod rsync {
                 let new_source = source.join(entryname);
                 let new_destination = destination.join(entryname);
 
-                if stat.is_file() {
+                let relative = new_source.strip_prefix(&self.host_config.source).unwrap_or(&new_source);
+                if self.filter.excludes(relative, stat.is_dir()) {
+                    continue;
+                }
+
+                // Only recorded, restores make them again
+                let link = (stat.is_file() && self.links.contains_key(&new_source)).then_some(SpecialKind::Hardlink);
+                if let Some(kind) = SpecialKind::of(&stat).or(link) {
+                    self.record_special(&new_source, &new_destination, kind, &stat);
+                    continue;
+                }
+
+                if stat.is_file() && is_elevated(self.host_config, &new_source) {
+                    if let Err(err) = self.copy_elevated(&new_source, &new_destination) {
+                        self.cancelled()?;
+                        self.skipped.borrow_mut().push(new_source.clone());
+                        println!("{} Could not receive file through sudo: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
+                    }
+                }
+                else if stat.is_file() {
+                    self.files_seen.set(self.files_seen.get() + 1);
+                    self.report_progress(&new_source);
                     match self.copy_remote_file(&new_source, &new_destination) {
                         Ok(_) => (),
                         Err(err) => { 
+                            self.cancelled()?;
+                            self.skipped.borrow_mut().push(new_source.clone());
                             println!("{} Could not receive file, please check permissions: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
                         }
                     }
                 }
                 else if stat.is_dir() {
-                    let destination_subdir = destination.join(&entryname);
+                    let destination_subdir = destination.join(entryname);
                     fs::create_dir_all(&destination_subdir).map_err(|err| {
                         Trap::FS(format!("Could not create directory: {}\nCheck permissions!", err))
                     })?;
@@ -410,6 +1622,8 @@ pub mod rsync {
                     match self.copy_remote_directory(&new_source, &new_destination) {
                         Ok(_) => (),
                         Err(err) => { 
+                            self.cancelled()?;
+                            self.skipped.borrow_mut().push(new_source.clone());
                             println!("{} Directory out of reach, please check permissions: {:?}", <Style as Clone>::clone(&self.style).bold().red().apply_to(String::from("Skipping")), err);
                         }
                     }
@@ -424,57 +1638,133 @@ pub mod rsync {
             // TODO: MULTITHREADING
             
             if self.incremental {
-                // check mtime data at local and source
-                let remote_mtime: &u64 = &self.remote_file_mtime(source)?; 
-
-                let dest_as_source = self.into_source(destination)?;
-                if remote_mtime <= self.record.snapshot.mtime(&dest_as_source).unwrap_or(&0) {
-                    println!("{} {}@{}:{:?}", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Skipping")), self.host_config.user, self.host_config.identifier, source);
-                    return Ok(());
+                // check mtime data at local and source, and the contents with `change_detection`
+                let stat = self.remote_filestat(source)?;
+                let (remote_mtime, remote_size) = (stat.mtime.unwrap_or(u64::MAX), stat.size.unwrap_or(0));
+
+                let dest_as_source = self.to_source(destination)?;
+                let recorded = self.record.snapshot.entries.get(&dest_as_source);
+                match compare(self.host_config.change_detection(), recorded, remote_mtime, remote_size, || self.remote_digest(source, remote_size)) {
+                    Verdict::Fetch => (),
+                    verdict => {
+                        // A file that was only touched or given another owner keeps its contents
+                        let chowned = recorded.is_some_and(|entry| (entry.uid, entry.gid) != (stat.uid, stat.gid));
+                        if verdict == Verdict::Touch || chowned {
+                            let entry = FileEntry { mtime: remote_mtime, uid: stat.uid, gid: stat.gid, ..recorded.unwrap().clone() };
+                            if let Some(journal) = self.journal.borrow_mut().as_mut() {
+                                journal.append(source, &entry)?;
+                            }
+                        }
+                        if self.progress.is_none() {
+                            println!("{} {}@{}:{:?}", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Skipping")), self.host_config.user, self.host_config.identifier, source);
+                        }
+                        return Ok(());
+                    },
                 }
             }
 
+            if let Some(key) = &self.host_config.encrypt_key {
+                return self.copy_remote_file_encrypted(key, source, destination);
+            }
+
            /*---------------------------------------------------------------------------*
             * Starting proceess of copying the file from remote to locally, also ensuring*
             * metadata and permissons of the the file.                                  *
             * Need to be run in sudo if it is going to write in /
             *---------------------------------------------------------------------------*/
 
-            let (mut channel, _) = self.sess.as_ref().unwrap().scp_recv(source).map_err(|err| {
-                Trap::Copy(format!("Could not receive file from remote path: {}", err))
-            })?;
+            let stat = self.remote_filestat(source)?;
+            let (mtime, remote_size) = (stat.mtime.unwrap_or(0), stat.size.unwrap_or(0));
+
+            // Picking up where an interrupted run was cut off, if the file is as it was then
+            let partial = self.partial.borrow_mut().take_if(|partial| partial.source == source);
+            let (mut file, size) = match partial {
+                Some(partial) if partial.unchanged(mtime, remote_size) => {
+                    let sftp = self.sess.as_ref().unwrap().sftp().map_err(|err| {
+                        Trap::Session(format!("Could not init SFTP session: {}", err))
+                    })?;
+                    let mut remote = sftp.open(source).map_err(|err| {
+                        Trap::Copy(format!("Could not open remote file: {}", err))
+                    })?;
+                    remote.seek(SeekFrom::Start(partial.offset)).map_err(|err| {
+                        Trap::Copy(format!("Could not seek in remote file: {}", err))
+                    })?;
 
+                    let mut file = partial.take(self.global_config, self.host_config, destination)?;
+                    if self.progress.is_none() {
+                        print!("{} {}@{}:{:?} from byte {} ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Resuming")), self.host_config.user, self.host_config.identifier, source, partial.offset);
+                    }
+                    let size = self.receive(&mut remote, &mut file, source, destination, &stat, partial.offset)?;
+                    (file, size)
+                },
+                partial => {
+                    if partial.is_some() {
+                        Partial::clear(self.global_config, self.host_config)?;
+                    }
 
-            let mut file = fs::File::create(destination).map_err(|err| {
-                Trap::FS(format!("Could not create file: {}\nCheck permissions!", err))
-            })?;
+                    if let Some(fetched) = self.fetch_delta(source, destination, &stat) {
+                        fetched
+                    } else {
+                        let (mut channel, _) = self.sess.as_ref().unwrap().scp_recv(source).map_err(|err| {
+                            Trap::Copy(format!("Could not receive file from remote path: {}", err))
+                        })?;
 
-            print!("{} {}@{}:{:?} ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), self.host_config.user, self.host_config.identifier, source);
-            let mut buffer = [0; 4096];
-            loop {
-                match channel.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        file.write_all(&buffer[..n]).map_err(|err| {
-                            Trap::FS(format!("Could not write to file: {}", err))
+                        let mut file = fs::File::create(destination).map_err(|err| {
+                            Trap::FS(format!("Could not create file: {}\nCheck permissions!", err))
                         })?;
+
+                        if self.progress.is_none() {
+                            print!("{} {}@{}:{:?} ... ", <Style as Clone>::clone(&self.style).bold().blue().apply_to(String::from("Getting")), self.host_config.user, self.host_config.identifier, source);
+                        }
+                        let size = self.receive(&mut channel, &mut file, source, destination, &stat, 0)?;
+                        (file, size)
                     }
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                    Err(err) => {
-                        return Err(Trap::Channel(format!("Could not read from channel: {}", err)));
-                    }
-                }
+                },
+            };
+            if self.progress.is_none() {
+                println!("Done");
+            }
+            self.files_transferred.set(self.files_transferred.get() + 1);
+
+            if let Some(watch) = &self.sla {
+                watch.check(self.global_config, &self.host_config.identifier, self.bytes_transferred.get());
             }
-            println!("Done");
 
             // Sets metadata for the newly created file to the same as the remote file.
-            // print!("Copying metadata... ");            
-            let stat = self.remote_filestat(source)?;
+            // The owner is kept in the record, restores as root give it back.
+            let (uid, gid) = (stat.uid, stat.gid);
             let _ = set_metadata(&mut file, stat);
-            // println!("Done");
+
+            let mut entry = FileEntry { uid, gid, ..FileEntry::from(destination.to_path_buf(), self.snapshot_root_path.clone().unwrap(), mtime, size) };
+            entry.hash = Some(hash_contents(&entry.file_path)?);
+            if self.host_config.change_detection() == ChangeDetection::Sampled {
+                entry.sample = fs::File::open(&entry.file_path).and_then(|mut file| sample_digest(&mut file, size)).ok();
+            }
+            self.dedup(&mut entry)?;
+            if let Some(journal) = self.journal.borrow_mut().as_mut() {
+                journal.append(source, &entry)?;
+            }
 
             Ok(())
         }
+
+    }
+
+    /// Resolves `identifier` on a separate thread, as the system resolver
+    /// has no timeout of its own. A lookup that times out is left to finish
+    /// in the background.
+    fn resolve(identifier: &str, port: u16, timeout: Duration) -> Result<Vec<SocketAddr>, Trap> {
+        let (sender, receiver) = mpsc::channel();
+        let target = format!("{}:{}", identifier, port);
+        thread::spawn(move || {
+            let _ = sender.send(target.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>()));
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(addrs)) => Ok(addrs),
+            Ok(Err(err)) => Err(Trap::Connect(format!("Could not resolve `{}`: {}", identifier, err))),
+            Err(_) => Err(Trap::Connect(format!("Resolving `{}` timed out after {}s", identifier, timeout.as_secs()))),
+        }
     }
 
     pub struct Samba {}
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use crate::config::HostConfig;
+use crate::filter::{glob_match, Filter};
+use crate::logging::Trap;
+use crate::snapshot::Snapshot;
+
+/// What booting a restore of a system needs, any one of its patterns will do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Artifact {
+    pub name: &'static str,
+    pub patterns: &'static [&'static str], // absolute paths on the host, `*` and `**` as in `exclude`
+}
+
+/// Artifacts every snapshot of a host with `system_backup` has to hold
+pub const ARTIFACTS: [Artifact; 4] = [
+    Artifact { name: "fstab", patterns: &["/etc/fstab"] },
+    Artifact { name: "boot loader config", patterns: &[
+        "/boot/grub/grub.cfg",
+        "/boot/grub2/grub.cfg",
+        "/boot/loader/loader.conf",
+        "/boot/efi/EFI/**",
+        "/boot/extlinux/extlinux.conf",
+        "/boot/firmware/config.txt",
+    ] },
+    Artifact { name: "initramfs", patterns: &["/boot/initrd*", "/boot/initramfs*"] },
+    Artifact { name: "package state", patterns: &[
+        "/var/lib/dpkg/status",
+        "/var/lib/rpm/**",
+        "/usr/lib/sysimage/rpm/**",
+        "/var/lib/pacman/local/**",
+        "/lib/apk/db/installed",
+    ] },
+];
+
+/// The artifacts `snapshot` of `host_config` lacks. The package list
+/// `system_state` captures stands in for the package database.
+pub fn missing(host_config: &HostConfig, snapshot: &Snapshot) -> Vec<Artifact> {
+    let paths: Vec<String> = snapshot.entries.keys()
+        .map(|path| path.to_string_lossy().trim_start_matches('/').to_string())
+        .collect();
+
+    ARTIFACTS.iter()
+        .filter(|artifact| !(artifact.name == "package state" && host_config.system_state.unwrap_or(false)))
+        .filter(|artifact| !artifact.patterns.iter().any(|pattern| {
+            let pattern = pattern.trim_start_matches('/');
+            paths.iter().any(|path| glob_match(pattern.as_bytes(), path.as_bytes()))
+        }))
+        .copied()
+        .collect()
+}
+
+/// Why `artifact` did not make it into a snapshot of `host_config`
+fn reason(host_config: &HostConfig, filter: &Filter, artifact: &Artifact) -> &'static str {
+    let paths: Vec<&Path> = artifact.patterns.iter()
+        .map(|pattern| Path::new(pattern.trim_end_matches("/**")))
+        .collect();
+
+    let within: Vec<&Path> = paths.iter().filter_map(|path| path.strip_prefix(&host_config.source).ok()).collect();
+    if within.is_empty() {
+        return "it lies outside `source`";
+    }
+
+    // An exclude that leaves out the file or a directory it is in
+    let excluded = within.iter().any(|relative| {
+        let relative = Path::new(relative.to_str().unwrap_or_default().trim_end_matches('*'));
+        filter.excludes_within(relative) || filter.excludes(relative, true)
+    });
+    match excluded {
+        true => "`exclude` leaves it out",
+        false => "it was not found on the host",
+    }
+}
+
+/// The error a run of `host_config` ends with when its snapshot lacks
+/// `missing`, naming each and why
+pub fn report(host_config: &HostConfig, missing: &[Artifact]) -> Trap {
+    let filter = Filter::new(host_config);
+    let lines: Vec<String> = missing.iter()
+        .map(|artifact| format!("  {} ({}): {}", artifact.name, artifact.patterns.join(", "), reason(host_config, &filter, artifact)))
+        .collect();
+    Trap::Verify(format!("The snapshot of `{}` lacks {} artifact(s) a restore needs to boot, it was discarded:\n{}", host_config.identifier, missing.len(), lines.join("\n")))
+}
+
+#[test]
+fn test_bootable() {
+    use crate::snapshot::FileEntry;
+    use std::path::PathBuf;
+
+    let host_config = HostConfig {
+        identifier: String::from("web"),
+        source: PathBuf::from("/"),
+        exclude: Some(vec![String::from("initrd*")]),
+        ..Default::default()
+    };
+    let mut snapshot = Snapshot::new();
+    for path in ["/etc/fstab", "/boot/efi/EFI/debian/grubx64.efi", "/var/lib/dpkg/status", "/etc/hosts"] {
+        snapshot.entries.insert(PathBuf::from(path), FileEntry::new());
+    }
+
+    let lacking = missing(&host_config, &snapshot);
+    assert_eq!(lacking.iter().map(|artifact| artifact.name).collect::<Vec<_>>(), vec!["initramfs"]);
+    let Trap::Verify(message) = report(&host_config, &lacking) else { panic!() };
+    assert!(message.contains("  initramfs (/boot/initrd*, /boot/initramfs*): `exclude` leaves it out"));
+
+    // Package state captured by `system_state` will do, what lies outside of `source` is named as such
+    let etc = HostConfig { source: PathBuf::from("/etc"), system_state: Some(true), exclude: None, ..host_config.clone() };
+    snapshot.entries.clear();
+    snapshot.entries.insert(PathBuf::from("/etc/fstab"), FileEntry::new());
+    let lacking = missing(&etc, &snapshot);
+    assert_eq!(lacking.len(), 2);
+    let Trap::Verify(message) = report(&etc, &lacking) else { panic!() };
+    assert!(message.contains("boot loader config") && message.contains("it lies outside `source`"));
+}
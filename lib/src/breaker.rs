@@ -0,0 +1,146 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::notify::alert;
+use crate::traits::JsonFile;
+
+/// Consecutive failed runs of a host, and when they tripped its breaker.
+/// Stored at $backups/$identifier/.records/breaker.json
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Breaker {
+    pub failures: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tripped: Option<i64>, // unix seconds
+}
+
+impl Breaker {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        global_config.backups
+            .join(&host_config.identifier)
+            .join(".records")
+            .join("breaker.json")
+    }
+
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Self, Trap> {
+        let path = Self::path(global_config, host_config);
+        Breaker::deserialize_json(&path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))
+    }
+
+    fn save(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        let path = Self::path(global_config, host_config);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+
+        self.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+    }
+
+    /// Whether scheduled runs of the host are held back at `now`. Once
+    /// `trip_cooldown` hours have passed one run is let through, and tripping
+    /// again takes only that run failing.
+    pub fn holds(&self, global_config: &GlobalConfig, now: i64) -> bool {
+        match (self.tripped, global_config.trip_cooldown) {
+            (Some(tripped), Some(hours)) => now < tripped + hours as i64 * 60 * 60,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Counts a finished run of `host_config`. A success closes the breaker,
+    /// the `trip_after`th failure in a row trips it with an alert of its own.
+    pub fn record(global_config: &GlobalConfig, host_config: &HostConfig, hostname: &str, success: bool, now: i64) -> Result<Self, Trap> {
+        let mut breaker = Breaker::load(global_config, host_config)?;
+        let limit = match global_config.trip_after {
+            Some(limit) if !success => limit,
+            _ if breaker == Breaker::default() => return Ok(breaker),
+            _ => {
+                breaker = Breaker::default();
+                breaker.save(global_config, host_config)?;
+                return Ok(breaker);
+            },
+        };
+
+        breaker.failures += 1;
+        if breaker.failures >= limit && !breaker.holds(global_config, now) {
+            breaker.tripped = Some(now);
+            let until = match global_config.trip_cooldown {
+                Some(hours) => format!("for {} hours or until `rensen reset {}`", hours, hostname),
+                None => format!("until `rensen reset {}`", hostname),
+            };
+            alert(global_config, hostname, &Trap::Breaker(format!(
+                "`{}` failed {} runs in a row, holding back its scheduled backups {}", hostname, breaker.failures, until
+            )));
+        }
+
+        breaker.save(global_config, host_config)?;
+        Ok(breaker)
+    }
+
+    /// Closes the breaker of `host_config`, e.g. once it has been fixed
+    pub fn reset(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        global_config.ensure_writable("reset hosts")?;
+        Breaker::default().save(global_config, host_config)
+    }
+}
+
+impl JsonFile for Breaker {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(Breaker::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let breaker: Breaker = serde_json::from_str(&contents)?;
+        Ok(breaker)
+    }
+}
+
+#[test]
+fn test_breaker_trips() {
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_breaker"),
+        log: std::env::temp_dir().join("rensen_test_breaker.log"),
+        trip_after: Some(3),
+        trip_cooldown: Some(24),
+        ..Default::default()
+    };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let _ = fs::remove_dir_all(&global_config.backups);
+
+    let hour = 60 * 60;
+    for now in 0..2 {
+        assert_eq!(Breaker::record(&global_config, &host_config, "host", false, now).unwrap().tripped, None);
+    }
+    let breaker = Breaker::record(&global_config, &host_config, "host", false, 2).unwrap();
+    assert_eq!(breaker, Breaker { failures: 3, tripped: Some(2) });
+    assert!(breaker.holds(&global_config, 23 * hour));
+
+    // After the cool-down a single failure trips it again
+    assert!(!breaker.holds(&global_config, 25 * hour));
+    let breaker = Breaker::record(&global_config, &host_config, "host", false, 25 * hour).unwrap();
+    assert_eq!(breaker.tripped, Some(25 * hour));
+
+    // Without a cool-down only a success or a reset closes it
+    let manual = GlobalConfig { trip_cooldown: None, ..global_config.clone() };
+    assert!(breaker.holds(&manual, i64::MAX));
+    Breaker::reset(&global_config, &host_config).unwrap();
+    assert_eq!(Breaker::load(&global_config, &host_config).unwrap(), Breaker::default());
+    let _ = fs::remove_dir_all(&global_config.backups);
+    let _ = fs::remove_file(&global_config.log);
+}
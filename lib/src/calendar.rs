@@ -0,0 +1,161 @@
+use chrono::{DateTime, Duration, Local, Utc};
+
+use crate::config::{GlobalConfig, Host};
+use crate::history::RunOutcome;
+use crate::logging::Trap;
+use crate::retire::Retirement;
+use crate::schedule::host_schedule;
+
+/// Days ahead exported by default
+pub const DEFAULT_CALENDAR_DAYS: i64 = 14;
+
+/// Length of the windows of hosts that have not had a successful run yet
+pub const DEFAULT_WINDOW_MINUTES: i64 = 60;
+
+/// How many of the latest successful runs a window length is taken from
+const RECENT_RUNS: usize = 10;
+
+/// A time something runs on the backup server, as one calendar event
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    pub kind: String,              // `backup` or `maintenance`
+    pub hostname: Option<String>,  // None for windows of all hosts
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+}
+
+/// The backup windows of `hosts` from their `cron_schedule` and the
+/// `maintenance` window, in the `days` after `now`, sorted by start.
+/// Backups last as long as the median of the latest successful runs of the
+/// host in `runs`. Retired hosts have none.
+pub fn windows(global_config: &GlobalConfig, hosts: &[&Host], runs: &[RunOutcome], now: &DateTime<Local>, days: i64) -> Result<Vec<Window>, Trap> {
+    let horizon = *now + Duration::days(days);
+    let mut windows = Vec::new();
+
+    for host in hosts {
+        if Retirement::load(global_config, &host.config)?.is_some() {
+            continue;
+        }
+
+        let length = expected_duration(runs, &host.hostname);
+        for start in host_schedule(host)?.after(now).take_while(|start| *start < horizon) {
+            windows.push(Window { kind: String::from("backup"), hostname: Some(host.hostname.clone()), start, end: start + length });
+        }
+    }
+
+    if let Some(maintenance) = &global_config.maintenance {
+        let length = Duration::from_std(maintenance.window()).unwrap_or_default();
+        for start in maintenance.parse_schedule()?.after(now).take_while(|start| *start < horizon) {
+            windows.push(Window { kind: String::from("maintenance"), hostname: None, start, end: start + length });
+        }
+    }
+
+    windows.sort_by_key(|window| window.start);
+    Ok(windows)
+}
+
+/// Median duration of the latest successful runs of `hostname`
+pub fn expected_duration(runs: &[RunOutcome], hostname: &str) -> Duration {
+    let mut durations: Vec<i64> = runs.iter().rev()
+        .filter(|run| run.hostname == hostname && run.success)
+        .take(RECENT_RUNS)
+        .map(|run| run.duration().max(60))
+        .collect();
+    durations.sort();
+
+    match durations.get(durations.len() / 2) {
+        Some(seconds) => Duration::seconds(*seconds),
+        None => Duration::minutes(DEFAULT_WINDOW_MINUTES),
+    }
+}
+
+/// `windows` as an iCalendar (RFC 5545) feed, with times in UTC
+pub fn to_ical(windows: &[Window], now: &DateTime<Local>) -> String {
+    let stamp = ical_time(now);
+    let mut lines = vec![
+        String::from("BEGIN:VCALENDAR"),
+        String::from("VERSION:2.0"),
+        String::from("PRODID:-//rensen//backup windows//EN"),
+        String::from("CALSCALE:GREGORIAN"),
+    ];
+
+    for window in windows {
+        let (summary, subject) = match &window.hostname {
+            Some(hostname) => (format!("{} of {}", capitalized(&window.kind), hostname), hostname.as_str()),
+            None => (capitalized(&window.kind), "all"),
+        };
+
+        lines.push(String::from("BEGIN:VEVENT"));
+        lines.push(format!("UID:{}-{}-{}@rensen", window.kind, subject, window.start.timestamp()));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!("DTSTART:{}", ical_time(&window.start)));
+        lines.push(format!("DTEND:{}", ical_time(&window.end)));
+        lines.push(format!("SUMMARY:{}", escape(&summary)));
+        lines.push(format!("CATEGORIES:{}", escape(&window.kind)));
+        lines.push(String::from("TRANSP:TRANSPARENT"));
+        lines.push(String::from("END:VEVENT"));
+    }
+    lines.push(String::from("END:VCALENDAR"));
+
+    let mut ical = lines.join("\r\n");
+    ical.push_str("\r\n");
+    ical
+}
+
+fn ical_time(time: &DateTime<Local>) -> String {
+    time.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn capitalized(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Escapes the characters TEXT values can not hold as they are
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+#[test]
+fn test_calendar() {
+    use chrono::TimeZone;
+    use crate::config::HostConfig;
+    use crate::maintenance::MaintenanceConfig;
+
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_calendar"),
+        maintenance: Some(MaintenanceConfig { schedule: String::from("0 0 3 * * Sun"), hours: Some(1.5), ..Default::default() }),
+        ..Default::default()
+    };
+    let host = Host {
+        hostname: String::from("web01"),
+        config: HostConfig { identifier: String::from("web01"), cron_schedule: Some(String::from("0 30 1 * * *")), ..Default::default() },
+    };
+    let runs: Vec<RunOutcome> = [(0, 1200, true), (0, 1800, true), (0, 9000, false), (0, 600, true)].iter()
+        .map(|(started, finished, success)| RunOutcome { hostname: String::from("web01"), started: *started, finished: *finished, success: *success, ..Default::default() })
+        .collect();
+    assert_eq!(expected_duration(&runs, "web01"), Duration::seconds(1200));
+    assert_eq!(expected_duration(&runs, "db01"), Duration::minutes(DEFAULT_WINDOW_MINUTES));
+
+    let now = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap(); // a Wednesday
+    let windows = windows(&global_config, &[&host], &runs, &now, 7).unwrap();
+    assert_eq!(windows.iter().filter(|window| window.kind == "backup").count(), 7);
+    assert_eq!(windows[0].start, Local.with_ymd_and_hms(2024, 5, 2, 1, 30, 0).unwrap());
+    assert_eq!(windows[0].end - windows[0].start, Duration::seconds(1200));
+
+    let maintenance = windows.iter().find(|window| window.kind == "maintenance").unwrap();
+    assert_eq!((maintenance.start, maintenance.end - maintenance.start), (Local.with_ymd_and_hms(2024, 5, 5, 3, 0, 0).unwrap(), Duration::minutes(90)));
+
+    let ical = to_ical(&windows, &now);
+    assert!(ical.starts_with("BEGIN:VCALENDAR\r\n") && ical.ends_with("END:VCALENDAR\r\n"));
+    assert_eq!(ical.matches("BEGIN:VEVENT").count(), 8);
+    assert!(ical.contains("SUMMARY:Backup of web01\r\n") && ical.contains("SUMMARY:Maintenance\r\n"));
+    assert!(ical.contains(&format!("DTSTART:{}\r\n", ical_time(&windows[0].start))));
+    assert_eq!(escape("a,b;c"), "a\\,b\\;c");
+}
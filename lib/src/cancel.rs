@@ -0,0 +1,157 @@
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::logging::Trap;
+
+/// How long a cancelled run has to wind down before its connection is shut
+pub const CANCEL_GRACE: Duration = Duration::from_secs(60);
+
+/// Stops a run from another thread, e.g. once it ran past the `timeout` of
+/// its host or a user cancelled it. The run checks it between files and
+/// blocks and winds down on its own, thawing what it froze and running
+/// `post_backup_cmd`. The rsync it waits on is killed right away. A run
+/// stuck reading from a host that stopped answering has its connection shut
+/// down instead.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    cause: Mutex<Option<Cause>>,      // the first, a run cancelled by hand does not time out after
+    stream: Mutex<Option<TcpStream>>, // a clone of the run's ssh connection
+    process: Mutex<Option<u32>>,      // the rsync the run waits on
+}
+
+/// Why a run was cancelled, with the reason its trap gives
+#[derive(Debug, Clone)]
+enum Cause {
+    Cancelled(String),
+    Timeout(String),
+}
+
+/// Cancels its run once the timeout passed, unless dropped before
+pub struct Deadline {
+    _done: mpsc::Sender<()>,
+}
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the run, which ends with a Trap::Cancelled of `reason`
+    pub fn cancel(&self, reason: &str) {
+        self.stop(Cause::Cancelled(reason.to_string()));
+    }
+
+    fn stop(&self, cause: Cause) {
+        self.inner.cause.lock().unwrap().get_or_insert(cause);
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        if let Some(pid) = *self.inner.process.lock().unwrap() {
+            kill(pid);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// The trap the run ends with, once cancelled
+    pub fn trap(&self) -> Option<Trap> {
+        match self.inner.cause.lock().unwrap().clone()? {
+            Cause::Cancelled(reason) => Some(Trap::Cancelled(reason)),
+            Cause::Timeout(reason) => Some(Trap::Timeout(reason)),
+        }
+    }
+
+    /// Shuts the run's connection down, which ends any read it is blocked in
+    pub fn sever(&self) {
+        if let Some(stream) = self.inner.stream.lock().unwrap().as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Makes `stream` the connection `sever` shuts down
+    pub fn attach_stream(&self, stream: &TcpStream) {
+        *self.inner.stream.lock().unwrap() = stream.try_clone().ok();
+    }
+
+    /// Makes `pid` the process `cancel` kills, none once it exited. One that
+    /// is attached after the run was cancelled is killed right away.
+    pub fn attach_process(&self, pid: Option<u32>) {
+        let mut process = self.inner.process.lock().unwrap();
+        *process = pid;
+        if let (Some(pid), true) = (pid, self.is_cancelled()) {
+            kill(pid);
+        }
+    }
+
+    /// Cancels the run with a Trap::Timeout of `reason` once `timeout`
+    /// passed, and severs it CANCEL_GRACE after that if it is still going
+    pub fn after(&self, timeout: Duration, reason: String) -> Deadline {
+        self.after_with_grace(timeout, CANCEL_GRACE, reason)
+    }
+
+    fn after_with_grace(&self, timeout: Duration, grace: Duration, reason: String) -> Deadline {
+        let (done, finished) = mpsc::channel::<()>();
+        let cancel = self.clone();
+        thread::spawn(move || {
+            if finished.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            cancel.stop(Cause::Timeout(reason));
+            if finished.recv_timeout(grace) == Err(RecvTimeoutError::Timeout) {
+                cancel.sever();
+            }
+        });
+        Deadline { _done: done }
+    }
+}
+
+fn kill(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+#[test]
+fn test_cancel() {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::process::Command;
+
+    let cancel = Cancel::new();
+    assert!(cancel.trap().is_none());
+
+    // Finished in time, nothing happens
+    drop(cancel.after(Duration::from_millis(50), String::from("too slow")));
+    thread::sleep(Duration::from_millis(100));
+    assert!(!cancel.is_cancelled());
+
+    // The connection is shut once the grace is over
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let _server = listener.accept().unwrap();
+    cancel.attach_stream(&stream);
+    let _deadline = cancel.after_with_grace(Duration::from_millis(10), Duration::from_millis(10), String::from("`web` ran past its timeout of 1s"));
+    assert_eq!(stream.read(&mut [0; 16]).unwrap_or(0), 0);
+    assert!(matches!(cancel.trap(), Some(Trap::Timeout(reason)) if reason == "`web` ran past its timeout of 1s"));
+
+    // Cancelled by hand it stays so, whatever times out after
+    let cancelled = Cancel::new();
+    cancelled.cancel("cancelled by `alice`");
+    cancelled.stop(Cause::Timeout(String::from("too slow")));
+    assert!(matches!(cancelled.trap(), Some(Trap::Cancelled(reason)) if reason == "cancelled by `alice`"));
+
+    // A process of a cancelled run does not get to run
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+    cancel.attach_process(Some(child.id()));
+    assert!(!child.wait().unwrap().success());
+}
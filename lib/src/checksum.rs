@@ -0,0 +1,244 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256, Sha512};
+use sha3::Sha3_256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::traits::JsonFile;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    Sha3_256,
+}
+
+impl Algorithm {
+    /// Going by the tag of BSD style lines, and the length of the hash otherwise
+    fn detect(tag: Option<&str>, hash: &str) -> Option<Algorithm> {
+        match (tag.map(|tag| tag.to_uppercase()).as_deref(), hash.len()) {
+            (Some("SHA256"), 64) | (None, 64) => Some(Algorithm::Sha256),
+            (Some("SHA512"), 128) | (None, 128) => Some(Algorithm::Sha512),
+            (Some("SHA3-256"), 64) => Some(Algorithm::Sha3_256),
+            _ => None,
+        }
+    }
+
+    pub fn digest(&self, path: &Path) -> io::Result<String> {
+        let mut file = File::open(path)?;
+        self.digest_with(|hasher| io::copy(&mut file, hasher).map(|_| ()))
+    }
+
+    /// Hashes what `write` writes, e.g. the chunks of a file of a `dedup` host
+    pub fn digest_with<F>(&self, write: F) -> io::Result<String>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>
+    {
+        match self {
+            Algorithm::Sha256 => hex(write, Sha256::new()),
+            Algorithm::Sha512 => hex(write, Sha512::new()),
+            Algorithm::Sha3_256 => hex(write, Sha3_256::new()),
+        }
+    }
+}
+
+fn hex<F, D>(write: F, mut hasher: D) -> io::Result<String>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    D: Digest + Write,
+{
+    write(&mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// A line of a checksum manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listed {
+    pub file: PathBuf,    // as written in the manifest, relative to it
+    pub hash: String,     // lowercase hex
+    pub algorithm: Algorithm,
+}
+
+/// Parses the lines of a manifest like SHA256SUMS, in either the GNU
+/// (`hash  file`, `hash *file`) or the BSD (`SHA256 (file) = hash`) format.
+/// Lines with other algorithms (md5, sha1) are left out.
+pub fn parse_manifest(contents: &str) -> Vec<Listed> {
+    contents.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (tag, file, hash) = match line.split_once(" (") {
+                Some((tag, rest)) if !tag.contains(' ') => {
+                    let (file, hash) = rest.rsplit_once(") = ")?;
+                    (Some(tag), file, hash)
+                },
+                _ => {
+                    let (hash, file) = line.split_once(' ')?;
+                    (None, file.strip_prefix(['*', ' ']).unwrap_or(file), hash)
+                },
+            };
+
+            if !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+
+            let algorithm = Algorithm::detect(tag, hash)?;
+            Some(Listed { file: PathBuf::from(file), hash: hash.to_lowercase(), algorithm })
+        })
+        .collect()
+}
+
+/// A file that does not match the checksum its manifest lists
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChecksumMismatch {
+    pub manifest: PathBuf,      // source path
+    pub file: PathBuf,          // source path
+    pub expected: String,
+    pub actual: Option<String>, // none if the file is missing
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.actual {
+            Some(actual) => write!(f, "{:?} hashes to {} instead of {} listed in {:?}", self.file, actual, self.expected, self.manifest),
+            None => write!(f, "{:?} listed in {:?} is missing", self.file, self.manifest),
+        }
+    }
+}
+
+/// Checks the files listed in the manifest at (source path) `manifest`.
+/// `locate` maps the source path of a listed file to its local copy. Files
+/// it has no copy of are reported missing if `require_all` is set, e.g. for
+/// a restore, and left out otherwise, e.g. files an incremental run did not
+/// fetch.
+pub fn verify_manifest<F>(manifest: &Path, contents: &str, require_all: bool, locate: F) -> Vec<ChecksumMismatch>
+where
+    F: Fn(&Path) -> Option<PathBuf>
+{
+    verify_manifest_by(manifest, contents, require_all, |file, algorithm| {
+        locate(file).map(|local| algorithm.digest(&local).ok())
+    })
+}
+
+/// Same as verify_manifest, with the hash of a listed file (source path)
+/// given by `digest` instead of read from a local copy, e.g. for files kept
+/// in the chunk store. None if it has no copy of the file, Some(None) if
+/// the copy could not be hashed.
+pub fn verify_manifest_by<F>(manifest: &Path, contents: &str, require_all: bool, digest: F) -> Vec<ChecksumMismatch>
+where
+    F: Fn(&Path, Algorithm) -> Option<Option<String>>
+{
+    let base = manifest.parent().unwrap_or(Path::new("/"));
+    let mut mismatches = Vec::new();
+
+    for listed in parse_manifest(contents) {
+        let file = base.join(&listed.file);
+        let actual = match digest(&file, listed.algorithm) {
+            Some(actual) => actual,
+            None if require_all => None,
+            None => continue,
+        };
+
+        if actual.as_deref() != Some(listed.hash.as_str()) {
+            mismatches.push(ChecksumMismatch { manifest: manifest.to_path_buf(), file, expected: listed.hash, actual });
+        }
+    }
+
+    mismatches
+}
+
+/// Checksum mismatches found at backup time, by snapshot.
+/// Stored at $backups/$identifier/.records/checksums.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChecksumLog {
+    pub snapshots: BTreeMap<String, Vec<ChecksumMismatch>>,
+}
+
+impl ChecksumLog {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("checksums.json")
+    }
+
+    /// Adds the mismatches of `snapshot` to the log of `host_config`
+    pub fn record(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str, mismatches: &[ChecksumMismatch]) -> Result<(), Trap> {
+        let path = Self::path(global_config, host_config);
+        let mut log = ChecksumLog::deserialize_json(&path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))?;
+
+        log.snapshots.insert(snapshot.to_string(), mismatches.to_vec());
+        log.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+    }
+}
+
+impl JsonFile for ChecksumLog {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(ChecksumLog::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let log: ChecksumLog = serde_json::from_str(&contents)?;
+        Ok(log)
+    }
+}
+
+#[test]
+fn test_verify_manifest() {
+    use std::fs;
+
+    let root = std::env::temp_dir().join("rensen_test_checksum");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("good.iso"), "good").unwrap();
+    fs::write(root.join("bad.iso"), "bitrot").unwrap();
+
+    // All three list the hash of good.iso, md5 lines are left out
+    let good = Algorithm::Sha256.digest(&root.join("good.iso")).unwrap();
+    let contents = format!(
+        "{0}  good.iso\n{0} *bad.iso\nSHA256 (gone.iso) = {0}\nd41d8cd98f00b204e9800998ecf8427e  old.iso\n",
+        good
+    );
+    assert_eq!(parse_manifest(&contents).len(), 3);
+
+    // Source /srv/iso is backed up to `root`
+    let locate = |file: &Path| Some(root.join(file.strip_prefix("/srv/iso").unwrap())).filter(|path| path.exists());
+    let manifest = Path::new("/srv/iso/SHA256SUMS");
+
+    let mismatches = verify_manifest(manifest, &contents, false, locate);
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].file, PathBuf::from("/srv/iso/bad.iso"));
+    assert!(mismatches[0].actual.is_some());
+
+    let mismatches = verify_manifest(manifest, &contents, true, locate);
+    assert_eq!(mismatches.len(), 2);
+    assert_eq!(mismatches[1].actual, None);
+
+    // Hashes known without the file, e.g. the SHA3-256 of the record for `dedup` hosts
+    let sha3 = Algorithm::Sha3_256.digest(&root.join("good.iso")).unwrap();
+    let contents = format!("SHA3-256 (good.iso) = {0}\nSHA3-256 (bad.iso) = {0}\n", sha3);
+    let known = |file: &Path, algorithm: Algorithm| match file.file_name()?.to_str()? {
+        "good.iso" if algorithm == Algorithm::Sha3_256 => Some(Some(sha3.clone())),
+        _ => Some(Some(String::from("0000"))),
+    };
+    let mismatches = verify_manifest_by(manifest, &contents, true, known);
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].file, PathBuf::from("/srv/iso/bad.iso"));
+    let _ = fs::remove_dir_all(&root);
+}
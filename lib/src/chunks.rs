@@ -0,0 +1,354 @@
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use sha3::{Digest, Sha3_256};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::compact::snapshot_time;
+use crate::config::GlobalConfig;
+use crate::journal::Journal;
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::sparse::SparseWriter;
+use crate::traits::JsonFile;
+
+/// Directory below `backups` the chunks of every host with `dedup` go to
+pub const CHUNKS_DIR: &str = ".chunks";
+
+/// Chunks are cut where the rolling hash has this many low bits clear,
+/// which makes them 1 MiB on average
+const MASK: u64 = (1 << 20) - 1;
+const MIN_CHUNK: usize = 256 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Chunks no record refers to are only removed once they are this old,
+/// runs store their chunks before the record refers to them
+const GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Random value per byte for the gear hash, fixed so equal data is always
+/// cut the same
+const GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// The chunks of a snapshot, by their size on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChunkShare {
+    pub unique: u64, // only this snapshot refers to, freed once it goes
+    pub shared: u64, // other snapshots, of any host, refer to as well
+}
+
+/// Content-addressed store of file contents, split where the data itself
+/// says so, so an insert early in a file only changes the chunks around it.
+/// Chunks are kept gzipped by the SHA3-256 of what they hold, at
+/// $backups/.chunks/ab/abcdef..., at most once no matter how many snapshots
+/// and hosts hold the data.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    pub root: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(global_config: &GlobalConfig) -> Self {
+        ChunkStore { root: global_config.backups.join(CHUNKS_DIR) }
+    }
+
+    pub fn path(&self, digest: &str) -> PathBuf {
+        self.root.join(digest.get(..2).unwrap_or("00")).join(digest)
+    }
+
+    /// Stores `data` unless it is there already, returns its digest
+    fn put(&self, data: &[u8]) -> Result<String, Trap> {
+        let digest = format!("{:x}", Sha3_256::digest(data));
+        let path = self.path(&digest);
+
+        // Refreshed, so gc does not take it while the record that refers to it is written
+        if path.exists() {
+            let _ = File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+            return Ok(digest);
+        }
+
+        let staged = path.with_extension("part");
+        let write = || -> io::Result<()> {
+            fs::create_dir_all(path.parent().unwrap_or(&self.root))?;
+            let mut encoder = GzEncoder::new(File::create(&staged)?, Compression::fast());
+            encoder.write_all(data)?;
+            encoder.finish()?.sync_all()?;
+            fs::rename(&staged, &path)
+        };
+        write().map_err(|err| Trap::FS(format!("Could not store chunk {}: {}", digest, err)))?;
+        Ok(digest)
+    }
+
+    /// Splits the file at `path` into chunks and stores them, returns their
+    /// digests in order
+    pub fn store_file(&self, path: &Path) -> Result<Vec<String>, Trap> {
+        let file = File::open(path)
+            .map_err(|err| Trap::FS(format!("Could not open {:?}: {}", path, err)))?;
+
+        let mut chunks = Vec::new();
+        let mut chunk: Vec<u8> = Vec::with_capacity(MAX_CHUNK);
+        let mut hash: u64 = 0;
+        let mut reader = BufReader::with_capacity(64 * 1024, file);
+        loop {
+            let buffer = reader.fill_buf()
+                .map_err(|err| Trap::FS(format!("Could not read {:?}: {}", path, err)))?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let mut start = 0;
+            for (i, byte) in buffer.iter().enumerate() {
+                hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+                let length = chunk.len() + i + 1 - start;
+                if (length >= MIN_CHUNK && hash & MASK == 0) || length >= MAX_CHUNK {
+                    chunk.extend_from_slice(&buffer[start..=i]);
+                    chunks.push(self.put(&chunk)?);
+                    chunk.clear();
+                    hash = 0;
+                    start = i + 1;
+                }
+            }
+
+            chunk.extend_from_slice(&buffer[start..]);
+            let consumed = buffer.len();
+            reader.consume(consumed);
+        }
+
+        if !chunk.is_empty() {
+            chunks.push(self.put(&chunk)?);
+        }
+
+        Ok(chunks)
+    }
+
+    /// Puts the file made of `chunks` together at `destination`
+    pub fn restore_file(&self, chunks: &[String], destination: &Path) -> Result<u64, Trap> {
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+
+        let mut file = File::create(destination)
+            .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", destination, err)))?;
+        let mut writer = SparseWriter::new(&mut file)
+            .map_err(|err| Trap::FS(format!("Could not write {:?}: {}", destination, err)))?;
+        let mut size = 0;
+        for digest in chunks {
+            size += self.read(digest, &mut writer)?;
+        }
+        writer.finish()
+            .map_err(|err| Trap::FS(format!("Could not write {:?}: {}", destination, err)))?;
+
+        Ok(size)
+    }
+
+    /// Writes the contents of the chunk `digest` to `writer`, checking they
+    /// still hash to it, and returns their size
+    pub fn read<W: Write>(&self, digest: &str, writer: &mut W) -> Result<u64, Trap> {
+        let path = self.path(digest);
+        let mut data = Vec::new();
+        File::open(&path)
+            .and_then(|file| GzDecoder::new(file).read_to_end(&mut data))
+            .map_err(|err| Trap::Missing(format!("Could not read chunk {}: {}", digest, err)))?;
+
+        if format!("{:x}", Sha3_256::digest(&data)) != digest {
+            return Err(Trap::Verify(format!("Chunk {} does not match its digest", digest)));
+        }
+
+        writer.write_all(&data)
+            .map_err(|err| Trap::FS(format!("Could not write chunk {}: {}", digest, err)))?;
+        Ok(data.len() as u64)
+    }
+
+    /// Every chunk the records and journals below `backups` refer to, of
+    /// all hosts
+    pub fn referenced(global_config: &GlobalConfig) -> BTreeSet<String> {
+        let mut referenced = BTreeSet::new();
+        ChunkStore::each_referrer(global_config, |chunks| referenced.extend(chunks));
+        referenced
+    }
+
+    /// How many of the records and journals below `backups` refer to each
+    /// chunk, of all hosts
+    pub fn reference_counts(global_config: &GlobalConfig) -> HashMap<String, u32> {
+        let mut counts = HashMap::new();
+        ChunkStore::each_referrer(global_config, |chunks| {
+            for chunk in chunks {
+                *counts.entry(chunk).or_insert(0) += 1;
+            }
+        });
+        counts
+    }
+
+    /// The data `record`, one of those counted in `counts`, holds in the
+    /// store by its size on disk: what is freed once it goes, and what
+    /// other records hold as well
+    pub fn share(&self, counts: &HashMap<String, u32>, record: &Record) -> ChunkShare {
+        let chunks: BTreeSet<&String> = record.snapshot.entries.values().flat_map(|entry| entry.chunks.iter().flatten()).collect();
+
+        let mut share = ChunkShare::default();
+        for chunk in chunks {
+            let size = fs::metadata(self.path(chunk)).map(|metadata| metadata.len()).unwrap_or(0);
+            match counts.get(chunk).copied().unwrap_or(0) {
+                0 | 1 => share.unique += size,
+                _ => share.shared += size,
+            }
+        }
+
+        share
+    }
+
+    /// Calls `referrer` with the chunks of each record and journal below
+    /// `backups`
+    fn each_referrer<F>(global_config: &GlobalConfig, mut referrer: F)
+    where
+        F: FnMut(BTreeSet<String>)
+    {
+        let mut directories = vec![global_config.backups.clone()];
+        while let Some(directory) = directories.pop() {
+            let entries = match fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+                // Unpacked snapshots hold no records, and can be large
+                if !path.is_dir() || name == CHUNKS_DIR || snapshot_time(name).is_some() {
+                    continue;
+                }
+                if name != ".records" {
+                    directories.push(path);
+                    continue;
+                }
+
+                let files = fs::read_dir(&path).map(|files| files.filter_map(|file| file.ok()).map(|file| file.path()).collect()).unwrap_or(Vec::<PathBuf>::new());
+                for file in files {
+                    if file.extension().and_then(|extension| extension.to_str()) == Some("json") {
+                        if let Ok(record) = Record::deserialize_json(&file) {
+                            referrer(record.snapshot.entries.values().flat_map(|entry| entry.chunks.iter().flatten().cloned()).collect());
+                        }
+                    }
+                }
+
+                let journal = Journal::path(path.parent().unwrap_or(&path));
+                let replayed = Journal::replay(&journal).unwrap_or_default();
+                if !replayed.is_empty() {
+                    referrer(replayed.into_iter().flat_map(|(_, entry)| entry.chunks.into_iter().flatten()).collect());
+                }
+            }
+        }
+    }
+
+    /// The chunks nothing refers to anymore that are past the grace
+    /// period, with their size on disk
+    pub fn unreferenced(&self, global_config: &GlobalConfig) -> Vec<(PathBuf, u64)> {
+        let referenced = ChunkStore::referenced(global_config);
+        let now = SystemTime::now();
+
+        let mut unreferenced = Vec::new();
+        for directory in fs::read_dir(&self.root).into_iter().flatten().filter_map(|entry| entry.ok()) {
+            for chunk in fs::read_dir(directory.path()).into_iter().flatten().filter_map(|entry| entry.ok()) {
+                let metadata = match chunk.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+
+                let digest = chunk.file_name().to_string_lossy().into_owned();
+                let old = metadata.modified().ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .is_some_and(|age| age > GRACE);
+                if old && !referenced.contains(digest.trim_end_matches(".part")) {
+                    unreferenced.push((chunk.path(), metadata.len()));
+                }
+            }
+        }
+
+        unreferenced.sort();
+        unreferenced
+    }
+}
+
+#[test]
+fn test_chunk_store() {
+    use crate::snapshot::FileEntry;
+
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_chunks"), ..Default::default() };
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(&global_config.backups).unwrap();
+    let store = ChunkStore::new(&global_config);
+
+    // Data that does not repeat, with the same data behind a few extra bytes
+    let mut state: u64 = 1;
+    let data: Vec<u8> = (0..5 * 1024 * 1024).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect();
+    let shifted: Vec<u8> = b"inserted".iter().chain(data.iter()).cloned().collect();
+
+    let original = global_config.backups.join("original");
+    let insert = global_config.backups.join("shifted");
+    fs::write(&original, &data).unwrap();
+    fs::write(&insert, &shifted).unwrap();
+
+    let chunks = store.store_file(&original).unwrap();
+    let other = store.store_file(&insert).unwrap();
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().filter(|chunk| other.contains(chunk)).count() >= chunks.len() - 1);
+
+    let restored = global_config.backups.join("restored");
+    assert_eq!(store.restore_file(&chunks, &restored).unwrap(), data.len() as u64);
+    assert_eq!(fs::read(&restored).unwrap(), data);
+
+    // Only what a record refers to is kept, once past the grace period
+    let records = global_config.backups.join("host").join(".records");
+    fs::create_dir_all(&records).unwrap();
+    let mut record = Record::new();
+    let mut entry = FileEntry::from(PathBuf::from("/local/original"), PathBuf::from("/local"), 0, data.len() as u64);
+    entry.chunks = Some(chunks.clone());
+    record.snapshot.entries.insert(PathBuf::from("/original"), entry);
+    record.serialize_json(&records.join("record.json")).unwrap();
+    assert_eq!(ChunkStore::referenced(&global_config).len(), chunks.len());
+
+    // Shared with another snapshot but for the one chunk it alone has
+    let mut snapshot = record.clone();
+    snapshot.snapshot.entries.get_mut(Path::new("/original")).unwrap().chunks = Some(other.clone());
+    snapshot.serialize_json(&records.join("2024-01-01-00-00-00.json")).unwrap();
+    let counts = ChunkStore::reference_counts(&global_config);
+    let share = store.share(&counts, &snapshot);
+    let size = |chunks: Vec<&String>| chunks.iter().map(|chunk| fs::metadata(store.path(chunk)).unwrap().len()).sum::<u64>();
+    assert_eq!(share.unique, size(other.iter().filter(|chunk| !chunks.contains(chunk)).collect()));
+    assert_eq!(share.shared, size(other.iter().filter(|chunk| chunks.contains(chunk)).collect()));
+    fs::remove_file(records.join("2024-01-01-00-00-00.json")).unwrap();
+    assert!(store.unreferenced(&global_config).is_empty());
+
+    let old = SystemTime::now() - GRACE * 2;
+    for chunk in chunks.iter().chain(other.iter()) {
+        File::options().write(true).open(store.path(chunk)).unwrap().set_modified(old).unwrap();
+    }
+    let unreferenced = store.unreferenced(&global_config);
+    assert_eq!(unreferenced.len(), other.iter().filter(|chunk| !chunks.contains(chunk)).count());
+    assert!(unreferenced.iter().all(|(path, _)| !chunks.iter().any(|chunk| path.ends_with(chunk))));
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
@@ -0,0 +1,364 @@
+use std::io::{self, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Serialize, Deserialize};
+
+use crate::gzip::RsyncableGzEncoder;
+
+/// What archives are compressed with, per host, e.g.
+///
+/// compression: zstd
+/// compression_level: 9
+///
+/// zstd and xz are run as the `zstd` and `xz` programs, which have to be
+/// installed on the backup server. Archives are named after their codec,
+/// and read with the one the record of their snapshot names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Gzip,
+    Zstd, // much faster for about the same size
+    Xz,   // smallest, slowest
+    None, // a plain tar
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::None];
+
+    /// Extension of the archives compressed with it
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "tar.gz",
+            Codec::Zstd => "tar.zst",
+            Codec::Xz => "tar.xz",
+            Codec::None => "tar",
+        }
+    }
+
+    /// The archive of the snapshot at `snapshot_path`, compressed with it
+    pub fn archive(&self, snapshot_path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.{}", snapshot_path.display(), self.extension()))
+    }
+
+    /// Lowercase, as in the config
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+            Codec::None => "none",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::ALL.into_iter().find(|codec| codec.name() == name)
+    }
+
+    /// Level used if `compression_level` is not set
+    pub fn default_level(&self) -> u32 {
+        match self {
+            Codec::Gzip => 6,
+            Codec::Zstd => 3,
+            Codec::Xz => 6,
+            Codec::None => 0,
+        }
+    }
+
+    /// The program compressing, or with `decompress` decompressing, from
+    /// stdin to stdout, for the codecs that are not built in
+    fn program(&self, level: u32, decompress: bool) -> Option<Command> {
+        let name = match self {
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+            Codec::Gzip | Codec::None => return None,
+        };
+
+        let mut command = Command::new(name);
+        command.arg("-q").arg("-c");
+        match decompress {
+            true => command.arg("-d"),
+            false => command.arg(format!("-{}", level.clamp(1, if *self == Codec::Zstd { 19 } else { 9 }))),
+        };
+        Some(command)
+    }
+}
+
+/// Compressing stream an archive is written through, see encoder
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Rsyncable(RsyncableGzEncoder<W>),
+    Program(Piped<W>),
+    None(W),
+}
+
+/// Compresses what is written to it with `codec` at `level` into `writer`.
+/// `rsyncable` only applies to gzip.
+pub fn encoder<W: Write>(codec: Codec, level: u32, rsyncable: bool, writer: W) -> io::Result<Encoder<W>> {
+    let compression = Compression::new(level.min(9));
+    match (codec, codec.program(level, false)) {
+        (Codec::Gzip, _) if rsyncable => Ok(Encoder::Rsyncable(RsyncableGzEncoder::new(writer, compression))),
+        (Codec::Gzip, _) => Ok(Encoder::Gzip(GzEncoder::new(writer, compression))),
+        (_, Some(command)) => Ok(Encoder::Program(Piped::spawn(command, writer)?)),
+        _ => Ok(Encoder::None(writer)),
+    }
+}
+
+impl<W: Write> Encoder<W> {
+    /// Ends the stream and returns the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Rsyncable(encoder) => encoder.finish(),
+            Encoder::Program(piped) => piped.finish(),
+            Encoder::None(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Rsyncable(encoder) => encoder.write(buf),
+            Encoder::Program(piped) => piped.write(buf),
+            Encoder::None(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Rsyncable(encoder) => encoder.flush(),
+            Encoder::Program(_) => Ok(()),
+            Encoder::None(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Decompresses what `reader` reads, compressed with `codec`
+pub fn decoder<'a, R: Read + 'a>(codec: Codec, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let reader = BufReader::new(reader);
+    match (codec, codec.program(0, true)) {
+        (Codec::Gzip, _) => Ok(Box::new(MultiGzDecoder::new(reader))),
+        (_, Some(command)) => Ok(Box::new(Unpiped::spawn(command, reader)?)),
+        _ => Ok(Box::new(reader)),
+    }
+}
+
+/// Like decoder, for processes that may not run programs: only what needs
+/// one is decompressed, gzip and plain tars are left to the process.
+/// Returns the codec of what is left to decompress with the stream.
+pub fn predecoder<'a, R: Read + 'a>(codec: Codec, reader: R) -> io::Result<(Box<dyn Read + 'a>, Codec)> {
+    let reader = BufReader::new(reader);
+    match codec.program(0, true) {
+        Some(command) => Ok((Box::new(Unpiped::spawn(command, reader)?), Codec::None)),
+        None => Ok((Box::new(reader), codec)),
+    }
+}
+
+/* --------------------------------- */
+/* Programs as streams, on one       */
+/* thread by poll(2)-ing both pipes  */
+/* --------------------------------- */
+
+/// Writes at most this much to a pipe poll(2) found writable, so it never blocks
+const PIPE_CHUNK: usize = libc::PIPE_BUF;
+
+/// Whether `out` is readable and `into` writable, waiting for one of them
+fn poll(out: &ChildStdout, into: &ChildStdin) -> io::Result<(bool, bool)> {
+    let mut fds = [
+        libc::pollfd { fd: out.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: into.as_raw_fd(), events: libc::POLLOUT, revents: 0 },
+    ];
+    loop {
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } >= 0 {
+            // A hung up stdout reads as its end, a hung up stdin fails to write
+            let readable = fds[0].revents & (libc::POLLIN | libc::POLLHUP) != 0;
+            let writable = fds[1].revents & (libc::POLLOUT | libc::POLLERR | libc::POLLHUP) != 0;
+            return Ok((readable, writable));
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+fn spawn(mut command: Command) -> io::Result<(Child, ChildStdin, ChildStdout)> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| io::Error::new(err.kind(), format!("Could not run `{}`: {}", program, err)))?;
+    let (stdin, stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+    Ok((child, stdin, stdout))
+}
+
+fn wait(child: &mut Child) -> io::Result<()> {
+    let status = child.wait()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("The compressor exited with {}", status))),
+    }
+}
+
+/// A program compressing into `writer` what is written to it
+pub struct Piped<W: Write> {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    writer: W,
+}
+
+impl<W: Write> Piped<W> {
+    fn spawn(command: Command, writer: W) -> io::Result<Self> {
+        let (child, stdin, stdout) = spawn(command)?;
+        Ok(Piped { child, stdin, stdout, writer })
+    }
+
+    /// Moves what the program put out so far on to the writer
+    fn drain(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 64 * 1024];
+        match self.stdout.read(&mut buffer)? {
+            0 => Err(io::Error::new(io::ErrorKind::BrokenPipe, "The compressor closed its output early")),
+            read => self.writer.write_all(&buffer[..read]),
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        let Piped { mut child, stdin, mut stdout, mut writer } = self;
+        drop(stdin);
+        io::copy(&mut stdout, &mut writer)?;
+        wait(&mut child)?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for Piped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let (readable, writable) = poll(&self.stdout, &self.stdin)?;
+            if readable {
+                self.drain()?;
+            }
+            if writable {
+                return self.stdin.write(&buf[..buf.len().min(PIPE_CHUNK)]);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A program decompressing what `reader` reads
+struct Unpiped<R: Read> {
+    child: Child,
+    stdin: Option<ChildStdin>, // closed once the reader is at its end
+    stdout: ChildStdout,
+    reader: R,
+    pending: Vec<u8>,          // read, not written to the program yet
+    done: bool,
+}
+
+impl<R: Read> Unpiped<R> {
+    fn spawn(command: Command, reader: R) -> io::Result<Self> {
+        let (child, stdin, stdout) = spawn(command)?;
+        Ok(Unpiped { child, stdin: Some(stdin), stdout, reader, pending: Vec::new(), done: false })
+    }
+
+    fn feed(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            let mut buffer = [0; 64 * 1024];
+            let read = self.reader.read(&mut buffer)?;
+            if read == 0 {
+                self.stdin = None;
+                return Ok(());
+            }
+            self.pending.extend_from_slice(&buffer[..read]);
+        }
+
+        let written = self.stdin.as_mut().unwrap().write(&self.pending[..self.pending.len().min(PIPE_CHUNK)])?;
+        self.pending.drain(..written);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Unpiped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+
+        loop {
+            let readable = match &self.stdin {
+                Some(stdin) => {
+                    let (readable, writable) = poll(&self.stdout, stdin)?;
+                    if writable && !readable {
+                        self.feed()?;
+                    }
+                    readable
+                },
+                None => true,
+            };
+
+            if readable {
+                let read = self.stdout.read(buf)?;
+                if read == 0 {
+                    self.done = true;
+                    wait(&mut self.child)?;
+                }
+                return Ok(read);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn round_trip(codec: Codec) {
+    let data: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let mut encoder = encoder(codec, codec.default_level(), false, Vec::new())
+        .unwrap_or_else(|err| panic!("Could not compress with {}: {}", codec.name(), err));
+    encoder.write_all(&data).unwrap();
+    let encoded = encoder.finish().unwrap();
+    assert!(codec == Codec::None || encoded.len() < data.len() / 10);
+
+    let mut decoded = Vec::new();
+    decoder(codec, &encoded[..]).unwrap().read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, data);
+
+    // Left as it is unless a program is needed
+    let (mut predecoded, left) = predecoder(codec, &encoded[..]).unwrap();
+    let mut rest = Vec::new();
+    predecoded.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest == data, codec != Codec::Gzip);
+    assert_eq!(left, if codec == Codec::Gzip { Codec::Gzip } else { Codec::None });
+}
+
+#[test]
+fn test_codecs() {
+    round_trip(Codec::Gzip);
+    round_trip(Codec::None);
+
+    assert_eq!(serde_yaml::from_str::<Codec>("zstd").unwrap(), Codec::Zstd);
+    assert!(Codec::ALL.iter().all(|codec| Codec::from_name(codec.name()) == Some(*codec)));
+    assert_eq!(Codec::Zstd.archive(Path::new("/backups/host/2024-01-01-00-00-00")), Path::new("/backups/host/2024-01-01-00-00-00.tar.zst"));
+}
+
+#[test]
+#[ignore = "needs the zstd and xz programs, run with --ignored where they are installed"]
+fn test_codec_programs() {
+    round_trip(Codec::Zstd);
+    round_trip(Codec::Xz);
+}
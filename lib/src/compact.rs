@@ -0,0 +1,119 @@
+use chrono::{Local, NaiveDateTime};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::plan::{plan_compaction, Step};
+
+/// Result of compacting the records of a host
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Compaction {
+    pub compacted: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// When the snapshot a record belongs to was taken, from its file stem
+/// (`2024-05-15-08-10-30`). None for anything that is not a snapshot record,
+/// e.g. `record.json` or `sla.json`.
+pub fn snapshot_time(stem: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(stem, "%Y-%m-%d-%H-%M-%S").ok()
+        .and_then(|time| time.and_local_timezone(Local).earliest())
+        .map(|time| time.timestamp())
+}
+
+/// Compacts the snapshot records of `host_config` that are older than
+/// `record_retention` days, see plan_compaction.
+pub fn compact_records(global_config: &GlobalConfig, host_config: &HostConfig, now: i64) -> Result<Compaction, Trap> {
+    let plan = plan_compaction(global_config, host_config, now)?;
+    plan.execute(global_config)?;
+
+    let mut compaction = Compaction::default();
+    for step in plan.steps.iter() {
+        if let Step::Compact { bytes_before, bytes_after, .. } = step {
+            compaction.compacted += 1;
+            compaction.bytes_before += bytes_before;
+            compaction.bytes_after += bytes_after;
+        }
+    }
+
+    Ok(compaction)
+}
+
+#[cfg(test)]
+fn file_size(path: &std::path::Path) -> u64 {
+    std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+#[test]
+fn test_compact_records() {
+    use crate::record::Record;
+    use crate::traits::JsonFile;
+    use std::fs;
+    use crate::snapshot::FileEntry;
+    use std::path::PathBuf;
+
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_compact"),
+        record_retention: Some(30),
+        ..Default::default()
+    };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let records = global_config.backups.join("host").join(".records");
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(&records).unwrap();
+
+    let mut record = Record::new();
+    record.size = 10;
+    record.snapshot.entries.insert(PathBuf::from("/a"), FileEntry::new());
+    for name in ["2020-01-01-00-00-00", "record"] {
+        record.serialize_json(&records.join(format!("{}.json", name))).unwrap();
+    }
+
+    let now = Local::now().timestamp();
+    let compaction = compact_records(&global_config, &host_config, now).unwrap();
+    assert_eq!(compaction.compacted, 1);
+    assert!(compaction.bytes_after < compaction.bytes_before);
+
+    let old = Record::deserialize_json(&records.join("2020-01-01-00-00-00.json")).unwrap();
+    assert_eq!(old.size, 10);
+    assert_eq!(old.summary.unwrap().files, 1);
+    assert!(!Record::deserialize_json(&records.join("record.json")).unwrap().is_compacted());
+
+    // Nothing left to do the second time around
+    assert_eq!(compact_records(&global_config, &host_config, now).unwrap().compacted, 0);
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
+
+/// Load test for records at scale, run with `cargo test -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn test_record_million_entries() {
+    use crate::record::Record;
+    use crate::traits::JsonFile;
+    use crate::snapshot::FileEntry;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    let mut record = Record::new();
+    for i in 0..1_000_000 {
+        let source = PathBuf::from(format!("/srv/data/{}/{}/file-{}", i % 100, i % 1000, i));
+        let entry = FileEntry::from(PathBuf::from("/backups/host/2024-05-15-08-10-30").join(&source), PathBuf::from("/backups/host/2024-05-15-08-10-30"), i, i);
+        record.snapshot.entries.insert(source, entry);
+    }
+
+    let path = std::env::temp_dir().join("rensen_test_million.json");
+    let started = Instant::now();
+    record.serialize_json(&path).unwrap();
+    println!("serialize:   {:?} ({} bytes)", started.elapsed(), file_size(&path));
+
+    let started = Instant::now();
+    let mut record = Record::deserialize_json(&path).unwrap();
+    println!("deserialize: {:?}", started.elapsed());
+    assert_eq!(record.snapshot.entries.len(), 1_000_000);
+
+    let started = Instant::now();
+    record.compact(0);
+    record.serialize_json(&path).unwrap();
+    println!("compact:     {:?} ({} bytes)", started.elapsed(), file_size(&path));
+    let _ = std::fs::remove_file(&path);
+}
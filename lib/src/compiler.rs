@@ -1,15 +1,33 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::time::{Duration, UNIX_EPOCH};
 
 use crate::logging::*;
 use crate::snapshot::*; use crate::utils::*; use crate::traits::JsonFile;
 use crate::utils::make_tar_gz;
 
-use crate::record::Record;
+use crate::record::{snapshot_archive, Record};
+use crate::results::CompileReport;
+use crate::checksum::verify_manifest;
+use crate::state::STATE_DIR;
+use crate::listing::LISTING_FILE;
+use crate::chunks::ChunkStore;
+use crate::unpacked::UnpackCache;
+use crate::sandbox;
+use crate::special::{recreate, SpecialFiles, SpecialKind};
+use crate::ownership::{restore_owner, OwnerMap};
 
 pub struct Compiler {
     pub source_snapshot_path: PathBuf,
     pub source_snapshot: Snapshot,
+    pub scratch: Option<PathBuf>, // unpack here instead of next to the archives, keeps the repository untouched
+    pub checksums: Vec<PathBuf>,  // source paths of manifests the compiled files are checked against
+    pub archive: bool,            // pack the compiled snapshot into a .tar.gz, or leave it as a tree
+    pub chunks: Option<ChunkStore>, // where the contents of files backed up with `dedup` are
+    pub cache: Option<UnpackCache>, // keeps the unpacked archives for the next restore, instead of `scratch`
+    pub special_files: SpecialFiles,  // whether FIFOs and device nodes are made again, the `special_files` of the host
+    pub owner_map: OwnerMap,          // the `owner_map` of the host, owners are only restored when run as root
 }
 
 impl Compiler {
@@ -22,48 +40,159 @@ impl Compiler {
             }
         };
 
+        if record.is_compacted() {
+            return Err(Trap::InvalidInput(format!("Record {:?} has been compacted, only its summary is left", record_path)));
+        }
+
         let mut record_path = record_path.clone();
         strip_extension(&mut record_path);
-        Ok(Compiler { source_snapshot_path: record_path.to_path_buf(), source_snapshot: record.snapshot })
+        Ok(Compiler { source_snapshot_path: record_path.to_path_buf(), source_snapshot: record.snapshot, scratch: None, checksums: Vec::new(), archive: true, chunks: None, cache: None, special_files: SpecialFiles::default(), owner_map: OwnerMap::default() })
     } 
 
     /// Compiles from self.snapshot to destination
     /// note: destination has to be
     /// full path (including file + extension)
-    pub fn compile(&mut self, destination: &Path) -> Result<(), Trap> {
+    pub fn compile(&mut self, destination: &Path) -> Result<CompileReport, Trap> {
         // Directory at destination
 
         print!("Compiling ...");
 
         let full_destination = destination.join(self.source_snapshot_path.file_name().unwrap());
         let _ = fs::create_dir_all(&full_destination);
+        let mut report = CompileReport { destination: full_destination.clone(), ..Default::default() };
+        let mut compiled: HashMap<&Path, PathBuf> = HashMap::new();
+        let mut cached: HashMap<&Path, PathBuf> = HashMap::new();
 
         for entry in &self.source_snapshot.entries {
             let file_path = &entry.1.file_path;
             let snapshot_path = &entry.1.snapshot_path;
+            let mut unpack_path = self.unpack_path(snapshot_path);
+
+            if let Some(cache) = &self.cache {
+                if !cached.contains_key(snapshot_path.as_path()) {
+                    let (archive, codec) = snapshot_archive(snapshot_path);
+                    let tree = match cache.unpacked(&archive, codec, chrono::Local::now().timestamp()) {
+                        Ok((tree, fresh)) => {
+                            report.archives += fresh as u64;
+                            tree
+                        },
+                        Err(_) => unpack_path.clone(),
+                    };
+                    cached.insert(snapshot_path, tree);
+                }
+                unpack_path = cached[snapshot_path.as_path()].clone();
+            }
 
             // if a demaked version of the snapshot does not already exist
-            if !snapshot_path.exists() {
-                let _ = demake_tar_gz(
-                    format!("{}.tar.gz", entry.1.snapshot_path.as_path().to_str().unwrap()),
-                    snapshot_path
-                );  
+            if !unpack_path.exists() {
+                let (archive, codec) = snapshot_archive(snapshot_path);
+                let _ = sandbox::unpack(&archive, &unpack_path, codec);
+                report.archives += 1;
             }
 
             // The complete file destination 
             // (aka where it will collected with all other files in
             // the recored)
-            let file_destination = replace_common_prefix(&file_path, &snapshot_path, &full_destination.to_path_buf());
-            let _ = force_copy(&file_path, &file_destination);
+            let unpacked_file = replace_common_prefix(file_path, snapshot_path, &unpack_path);
+            let file_destination = replace_common_prefix(file_path, snapshot_path, &full_destination.to_path_buf());
+            // Files of `dedup` hosts are empty in the archive, their contents are in the chunk store.
+            // A record naming a path outside of the snapshot, or a symlink in the way, is skipped.
+            let copied = is_contained(&full_destination, &file_destination) && match (&entry.1.chunks, &self.chunks) {
+                (Some(chunks), Some(store)) => store.restore_file(chunks, &file_destination).is_ok(),
+                (Some(_), None) => false,
+                (None, _) => is_contained(&unpack_path, &unpacked_file) && force_copy(&unpacked_file, &file_destination).is_ok(),
+            };
+            match copied {
+                true => {
+                    // A fresh copy, with the mtime of the record and the mode it was archived with
+                    let _ = fs::File::options().write(true).open(&file_destination)
+                        .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.1.mtime)));
+                    if let Ok(metadata) = fs::metadata(&unpacked_file) {
+                        let _ = fs::set_permissions(&file_destination, metadata.permissions());
+                    }
+                    let _ = restore_owner(&file_destination, entry.1.uid, entry.1.gid, &self.owner_map);
+
+                    report.files += 1;
+                    report.bytes += entry.1.size;
+                    compiled.insert(entry.0, file_destination);
+                },
+                false => report.skipped.push(entry.0.clone()),
+            }
+
+        }
+
+        // Symlinks, further hard links and special files are only in the record
+        for (source, special) in self.source_snapshot.specials.iter() {
+            let file_destination = replace_common_prefix(&special.file_path, &special.snapshot_path, &full_destination);
+            let linked = special.target.as_ref()
+                .filter(|_| special.kind == SpecialKind::Hardlink)
+                .and_then(|target| compiled.get(target.as_path()))
+                .map(|path| path.as_path());
+            match is_contained(&full_destination, &file_destination).then(|| recreate(special, &file_destination, linked, self.special_files)) {
+                Some(Ok(made)) => {
+                    if made {
+                        let _ = restore_owner(&file_destination, special.uid, special.gid, &self.owner_map);
+                    }
+                    report.files += made as u64;
+                },
+                _ => report.skipped.push(source.clone()),
+            }
+        }
 
+        // The host's state as of this snapshot and the listing of a
+        // `metadata_only` source, if there are any
+        if let Some(snapshot_path) = self.state_snapshot_path() {
+            let unpack_path = self.unpack_path(&snapshot_path);
+            let (archive, codec) = snapshot_archive(&snapshot_path);
+            if !unpack_path.exists() && sandbox::unpack(&archive, &unpack_path, codec).is_ok() {
+                report.archives += 1;
+            }
+            if let Ok(files) = fs::read_dir(unpack_path.join(STATE_DIR)) {
+                for file in files.flatten() {
+                    let _ = force_copy(&file.path(), &full_destination.join(STATE_DIR).join(file.file_name()));
+                }
+            }
+            if unpack_path.join(LISTING_FILE).is_file() {
+                let _ = force_copy(&unpack_path.join(LISTING_FILE), &full_destination.join(LISTING_FILE));
+            }
+        }
+
+        // Everything a manifest lists has to be restored, and match
+        for manifest in self.checksums.iter() {
+            if let Some(contents) = compiled.get(manifest.as_path()).and_then(|path| fs::read_to_string(path).ok()) {
+                report.mismatches.extend(verify_manifest(manifest, &contents, true, |file| compiled.get(file).cloned()));
+            }
+        }
+
+        // Leaving what this restore read for the next one
+        if let Some(cache) = &self.cache {
+            cache.trim(&cached.into_values().collect::<Vec<PathBuf>>());
         }
 
         // Because `full_snapshot_path` is the `source` in this matter.
-        make_tar_gz(&full_destination, format!("{}.tar.gz", full_destination.to_str().unwrap()))
-            .map_err(|err| Trap::FS(format!("Could not archive and compress snapshot: {}", err)))?;
+        if self.archive {
+            make_tar_gz(&full_destination, format!("{}.tar.gz", full_destination.to_str().unwrap()))
+                .map_err(|err| Trap::FS(format!("Could not archive and compress snapshot: {}", err)))?;
+        }
 
         println!("Done");
-        Ok(())
+        Ok(report)
+    }
+
+    /// Where the archive of `snapshot_path` is unpacked to
+    fn unpack_path(&self, snapshot_path: &Path) -> PathBuf {
+        match (&self.scratch, snapshot_path.file_name()) {
+            (Some(scratch), Some(name)) => scratch.join(name),
+            _ => snapshot_path.to_path_buf(),
+        }
+    }
+
+    /// The snapshot `source_snapshot_path` is the record of, its archive
+    /// holds the captured state of the host and any listing
+    fn state_snapshot_path(&self) -> Option<PathBuf> {
+        let name = self.source_snapshot_path.file_name()?;
+        let snapshot_path = self.source_snapshot_path.parent()?.parent()?.join(name);
+        snapshot_archive(&snapshot_path).0.exists().then_some(snapshot_path)
     }
 
     /// Looping through entries and deleting all without the .tar.gz extension
@@ -71,7 +200,14 @@ impl Compiler {
     pub fn cleanup(&self) -> Result<(), Trap> {
         for entry in &self.source_snapshot.entries {
             let snapshot_path = strip_double_extension(&entry.1.snapshot_path);
-            let _ = fs::remove_dir_all(snapshot_path);
+            let _ = fs::remove_dir_all(self.unpack_path(&snapshot_path));
+        }
+        if let Some(snapshot_path) = self.state_snapshot_path() {
+            let _ = fs::remove_dir_all(self.unpack_path(&snapshot_path));
+        }
+
+        if let Some(scratch) = &self.scratch {
+            let _ = fs::remove_dir_all(scratch);
         }
         
         Ok(())
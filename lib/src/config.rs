@@ -5,16 +5,121 @@ use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::io::{self, Write, Read};
 use std::fmt;
+use std::time::Duration;
 
 use crate::traits;
+use crate::quiesce::QuiesceConfig;
+use crate::hooks::{HookFailure, DEFAULT_HOOK_TIMEOUT};
+use crate::codec::Codec;
+use crate::retry::RetryConfig;
+use crate::inventory::InventoryConfig;
+use crate::maintenance::MaintenanceConfig;
+use crate::auth::ControlConfig;
+use crate::runbook::RestoreConfig;
+use crate::retention::KeepPolicy;
+use crate::mirror::MirrorConfig;
+use crate::rsyncd::RsyncdConfig;
+use crate::remote::RemoteOs;
+use crate::incremental::ChangeDetection;
+use crate::special::SpecialFiles;
+use crate::ownership::OwnerMap;
+use crate::repository::RepositoryFormat;
+use crate::compact::snapshot_time;
+use crate::logging::{Trap, Level, LogFormat};
 use traits::YamlFile;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GlobalConfig {
     pub hosts: PathBuf,
     pub backups: PathBuf,
     pub snapshots: PathBuf,
     pub log: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub space_watermark: Option<u8>,  // percent free space, default: 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_watermark: Option<u8>, // percent free space at a host's source, default: 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_cmd: Option<String>,    // shell command run on alerts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<PathBuf>,     // default: `history.jsonl` next to the log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub templates: Option<Templates>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,     // e.g. `Europe/Oslo`, default: system local time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>, // seconds, default: 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_timeout: Option<u64>,     // seconds, default: 5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,      // replica used for list/verify/restore only, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_retention: Option<u32>, // days of per-file detail kept in snapshot records, default: forever
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_mtime_clamp: Option<u64>, // unix seconds, later mtimes in archives are set to it, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rsyncable: Option<bool>,      // rsync friendly gzip framing of archives, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warm_cache: Option<bool>,     // index records after each backup for faster listing, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inventory: Option<InventoryConfig>, // sources hosts are discovered from, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_backups: Option<usize>, // backups rensend runs at once, default: unlimited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replica: Option<PathBuf>,     // mounted offsite copy of `backups`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub helper_path: Option<PathBuf>, // helper binary deployed to hosts, default: /usr/lib/rensen/rensen-helper
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trip_after: Option<u32>,      // failed runs in a row that hold back a host's scheduled backups, default: never
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trip_cooldown: Option<u64>,   // hours until a held back host is tried again, default: until `reset`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode_watermark: Option<u8>,  // percent free inodes at the destination, default: 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<PathBuf>,       // default: `audit.jsonl` next to the log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manual_runs: Option<u32>,     // backups of a host started by hand per hour, more are refused, default: unlimited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<Level>,     // debug, info, warn or error, default: info
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_format: Option<LogFormat>, // text or json (one object per line), default: text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_bind: Option<String>, // address rensend serves /metrics at, e.g. `127.0.0.1:9184`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frontend_user: Option<String>, // unprivileged user /metrics is served as, default: none (rensend serves it itself)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_socket: Option<PathBuf>, // socket the front-end asks rensend over, default: /run/rensen/status.sock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control: Option<ControlConfig>, // who may start and cancel backups at `metrics_bind`, default: nobody
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<bool>,         // unpack and verify archives in confined subprocesses, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umask: Option<String>,        // octal, e.g. `0027` for 0640 files, default: inherited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,        // group written files belong to, e.g. `backup`, default: the user's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_cache: Option<usize>,  // records rensend keeps parsed between runs, default: 64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceConfig>, // nightly window verifying the snapshots of all hosts, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restore_cache: Option<u64>,   // MiB of unpacked archives kept for repeated restores, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<PathBuf>,        // where `keys init` and `keys rotate` put the keys of hosts, default: /etc/rensen/keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_rotation: Option<u32>,    // days ssh and encryption keys are used before reports warn, default: never
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offsite: Option<MirrorConfig>, // mirror of every host besides its own `mirrors`, e.g. an `s3` bucket, default: none
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_DNS_TIMEOUT: u64 = 5;
+
+/// Paths to user supplied templates (minijinja syntax)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Templates {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert: Option<PathBuf>,   // context: host, kind, message, time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<PathBuf>,  // context: generated, hosts
 }
 
 #[test]
@@ -24,12 +129,37 @@ fn test_global_config_serialize() {
         backups: PathBuf::from("/home/dto/bakcups/"),
         snapshots: PathBuf::from("/etc/rensen/hosts.yml"),
         log: PathBuf::from("/etc/rensen/log"),
+        ..Default::default()
     };
 
-    let path = PathBuf::from("gc.yml");
+    let path = std::env::temp_dir().join("gc.yml");
     let _ = gc.serialize_yaml(&path);
 }
 
+impl GlobalConfig {
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.unwrap_or(false)
+    }
+
+    /// Every code path that writes to the repository or the host settings goes
+    /// through here first, so a read-only instance can never modify them, and
+    /// none modifies a repository in a format other than its own.
+    pub fn ensure_writable(&self, action: &str) -> Result<(), Trap> {
+        if self.is_read_only() {
+            return Err(Trap::ReadOnly(format!("Refusing to {}: rensen is configured as read-only", action)));
+        }
+
+        RepositoryFormat::ensure_writable(self, action)
+    }
+}
+
+#[test]
+fn test_read_only() {
+    let global_config = GlobalConfig { read_only: Some(true), ..Default::default() };
+    assert!(matches!(global_config.ensure_writable("backup"), Err(Trap::ReadOnly(_))));
+    assert!(GlobalConfig::default().ensure_writable("backup").is_ok());
+}
+
 impl YamlFile for GlobalConfig {
     fn serialize_yaml(&self, file_path: &Path) -> std::io::Result<()> {
         let mut file = File::create(file_path)?;
@@ -55,15 +185,132 @@ impl YamlFile for GlobalConfig {
 }
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct HostConfig {
     pub user: String,
     pub identifier: String,        // machine addr
     pub port: Option<u16>,         // default: 22
     pub key: Option<PathBuf>, // default: "$HOME/.ssh/ed25516"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_key: Option<PathBuf>, // key `keys rotate` replaced, still authorized on the host until `keys prune`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_passphrase: Option<String>, // of an encrypted `key`: `file:<path>`, `env:<name>` or `prompt`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<bool>,           // authenticate with ssh-agent at $SSH_AUTH_SOCK before trying `key`, default: false
     pub source: PathBuf,
     pub destination: PathBuf,
     pub cron_schedule: Option<String>, // defualt `* 0 0 * * * *`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,      // instead of `cron_schedule`, a fixed interval as e.g. `6h` or `90m`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_at: Option<String>,        // instead of `cron_schedule`, a single run at local time as `2025-01-01T00:00`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub critical: Option<bool>,        // default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sla: Option<String>,           // deadline as `HH:MM`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sla_escalate: Option<bool>,    // alert on predicted misses, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,  // seconds, default: global `connect_timeout`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_timeout: Option<u64>,      // seconds, default: global `dns_timeout`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,          // seconds a scheduled run may take before rensend cancels it, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,  // glob patterns of paths below the source to skip, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include: Option<Vec<String>>,  // glob patterns kept even if an exclude matches, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiesce: Option<Vec<QuiesceConfig>>, // freeze/thaw around the copy, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_backup_cmd: Option<String>,   // run on the host before anything is fetched, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_backup_cmd: Option<String>,  // run on the host once the copy is done, whether it succeeded or not, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook_timeout: Option<u64>,        // seconds either may take, default: 300
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook_failure: Option<HookFailure>, // abort or continue, default: abort
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Codec>,       // of the archives, gzip, zstd, xz or none, default: gzip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_level: Option<u32>,   // default: 6, 3 for zstd
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finalize_cmd: Option<String>,     // run on the backup server before the snapshot is committed, vetoes it on failure, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,       // scheduled runs that could not reach the host are tried again, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_schedule: Option<String>,  // cron for scrubbing snapshots, default: never
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_percent: Option<u8>,       // share of snapshots per scheduled verify, default: 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub helper: Option<bool>,             // deploy and use rensen-helper on the host, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta_min_size: Option<u64>,      // MiB, changed files at least this large only fetch the blocks that changed, needs `helper`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_detection: Option<ChangeDetection>, // how incremental runs tell a file changed: mtime, sampled or full, default: mtime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<bool>,           // zlib compression of the ssh transport, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksums: Option<Vec<PathBuf>>,  // manifests like SHA256SUMS the source files are checked against, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restore: Option<RestoreConfig>,   // ordering and commands of `restore`, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirrors: Option<Vec<MirrorConfig>>, // destinations snapshots are copied to as well, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypt_key: Option<PathBuf>,     // passphrase file on the host, contents are encrypted there with it, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sudo: Option<Vec<PathBuf>>,       // paths the user can not read, fetched through `sudo -n` instead of sftp, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_state: Option<bool>,       // capture packages, services, crontabs and iptables into each snapshot, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_backup: Option<bool>,      // snapshots must hold what a restore needs to boot: fstab, boot config, initramfs, package state, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub special_files: Option<SpecialFiles>, // skip or recreate FIFOs and device nodes on restore, symlinks and hard links always are, default: skip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_map: Option<OwnerMap>, // uids and gids of the source to those of the machine restored to, default: as they are
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<u32>,           // days snapshots of `source` are kept, default: forever
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep: Option<KeepPolicy>,         // snapshots of `source` kept by count (last, daily, ...), default: all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_only: Option<bool>,      // list sizes, hashes and modes of `source` without fetching it, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources: Option<Vec<SourceConfig>>, // further paths, each with a snapshot chain of its own, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rsyncd: Option<RsyncdConfig>,     // fetch `source` from an rsync daemon instead of over ssh, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<bool>,              // keep file contents in the chunk store shared by all hosts, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_os: Option<RemoteOs>,      // unix or windows, the OS the host's OpenSSH runs on, default: unix
+    #[serde(skip)]
+    pub namespace: Option<String>,        // set on the configs `namespaces` derives for `sources`
+}
+
+/// A further source path of a host, backed up into a snapshot chain of its
+/// own at $backups/$identifier/$name, so it keeps its own retention
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceConfig {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,          // default: last component of `path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<u32>,        // days its snapshots are kept, default: forever
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep: Option<KeepPolicy>,      // its snapshots kept by count, default: all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_only: Option<bool>,   // list its files without fetching them, default: false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,          // ssh user it is read as, e.g. a service account, default: the host's `user`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,          // key of that user, default: the host's `key`
+}
+
+impl SourceConfig {
+    pub fn name(&self) -> String {
+        self.name.clone()
+            .or_else(|| self.path.file_name().map(|name| name.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| String::from("root"))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,6 +325,83 @@ pub struct Settings {
 
 impl HostConfig {
 
+    /// Where the snapshot chain of this config lives, relative to `backups`
+    pub fn repo_path(&self) -> PathBuf {
+        match &self.namespace {
+            Some(namespace) => PathBuf::from(&self.identifier).join(namespace),
+            None => PathBuf::from(&self.identifier),
+        }
+    }
+
+    /// $backups/$identifier, or $backups/$identifier/$name for a further source
+    pub fn root(&self, global_config: &GlobalConfig) -> PathBuf {
+        global_config.backups.join(self.repo_path())
+    }
+
+    /// This config followed by one per entry of `sources`, each backing up
+    /// that path into its own namespace. Each is backed up over a session
+    /// of its own, as the `user` of the source where it has one.
+    pub fn namespaces(&self) -> Result<Vec<HostConfig>, Trap> {
+        let host = self.with_remote_paths();
+        let mut namespaces = vec![host.clone()];
+        for source in host.sources.iter().flatten() {
+            let name = source.name();
+            if name.is_empty() || name.starts_with('.') || name.contains('/') || snapshot_time(&name).is_some() {
+                return Err(Trap::Config(format!("Invalid name `{}` for source {:?} of `{}`", name, source.path, self.identifier)));
+            }
+            if namespaces.iter().any(|other| other.namespace.as_ref() == Some(&name)) {
+                return Err(Trap::Config(format!("Two sources of `{}` are named `{}`", self.identifier, name)));
+            }
+
+            namespaces.push(HostConfig {
+                source: source.path.clone(),
+                retention: source.retention,
+                keep: source.keep.clone(),
+                metadata_only: source.metadata_only,
+                user: source.user.clone().unwrap_or(host.user.clone()),
+                key: source.key.clone().or(host.key.clone()),
+                previous_key: source.key.as_ref().map_or(host.previous_key.clone(), |_| None),
+                sources: None,
+                namespace: Some(name),
+                ..host.clone()
+            });
+        }
+
+        Ok(namespaces)
+    }
+
+    pub fn remote_os(&self) -> RemoteOs {
+        self.remote_os.unwrap_or_default()
+    }
+
+    /// This config with the paths on the host in the form the backup engine
+    /// works with, see RemoteOs::normalize. Those of Unix hosts stay as
+    /// they are.
+    pub fn with_remote_paths(&self) -> HostConfig {
+        let os = self.remote_os();
+        let mut config = self.clone();
+        if os == RemoteOs::Unix {
+            return config;
+        }
+
+        let normalize = |path: &PathBuf| os.normalize(path);
+        config.source = normalize(&self.source);
+        config.checksums = self.checksums.as_ref().map(|paths| paths.iter().map(normalize).collect());
+        for source in config.sources.iter_mut().flatten() {
+            source.path = normalize(&source.path);
+        }
+        if let Some(restore) = config.restore.as_mut() {
+            restore.target = restore.target.as_ref().map(normalize);
+            for path in restore.order.iter_mut().flatten() {
+                *path = normalize(path);
+            }
+            for post in restore.post.iter_mut().flatten() {
+                post.after = post.after.as_ref().map(normalize);
+            }
+        }
+        config
+    }
+
     pub fn from(
         user: String,
         identifier: String,
@@ -95,15 +419,105 @@ impl HostConfig {
             source,
             destination,
             cron_schedule: Some(cron_schedule),
+            ..Default::default()
+        }
+    }
+
+    /// Critical hosts keep getting backed up when the destination is running out of space
+    pub fn is_critical(&self) -> bool {
+        self.critical.unwrap_or(false)
+    }
+
+    /// How incremental runs tell a file changed. What is fetched of hosts
+    /// with `encrypt_key` is encrypted, it hashes to something else than the
+    /// file on the host, and files behind rsyncd can not be hashed there.
+    pub fn change_detection(&self) -> ChangeDetection {
+        match (&self.encrypt_key, &self.rsyncd) {
+            (None, None) => self.change_detection.unwrap_or_default(),
+            _ => ChangeDetection::Mtime,
         }
     }
+
+    /// How long establishing the TCP connection and SSH handshake may take
+    pub fn connect_timeout(&self, global_config: &GlobalConfig) -> Duration {
+        Duration::from_secs(self.connect_timeout
+            .or(global_config.connect_timeout)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT))
+    }
+
+    /// How long resolving `identifier` may take
+    pub fn dns_timeout(&self, global_config: &GlobalConfig) -> Duration {
+        Duration::from_secs(self.dns_timeout
+            .or(global_config.dns_timeout)
+            .unwrap_or(DEFAULT_DNS_TIMEOUT))
+    }
+
+    /// How long `pre_backup_cmd` or `post_backup_cmd` may take
+    pub fn hook_timeout(&self) -> Duration {
+        Duration::from_secs(self.hook_timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT))
+    }
+}
+
+#[test]
+fn test_timeouts() {
+    let global_config = GlobalConfig { connect_timeout: Some(30), ..Default::default() };
+    let host_config = HostConfig { dns_timeout: Some(1), ..Default::default() };
+
+    assert_eq!(host_config.connect_timeout(&global_config), Duration::from_secs(30));
+    assert_eq!(host_config.dns_timeout(&global_config), Duration::from_secs(1));
+    assert_eq!(HostConfig::default().dns_timeout(&GlobalConfig::default()), Duration::from_secs(DEFAULT_DNS_TIMEOUT));
+}
+
+#[test]
+fn test_source_users() {
+    let host_config = HostConfig {
+        user: String::from("backup"),
+        key: Some(PathBuf::from("/etc/rensen/keys/backup")),
+        sources: Some(vec![
+            SourceConfig { path: PathBuf::from("/var/lib/postgresql"), user: Some(String::from("postgres")), key: Some(PathBuf::from("/etc/rensen/keys/postgres")), ..Default::default() },
+            SourceConfig { path: PathBuf::from("/srv/www"), user: Some(String::from("www-data")), ..Default::default() },
+            SourceConfig { path: PathBuf::from("/var/log"), ..Default::default() },
+        ]),
+        ..Default::default()
+    };
+
+    let users: Vec<(String, Option<PathBuf>)> = host_config.namespaces().unwrap().into_iter().map(|config| (config.user, config.key)).collect();
+    assert_eq!(users, vec![
+        (String::from("backup"), Some(PathBuf::from("/etc/rensen/keys/backup"))),
+        (String::from("postgres"), Some(PathBuf::from("/etc/rensen/keys/postgres"))),
+        (String::from("www-data"), Some(PathBuf::from("/etc/rensen/keys/backup"))),
+        (String::from("backup"), Some(PathBuf::from("/etc/rensen/keys/backup"))),
+    ]);
+}
+
+#[test]
+fn test_windows_paths() {
+    use crate::runbook::RestoreConfig;
+
+    let host_config = HostConfig {
+        identifier: String::from("desk"),
+        source: PathBuf::from("C:\\Users\\backup"),
+        sources: Some(vec![SourceConfig { path: PathBuf::from("D:\\Shares\\"), ..Default::default() }]),
+        restore: Some(RestoreConfig { order: Some(vec![PathBuf::from("C:\\Users\\backup\\AppData")]), ..Default::default() }),
+        remote_os: Some(RemoteOs::Windows),
+        ..Default::default()
+    };
+
+    let namespaces = host_config.namespaces().unwrap();
+    assert_eq!(namespaces[0].source, PathBuf::from("/C:/Users/backup"));
+    assert_eq!((namespaces[1].source.clone(), namespaces[1].namespace.as_deref()), (PathBuf::from("/D:/Shares"), Some("Shares")));
+    assert!(namespaces[0].restore.as_ref().unwrap().order.as_ref().unwrap()[0].starts_with(&namespaces[0].source));
+    assert_eq!(namespaces[0].remote_os().native(&namespaces[0].source), "C:\\Users\\backup");
+
+    // Unix hosts keep their paths as written
+    assert_eq!(HostConfig { source: PathBuf::from("/srv/a\\b"), ..Default::default() }.with_remote_paths().source, PathBuf::from("/srv/a\\b"));
 }
 
 impl fmt::Display for HostConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "addr: {}\nuser: {}\nport: {}\nkey: {}\nsource: {}\ndestination: {}\ncron_schedule: {}",
+            "addr: {}\nuser: {}\nport: {}\nkey: {}\nsource: {}\ndestination: {}\ncron_schedule: {}\ninterval: {}\nrun_at: {}\ncritical: {}\nsla: {}",
             self.identifier,
             self.user,
             self.port.unwrap_or(22),
@@ -111,9 +525,13 @@ impl fmt::Display for HostConfig {
                 .as_ref()
                 .map(|path| path.display().to_string())
                 .unwrap_or_else(|| "$HOME/.ssh/ed25516".to_string()),
-            self.source.display(),
+            self.remote_os().native(&self.source),
             self.destination.display(),
-            self.cron_schedule.as_ref().unwrap(),
+            self.cron_schedule.as_deref().unwrap_or("none"),
+            self.interval.as_deref().unwrap_or("none"),
+            self.run_at.as_deref().unwrap_or("none"),
+            self.is_critical(),
+            self.sla.as_deref().unwrap_or("none"),
         )
     }
 }
@@ -137,16 +555,27 @@ impl Settings {
         Ok(())
     }
 
-    pub fn associated_config(&self, hostname: &String) -> Option<HostConfig> {
+    /// The config of `hostname`, or with `hostname:name` that of its source `name`
+    pub fn associated_config(&self, hostname: &str) -> Option<HostConfig> {
+        let (hostname, namespace) = match hostname.split_once(':') {
+            Some((hostname, namespace)) => (hostname, Some(namespace)),
+            None => (hostname, None),
+        };
+
         let mut host_config: Option<HostConfig> = None;
         for host in &self.hosts {
-            if hostname.to_owned() == host.hostname.to_owned() {
+            if hostname == host.hostname {
                 host_config = Some(host.config.clone());
                 break;
             }
         }
 
-        host_config
+        match namespace {
+            Some(namespace) => host_config?.namespaces().ok()?
+                .into_iter()
+                .find(|config| config.namespace.as_deref() == Some(namespace)),
+            None => host_config.map(|config| config.with_remote_paths()),
+        }
     }
 }
 
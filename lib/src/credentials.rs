@@ -0,0 +1,141 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::traits::JsonFile;
+
+/// When the `encrypt_key` of a host last changed, as seen on the host by its
+/// last backup. The key never leaves the host, so reports go by this.
+/// Kept at $backups/$identifier/.records/credentials.json.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyStamps {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypt_key: Option<KeyStamp>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyStamp {
+    pub path: PathBuf,
+    pub changed: i64, // unix seconds, mtime of the key
+    pub checked: i64, // unix seconds
+}
+
+impl KeyStamps {
+    /// Shared by all `sources` of the host, they use the same keys
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        global_config.backups
+            .join(&host_config.identifier)
+            .join(".records")
+            .join("credentials.json")
+    }
+
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Self {
+        KeyStamps::deserialize_json(&Self::path(global_config, host_config)).unwrap_or_default()
+    }
+
+    pub fn save(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        let path = Self::path(global_config, host_config);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", parent, err)))?;
+        }
+
+        self.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+    }
+}
+
+impl JsonFile for KeyStamps {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(KeyStamps::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let stamps: KeyStamps = serde_json::from_str(&contents)?;
+        Ok(stamps)
+    }
+}
+
+/// A key of a host in use for longer than `key_rotation` allows
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StaleKey {
+    pub kind: String,  // `ssh` or `encryption`
+    pub path: PathBuf,
+    pub days: i64,     // since it was last changed
+}
+
+/// The keys of `host_config` older than `key_rotation` days: its ssh `key`,
+/// by the mtime of the file here, and its `encrypt_key`, by what its last
+/// backup saw on the host. Empty without a `key_rotation`.
+pub fn stale_keys(global_config: &GlobalConfig, host_config: &HostConfig, now: i64) -> Vec<StaleKey> {
+    let limit = match global_config.key_rotation {
+        Some(days) => days as i64,
+        None => return Vec::new(),
+    };
+
+    let mut keys = Vec::new();
+    let ssh_changed = host_config.key.as_ref()
+        .and_then(|key| fs::metadata(key).ok())
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs() as i64);
+    if let (Some(key), Some(changed)) = (&host_config.key, ssh_changed) {
+        keys.push(StaleKey { kind: String::from("ssh"), path: key.clone(), days: (now - changed) / 86400 });
+    }
+
+    // A stamp of a key the host no longer uses does not count
+    if let Some(stamp) = KeyStamps::load(global_config, host_config).encrypt_key {
+        if host_config.encrypt_key.as_ref() == Some(&stamp.path) {
+            keys.push(StaleKey { kind: String::from("encryption"), path: stamp.path, days: (now - stamp.changed) / 86400 });
+        }
+    }
+
+    keys.retain(|key| key.days > limit);
+    keys
+}
+
+#[test]
+fn test_stale_keys() {
+    let root = std::env::temp_dir().join("rensen_test_credentials");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("id_ed25519"), "key").unwrap();
+
+    let global_config = GlobalConfig { backups: root.clone(), key_rotation: Some(90), ..Default::default() };
+    let host_config = HostConfig {
+        identifier: String::from("host"),
+        key: Some(root.join("id_ed25519")),
+        encrypt_key: Some(PathBuf::from("/etc/rensen/passphrase")),
+        ..Default::default()
+    };
+    let now = chrono::Local::now().timestamp();
+    assert!(stale_keys(&global_config, &host_config, now).is_empty());
+
+    let stamps = KeyStamps { encrypt_key: Some(KeyStamp { path: PathBuf::from("/etc/rensen/passphrase"), changed: now - 100 * 86400, checked: now }) };
+    stamps.save(&global_config, &host_config).unwrap();
+    assert_eq!(KeyStamps::load(&global_config, &host_config), stamps);
+
+    let stale = stale_keys(&global_config, &host_config, now);
+    assert_eq!(stale, vec![StaleKey { kind: String::from("encryption"), path: PathBuf::from("/etc/rensen/passphrase"), days: 100 }]);
+    assert_eq!(stale_keys(&global_config, &host_config, now + 91 * 86400).len(), 2);
+
+    // Neither once the host moved to another passphrase, nor without a policy
+    let moved = HostConfig { encrypt_key: Some(PathBuf::from("/etc/rensen/passphrase.new")), ..host_config.clone() };
+    assert!(stale_keys(&global_config, &moved, now).is_empty());
+    assert!(stale_keys(&GlobalConfig { key_rotation: None, ..global_config.clone() }, &host_config, now + 365 * 86400).is_empty());
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,122 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use sha3::{Digest, Sha3_256};
+use tar::Archive;
+
+use crate::chunks::ChunkStore;
+use crate::codec::decoder;
+use crate::config::GlobalConfig;
+use crate::record::snapshot_archive;
+use crate::snapshot::FileEntry;
+
+/// Bytes per block compared between the copy before and the host, the same
+/// for both sides, see `rensen-helper blocks`
+pub const DELTA_BLOCK: u64 = 1024 * 1024;
+
+/// sha3-256 of every `block_size` bytes of what `reader` reads, the last
+/// block may be shorter
+pub fn block_hashes<R: Read>(reader: R, block_size: u64) -> io::Result<Vec<String>> {
+    let mut reader = reader;
+    let mut hashes = Vec::new();
+    loop {
+        let mut hasher = Sha3_256::new();
+        let read = io::copy(&mut (&mut reader).take(block_size), &mut hasher)?;
+        if read == 0 {
+            return Ok(hashes);
+        }
+        hashes.push(format!("{:x}", hasher.finalize()));
+    }
+}
+
+/// Writes the version of a file whose blocks hash to `remote` and that is
+/// `size` bytes to `out`. Blocks that hash the same in `basis`, the copy
+/// before, are copied from it, the others are fetched by `fetch(offset, len)`.
+/// Returns the bytes fetched.
+pub fn reconstruct<B, W, F>(basis: &mut B, local: &[String], remote: &[String], block_size: u64, size: u64, mut fetch: F, out: &mut W) -> io::Result<u64>
+where
+    B: Read + Seek,
+    W: Write,
+    F: FnMut(u64, u64) -> io::Result<Vec<u8>>
+{
+    let mut fetched = 0;
+    for (index, hash) in remote.iter().enumerate() {
+        let offset = index as u64 * block_size;
+        let len = block_size.min(size.saturating_sub(offset));
+
+        if local.get(index) == Some(hash) {
+            basis.seek(SeekFrom::Start(offset))?;
+            let copied = io::copy(&mut (&mut *basis).take(len), out)?;
+            if copied != len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The copy before ended early"));
+            }
+        } else {
+            let block = fetch(offset, len)?;
+            if block.len() as u64 != len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Got {} of {} bytes at {}", block.len(), len, offset)));
+            }
+            out.write_all(&block)?;
+            fetched += len;
+        }
+    }
+
+    Ok(fetched)
+}
+
+/// The contents `entry` had as of the backup before, for a delta to start
+/// from: the chunks of `dedup` hosts, the file if the snapshot was not
+/// archived, or else its member of the archive, which are written to
+/// `scratch`. None if none of them is at hand, e.g. with a remote store.
+pub fn basis(global_config: &GlobalConfig, entry: &FileEntry, scratch: &Path) -> Option<File> {
+    if let Some(chunks) = &entry.chunks {
+        ChunkStore::new(global_config).restore_file(chunks, scratch).ok()?;
+        return File::open(scratch).ok();
+    }
+
+    if entry.file_path.is_file() {
+        return File::open(&entry.file_path).ok();
+    }
+
+    let member = entry.file_path.strip_prefix(&entry.snapshot_path).ok()?;
+    let (archive, codec) = snapshot_archive(&entry.snapshot_path);
+    let mut archive = Archive::new(decoder(codec, File::open(archive).ok()?).ok()?);
+    for archived in archive.entries().ok()? {
+        let mut archived = archived.ok()?;
+        if archived.path().ok()?.as_ref() == member {
+            let mut file = File::create(scratch).ok()?;
+            io::copy(&mut archived, &mut file).ok()?;
+            return File::open(scratch).ok();
+        }
+    }
+
+    let _ = fs::remove_file(scratch);
+    None
+}
+
+#[test]
+fn test_delta() {
+    use std::io::Cursor;
+
+    let block = 4;
+    let before = b"aaaabbbbccccdd".to_vec();
+    let after = b"aaaaXbbbccccddeeee".to_vec();
+    let (local, remote) = (block_hashes(&before[..], block).unwrap(), block_hashes(&after[..], block).unwrap());
+    assert_eq!((local.len(), remote.len()), (4, 5));
+
+    // Only the changed and the added blocks are fetched
+    let mut asked = Vec::new();
+    let mut out = Vec::new();
+    let fetch = |offset: u64, len: u64| {
+        asked.push(offset);
+        Ok(after[offset as usize..(offset + len) as usize].to_vec())
+    };
+    let fetched = reconstruct(&mut Cursor::new(&before), &local, &remote, block, after.len() as u64, fetch, &mut out).unwrap();
+    assert_eq!(out, after);
+    assert_eq!(asked, vec![4, 12, 16]);
+    assert_eq!(fetched, 10);
+
+    // A short block from the host is an error, not a truncated file
+    let short = |_: u64, _: u64| Ok(Vec::new());
+    assert!(reconstruct(&mut Cursor::new(&before), &[], &remote, block, after.len() as u64, short, &mut Vec::new()).is_err());
+}
@@ -0,0 +1,100 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::config::HostConfig;
+
+/// The effective config of a host at the time of a snapshot, kept in its
+/// record so the next run can tell what was edited in between
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigFingerprint {
+    pub hash: String,                       // sha256 of `settings`
+    pub settings: BTreeMap<String, String>, // dotted key -> value as JSON, e.g. `restore.target`
+}
+
+fn flatten(prefix: &str, value: &Value, settings: &mut BTreeMap<String, String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let key = match prefix.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", prefix, key),
+                };
+                flatten(&key, value, settings);
+            }
+        },
+        Value::Null => (),
+        // Lists are compared as a whole, an added exclude shows the full list
+        value => {
+            settings.insert(prefix.to_string(), value.to_string());
+        },
+    }
+}
+
+impl ConfigFingerprint {
+    pub fn of(host_config: &HostConfig) -> Self {
+        let mut settings = BTreeMap::new();
+        if let Ok(value) = serde_json::to_value(host_config) {
+            flatten("", &value, &mut settings);
+        }
+
+        let mut hasher = Sha256::new();
+        for (key, value) in settings.iter() {
+            hasher.update(key.as_bytes());
+            hasher.update([0]);
+            hasher.update(value.as_bytes());
+            hasher.update([0]);
+        }
+        let hash = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        Self { hash, settings }
+    }
+
+    /// The settings that differ in `current`, one line each
+    pub fn changes(&self, current: &ConfigFingerprint) -> Vec<String> {
+        if self.hash == current.hash {
+            return Vec::new();
+        }
+
+        let mut changes = Vec::new();
+        for (key, value) in current.settings.iter() {
+            match self.settings.get(key) {
+                Some(previous) if previous == value => (),
+                Some(previous) => changes.push(format!("`{}` changed from {} to {}", key, previous, value)),
+                None => changes.push(format!("`{}` set to {}", key, value)),
+            }
+        }
+        for (key, value) in self.settings.iter().filter(|(key, _)| !current.settings.contains_key(*key)) {
+            changes.push(format!("`{}` unset, was {}", key, value));
+        }
+
+        changes
+    }
+}
+
+#[test]
+fn test_config_changes() {
+    use std::path::PathBuf;
+
+    let before = HostConfig { identifier: String::from("host"), source: PathBuf::from("/etc"), ..Default::default() };
+    let after = HostConfig {
+        compress: Some(true),
+        checksums: Some(vec![PathBuf::from("/etc/SHA256SUMS")]),
+        source: PathBuf::from("/srv"),
+        ..before.clone()
+    };
+
+    let previous = ConfigFingerprint::of(&before);
+    assert_eq!(previous, ConfigFingerprint::of(&before.clone()));
+    assert!(previous.changes(&ConfigFingerprint::of(&before)).is_empty());
+
+    let current = ConfigFingerprint::of(&after);
+    assert_ne!(previous.hash, current.hash);
+    assert_eq!(previous.changes(&current), vec![
+        String::from("`checksums` set to [\"/etc/SHA256SUMS\"]"),
+        String::from("`compress` set to true"),
+        String::from("`source` changed from \"/etc\" to \"/srv\""),
+    ]);
+    assert_eq!(current.changes(&previous)[2], "`compress` unset, was true");
+}
@@ -0,0 +1,95 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use ssh2::FileStat;
+
+use crate::config::HostConfig;
+use crate::helper::quote;
+use crate::incremental::ChangeDetection;
+use crate::utils::{digest_reader, sample_stream_digest};
+
+/// Whether `path` on the host is read through sudo, being at or below one of
+/// the `sudo` paths of `host_config`
+pub fn is_elevated(host_config: &HostConfig, path: &Path) -> bool {
+    host_config.sudo.iter().flatten().any(|elevated| path.starts_with(elevated))
+}
+
+/// Lists mtime, size, mode, uid, gid and path of every file below `path`,
+/// as root. `-n` makes sudo fail instead of asking for a password.
+pub fn list_command(path: &Path) -> String {
+    format!("sudo -n find {} -type f -printf '%T@ %s %m %U %G %p\\n'", quote(path))
+}
+
+/// Writes the file at `path` to stdout, as root
+pub fn read_command(path: &Path) -> String {
+    format!("sudo -n cat -- {}", quote(path))
+}
+
+/// The hash `detection` compares a file of `size` bytes with the record by,
+/// of `output`, its contents as read_command writes them. Sampled ones are
+/// read whole as well, the output can not seek.
+pub fn read_digest<R: Read>(detection: ChangeDetection, output: &mut R, size: u64) -> io::Result<String> {
+    match detection {
+        ChangeDetection::Sampled => sample_stream_digest(output, size),
+        _ => digest_reader(output),
+    }
+}
+
+/// The files of the output of list_command with what sftp would have stat'd
+pub fn parse_list(output: &str) -> Vec<(PathBuf, FileStat)> {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(6, ' ');
+            let mtime = fields.next()?.split('.').next()?.parse().ok()?;
+            let size = fields.next()?.parse().ok()?;
+            let perm = u32::from_str_radix(fields.next()?, 8).ok()?;
+            let uid = fields.next()?.parse().ok()?;
+            let gid = fields.next()?.parse().ok()?;
+            let path = PathBuf::from(fields.next()?);
+            let stat = FileStat { size: Some(size), uid: Some(uid), gid: Some(gid), perm: Some(perm), atime: None, mtime: Some(mtime) };
+            Some((path, stat))
+        })
+        .collect()
+}
+
+#[test]
+fn test_elevate() {
+    let host_config = HostConfig { source: PathBuf::from("/etc"), sudo: Some(vec![PathBuf::from("/etc/ssl/private"), PathBuf::from("/etc/shadow")]), ..Default::default() };
+    assert!(is_elevated(&host_config, Path::new("/etc/ssl/private/web.key")));
+    assert!(is_elevated(&host_config, Path::new("/etc/shadow")));
+    assert!(!is_elevated(&host_config, Path::new("/etc/shadowsocks")));
+    assert!(!is_elevated(&host_config, Path::new("/etc/hosts")));
+
+    assert_eq!(read_command(Path::new("/etc/it's")), "sudo -n cat -- '/etc/it'\\''s'");
+    let listed = parse_list("1714561200.25 1704 600 0 0 /etc/ssl/private/web key.pem\ngarbage\n");
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].0, PathBuf::from("/etc/ssl/private/web key.pem"));
+    assert_eq!((listed[0].1.mtime, listed[0].1.size, listed[0].1.perm), (Some(1714561200), Some(1704), Some(0o600)));
+}
+
+#[test]
+fn test_read_digest() {
+    use crate::incremental::{compare, Verdict};
+    use crate::snapshot::FileEntry;
+    use crate::utils::sample_digest;
+
+    // A root-only file rewritten in place with its mtime and size kept
+    let before = b"root:$6$before:19700:0:99999:7:::\n";
+    let after = b"root:$6$after!:19700:0:99999:7:::\n";
+    let size = before.len() as u64;
+    let entry = FileEntry {
+        mtime: 1714561200,
+        size,
+        hash: Some(digest_reader(&mut &before[..]).unwrap()),
+        sample: Some(sample_digest(&mut io::Cursor::new(before), size).unwrap()),
+        ..FileEntry::new()
+    };
+
+    for detection in [ChangeDetection::Sampled, ChangeDetection::Full] {
+        let changed = compare(detection, Some(&entry), entry.mtime, size, || read_digest(detection, &mut &after[..], size).ok());
+        assert_eq!(changed, Verdict::Fetch);
+        let kept = compare(detection, Some(&entry), entry.mtime, size, || read_digest(detection, &mut &before[..], size).ok());
+        assert_eq!(kept, Verdict::Skip);
+    }
+    assert_eq!(compare(ChangeDetection::Mtime, Some(&entry), entry.mtime, size, || read_digest(ChangeDetection::Mtime, &mut &after[..], size).ok()), Verdict::Skip);
+}
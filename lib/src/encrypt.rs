@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use ssh2::{Channel, Session};
+
+use crate::helper::quote;
+use crate::logging::Trap;
+
+/// Cipher file contents are encrypted with on the host, by `openssl enc`
+pub const CIPHER: &str = "aes-256-cbc";
+
+/// Encrypts `path` on the host to stdout, with the passphrase in `key`, a
+/// file on the host that never leaves it
+pub fn encrypt_command(key: &Path, path: &Path) -> String {
+    format!("openssl enc -{} -pbkdf2 -salt -pass file:{} -in {}", CIPHER, quote(key), quote(path))
+}
+
+/// Decrypts stdin on the host into `path`, see encrypt_command
+pub fn decrypt_command(key: &Path, path: &Path) -> String {
+    format!("openssl enc -d -{} -pbkdf2 -pass file:{} -out {}", CIPHER, quote(key), quote(path))
+}
+
+fn finish(mut channel: Channel, command: &str) -> Result<(), Trap> {
+    let mut errors = String::new();
+    let _ = channel.stderr().read_to_string(&mut errors);
+    let _ = channel.wait_close();
+
+    match channel.exit_status() {
+        Ok(0) => Ok(()),
+        Ok(status) => Err(Trap::Encrypt(format!("`{}` exited with status {}: {}", command, status, errors.trim()))),
+        Err(err) => Err(Trap::Encrypt(format!("`{}` did not finish: {}", command, err))),
+    }
+}
+
+/// Streams `source` from the host into `destination`, encrypted before it
+/// leaves the host. Returns the number of (encrypted) bytes received.
+pub fn fetch_encrypted<W: Write>(sess: &Session, key: &Path, source: &Path, destination: &mut W) -> Result<u64, Trap> {
+    let command = encrypt_command(key, source);
+    let mut channel = sess.channel_session()
+        .map_err(|err| Trap::Channel(format!("Could not open channel: {}", err)))?;
+    channel.exec(&command)
+        .map_err(|err| Trap::Channel(format!("Could not execute `{}`: {}", command, err)))?;
+
+    let size = io::copy(&mut channel, destination)
+        .map_err(|err| Trap::Channel(format!("Could not read encrypted {:?}: {}", source, err)))?;
+
+    finish(channel, &command)?;
+    Ok(size)
+}
+
+/// Uploads the encrypted `local` file to `remote` on the host, decrypting it
+/// there
+pub fn upload_decrypted(sess: &Session, key: &Path, local: &Path, remote: &Path) -> Result<(), Trap> {
+    let command = decrypt_command(key, remote);
+    let mut source = File::open(local)
+        .map_err(|err| Trap::FS(format!("Could not open {:?}: {}", local, err)))?;
+    let mut channel = sess.channel_session()
+        .map_err(|err| Trap::Channel(format!("Could not open channel: {}", err)))?;
+    channel.exec(&command)
+        .map_err(|err| Trap::Channel(format!("Could not execute `{}`: {}", command, err)))?;
+
+    io::copy(&mut source, &mut channel)
+        .map_err(|err| Trap::Channel(format!("Could not upload {:?}: {}", remote, err)))?;
+    channel.send_eof()
+        .map_err(|err| Trap::Channel(format!("Could not finish upload of {:?}: {}", remote, err)))?;
+
+    finish(channel, &command)
+}
+
+#[test]
+fn test_encrypt_round_trip() {
+    use std::fs;
+    use std::process::Command;
+
+    let root = std::env::temp_dir().join("rensen_test_encrypt");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("key"), "passphrase").unwrap();
+    fs::write(root.join("it's secret"), "plain data").unwrap();
+
+    // What the host runs, here without ssh in between
+    let run = |command: String, input: &[u8]| {
+        let mut child = Command::new("sh").arg("-c").arg(command)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let encrypted = run(encrypt_command(&root.join("key"), &root.join("it's secret")), b"");
+    if !encrypted.status.success() {
+        return; // no openssl here
+    }
+    assert!(!encrypted.stdout.windows(10).any(|window| window == b"plain data"));
+
+    let decrypted = run(decrypt_command(&root.join("key"), &root.join("restored")), &encrypted.stdout);
+    assert!(decrypted.status.success());
+    assert_eq!(fs::read_to_string(root.join("restored")).unwrap(), "plain data");
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,89 @@
+use std::process;
+
+use crate::logging::Trap;
+
+/// Exit codes shared by `rensen` and `rensend`, so wrapper scripts and
+/// systemd (`Restart=on-failure`, `RestartPreventExitStatus=`) can tell
+/// what went wrong without parsing the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExitCode {
+    Success    = 0,
+    Failure    = 1, // Anything not covered below
+    Warnings   = 2, // Completed, but files were skipped, a deadline was missed or the source is nearly full
+    Partial    = 3, // Some hosts succeeded while others failed
+    Config     = 4, // Invalid configuration or usage, or a repository of another format, retrying won't help
+    Connection = 5, // Host unreachable or authentication failed
+    Lock       = 6, // Another backup of the same host is already running, or it was run by hand too often
+}
+
+impl ExitCode {
+    /// Combines the exit codes of several hosts into one. Mixed success and
+    /// failure results in `Partial`, otherwise the most severe code wins.
+    pub fn combine<I>(codes: I) -> Self
+    where
+        I: IntoIterator<Item = ExitCode>
+    {
+        let codes: Vec<ExitCode> = codes.into_iter().collect();
+        let failed = codes.iter().filter(|code| code.is_failure()).count();
+
+        if failed > 0 && failed < codes.len() {
+            return ExitCode::Partial;
+        }
+
+        codes.into_iter().max().unwrap_or(ExitCode::Success)
+    }
+
+    pub fn is_failure(&self) -> bool {
+        !matches!(self, ExitCode::Success | ExitCode::Warnings)
+    }
+}
+
+impl From<&Trap> for ExitCode {
+    fn from(trap: &Trap) -> Self {
+        match trap {
+            Trap::Config(_)
+            | Trap::Missing(_)
+            | Trap::InvalidInput(_)
+            | Trap::Deserialize(_)
+            | Trap::ReadOnly(_)
+            | Trap::Incompatible(_) => ExitCode::Config,
+            Trap::Connect(_)
+            | Trap::Session(_)
+            | Trap::Handshake(_)
+            | Trap::KeyLoad(_)
+            | Trap::Auth(_)         => ExitCode::Connection,
+            Trap::Lock(_)
+            | Trap::RateLimit(_)    => ExitCode::Lock,
+            Trap::Sla(_)
+            | Trap::Source(_)       => ExitCode::Warnings,
+            _                       => ExitCode::Failure,
+        }
+    }
+}
+
+impl From<&Result<(), Trap>> for ExitCode {
+    fn from(result: &Result<(), Trap>) -> Self {
+        match result {
+            Ok(_) => ExitCode::Success,
+            Err(trap) => ExitCode::from(trap),
+        }
+    }
+}
+
+impl From<ExitCode> for process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        process::ExitCode::from(code as u8)
+    }
+}
+
+#[test]
+fn test_exit_code() {
+    assert_eq!(ExitCode::from(&Trap::Connect(String::new())), ExitCode::Connection);
+    assert_eq!(ExitCode::from(&Trap::Config(String::new())), ExitCode::Config);
+    assert_eq!(ExitCode::from(&Ok(())), ExitCode::Success);
+
+    assert_eq!(ExitCode::combine([]), ExitCode::Success);
+    assert_eq!(ExitCode::combine([ExitCode::Success, ExitCode::Warnings]), ExitCode::Warnings);
+    assert_eq!(ExitCode::combine([ExitCode::Success, ExitCode::Connection]), ExitCode::Partial);
+    assert_eq!(ExitCode::combine([ExitCode::Lock, ExitCode::Connection]), ExitCode::Lock);
+}
@@ -0,0 +1,173 @@
+use std::path::Path;
+
+use crate::config::HostConfig;
+
+/// The `exclude` and `include` glob patterns of a host, matched like rsync's
+/// --exclude against paths relative to the source:
+///
+/// - a pattern without a `/` matches a name at any depth, e.g. `*.log`
+/// - one with a `/` matches from the source on, e.g. `/var/cache` or `build/*.o`
+/// - a trailing `/` only matches directories, e.g. `node_modules/`
+/// - `*` and `?` stop at a `/`, `**` does not, `[a-z]` matches one of a class
+///
+/// A path an `include` matches is kept even if an `exclude` matches it too.
+/// An excluded directory is skipped whole, nothing below it is looked at.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    exclude: Vec<Pattern>,
+    include: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    glob: String,
+    anchored: bool, // matched against the whole path instead of the name
+    dir_only: bool,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        let dir_only = pattern.len() > 1 && pattern.ends_with('/');
+        let glob = pattern.trim_end_matches('/');
+        let anchored = glob.contains('/');
+        Pattern { glob: glob.trim_start_matches('/').to_string(), anchored, dir_only }
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let text = match self.anchored {
+            true => path,
+            false => path.rsplit('/').next().unwrap_or(path),
+        };
+        glob_match(self.glob.as_bytes(), text.as_bytes())
+    }
+}
+
+impl Filter {
+    pub fn new(host_config: &HostConfig) -> Self {
+        let parse = |patterns: &Option<Vec<String>>| patterns.iter().flatten().map(|pattern| Pattern::parse(pattern)).collect();
+        Filter { exclude: parse(&host_config.exclude), include: parse(&host_config.include) }
+    }
+
+    /// Whether `relative`, a path below the source, is left out itself
+    pub fn excludes(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.exclude.is_empty() {
+            return false;
+        }
+
+        let path = relative.to_string_lossy();
+        let path = path.trim_start_matches('/');
+        self.exclude.iter().any(|pattern| pattern.matches(path, is_dir))
+            && !self.include.iter().any(|pattern| pattern.matches(path, is_dir))
+    }
+
+    /// Whether the file `relative` is left out, itself or with a directory
+    /// it is in, for sources listed as a whole instead of walked
+    pub fn excludes_within(&self, relative: &Path) -> bool {
+        if self.exclude.is_empty() {
+            return false;
+        }
+
+        relative.ancestors()
+            .skip(1)
+            .filter(|parent| !parent.as_os_str().is_empty() && parent.as_os_str() != "/")
+            .any(|parent| self.excludes(parent, true))
+            || self.excludes(relative, false)
+    }
+}
+
+/// Whether `text` matches the glob `pattern` as a whole
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            // `**/` also matches no directory at all
+            let rest = &pattern[2..];
+            if let Some(after) = rest.strip_prefix(b"/") {
+                if glob_match(after, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|skip| glob_match(rest, &text[skip..]))
+        },
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            let within = text.iter().position(|byte| *byte == b'/').unwrap_or(text.len());
+            (0..=within).any(|skip| glob_match(rest, &text[skip..]))
+        },
+        Some(b'?') => matches!(text.first(), Some(byte) if *byte != b'/') && glob_match(&pattern[1..], &text[1..]),
+        Some(b'[') => match (class(&pattern[1..]), text.first()) {
+            (Some((set, negated, rest)), Some(byte)) => {
+                *byte != b'/' && in_class(set, *byte) != negated && glob_match(rest, &text[1..])
+            },
+            (Some(_), None) => false,
+            // An unterminated class is a plain `[`
+            (None, _) => text.first() == Some(&b'[') && glob_match(&pattern[1..], &text[1..]),
+        },
+        Some(byte) => text.first() == Some(byte) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// The members of the class `pattern` starts with (after its `[`), whether
+/// it is negated, and the pattern after its `]`
+fn class(pattern: &[u8]) -> Option<(&[u8], bool, &[u8])> {
+    let (negated, pattern) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+
+    // A `]` right at the start is a member
+    let end = pattern.iter().skip(1).position(|byte| *byte == b']')? + 1;
+    Some((&pattern[..end], negated, &pattern[end + 1..]))
+}
+
+fn in_class(set: &[u8], byte: u8) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == b'-' {
+            if (set[i]..=set[i + 2]).contains(&byte) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == byte {
+                return true;
+            }
+            i += 1;
+        }
+    }
+
+    false
+}
+
+#[test]
+fn test_filter() {
+    let host_config = HostConfig {
+        exclude: Some(vec![String::from("*.log"), String::from("node_modules/"), String::from("/var/cache"), String::from("build/**/*.o"), String::from("tmp[0-9]")]),
+        include: Some(vec![String::from("keep.log")]),
+        ..Default::default()
+    };
+    let filter = Filter::new(&host_config);
+
+    assert!(filter.excludes(Path::new("app/debug.log"), false));
+    assert!(!filter.excludes(Path::new("app/keep.log"), false));
+    assert!(!filter.excludes(Path::new("app/debug.log.gz"), false));
+    assert!(filter.excludes(Path::new("web/node_modules"), true));
+    assert!(!filter.excludes(Path::new("web/node_modules"), false));
+    assert!(filter.excludes(Path::new("/var/cache"), true));
+    assert!(!filter.excludes(Path::new("srv/var/cache"), true));
+    assert!(filter.excludes(Path::new("build/main.o"), false));
+    assert!(filter.excludes(Path::new("build/a/b/main.o"), false));
+    assert!(filter.excludes(Path::new("tmp1"), true) && !filter.excludes(Path::new("tmpx"), true));
+
+    // Files within an excluded directory, for flat listings
+    assert!(filter.excludes_within(Path::new("web/node_modules/react/index.js")));
+    assert!(!filter.excludes_within(Path::new("web/src/index.js")));
+    assert!(!Filter::new(&HostConfig::default()).excludes_within(Path::new("a.log")));
+
+    assert!(glob_match(b"*.[ch]", b"main.c") && !glob_match(b"*.[!ch]", b"main.c"));
+    assert!(!glob_match(b"*", b"a/b") && glob_match(b"**", b"a/b"));
+}
@@ -0,0 +1,123 @@
+use chrono::{DateTime, Local};
+use std::fs;
+
+use crate::compact::snapshot_time;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::record::snapshot_archive;
+use crate::retention::expired;
+use crate::retire::Retirement;
+use crate::verify::snapshots;
+
+/// A month, roughly, for projections
+pub const MONTH: i64 = 30 * 24 * 60 * 60;
+
+/// Projected size of the snapshot archives of one chain
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Forecast {
+    pub current: u64,             // bytes of archives kept right now, after pruning
+    pub months: Vec<u64>,         // bytes at the end of each of the next months
+    pub interval: Option<i64>,    // seconds between snapshots so far, None with fewer than two
+    pub snapshot_bytes: u64,      // bytes a new snapshot is expected to add
+}
+
+/// Projects the archives of `host_config` over the next `months`, assuming
+/// snapshots keep coming at the pace and size they have so far and are
+/// pruned by its retention. Snapshots the live record still references are
+/// not singled out, so a chain that rarely changes may keep more.
+pub fn forecast(global_config: &GlobalConfig, host_config: &HostConfig, now: i64, months: u32) -> Forecast {
+    let root = host_config.root(global_config);
+    let taken: Vec<(String, i64, u64)> = snapshots(global_config, host_config).into_iter()
+        .filter_map(|name| {
+            let time = snapshot_time(&name)?;
+            let size = fs::metadata(snapshot_archive(&root.join(&name)).0).map(|metadata| metadata.len()).unwrap_or(0);
+            Some((name, time, size))
+        })
+        .collect();
+
+    // Retired hosts take no more snapshots and keep them all until the final expiry
+    if let Ok(Some(retirement)) = Retirement::load(global_config, host_config) {
+        let current = taken.iter().map(|(_, _, size)| size).sum();
+        let months = (1..=months as i64)
+            .map(|month| match retirement.until {
+                Some(until) if now + month * MONTH > until => 0,
+                _ => current,
+            })
+            .collect();
+        return Forecast { current, months, interval: None, snapshot_bytes: 0 };
+    }
+
+    project(host_config, &taken, now, months)
+}
+
+fn name_of(time: i64) -> String {
+    DateTime::from_timestamp(time, 0)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d-%H-%M-%S").to_string())
+        .unwrap_or_default()
+}
+
+/// The projection of forecast for the snapshots `taken` (name, time and
+/// archive size, oldest first)
+fn project(host_config: &HostConfig, taken: &[(String, i64, u64)], now: i64, months: u32) -> Forecast {
+    let interval = match (taken.first(), taken.last()) {
+        (Some(first), Some(last)) if taken.len() > 1 && last.1 > first.1 => Some((last.1 - first.1) / (taken.len() as i64 - 1)),
+        _ => None,
+    };
+
+    // The first one is the full backup, what follows is what growth looks like
+    let recent: Vec<u64> = taken.iter().skip(1).rev().take(10).map(|(_, _, size)| *size).collect();
+    let snapshot_bytes = match recent.len() {
+        0 => 0,
+        n => recent.iter().sum::<u64>() / n as u64,
+    };
+
+    let mut snapshots: Vec<(String, u64)> = taken.iter().map(|(name, _, size)| (name.clone(), *size)).collect();
+    let kept_at = |snapshots: &[(String, u64)], at: i64| -> u64 {
+        let names: Vec<String> = snapshots.iter().map(|(name, _)| name.clone()).collect();
+        let gone = expired(host_config, &names, at);
+        snapshots.iter().filter(|(name, _)| !gone.contains(name)).map(|(_, size)| size).sum()
+    };
+
+    let mut forecast = Forecast { current: kept_at(&snapshots, now), months: Vec::new(), interval, snapshot_bytes };
+    let mut next = taken.last().map(|(_, time, _)| *time).unwrap_or(now);
+    for month in 1..=months as i64 {
+        let end = now + month * MONTH;
+        while let Some(step) = interval.filter(|step| next + step <= end) {
+            next += step;
+            snapshots.push((name_of(next), snapshot_bytes));
+        }
+
+        // Pruned as of then, which is also what later months start from
+        let names: Vec<String> = snapshots.iter().map(|(name, _)| name.clone()).collect();
+        let gone = expired(host_config, &names, end);
+        snapshots.retain(|(name, _)| !gone.contains(name));
+        forecast.months.push(snapshots.iter().map(|(_, size)| size).sum());
+    }
+
+    forecast
+}
+
+#[test]
+fn test_forecast() {
+    use crate::retention::KeepPolicy;
+
+    let day = 24 * 60 * 60;
+    let start = snapshot_time("2024-01-01-00-00-00").unwrap();
+    let taken: Vec<(String, i64, u64)> = (0..10)
+        .map(|i| (name_of(start + i * day), start + i * day, if i == 0 { 1000 } else { 10 }))
+        .collect();
+    let now = start + 9 * day;
+
+    // Kept forever, it grows by a snapshot a day
+    let forever = project(&HostConfig::default(), &taken, now, 2);
+    assert_eq!((forever.interval, forever.snapshot_bytes, forever.current), (Some(day), 10, 1090));
+    assert_eq!(forever.months, vec![1090 + 30 * 10, 1090 + 60 * 10]);
+
+    // A keep policy caps it, the full backup ages out as well
+    let capped = HostConfig { keep: Some(KeepPolicy { last: Some(7), ..Default::default() }), ..Default::default() };
+    let forecast = project(&capped, &taken, now, 2);
+    assert_eq!(forecast.current, 70);
+    assert_eq!(forecast.months, vec![70, 70]);
+
+    // A single snapshot tells nothing about growth
+    assert_eq!(project(&HostConfig::default(), &taken[..1], now, 1).months, vec![1000]);
+}
@@ -0,0 +1,118 @@
+use std::io::{self, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Size of the rolling window, and the average size of a gzip member
+pub const RSYNC_WINDOW: usize = 4096;
+
+/// Members are never cut shorter than this, keeps the overhead of headers down
+const MIN_MEMBER: usize = 64 * 1024;
+
+/// Gzip encoder in the spirit of `gzip --rsyncable`. The output is a series
+/// of complete gzip members, each one ending where a rolling sum over the
+/// last RSYNC_WINDOW input bytes hits a boundary. As the boundaries depend on
+/// the content only, a change in the input only affects the members around
+/// it, and rsync/zsync can skip the rest of the archive when replicating.
+///
+/// Any gzip reader handling multiple members (`gzip -d`, MultiGzDecoder) can
+/// read the output.
+pub struct RsyncableGzEncoder<W: Write> {
+    encoder: Option<GzEncoder<W>>,
+    compression: Compression,
+    window: Box<[u8; RSYNC_WINDOW]>,
+    position: usize,
+    sum: u32,
+    member_len: usize,
+}
+
+impl<W: Write> RsyncableGzEncoder<W> {
+    pub fn new(writer: W, compression: Compression) -> Self {
+        RsyncableGzEncoder {
+            encoder: Some(GzEncoder::new(writer, compression)),
+            compression,
+            window: Box::new([0; RSYNC_WINDOW]),
+            position: 0,
+            sum: 0,
+            member_len: 0,
+        }
+    }
+
+    /// Finishes the last member and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.encoder.take().unwrap().finish()
+    }
+
+    fn encoder(&mut self) -> &mut GzEncoder<W> {
+        self.encoder.as_mut().unwrap()
+    }
+
+    /// Ends the current member and starts the next one
+    fn cut(&mut self) -> io::Result<()> {
+        let writer = self.encoder.take().unwrap().finish()?;
+        self.encoder = Some(GzEncoder::new(writer, self.compression));
+        self.member_len = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for RsyncableGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for (i, &byte) in buf.iter().enumerate() {
+            self.sum = self.sum
+                .wrapping_sub(self.window[self.position] as u32)
+                .wrapping_add(byte as u32);
+            self.window[self.position] = byte;
+            self.position = (self.position + 1) % RSYNC_WINDOW;
+            self.member_len += 1;
+
+            if self.member_len >= MIN_MEMBER && self.sum.is_multiple_of(RSYNC_WINDOW as u32) {
+                self.encoder().write_all(&buf[start..=i])?;
+                start = i + 1;
+                self.cut()?;
+            }
+        }
+
+        self.encoder().write_all(&buf[start..])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder().flush()
+    }
+}
+
+#[test]
+fn test_rsyncable_gz() {
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    // Pseudo random input, so the rolling sum actually moves around
+    let mut state: u32 = 1;
+    let mut input: Vec<u8> = (0..1_000_000).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect();
+
+    let compress = |input: &[u8]| {
+        let mut encoder = RsyncableGzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(input).unwrap();
+        encoder.finish().unwrap()
+    };
+
+    let original = compress(&input);
+    let mut decoded = Vec::new();
+    MultiGzDecoder::new(&original[..]).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, input);
+
+    // Changing a byte near the start leaves the tail of the output as it was
+    input[1000] ^= 0xff;
+    let changed = compress(&input);
+    let common_tail = original.iter().rev()
+        .zip(changed.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    assert!(common_tail > original.len() / 2, "{} of {}", common_tail, original.len());
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use ssh2::{RenameFlags, Session};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+
+/// Protocol of the helper this build speaks, see helper/src/main.rs
+pub const HELPER_PROTOCOL: u32 = 3;
+
+/// Where the helper is kept on hosts, relative to the ssh user's home
+pub const REMOTE_HELPER_PATH: &str = ".rensen/rensen-helper";
+
+/// Local helper binary deployed when `helper_path` is not set
+pub const DEFAULT_HELPER_PATH: &str = "/usr/lib/rensen/rensen-helper";
+
+/// A helper found on a host that speaks our protocol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Helper {
+    pub protocol: u32,
+    pub version: String,
+}
+
+/// A file as listed by `rensen-helper tree`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HelperEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+/// Parses `rensen-helper <protocol> <version>`
+pub fn parse_version(output: &str) -> Option<Helper> {
+    let mut fields = output.split_whitespace();
+    if fields.next()? != "rensen-helper" {
+        return None;
+    }
+
+    let protocol = fields.next()?.parse().ok()?;
+    let version = fields.next()?.to_string();
+    Some(Helper { protocol, version })
+}
+
+/// Hard links and device nodes below a directory, as listed by
+/// `rensen-helper special`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HelperSpecial {
+    pub links: Vec<Vec<PathBuf>>,       // the paths of each file with more than one, sorted
+    pub devices: HashMap<PathBuf, u64>, // device number of each device node
+}
+
+/// Parses `hash  path` lines
+pub fn parse_sha3(output: &str) -> Vec<(PathBuf, String)> {
+    output.lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, path)| (PathBuf::from(path), hash.to_string()))
+        .collect()
+}
+
+/// Parses `mtime size path` lines
+pub fn parse_tree(output: &str) -> Vec<HelperEntry> {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let mtime = fields.next()?.parse().ok()?;
+            let size = fields.next()?.parse().ok()?;
+            let path = PathBuf::from(fields.next()?);
+            Some(HelperEntry { path, size, mtime })
+        })
+        .collect()
+}
+
+/// Parses `link dev:ino path` and `device rdev path` lines
+pub fn parse_special(output: &str) -> HelperSpecial {
+    let mut inodes: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+    let mut devices = HashMap::new();
+    for line in output.lines() {
+        let mut fields = line.splitn(3, ' ');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("link"), Some(inode), Some(path)) => inodes.entry(inode).or_default().push(PathBuf::from(path)),
+            (Some("device"), Some(rdev), Some(path)) => {
+                if let Ok(rdev) = rdev.parse() {
+                    devices.insert(PathBuf::from(path), rdev);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // Links of which only one is below the directory are files like any other
+    let links = inodes.into_values()
+        .filter(|paths| paths.len() > 1)
+        .map(|mut paths| { paths.sort(); paths })
+        .collect();
+    HelperSpecial { links, devices }
+}
+
+pub(crate) fn quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
+
+/// Runs the helper with `args`, returning its stdout
+fn run(sess: &Session, args: &str) -> Result<String, Trap> {
+    let command = format!("{} {}", REMOTE_HELPER_PATH, args);
+    let mut channel = sess.channel_session()
+        .map_err(|err| Trap::Helper(format!("Could not open channel: {}", err)))?;
+
+    channel.exec(&command)
+        .map_err(|err| Trap::Helper(format!("Could not execute `{}`: {}", command, err)))?;
+
+    let mut output = String::new();
+    let mut errors = String::new();
+    channel.read_to_string(&mut output)
+        .map_err(|err| Trap::Helper(format!("Could not read output of `{}`: {}", command, err)))?;
+    let _ = channel.stderr().read_to_string(&mut errors);
+    let _ = channel.wait_close();
+
+    match channel.exit_status() {
+        Ok(0) => Ok(output),
+        Ok(status) => Err(Trap::Helper(format!("`{}` exited with status {}: {}", command, status, errors.trim()))),
+        Err(err) => Err(Trap::Helper(format!("`{}` did not finish: {}", command, err))),
+    }
+}
+
+impl Helper {
+    /// The helper on the host, if there is one speaking our protocol
+    pub fn probe(sess: &Session) -> Option<Helper> {
+        run(sess, "version").ok()
+            .and_then(|output| parse_version(&output))
+            .filter(|helper| helper.protocol == HELPER_PROTOCOL)
+    }
+
+    /// Uploads the local helper binary over sftp, replacing whatever is there
+    pub fn deploy(sess: &Session, local: &Path) -> Result<(), Trap> {
+        let binary = fs::read(local)
+            .map_err(|err| Trap::Helper(format!("Could not read helper binary {:?}: {}", local, err)))?;
+
+        let sftp = sess.sftp()
+            .map_err(|err| Trap::Helper(format!("Could not open sftp: {}", err)))?;
+
+        let remote = Path::new(REMOTE_HELPER_PATH);
+        let staging = remote.with_extension("new");
+        if let Some(parent) = remote.parent() {
+            // Fails when it exists, which is fine
+            let _ = sftp.mkdir(parent, 0o700);
+        }
+
+        let mut file = sftp.open_mode(&staging, ssh2::OpenFlags::WRITE | ssh2::OpenFlags::CREATE | ssh2::OpenFlags::TRUNCATE, 0o755, ssh2::OpenType::File)
+            .map_err(|err| Trap::Helper(format!("Could not create {:?} on host: {}", staging, err)))?;
+        file.write_all(&binary)
+            .map_err(|err| Trap::Helper(format!("Could not upload helper: {}", err)))?;
+        drop(file);
+
+        // Some servers lack posix-rename, the old binary goes first there
+        if sftp.rename(&staging, remote, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC)).is_err() {
+            let _ = sftp.unlink(remote);
+            sftp.rename(&staging, remote, None)
+                .map_err(|err| Trap::Helper(format!("Could not move helper into place: {}", err)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Negotiates the helper for `host_config`. A missing or outdated helper
+    /// is (re)deployed once. With `helper` unset, or when deployment fails,
+    /// this is `Ok(None)` and callers stay on pure sftp.
+    pub fn negotiate(global_config: &GlobalConfig, host_config: &HostConfig, sess: &Session) -> Result<Option<Helper>, Trap> {
+        if !host_config.helper.unwrap_or(false) {
+            return Ok(None);
+        }
+
+        if let Some(helper) = Helper::probe(sess) {
+            return Ok(Some(helper));
+        }
+
+        let local = global_config.helper_path.clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_HELPER_PATH));
+
+        Helper::deploy(sess, &local)?;
+        Helper::probe(sess)
+            .map(Some)
+            .ok_or(Trap::Helper(format!("Deployed helper from {:?} does not speak protocol {}", local, HELPER_PROTOCOL)))
+    }
+
+    /// sha3-256 of each of `paths` on the host
+    pub fn sha3(&self, sess: &Session, paths: &[PathBuf]) -> Result<Vec<(PathBuf, String)>, Trap> {
+        let args: Vec<String> = paths.iter().map(|path| quote(path)).collect();
+        Ok(parse_sha3(&run(sess, &format!("sha3 {}", args.join(" ")))?))
+    }
+
+    /// Every file below `dir` on the host, in one round trip
+    pub fn tree(&self, sess: &Session, dir: &Path) -> Result<Vec<HelperEntry>, Trap> {
+        Ok(parse_tree(&run(sess, &format!("tree {}", quote(dir)))?))
+    }
+
+    /// The hard links and device nodes below `dir` on the host, which sftp
+    /// does not tell
+    pub fn special(&self, sess: &Session, dir: &Path) -> Result<HelperSpecial, Trap> {
+        Ok(parse_special(&run(sess, &format!("special {}", quote(dir)))?))
+    }
+
+    /// sha3-256 of every `block_size` bytes of `path` on the host, see delta
+    pub fn blocks(&self, sess: &Session, path: &Path, block_size: u64) -> Result<Vec<String>, Trap> {
+        let output = run(sess, &format!("blocks {} {}", block_size, quote(path)))?;
+        Ok(output.lines().map(String::from).collect())
+    }
+}
+
+#[test]
+fn test_parse_helper_output() {
+    assert_eq!(parse_version("rensen-helper 1 0.1.0\n"), Some(Helper { protocol: 1, version: String::from("0.1.0") }));
+    assert_eq!(parse_version("sh: rensen-helper: not found"), None);
+    assert_eq!(parse_version("rensen-helper x 0.1.0"), None);
+
+    let hashes = parse_sha3("abc  /etc/my file\n");
+    assert_eq!(hashes, vec![(PathBuf::from("/etc/my file"), String::from("abc"))]);
+
+    let entries = parse_tree("10 5 /srv/a b\nbroken\n");
+    assert_eq!(entries, vec![HelperEntry { path: PathBuf::from("/srv/a b"), size: 5, mtime: 10 }]);
+
+    let special = parse_special("link 2049:17 /srv/b\nlink 2049:9 /srv/once\nlink 2049:17 /srv/a c\ndevice 259 /srv/dev/null\n");
+    assert_eq!(special.links, vec![vec![PathBuf::from("/srv/a c"), PathBuf::from("/srv/b")]]);
+    assert_eq!(special.devices, HashMap::from([(PathBuf::from("/srv/dev/null"), 259)]));
+}
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::snapshot::Snapshot;
+
+/// Upper bounds of the size buckets, in bytes, files of that size or more
+/// go to the last bucket
+pub const SIZE_BOUNDS: [u64; 5] = [
+    4 * 1024,
+    64 * 1024,
+    1024 * 1024,
+    16 * 1024 * 1024,
+    256 * 1024 * 1024,
+];
+
+/// Extensions kept by name, the ones with fewer bytes are counted as `other`
+pub const MAX_EXTENSIONS: usize = 16;
+
+/// Files and bytes counted in one bucket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Bucket {
+    fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+    }
+}
+
+/// What the files a snapshot fetched are, by extension and by size, so the
+/// growth of a host can be told apart without walking its archives. Kept in
+/// the snapshot's record, it outlives compaction.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub extensions: BTreeMap<String, Bucket>, // lowercase, "" for none and "other" for the rest
+    pub sizes: Vec<Bucket>,                   // one per SIZE_BOUNDS and one above
+}
+
+impl Histogram {
+    /// Of the entries of `snapshot` that were fetched into `snapshot_root`
+    pub fn of(snapshot: &Snapshot, snapshot_root: &Path) -> Self {
+        let mut extensions: BTreeMap<String, Bucket> = BTreeMap::new();
+        let mut sizes = vec![Bucket::default(); SIZE_BOUNDS.len() + 1];
+        for (path, entry) in snapshot.entries.iter().filter(|(_, entry)| entry.snapshot_path == snapshot_root) {
+            extensions.entry(extension(path)).or_default().add(entry.size);
+            sizes[size_bucket(entry.size)].add(entry.size);
+        }
+
+        // A small histogram, the extensions with the most bytes by name
+        if extensions.len() > MAX_EXTENSIONS {
+            let mut ranked: Vec<(String, Bucket)> = extensions.into_iter().collect();
+            ranked.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(&b.0)));
+            let rest = ranked.split_off(MAX_EXTENSIONS - 1);
+            extensions = ranked.into_iter().collect();
+            let other = extensions.entry(String::from("other")).or_default();
+            for (_, bucket) in rest {
+                other.files += bucket.files;
+                other.bytes += bucket.bytes;
+            }
+        }
+
+        Histogram { extensions, sizes }
+    }
+
+    /// The extensions, most bytes first
+    pub fn ranked(&self) -> Vec<(&str, Bucket)> {
+        let mut ranked: Vec<(&str, Bucket)> = self.extensions.iter().map(|(name, bucket)| (name.as_str(), *bucket)).collect();
+        ranked.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(b.0)));
+        ranked
+    }
+}
+
+/// The extension of `path` as counted, lowercase
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// The index of the bucket of a file of `size` bytes
+pub fn size_bucket(size: u64) -> usize {
+    SIZE_BOUNDS.iter().position(|bound| size < *bound).unwrap_or(SIZE_BOUNDS.len())
+}
+
+#[test]
+fn test_histogram() {
+    use crate::snapshot::FileEntry;
+    use std::path::PathBuf;
+
+    let root = PathBuf::from("/backups/db/2024-05-02-00-00-00");
+    let before = PathBuf::from("/backups/db/2024-05-01-00-00-00");
+    let mut snapshot = Snapshot::new();
+    for (path, snapshot_path, size) in [
+        ("/var/lib/pg/000001.WAL", &root, 16 * 1024 * 1024),
+        ("/var/lib/pg/000002.wal", &root, 16 * 1024 * 1024),
+        ("/var/lib/pg/PG_VERSION", &root, 3),
+        ("/var/lib/pg/base.tar", &before, 1024),
+    ] {
+        snapshot.entries.insert(PathBuf::from(path), FileEntry::from(PathBuf::new(), snapshot_path.clone(), 0, size));
+    }
+
+    // Only what the snapshot fetched counts
+    let histogram = Histogram::of(&snapshot, &root);
+    assert_eq!(histogram.ranked(), vec![("wal", Bucket { files: 2, bytes: 32 * 1024 * 1024 }), ("", Bucket { files: 1, bytes: 3 })]);
+    assert_eq!(histogram.sizes.iter().map(|bucket| bucket.files).collect::<Vec<_>>(), vec![1, 0, 0, 0, 2, 0]);
+    assert_eq!((size_bucket(0), size_bucket(4095), size_bucket(4096), size_bucket(u64::MAX)), (0, 0, 1, 5));
+
+    // Past MAX_EXTENSIONS the smallest are summed up as other
+    let mut snapshot = Snapshot::new();
+    for i in 0..20u64 {
+        snapshot.entries.insert(PathBuf::from(format!("/f.e{}", i)), FileEntry::from(PathBuf::new(), root.clone(), 0, 100 + i));
+    }
+    let histogram = Histogram::of(&snapshot, &root);
+    assert_eq!(histogram.extensions.len(), MAX_EXTENSIONS);
+    assert_eq!(histogram.extensions["other"], Bucket { files: 5, bytes: 100 + 101 + 102 + 103 + 104 });
+    assert!(histogram.extensions.contains_key("e19"));
+}
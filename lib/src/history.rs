@@ -0,0 +1,336 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::GlobalConfig;
+use crate::logging::{Trap, log_trap};
+use crate::ledger::TransferLedger;
+use crate::quota::DiskUsage;
+use crate::usage::ResourceUsage;
+use crate::phases::Phases;
+
+/// Outcome and stats of a single backup run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunOutcome {
+    pub hostname: String,
+    pub started: i64,
+    pub finished: i64,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub bytes: u64,  // transferred during the run
+    pub files: u64,  // transferred during the run
+    pub size: u64,   // total size of the host's record after the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<DiskUsage>, // filesystem usage at the host's source before the run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notices: Vec<String>,      // see BackupReport::notices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_inodes: Option<u64>, // inodes the host takes up at the destination after the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResourceUsage>,    // CPU, memory and disk I/O the run took on the backup server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phases: Option<Phases>,          // where the time of the run went
+}
+
+impl RunOutcome {
+    pub fn duration(&self) -> i64 {
+        self.finished - self.started
+    }
+}
+
+/// Append-only database of every run, for all hosts.
+/// Kept apart from the per-host records as one JSON object per line.
+pub struct History {
+    pub path: PathBuf,
+}
+
+impl History {
+    pub fn new(global_config: &GlobalConfig) -> Self {
+        let path = match &global_config.history {
+            Some(path) => path.clone(),
+            None => global_config.log
+                .parent()
+                .unwrap_or(Path::new("/etc/rensen"))
+                .join("history.jsonl"),
+        };
+
+        Self { path }
+    }
+
+    /// Records a finished run in the history and the transfer ledger.
+    /// Failures are logged, they should never fail the run itself.
+    pub fn record(global_config: &GlobalConfig, outcome: &RunOutcome) {
+        if let Err(err) = History::new(global_config).append(outcome) {
+            log_trap(global_config, &err);
+        }
+
+        let ledger_path = TransferLedger::path(global_config);
+        if let Err(err) = TransferLedger::record(&ledger_path, &outcome.hostname, outcome.started, outcome.bytes, outcome.files) {
+            log_trap(global_config, &err);
+        }
+    }
+
+    pub fn append(&self, outcome: &RunOutcome) -> Result<(), Trap> {
+        let line = serde_json::to_string(outcome)
+            .map_err(|err| Trap::Serialize(format!("Could not serialize run outcome: {}", err)))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| Trap::FS(format!("Could not open history at {:?}: {}", self.path, err)))?;
+
+        writeln!(file, "{}", line)
+            .map_err(|err| Trap::FS(format!("Could not write to history at {:?}: {}", self.path, err)))
+    }
+
+    /// Every run in the history, oldest first.
+    /// Lines which can not be parsed (e.g. after a crash mid-write) are skipped.
+    pub fn load(&self) -> Result<Vec<RunOutcome>, Trap> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut outcomes = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| Trap::FS(format!("Could not read history: {}", err)))?;
+            if let Ok(outcome) = serde_json::from_str::<RunOutcome>(&line) {
+                outcomes.push(outcome);
+            }
+        }
+
+        Ok(outcomes)
+    }
+
+    /// The `last` most recent runs of `hostname`, oldest first
+    pub fn for_host(&self, hostname: &str, last: usize) -> Result<Vec<RunOutcome>, Trap> {
+        let mut outcomes: Vec<RunOutcome> = self.load()?
+            .into_iter()
+            .filter(|outcome| outcome.hostname == hostname)
+            .collect();
+
+        let skip = outcomes.len().saturating_sub(last);
+        outcomes.drain(..skip);
+        Ok(outcomes)
+    }
+
+    /// When the last successful run of `hostname` started
+    pub fn last_success(&self, hostname: &str) -> Result<Option<i64>, Trap> {
+        Ok(self.load()?
+            .into_iter()
+            .filter(|outcome| outcome.hostname == hostname && outcome.success)
+            .map(|outcome| outcome.started)
+            .max())
+    }
+}
+
+/// Formats `history export` can write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn from(format: &str) -> Result<Self, Trap> {
+        match format.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(Trap::InvalidInput(format!("Unknown export format `{}`, expected csv or parquet", format))),
+        }
+    }
+}
+
+impl History {
+    /// Runs of all hosts started within `since..until` (unix seconds, both optional)
+    pub fn range(&self, since: Option<i64>, until: Option<i64>) -> Result<Vec<RunOutcome>, Trap> {
+        Ok(self.load()?
+            .into_iter()
+            .filter(|outcome| since.is_none_or(|since| outcome.started >= since))
+            .filter(|outcome| until.is_none_or(|until| outcome.started < until))
+            .collect())
+    }
+}
+
+const CSV_HEADER: &str = "hostname,started,finished,duration,success,error,bytes,files,size,source_total,source_free";
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes `outcomes` as CSV, one run per row
+pub fn export_csv<W: Write>(outcomes: &[RunOutcome], writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "{}", CSV_HEADER)?;
+    for run in outcomes {
+        let (source_total, source_free) = match &run.source {
+            Some(usage) => (usage.total.to_string(), usage.free.to_string()),
+            None => (String::new(), String::new()),
+        };
+
+        writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&run.hostname), run.started, run.finished, run.duration(), run.success,
+            csv_field(run.error.as_deref().unwrap_or("")), run.bytes, run.files, run.size,
+            source_total, source_free)?;
+    }
+
+    Ok(())
+}
+
+/// Writes `outcomes` as a single row group parquet file
+#[cfg(feature = "parquet")]
+pub fn export_parquet(outcomes: &[RunOutcome], destination: &Path) -> Result<(), Trap> {
+    use std::sync::Arc;
+    use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    let map_err = |err: parquet::errors::ParquetError| Trap::Serialize(format!("Could not write parquet: {}", err));
+
+    let schema = Arc::new(parse_message_type("
+        message run {
+            REQUIRED BYTE_ARRAY hostname (UTF8);
+            REQUIRED INT64 started;
+            REQUIRED INT64 finished;
+            REQUIRED INT64 duration;
+            REQUIRED BOOLEAN success;
+            OPTIONAL BYTE_ARRAY error (UTF8);
+            REQUIRED INT64 bytes;
+            REQUIRED INT64 files;
+            REQUIRED INT64 size;
+            OPTIONAL INT64 source_total;
+            OPTIONAL INT64 source_free;
+        }
+    ").map_err(map_err)?);
+
+    let file = File::create(destination)
+        .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", destination, err)))?;
+
+    let mut writer = SerializedFileWriter::new(file, schema, Arc::new(WriterProperties::builder().build()))
+        .map_err(map_err)?;
+    let mut row_group = writer.next_row_group().map_err(map_err)?;
+
+    let int64 = |f: fn(&RunOutcome) -> i64| outcomes.iter().map(f).collect::<Vec<i64>>();
+    let mut column_index = 0;
+    while let Some(mut column) = row_group.next_column().map_err(map_err)? {
+        match column_index {
+            0 => {
+                let values: Vec<ByteArray> = outcomes.iter().map(|run| ByteArray::from(run.hostname.as_str())).collect();
+                column.typed::<ByteArrayType>().write_batch(&values, None, None).map_err(map_err)?;
+            },
+            4 => {
+                let values: Vec<bool> = outcomes.iter().map(|run| run.success).collect();
+                column.typed::<BoolType>().write_batch(&values, None, None).map_err(map_err)?;
+            },
+            5 => {
+                let values: Vec<ByteArray> = outcomes.iter()
+                    .filter_map(|run| run.error.as_deref().map(ByteArray::from))
+                    .collect();
+                let levels: Vec<i16> = outcomes.iter().map(|run| run.error.is_some() as i16).collect();
+                column.typed::<ByteArrayType>().write_batch(&values, Some(&levels), None).map_err(map_err)?;
+            },
+            9 | 10 => {
+                let usage = |run: &RunOutcome| run.source.map(|usage| match column_index {
+                    9 => usage.total as i64,
+                    _ => usage.free as i64,
+                });
+                let values: Vec<i64> = outcomes.iter().filter_map(usage).collect();
+                let levels: Vec<i16> = outcomes.iter().map(|run| run.source.is_some() as i16).collect();
+                column.typed::<Int64Type>().write_batch(&values, Some(&levels), None).map_err(map_err)?;
+            },
+            index => {
+                let values = match index {
+                    1 => int64(|run| run.started),
+                    2 => int64(|run| run.finished),
+                    3 => int64(|run| run.duration()),
+                    6 => int64(|run| run.bytes as i64),
+                    7 => int64(|run| run.files as i64),
+                    _ => int64(|run| run.size as i64),
+                };
+                column.typed::<Int64Type>().write_batch(&values, None, None).map_err(map_err)?;
+            },
+        }
+
+        column.close().map_err(map_err)?;
+        column_index += 1;
+    }
+
+    row_group.close().map_err(map_err)?;
+    writer.close().map_err(map_err)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+pub fn export_parquet(_outcomes: &[RunOutcome], _destination: &Path) -> Result<(), Trap> {
+    Err(Trap::InvalidInput(String::from("rensen was built without parquet support (enable the `parquet` feature)")))
+}
+
+/// Relative change in percent between the average of the older and the
+/// newer half of `values`. None if there are too few values to compare.
+pub fn trend(values: &[f64]) -> Option<f64> {
+    if values.len() < 2 {
+        return None;
+    }
+
+    let half = values.len() / 2;
+    let older = values[..half].iter().sum::<f64>() / half as f64;
+    let newer = values[half..].iter().sum::<f64>() / (values.len() - half) as f64;
+    if older == 0.0 {
+        return None;
+    }
+
+    Some((newer - older) / older * 100.0)
+}
+
+#[test]
+fn test_history() {
+    let global_config = GlobalConfig {
+        history: Some(std::env::temp_dir().join("rensen_test_history.jsonl")),
+        ..Default::default()
+    };
+
+    let history = History::new(&global_config);
+    let _ = std::fs::remove_file(&history.path);
+
+    for (i, hostname) in ["a", "b", "a", "a"].iter().enumerate() {
+        let outcome = RunOutcome { hostname: hostname.to_string(), started: i as i64, finished: i as i64 + 10, success: true, ..Default::default() };
+        history.append(&outcome).unwrap();
+    }
+
+    let runs = history.for_host("a", 2).unwrap();
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0].started, 2);
+
+    assert_eq!(history.range(Some(1), Some(3)).unwrap().len(), 2);
+
+    let mut csv = Vec::new();
+    export_csv(&runs, &mut csv).unwrap();
+    assert_eq!(String::from_utf8(csv).unwrap().lines().nth(1), Some("a,2,12,10,true,,0,0,0,,"));
+
+    assert_eq!(trend(&[10.0, 10.0, 20.0, 20.0]), Some(100.0));
+    assert_eq!(trend(&[10.0]), None);
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn test_export_parquet() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let runs = vec![
+        RunOutcome { hostname: String::from("a"), success: true, source: Some(DiskUsage { total: 10, free: 5 }), ..Default::default() },
+        RunOutcome { hostname: String::from("b"), error: Some(String::from("Connect: refused")), ..Default::default() },
+    ];
+
+    let path = std::env::temp_dir().join("rensen_test_history.parquet");
+    export_parquet(&runs, &path).unwrap();
+
+    let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+}
@@ -0,0 +1,144 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use ssh2::Session;
+
+use crate::logging::Trap;
+
+/// Default time `pre_backup_cmd` or `post_backup_cmd` may take
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+
+/// What a failing `pre_backup_cmd` or `post_backup_cmd` does to the run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    #[default]
+    Abort,    // the run fails, before anything is fetched for `pre_backup_cmd`
+    Continue, // the run carries on, with the failure as a warning
+}
+
+/// Runs `command` through the remote shell over `sess`, failing on a
+/// non-zero exit status or once `timeout` passes without it finishing
+pub fn run_hook(sess: &Session, command: &str, timeout: Duration) -> Result<(), Trap> {
+    sess.set_timeout(timeout.as_millis().min(u32::MAX as u128) as u32);
+    let result = (|| {
+        let mut channel = sess.channel_session()
+            .map_err(|err| Trap::Hook(format!("Could not open channel: {}", err)))?;
+
+        channel.exec(command)
+            .map_err(|err| Trap::Hook(format!("Could not execute `{}`: {}", command, err)))?;
+
+        // Drained, so a chatty command does not stall on a full window
+        let _ = std::io::copy(&mut channel, &mut std::io::sink());
+        let mut output = String::new();
+        let _ = channel.stderr().read_to_string(&mut output);
+        channel.wait_close()
+            .map_err(|err| Trap::Hook(format!("`{}` did not finish within {}s: {}", command, timeout.as_secs(), err)))?;
+
+        match channel.exit_status() {
+            Ok(0) => Ok(()),
+            Ok(status) => Err(Trap::Hook(format!("`{}` exited with status {}: {}", command, status, output.trim()))),
+            Err(err) => Err(Trap::Hook(format!("`{}` did not finish: {}", command, err))),
+        }
+    })();
+    sess.set_timeout(0);
+
+    result
+}
+
+/// What a run fetched, handed to `finalize_cmd` and the finalize callback
+/// of Sftp before the snapshot is committed to the record
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FinalizeStats {
+    pub identifier: String,
+    pub snapshot: String,  // e.g. 2024-05-15-08-10-30
+    pub path: PathBuf,     // the snapshot's tree, not archived yet
+    pub incremental: bool,
+    pub files: u64,        // fetched by this run
+    pub bytes: u64,
+    pub deleted: u64,      // gone from the source since the run before
+    pub size: u64,         // of everything in the record
+    pub skipped: u64,      // could not be fetched
+}
+
+/// Checks a run before its snapshot is committed, vetoing it with the reason
+pub type Finalizer = Box<dyn Fn(&FinalizeStats) -> Result<(), String>>;
+
+/// Runs `command` through `sh -c` on the backup server with `stats` in the
+/// environment, as RENSEN_HOST, RENSEN_SNAPSHOT, RENSEN_SNAPSHOT_PATH,
+/// RENSEN_INCREMENTAL, RENSEN_FILES, RENSEN_BYTES, RENSEN_DELETED, RENSEN_SIZE
+/// and RENSEN_SKIPPED. A non-zero exit status, or not finishing within
+/// `timeout`, vetoes the snapshot.
+pub fn run_finalize_hook(command: &str, stats: &FinalizeStats, timeout: Duration) -> Result<(), Trap> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("RENSEN_HOST", &stats.identifier)
+        .env("RENSEN_SNAPSHOT", &stats.snapshot)
+        .env("RENSEN_SNAPSHOT_PATH", &stats.path)
+        .env("RENSEN_INCREMENTAL", stats.incremental.to_string())
+        .env("RENSEN_FILES", stats.files.to_string())
+        .env("RENSEN_BYTES", stats.bytes.to_string())
+        .env("RENSEN_DELETED", stats.deleted.to_string())
+        .env("RENSEN_SIZE", stats.size.to_string())
+        .env("RENSEN_SKIPPED", stats.skipped.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Trap::Hook(format!("Could not run finalize_cmd `{}`: {}", command, err)))?;
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < timeout => std::thread::sleep(Duration::from_millis(50)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Trap::Hook(format!("finalize_cmd `{}` did not finish within {}s, snapshot vetoed", command, timeout.as_secs())));
+            },
+            Err(err) => return Err(Trap::Hook(format!("finalize_cmd `{}` did not finish: {}", command, err))),
+        }
+    };
+
+    let mut output = String::new();
+    if let Some(mut stderr) = child.stderr.take() {
+        let _ = stderr.read_to_string(&mut output);
+    }
+    match status.success() {
+        true => Ok(()),
+        false => Err(Trap::Hook(format!("finalize_cmd `{}` vetoed the snapshot with {}: {}", command, status, output.trim()))),
+    }
+}
+
+#[test]
+fn test_finalize_hook() {
+    let stats = FinalizeStats { identifier: String::from("db01"), files: 12, ..Default::default() };
+    let timeout = Duration::from_secs(5);
+
+    assert!(run_finalize_hook("test \"$RENSEN_HOST $RENSEN_FILES\" = 'db01 12'", &stats, timeout).is_ok());
+    let vetoed = run_finalize_hook("echo too few files >&2; exit 3", &stats, timeout).unwrap_err();
+    assert!(vetoed.to_string().contains("too few files"));
+    assert!(run_finalize_hook("sleep 5", &stats, Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn test_hook_config() {
+    use crate::config::HostConfig;
+
+    let host_config: HostConfig = serde_yaml::from_str("
+        user: backup
+        identifier: db01
+        source: /srv
+        destination: /backups
+        pre_backup_cmd: pg_dump app > /srv/dump/app.sql
+        hook_failure: continue
+    ").unwrap();
+    assert_eq!(host_config.hook_failure, Some(HookFailure::Continue));
+    assert_eq!(host_config.hook_timeout(), Duration::from_secs(DEFAULT_HOOK_TIMEOUT));
+    assert_eq!(HostConfig::default().hook_failure.unwrap_or_default(), HookFailure::Abort);
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::HostConfig;
+use crate::filter::Filter;
+use crate::record::Record;
+use crate::rsyncd::RemoteFile;
+use crate::snapshot::FileEntry;
+
+/// How an incremental backup tells whether a file changed since the record
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeDetection {
+    #[default]
+    Mtime,   // modified after the recorded mtime
+    Sampled, // of another size, or its first, middle and last KiB hash to something else
+    Full,    // of another size, or its contents hash to something else
+}
+
+/// What an incremental backup does with a file on the host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Fetch,
+    Skip,
+    Touch, // the contents are as recorded under another mtime, which the record takes on
+}
+
+/// Compares a file on the host of `mtime` and `size` with its `recorded`
+/// entry by `detection`. `digest` hashes the file on the host the way
+/// `detection` does, it is only asked when the sizes match. Entries without
+/// a hash of that kind, and files that could not be hashed, go by mtime.
+pub fn compare(detection: ChangeDetection, recorded: Option<&FileEntry>, mtime: u64, size: u64, digest: impl FnOnce() -> Option<String>) -> Verdict {
+    let Some(entry) = recorded else { return Verdict::Fetch };
+    let by_mtime = match mtime > entry.mtime {
+        true => Verdict::Fetch,
+        false => Verdict::Skip,
+    };
+
+    let kept = match detection {
+        ChangeDetection::Mtime => return by_mtime,
+        ChangeDetection::Sampled => entry.sample.as_ref(),
+        ChangeDetection::Full => entry.hash.as_ref(),
+    };
+    if size != entry.size {
+        return Verdict::Fetch;
+    }
+    let Some(kept) = kept else { return by_mtime };
+    match digest() {
+        Some(digest) if digest != *kept => Verdict::Fetch,
+        Some(_) if mtime != entry.mtime => Verdict::Touch,
+        Some(_) => Verdict::Skip,
+        None => by_mtime,
+    }
+}
+
+/// What a backup would transfer given the record of the one before and a
+/// listing of the source, without transferring anything. Paths are relative
+/// to the source, as in the listing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferPlan {
+    pub fetch: Vec<RemoteFile>, // new, or changed since the record
+    pub skip: Vec<RemoteFile>,  // as the record has them
+    pub hash: Vec<RemoteFile>,  // changed or not by their contents, which a backup hashes and the plan does not
+    pub delete: Vec<PathBuf>,   // in the record but gone from the source, or excluded since
+}
+
+impl TransferPlan {
+    /// Bytes the fetches add up to
+    pub fn fetch_size(&self) -> u64 {
+        self.fetch.iter().map(|file| file.size).sum()
+    }
+}
+
+/// The change detection of rensen on its own, for tools that want to know
+/// what a backup of `host_config` on top of `record` would do with the files
+/// in `listing`. Files are compared by the `change_detection` of the host as
+/// a backup compares them, those only their contents tell apart are left to
+/// `hash`. `exclude` and `include` of the host are applied the way a backup
+/// does: excluded files are neither fetched nor skipped, and recorded files
+/// that are excluded now are deleted.
+pub fn plan_transfer(record: &Record, host_config: &HostConfig, listing: &[RemoteFile]) -> TransferPlan {
+    let source = &host_config.source;
+    let filter = Filter::new(host_config);
+    let detection = host_config.change_detection();
+
+    let mut plan = TransferPlan::default();
+    let mut listed = BTreeSet::new();
+    for file in listing.iter().filter(|file| !filter.excludes_within(&file.path)) {
+        let path = source.join(&file.path);
+        let mut hashed = false;
+        let verdict = compare(detection, record.snapshot.entries.get(&path), file.mtime, file.size, || {
+            hashed = true;
+            None
+        });
+        match (hashed, verdict) {
+            (true, _) => plan.hash.push(file.clone()),
+            (false, Verdict::Fetch) => plan.fetch.push(file.clone()),
+            (false, _) => plan.skip.push(file.clone()),
+        }
+        listed.insert(path);
+    }
+
+    plan.delete = record.snapshot.entries.keys()
+        .filter(|path| !listed.contains(*path))
+        .map(|path| path.strip_prefix(source).unwrap_or(path).to_path_buf())
+        .collect();
+    plan.delete.sort();
+    plan
+}
+
+#[test]
+fn test_plan_transfer() {
+    use crate::snapshot::FileEntry;
+
+    let host_config = HostConfig { source: PathBuf::from("/srv"), exclude: Some(vec![String::from("*.log")]), ..Default::default() };
+    let mut record = Record::new();
+    for (path, mtime) in [("a", 100), ("b", 100), ("gone", 100), ("app.log", 100)] {
+        let entry = FileEntry::from(PathBuf::from("/backups/srv").join(path), PathBuf::from("/backups"), mtime, 10);
+        record.snapshot.entries.insert(PathBuf::from("/srv").join(path), entry);
+    }
+
+    let file = |path: &str, mtime: u64| RemoteFile { path: PathBuf::from(path), size: 10, mtime };
+    let listing = [file("a", 100), file("b", 200), file("new", 50), file("app.log", 300), file("debug.log", 300)];
+    let plan = plan_transfer(&record, &host_config, &listing);
+
+    assert_eq!(plan.fetch, vec![file("b", 200), file("new", 50)]);
+    assert_eq!(plan.skip, vec![file("a", 100)]);
+    assert_eq!(plan.delete, vec![PathBuf::from("app.log"), PathBuf::from("gone")]);
+    assert_eq!(plan.fetch_size(), 20);
+
+    // Everything is fetched without a record to go by
+    assert_eq!(plan_transfer(&Record::new(), &host_config, &listing).fetch.len(), 3);
+
+    // By contents, files of the recorded size are left to be hashed, whatever their mtime
+    for path in ["a", "b"] {
+        let entry = record.snapshot.entries.get_mut(&PathBuf::from("/srv").join(path)).unwrap();
+        entry.hash = Some(String::from("aa"));
+    }
+    let listing = [file("a", 100), file("b", 200), RemoteFile { path: PathBuf::from("gone"), size: 11, mtime: 100 }];
+    let full = HostConfig { change_detection: Some(ChangeDetection::Full), ..host_config.clone() };
+    let plan = plan_transfer(&record, &full, &listing);
+    assert_eq!(plan.hash, vec![file("a", 100), file("b", 200)]);
+    assert_eq!(plan.fetch, vec![listing[2].clone()]);
+    assert!(plan.skip.is_empty());
+
+    // As a backup, hosts with `encrypt_key` go by mtime
+    let encrypted = HostConfig { encrypt_key: Some(PathBuf::from("/etc/rensen/web.key")), ..full.clone() };
+    assert_eq!(plan_transfer(&record, &encrypted, &listing).fetch, vec![file("b", 200)]);
+}
+
+#[test]
+fn test_compare() {
+    let entry = FileEntry { mtime: 100, size: 10, hash: Some(String::from("aa")), sample: Some(String::from("bb")), ..FileEntry::new() };
+    let never = || -> Option<String> { panic!("hashed") };
+
+    // By mtime, untouched files are not hashed at all
+    assert_eq!(compare(ChangeDetection::Mtime, Some(&entry), 200, 10, never), Verdict::Fetch);
+    assert_eq!(compare(ChangeDetection::Mtime, Some(&entry), 100, 99, never), Verdict::Skip);
+    assert_eq!(compare(ChangeDetection::Full, None, 100, 10, never), Verdict::Fetch);
+    assert_eq!(compare(ChangeDetection::Full, Some(&entry), 100, 11, never), Verdict::Fetch);
+
+    // Touched but the same, changed with the mtime kept, and what could not be hashed
+    assert_eq!(compare(ChangeDetection::Full, Some(&entry), 200, 10, || Some(String::from("aa"))), Verdict::Touch);
+    assert_eq!(compare(ChangeDetection::Full, Some(&entry), 100, 10, || Some(String::from("cc"))), Verdict::Fetch);
+    assert_eq!(compare(ChangeDetection::Sampled, Some(&entry), 100, 10, || Some(String::from("bb"))), Verdict::Skip);
+    assert_eq!(compare(ChangeDetection::Sampled, Some(&entry), 200, 10, || None), Verdict::Fetch);
+
+    // Entries from before the hash was kept
+    let old = FileEntry { sample: None, ..entry.clone() };
+    assert_eq!(compare(ChangeDetection::Sampled, Some(&old), 100, 10, never), Verdict::Skip);
+}
@@ -0,0 +1,262 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::codec::Codec;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::traits::JsonFile;
+use crate::verify::snapshots;
+
+/// Aggregate stats of one snapshot record, along with the size and mtime the
+/// record file had when they were taken. An entry only counts as long as
+/// both still match, so records rewritten by compaction are picked up again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub size: u64,
+    pub files: u64,
+    pub deleted: u64,
+    pub compacted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<Codec>, // of the snapshot's archive, none in entries from before, which are taken again
+    pub record_len: u64,
+    pub record_mtime: i64,
+}
+
+/// A file in the per-snapshot file index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexedFile {
+    pub path: PathBuf,     // source path
+    pub size: u64,
+    pub mtime: u64,
+    pub snapshot: String,  // snapshot the file is archived in
+}
+
+/// Cache of derived data for the records of a host, built after a backup when
+/// `warm_cache` is set. Stored at $backups/$identifier/.records/index/, with the
+/// stats in snapshots.json and a sorted file list per snapshot next to it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotIndex {
+    pub snapshots: BTreeMap<String, IndexEntry>,
+}
+
+impl SnapshotIndex {
+    pub fn dir(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("index")
+    }
+
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Self {
+        SnapshotIndex::deserialize_json(&Self::dir(global_config, host_config).join("snapshots.json"))
+            .unwrap_or_default()
+    }
+
+    /// The cached stats of `snapshot`, if its record has not changed since
+    pub fn lookup(&self, snapshot: &str, record_path: &Path) -> Option<&IndexEntry> {
+        let (record_len, record_mtime) = record_stamp(record_path)?;
+        self.snapshots.get(snapshot)
+            .filter(|entry| entry.record_len == record_len && entry.record_mtime == record_mtime && entry.codec.is_some())
+    }
+}
+
+impl JsonFile for SnapshotIndex {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(SnapshotIndex::default()),
+        };
+
+        let index: SnapshotIndex = serde_json::from_reader(BufReader::new(file))?;
+        Ok(index)
+    }
+}
+
+fn record_stamp(record_path: &Path) -> Option<(u64, i64)> {
+    let metadata = fs::metadata(record_path).ok()?;
+    let mtime = metadata.modified().ok()?
+        .duration_since(UNIX_EPOCH).ok()?
+        .as_secs() as i64;
+
+    Some((metadata.len(), mtime))
+}
+
+/// Brings the index of `host_config` up to date with its records, building
+/// entries only for records that are new or changed, and dropping those of
+/// records which are gone. Returns the number of records indexed.
+pub fn warm_index(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<usize, Trap> {
+    global_config.ensure_writable("write the snapshot index")?;
+
+    let records_path = host_config.root(global_config).join(".records");
+    let dir = SnapshotIndex::dir(global_config, host_config);
+    fs::create_dir_all(&dir)
+        .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", dir, err)))?;
+
+    let mut index = SnapshotIndex::load(global_config, host_config);
+    let snapshots = snapshots(global_config, host_config);
+    let mut indexed = 0;
+
+    index.snapshots.retain(|snapshot, _| snapshots.contains(snapshot));
+    for snapshot in snapshots.iter() {
+        let record_path = records_path.join(format!("{}.json", snapshot));
+        if index.lookup(snapshot, &record_path).is_some() {
+            continue;
+        }
+
+        // Taken before reading, a record changing in between is indexed again next time
+        let (record_len, record_mtime) = match record_stamp(&record_path) {
+            Some(stamp) => stamp,
+            None => continue,
+        };
+
+        let record = Record::deserialize_json(&record_path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+
+        let files = file_list(&record);
+        write_files(&dir.join(format!("{}.json", snapshot)), &files)?;
+
+        let (files, deleted) = match record.summary {
+            Some(summary) => (summary.files, summary.deleted),
+            None => (files.len() as u64, record.snapshot.deleted_entries.len() as u64),
+        };
+
+        index.snapshots.insert(snapshot.clone(), IndexEntry {
+            size: record.size,
+            files,
+            deleted,
+            compacted: record.is_compacted(),
+            codec: Some(record.codec.unwrap_or_default()),
+            record_len,
+            record_mtime,
+        });
+        indexed += 1;
+    }
+
+    // File lists of snapshots that no longer exist
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let stem = entry.path().file_stem().and_then(|stem| stem.to_str()).map(String::from).unwrap_or_default();
+            if stem != "snapshots" && !index.snapshots.contains_key(&stem) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    let index_path = dir.join("snapshots.json");
+    index.serialize_json(&index_path)
+        .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", index_path, err)))?;
+
+    Ok(indexed)
+}
+
+/// The files of a record sorted by source path
+pub fn file_list(record: &Record) -> Vec<IndexedFile> {
+    let mut files: Vec<IndexedFile> = record.snapshot.entries.iter()
+        .map(|(source, entry)| IndexedFile {
+            path: source.clone(),
+            size: entry.size,
+            mtime: entry.mtime,
+            snapshot: entry.snapshot_path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        })
+        .collect();
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+fn write_files(path: &Path, files: &[IndexedFile]) -> Result<(), Trap> {
+    let write = || -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, files)?;
+        writer.flush()
+    };
+
+    write().map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+}
+
+/// The files of `snapshot`, from the index when it is current and from the
+/// record otherwise.
+pub fn snapshot_files(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str) -> Result<Vec<IndexedFile>, Trap> {
+    files_below(&host_config.root(global_config), snapshot)
+}
+
+/// snapshot_files of the host whose snapshots are kept at `root`
+pub(crate) fn files_below(root: &Path, snapshot: &str) -> Result<Vec<IndexedFile>, Trap> {
+    let records_path = root.join(".records");
+    let record_path = records_path.join(format!("{}.json", snapshot));
+    let index_path = records_path.join("index");
+
+    let index = SnapshotIndex::deserialize_json(&index_path.join("snapshots.json")).unwrap_or_default();
+    if index.lookup(snapshot, &record_path).is_some() {
+        let files_path = index_path.join(format!("{}.json", snapshot));
+        if let Ok(file) = File::open(&files_path) {
+            if let Ok(files) = serde_json::from_reader(BufReader::new(file)) {
+                return Ok(files);
+            }
+        }
+    }
+
+    let record = Record::deserialize_json(&record_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+
+    Ok(file_list(&record))
+}
+
+#[test]
+fn test_warm_index() {
+    use crate::snapshot::FileEntry;
+
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_index"),
+        ..Default::default()
+    };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let records = global_config.backups.join("host").join(".records");
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(&records).unwrap();
+
+    let mut record = Record::new();
+    record.size = 10;
+    for name in ["/b", "/a"] {
+        let entry = FileEntry::from(PathBuf::new(), global_config.backups.join("host/2024-01-01-00-00-00"), 1, 5);
+        record.snapshot.entries.insert(PathBuf::from(name), entry);
+    }
+    record.serialize_json(&records.join("2024-01-01-00-00-00.json")).unwrap();
+    record.serialize_json(&records.join("2024-01-02-00-00-00.json")).unwrap();
+
+    assert_eq!(warm_index(&global_config, &host_config).unwrap(), 2);
+    assert_eq!(warm_index(&global_config, &host_config).unwrap(), 0);
+
+    let index = SnapshotIndex::load(&global_config, &host_config);
+    let entry = index.lookup("2024-01-01-00-00-00", &records.join("2024-01-01-00-00-00.json")).unwrap();
+    assert_eq!((entry.size, entry.files, entry.compacted), (10, 2, false));
+
+    let files = snapshot_files(&global_config, &host_config, "2024-01-01-00-00-00").unwrap();
+    assert_eq!(files.iter().map(|file| file.path.clone()).collect::<Vec<_>>(), vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+    assert_eq!(files[0].snapshot, "2024-01-01-00-00-00");
+
+    // A rewritten record is indexed again, a removed one dropped
+    record.compact(0);
+    record.serialize_json(&records.join("2024-01-01-00-00-00.json")).unwrap();
+    fs::remove_file(records.join("2024-01-02-00-00-00.json")).unwrap();
+    let _ = warm_index(&global_config, &host_config).unwrap();
+
+    let index = SnapshotIndex::load(&global_config, &host_config);
+    assert_eq!(index.snapshots.len(), 1);
+    assert!(index.snapshots["2024-01-01-00-00-00"].compacted);
+    assert!(!SnapshotIndex::dir(&global_config, &host_config).join("2024-01-02-00-00-00.json").exists());
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::process::Command;
+
+use serde::{Serialize, Deserialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::config::{Host, HostConfig, Settings};
+use crate::logging::Trap;
+use crate::traits::Inventory;
+
+/// Inventory section of the global config, e.g.
+///
+/// inventory:
+///   sources:
+///     - provider: ansible
+///       location: /etc/ansible/hosts
+///       group: webservers
+///     - provider: url
+///       location: https://cmdb.example.com/hosts.json
+///     - provider: srv
+///       location: _ssh._tcp.example.com
+///   template:
+///     user: backup
+///     source: /srv
+///     destination: /backups/{hostname}
+///     cron_schedule: "0 0 2 * * *"
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InventoryConfig {
+    pub sources: Vec<InventorySource>,
+    pub template: Mapping,             // host config for discovered hosts, `{hostname}` is substituted
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InventorySource {
+    pub provider: String,              // ansible, url or srv
+    pub location: String,              // inventory file, file path or URL, SRV record name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,         // provider `ansible` only, default: all hosts
+}
+
+impl InventorySource {
+    /// The built-in provider selected by `provider`
+    pub fn plugin(&self) -> Result<Box<dyn Inventory>, Trap> {
+        match self.provider.to_lowercase().as_str() {
+            "ansible" => Ok(Box::new(Ansible { path: self.location.clone(), group: self.group.clone() })),
+            "url" | "file" => Ok(Box::new(Url { location: self.location.clone() })),
+            "srv" | "dns" => Ok(Box::new(Srv { name: self.location.clone() })),
+            provider => Err(Trap::Config(format!("Unknown inventory provider `{}` for `{}`", provider, self.location))),
+        }
+    }
+}
+
+/// A machine as found in an inventory
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Discovered {
+    pub hostname: String,
+    #[serde(default)]
+    pub addr: Option<String>,          // default: hostname
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+/// Ansible INI inventory. Hosts of `group`, including its children, or of
+/// all groups. `ansible_host`, `ansible_port` and `ansible_user` are used.
+pub struct Ansible {
+    pub path: String,
+    pub group: Option<String>,
+}
+
+impl Inventory for Ansible {
+    fn discover(&self) -> Result<Vec<Discovered>, Trap> {
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|err| Trap::Inventory(format!("Could not read {}: {}", self.path, err)))?;
+
+        Ok(parse_ansible(&contents, self.group.as_deref()))
+    }
+}
+
+/// JSON or CSV list of hosts, at a path or a http(s) URL. JSON is an array of
+/// objects with `hostname` and optionally `addr`, `port` and `user`; CSV has
+/// a header row naming the same columns.
+pub struct Url {
+    pub location: String,
+}
+
+impl Inventory for Url {
+    fn discover(&self) -> Result<Vec<Discovered>, Trap> {
+        let contents = match self.location.starts_with("http://") || self.location.starts_with("https://") {
+            true => run("curl", &["-fsSL", "--max-time", "30", &self.location])?,
+            false => fs::read_to_string(&self.location)
+                .map_err(|err| Trap::Inventory(format!("Could not read {}: {}", self.location, err)))?,
+        };
+
+        match contents.trim_start().starts_with('[') {
+            true => serde_json::from_str(&contents)
+                .map_err(|err| Trap::Inventory(format!("Invalid JSON inventory at {}: {}", self.location, err))),
+            false => parse_csv(&contents)
+                .map_err(|err| Trap::Inventory(format!("Invalid CSV inventory at {}: {}", self.location, err))),
+        }
+    }
+}
+
+/// DNS SRV records, one host per target, with the port of the record
+pub struct Srv {
+    pub name: String,
+}
+
+impl Inventory for Srv {
+    fn discover(&self) -> Result<Vec<Discovered>, Trap> {
+        let output = run("dig", &["+short", "SRV", &self.name])?;
+        Ok(parse_srv(&output))
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, Trap> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|err| Trap::Inventory(format!("Could not run `{}`: {}", program, err)))?;
+
+    if !output.status.success() {
+        return Err(Trap::Inventory(format!("`{} {}` failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn parse_ansible(contents: &str, group: Option<&str>) -> Vec<Discovered> {
+    let mut hosts: BTreeMap<String, (Discovered, BTreeSet<String>)> = BTreeMap::new();
+    let mut children: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut section = (String::from("ungrouped"), "");
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            section = match name.split_once(':') {
+                Some((name, kind)) => (name.to_string(), if kind == "children" { "children" } else { "vars" }),
+                None => (name.to_string(), ""),
+            };
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let name = fields.next().unwrap_or_default().to_string();
+        match section.1 {
+            "children" => { children.entry(section.0.clone()).or_default().push(name); continue },
+            "vars" => continue,
+            _ => (),
+        }
+
+        let (host, groups) = hosts.entry(name.clone())
+            .or_insert_with(|| (Discovered { hostname: name, ..Default::default() }, BTreeSet::new()));
+        groups.insert(section.0.clone());
+
+        for (key, value) in fields.filter_map(|field| field.split_once('=')) {
+            match key {
+                "ansible_host" => host.addr = Some(value.to_string()),
+                "ansible_port" => host.port = value.parse().ok(),
+                "ansible_user" => host.user = Some(value.to_string()),
+                _ => (),
+            }
+        }
+    }
+
+    // The group along with all groups nested in it
+    let wanted = group.map(|group| {
+        let mut wanted = BTreeSet::new();
+        let mut pending = vec![group.to_string()];
+        while let Some(group) = pending.pop() {
+            if wanted.insert(group.clone()) {
+                pending.extend(children.get(&group).cloned().unwrap_or_default());
+            }
+        }
+        wanted
+    });
+
+    hosts.into_values()
+        .filter(|(_, groups)| wanted.as_ref().is_none_or(|wanted| !wanted.is_disjoint(groups)))
+        .map(|(host, _)| host)
+        .collect()
+}
+
+pub fn parse_csv(contents: &str) -> Result<Vec<Discovered>, String> {
+    let mut lines = contents.lines().map(str::trim).filter(|line| !line.is_empty());
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split(',').map(str::trim).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    if !header.contains(&"hostname") {
+        return Err(String::from("missing `hostname` column"));
+    }
+
+    let mut hosts = Vec::new();
+    for line in lines {
+        let mut host = Discovered::default();
+        for (column, value) in header.iter().zip(line.split(',').map(str::trim)) {
+            let value = Some(value.to_string()).filter(|value| !value.is_empty());
+            match *column {
+                "hostname" => host.hostname = value.unwrap_or_default(),
+                "addr" => host.addr = value,
+                "port" => host.port = match value {
+                    Some(port) => Some(port.parse().map_err(|err| format!("invalid port `{}`: {}", port, err))?),
+                    None => None,
+                },
+                "user" => host.user = value,
+                _ => (),
+            }
+        }
+
+        if !host.hostname.is_empty() {
+            hosts.push(host);
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// `dig +short SRV` output: `priority weight port target.` per line
+pub fn parse_srv(output: &str) -> Vec<Discovered> {
+    output.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, _, port, target] => {
+                    let target = target.trim_end_matches('.').to_string();
+                    Some(Discovered { hostname: target.clone(), addr: Some(target), port: port.parse().ok(), user: None })
+                },
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// A proposed change to the host settings
+#[derive(Debug, Clone)]
+pub enum Enrollment {
+    Add(Host),
+    Update { hostname: String, before: Box<HostConfig>, after: HostConfig },
+}
+
+/// The host config `template` gives for `host`
+pub fn host_config(template: &Mapping, host: &Discovered) -> Result<HostConfig, Trap> {
+    let mut mapping = Mapping::new();
+    for (key, value) in template.iter() {
+        let value = match value {
+            Value::String(value) => Value::String(value.replace("{hostname}", &host.hostname)),
+            value => value.clone(),
+        };
+        mapping.insert(key.clone(), value);
+    }
+
+    let addr = host.addr.clone().unwrap_or_else(|| host.hostname.clone());
+    mapping.insert(Value::from("identifier"), Value::from(addr));
+    if let Some(port) = host.port {
+        mapping.insert(Value::from("port"), Value::from(port as u64));
+    }
+    if let Some(user) = &host.user {
+        mapping.insert(Value::from("user"), Value::from(user.clone()));
+    }
+
+    serde_yaml::from_value(Value::Mapping(mapping))
+        .map_err(|err| Trap::Config(format!("Inventory template does not give a valid host config for `{}`: {}", host.hostname, err)))
+}
+
+/// What enrolling `discovered` would change in `settings`: hosts not in the
+/// settings yet are added from the template, known hosts only get their
+/// address, port and user updated. Hosts are never removed.
+pub fn plan(settings: &Settings, template: &Mapping, discovered: &[Discovered]) -> Result<Vec<Enrollment>, Trap> {
+    let mut enrollments = Vec::new();
+    let mut seen = BTreeSet::new();
+
+    for host in discovered {
+        if !seen.insert(host.hostname.clone()) {
+            continue;
+        }
+
+        let existing = settings.hosts.iter().find(|existing| existing.hostname == host.hostname);
+        match existing {
+            None => enrollments.push(Enrollment::Add(Host { hostname: host.hostname.clone(), config: host_config(template, host)? })),
+            Some(existing) => {
+                let mut after = existing.config.clone();
+                after.identifier = host.addr.clone().unwrap_or_else(|| host.hostname.clone());
+                after.port = host.port.or(after.port);
+                after.user = host.user.clone().unwrap_or(after.user);
+
+                if after.identifier != existing.config.identifier || after.port != existing.config.port || after.user != existing.config.user {
+                    enrollments.push(Enrollment::Update { hostname: host.hostname.clone(), before: Box::new(existing.config.clone()), after });
+                }
+            }
+        }
+    }
+
+    Ok(enrollments)
+}
+
+/// Applies `enrollments` from plan() to `settings`
+pub fn enroll(settings: &mut Settings, enrollments: Vec<Enrollment>) {
+    for enrollment in enrollments {
+        match enrollment {
+            Enrollment::Add(host) => settings.hosts.push(host),
+            Enrollment::Update { hostname, after, .. } => {
+                if let Some(host) = settings.hosts.iter_mut().find(|host| host.hostname == hostname) {
+                    host.config = after;
+                }
+            }
+        }
+    }
+}
+
+/// Hosts of all sources of `config`, a failing source only failing itself
+pub fn discover_all(config: &InventoryConfig) -> (Vec<Discovered>, Vec<Trap>) {
+    let mut hosts = Vec::new();
+    let mut errors = Vec::new();
+
+    for source in config.sources.iter() {
+        match source.plugin().and_then(|plugin| plugin.discover()) {
+            Ok(discovered) => hosts.extend(discovered),
+            Err(err) => errors.push(err),
+        }
+    }
+
+    (hosts, errors)
+}
+
+#[test]
+fn test_parse_inventories() {
+    let ini = "
+        # comment
+        db1 ansible_host=10.0.0.5 ansible_port=2222
+        [web]
+        web1 ansible_user=deploy
+        web2
+        [edge]
+        edge1
+        [frontend:children]
+        web
+        [web:vars]
+        ntp=pool.ntp.org
+    ";
+
+    let all = parse_ansible(ini, None);
+    assert_eq!(all.iter().map(|host| host.hostname.as_str()).collect::<Vec<_>>(), vec!["db1", "edge1", "web1", "web2"]);
+    assert_eq!(all[0], Discovered { hostname: "db1".into(), addr: Some("10.0.0.5".into()), port: Some(2222), user: None });
+
+    let frontend = parse_ansible(ini, Some("frontend"));
+    assert_eq!(frontend.iter().map(|host| host.hostname.as_str()).collect::<Vec<_>>(), vec!["web1", "web2"]);
+    assert_eq!(frontend[0].user.as_deref(), Some("deploy"));
+
+    let csv = parse_csv("hostname,addr,port\nnas,192.168.1.2,\nbox,,2200\n").unwrap();
+    assert_eq!(csv[0], Discovered { hostname: "nas".into(), addr: Some("192.168.1.2".into()), port: None, user: None });
+    assert_eq!(csv[1].port, Some(2200));
+    assert!(parse_csv("addr\n1.2.3.4\n").is_err());
+
+    let srv = parse_srv("10 5 22 a.example.com.\n10 5 2222 b.example.com.\n");
+    assert_eq!(srv[1], Discovered { hostname: "b.example.com".into(), addr: Some("b.example.com".into()), port: Some(2222), user: None });
+}
+
+#[test]
+fn test_plan_enrollment() {
+    let template: Mapping = serde_yaml::from_str("user: backup\nsource: /srv\ndestination: /backups/{hostname}\n").unwrap();
+    let known = HostConfig { user: "root".into(), identifier: "10.0.0.1".into(), ..Default::default() };
+    let mut settings = Settings::new(vec![Host { hostname: "db1".into(), config: known }]);
+
+    let discovered = vec![
+        Discovered { hostname: "db1".into(), addr: Some("10.0.0.5".into()), ..Default::default() },
+        Discovered { hostname: "web1".into(), port: Some(2222), ..Default::default() },
+    ];
+
+    let enrollments = plan(&settings, &template, &discovered).unwrap();
+    assert_eq!(enrollments.len(), 2);
+    assert!(matches!(&enrollments[0], Enrollment::Update { after, .. } if after.identifier == "10.0.0.5" && after.user == "root"));
+    match &enrollments[1] {
+        Enrollment::Add(host) => {
+            assert_eq!(host.config.identifier, "web1");
+            assert_eq!(host.config.port, Some(2222));
+            assert_eq!(host.config.destination, std::path::PathBuf::from("/backups/web1"));
+        },
+        _ => panic!("web1 should be added"),
+    }
+
+    enroll(&mut settings, enrollments);
+    assert_eq!(settings.hosts.len(), 2);
+    assert!(plan(&settings, &template, &discovered).unwrap().is_empty());
+}
@@ -0,0 +1,130 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::logging::Trap;
+use crate::snapshot::FileEntry;
+
+/// Entries are flushed to disk every this many files
+pub const CHECKPOINT: usize = 1000;
+
+#[derive(Serialize)]
+struct Line<'a> {
+    source: &'a Path,
+    entry: &'a FileEntry,
+}
+
+#[derive(Deserialize)]
+struct OwnedLine {
+    source: PathBuf,
+    entry: FileEntry,
+}
+
+/// Append-only log of the files transferred during a run, one JSON object per
+/// line. It is merged into the record once the run is done, so the record is
+/// written once per run rather than kept up to date file by file. If a run
+/// dies midway, the next one picks up the journal and keeps what was copied.
+pub struct Journal {
+    pub path: PathBuf,
+    writer: BufWriter<File>,
+    pending: usize,
+}
+
+impl Journal {
+    /// $backups/$identifier/.records/journal.jsonl
+    pub fn path(host_root_path: &Path) -> PathBuf {
+        host_root_path.join(".records").join("journal.jsonl")
+    }
+
+    pub fn open(path: &Path) -> Result<Self, Trap> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| Trap::FS(format!("Could not open journal {:?}: {}", path, err)))?;
+
+        Ok(Journal { path: path.to_path_buf(), writer: BufWriter::new(file), pending: 0 })
+    }
+
+    pub fn append(&mut self, source: &Path, entry: &FileEntry) -> Result<(), Trap> {
+        let line = serde_json::to_string(&Line { source, entry })
+            .map_err(|err| Trap::Serialize(format!("Could not serialize journal entry: {}", err)))?;
+
+        writeln!(self.writer, "{}", line)
+            .map_err(|err| Trap::FS(format!("Could not write to journal {:?}: {}", self.path, err)))?;
+
+        self.pending += 1;
+        if self.pending >= CHECKPOINT {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Trap> {
+        self.pending = 0;
+        self.writer.flush()
+            .map_err(|err| Trap::FS(format!("Could not flush journal {:?}: {}", self.path, err)))
+    }
+
+    /// Every entry in the journal at `path`, oldest first. The last line may
+    /// be cut off by a crash, lines that can not be parsed are skipped.
+    pub fn replay(path: &Path) -> Result<Vec<(PathBuf, FileEntry)>, Trap> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| Trap::FS(format!("Could not read journal {:?}: {}", path, err)))?;
+            if let Ok(line) = serde_json::from_str::<OwnedLine>(&line) {
+                entries.push((line.source, line.entry));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Removes the journal once it has been merged into the record
+    pub fn remove(path: &Path) -> Result<(), Trap> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(Trap::FS(format!("Could not remove journal {:?}: {}", path, err)))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_journal() {
+    let path = std::env::temp_dir().join("rensen_test_journal").join("journal.jsonl");
+    let _ = fs::remove_file(&path);
+
+    let mut journal = Journal::open(&path).unwrap();
+    for i in 0..3 {
+        let entry = FileEntry::from(PathBuf::from(format!("/local/{}", i)), PathBuf::from("/local"), i, i * 10);
+        journal.append(&PathBuf::from(format!("/remote/{}", i)), &entry).unwrap();
+    }
+    journal.flush().unwrap();
+
+    // A run that died mid-write
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    write!(file, "{{\"source\": \"/remote/3\", \"ent").unwrap();
+
+    let entries = Journal::replay(&path).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[2].0, PathBuf::from("/remote/2"));
+    assert_eq!(entries[2].1.size, 20);
+
+    Journal::remove(&path).unwrap();
+    assert!(Journal::replay(&path).unwrap().is_empty());
+    Journal::remove(&path).unwrap();
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::backup::rsync::Sftp;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::traits::Rsync;
+
+pub const DEFAULT_KEYS_DIR: &str = "/etc/rensen/keys";
+
+/// How to log in to a host to change what keys it authorizes
+pub enum Login<'a> {
+    Password(&'a str), // one-time credentials of its user, for the first key
+    Key,               // the `key` of its config
+}
+
+pub fn keys_dir(global_config: &GlobalConfig) -> PathBuf {
+    global_config.keys.clone().unwrap_or(PathBuf::from(DEFAULT_KEYS_DIR))
+}
+
+/// Where a new key of `hostname` goes, $keys/$hostname/id_ed25519-$now. Each
+/// key gets a file of its own, so rotating never overwrites the one in use.
+pub fn key_path(global_config: &GlobalConfig, hostname: &str, now: i64) -> PathBuf {
+    keys_dir(global_config).join(hostname).join(format!("id_ed25519-{}", now))
+}
+
+/// Generates an ed25519 key pair without a passphrase at `path` and
+/// `path`.pub with ssh-keygen, returning the public key
+pub fn generate(path: &Path, comment: &str) -> Result<String, Trap> {
+    if path.exists() {
+        return Err(Trap::FS(format!("There is a key at {:?} already", path)));
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .and_then(|_| fs::set_permissions(parent, fs::Permissions::from_mode(0o700)))
+            .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", parent, err)))?;
+    }
+
+    let output = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-C", comment, "-f"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| Trap::STD(format!("Could not run ssh-keygen: {}", err)))?;
+    if !output.status.success() {
+        return Err(Trap::STD(format!("ssh-keygen failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+
+    public_key(path)
+}
+
+/// The public key next to the private key at `path`
+pub fn public_key(path: &Path) -> Result<String, Trap> {
+    let public_path = PathBuf::from(format!("{}.pub", path.display()));
+    fs::read_to_string(&public_path)
+        .map(|key| key.trim().to_string())
+        .map_err(|err| Trap::FS(format!("Could not read {:?}: {}", public_path, err)))
+}
+
+/// Adds `public_key` to the authorized_keys of the user on the host, unless
+/// it is there already
+pub fn authorize_command(public_key: &str) -> String {
+    let key = quote(public_key);
+    format!("umask 077; mkdir -p ~/.ssh && touch ~/.ssh/authorized_keys && \
+             (grep -qxF {key} ~/.ssh/authorized_keys || echo {key} >> ~/.ssh/authorized_keys)")
+}
+
+/// Removes `public_key` from the authorized_keys of the user on the host,
+/// leaving every other key there
+pub fn revoke_command(public_key: &str) -> String {
+    format!("umask 077; [ -f ~/.ssh/authorized_keys ] || exit 0; \
+             grep -vxF {} ~/.ssh/authorized_keys > ~/.ssh/authorized_keys.rensen; \
+             mv ~/.ssh/authorized_keys.rensen ~/.ssh/authorized_keys", quote(public_key))
+}
+
+/// Passphrases asked for at the terminal, by key, so hosts sharing a key and
+/// the `sources` of a host ask only once
+static PROMPTED: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
+
+/// The passphrase of the `key` of `host_config`, from where its
+/// `key_passphrase` refers to. None for keys without one.
+pub fn key_passphrase(host_config: &HostConfig) -> Result<Option<String>, Trap> {
+    let reference = match &host_config.key_passphrase {
+        Some(reference) => reference.as_str(),
+        None => return Ok(None),
+    };
+
+    let passphrase = match reference.split_once(':') {
+        Some(("file", path)) => fs::read_to_string(path)
+            .map(|contents| contents.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|err| Trap::Auth(format!("Could not read the key passphrase from {:?}: {}", path, err)))?,
+        Some(("env", name)) => std::env::var(name)
+            .map_err(|_| Trap::Auth(format!("The key passphrase is not set in ${}", name)))?,
+        None if reference == "prompt" => prompt_passphrase(host_config)?,
+        _ => return Err(Trap::Config(format!("key_passphrase `{}` is not `file:<path>`, `env:<name>` or `prompt`", reference))),
+    };
+
+    Ok(Some(passphrase))
+}
+
+fn prompt_passphrase(host_config: &HostConfig) -> Result<String, Trap> {
+    let key = host_config.key.clone().unwrap_or_default();
+    let mut prompted = PROMPTED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(passphrase) = prompted.get(&key) {
+        return Ok(passphrase.clone());
+    }
+
+    // rensend and timers have nobody to ask
+    if !std::io::stdin().is_terminal() {
+        return Err(Trap::Auth(format!("The passphrase of {:?} can only be given at a terminal, use `file:` or `env:` for unattended runs", key)));
+    }
+
+    eprint!("Passphrase of {:?}: ", key);
+    let _ = std::io::stderr().flush();
+    let passphrase = console::Term::stderr().read_secure_line()
+        .map_err(|err| Trap::ReadInput(format!("Could not read the key passphrase: {}", err)))?;
+
+    prompted.insert(key, passphrase.clone());
+    Ok(passphrase)
+}
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+fn login<'a>(global_config: &'a GlobalConfig, host_config: &'a HostConfig, login: Login) -> Result<Sftp<'a>, Trap> {
+    let mut sftp = Sftp::new(host_config, global_config, Record::new(), false);
+    sftp.connect()?;
+    match login {
+        Login::Password(password) => sftp.auth_password(password)?,
+        Login::Key => sftp.auth()?,
+    }
+
+    Ok(sftp)
+}
+
+/// Logs in to the host with `key`, to make sure it works before it is used
+fn check(global_config: &GlobalConfig, host_config: &HostConfig, key: &Path) -> Result<(), Trap> {
+    let host_config = HostConfig { key: Some(key.to_path_buf()), ..host_config.clone() };
+    login(global_config, &host_config, Login::Key)?
+        .exec("true")
+        .map(|_| ())
+}
+
+/// Generates the first key of `hostname` and, given the password of its
+/// user, authorizes it on the host and checks it works. Returns the key to
+/// put in the config as `key`.
+pub fn init(global_config: &GlobalConfig, host_config: &HostConfig, hostname: &str, password: Option<&str>, now: i64) -> Result<PathBuf, Trap> {
+    let path = key_path(global_config, hostname, now);
+    let public = generate(&path, &format!("rensen@{}", hostname))?;
+
+    if let Some(password) = password {
+        login(global_config, host_config, Login::Password(password))?
+            .exec(&authorize_command(&public))?;
+        check(global_config, host_config, &path)?;
+    }
+
+    Ok(path)
+}
+
+/// Generates a new key of `hostname` and authorizes it on the host over the
+/// current one. The current key stays authorized, so anything still using
+/// it keeps working, until it is pruned. Returns the new key.
+pub fn rotate(global_config: &GlobalConfig, host_config: &HostConfig, hostname: &str, now: i64) -> Result<PathBuf, Trap> {
+    if let Some(previous) = &host_config.previous_key {
+        return Err(Trap::InvalidInput(format!("{:?} is still authorized from the last rotation, prune it first", previous)));
+    }
+
+    let path = key_path(global_config, hostname, now);
+    let public = generate(&path, &format!("rensen@{}", hostname))?;
+    login(global_config, host_config, Login::Key)?
+        .exec(&authorize_command(&public))?;
+    check(global_config, host_config, &path)?;
+
+    Ok(path)
+}
+
+/// Revokes the `previous_key` of the host, logging in with the current key,
+/// and removes its files if they were generated by rensen. Returns it.
+pub fn prune(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<PathBuf, Trap> {
+    let previous = host_config.previous_key.clone()
+        .ok_or(Trap::InvalidInput(String::from("There is no previous key to prune")))?;
+
+    let public = public_key(&previous)?;
+    login(global_config, host_config, Login::Key)?
+        .exec(&revoke_command(&public))?;
+
+    // Keys from elsewhere, e.g. ~/.ssh, may be used for other hosts too
+    if previous.starts_with(keys_dir(global_config)) {
+        let _ = fs::remove_file(PathBuf::from(format!("{}.pub", previous.display())));
+        fs::remove_file(&previous)
+            .map_err(|err| Trap::FS(format!("Could not remove {:?}: {}", previous, err)))?;
+    }
+
+    Ok(previous)
+}
+
+#[test]
+fn test_keys() {
+    let root = std::env::temp_dir().join("rensen_test_keys");
+    let _ = fs::remove_dir_all(&root);
+    let global_config = GlobalConfig { keys: Some(root.join("keys")), ..Default::default() };
+    assert_eq!(key_path(&global_config, "web01", 1700000000), root.join("keys/web01/id_ed25519-1700000000"));
+    assert_eq!(keys_dir(&GlobalConfig::default()), PathBuf::from(DEFAULT_KEYS_DIR));
+
+    // The commands run the way they would on the host, with $HOME in the test dir
+    let run = |command: &str| {
+        let status = Command::new("sh").arg("-c").arg(command).env("HOME", &root).status().unwrap();
+        assert!(status.success(), "{}", command);
+    };
+    let authorized = || fs::read_to_string(root.join(".ssh/authorized_keys")).unwrap();
+
+    fs::create_dir_all(root.join(".ssh")).unwrap();
+    fs::write(root.join(".ssh/authorized_keys"), "ssh-ed25519 AAAAother admin@laptop\n").unwrap();
+    run(&authorize_command("ssh-ed25519 AAAAold rensen@web01"));
+    run(&authorize_command("ssh-ed25519 AAAAnew rensen@web01"));
+    run(&authorize_command("ssh-ed25519 AAAAnew rensen@web01"));
+    assert_eq!(authorized().lines().count(), 3);
+
+    run(&revoke_command("ssh-ed25519 AAAAold rensen@web01"));
+    assert_eq!(authorized(), "ssh-ed25519 AAAAother admin@laptop\nssh-ed25519 AAAAnew rensen@web01\n");
+    assert_eq!(fs::metadata(root.join(".ssh/authorized_keys")).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let passphrase_path = root.join("passphrase");
+    fs::write(&passphrase_path, "hunter2\n").unwrap();
+    let with = |reference: &str| HostConfig { key_passphrase: Some(reference.to_string()), ..Default::default() };
+    assert_eq!(key_passphrase(&with(&format!("file:{}", passphrase_path.display()))).unwrap().as_deref(), Some("hunter2"));
+    assert_eq!(key_passphrase(&with("env:PATH")).unwrap(), std::env::var("PATH").ok());
+    assert!(key_passphrase(&with("env:RENSEN_TEST_NO_SUCH_VAR")).is_err());
+    assert!(key_passphrase(&with("hunter2")).is_err());
+    assert!(key_passphrase(&HostConfig::default()).unwrap().is_none());
+
+    // Only where ssh-keygen is installed
+    let path = key_path(&global_config, "web01", 1);
+    if let Ok(public) = generate(&path, "rensen@web01") {
+        assert!(public.starts_with("ssh-ed25519 ") && public.ends_with(" rensen@web01"));
+        assert!(generate(&path, "rensen@web01").is_err());
+    }
+    let _ = fs::remove_dir_all(&root);
+}
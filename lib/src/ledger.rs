@@ -0,0 +1,145 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, TimeZone};
+
+use crate::config::GlobalConfig;
+use crate::logging::Trap;
+use crate::traits::JsonFile;
+
+/// Transfer totals of one host during one month
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub bytes: u64,
+    pub files: u64,
+    pub runs: u64,
+}
+
+/// Bytes transferred per host and month, for chargeback between the teams
+/// sharing a destination. Unlike the history it is never pruned.
+///
+/// { "2024-05": { "myserver": { "bytes": 1024, "files": 2, "runs": 1 } } }
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferLedger {
+    pub months: BTreeMap<String, BTreeMap<String, Transfer>>,
+}
+
+impl TransferLedger {
+    /// `ledger.json` next to the history
+    pub fn path(global_config: &GlobalConfig) -> PathBuf {
+        global_config.log
+            .parent()
+            .unwrap_or(Path::new("/etc/rensen"))
+            .join("ledger.json")
+    }
+
+    /// Month key (`YYYY-MM`, local time) of a unix timestamp
+    pub fn month(timestamp: i64) -> String {
+        Local.timestamp_opt(timestamp, 0)
+            .single()
+            .map(|time| time.format("%Y-%m").to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn add(&mut self, hostname: &str, started: i64, bytes: u64, files: u64) {
+        let transfer = self.months
+            .entry(Self::month(started))
+            .or_default()
+            .entry(hostname.to_string())
+            .or_default();
+
+        transfer.bytes += bytes;
+        transfer.files += files;
+        transfer.runs += 1;
+    }
+
+    /// Adds a run to the ledger at `path`. The file is locked while it is
+    /// updated, as hosts finishing at the same time would lose each other's runs.
+    pub fn record(path: &Path, hostname: &str, started: i64, bytes: u64, files: u64) -> Result<(), Trap> {
+        let map_err = |err: std::io::Error| Trap::FS(format!("Could not update ledger at {:?}: {}", path, err));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(map_err)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(map_err(std::io::Error::last_os_error()));
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).map_err(map_err)?;
+
+        let mut ledger: TransferLedger = match contents.trim().is_empty() {
+            true => TransferLedger::default(),
+            false => serde_json::from_str(&contents)
+                .map_err(|err| Trap::Deserialize(format!("Could not read ledger at {:?}: {}", path, err)))?,
+        };
+
+        ledger.add(hostname, started, bytes, files);
+
+        let json = serde_json::to_string_pretty(&ledger)
+            .map_err(|err| Trap::Serialize(format!("Could not serialize ledger: {}", err)))?;
+
+        file.seek(SeekFrom::Start(0)).map_err(map_err)?;
+        file.set_len(0).map_err(map_err)?;
+        file.write_all(json.as_bytes()).map_err(map_err)?;
+
+        // Lock is released when the file is closed
+        Ok(())
+    }
+
+    /// Totals of `hostname` for every month it transferred anything, oldest first
+    pub fn for_host(&self, hostname: &str) -> Vec<(&str, Transfer)> {
+        self.months.iter()
+            .filter_map(|(month, hosts)| hosts.get(hostname).map(|transfer| (month.as_str(), *transfer)))
+            .collect()
+    }
+}
+
+impl JsonFile for TransferLedger {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(TransferLedger::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let ledger: TransferLedger = serde_json::from_str(&contents)?;
+        Ok(ledger)
+    }
+}
+
+#[test]
+fn test_transfer_ledger() {
+    let path = std::env::temp_dir().join("rensen_test_ledger.json");
+    let _ = std::fs::remove_file(&path);
+
+    let may = Local.with_ymd_and_hms(2024, 5, 10, 12, 0, 0).unwrap().timestamp();
+    let june = Local.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap().timestamp();
+
+    TransferLedger::record(&path, "a", may, 100, 1).unwrap();
+    TransferLedger::record(&path, "a", may, 50, 2).unwrap();
+    TransferLedger::record(&path, "b", june, 10, 1).unwrap();
+    TransferLedger::record(&path, "a", june, 1, 1).unwrap();
+
+    let ledger = TransferLedger::deserialize_json(&path).unwrap();
+    assert_eq!(ledger.months["2024-05"]["a"], Transfer { bytes: 150, files: 3, runs: 2 });
+    assert_eq!(ledger.for_host("a").len(), 2);
+    assert_eq!(ledger.for_host("b"), vec![("2024-06", Transfer { bytes: 10, files: 1, runs: 1 })]);
+}
@@ -6,3 +6,79 @@ pub mod utils;
 pub mod compiler;
 pub mod snapshot;
 pub mod traits;
+pub mod notify;
+pub mod quota;
+pub mod sla;
+pub mod history;
+pub mod template;
+pub mod report;
+pub mod units;
+pub mod exit;
+pub mod lock;
+pub mod schedule;
+pub mod quiesce;
+pub mod ledger;
+pub mod compact;
+pub mod journal;
+pub mod gzip;
+pub mod verify;
+pub mod index;
+pub mod inventory;
+pub mod plan;
+pub mod results;
+pub mod replica;
+pub mod helper;
+pub mod seed;
+pub mod checksum;
+pub mod runbook;
+pub mod mirror;
+pub mod drift;
+pub mod breaker;
+pub mod encrypt;
+pub mod state;
+pub mod retention;
+pub mod annotate;
+pub mod forecast;
+pub mod listing;
+pub mod audit;
+pub mod retire;
+pub mod store;
+pub mod webdav;
+pub mod resume;
+pub mod rsyncd;
+pub mod usage;
+pub mod chunks;
+pub mod maintenance;
+pub mod unpacked;
+pub mod hooks;
+pub mod filter;
+pub mod sandbox;
+pub mod retry;
+pub mod ownership;
+pub mod keys;
+pub mod credentials;
+pub mod calendar;
+pub mod queue;
+pub mod simulate;
+pub mod incremental;
+pub mod progress;
+pub mod codec;
+pub mod elevate;
+pub mod delta;
+pub mod preflight;
+pub mod phases;
+pub mod s3;
+pub mod histogram;
+pub mod remote;
+pub mod advise;
+pub mod cancel;
+pub mod bootable;
+pub mod status;
+pub mod migrate;
+pub mod special;
+pub mod repository;
+pub mod sparse;
+pub mod auth;
+
+#[cfg(test)]
+mod tests;
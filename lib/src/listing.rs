@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::helper::quote;
+use crate::logging::Trap;
+
+/// File in a snapshot of a `metadata_only` source, listing what is there
+/// instead of its contents
+pub const LISTING_FILE: &str = "listing.tsv";
+
+/// A file of a `metadata_only` source as it was on the host
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListedFile {
+    pub path: String,
+    pub size: u64,
+    pub mode: String,            // octal, e.g. 644
+    pub owner: String,           // uid:gid
+    pub mtime: u64,              // unix seconds
+    pub sha256: Option<String>,  // None if the host could not read it
+}
+
+/// Lists size, mode, owner and mtime of every file below `source`
+pub fn stat_command(source: &Path) -> String {
+    format!("find {} -type f -printf '%s\\t%m\\t%U:%G\\t%T@\\t%p\\n'", quote(source))
+}
+
+/// Hashes every file below `source`, on the host
+pub fn hash_command(source: &Path) -> String {
+    format!("find {} -type f -exec sha256sum {{}} + 2>/dev/null; true", quote(source))
+}
+
+/// The files of the outputs of stat_command and hash_command, by path
+pub fn parse_listing(stats: &str, hashes: &str) -> Vec<ListedFile> {
+    let hashes: HashMap<&str, &str> = hashes.lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(hash, path)| (path.trim_start_matches([' ', '*']), hash))
+        .collect();
+
+    let mut files: Vec<ListedFile> = stats.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(5, '\t');
+            let size = fields.next()?.parse().ok()?;
+            let mode = fields.next()?.to_string();
+            let owner = fields.next()?.to_string();
+            let mtime = fields.next()?.split('.').next()?.parse().ok()?;
+            let path = fields.next()?.to_string();
+            let sha256 = hashes.get(path.as_str()).map(|hash| hash.to_string());
+            Some(ListedFile { path, size, mode, owner, mtime, sha256 })
+        })
+        .collect();
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+/// Writes `files` to `destination` as tab separated values, one per line
+pub fn write_listing(files: &[ListedFile], destination: &Path) -> Result<(), Trap> {
+    let mut file = File::create(destination)
+        .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", destination, err)))?;
+
+    let mut contents = String::from("path\tsize\tmode\towner\tmtime\tsha256\n");
+    for listed in files {
+        contents.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\n",
+            listed.path, listed.size, listed.mode, listed.owner, listed.mtime, listed.sha256.as_deref().unwrap_or("-")));
+    }
+
+    file.write_all(contents.as_bytes())
+        .map_err(|err| Trap::FS(format!("Could not write {:?}: {}", destination, err)))
+}
+
+#[test]
+fn test_listing() {
+    let stats = "1024\t644\t1000:1000\t1714561200.5\t/srv/media/a.mkv\n\
+                 7\t600\t0:0\t1714561300.0\t/srv/media/tab\there\n\
+                 garbage\n";
+    let hashes = "e3b0c442  /srv/media/a.mkv\n";
+
+    let files = parse_listing(stats, hashes);
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0], ListedFile {
+        path: String::from("/srv/media/a.mkv"),
+        size: 1024,
+        mode: String::from("644"),
+        owner: String::from("1000:1000"),
+        mtime: 1714561200,
+        sha256: Some(String::from("e3b0c442")),
+    });
+    assert_eq!((files[1].path.as_str(), files[1].sha256.as_deref()), ("/srv/media/tab\there", None));
+
+    let destination = std::env::temp_dir().join("rensen_test_listing.tsv");
+    write_listing(&files, &destination).unwrap();
+    let written = std::fs::read_to_string(&destination).unwrap();
+    assert_eq!(written.lines().nth(1), Some("/srv/media/a.mkv\t1024\t644\t1000:1000\t1714561200\te3b0c442"));
+    let _ = std::fs::remove_file(&destination);
+}
@@ -0,0 +1,82 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+
+/// Lock held while a host is being backed up or its records are rewritten,
+/// so the daemon and `rensen` never write the same snapshot tree or record
+/// at once. An advisory flock on the lock file, which the kernel releases
+/// when the holder exits, so a crashed run never leaves the host locked.
+/// Released when dropped.
+#[derive(Debug)]
+pub struct HostLock {
+    _file: File,
+}
+
+impl HostLock {
+    /// $backups/$identifier/.records/lock
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        global_config.backups
+            .join(&host_config.identifier)
+            .join(".records")
+            .join("lock")
+    }
+
+    /// Takes the lock for `host_config`, failing right away if another
+    /// process holds it
+    pub fn acquire(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Self, Trap> {
+        global_config.ensure_writable(&format!("lock `{}`", host_config.identifier))?;
+
+        let path = Self::path(global_config, host_config);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+
+        // Never removed, a process could otherwise lock a file that is gone
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .map_err(|err| Trap::FS(format!("Could not open lock {:?}: {}", path, err)))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(Trap::FS(format!("Could not take lock {:?}: {}", path, err)));
+            }
+
+            // The holder wrote its pid once it had the lock
+            return Err(match fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse::<i32>().ok()) {
+                Some(pid) => Trap::Lock(format!("`{}` is already being backed up by process {}", host_config.identifier, pid)),
+                None => Trap::Lock(format!("`{}` is already being backed up", host_config.identifier)),
+            });
+        }
+
+        let _ = file.set_len(0).and_then(|_| write!(file, "{}", std::process::id()));
+        Ok(HostLock { _file: file })
+    }
+}
+
+#[test]
+fn test_host_lock() {
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_lock"),
+        ..Default::default()
+    };
+    let host_config = HostConfig { identifier: String::from("lockhost"), ..Default::default() };
+    let _ = fs::remove_dir_all(&global_config.backups);
+
+    let lock = HostLock::acquire(&global_config, &host_config).unwrap();
+    assert!(matches!(HostLock::acquire(&global_config, &host_config), Err(Trap::Lock(_))));
+    drop(lock);
+
+    // Stale lock from a process that is gone
+    fs::write(HostLock::path(&global_config, &host_config), "999999999").unwrap();
+    let lock = HostLock::acquire(&global_config, &host_config).unwrap();
+    assert_eq!(fs::read_to_string(HostLock::path(&global_config, &host_config)).unwrap(), std::process::id().to_string());
+    drop(lock);
+    assert!(HostLock::acquire(&global_config, &host_config).is_ok());
+
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
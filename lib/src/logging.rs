@@ -1,11 +1,59 @@
+use serde::{Serialize, Deserialize};
 use std::fs::{OpenOptions, File};
 use std::path::Path;
 use std::io::prelude::*;
+use std::fmt;
 
-use log::error;
+use chrono::Local;
+use log::{error, warn, info, debug};
 use crate::utils::get_datetime;
 use crate::config::GlobalConfig;
 
+/// Severity of a log line, lines below `log_level` are not written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self {
+            Level::Debug => "DEBUG",
+            Level::Info  => "INFO",
+            Level::Warn  => "WARN",
+            Level::Error => "ERROR",
+        };
+        write!(f, "{}", level)
+    }
+}
+
+/// How the log is written, `json` being one object per line for journald,
+/// ELK and the like
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// One line of the log in the `json` format
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogLine {
+    pub time: String,  // RFC 3339, local time
+    pub level: Level,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,  // of the trap, e.g. `Connect`
+    pub message: String,
+}
+
 #[derive(Debug)]
 pub enum Trap {
 
@@ -26,38 +74,141 @@ pub enum Trap {
     Serialize(String),
     Metadata(String),
     Scheduler(String),
+    Quota(String),
+    Sla(String),
+    Lock(String),
+    Source(String),
+    Quiesce(String),
+    ReadOnly(String),
+    Verify(String),
+    Inventory(String),
+    Replica(String),
+    Helper(String),
+    Seed(String),
+    Checksum(String),
+    Restore(String),
+    Mirror(String),
+    Breaker(String),
+    Encrypt(String),
+    WebDav(String),
+    Rsyncd(String),
+    Hook(String),
+    Sandbox(String),
+    Timeout(String),
+    Cancelled(String),
+    RateLimit(String),
+    Incompatible(String),
 
 
 }
 
-pub fn log_trap(global_config: &GlobalConfig, trap: &Trap) {
-    let trap_msg = match trap {
-        Trap::STD(msg)          => format!("STD: {}", msg),
-        Trap::Connect(msg)      => format!("Connect: {}", msg),
-        Trap::Session(msg)      => format!("Session: {}", msg),
-        Trap::Handshake(msg)    => format!("Handshake: {}", msg),
-        Trap::KeyLoad(msg)      => format!("KeyLoad: {}", msg),
-        Trap::Auth(msg)         => format!("Auth: {}", msg),
-        Trap::Channel(msg)      => format!("Channel: {}", msg),
-        Trap::FS(msg)           => format!("FS: {}", msg),
-        Trap::Config(msg)       => format!("Config: {}", msg),
-        Trap::Copy(msg)         => format!("Copy: {}", msg),
-        Trap::Missing(msg)      => format!("Missing: {}", msg),
-        Trap::InvalidInput(msg) => format!("Invalid: {}", msg),
-        Trap::ReadInput(msg)    => format!("ReadInput: {}", msg),
-        Trap::Serialize(msg)    => format!("Serialize: {}", msg),
-        Trap::Deserialize(msg)  => format!("Deserialize: {}", msg),
-        Trap::Metadata(msg)     => format!("Metadata: {}", msg),
-        Trap::Scheduler(msg)     => format!("Scheduler: {}", msg),
-    };
-    
+impl Trap {
+    /// How severe the trap is when logged. Most stop what they happened in,
+    /// those that only hold something back are warnings.
+    pub fn level(&self) -> Level {
+        match self {
+            Trap::Missing(_) | Trap::Breaker(_) | Trap::Sla(_) | Trap::Cancelled(_) => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+
+    /// Returns the kind of the trap along with its message
+    pub fn parts(&self) -> (&'static str, &str) {
+        match self {
+            Trap::STD(msg)          => ("STD", msg),
+            Trap::Connect(msg)      => ("Connect", msg),
+            Trap::Session(msg)      => ("Session", msg),
+            Trap::Handshake(msg)    => ("Handshake", msg),
+            Trap::KeyLoad(msg)      => ("KeyLoad", msg),
+            Trap::Auth(msg)         => ("Auth", msg),
+            Trap::Channel(msg)      => ("Channel", msg),
+            Trap::FS(msg)           => ("FS", msg),
+            Trap::Config(msg)       => ("Config", msg),
+            Trap::Copy(msg)         => ("Copy", msg),
+            Trap::Missing(msg)      => ("Missing", msg),
+            Trap::InvalidInput(msg) => ("Invalid", msg),
+            Trap::ReadInput(msg)    => ("ReadInput", msg),
+            Trap::Serialize(msg)    => ("Serialize", msg),
+            Trap::Deserialize(msg)  => ("Deserialize", msg),
+            Trap::Metadata(msg)     => ("Metadata", msg),
+            Trap::Scheduler(msg)    => ("Scheduler", msg),
+            Trap::Quota(msg)        => ("Quota", msg),
+            Trap::Sla(msg)          => ("SLA", msg),
+            Trap::Lock(msg)         => ("Lock", msg),
+            Trap::Source(msg)       => ("Source", msg),
+            Trap::Quiesce(msg)      => ("Quiesce", msg),
+            Trap::ReadOnly(msg)     => ("ReadOnly", msg),
+            Trap::Verify(msg)       => ("Verify", msg),
+            Trap::Inventory(msg)    => ("Inventory", msg),
+            Trap::Replica(msg)      => ("Replica", msg),
+            Trap::Helper(msg)       => ("Helper", msg),
+            Trap::Seed(msg)         => ("Seed", msg),
+            Trap::Checksum(msg)     => ("Checksum", msg),
+            Trap::Restore(msg)      => ("Restore", msg),
+            Trap::Mirror(msg)       => ("Mirror", msg),
+            Trap::Breaker(msg)      => ("Breaker", msg),
+            Trap::Encrypt(msg)      => ("Encrypt", msg),
+            Trap::WebDav(msg)       => ("WebDAV", msg),
+            Trap::Rsyncd(msg)       => ("Rsyncd", msg),
+            Trap::Hook(msg)         => ("Hook", msg),
+            Trap::Sandbox(msg)      => ("Sandbox", msg),
+            Trap::Timeout(msg)      => ("Timeout", msg),
+            Trap::Cancelled(msg)    => ("Cancelled", msg),
+            Trap::RateLimit(msg)    => ("RateLimit", msg),
+            Trap::Incompatible(msg) => ("Incompatible", msg),
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, msg) = self.parts();
+        write!(f, "{}: {}", kind, msg)
+    }
+}
+
+/// Formats a line of the log, without its newline
+pub fn format_line(format: LogFormat, level: Level, host: Option<&str>, kind: Option<&str>, message: &str) -> String {
+    match format {
+        LogFormat::Text => {
+            let host = host.map(|host| format!(" [{}]", host)).unwrap_or_default();
+            let kind = kind.map(|kind| format!(" {}:", kind)).unwrap_or_default();
+            format!("[{}] {}{}{} {}", get_datetime(), level, host, kind, message)
+        },
+        LogFormat::Json => {
+            let line = LogLine {
+                time: Local::now().to_rfc3339(),
+                level,
+                host: host.map(String::from),
+                kind: kind.map(String::from),
+                message: message.to_string(),
+            };
+            serde_json::to_string(&line).unwrap_or_default()
+        },
+    }
+}
+
+/// Writes a line to the log if `level` is at least the configured
+/// `log_level`, with the host it is about if any
+pub fn log_event(global_config: &GlobalConfig, level: Level, host: Option<&str>, kind: Option<&str>, message: &str) {
+    if level < global_config.log_level.unwrap_or_default() {
+        return;
+    }
+
+    let line = format_line(global_config.log_format.unwrap_or_default(), level, host, kind, message);
+    match level {
+        Level::Debug => debug!("{}", line),
+        Level::Info  => info!("{}", line),
+        Level::Warn  => warn!("{}", line),
+        Level::Error => error!("{}", line),
+    }
+
     // Opening log file
     if !Path::new(&global_config.log).exists() {
         let _ = File::create(&global_config.log);
     }
 
     let mut file = match OpenOptions::new()
-        .write(true)
         .append(true)
         .open(&global_config.log) {
             Ok(file) => file,
@@ -67,11 +218,39 @@ pub fn log_trap(global_config: &GlobalConfig, trap: &Trap) {
             },
     };
 
-    let current_time = get_datetime();
-
-    if let Err(err) = writeln!(file, "[{}] {}", current_time, trap_msg) {
+    if let Err(err) = writeln!(file, "{}", line) {
         eprintln!("Problems writing to log file `{:?}`. Please check permissions: {}", &global_config.log, err);
     }
+}
+
+pub fn log_trap(global_config: &GlobalConfig, trap: &Trap) {
+    let (kind, message) = trap.parts();
+    log_event(global_config, trap.level(), None, Some(kind), message);
+}
+
+/// log_trap for a trap about `hostname`
+pub fn log_host_trap(global_config: &GlobalConfig, hostname: &str, trap: &Trap) {
+    let (kind, message) = trap.parts();
+    log_event(global_config, trap.level(), Some(hostname), Some(kind), message);
+}
+
+#[test]
+fn test_log_levels_and_format() {
+    let log = std::env::temp_dir().join("rensen_test_logging.log");
+    let _ = std::fs::remove_file(&log);
+    let global_config = GlobalConfig { log: log.clone(), log_level: Some(Level::Warn), log_format: Some(LogFormat::Json), ..Default::default() };
+
+    log_event(&global_config, Level::Info, Some("web01"), None, "Backup started");
+    log_host_trap(&global_config, "web01", &Trap::Connect(String::from("timed out")));
+    log_trap(&global_config, &Trap::Missing(String::from("no cron_schedule")));
+
+    let contents = std::fs::read_to_string(&log).unwrap();
+    let lines: Vec<LogLine> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!((lines[0].level, lines[0].host.as_deref(), lines[0].kind.as_deref()), (Level::Error, Some("web01"), Some("Connect")));
+    assert_eq!((lines[1].level, lines[1].host.as_deref(), lines[1].message.as_str()), (Level::Warn, None, "no cron_schedule"));
 
-    error!("{}", trap_msg);
+    let text = format_line(LogFormat::Text, Level::Error, Some("web01"), Some("Connect"), "timed out");
+    assert!(text.ends_with("] ERROR [web01] Connect: timed out"));
+    let _ = std::fs::remove_file(&log);
 }
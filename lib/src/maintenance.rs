@@ -0,0 +1,232 @@
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cron::Schedule;
+
+use crate::chunks::ChunkStore;
+use crate::config::{GlobalConfig, Host, HostConfig};
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::seed::{parse_rate, Throttle};
+use crate::traits::JsonFile;
+use crate::verify::{snapshots, verify_snapshot_with, Verified, VerifyResult, VerifyState};
+
+/// Verification of the snapshots of every host in a nightly window, e.g.
+///
+/// maintenance:
+///   schedule: "0 0 1 * * *"
+///   hours: 2
+///   rate: 200M
+///
+/// Snapshots never verified go first, then those verified the longest ago.
+/// Once the window is over no more are started, the rest are picked up the
+/// next night.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    pub schedule: String,                 // cron the window opens at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hours: Option<f64>,               // length of the window, default: 2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<String>,             // archive reads of all verifies together per second, e.g. `200M`, default: unlimited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel: Option<usize>,          // snapshots verified at once, default: 2
+}
+
+pub const DEFAULT_MAINTENANCE_HOURS: f64 = 2.0;
+pub const DEFAULT_MAINTENANCE_PARALLEL: usize = 2;
+
+impl MaintenanceConfig {
+    pub fn parse_schedule(&self) -> Result<Schedule, Trap> {
+        Schedule::from_str(&self.schedule)
+            .map_err(|err| Trap::Config(format!("Invalid maintenance schedule `{}`: {}", self.schedule, err)))
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs_f64(self.hours.unwrap_or(DEFAULT_MAINTENANCE_HOURS).max(0.0) * 3600.0)
+    }
+}
+
+/// A snapshot waiting to be verified
+#[derive(Debug, Clone)]
+pub struct Due {
+    pub hostname: String,
+    pub host_config: HostConfig,
+    pub snapshot: String,
+    pub verified: Option<i64>, // when it was last verified
+}
+
+/// Every snapshot of `hosts`, in the order they are to be verified: never
+/// verified first, then by their last verification
+pub fn due(global_config: &GlobalConfig, hosts: &[Host]) -> Vec<Due> {
+    let mut due = Vec::new();
+    for host in hosts.iter().filter(|host| host.hostname != "dummy") {
+        for host_config in host.config.namespaces().unwrap_or_default() {
+            let state = VerifyState::deserialize_json(&VerifyState::path(global_config, &host_config)).unwrap_or_default();
+            let hostname = match &host_config.namespace {
+                Some(name) => format!("{}:{}", host.hostname, name),
+                None => host.hostname.clone(),
+            };
+
+            for snapshot in snapshots(global_config, &host_config) {
+                let verified = state.snapshots.get(&snapshot).map(|verified| verified.time);
+                due.push(Due { hostname: hostname.clone(), host_config: host_config.clone(), snapshot, verified });
+            }
+        }
+    }
+
+    due.sort_by(|a, b| (a.verified, &a.snapshot, &a.hostname).cmp(&(b.verified, &b.snapshot, &b.hostname)));
+    due
+}
+
+/// What a maintenance window got through
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceReport {
+    pub verified: Vec<(String, VerifyResult)>, // by hostname
+    pub left: usize,                            // snapshots the window was too short for
+    pub bytes: u64,                             // of archives read
+    pub warnings: Vec<String>,                  // verify states that could not be written
+}
+
+/// Reads through the throttle shared by all verifies of a window
+struct Limited {
+    file: File,
+    throttle: Arc<Mutex<Throttle>>,
+    bytes: Arc<Mutex<u64>>,
+}
+
+impl Read for Limited {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buffer)?;
+        *self.bytes.lock().unwrap() += read as u64;
+        let delay = self.throttle.lock().unwrap().delay(read);
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        Ok(read)
+    }
+}
+
+/// Verifies the snapshots of `hosts` in the order of `due` until the window
+/// of `config` is over, noting each in the verify state of its host as it
+/// is done. On a read-only replica nothing is noted.
+pub fn run_maintenance(global_config: &GlobalConfig, hosts: &[Host], config: &MaintenanceConfig, now: i64) -> Result<MaintenanceReport, Trap> {
+    let limit = config.rate.as_deref().map(parse_rate).transpose()?;
+    let deadline = Instant::now() + config.window();
+
+    let queue = Mutex::new(due(global_config, hosts).into_iter().collect::<VecDeque<Due>>());
+    let throttle = Arc::new(Mutex::new(Throttle::new(limit)));
+    let bytes = Arc::new(Mutex::new(0));
+    let verified = Mutex::new(Vec::new());
+    let warnings = Mutex::new(Vec::new());
+    let noting = Mutex::new(());
+    let chunks = ChunkStore::new(global_config);
+
+    let worker = || loop {
+        if Instant::now() >= deadline {
+            return;
+        }
+        let Some(due) = queue.lock().unwrap().pop_front() else { return };
+
+        let host_root_path = due.host_config.root(global_config);
+        let record_path = host_root_path.join(".records").join(format!("{}.json", due.snapshot));
+        let result = Record::deserialize_json(&record_path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))
+            .and_then(|record| verify_snapshot_with(&host_root_path.join(&due.snapshot), &record, &chunks, |file| {
+                Box::new(Limited { file, throttle: Arc::clone(&throttle), bytes: Arc::clone(&bytes) })
+            }));
+
+        // A snapshot that can not be read through fails its verification
+        let result = result.unwrap_or_else(|err| VerifyResult { snapshot: due.snapshot.clone(), problems: vec![err.to_string()], ..Default::default() });
+
+        // Hosts can have several snapshots verified at once
+        if !global_config.is_read_only() {
+            let _noting = noting.lock().unwrap();
+            if let Err(err) = note(global_config, &due.host_config, &due.snapshot, Verified { time: now, ok: result.is_ok() }) {
+                warnings.lock().unwrap().push(err.to_string());
+            }
+        }
+        verified.lock().unwrap().push((due.hostname, result));
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..config.parallel.unwrap_or(DEFAULT_MAINTENANCE_PARALLEL).max(1) {
+            scope.spawn(worker);
+        }
+    });
+
+    let bytes = *bytes.lock().unwrap();
+    Ok(MaintenanceReport {
+        verified: verified.into_inner().unwrap(),
+        left: queue.into_inner().unwrap().len(),
+        bytes,
+        warnings: warnings.into_inner().unwrap(),
+    })
+}
+
+fn note(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str, verified: Verified) -> Result<(), Trap> {
+    let state_path = VerifyState::path(global_config, host_config);
+    let mut state = VerifyState::deserialize_json(&state_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", state_path, err)))?;
+    state.snapshots.insert(snapshot.to_string(), verified);
+    state.serialize_json(&state_path)
+        .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", state_path, err)))
+}
+
+#[test]
+fn test_maintenance() {
+    use crate::snapshot::FileEntry;
+    use crate::utils::make_tar_gz;
+    use std::fs;
+    use std::path::PathBuf;
+
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_maintenance"), ..Default::default() };
+    let _ = fs::remove_dir_all(&global_config.backups);
+    let hosts: Vec<Host> = ["web", "db"].iter().map(|name| Host {
+        hostname: name.to_string(),
+        config: HostConfig { identifier: name.to_string(), ..Default::default() },
+    }).collect();
+
+    for host in hosts.iter() {
+        let root = host.config.root(&global_config);
+        fs::create_dir_all(root.join(".records")).unwrap();
+        for snapshot in ["2024-01-01-00-00-00", "2024-01-02-00-00-00"] {
+            let snapshot_path = root.join(snapshot);
+            fs::create_dir_all(snapshot_path.join("src")).unwrap();
+            fs::write(snapshot_path.join("src/a"), "1234").unwrap();
+            make_tar_gz(&snapshot_path, root.join(format!("{}.tar.gz", snapshot))).unwrap();
+
+            let mut record = Record::new();
+            record.snapshot.entries.insert(PathBuf::from("/src/a"), FileEntry::from(snapshot_path.join("src/a"), snapshot_path.clone(), 0, 4));
+            record.serialize_json(&root.join(".records").join(format!("{}.json", snapshot))).unwrap();
+        }
+    }
+
+    // One of web verified already, it goes last
+    note(&global_config, &hosts[0].config, "2024-01-01-00-00-00", Verified { time: 10, ok: true }).unwrap();
+    let order: Vec<(String, String)> = due(&global_config, &hosts).into_iter().map(|due| (due.hostname, due.snapshot)).collect();
+    assert_eq!(order, vec![
+        (String::from("db"), String::from("2024-01-01-00-00-00")),
+        (String::from("db"), String::from("2024-01-02-00-00-00")),
+        (String::from("web"), String::from("2024-01-02-00-00-00")),
+        (String::from("web"), String::from("2024-01-01-00-00-00")),
+    ]);
+
+    let config = MaintenanceConfig { schedule: String::from("0 0 1 * * *"), rate: Some(String::from("1M")), ..Default::default() };
+    let report = run_maintenance(&global_config, &hosts, &config, 20).unwrap();
+    assert_eq!((report.verified.len(), report.left), (4, 0));
+    assert!(report.verified.iter().all(|(_, result)| result.is_ok()) && report.bytes > 0);
+    let state = VerifyState::deserialize_json(&VerifyState::path(&global_config, &hosts[0].config)).unwrap();
+    assert_eq!(state.snapshots.get("2024-01-01-00-00-00"), Some(&Verified { time: 20, ok: true }));
+
+    // A window that is over starts nothing
+    let config = MaintenanceConfig { hours: Some(0.0), ..config };
+    assert_eq!(run_maintenance(&global_config, &hosts, &config, 30).unwrap().left, 4);
+    assert!(config.parse_schedule().is_ok());
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
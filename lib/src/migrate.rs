@@ -0,0 +1,351 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::chunks::ChunkStore;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::sandbox;
+use crate::snapshot::{Snapshot, SnapshotMeta};
+use crate::store::LocalStore;
+use crate::traits::{JsonFile, Store};
+use crate::utils::{is_contained, replace_common_prefix, write_tar_gz, ArchiveOptions};
+
+/// Directory below `snapshots` archives are unpacked in while migrated
+pub const MIGRATE_DIR: &str = ".migrate";
+
+/// How the snapshots of a host keep the contents of its files
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    Tar,     // in the snapshot's archive
+    Chunked, // in the chunk store, the archive holds them empty, as with `dedup`
+}
+
+impl Layout {
+    pub fn from(layout: &str) -> Result<Self, Trap> {
+        match layout.to_lowercase().as_str() {
+            "tar" => Ok(Layout::Tar),
+            "chunked" => Ok(Layout::Chunked),
+            // Trees only last while a run writes them or a restore reads them, gc takes them after
+            "plain" => Err(Trap::InvalidInput(String::from("Snapshots are not kept as plain trees, only as archives, expected tar or chunked"))),
+            _ => Err(Trap::InvalidInput(format!("Unknown layout `{}`, expected tar or chunked", layout))),
+        }
+    }
+
+    /// The layout runs of `host_config` write
+    pub fn of(host_config: &HostConfig) -> Self {
+        match host_config.dedup.unwrap_or(false) {
+            true => Layout::Chunked,
+            false => Layout::Tar,
+        }
+    }
+}
+
+/// Snapshots a migration of a host is done with, so an interrupted one
+/// picks up after them. Stored at $backups/$identifier/.records/migrate.json
+/// until the migration is done.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Migration {
+    to: Layout,
+    done: Vec<String>,
+}
+
+impl Migration {
+    fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("migrate.json")
+    }
+
+    /// The one in progress to `to`, or a fresh one
+    fn load(global_config: &GlobalConfig, host_config: &HostConfig, to: Layout) -> Self {
+        Migration::deserialize_json(&Migration::path(global_config, host_config)).ok()
+            .filter(|migration| migration.to == to)
+            .unwrap_or(Migration { to, done: Vec::new() })
+    }
+
+    fn save(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        let path = Migration::path(global_config, host_config);
+        self.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+    }
+}
+
+impl JsonFile for Migration {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        file.sync_all()
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = File::open(file_path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let migration: Migration = serde_json::from_str(&contents)?;
+        Ok(migration)
+    }
+}
+
+/// What a migration did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrateReport {
+    pub migrated: Vec<String>, // snapshots rewritten by this call
+    pub resumed: usize,        // snapshots an interrupted migration was done with already
+    pub files: u64,            // whose contents moved
+}
+
+/// Moves the contents of the files of every snapshot of `host_config` into
+/// the layout `to`, oldest snapshot first, calling `progress` with each.
+/// The host has to be configured for `to` already, so runs and compaction
+/// keep to it from then on, and must not be backed up meanwhile.
+///
+/// A snapshot is migrated in steps a crash can stop at without losing
+/// anything: contents go into the chunk store before the records refer to
+/// them and the archive is emptied, and back into the archive before the
+/// records stop referring to them. Each step can be done again, so an
+/// interrupted migration starts over at the snapshot it stopped at.
+pub fn migrate<F>(global_config: &GlobalConfig, host_config: &HostConfig, to: Layout, mut progress: F) -> Result<MigrateReport, Trap>
+where
+    F: FnMut(&str)
+{
+    if Layout::of(host_config) != to {
+        return Err(Trap::InvalidInput(match to {
+            Layout::Chunked => format!("Set `dedup: true` for `{}` before migrating it to chunked", host_config.identifier),
+            Layout::Tar => format!("Unset `dedup` for `{}` before migrating it to tar", host_config.identifier),
+        }));
+    }
+
+    let listed = Snapshot::list(global_config, host_config)?;
+    let mut migration = Migration::load(global_config, host_config, to);
+    let mut report = MigrateReport::default();
+    for snapshot in listed.iter() {
+        if migration.done.contains(&snapshot.name) {
+            report.resumed += 1;
+            continue;
+        }
+
+        progress(&snapshot.name);
+        report.files += migrate_snapshot(global_config, host_config, &listed, snapshot, to)?;
+        migration.done.push(snapshot.name.clone());
+        migration.save(global_config, host_config)?;
+        report.migrated.push(snapshot.name.clone());
+    }
+
+    let path = Migration::path(global_config, host_config);
+    if path.exists() {
+        fs::remove_file(&path)
+            .map_err(|err| Trap::FS(format!("Could not remove {:?}: {}", path, err)))?;
+    }
+    Ok(report)
+}
+
+/// Migrates the files of `snapshot`, returns how many moved. The records
+/// of it and the snapshots after it, and the live one, refer to them.
+fn migrate_snapshot(global_config: &GlobalConfig, host_config: &HostConfig, listed: &[SnapshotMeta], snapshot: &SnapshotMeta, to: Layout) -> Result<u64, Trap> {
+    // A tree without an archive is a run that has yet to finish
+    let Some(archive) = &snapshot.archive else { return Ok(0) };
+    let snapshot_path = snapshot.path();
+
+    let mut records: Vec<PathBuf> = listed.iter()
+        .skip_while(|listed| listed.name != snapshot.name)
+        .map(|listed| listed.record_path())
+        .collect();
+    records.push(host_config.root(global_config).join(".records").join("record.json"));
+
+    // Every file of the snapshot some record still refers to, with its
+    // chunks if any record has them, as one interrupted midway may
+    let mut files: HashMap<PathBuf, Option<Vec<String>>> = HashMap::new();
+    for record_path in records.iter() {
+        let record = load(record_path)?;
+        for entry in record.snapshot.entries.values().filter(|entry| entry.snapshot_path == snapshot_path) {
+            let chunks = files.entry(entry.file_path.clone()).or_default();
+            if chunks.is_none() {
+                chunks.clone_from(&entry.chunks);
+            }
+        }
+    }
+
+    let staging = global_config.snapshots.join(MIGRATE_DIR).join(host_config.repo_path()).join(&snapshot.name);
+    let _ = fs::remove_dir_all(&staging);
+    sandbox::unpack(archive, &staging, snapshot.codec.unwrap_or_default())
+        .map_err(|err| Trap::FS(format!("Could not unpack {:?}: {}", archive, err)))?;
+
+    let store = ChunkStore::new(global_config);
+    let mut moved: HashMap<PathBuf, Option<Vec<String>>> = HashMap::new();
+    for (file_path, chunks) in files.iter() {
+        let staged = replace_common_prefix(file_path, &snapshot_path, &staging);
+        if !is_contained(&staging, &staged) || !staged.is_file() {
+            continue;
+        }
+
+        match (to, chunks) {
+            // Stored again after an interruption, it is emptied once more
+            (Layout::Chunked, chunks) => {
+                let chunks = match chunks {
+                    Some(chunks) => chunks.clone(),
+                    None => store.store_file(&staged)?,
+                };
+                empty(&staged)?;
+                moved.insert(file_path.clone(), Some(chunks));
+            },
+            (Layout::Tar, Some(chunks)) => {
+                let mtime = fs::metadata(&staged).and_then(|metadata| metadata.modified()).ok();
+                store.restore_file(chunks, &staged)?;
+                if let Some(mtime) = mtime {
+                    let _ = File::options().write(true).open(&staged).and_then(|file| file.set_modified(mtime));
+                }
+                moved.insert(file_path.clone(), None);
+            },
+            (Layout::Tar, None) => (),
+        }
+    }
+
+    // Whichever order keeps every file readable from the archive or the chunk store
+    if to == Layout::Chunked {
+        refer(&records, &snapshot_path, &moved)?;
+    }
+    rearchive(global_config, host_config, snapshot, &staging, archive)?;
+    if to == Layout::Tar {
+        refer(&records, &snapshot_path, &moved)?;
+    }
+
+    // A tree left unpacked next to the archive would be restored from instead
+    if snapshot_path.is_dir() {
+        let _ = fs::remove_dir_all(&snapshot_path);
+    }
+    let _ = fs::remove_dir_all(&staging);
+    Ok(moved.len() as u64)
+}
+
+fn load(record_path: &Path) -> Result<Record, Trap> {
+    Record::deserialize_json(record_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))
+}
+
+fn empty(path: &Path) -> Result<(), Trap> {
+    let mtime = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    File::options().write(true).open(path)
+        .and_then(|file| {
+            file.set_len(0)?;
+            mtime.map_or(Ok(()), |mtime| file.set_modified(mtime))
+        })
+        .map_err(|err| Trap::FS(format!("Could not empty {:?}: {}", path, err)))
+}
+
+/// Sets the chunks of the entries `moved` of the snapshot at
+/// `snapshot_path` in `records`, rewriting those that changed
+fn refer(records: &[PathBuf], snapshot_path: &Path, moved: &HashMap<PathBuf, Option<Vec<String>>>) -> Result<(), Trap> {
+    for record_path in records {
+        let mut record = load(record_path)?;
+        let mut changed = false;
+        for entry in record.snapshot.entries.values_mut().filter(|entry| entry.snapshot_path == snapshot_path) {
+            if let Some(chunks) = moved.get(&entry.file_path).filter(|chunks| **chunks != entry.chunks) {
+                entry.chunks = chunks.clone();
+                changed = true;
+            }
+        }
+
+        if changed {
+            record.serialize_json(record_path)
+                .map_err(|err| Trap::Serialize(format!("Could not write record {:?}: {}", record_path, err)))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Packs `staging` over the archive of `snapshot`, with the codec it had
+fn rearchive(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &SnapshotMeta, staging: &Path, archive: &Path) -> Result<(), Trap> {
+    let key = archive.strip_prefix(&global_config.backups)
+        .map_err(|_| Trap::FS(format!("Snapshot {:?} is not below {:?}", archive, global_config.backups)))?;
+
+    let host = ArchiveOptions::for_host(global_config, host_config);
+    let options = match snapshot.codec {
+        Some(codec) if codec != host.codec => ArchiveOptions { codec, level: None, ..host },
+        _ => host,
+    };
+
+    let store = LocalStore::new(&global_config.backups);
+    let mut writer = store.put(key)?;
+    write_tar_gz(staging, &mut writer, &options)
+        .and_then(|_| writer.flush())
+        .map_err(|err| Trap::FS(format!("Could not archive {:?}: {}", staging, err)))?;
+    drop(writer);
+
+    store.finalize(key)
+}
+
+#[test]
+fn test_migrate() {
+    use crate::snapshot::FileEntry;
+    use crate::utils::make_tar_gz;
+    use tar::Archive;
+
+    let backups = std::env::temp_dir().join("rensen_test_migrate");
+    let global_config = GlobalConfig { backups: backups.join("backups"), snapshots: backups.join("snapshots"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("db"), source: PathBuf::from("/srv"), dedup: Some(true), ..Default::default() };
+    let root = host_config.root(&global_config);
+    let _ = fs::remove_dir_all(&backups);
+    fs::create_dir_all(root.join(".records")).unwrap();
+
+    // The second snapshot fetched `b`, `a` is still the one of the first
+    let names = ["2024-05-01-00-00-00", "2024-05-02-00-00-00"];
+    let mut record = Record::new();
+    for (run, name) in names.iter().enumerate() {
+        let snapshot_path = root.join(name);
+        let file = ["a", "b"][run];
+        fs::create_dir_all(&snapshot_path).unwrap();
+        fs::write(snapshot_path.join(file), format!("contents of {}", file)).unwrap();
+        record.snapshot.entries.insert(Path::new("/srv").join(file), FileEntry::from(snapshot_path.join(file), snapshot_path.clone(), run as u64, 13));
+        record.serialize_json(&root.join(".records").join(format!("{}.json", name))).unwrap();
+        make_tar_gz(&snapshot_path, format!("{}.tar.gz", snapshot_path.display())).unwrap();
+    }
+    record.serialize_json(&root.join(".records").join("record.json")).unwrap();
+
+    let members = |name: &str| -> Vec<(String, u64)> {
+        let mut archive = Archive::new(crate::codec::decoder(crate::codec::Codec::Gzip, File::open(root.join(format!("{}.tar.gz", name))).unwrap()).unwrap());
+        archive.entries().unwrap()
+            .map(|member| member.unwrap())
+            .filter(|member| member.header().entry_type().is_file())
+            .map(|member| (member.path().unwrap().to_string_lossy().to_string(), member.header().size().unwrap()))
+            .collect()
+    };
+
+    // Picks up after the first snapshot an interrupted migration was done with,
+    // which had moved nothing so far
+    Migration { to: Layout::Chunked, done: vec![names[0].to_string()] }.save(&global_config, &host_config).unwrap();
+    let report = migrate(&global_config, &host_config, Layout::Chunked, |_| ()).unwrap();
+    assert_eq!((report.migrated, report.resumed, report.files), (vec![names[1].to_string()], 1, 1));
+    assert_eq!(members(names[0]), vec![(String::from("a"), 13)]);
+    let report = migrate(&global_config, &host_config, Layout::Chunked, |_| ()).unwrap();
+    assert_eq!((report.migrated.len(), report.files), (2, 2));
+    assert!(!Migration::path(&global_config, &host_config).exists());
+
+    // Every record refers to the chunks, the archives hold the files empty
+    let live = load(&root.join(".records").join("record.json")).unwrap();
+    let first = load(&root.join(".records").join(format!("{}.json", names[0]))).unwrap();
+    let chunks = live.snapshot.entries[Path::new("/srv/a")].chunks.clone().unwrap();
+    assert_eq!(first.snapshot.entries[Path::new("/srv/a")].chunks, Some(chunks.clone()));
+    assert_eq!(members(names[0]), vec![(String::from("a"), 0)]);
+    let mut contents = Vec::new();
+    ChunkStore::new(&global_config).read(&chunks[0], &mut contents).unwrap();
+    assert_eq!(contents, b"contents of a");
+
+    // And back, the contents are in the archives again
+    assert!(migrate(&global_config, &host_config, Layout::Tar, |_| ()).is_err());
+    let tar = HostConfig { dedup: None, ..host_config.clone() };
+    let mut migrated = Vec::new();
+    migrate(&global_config, &tar, Layout::Tar, |name| migrated.push(name.to_string())).unwrap();
+    assert_eq!(migrated, names);
+    assert_eq!(members(names[1]), vec![(String::from("b"), 13)]);
+    assert!(load(&root.join(".records").join("record.json")).unwrap().snapshot.entries.values().all(|entry| entry.chunks.is_none()));
+
+    assert!(matches!(Layout::from("plain"), Err(Trap::InvalidInput(_))));
+    assert_eq!(Layout::from("Chunked").unwrap(), Layout::Chunked);
+}
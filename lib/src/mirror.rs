@@ -0,0 +1,323 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::traits::{JsonFile, Store};
+use crate::store::{LocalStore, Spool};
+use crate::webdav::{WebDavConfig, WebDavStore};
+use crate::s3::{S3Config, S3Store};
+use crate::record::{snapshot_codec, Record};
+use crate::chunks::{ChunkStore, CHUNKS_DIR};
+
+/// Extra destination of a host's snapshots, e.g.
+///
+/// mirrors:
+///   - path: /mnt/second/backups
+///   - name: s3
+///     command: aws s3 cp "$RENSEN_FILE" "s3://bucket/$RENSEN_NAME"
+///     defer: true
+///   - name: nextcloud
+///     webdav: { url: "https://cloud.example.com/remote.php/dav/files/backup/rensen", user: backup }
+///   - name: bucket
+///     s3: { endpoint: "https://s3.eu-central-1.amazonaws.com", region: eu-central-1, bucket: offsite, ... }
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MirrorConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,     // shown in status, default: `path` or `command`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,    // local directory laid out like `backups`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,  // run once per file, for anything else (S3, rclone, ...)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webdav: Option<WebDavConfig>, // WebDAV collection laid out like `backups`, e.g. of a Nextcloud
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3: Option<S3Config>,     // S3 bucket laid out like `backups`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defer: Option<bool>,      // mirror after the run is done instead of during it, default: false
+}
+
+impl MirrorConfig {
+    pub fn label(&self) -> String {
+        match (&self.name, &self.path, &self.command, &self.webdav, &self.s3) {
+            (Some(name), _, _, _, _) => name.clone(),
+            (None, Some(path), _, _, _) => path.display().to_string(),
+            (None, None, Some(command), _, _) => command.clone(),
+            (None, None, None, Some(webdav), _) => webdav.url.clone(),
+            (None, None, None, None, Some(s3)) => format!("s3://{}/{}", s3.bucket, s3.prefix.as_deref().unwrap_or_default()),
+            (None, None, None, None, None) => String::from("unnamed"),
+        }
+    }
+
+    pub fn is_deferred(&self) -> bool {
+        self.defer.unwrap_or(false)
+    }
+
+    /// Copies `files` (relative to `backups`) to this destination
+    fn copy(&self, global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str, files: &[PathBuf]) -> Result<(), Trap> {
+        let store: Option<Box<dyn Store>> = match (&self.path, &self.webdav, &self.s3) {
+            (Some(path), _, _) => Some(Box::new(LocalStore::new(path))),
+            (None, Some(webdav), _) => Some(Box::new(WebDavStore::new(webdav, Spool::new(&global_config.backups))?)),
+            (None, None, Some(s3)) => Some(Box::new(S3Store::new(s3, Spool::new(&global_config.backups))?)),
+            (None, None, None) => None,
+        };
+
+        for name in files {
+            let file = global_config.backups.join(name);
+            match (&store, &self.command) {
+                (Some(store), _) => {
+                    // Finalized once copied, a mirror never holds half a file
+                    let mut writer = store.put(name)?;
+                    File::open(&file)
+                        .and_then(|mut source| io::copy(&mut source, &mut writer))
+                        .and_then(|_| writer.flush())
+                        .map_err(|err| Trap::Mirror(format!("Could not copy {:?} to `{}`: {}", file, self.label(), err)))?;
+                    drop(writer);
+                    store.finalize(name)?;
+                },
+                (None, Some(command)) => {
+                    let status = Command::new("sh")
+                        .arg("-c")
+                        .arg(command)
+                        .env("RENSEN_FILE", &file)
+                        .env("RENSEN_NAME", name)
+                        .env("RENSEN_HOST", &host_config.identifier)
+                        .env("RENSEN_SNAPSHOT", snapshot)
+                        .status()
+                        .map_err(|err| Trap::Mirror(format!("Could not run `{}`: {}", command, err)))?;
+
+                    if !status.success() {
+                        return Err(Trap::Mirror(format!("`{}` exited with {} for {:?}", command, status, name)));
+                    }
+                },
+                (None, None) => return Err(Trap::Config(format!("Mirror `{}` has neither `path`, `webdav`, `s3` nor `command`", self.label()))),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How one destination of a host is doing
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MirrorState {
+    pub pending: Vec<String>,          // snapshots still to be mirrored, oldest first
+    pub last_snapshot: Option<String>, // last one mirrored
+    pub last_success: Option<i64>,     // unix seconds
+    pub last_error: Option<String>,
+}
+
+/// Status of every mirror of a host, by label.
+/// Stored at $backups/$identifier/.records/mirrors.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MirrorStatus {
+    pub destinations: BTreeMap<String, MirrorState>,
+}
+
+impl MirrorStatus {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("mirrors.json")
+    }
+
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Self, Trap> {
+        let path = Self::path(global_config, host_config);
+        MirrorStatus::deserialize_json(&path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))
+    }
+
+    fn save(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        let path = Self::path(global_config, host_config);
+        self.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+    }
+}
+
+impl JsonFile for MirrorStatus {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(MirrorStatus::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let status: MirrorStatus = serde_json::from_str(&contents)?;
+        Ok(status)
+    }
+}
+
+/// The mirrors of `host_config`, then the `offsite` one all hosts have
+pub fn mirrors(global_config: &GlobalConfig, host_config: &HostConfig) -> Vec<MirrorConfig> {
+    let offsite = global_config.offsite.iter().map(|offsite| MirrorConfig {
+        name: offsite.name.clone().or(Some(String::from("offsite"))),
+        ..offsite.clone()
+    });
+    host_config.mirrors.iter().flatten().cloned().chain(offsite).collect()
+}
+
+/// The files making up `snapshot`, relative to `backups`: its archive, the
+/// chunks of files it fetched on `dedup` hosts, which the archive only holds
+/// empty, and its record. The live record goes last, so a mirror only points
+/// at snapshots it has in full.
+fn snapshot_files(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str) -> Vec<PathBuf> {
+    let host = host_config.repo_path();
+    let record = host.join(".records").join(format!("{}.json", snapshot));
+    let codec = snapshot_codec(&global_config.backups.join(&host).join(snapshot));
+    let mut files = vec![codec.archive(&host.join(snapshot))];
+
+    // Those of files fetched before went along with the snapshots that fetched them
+    if let Ok(record) = Record::deserialize_json(&global_config.backups.join(&record)) {
+        let store = ChunkStore { root: PathBuf::from(CHUNKS_DIR) };
+        let chunks: BTreeSet<&String> = record.snapshot.entries.values()
+            .filter(|entry| entry.snapshot_path.file_name().is_some_and(|name| name == snapshot))
+            .flat_map(|entry| entry.chunks.iter().flatten())
+            .collect();
+        files.extend(chunks.into_iter().map(|digest| store.path(digest)));
+    }
+
+    files.push(record);
+    files.push(host.join(".records").join("record.json"));
+    files
+}
+
+/// Mirrors the pending snapshots of every destination of `host_config`, or
+/// only of those `filter` picks. A destination stops at its first failure
+/// and keeps the rest pending for the next attempt.
+fn mirror_pending<F>(global_config: &GlobalConfig, host_config: &HostConfig, status: &mut MirrorStatus, now: i64, filter: F) -> Vec<Trap>
+where
+    F: Fn(&MirrorConfig) -> bool
+{
+    let mut errors = Vec::new();
+    for mirror in mirrors(global_config, host_config).iter().filter(|mirror| filter(mirror)) {
+        let state = status.destinations.entry(mirror.label()).or_default();
+        while let Some(snapshot) = state.pending.first().cloned() {
+            match mirror.copy(global_config, host_config, &snapshot, &snapshot_files(global_config, host_config, &snapshot)) {
+                Ok(()) => {
+                    state.pending.remove(0);
+                    state.last_snapshot = Some(snapshot);
+                    state.last_success = Some(now);
+                    state.last_error = None;
+                },
+                Err(err) => {
+                    state.last_error = Some(err.to_string());
+                    errors.push(Trap::Mirror(format!("Could not mirror {} of `{}` to `{}`: {}", snapshot, host_config.identifier, mirror.label(), err)));
+                    break;
+                },
+            }
+        }
+    }
+
+    errors
+}
+
+/// Queues `snapshot` for every mirror of `host_config` and mirrors it right
+/// away to those not deferred. The primary copy is done by then, so failures
+/// are returned for the run to warn about rather than failing it.
+pub fn mirror_snapshot(global_config: &GlobalConfig, host_config: &HostConfig, snapshot: &str, now: i64) -> Vec<Trap> {
+    let mirrors = mirrors(global_config, host_config);
+    if mirrors.is_empty() {
+        return Vec::new();
+    }
+
+    let mut status = match MirrorStatus::load(global_config, host_config) {
+        Ok(status) => status,
+        Err(err) => return vec![err],
+    };
+
+    for mirror in mirrors.iter() {
+        let state = status.destinations.entry(mirror.label()).or_default();
+        if !state.pending.iter().any(|pending| pending == snapshot) {
+            state.pending.push(snapshot.to_string());
+        }
+    }
+
+    let mut errors = mirror_pending(global_config, host_config, &mut status, now, |mirror| !mirror.is_deferred());
+    if let Err(err) = status.save(global_config, host_config) {
+        errors.push(err);
+    }
+
+    errors
+}
+
+/// Catches every mirror of `host_config` up, deferred ones as well as those
+/// that failed during a run
+pub fn flush_mirrors(global_config: &GlobalConfig, host_config: &HostConfig, now: i64) -> Result<Vec<Trap>, Trap> {
+    let mut status = MirrorStatus::load(global_config, host_config)?;
+    let errors = mirror_pending(global_config, host_config, &mut status, now, |_| true);
+    status.save(global_config, host_config)?;
+    Ok(errors)
+}
+
+#[test]
+fn test_mirror_snapshot() {
+    use std::fs;
+
+    let root = std::env::temp_dir().join("rensen_test_mirror");
+    let _ = fs::remove_dir_all(&root);
+    let global_config = GlobalConfig { backups: root.join("backups"), ..Default::default() };
+    let host_config = HostConfig {
+        identifier: String::from("host"),
+        mirrors: Some(vec![
+            MirrorConfig { path: Some(root.join("second")), ..Default::default() },
+            MirrorConfig { name: Some(String::from("offsite")), path: Some(root.join("offsite")), defer: Some(true), ..Default::default() },
+            MirrorConfig { name: Some(String::from("broken")), command: Some(String::from("exit 1")), ..Default::default() },
+        ]),
+        ..Default::default()
+    };
+
+    let records = global_config.backups.join("host").join(".records");
+    fs::create_dir_all(&records).unwrap();
+    fs::write(global_config.backups.join("host").join("2024-01-01-00-00-00.tar.gz"), "archive").unwrap();
+    fs::write(records.join("2024-01-01-00-00-00.json"), "{}").unwrap();
+    fs::write(records.join("record.json"), "{}").unwrap();
+
+    // Only the failing destination fails, the others carry on
+    let errors = mirror_snapshot(&global_config, &host_config, "2024-01-01-00-00-00", 1);
+    assert_eq!(errors.len(), 1);
+    assert!(root.join("second/host/2024-01-01-00-00-00.tar.gz").is_file());
+    assert!(!root.join("offsite/host").exists());
+
+    let status = MirrorStatus::load(&global_config, &host_config).unwrap();
+    let second = &status.destinations[&root.join("second").display().to_string()];
+    assert_eq!((second.pending.len(), second.last_success), (0, Some(1)));
+    assert_eq!(status.destinations["offsite"].pending, vec![String::from("2024-01-01-00-00-00")]);
+    assert!(status.destinations["broken"].last_error.is_some());
+
+    // Deferred ones are caught up later, failed ones stay pending
+    assert_eq!(flush_mirrors(&global_config, &host_config, 2).unwrap().len(), 1);
+    assert!(root.join("offsite/host/.records/record.json").is_file());
+    let status = MirrorStatus::load(&global_config, &host_config).unwrap();
+    assert_eq!(status.destinations["offsite"].last_snapshot.as_deref(), Some("2024-01-01-00-00-00"));
+    assert_eq!(status.destinations["broken"].pending.len(), 1);
+
+    // The offsite mirror of every host gets the chunks fetched by the snapshot along
+    let global_config = GlobalConfig { offsite: Some(MirrorConfig { path: Some(root.join("bucket")), ..Default::default() }), ..global_config };
+    let host_config = HostConfig { mirrors: None, ..host_config };
+    let mut record = Record::new();
+    let mut entry = crate::snapshot::FileEntry::from(PathBuf::from("a"), global_config.backups.join("host/2024-01-02-00-00-00"), 0, 0);
+    entry.chunks = Some(vec![String::from("abcd")]);
+    record.snapshot.entries.insert(PathBuf::from("/srv/a"), entry);
+    record.serialize_json(&records.join("2024-01-02-00-00-00.json")).unwrap();
+    fs::write(global_config.backups.join("host").join("2024-01-02-00-00-00.tar.gz"), "archive").unwrap();
+    fs::create_dir_all(global_config.backups.join(".chunks/ab")).unwrap();
+    fs::write(global_config.backups.join(".chunks/ab/abcd"), "chunk").unwrap();
+
+    assert_eq!(mirrors(&global_config, &host_config)[0].label(), "offsite");
+    assert!(mirror_snapshot(&global_config, &host_config, "2024-01-02-00-00-00", 3).is_empty());
+    assert!(root.join("bucket/.chunks/ab/abcd").is_file());
+    assert!(root.join("bucket/host/2024-01-02-00-00-00.tar.gz").is_file());
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,73 @@
+use std::process::Command;
+use serde::Serialize;
+use chrono::Utc;
+
+use crate::config::GlobalConfig;
+use crate::logging::{log_trap, log_host_trap, Trap};
+use crate::template::render_file;
+use crate::units::Units;
+
+/// Context given to `templates.alert`
+#[derive(Debug, Serialize)]
+pub struct AlertContext<'a> {
+    pub host: &'a str,
+    pub kind: &'a str,
+    pub message: &'a str,
+    pub time: String,
+}
+
+/// Body of the alert, rendered with `templates.alert` if configured.
+/// Falls back to the plain trap message if the template fails.
+pub fn alert_body(global_config: &GlobalConfig, hostname: &str, trap: &Trap) -> String {
+    let template = match global_config.templates.as_ref().and_then(|templates| templates.alert.as_ref()) {
+        Some(template) => template,
+        None => return trap.to_string(),
+    };
+
+    let (kind, message) = trap.parts();
+    let context = AlertContext {
+        host: hostname,
+        kind,
+        message,
+        time: Units::new(global_config, false).unwrap_or_default().datetime(&Utc::now()),
+    };
+
+    match render_file(template, context) {
+        Ok(body) => body,
+        Err(err) => {
+            log_trap(global_config, &err);
+            trap.to_string()
+        }
+    }
+}
+
+/// Raises an alert for `hostname`.
+///
+/// The trap is always written to the log. If `alert_cmd` is set in the
+/// global config it is run through `sh -c`, with the alert passed in the
+/// environment as `RENSEN_HOST` and `RENSEN_ALERT` (see alert_body).
+pub fn alert(global_config: &GlobalConfig, hostname: &str, trap: &Trap) {
+    log_host_trap(global_config, hostname, trap);
+
+    let cmd = match &global_config.alert_cmd {
+        Some(cmd) => cmd,
+        None => return,
+    };
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("RENSEN_HOST", hostname)
+        .env("RENSEN_ALERT", alert_body(global_config, hostname, trap))
+        .status();
+
+    match status {
+        Ok(status) if !status.success() => {
+            log_trap(global_config, &Trap::STD(format!("alert_cmd exited with {}", status)));
+        },
+        Err(err) => {
+            log_trap(global_config, &Trap::STD(format!("Could not run alert_cmd `{}`: {}", cmd, err)));
+        },
+        _ => (),
+    }
+}
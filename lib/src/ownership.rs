@@ -0,0 +1,123 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::config::GlobalConfig;
+use crate::logging::Trap;
+
+/// Owners of the source to those of the machine restored to, for one that
+/// numbers its users and groups differently. Ids not in it are kept.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OwnerMap {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub uids: HashMap<u32, u32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub gids: HashMap<u32, u32>,
+}
+
+impl OwnerMap {
+    pub fn uid(&self, uid: u32) -> u32 {
+        self.uids.get(&uid).copied().unwrap_or(uid)
+    }
+
+    pub fn gid(&self, gid: u32) -> u32 {
+        self.gids.get(&gid).copied().unwrap_or(gid)
+    }
+}
+
+/// Whether files restored get the owner they were backed up with, which
+/// only root may give them
+pub fn restores_owners() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Gives `path` the owner of the source, as mapped by `map`, without
+/// following a symlink. Nothing unless run as root, or with no owner
+/// recorded, e.g. in records from before owners were kept.
+pub fn restore_owner(path: &Path, uid: Option<u32>, gid: Option<u32>, map: &OwnerMap) -> io::Result<()> {
+    if !restores_owners() || (uid.is_none() && gid.is_none()) {
+        return Ok(());
+    }
+
+    std::os::unix::fs::lchown(path, uid.map(|uid| map.uid(uid)), gid.map(|gid| map.gid(gid)))
+}
+
+/// Sets up the process so the archives, records and logs it writes get the
+/// mode and group of `umask` and `group`, e.g. `0027` and `backup` for
+/// root:backup 0640 files in root:backup 0750 directories. Files are owned
+/// by the user rensen runs as. Must run before anything is written.
+pub fn apply(global_config: &GlobalConfig) -> Result<(), Trap> {
+    if let Some(umask) = &global_config.umask {
+        let mask = parse_umask(umask)
+            .ok_or(Trap::Config(format!("umask `{}` is not an octal mode like 0027", umask)))?;
+        unsafe { libc::umask(mask) };
+    }
+
+    if let Some(group) = &global_config.group {
+        let gid = group_id(group)
+            .ok_or(Trap::Config(format!("Unknown group `{}`", group)))?;
+        // New files take the effective group of the process that creates them
+        if unsafe { libc::setegid(gid) } != 0 {
+            return Err(Trap::Config(format!("Could not write files as group `{}`: {}", group, std::io::Error::last_os_error())));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_umask(umask: &str) -> Option<libc::mode_t> {
+    libc::mode_t::from_str_radix(umask, 8).ok().filter(|mask| *mask <= 0o777)
+}
+
+/// Gid of the group `name`, or `name` itself if it is numeric
+fn group_id(name: &str) -> Option<u32> {
+    if let Ok(gid) = name.parse() {
+        return Some(gid);
+    }
+
+    let c_name = std::ffi::CString::new(name).ok()?;
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return None;
+    }
+
+    Some(unsafe { (*group).gr_gid })
+}
+
+#[test]
+fn test_ownership() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    assert_eq!(parse_umask("0027"), Some(0o027));
+    assert_eq!(parse_umask("027"), Some(0o027));
+    assert!(parse_umask("0999").is_none() && parse_umask("01777").is_none());
+    assert_eq!(group_id("root"), Some(0));
+    assert!(group_id("rensen-no-such-group").is_none());
+
+    // The umask most tests run under anyway, and the group we already have
+    let gid = unsafe { libc::getegid() };
+    let global_config = GlobalConfig { umask: Some(String::from("0022")), group: Some(gid.to_string()), ..Default::default() };
+    apply(&global_config).unwrap();
+
+    let path = std::env::temp_dir().join("rensen_test_ownership");
+    let _ = std::fs::remove_file(&path);
+    std::fs::write(&path, b"").unwrap();
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+    assert_eq!(metadata.gid(), gid);
+    let _ = std::fs::remove_file(&path);
+
+    assert!(apply(&GlobalConfig { umask: Some(String::from("rw-r-----")), ..Default::default() }).is_err());
+
+    // Mapped ids, the others as they are
+    let map: OwnerMap = serde_yaml::from_str("uids:\n  1000: 1001\ngids:\n  100: 998\n").unwrap();
+    assert_eq!((map.uid(1000), map.uid(0), map.gid(100), map.gid(5)), (1001, 0, 998, 5));
+    std::fs::write(&path, b"").unwrap();
+    restore_owner(&path, Some(1000), Some(100), &map).unwrap();
+    if restores_owners() {
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (1001, 998));
+    }
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,50 @@
+use std::time::Instant;
+
+use serde::{Serialize, Deserialize};
+
+/// Where the time of a run went, in milliseconds, so a backup that got
+/// slower shows which stage did. Traversing is the copy stage without the
+/// time spent receiving file contents, which is the transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Phases {
+    pub connect_ms: u64,  // connecting, logging in, the preflight and the helper
+    pub traverse_ms: u64, // listing and stat-ing the source
+    pub transfer_ms: u64, // receiving file contents
+    pub archive_ms: u64,  // archiving and compressing the snapshot
+    pub record_ms: u64,   // merging the journal, finding deletions and writing the records
+}
+
+impl Phases {
+    /// Each phase by name, in the order a run goes through them
+    pub fn named(&self) -> [(&'static str, u64); 5] {
+        [
+            ("connect", self.connect_ms),
+            ("traverse", self.traverse_ms),
+            ("transfer", self.transfer_ms),
+            ("archive", self.archive_ms),
+            ("record", self.record_ms),
+        ]
+    }
+
+    /// The phase that took longest
+    pub fn slowest(&self) -> (&'static str, u64) {
+        self.named().into_iter().rev().max_by_key(|(_, ms)| *ms).unwrap()
+    }
+}
+
+/// Milliseconds since `start`
+pub fn ms_since(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+#[test]
+fn test_phases() {
+    let phases = Phases { connect_ms: 200, traverse_ms: 1500, transfer_ms: 9000, archive_ms: 9000, record_ms: 40 };
+    assert_eq!(phases.named().map(|(name, _)| name), ["connect", "traverse", "transfer", "archive", "record"]);
+
+    // Ties go to the earlier phase
+    assert_eq!(phases.slowest(), ("transfer", 9000));
+
+    let json = serde_json::to_string(&phases).unwrap();
+    assert_eq!(serde_json::from_str::<Phases>(&json).unwrap(), phases);
+}
@@ -0,0 +1,387 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::chunks::ChunkStore;
+use crate::codec::Codec;
+use crate::compact::snapshot_time;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::index::SnapshotIndex;
+use crate::logging::Trap;
+use crate::record::{snapshot_archive, Record};
+use crate::results::MaintenanceReport;
+use crate::traits::JsonFile;
+use crate::verify::snapshots;
+use crate::retention::expired;
+use crate::retire::Retirement;
+use crate::store::STAGING_SUFFIX;
+
+/// One change a maintenance operation makes to the repository
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Rewrites a snapshot record without its per-file detail
+    Compact { record: PathBuf, bytes_before: u64, bytes_after: u64 },
+    /// Removes a file or directory
+    Remove { path: PathBuf, bytes: u64, reason: &'static str },
+}
+
+impl Step {
+    pub fn path(&self) -> &Path {
+        match self {
+            Step::Compact { record, .. } => record,
+            Step::Remove { path, .. } => path,
+        }
+    }
+
+    /// Bytes the step gives back on the destination
+    pub fn freed(&self) -> u64 {
+        match self {
+            Step::Compact { bytes_before, bytes_after, .. } => bytes_before.saturating_sub(*bytes_after),
+            Step::Remove { bytes, .. } => *bytes,
+        }
+    }
+}
+
+/// What a maintenance operation is going to do. Every destructive operation
+/// first builds one of these and then carries out exactly its steps, so what
+/// `--dry-run` prints is what a real run does.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+    pub now: i64, // unix seconds, stamped into compacted records
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn freed(&self) -> u64 {
+        self.steps.iter().map(Step::freed).sum()
+    }
+
+    /// Carries out the steps, in order
+    pub fn execute(&self, global_config: &GlobalConfig) -> Result<MaintenanceReport, Trap> {
+        let mut report = MaintenanceReport::default();
+        if self.is_empty() {
+            return Ok(report);
+        }
+
+        global_config.ensure_writable("modify the repository")?;
+
+        for step in self.steps.iter() {
+            match step {
+                Step::Compact { record: path, .. } => {
+                    let mut record = Record::deserialize_json(path)
+                        .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", path, err)))?;
+                    record.compact(self.now);
+                    record.serialize_json(path)
+                        .map_err(|err| Trap::Serialize(format!("Could not write compacted record {:?}: {}", path, err)))?;
+                    report.compacted += 1;
+                },
+                Step::Remove { path, .. } => {
+                    let removed = match path.is_dir() {
+                        true => fs::remove_dir_all(path),
+                        false => fs::remove_file(path),
+                    };
+                    removed.map_err(|err| Trap::FS(format!("Could not remove {:?}: {}", path, err)))?;
+                    report.removed += 1;
+                },
+            }
+            report.freed += step.freed();
+        }
+
+        Ok(report)
+    }
+}
+
+/// Counts what is written to it
+#[derive(Default)]
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn disk_usage(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    fs::read_dir(path)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| disk_usage(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+/// Compaction of the snapshot records of `host_config` older than
+/// `record_retention` days. The live `record.json` is never touched, as it is
+/// what incremental runs and `latest` compile from, nor are the records of
+/// retired hosts.
+pub fn plan_compaction(global_config: &GlobalConfig, host_config: &HostConfig, now: i64) -> Result<Plan, Trap> {
+    let mut plan = Plan { steps: Vec::new(), now };
+    let days = match global_config.record_retention {
+        // Records of `dedup` hosts are all that refers to their chunks
+        Some(days) if Retirement::load(global_config, host_config)?.is_none() && !host_config.dedup.unwrap_or(false) => days,
+        _ => return Ok(plan),
+    };
+
+    let horizon = now - days as i64 * 24 * 60 * 60;
+    let records_path = host_config.root(global_config)
+        .join(".records");
+
+    for snapshot in snapshots(global_config, host_config) {
+        if !matches!(snapshot_time(&snapshot), Some(taken) if taken < horizon) {
+            continue;
+        }
+
+        let path = records_path.join(format!("{}.json", snapshot));
+        let mut record = Record::deserialize_json(&path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", path, err)))?;
+
+        if record.is_compacted() {
+            continue;
+        }
+
+        // Sized the way Record::serialize_json writes it
+        record.compact(now);
+        let mut counter = Counter::default();
+        serde_json::to_writer_pretty(&mut counter, &record)
+            .map_err(|err| Trap::Serialize(format!("Could not size compacted record {:?}: {}", path, err)))?;
+
+        plan.steps.push(Step::Compact { bytes_before: disk_usage(&path), bytes_after: counter.0, record: path });
+    }
+
+    Ok(plan)
+}
+
+/// Leftovers in the backups of `host_config` nothing refers to anymore:
+/// unpacked snapshot directories next to their finished archive, e.g. after
+/// an interrupted compile, archives an interrupted run never finalized, and
+/// index files of snapshots that are gone.
+pub fn plan_gc(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Plan, Trap> {
+    let mut plan = Plan::default();
+    let host_root_path = host_config.root(global_config);
+
+    let entries = match fs::read_dir(&host_root_path) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(plan),
+    };
+
+    let paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    let mut unpacked: Vec<PathBuf> = paths.iter()
+        .filter(|path| path.is_dir())
+        .filter(|path| path.file_name().and_then(|name| name.to_str()).and_then(snapshot_time).is_some())
+        .filter(|path| snapshot_archive(path).0.is_file())
+        .cloned()
+        .collect();
+
+    unpacked.sort();
+    for path in unpacked {
+        plan.steps.push(Step::Remove { bytes: disk_usage(&path), path, reason: "unpacked, archive exists" });
+    }
+
+    let mut staged: Vec<PathBuf> = paths.into_iter()
+        .filter(|path| path.is_file() && Codec::ALL.iter().any(|codec| path.to_string_lossy().ends_with(&format!(".{}{}", codec.extension(), STAGING_SUFFIX))))
+        .collect();
+    staged.sort();
+    for path in staged {
+        plan.steps.push(Step::Remove { bytes: disk_usage(&path), path, reason: "archive never finalized" });
+    }
+
+    let snapshots = snapshots(global_config, host_config);
+    let index_dir = SnapshotIndex::dir(global_config, host_config);
+    let mut orphans: Vec<PathBuf> = fs::read_dir(&index_dir)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+
+    orphans.retain(|path| {
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        stem != "snapshots" && !snapshots.iter().any(|snapshot| snapshot == stem)
+    });
+    orphans.sort();
+    for path in orphans {
+        plan.steps.push(Step::Remove { bytes: disk_usage(&path), path, reason: "index of removed snapshot" });
+    }
+
+    // Shared by every host with `dedup`, so only gone once no record of any of them refers to it
+    if host_config.dedup.unwrap_or(false) {
+        for (path, bytes) in ChunkStore::new(global_config).unreferenced(global_config) {
+            plan.steps.push(Step::Remove { path, bytes, reason: "chunk no record refers to" });
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Snapshots of `host_config` past its retention (see `expired`), each with
+/// its record and index. Every one the live record still has files in is
+/// kept as well, since incremental runs only fetch changes. Retired hosts
+/// keep all of their snapshots until their final expiry instead.
+pub fn plan_prune(global_config: &GlobalConfig, host_config: &HostConfig, now: i64) -> Result<Plan, Trap> {
+    let mut plan = Plan { steps: Vec::new(), now };
+    let retirement = Retirement::load(global_config, host_config)?;
+    if retirement.is_none() && host_config.retention.is_none() && host_config.keep.is_none() {
+        return Ok(plan);
+    }
+
+    let host_root_path = host_config.root(global_config);
+    let records_path = host_root_path.join(".records");
+
+    let record_path = records_path.join("record.json");
+    let record = Record::deserialize_json(&record_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+    let referenced: Vec<&std::ffi::OsStr> = record.snapshot.entries.values()
+        .filter_map(|entry| entry.snapshot_path.file_name())
+        .collect();
+
+    let expired = match &retirement {
+        Some(retirement) => retirement.expired(&snapshots(global_config, host_config), now),
+        None => expired(host_config, &snapshots(global_config, host_config), now),
+    };
+
+    let index_dir = SnapshotIndex::dir(global_config, host_config);
+    let indexes: Vec<PathBuf> = fs::read_dir(&index_dir)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+
+    // Past the final expiry of a retired host there are no more runs to keep them for
+    let reason = match retirement {
+        Some(_) => "past final expiry",
+        None => "past retention",
+    };
+    for snapshot in expired {
+        if retirement.is_none() && referenced.iter().any(|name| *name == snapshot.as_str()) {
+            continue;
+        }
+
+        // Whatever it was compressed with, the record may be gone already
+        let mut paths: Vec<PathBuf> = Codec::ALL.iter().map(|codec| codec.archive(&host_root_path.join(&snapshot))).collect();
+        paths.push(host_root_path.join(&snapshot));
+        paths.push(records_path.join(format!("{}.json", snapshot)));
+        paths.extend(indexes.iter().filter(|path| path.file_stem().and_then(|stem| stem.to_str()) == Some(snapshot.as_str())).cloned());
+
+        for path in paths.into_iter().filter(|path| path.exists()) {
+            plan.steps.push(Step::Remove { bytes: disk_usage(&path), path, reason });
+        }
+    }
+
+    Ok(plan)
+}
+
+#[test]
+fn test_plan_matches_execution() {
+    use crate::snapshot::FileEntry;
+    use chrono::Local;
+
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_plan"),
+        record_retention: Some(30),
+        ..Default::default()
+    };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let host_root = global_config.backups.join("host");
+    let records = host_root.join(".records");
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(&records).unwrap();
+    fs::create_dir_all(records.join("index")).unwrap();
+
+    let mut record = Record::new();
+    record.snapshot.entries.insert(PathBuf::from("/a"), FileEntry::new());
+    record.serialize_json(&records.join("2020-01-01-00-00-00.json")).unwrap();
+    fs::write(records.join("index").join("2019-01-01-00-00-00.json"), "[]").unwrap();
+    fs::create_dir_all(host_root.join("2020-01-01-00-00-00")).unwrap();
+    fs::write(host_root.join("2020-01-01-00-00-00").join("file"), "data").unwrap();
+    fs::write(host_root.join("2020-01-01-00-00-00.tar.gz"), "").unwrap();
+    fs::write(host_root.join("2020-01-02-00-00-00.tar.zst.part"), "half").unwrap();
+
+    let now = Local::now().timestamp();
+    let compaction = plan_compaction(&global_config, &host_config, now).unwrap();
+    let gc = plan_gc(&global_config, &host_config).unwrap();
+    assert_eq!(compaction.steps.len(), 1);
+    assert_eq!(gc.steps.len(), 3);
+    assert_eq!(gc.freed(), 10);
+
+    // Planning changes nothing
+    assert_eq!(plan_compaction(&global_config, &host_config, now).unwrap(), compaction);
+    assert_eq!(plan_gc(&global_config, &host_config).unwrap(), gc);
+
+    assert_eq!(compaction.execute(&global_config).unwrap().compacted, 1);
+    assert_eq!(gc.execute(&global_config).unwrap(), MaintenanceReport { compacted: 0, removed: 3, freed: 10 });
+    match &compaction.steps[0] {
+        Step::Compact { record, bytes_after, .. } => assert_eq!(disk_usage(record), *bytes_after),
+        step => panic!("unexpected {:?}", step),
+    }
+    assert!(gc.steps.iter().all(|step| !step.path().exists()));
+    assert!(plan_compaction(&global_config, &host_config, now).unwrap().is_empty());
+    assert!(plan_gc(&global_config, &host_config).unwrap().is_empty());
+
+    let read_only = GlobalConfig { read_only: Some(true), ..global_config.clone() };
+    assert!(Plan { steps: gc.steps.clone(), now }.execute(&read_only).is_err());
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
+
+#[test]
+fn test_plan_prune_per_source() {
+    use crate::config::SourceConfig;
+    use crate::retention::KeepPolicy;
+    use crate::snapshot::FileEntry;
+    use chrono::Local;
+
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_prune"), ..Default::default() };
+    let host_config = HostConfig {
+        identifier: String::from("host"),
+        source: PathBuf::from("/etc"),
+        sources: Some(vec![SourceConfig { path: PathBuf::from("/var/lib/data"), retention: Some(7), ..Default::default() }]),
+        ..Default::default()
+    };
+    let _ = fs::remove_dir_all(&global_config.backups);
+
+    let namespaces = host_config.namespaces().unwrap();
+    assert_eq!(namespaces.len(), 2);
+    assert_eq!(namespaces[1].root(&global_config), global_config.backups.join("host").join("data"));
+
+    // Three old snapshots of each source, the live record still has files in the second
+    for namespace in namespaces.iter() {
+        let root = namespace.root(&global_config);
+        fs::create_dir_all(root.join(".records")).unwrap();
+        for snapshot in ["2020-01-01-00-00-00", "2020-01-02-00-00-00", "2020-01-03-00-00-00"] {
+            fs::write(root.join(format!("{}.tar.gz", snapshot)), "archive").unwrap();
+            fs::write(root.join(".records").join(format!("{}.json", snapshot)), "{}").unwrap();
+        }
+
+        let mut record = Record::new();
+        let entry = FileEntry { snapshot_path: root.join("2020-01-02-00-00-00"), ..FileEntry::new() };
+        record.snapshot.entries.insert(namespace.source.join("file"), entry);
+        record.serialize_json(&root.join(".records").join("record.json")).unwrap();
+    }
+
+    // Only the source with a retention is pruned, sparing what is still referenced and the newest
+    let now = Local::now().timestamp();
+    assert!(plan_prune(&global_config, &namespaces[0], now).unwrap().is_empty());
+    let plan = plan_prune(&global_config, &namespaces[1], now).unwrap();
+    assert_eq!(plan.steps.len(), 2);
+    assert!(plan.steps.iter().all(|step| step.path().to_string_lossy().contains("2020-01-01-00-00-00")));
+
+    assert_eq!(plan.execute(&global_config).unwrap().removed, 2);
+    assert_eq!(snapshots(&global_config, &namespaces[1]), vec!["2020-01-02-00-00-00", "2020-01-03-00-00-00"]);
+    assert_eq!(snapshots(&global_config, &namespaces[0]).len(), 3);
+
+    // Without `retention` a `keep` policy alone decides
+    let keep = |policy: KeepPolicy| HostConfig { keep: Some(policy), ..namespaces[0].clone() };
+    assert!(plan_prune(&global_config, &keep(KeepPolicy { last: Some(3), ..Default::default() }), now).unwrap().is_empty());
+    let plan = plan_prune(&global_config, &keep(KeepPolicy { monthly: Some(1), ..Default::default() }), now).unwrap();
+    assert!(plan.steps.len() == 2 && plan.steps.iter().all(|step| step.path().to_string_lossy().contains("2020-01-01-00-00-00")));
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use crate::config::HostConfig;
+use crate::elevate::is_elevated;
+use crate::helper::quote;
+use crate::logging::Trap;
+
+/// What is wrong with a path a backup needs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Issue {
+    Missing,
+    Unreadable,    // by the user
+    Untraversable, // a directory the user can not enter
+    Unelevated,    // one of `sudo` that `sudo -n` does not read
+}
+
+/// A path found wanting before anything was transferred
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub field: &'static str, // of the host's config that names it
+    pub issue: Issue,
+}
+
+/// The paths on the host a backup of `host_config` reads, with the field
+/// naming each. Those read through sudo are only checked with sudo.
+pub fn paths(host_config: &HostConfig) -> Vec<(PathBuf, &'static str)> {
+    let mut paths = Vec::new();
+    if !is_elevated(host_config, &host_config.source) {
+        paths.push((host_config.source.clone(), "source"));
+    }
+    if let Some(key) = &host_config.encrypt_key {
+        paths.push((key.clone(), "encrypt_key"));
+    }
+    for elevated in host_config.sudo.iter().flatten() {
+        if elevated.starts_with(&host_config.source) || host_config.source.starts_with(elevated) {
+            paths.push((elevated.clone(), "sudo"));
+        }
+    }
+    paths
+}
+
+/// A shell command naming each of `paths` that is wanting as `issue path`,
+/// in a single round trip
+pub fn check_command(paths: &[(PathBuf, &'static str)]) -> String {
+    paths.iter()
+        .map(|(path, field)| {
+            let path = quote(path);
+            match *field {
+                "sudo" => format!("sudo -n test -r {0} 2>/dev/null || echo unelevated {0}", path),
+                _ => format!("if [ ! -e {0} ]; then echo missing {0}; elif [ ! -r {0} ]; then echo unreadable {0}; elif [ -d {0} ] && [ ! -x {0} ]; then echo untraversable {0}; fi", path),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// The problems in the output of check_command for `paths`
+pub fn parse_problems(output: &str, paths: &[(PathBuf, &'static str)]) -> Vec<Problem> {
+    output.lines()
+        .filter_map(|line| {
+            let (issue, path) = line.split_once(' ')?;
+            let issue = match issue {
+                "missing" => Issue::Missing,
+                "unreadable" => Issue::Unreadable,
+                "untraversable" => Issue::Untraversable,
+                "unelevated" => Issue::Unelevated,
+                _ => return None,
+            };
+            let (path, field) = paths.iter().find(|(listed, _)| listed.as_os_str() == path)?;
+            Some(Problem { path: path.clone(), field, issue })
+        })
+        .collect()
+}
+
+/// The problem and what to do about it, for the user of `host_config`
+pub fn describe(host_config: &HostConfig, problem: &Problem) -> String {
+    let (user, path) = (&host_config.user, problem.path.display());
+    match problem.issue {
+        Issue::Missing => format!("{} does not exist, check `{}` of `{}`", path, problem.field, host_config.identifier),
+        Issue::Unreadable => format!("{} is not readable by `{}`, e.g. `setfacl -R -m u:{}:rX {}` on the host, or list it under `sudo`", path, user, user, path),
+        Issue::Untraversable => format!("{} can not be entered by `{}`, e.g. `setfacl -m u:{}:rx {}` on the host, or list it under `sudo`", path, user, user, path),
+        Issue::Unelevated => format!("{} is not readable through `sudo -n` by `{}`, it needs a NOPASSWD rule for `find` and `cat` in sudoers", path, user),
+    }
+}
+
+/// All of `problems` in one trap, so they can be fixed in one go
+pub fn report(host_config: &HostConfig, problems: &[Problem]) -> Trap {
+    let lines: Vec<String> = problems.iter().map(|problem| format!("  {}", describe(host_config, problem))).collect();
+    Trap::Missing(format!("{} path(s) of `{}` are out of reach, nothing was transferred:\n{}", problems.len(), host_config.identifier, lines.join("\n")))
+}
+
+#[test]
+fn test_preflight() {
+    let host_config = HostConfig {
+        user: String::from("backup"),
+        identifier: String::from("web"),
+        source: PathBuf::from("/srv"),
+        encrypt_key: Some(PathBuf::from("/etc/rensen/key")),
+        sudo: Some(vec![PathBuf::from("/srv/private"), PathBuf::from("/etc/shadow")]),
+        ..Default::default()
+    };
+
+    // Paths outside of the source are not read through sudo by this backup
+    let paths = paths(&host_config);
+    assert_eq!(paths.iter().map(|(_, field)| *field).collect::<Vec<_>>(), vec!["source", "encrypt_key", "sudo"]);
+    assert!(check_command(&paths).contains("sudo -n test -r '/srv/private' 2>/dev/null || echo unelevated '/srv/private'"));
+
+    let problems = parse_problems("missing /srv\nunelevated /srv/private\nmissing /elsewhere\n", &paths);
+    assert_eq!(problems, vec![
+        Problem { path: PathBuf::from("/srv"), field: "source", issue: Issue::Missing },
+        Problem { path: PathBuf::from("/srv/private"), field: "sudo", issue: Issue::Unelevated },
+    ]);
+
+    let Trap::Missing(message) = report(&host_config, &problems) else { panic!() };
+    assert!(message.starts_with("2 path(s) of `web` are out of reach"));
+    assert!(message.contains("  /srv does not exist, check `source` of `web`\n"));
+}
@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+/// How far a backup got, as handed to a ProgressSink
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    pub files_done: u64,          // looked at, fetched or skipped as unchanged
+    pub files_total: Option<u64>, // from the listing, or the record of the run before, none on a first run
+    pub bytes_done: u64,          // fetched
+    pub bytes_total: Option<u64>, // known for listed sources only
+    pub current: PathBuf,         // the file being fetched, on the host
+}
+
+impl Progress {
+    /// Share of the files done, none without a total. Sources that grew
+    /// since the record stay at 100% for the rest of the run.
+    pub fn ratio(&self) -> Option<f64> {
+        self.files_total
+            .filter(|total| *total > 0)
+            .map(|total| (self.files_done as f64 / total as f64).min(1.0))
+    }
+}
+
+/// Told about a backup as it goes, see `progress` of Sftp: once per file
+/// and every PROGRESS_BYTES of a file being fetched. Called on the thread
+/// running the backup, which waits for it.
+pub trait ProgressSink {
+    fn progress(&self, progress: &Progress);
+}
+
+impl<F: Fn(&Progress)> ProgressSink for F {
+    fn progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Bytes of a file fetched between calls to a ProgressSink
+pub const PROGRESS_BYTES: u64 = 1024 * 1024;
+
+/// A bar of `width` characters for `progress`, e.g.
+/// `[#########-----------] 45%  120/266 files  1.2 GiB  /srv/app/data.db`,
+/// with `bytes` formatting the byte counts
+pub fn render_bar(progress: &Progress, width: usize, bytes: impl Fn(u64) -> String) -> String {
+    let files = match progress.files_total {
+        Some(total) => format!("{}/{} files", progress.files_done, total),
+        None => format!("{} files", progress.files_done),
+    };
+    let fetched = match progress.bytes_total {
+        Some(total) => format!("{}/{}", bytes(progress.bytes_done), bytes(total)),
+        None => bytes(progress.bytes_done),
+    };
+
+    match progress.ratio() {
+        Some(ratio) => {
+            let filled = (ratio * width as f64).round() as usize;
+            format!("[{}{}] {:>3}%  {}  {}  {}", "#".repeat(filled), "-".repeat(width - filled), (ratio * 100.0) as u64, files, fetched, progress.current.display())
+        },
+        None => format!("{}  {}  {}", files, fetched, progress.current.display()),
+    }
+}
+
+#[test]
+fn test_progress() {
+    use std::cell::RefCell;
+
+    let progress = Progress { files_done: 5, files_total: Some(20), bytes_done: 2048, bytes_total: None, current: PathBuf::from("/srv/a") };
+    assert_eq!(progress.ratio(), Some(0.25));
+    assert_eq!(render_bar(&progress, 8, |bytes| format!("{}B", bytes)), "[##------]  25%  5/20 files  2048B  /srv/a");
+    assert_eq!(render_bar(&Progress { files_total: None, ..progress.clone() }, 8, |bytes| bytes.to_string()), "5 files  2048  /srv/a");
+    assert_eq!(Progress { files_done: 30, ..progress.clone() }.ratio(), Some(1.0));
+
+    // Closures are sinks
+    let seen = RefCell::new(Vec::new());
+    let sink = |progress: &Progress| seen.borrow_mut().push(progress.files_done);
+    sink.progress(&progress);
+    assert_eq!(*seen.borrow(), vec![5]);
+}
@@ -0,0 +1,192 @@
+use crate::history::RunOutcome;
+
+/// Expected run time in seconds of hosts that have never been backed up
+pub const DEFAULT_EXPECTED_DURATION: u64 = 600;
+
+/// How many of the latest runs of a host its expected run time is taken from
+const RECENT_RUNS: usize = 10;
+
+/// Expected run time of a backup of `hostname` in seconds, the mean of its
+/// latest runs in `runs`
+pub fn expected_duration(runs: &[RunOutcome], hostname: &str) -> u64 {
+    let recent: Vec<u64> = runs.iter().rev()
+        .filter(|run| run.hostname == hostname)
+        .take(RECENT_RUNS)
+        .map(|run| run.duration().max(1) as u64)
+        .collect();
+
+    match recent.is_empty() {
+        true => DEFAULT_EXPECTED_DURATION,
+        false => recent.iter().sum::<u64>() / recent.len() as u64,
+    }
+}
+
+/// A queued task along with what is needed to schedule it fairly
+#[derive(Debug)]
+struct Queued<T> {
+    key: String,     // hostname
+    cost: u64,       // expected run time in seconds
+    critical: bool,
+    deficit: u64,    // run time handed to other hosts while waiting
+    task: T,
+}
+
+impl<T> Queued<T> {
+    /// Negative once others got more than this one's run time
+    fn remaining(&self) -> i64 {
+        self.cost as i64 - self.deficit as i64
+    }
+}
+
+/// Deficit based queue for backups competing for a limited number of slots.
+/// The next task is the one whose expected run time is most covered by the
+/// run time given to others while it waited, so cheap hosts go first, but a
+/// huge host waiting long enough is never passed over again, however many
+/// cheap hosts keep arriving. Critical hosts go first, and a host is only
+/// queued once.
+#[derive(Debug)]
+pub struct FairQueue<T> {
+    queue: Vec<Queued<T>>,
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> FairQueue<T> {
+    pub fn new() -> Self {
+        FairQueue { queue: Vec::new() }
+    }
+
+    /// Queues `task`, false if a task for `key` is waiting already
+    pub fn push(&mut self, key: &str, cost: u64, critical: bool, task: T) -> bool {
+        if self.contains(key) {
+            return false;
+        }
+
+        self.queue.push(Queued { key: key.to_string(), cost, critical, deficit: 0, task });
+        true
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.queue.iter().any(|queued| queued.key == key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Takes the task of `key` out of the queue, e.g. once it was cancelled
+    pub fn remove(&mut self, key: &str) -> Option<T> {
+        let index = self.queue.iter().position(|queued| queued.key == key)?;
+        Some(self.queue.remove(index).task)
+    }
+
+    /// Takes the next task, the longest waiting one on ties
+    pub fn pop(&mut self) -> Option<(String, T)> {
+        let critical = self.queue.iter().any(|queued| queued.critical);
+        let (index, _) = self.queue.iter()
+            .enumerate()
+            .filter(|(_, queued)| queued.critical == critical)
+            .min_by_key(|(index, queued)| (queued.remaining(), *index))?;
+
+        let chosen = self.queue.remove(index);
+        for queued in self.queue.iter_mut() {
+            queued.deficit += chosen.cost;
+        }
+
+        Some((chosen.key, chosen.task))
+    }
+}
+
+#[test]
+fn test_fair_queue_order() {
+    let mut queue = FairQueue::new();
+    assert!(queue.push("huge", 3600, false, ()));
+    assert!(queue.push("small", 60, false, ()));
+    assert!(queue.push("critical", 7200, true, ()));
+    assert!(!queue.push("small", 60, false, ()));
+
+    assert_eq!(queue.pop().unwrap().0, "critical");
+    assert_eq!(queue.pop().unwrap().0, "small");
+    assert_eq!(queue.pop().unwrap().0, "huge");
+    assert!(queue.pop().is_none());
+}
+
+#[test]
+fn test_fair_queue_remove() {
+    let mut queue = FairQueue::new();
+    queue.push("huge", 3600, false, 1);
+    queue.push("small", 60, false, 2);
+
+    // Gone for good, the rest keep their turn
+    assert_eq!(queue.remove("small"), Some(2));
+    assert!(queue.remove("small").is_none());
+    assert!(!queue.contains("small"));
+    assert_eq!(queue.pop(), Some((String::from("huge"), 1)));
+    assert!(queue.remove("huge").is_none());
+}
+
+/// Runs `fleet` of (hostname, seconds) with `slots` concurrent backups until
+/// `until`, hosts in `requeue` being queued again as soon as they finish.
+/// Returns when each host was first started.
+#[cfg(test)]
+fn simulate(fleet: &[(&str, u64)], slots: usize, requeue: &[&str], until: u64) -> std::collections::HashMap<String, u64> {
+    use std::collections::HashMap;
+
+    let mut queue = FairQueue::new();
+    let mut started: HashMap<String, u64> = HashMap::new();
+    let mut running: Vec<(u64, String)> = Vec::new(); // (finishes, hostname)
+    let cost: HashMap<&str, u64> = fleet.iter().cloned().collect();
+
+    for (hostname, seconds) in fleet {
+        queue.push(hostname, *seconds, false, ());
+    }
+
+    let mut now = 0;
+    while now < until {
+        while running.len() < slots {
+            let Some((hostname, _)) = queue.pop() else { break };
+            started.entry(hostname.clone()).or_insert(now);
+            running.push((now + cost[hostname.as_str()], hostname));
+        }
+
+        running.sort();
+        if running.is_empty() {
+            break;
+        }
+
+        let (finished, hostname) = running.remove(0);
+        now = finished;
+        if requeue.contains(&hostname.as_str()) {
+            queue.push(&hostname, cost[hostname.as_str()], false, ());
+        }
+    }
+
+    started
+}
+
+#[test]
+fn test_fair_queue_mixed_fleet() {
+    // Four hosts taking three hours each and twenty taking five minutes, two at a time
+    let mut fleet: Vec<(String, u64)> = (0..4).map(|i| (format!("huge{}", i), 3 * 3600)).collect();
+    fleet.extend((0..20).map(|i| (format!("small{}", i), 300)));
+    let fleet: Vec<(&str, u64)> = fleet.iter().map(|(hostname, seconds)| (hostname.as_str(), *seconds)).collect();
+
+    // All due at midnight: the small ones are done within the hour instead
+    // of waiting behind the huge ones, and the huge ones still all run
+    let started = simulate(&fleet, 2, &[], 24 * 3600);
+    assert_eq!(started.len(), fleet.len(), "every host got to run");
+    let small = started.iter().filter(|(hostname, _)| hostname.starts_with("small")).map(|(_, at)| *at).max().unwrap();
+    assert!(small < 3600, "last small host started after {}s", small);
+
+    // A huge host is not starved by small ones that are due again all the time
+    let started = simulate(&fleet[3..9], 1, &["small0", "small1", "small2", "small3", "small4"], 24 * 3600);
+    assert!(started["huge3"] <= 4 * 3600, "huge host started after {}s", started["huge3"]);
+}
@@ -0,0 +1,224 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use ssh2::{Channel, Session};
+
+use crate::logging::Trap;
+use crate::traits::Quiesce;
+
+/// Default time a freeze or thaw may take
+pub const DEFAULT_QUIESCE_TIMEOUT: u64 = 30;
+
+/// Quiesce entry in a host config, e.g.
+///
+/// quiesce:
+///   - path: /var/lib/mysql
+///     method: mysql
+///   - path: /srv/data
+///     method: fsfreeze
+///     timeout: 10
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QuiesceConfig {
+    pub path: PathBuf,                 // remote path the method applies to
+    pub method: String,                // fsfreeze, mysql or command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freeze: Option<String>,        // method `command` only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thaw: Option<String>,          // method `command` only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,          // seconds, default: 30
+}
+
+impl QuiesceConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_QUIESCE_TIMEOUT))
+    }
+
+    /// Whether this entry concerns a backup of `source`
+    pub fn applies_to(&self, source: &Path) -> bool {
+        self.path.starts_with(source) || source.starts_with(&self.path)
+    }
+
+    /// The built-in (or command) plugin selected by `method`
+    pub fn plugin(&self) -> Result<Box<dyn Quiesce>, Trap> {
+        match self.method.to_lowercase().as_str() {
+            "fsfreeze" => Ok(Box::new(Fsfreeze)),
+            "mysql" | "mariadb" => Ok(Box::new(MysqlLock::default())),
+            "command" => match (&self.freeze, &self.thaw) {
+                (Some(freeze), Some(thaw)) => Ok(Box::new(Command { freeze: freeze.clone(), thaw: thaw.clone() })),
+                _ => Err(Trap::Config(format!("Quiesce method `command` for {:?} needs both `freeze` and `thaw`", self.path))),
+            },
+            method => Err(Trap::Config(format!("Unknown quiesce method `{}` for {:?}", method, self.path))),
+        }
+    }
+}
+
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
+
+/// Runs `command` to completion, failing on a non-zero exit status
+fn run(sess: &Session, command: &str) -> Result<(), Trap> {
+    let mut channel = sess.channel_session()
+        .map_err(|err| Trap::Quiesce(format!("Could not open channel: {}", err)))?;
+
+    channel.exec(command)
+        .map_err(|err| Trap::Quiesce(format!("Could not execute `{}`: {}", command, err)))?;
+
+    let mut output = String::new();
+    let _ = channel.stderr().read_to_string(&mut output);
+    let _ = channel.wait_close();
+
+    match channel.exit_status() {
+        Ok(0) => Ok(()),
+        Ok(status) => Err(Trap::Quiesce(format!("`{}` exited with status {}: {}", command, status, output.trim()))),
+        Err(err) => Err(Trap::Quiesce(format!("`{}` did not finish: {}", command, err))),
+    }
+}
+
+/// Blocks writes to the filesystem mounted at the path with fsfreeze(8)
+pub struct Fsfreeze;
+
+impl Quiesce for Fsfreeze {
+    fn freeze(&mut self, sess: &Session, path: &Path) -> Result<(), Trap> {
+        run(sess, &format!("fsfreeze --freeze {}", quote(path)))
+    }
+
+    fn thaw(&mut self, sess: &Session, path: &Path) -> Result<(), Trap> {
+        run(sess, &format!("fsfreeze --unfreeze {}", quote(path)))
+    }
+}
+
+/// Flushes and read-locks all tables of a MySQL/MariaDB server. The lock only
+/// lives as long as the client session, so the `mysql` client is kept open on
+/// its own channel until thawed.
+#[derive(Default)]
+pub struct MysqlLock {
+    channel: Option<Channel>,
+}
+
+impl Quiesce for MysqlLock {
+    fn freeze(&mut self, sess: &Session, _path: &Path) -> Result<(), Trap> {
+        let mut channel = sess.channel_session()
+            .map_err(|err| Trap::Quiesce(format!("Could not open channel: {}", err)))?;
+
+        channel.exec("mysql --batch --skip-column-names")
+            .map_err(|err| Trap::Quiesce(format!("Could not start mysql: {}", err)))?;
+
+        channel.write_all(b"FLUSH TABLES WITH READ LOCK;\nSELECT 'rensen-locked';\n")
+            .map_err(|err| Trap::Quiesce(format!("Could not write to mysql: {}", err)))?;
+
+        // Waiting for the marker, which is only printed once the lock is held
+        let mut output = String::new();
+        let mut buffer = [0; 256];
+        while !output.contains("rensen-locked") {
+            match channel.read(&mut buffer) {
+                Ok(0) => {
+                    let mut error = String::new();
+                    let _ = channel.stderr().read_to_string(&mut error);
+                    return Err(Trap::Quiesce(format!("mysql exited before the lock was taken: {}", error.trim())));
+                },
+                Ok(n) => output.push_str(&String::from_utf8_lossy(&buffer[..n])),
+                Err(err) => return Err(Trap::Quiesce(format!("Could not read from mysql: {}", err))),
+            }
+        }
+
+        self.channel = Some(channel);
+        Ok(())
+    }
+
+    fn thaw(&mut self, _sess: &Session, _path: &Path) -> Result<(), Trap> {
+        let mut channel = match self.channel.take() {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+
+        // Closing the session releases the lock even if UNLOCK does not make it
+        let unlocked = channel.write_all(b"UNLOCK TABLES;\n");
+        let _ = channel.send_eof();
+        let _ = channel.wait_close();
+
+        unlocked.map_err(|err| Trap::Quiesce(format!("Could not unlock tables: {}", err)))
+    }
+}
+
+/// User supplied freeze and thaw commands, run through the remote shell
+pub struct Command {
+    pub freeze: String,
+    pub thaw: String,
+}
+
+impl Quiesce for Command {
+    fn freeze(&mut self, sess: &Session, _path: &Path) -> Result<(), Trap> {
+        run(sess, &self.freeze)
+    }
+
+    fn thaw(&mut self, sess: &Session, _path: &Path) -> Result<(), Trap> {
+        run(sess, &self.thaw)
+    }
+}
+
+/// Entries that have been frozen, thawed in reverse order
+pub struct Frozen {
+    entries: Vec<(Box<dyn Quiesce>, QuiesceConfig)>,
+}
+
+/// Freezes every entry in `configs` that applies to `source`, in order.
+/// If one fails, those already frozen are thawed again before returning.
+pub fn freeze_all(sess: &Session, configs: &[QuiesceConfig], source: &Path) -> Result<Frozen, Trap> {
+    let mut frozen = Frozen { entries: Vec::new() };
+
+    for config in configs.iter().filter(|config| config.applies_to(source)) {
+        let mut plugin = config.plugin()?;
+
+        sess.set_timeout(config.timeout().as_millis() as u32);
+        let result = plugin.freeze(sess, &config.path);
+        sess.set_timeout(0);
+
+        if let Err(err) = result {
+            let _ = frozen.thaw(sess);
+            return Err(err);
+        }
+
+        frozen.entries.push((plugin, config.clone()));
+    }
+
+    Ok(frozen)
+}
+
+impl Frozen {
+    /// Thaws everything, carrying on past failures. Returns the first failure.
+    pub fn thaw(&mut self, sess: &Session) -> Result<(), Trap> {
+        let mut first_err = None;
+
+        while let Some((mut plugin, config)) = self.entries.pop() {
+            sess.set_timeout(config.timeout().as_millis() as u32);
+            let result = plugin.thaw(sess, &config.path);
+            sess.set_timeout(0);
+
+            if let Err(err) = result {
+                first_err.get_or_insert(err);
+            }
+        }
+
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_quiesce_config() {
+    let config = QuiesceConfig { path: PathBuf::from("/var/lib/mysql"), method: String::from("mysql"), ..Default::default() };
+    assert!(config.applies_to(Path::new("/var/lib")));
+    assert!(config.applies_to(Path::new("/var/lib/mysql/data")));
+    assert!(!config.applies_to(Path::new("/srv")));
+    assert!(config.plugin().is_ok());
+
+    let command = QuiesceConfig { method: String::from("command"), freeze: Some(String::from("true")), ..Default::default() };
+    assert!(matches!(command.plugin(), Err(Trap::Config(_))));
+    assert_eq!(quote(Path::new("/it's")), "'/it'\\''s'");
+}
@@ -0,0 +1,212 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::{GlobalConfig, Host};
+use crate::logging::{log_trap, Trap};
+
+/// Default percentage of free space at the destination before
+/// non-critical hosts are deferred.
+pub const DEFAULT_SPACE_WATERMARK: u8 = 10;
+
+/// Default percentage of free inodes at the destination before backups
+/// warn about them
+pub const DEFAULT_INODE_WATERMARK: u8 = 10;
+
+/// Space usage of the filesystem a path lives on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    pub total: u64,
+    pub free: u64,
+}
+
+impl DiskUsage {
+    pub fn free_percent(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+
+        self.free as f64 / self.total as f64 * 100.0
+    }
+}
+
+/// Inode usage of the filesystem a path lives on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct InodeUsage {
+    pub total: u64,
+    pub free: u64,
+}
+
+impl InodeUsage {
+    pub fn free_percent(&self) -> f64 {
+        // Some filesystems (btrfs) have no fixed number of inodes
+        if self.total == 0 {
+            return 100.0;
+        }
+
+        self.free as f64 / self.total as f64 * 100.0
+    }
+}
+
+/// Wrapper for statvfs(3), giving space and inode usage.
+/// If `path` does not exist yet, the closest existing ancestor is used instead.
+fn statvfs(path: &Path) -> Result<(DiskUsage, InodeUsage), Trap> {
+    let existing = path.ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("/"));
+
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|err| Trap::FS(format!("Invalid path {:?}: {}", existing, err)))?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(Trap::FS(format!("Could not stat filesystem at {:?}: {}", existing, io::Error::last_os_error())));
+    }
+
+    let disk = DiskUsage {
+        total: stat.f_blocks as u64 * stat.f_frsize as u64,
+        free: stat.f_bavail as u64 * stat.f_frsize as u64,
+    };
+    let inodes = InodeUsage {
+        total: stat.f_files as u64,
+        free: stat.f_favail as u64,
+    };
+
+    Ok((disk, inodes))
+}
+
+pub fn disk_usage(path: &Path) -> Result<DiskUsage, Trap> {
+    statvfs(path).map(|(disk, _)| disk)
+}
+
+pub fn inode_usage(path: &Path) -> Result<InodeUsage, Trap> {
+    statvfs(path).map(|(_, inodes)| inodes)
+}
+
+/// Number of inodes `path` and everything below it take up
+pub fn count_inodes(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+
+    if !metadata.is_dir() {
+        return 1;
+    }
+
+    1 + fs::read_dir(path)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| count_inodes(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+/// Whether the destination has less free inodes than `inode_watermark`.
+/// `held` is what the host takes up there, `unpacked` its snapshot
+/// directories left next to their archive, which hold one inode per file.
+pub fn inodes_nearly_exhausted(global_config: &GlobalConfig, hostname: &str, usage: &InodeUsage, held: u64, unpacked: usize) -> Option<Trap> {
+    let watermark = global_config.inode_watermark.unwrap_or(DEFAULT_INODE_WATERMARK) as f64;
+    if usage.free_percent() >= watermark {
+        return None;
+    }
+
+    let hint = match unpacked {
+        0 => String::new(),
+        unpacked => format!(", {} unpacked snapshot directories could be removed with `rensen gc {}`", unpacked, hostname),
+    };
+
+    Some(Trap::Quota(format!(
+        "Only {:.1}% of inodes free at {:?} (watermark {}%), `{}` holds {} of them{}",
+        usage.free_percent(), global_config.backups, watermark, hostname, held, hint
+    )))
+}
+
+/// Parses the output of `df -Pk <path>`, which reports 1024-byte blocks:
+///
+/// Filesystem 1024-blocks Used Available Capacity Mounted on
+/// /dev/sda1     41152736 9432 31720304       23% /
+pub fn parse_df(output: &str) -> Option<DiskUsage> {
+    let fields: Vec<&str> = output.lines().nth(1)?.split_whitespace().collect();
+    let total = fields.get(1)?.parse::<u64>().ok()?;
+    let free = fields.get(3)?.parse::<u64>().ok()?;
+
+    Some(DiskUsage { total: total * 1024, free: free * 1024 })
+}
+
+/// Whether the source of a host has less free space than `source_watermark`
+pub fn source_nearly_full(global_config: &GlobalConfig, usage: &DiskUsage) -> bool {
+    let watermark = global_config.source_watermark.unwrap_or(DEFAULT_SPACE_WATERMARK) as f64;
+    usage.free_percent() < watermark
+}
+
+/// Checks the free space at the backup destination against `space_watermark`.
+/// Returns Trap::Quota if `host` should be deferred, which is only the case for
+/// non-critical hosts once the free space has dropped below the watermark.
+///
+/// Failing to read the usage is logged but never blocks a backup.
+pub fn check_quota(global_config: &GlobalConfig, host: &Host) -> Result<(), Trap> {
+    let usage = match disk_usage(&global_config.backups) {
+        Ok(usage) => usage,
+        Err(err) => {
+            log_trap(global_config, &err);
+            return Ok(());
+        }
+    };
+
+    let watermark = global_config.space_watermark.unwrap_or(DEFAULT_SPACE_WATERMARK) as f64;
+    if usage.free_percent() >= watermark || host.config.is_critical() {
+        return Ok(());
+    }
+
+    Err(Trap::Quota(format!(
+        "Only {:.1}% free at {:?} (watermark {}%): deferring non-critical host `{}`",
+        usage.free_percent(), global_config.backups, watermark, host.hostname
+    )))
+}
+
+#[test]
+fn test_check_quota() {
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_quota"),
+        log: std::env::temp_dir().join("rensen_test_quota.log"),
+        space_watermark: Some(100),
+        ..Default::default()
+    };
+
+    let mut host = Host { hostname: String::from("test"), config: Default::default() };
+    assert!(matches!(check_quota(&global_config, &host), Err(Trap::Quota(_))));
+
+    host.config.critical = Some(true);
+    assert!(check_quota(&global_config, &host).is_ok());
+}
+
+#[test]
+fn test_parse_df() {
+    let output = "Filesystem     1024-blocks    Used Available Capacity Mounted on\n/dev/sda1          1000     900       100      90% /\n";
+    let usage = parse_df(output).unwrap();
+    assert_eq!(usage, DiskUsage { total: 1024 * 1000, free: 1024 * 100 });
+    assert!(source_nearly_full(&GlobalConfig { source_watermark: Some(20), ..Default::default() }, &usage));
+
+    assert_eq!(parse_df("df: /missing: No such file or directory"), None);
+}
+
+#[test]
+fn test_inodes_nearly_exhausted() {
+    let root = std::env::temp_dir().join("rensen_test_inodes");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("2024-01-01-00-00-00")).unwrap();
+    fs::write(root.join("2024-01-01-00-00-00").join("file"), "data").unwrap();
+    fs::write(root.join("2024-01-01-00-00-00.tar.gz"), "").unwrap();
+    assert_eq!(count_inodes(&root), 4);
+
+    let global_config = GlobalConfig { inode_watermark: Some(20), ..Default::default() };
+    let usage = InodeUsage { total: 1000, free: 100 };
+    let trap = inodes_nearly_exhausted(&global_config, "test", &usage, 4, 1).unwrap();
+    assert!(trap.to_string().contains("rensen gc test"));
+
+    assert!(inodes_nearly_exhausted(&global_config, "test", &InodeUsage { total: 1000, free: 500 }, 4, 0).is_none());
+    assert!(inodes_nearly_exhausted(&global_config, "test", &InodeUsage { total: 0, free: 0 }, 4, 0).is_none());
+    let _ = fs::remove_dir_all(&root);
+}
@@ -1,19 +1,46 @@
 use serde::{Serialize, Deserialize}; 
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::io::BufReader;
 use std::io::prelude::*;
 use crate::traits::JsonFile;
+use crate::utils::write_atomic;
 use std::fmt::{Display, Formatter, Result};
 use crate::snapshot::*;
+use crate::drift::ConfigFingerprint;
+use crate::codec::Codec;
+use crate::histogram::Histogram;
 
 
 /* listened to "Plastic Love" while coding this. */
 
 /// A record storing the data for precompressed files.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Record {
     pub size: u64,
     pub snapshot: Snapshot,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<RecordSummary>, // set once the per-file detail has been compacted away
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ConfigFingerprint>, // host config the snapshot was taken with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<Codec>, // the snapshot's archive is compressed with, none for gzip in records from before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Histogram>, // of the files the snapshot fetched, none in records from before
+}
+
+/// What is left of a snapshot record after compaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordSummary {
+    pub files: u64,
+    pub deleted: u64,
+    pub compacted: i64, // unix seconds
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Record {
@@ -21,10 +48,60 @@ impl Record {
         Record {
             size: 0,
             snapshot: Snapshot::new(),
+            summary: None,
+            config: None,
+            codec: None,
+            histogram: None,
+        }
+    }
+
+    pub fn is_compacted(&self) -> bool {
+        self.summary.is_some()
+    }
+
+    /// Drops the per-file entries, keeping their size and counts
+    pub fn compact(&mut self, now: i64) {
+        if self.is_compacted() {
+            return;
         }
+
+        self.summary = Some(RecordSummary {
+            files: self.snapshot.entries.len() as u64,
+            deleted: self.snapshot.deleted_entries.len() as u64,
+            compacted: now,
+        });
+        self.snapshot = Snapshot::new();
     }
 }
 
+/// The codec the archive of the snapshot at `snapshot_path` is compressed
+/// with, as its record says. Gzip for records from before, or none at all.
+pub fn snapshot_codec(snapshot_path: &Path) -> Codec {
+    // Only the codec is read of what may be a large record
+    #[derive(Deserialize)]
+    struct Recorded {
+        #[serde(default)]
+        codec: Option<Codec>,
+    }
+
+    let record_path = match (snapshot_path.parent(), snapshot_path.file_name()) {
+        (Some(host_root_path), Some(name)) => host_root_path.join(".records").join(format!("{}.json", name.to_string_lossy())),
+        _ => return Codec::default(),
+    };
+
+    File::open(record_path).ok()
+        .and_then(|file| serde_json::from_reader::<_, Recorded>(BufReader::new(file)).ok())
+        .and_then(|recorded| recorded.codec)
+        .unwrap_or_default()
+}
+
+/// The archive of the snapshot at `snapshot_path`, along with the codec
+/// it is compressed with
+pub fn snapshot_archive(snapshot_path: &Path) -> (PathBuf, Codec) {
+    let codec = snapshot_codec(snapshot_path);
+    (codec.archive(snapshot_path), codec)
+}
+
 impl Display for Record {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         write!(f, "\tsnapshot: {}\n\t", self.snapshot)
@@ -33,11 +110,11 @@ impl Display for Record {
 
 impl JsonFile for Record {
 
+    /// Streamed to disk, records with millions of entries would otherwise
+    /// be held in memory twice while written. Replaces the record at once,
+    /// a crash while writing leaves the one before.
     fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
-        let mut file = File::create(file_path)?;
-        let json_str = serde_json::to_string_pretty(&self)?;
-        write!(file, "{}", json_str)?;
-        Ok(())
+        write_atomic(file_path, |writer| Ok(serde_json::to_writer_pretty(writer, &self)?))
     }
 
     fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
@@ -66,11 +143,27 @@ fn test_serialize_record() {
     entries.insert("/home/bam/backups/file1".into(), PathBufx::from(PathBuf::from("/home/cbroo/files/file4"), 123897));
     */
 
-    let mut record = Record::new();
-    record.serialize_json(Path::new("record.json")).unwrap();
+    let record = Record::new();
+    record.serialize_json(&std::env::temp_dir().join("record.json")).unwrap();
+}
+
+#[test]
+fn test_snapshot_codec() {
+    let root = std::env::temp_dir().join("rensen_test_snapshot_codec");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join(".records")).unwrap();
+
+    let record = Record { codec: Some(Codec::Xz), ..Record::new() };
+    record.serialize_json(&root.join(".records").join("2024-01-01-00-00-00.json")).unwrap();
+    Record::new().serialize_json(&root.join(".records").join("2024-01-02-00-00-00.json")).unwrap();
+
+    assert_eq!(snapshot_archive(&root.join("2024-01-01-00-00-00")), (root.join("2024-01-01-00-00-00.tar.xz"), Codec::Xz));
+    assert_eq!(snapshot_codec(&root.join("2024-01-02-00-00-00")), Codec::Gzip);
+    assert_eq!(snapshot_codec(&root.join("2024-01-03-00-00-00")), Codec::Gzip);
+    let _ = std::fs::remove_dir_all(&root);
 }
 
 #[test]
 fn test_deserialize_record() {
-    let record: Record = Record::deserialize_json(Path::new("tests/record.json")).unwrap();
+    let _record: Record = Record::deserialize_json(Path::new("tests/record.json")).unwrap();
 }
@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+/// The OS the host's OpenSSH runs on, which decides how its paths are
+/// written. OpenSSH for Windows names `C:\Users\backup` `/C:/Users/backup`
+/// over SFTP, that form is what the snapshots and records of the host hold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteOs {
+    #[default]
+    Unix,
+    Windows,
+}
+
+impl RemoteOs {
+    /// `path` as written in the config in the form the host's SFTP server
+    /// names it. Unix paths are left as they are.
+    pub fn normalize(&self, path: &Path) -> PathBuf {
+        if *self == RemoteOs::Unix {
+            return path.to_path_buf();
+        }
+
+        let path = path.to_string_lossy().replace('\\', "/");
+        let path = path.strip_prefix('/').filter(|rest| drive(rest).is_some()).unwrap_or(&path);
+        let (drive, rest) = match drive(path) {
+            Some(letter) => (format!("/{}:", letter.to_ascii_uppercase()), &path[2..]),
+            None => (String::new(), path),
+        };
+
+        let components: Vec<&str> = rest.split('/').filter(|component| !component.is_empty() && *component != ".").collect();
+        match !drive.is_empty() || rest.starts_with('/') {
+            true => PathBuf::from(format!("{}/{}", drive, components.join("/"))),
+            false => PathBuf::from(components.join("/")),
+        }
+    }
+
+    /// `path` as the host's own tools write it, for messages
+    pub fn native(&self, path: &Path) -> String {
+        let path = path.to_string_lossy();
+        match self {
+            RemoteOs::Unix => path.to_string(),
+            RemoteOs::Windows => path.strip_prefix('/')
+                .filter(|rest| drive(rest).is_some())
+                .unwrap_or(&path)
+                .replace('/', "\\"),
+        }
+    }
+}
+
+/// The letter of `path` if it starts with a drive, as in `C:`
+fn drive(path: &str) -> Option<char> {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(letter), Some(':'), None | Some('/')) if letter.is_ascii_alphabetic() => Some(letter),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_remote_os() {
+    let windows = RemoteOs::Windows;
+    assert_eq!(windows.normalize(Path::new("C:\\Users\\backup\\")), PathBuf::from("/C:/Users/backup"));
+    assert_eq!(windows.normalize(Path::new("d:/Data\\.\\Shares")), PathBuf::from("/D:/Data/Shares"));
+    assert_eq!(windows.normalize(Path::new("/C:/Users/backup")), PathBuf::from("/C:/Users/backup"));
+    assert_eq!(windows.normalize(Path::new("E:")), PathBuf::from("/E:/"));
+    assert_eq!(windows.normalize(Path::new("\\Users")), PathBuf::from("/Users"));
+    assert_eq!(windows.normalize(Path::new("Documents\\Reports")), PathBuf::from("Documents/Reports"));
+
+    // The source, its subdirectories and file names come out as a Unix server gives them
+    let source = windows.normalize(Path::new("C:\\Users\\backup"));
+    assert_eq!(source.file_stem().unwrap(), "backup");
+    assert_eq!(source.join("Documents").join("a.txt").strip_prefix(&source).unwrap(), Path::new("Documents/a.txt"));
+    assert_eq!(windows.native(&source.join("a.txt")), "C:\\Users\\backup\\a.txt");
+
+    assert_eq!(RemoteOs::Unix.normalize(Path::new("/srv/a\\b")), PathBuf::from("/srv/a\\b"));
+    assert_eq!(RemoteOs::Unix.native(Path::new("/srv")), "/srv");
+}
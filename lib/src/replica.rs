@@ -0,0 +1,205 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::codec::Codec;
+use crate::logging::Trap;
+use crate::record::snapshot_codec;
+use crate::traits::JsonFile;
+use crate::utils::digest_file;
+use crate::verify::snapshots;
+
+/// Hash of a snapshot's archive. Records are left out, compaction rewrites them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sealed {
+    pub archive: String,
+    pub time: i64, // unix seconds, when first hashed
+}
+
+/// The hashes each snapshot of a host had when it was first cross-checked.
+/// Archives are never rewritten after a backup, so one that stops matching
+/// its seal has been tampered with or corrupted on the primary itself.
+/// Stored at $backups/$identifier/.records/sealed.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Seals {
+    pub snapshots: BTreeMap<String, Sealed>,
+}
+
+impl Seals {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("sealed.json")
+    }
+}
+
+impl JsonFile for Seals {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(Seals::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let seals: Seals = serde_json::from_str(&contents)?;
+        Ok(seals)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DivergenceKind {
+    Missing,  // on the replica
+    Differs,  // from the primary
+    Extra,    // only on the replica
+    Tampered, // primary archive no longer matches its seal
+}
+
+/// A snapshot that is not the same on the primary and the replica
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub snapshot: String,
+    pub file: &'static str, // `archive` or `record`
+    pub kind: DivergenceKind,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.kind {
+            DivergenceKind::Missing  => "missing on the replica",
+            DivergenceKind::Differs  => "differs on the replica",
+            DivergenceKind::Extra    => "only on the replica",
+            DivergenceKind::Tampered => "changed on the primary since it was sealed",
+        };
+        write!(f, "{} {} {}", self.snapshot, self.file, what)
+    }
+}
+
+fn archive_path(host_root_path: &Path, snapshot: &str, codec: Codec) -> PathBuf {
+    codec.archive(&host_root_path.join(snapshot))
+}
+
+fn record_path(host_root_path: &Path, snapshot: &str) -> PathBuf {
+    host_root_path.join(".records").join(format!("{}.json", snapshot))
+}
+
+/// Compares the archives and records of every snapshot of `host_config` with
+/// the copy under `replica` (a mounted replica of `backups`), and checks the
+/// primary archives against their seals. Snapshots seen for the first time
+/// are sealed, unless this is a read-only instance.
+///
+/// Records rewritten by compaction differ until the replica has caught up,
+/// so this is best run right after replication.
+pub fn cross_check(global_config: &GlobalConfig, host_config: &HostConfig, replica: &Path, now: i64) -> Result<Vec<Divergence>, Trap> {
+    let primary_root = host_config.root(global_config);
+    let replica_config = GlobalConfig { backups: replica.to_path_buf(), ..global_config.clone() };
+    let replica_root = host_config.root(&replica_config);
+
+    let seals_path = Seals::path(global_config, host_config);
+    let mut seals = Seals::deserialize_json(&seals_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", seals_path, err)))?;
+
+    let primary = snapshots(global_config, host_config);
+    let mut divergences = Vec::new();
+
+    for snapshot in primary.iter() {
+        // Nothing to compare without the archive, `verify` reports those
+        // The replica has it by the name it has on the primary
+        let codec = snapshot_codec(&primary_root.join(snapshot));
+        if !archive_path(&primary_root, snapshot, codec).is_file() {
+            continue;
+        }
+
+        let archive = digest_file(&archive_path(&primary_root, snapshot, codec))?;
+        let record = digest_file(&record_path(&primary_root, snapshot))?;
+
+        match seals.snapshots.get(snapshot) {
+            Some(sealed) if sealed.archive != archive => {
+                divergences.push(Divergence { snapshot: snapshot.clone(), file: "archive", kind: DivergenceKind::Tampered });
+            },
+            Some(_) => (),
+            None => { seals.snapshots.insert(snapshot.clone(), Sealed { archive: archive.clone(), time: now }); },
+        }
+
+        for (file, path, primary_hash) in [
+            ("archive", archive_path(&replica_root, snapshot, codec), &archive),
+            ("record", record_path(&replica_root, snapshot), &record),
+        ] {
+            let kind = match path.is_file() {
+                false => DivergenceKind::Missing,
+                true if digest_file(&path)? != *primary_hash => DivergenceKind::Differs,
+                true => continue,
+            };
+            divergences.push(Divergence { snapshot: snapshot.clone(), file, kind });
+        }
+    }
+
+    for snapshot in snapshots(&replica_config, host_config) {
+        if !primary.contains(&snapshot) {
+            divergences.push(Divergence { snapshot, file: "record", kind: DivergenceKind::Extra });
+        }
+    }
+
+    seals.snapshots.retain(|snapshot, _| primary.contains(snapshot));
+    if !global_config.is_read_only() {
+        seals.serialize_json(&seals_path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", seals_path, err)))?;
+    }
+
+    Ok(divergences)
+}
+
+#[test]
+fn test_cross_check() {
+    use std::fs;
+
+    let root = std::env::temp_dir().join("rensen_test_replica");
+    let global_config = GlobalConfig { backups: root.join("primary"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let replica = root.join("replica");
+    let _ = fs::remove_dir_all(&root);
+
+    for backups in [&global_config.backups, &replica] {
+        let host_root = backups.join("host");
+        fs::create_dir_all(host_root.join(".records")).unwrap();
+        for snapshot in ["2024-01-01-00-00-00", "2024-01-02-00-00-00"] {
+            fs::write(archive_path(&host_root, snapshot, Codec::Gzip), snapshot).unwrap();
+            fs::write(record_path(&host_root, snapshot), "{}").unwrap();
+        }
+    }
+
+    assert!(cross_check(&global_config, &host_config, &replica, 1).unwrap().is_empty());
+    assert_eq!(Seals::deserialize_json(&Seals::path(&global_config, &host_config)).unwrap().snapshots.len(), 2);
+
+    // Silent corruption on the replica, a snapshot it never got, and one it kept
+    let replica_root = replica.join("host");
+    fs::write(archive_path(&replica_root, "2024-01-01-00-00-00", Codec::Gzip), "bitrot").unwrap();
+    fs::remove_file(record_path(&replica_root, "2024-01-02-00-00-00")).unwrap();
+    fs::write(record_path(&replica_root, "2023-12-31-00-00-00"), "{}").unwrap();
+
+    let divergences = cross_check(&global_config, &host_config, &replica, 2).unwrap();
+    assert_eq!(divergences, vec![
+        Divergence { snapshot: "2024-01-01-00-00-00".into(), file: "archive", kind: DivergenceKind::Differs },
+        Divergence { snapshot: "2024-01-02-00-00-00".into(), file: "record", kind: DivergenceKind::Missing },
+        Divergence { snapshot: "2023-12-31-00-00-00".into(), file: "record", kind: DivergenceKind::Extra },
+    ]);
+
+    // An archive rewritten on the primary no longer matches its seal, even
+    // after it has been replicated as well
+    fs::write(archive_path(&global_config.backups.join("host"), "2024-01-02-00-00-00", Codec::Gzip), "tampered").unwrap();
+    fs::write(archive_path(&replica_root, "2024-01-02-00-00-00", Codec::Gzip), "tampered").unwrap();
+    let divergences = cross_check(&global_config, &host_config, &replica, 3).unwrap();
+    assert!(divergences.contains(&Divergence { snapshot: "2024-01-02-00-00-00".into(), file: "archive", kind: DivergenceKind::Tampered }));
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,78 @@
+use serde::Serialize;
+
+use crate::config::{GlobalConfig, Settings};
+use crate::credentials::{stale_keys, StaleKey};
+use crate::history::{History, RunOutcome};
+use crate::logging::Trap;
+use crate::sla::SlaLedger;
+use crate::template::render_file;
+use crate::units::Units;
+use crate::traits::JsonFile;
+
+/// State of a single host, as shown in reports
+#[derive(Debug, Serialize)]
+pub struct HostReport {
+    pub hostname: String,
+    pub identifier: String,
+    pub critical: bool,
+    pub sla: Option<String>,
+    pub sla_hits: usize,
+    pub sla_misses: usize,
+    pub sla_last_hit: Option<bool>,
+    pub last_run: Option<RunOutcome>,
+    pub stale_keys: Vec<StaleKey>, // past `key_rotation`
+}
+
+/// Fleet report, also the context given to `templates.report`
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub generated: String,
+    pub hosts: Vec<HostReport>,
+}
+
+impl Report {
+    pub fn collect(global_config: &GlobalConfig, settings: &Settings) -> Result<Self, Trap> {
+        let units = Units::new(global_config, false)?;
+        let runs = History::new(global_config).load()?;
+        let now = chrono::Local::now().timestamp();
+        let mut hosts = Vec::new();
+
+        for host in settings.hosts.iter() {
+            if host.hostname == "dummy" {
+                continue;
+            }
+
+            let ledger = match &host.config.sla {
+                Some(_) => SlaLedger::deserialize_json(&SlaLedger::path(global_config, &host.config))
+                    .map_err(|err| Trap::Deserialize(format!("Could not deserialize SLA ledger: {}", err)))?,
+                None => SlaLedger::default(),
+            };
+
+            hosts.push(HostReport {
+                hostname: host.hostname.clone(),
+                identifier: host.config.identifier.clone(),
+                critical: host.config.is_critical(),
+                sla: host.config.sla.clone(),
+                sla_hits: ledger.hits(),
+                sla_misses: ledger.misses(),
+                sla_last_hit: ledger.entries.last().map(|entry| entry.hit),
+                last_run: runs.iter().rev().find(|run| run.hostname == host.hostname).cloned(),
+                stale_keys: stale_keys(global_config, &host.config, now),
+            });
+        }
+
+        Ok(Self {
+            generated: units.datetime(&chrono::Utc::now()),
+            hosts,
+        })
+    }
+
+    /// Renders the report with `templates.report`.
+    /// Returns None if no template is configured.
+    pub fn render(&self, global_config: &GlobalConfig) -> Result<Option<String>, Trap> {
+        match global_config.templates.as_ref().and_then(|templates| templates.report.as_ref()) {
+            Some(path) => Ok(Some(render_file(path, self)?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::GlobalConfig;
+use crate::logging::Trap;
+use crate::traits::JsonFile;
+
+/// The format of the repository this build writes
+pub const FORMAT: u32 = 1;
+
+/// The oldest format of the repository this build still reads
+pub const OLDEST_FORMAT: u32 = 1;
+
+/// The format the repository is in, so a build never writes one that
+/// another reading it, e.g. a standby rensend, does not understand. It only
+/// moves on with `rensen format --upgrade`. Repositories from before the
+/// marker are in format 1.
+/// Stored at $backups/.format.json
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RepositoryFormat {
+    pub format: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_by: Option<String>, // version of the rensen that upgraded it
+}
+
+impl Default for RepositoryFormat {
+    fn default() -> Self {
+        RepositoryFormat { format: 1, written_by: None }
+    }
+}
+
+impl RepositoryFormat {
+    pub fn path(global_config: &GlobalConfig) -> PathBuf {
+        global_config.backups.join(".format.json")
+    }
+
+    pub fn load(global_config: &GlobalConfig) -> Result<Self, Trap> {
+        let path = Self::path(global_config);
+        RepositoryFormat::deserialize_json(&path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))
+    }
+
+    fn written_by(&self) -> String {
+        self.written_by.as_ref()
+            .map(|version| format!(", written by rensen {}", version))
+            .unwrap_or_default()
+    }
+
+    /// Errors with Trap::Incompatible if this build can not read the
+    /// repository, checked on startup
+    pub fn check(global_config: &GlobalConfig) -> Result<Self, Trap> {
+        let marker = RepositoryFormat::load(global_config)?;
+        if marker.format > FORMAT {
+            return Err(Trap::Incompatible(format!("The repository at {:?} is in format {}{}, this rensen reads formats {} to {}: upgrade rensen", global_config.backups, marker.format, marker.written_by(), OLDEST_FORMAT, FORMAT)));
+        }
+        if marker.format < OLDEST_FORMAT {
+            return Err(Trap::Incompatible(format!("The repository at {:?} is in format {}, this rensen reads formats {} to {}: upgrade it with an older rensen first", global_config.backups, marker.format, OLDEST_FORMAT, FORMAT)));
+        }
+
+        Ok(marker)
+    }
+
+    /// Errors with Trap::Incompatible if the repository is in another
+    /// format than the one this build writes, before it writes anything
+    pub fn ensure_writable(global_config: &GlobalConfig, action: &str) -> Result<(), Trap> {
+        let marker = RepositoryFormat::check(global_config)?;
+        if marker.format != FORMAT {
+            return Err(Trap::Incompatible(format!("Refusing to {}: the repository at {:?} is in format {}, this rensen writes format {}. Run `rensen format --upgrade` once every rensen reading it is as new as this one", action, global_config.backups, marker.format, FORMAT)));
+        }
+
+        Ok(())
+    }
+
+    /// Moves the repository on to the format this build writes, returning
+    /// the one it was in
+    pub fn upgrade(global_config: &GlobalConfig) -> Result<u32, Trap> {
+        if global_config.is_read_only() {
+            return Err(Trap::ReadOnly(String::from("Refusing to upgrade the repository: rensen is configured as read-only")));
+        }
+        let marker = RepositoryFormat::check(global_config)?;
+        let upgraded = RepositoryFormat { format: FORMAT, written_by: Some(env!("CARGO_PKG_VERSION").to_string()) };
+
+        let path = Self::path(global_config);
+        fs::create_dir_all(&global_config.backups)
+            .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", global_config.backups, err)))?;
+        upgraded.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))?;
+        Ok(marker.format)
+    }
+}
+
+impl JsonFile for RepositoryFormat {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(RepositoryFormat::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let marker: RepositoryFormat = serde_json::from_str(&contents)?;
+        Ok(marker)
+    }
+}
+
+#[test]
+fn test_repository_format() {
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_repository"), ..Default::default() };
+    let _ = fs::remove_dir_all(&global_config.backups);
+
+    // Without a marker it is in format 1
+    assert_eq!(RepositoryFormat::check(&global_config).unwrap().format, 1);
+    assert!(global_config.ensure_writable("back up `web`").is_ok());
+    assert_eq!(RepositoryFormat::upgrade(&global_config).unwrap(), 1);
+    assert_eq!(RepositoryFormat::load(&global_config).unwrap().written_by.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+
+    // Written by a newer rensen, neither read nor written
+    let newer = RepositoryFormat { format: FORMAT + 1, written_by: Some(String::from("9.0.0")) };
+    newer.serialize_json(&RepositoryFormat::path(&global_config)).unwrap();
+    let Err(Trap::Incompatible(message)) = global_config.ensure_writable("back up `web`") else { panic!() };
+    assert!(message.contains("written by rensen 9.0.0"));
+    assert!(matches!(RepositoryFormat::upgrade(&global_config), Err(Trap::Incompatible(_))));
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
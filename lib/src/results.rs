@@ -0,0 +1,125 @@
+use std::path::PathBuf;
+
+use serde::{Serialize, Deserialize};
+
+use crate::checksum::ChecksumMismatch;
+use crate::exit::ExitCode;
+use crate::logging::Trap;
+use crate::quota::DiskUsage;
+
+/// What a finished backup did. Things that went wrong without failing the
+/// run end up in `skipped` and `warnings`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackupReport {
+    pub snapshot: String,          // e.g. `2024-05-15-08-10-30`
+    pub incremental: bool,
+    pub files: u64,                // transferred during the run
+    pub bytes: u64,                // transferred during the run
+    pub deleted: u64,              // files found gone from the source
+    pub size: u64,                 // total size of the host's record after the run
+    pub skipped: Vec<PathBuf>,     // remote files and directories that could not be read
+    pub warnings: Vec<String>,
+    pub source: Option<DiskUsage>, // filesystem usage at the host's source before the run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notices: Vec<String>,      // worth knowing, but not wrong, e.g. config edits since the last snapshot
+}
+
+impl BackupReport {
+    /// `Warnings` if anything was skipped or went wrong along the way
+    pub fn exit_code(&self) -> ExitCode {
+        match self.skipped.is_empty() && self.warnings.is_empty() {
+            true => ExitCode::Success,
+            false => ExitCode::Warnings,
+        }
+    }
+}
+
+impl From<&Result<BackupReport, Trap>> for ExitCode {
+    fn from(result: &Result<BackupReport, Trap>) -> Self {
+        match result {
+            Ok(report) => report.exit_code(),
+            Err(trap) => ExitCode::from(trap),
+        }
+    }
+}
+
+/// What compiling a snapshot produced
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CompileReport {
+    pub destination: PathBuf,
+    pub files: u64,
+    pub bytes: u64,
+    pub archives: u64,             // snapshot archives unpacked for it
+    pub skipped: Vec<PathBuf>,     // files of the record that could not be copied out
+    pub mismatches: Vec<ChecksumMismatch>, // files that do not match the host's `checksums` manifests
+}
+
+impl CompileReport {
+    pub fn exit_code(&self) -> ExitCode {
+        match self.skipped.is_empty() && self.mismatches.is_empty() {
+            true => ExitCode::Success,
+            false => ExitCode::Warnings,
+        }
+    }
+}
+
+/// What a restore uploaded, and with a `Verify` step how the files on the
+/// host compare with the snapshot
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RestoreReport {
+    pub uploaded: u64,             // files
+    pub verified: Option<u64>,     // files compared, None without a `Verify` step
+    pub mismatched: Vec<PathBuf>,  // paths on the host that differ from the snapshot
+    pub missing: Vec<PathBuf>,     // paths on the host that were not there to compare
+}
+
+impl RestoreReport {
+    /// `Failure` if the verification found anything off, restored files that
+    /// cannot be trusted are not a warning
+    pub fn exit_code(&self) -> ExitCode {
+        match self.mismatched.is_empty() && self.missing.is_empty() {
+            true => ExitCode::Success,
+            false => ExitCode::Failure,
+        }
+    }
+}
+
+/// What carrying out a maintenance plan did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    pub compacted: u64,
+    pub removed: u64,
+    pub freed: u64,                // bytes
+}
+
+/// What exporting or importing a seed copied
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SeedReport {
+    pub snapshot: String,          // the snapshot the seed becomes
+    pub files: u64,                // copied this time
+    pub bytes: u64,                // copied this time
+    pub resumed: u64,              // files already copied by an earlier, interrupted run
+    pub skipped: Vec<PathBuf>,     // source files that could not be read
+}
+
+impl SeedReport {
+    pub fn exit_code(&self) -> ExitCode {
+        match self.skipped.is_empty() {
+            true => ExitCode::Success,
+            false => ExitCode::Warnings,
+        }
+    }
+}
+
+#[test]
+fn test_report_exit_codes() {
+    let mut report = BackupReport::default();
+    assert_eq!(ExitCode::from(&Ok(report.clone())), ExitCode::Success);
+
+    report.skipped.push(PathBuf::from("/etc/shadow"));
+    assert_eq!(ExitCode::from(&Ok(report)), ExitCode::Warnings);
+    assert_eq!(ExitCode::from(&Err::<BackupReport, Trap>(Trap::Auth(String::new()))), ExitCode::Connection);
+
+    let compile = CompileReport { skipped: vec![PathBuf::from("/a")], ..Default::default() };
+    assert_eq!(compile.exit_code(), ExitCode::Warnings);
+}
@@ -0,0 +1,192 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::traits::JsonFile;
+use crate::utils::hash_file;
+
+/// A transfer is checkpointed every this many bytes, smaller files are
+/// fetched again whole
+pub const RESUME_CHECKPOINT: u64 = 8 * 1024 * 1024;
+
+/// hash_file reads this many bytes, the block hashed ends at the offset
+const BLOCK: u64 = 1024;
+
+/// The file a run was fetching when it was cut off, up to the last
+/// checkpoint. Stored at $backups/$identifier/.records/partial.json, with the
+/// bytes received kept next to it until the next run picks them up.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Partial {
+    pub source: PathBuf,      // on the host
+    pub destination: PathBuf, // in the snapshot it was fetched into
+    pub mtime: u64,           // of the source, when the transfer started
+    pub size: u64,            // of the source, when the transfer started
+    pub offset: u64,          // bytes received and checked
+    pub hash: String,         // hash_file of the block before `offset`
+}
+
+impl Partial {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("partial.json")
+    }
+
+    /// Where the bytes of the partial file wait between runs
+    pub fn data_path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("partial")
+    }
+
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Option<Self>, Trap> {
+        let path = Self::path(global_config, host_config);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        Partial::deserialize_json(&path)
+            .map(Some)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))
+    }
+
+    /// Notes that the first `offset` bytes of `source` are in `destination`
+    pub fn checkpoint(global_config: &GlobalConfig, host_config: &HostConfig, source: &Path, destination: &Path, mtime: u64, size: u64, offset: u64) -> Result<Self, Trap> {
+        let partial = Partial {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            mtime,
+            size,
+            offset,
+            hash: hash_file(destination, offset.saturating_sub(BLOCK))?,
+        };
+
+        let path = Self::path(global_config, host_config);
+        partial.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))?;
+        Ok(partial)
+    }
+
+    /// Forgets the partial file of `host_config`, if any
+    pub fn clear(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        for path in [Self::path(global_config, host_config), Self::data_path(global_config, host_config)] {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Trap::FS(format!("Could not remove {:?}: {}", path, err)));
+                },
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `file` still holds what was checkpointed: at least `offset`
+    /// bytes, ending in the block that was hashed
+    fn intact(&self, file: &Path) -> bool {
+        fs::metadata(file).map(|metadata| metadata.len() >= self.offset).unwrap_or(false)
+            && hash_file(file, self.offset.saturating_sub(BLOCK)).map(|hash| hash == self.hash).unwrap_or(false)
+    }
+
+    /// Moves the bytes of an interrupted transfer of `host_config` out of
+    /// the snapshot they were fetched into, before that is archived. None if
+    /// there is nothing intact to resume from.
+    pub fn recover(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Option<Self>, Trap> {
+        let partial = match Partial::load(global_config, host_config)? {
+            Some(partial) => partial,
+            None => return Ok(None),
+        };
+
+        // Picked up by a run that died before it got to the file
+        let data_path = Self::data_path(global_config, host_config);
+        if partial.intact(&data_path) {
+            return Ok(Some(partial));
+        }
+
+        if !partial.intact(&partial.destination) {
+            Partial::clear(global_config, host_config)?;
+            return Ok(None);
+        }
+
+        // Whatever came after the checkpoint is not known to be good
+        OpenOptions::new().write(true).open(&partial.destination)
+            .and_then(|file| file.set_len(partial.offset))
+            .and_then(|_| fs::rename(&partial.destination, &data_path))
+            .map_err(|err| Trap::FS(format!("Could not recover {:?}: {}", partial.destination, err)))?;
+        Ok(Some(partial))
+    }
+
+    /// Whether the source is still what was being fetched
+    pub fn unchanged(&self, mtime: u64, size: u64) -> bool {
+        self.mtime == mtime && self.size == size && self.offset <= size
+    }
+
+    /// Moves the bytes received so far to `destination`, opened at their end
+    /// to write the rest
+    pub fn take(&self, global_config: &GlobalConfig, host_config: &HostConfig, destination: &Path) -> Result<File, Trap> {
+        let data_path = Self::data_path(global_config, host_config);
+        fs::rename(&data_path, destination)
+            .and_then(|_| OpenOptions::new().write(true).open(destination))
+            .and_then(|mut file| file.seek(SeekFrom::End(0)).map(|_| file))
+            .map_err(|err| Trap::FS(format!("Could not resume into {:?}: {}", destination, err)))
+    }
+}
+
+impl JsonFile for Partial {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = File::open(file_path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let partial: Partial = serde_json::from_str(&contents)?;
+        Ok(partial)
+    }
+}
+
+#[test]
+fn test_partial() {
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_resume"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let snapshot = global_config.backups.join("host").join("2024-05-01-14-05-00");
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(global_config.backups.join("host").join(".records")).unwrap();
+    fs::create_dir_all(&snapshot).unwrap();
+
+    // Cut off past the checkpoint, the tail after it is dropped
+    let contents: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    let destination = snapshot.join("video.mkv");
+    fs::write(&destination, &contents[..3000]).unwrap();
+    let source = Path::new("/srv/video.mkv");
+    Partial::checkpoint(&global_config, &host_config, source, &destination, 7, 5000, 2048).unwrap();
+    fs::write(&destination, &contents[..2500]).unwrap();
+
+    let partial = Partial::recover(&global_config, &host_config).unwrap().unwrap();
+    assert!(!destination.exists());
+    assert_eq!(fs::metadata(Partial::data_path(&global_config, &host_config)).unwrap().len(), 2048);
+    assert!(partial.unchanged(7, 5000));
+    assert!(!partial.unchanged(8, 5000));
+
+    // Recovered again by a run that died before resuming
+    assert_eq!(Partial::recover(&global_config, &host_config).unwrap(), Some(partial.clone()));
+
+    let resumed = snapshot.join("resumed.mkv");
+    let mut file = partial.take(&global_config, &host_config, &resumed).unwrap();
+    file.write_all(&contents[2048..]).unwrap();
+    assert_eq!(fs::read(&resumed).unwrap(), contents);
+
+    // Bytes that no longer match are not resumed from
+    Partial::checkpoint(&global_config, &host_config, source, &resumed, 7, 5000, 4096).unwrap();
+    fs::write(&resumed, vec![0; 5000]).unwrap();
+    assert_eq!(Partial::recover(&global_config, &host_config).unwrap(), None);
+    assert!(!Partial::path(&global_config, &host_config).exists());
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
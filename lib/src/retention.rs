@@ -0,0 +1,109 @@
+use chrono::{DateTime, Local};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeSet;
+
+use crate::compact::snapshot_time;
+use crate::config::HostConfig;
+
+/// Which snapshots of a host are kept, by count rather than age, e.g.
+///
+/// keep:
+///   last: 7       # the 7 newest
+///   daily: 14     # the newest of each of the last 14 days with a snapshot
+///   weekly: 8
+///   monthly: 12
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeepPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weekly: Option<u32>,  // ISO weeks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly: Option<u32>,
+}
+
+impl KeepPolicy {
+    /// The snapshots among `snapshots` (names, oldest first) the policy
+    /// keeps. Each rule keeps on its own, a snapshot is kept if any does.
+    pub fn keeps(&self, snapshots: &[String]) -> BTreeSet<String> {
+        let taken: Vec<(&String, DateTime<Local>)> = snapshots.iter().rev()
+            .filter_map(|name| snapshot_time(name)
+                .and_then(|time| DateTime::from_timestamp(time, 0))
+                .map(|time| (name, time.with_timezone(&Local))))
+            .collect();
+
+        let mut kept: BTreeSet<String> = taken.iter()
+            .take(self.last.unwrap_or(0) as usize)
+            .map(|(name, _)| name.to_string())
+            .collect();
+
+        for (count, period) in [(self.daily, "%Y-%m-%d"), (self.weekly, "%G-%V"), (self.monthly, "%Y-%m")] {
+            let mut periods = BTreeSet::new();
+            for (name, time) in taken.iter() {
+                let period = time.format(period).to_string();
+                if periods.contains(&period) {
+                    continue;
+                }
+                if periods.len() >= count.unwrap_or(0) as usize {
+                    break;
+                }
+
+                // Newest first, so this is the last snapshot of its period
+                periods.insert(period);
+                kept.insert(name.to_string());
+            }
+        }
+
+        kept
+    }
+}
+
+/// The snapshots among `snapshots` (oldest first) past the retention of
+/// `host_config` at `now`. With both `retention` days and a `keep` policy a
+/// snapshot goes once it is too old and the policy does not keep it. The
+/// newest snapshot is never expired.
+pub fn expired(host_config: &HostConfig, snapshots: &[String], now: i64) -> Vec<String> {
+    if host_config.retention.is_none() && host_config.keep.is_none() {
+        return Vec::new();
+    }
+
+    let horizon = host_config.retention.map(|days| now - days as i64 * 24 * 60 * 60);
+    let kept = host_config.keep.as_ref().map(|keep| keep.keeps(snapshots)).unwrap_or_default();
+
+    snapshots[..snapshots.len().saturating_sub(1)].iter()
+        .filter(|snapshot| match (snapshot_time(snapshot), horizon) {
+            (Some(taken), Some(horizon)) => taken < horizon,
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+        .filter(|snapshot| !kept.contains(*snapshot))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn test_keep_policy() {
+    let snapshots: Vec<String> = [
+        "2024-04-28-08-00-00", // sunday
+        "2024-04-30-08-00-00",
+        "2024-05-01-08-00-00",
+        "2024-05-01-20-00-00",
+        "2024-05-02-08-00-00",
+        "2024-05-03-08-00-00",
+    ].iter().map(|name| name.to_string()).collect();
+
+    let keep = |policy: KeepPolicy| policy.keeps(&snapshots).into_iter().collect::<Vec<_>>();
+    assert!(keep(KeepPolicy::default()).is_empty());
+    assert_eq!(keep(KeepPolicy { last: Some(2), ..Default::default() }), ["2024-05-02-08-00-00", "2024-05-03-08-00-00"]);
+    assert_eq!(keep(KeepPolicy { daily: Some(3), ..Default::default() }), [
+        "2024-05-01-20-00-00", "2024-05-02-08-00-00", "2024-05-03-08-00-00",
+    ]);
+    assert_eq!(keep(KeepPolicy { weekly: Some(5), monthly: Some(1), ..Default::default() }), [
+        "2024-04-28-08-00-00", "2024-05-03-08-00-00",
+    ]);
+    assert_eq!(keep(KeepPolicy { last: Some(1), monthly: Some(2), ..Default::default() }), [
+        "2024-04-30-08-00-00", "2024-05-03-08-00-00",
+    ]);
+}
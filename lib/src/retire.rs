@@ -0,0 +1,138 @@
+use chrono::{Local, NaiveDate};
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::traits::JsonFile;
+
+/// A host that is gone, with its snapshots kept as they were. It is no
+/// longer scheduled or backed up, and prune leaves its snapshots alone until
+/// `until`, past which all of them go. Stored at
+/// $backups/$identifier/.records/retired.json
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Retirement {
+    pub retired: i64,       // unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<i64>, // unix seconds, default: kept forever
+}
+
+impl Retirement {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        global_config.backups
+            .join(&host_config.identifier)
+            .join(".records")
+            .join("retired.json")
+    }
+
+    /// The retirement of `host_config`, None while it is in service
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Option<Self>, Trap> {
+        let path = Self::path(global_config, host_config);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        Retirement::deserialize_json(&path)
+            .map(Some)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))
+    }
+
+    /// Retires `host_config`, with its snapshots kept until `until` if set,
+    /// and notes it in the audit log. Retiring it again moves `until`.
+    pub fn retire(global_config: &GlobalConfig, host_config: &HostConfig, hostname: &str, until: Option<i64>, now: i64) -> Result<Self, Trap> {
+        global_config.ensure_writable(&format!("retire `{}`", hostname))?;
+        let retired = Retirement::load(global_config, host_config)?.map(|retirement| retirement.retired).unwrap_or(now);
+        let retirement = Retirement { retired, until };
+
+        let path = Self::path(global_config, host_config);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+        retirement.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))?;
+
+        let detail = match until {
+            Some(until) => format!("snapshots kept until {}", until),
+            None => String::from("snapshots kept forever"),
+        };
+        AuditLog::new(global_config).append(&AuditEntry::new(hostname, "retire", &detail, now))?;
+        Ok(retirement)
+    }
+
+    /// Errors with Trap::ReadOnly if `host_config` is retired, for whatever
+    /// would change its snapshots other than their final expiry
+    pub fn ensure_active(global_config: &GlobalConfig, host_config: &HostConfig, action: &str) -> Result<(), Trap> {
+        match Retirement::load(global_config, host_config)? {
+            Some(_) => Err(Trap::ReadOnly(format!("Refusing to {}: `{}` is retired", action, host_config.identifier))),
+            None => Ok(()),
+        }
+    }
+
+    /// The snapshots among `snapshots` past the final expiry at `now`, all of
+    /// them or none
+    pub fn expired(&self, snapshots: &[String], now: i64) -> Vec<String> {
+        match self.until {
+            Some(until) if now > until => snapshots.to_vec(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// End of the day `date` (`YYYY-MM-DD`) in local time, as unix seconds
+pub fn parse_until(date: &str) -> Result<i64, Trap> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+        .and_then(|date| date.and_hms_opt(23, 59, 59))
+        .and_then(|time| time.and_local_timezone(Local).latest())
+        .map(|time| time.timestamp())
+        .ok_or(Trap::InvalidInput(format!("`{}` is not a date like 2025-12-31", date)))
+}
+
+impl JsonFile for Retirement {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = File::open(file_path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let retirement: Retirement = serde_json::from_str(&contents)?;
+        Ok(retirement)
+    }
+}
+
+#[test]
+fn test_retire() {
+    let backups = std::env::temp_dir().join("rensen_test_retire");
+    let global_config = GlobalConfig { backups: backups.clone(), log: backups.join("log"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let _ = fs::remove_dir_all(&backups);
+    fs::create_dir_all(&backups).unwrap();
+
+    assert_eq!(Retirement::load(&global_config, &host_config).unwrap(), None);
+    assert!(Retirement::ensure_active(&global_config, &host_config, "back up `host`").is_ok());
+
+    let until = parse_until("2025-12-31").unwrap();
+    assert!(parse_until("someday").is_err());
+    Retirement::retire(&global_config, &host_config, "myserver", None, 100).unwrap();
+    let retirement = Retirement::retire(&global_config, &host_config, "myserver", Some(until), 200).unwrap();
+    assert_eq!(retirement, Retirement { retired: 100, until: Some(until) });
+    assert!(matches!(Retirement::ensure_active(&global_config, &host_config, "back up `host`"), Err(Trap::ReadOnly(_))));
+
+    // Frozen until the final expiry, then every snapshot goes
+    let snapshots = vec![String::from("2024-05-01-14-05-00"), String::from("2024-05-02-14-05-00")];
+    assert!(retirement.expired(&snapshots, until).is_empty());
+    assert_eq!(retirement.expired(&snapshots, until + 1), snapshots);
+
+    let entries = AuditLog::new(&global_config).for_host("myserver").unwrap();
+    assert_eq!(entries.iter().map(|entry| (entry.time, entry.action.as_str())).collect::<Vec<_>>(), [(100, "retire"), (200, "retire")]);
+    assert_eq!(entries[0].detail, "snapshots kept forever");
+    let _ = fs::remove_dir_all(&backups);
+}
@@ -0,0 +1,65 @@
+use serde::{Serialize, Deserialize};
+use std::time::Duration;
+
+use crate::logging::Trap;
+
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RETRY_BACKOFF: u64 = 60;
+pub const DEFAULT_RETRY_JITTER: f64 = 0.2;
+
+/// Retries of scheduled runs that failed to reach the host, e.g.
+///
+/// retry:
+///   attempts: 4
+///   backoff: 120
+///
+/// The nth retry waits `backoff` * 2^(n-1) seconds, give or take `jitter`
+/// of it, so hosts that failed together do not all come back at once.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,  // runs in all, the first included, default: 3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<u64>,   // seconds before the first retry, default: 60
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<f64>,    // share of the delay it is moved by at most, default: 0.2
+}
+
+impl RetryConfig {
+    /// Whether a run that failed with `trap` on attempt `attempt` (the first
+    /// is 0) is tried again
+    pub fn retries(&self, trap: &Trap, attempt: u32) -> bool {
+        is_transient(trap) && attempt + 1 < self.attempts.unwrap_or(DEFAULT_RETRY_ATTEMPTS)
+    }
+
+    /// How long to wait before retrying after attempt `attempt` failed.
+    /// `spread` is in [0, 1) and picks where in the jitter it ends up.
+    pub fn delay(&self, attempt: u32, spread: f64) -> Duration {
+        let backoff = self.backoff.unwrap_or(DEFAULT_RETRY_BACKOFF) as f64 * 2f64.powi(attempt.min(16) as i32);
+        let jitter = self.jitter.unwrap_or(DEFAULT_RETRY_JITTER).clamp(0.0, 1.0);
+        Duration::from_secs_f64(backoff * (1.0 + jitter * (2.0 * spread - 1.0)))
+    }
+}
+
+/// Failures a later attempt may well not run into: the host could not be
+/// reached or the connection broke. Refused keys, bad configs and full
+/// destinations fail the same way again.
+pub fn is_transient(trap: &Trap) -> bool {
+    matches!(trap, Trap::Connect(_) | Trap::Session(_) | Trap::Handshake(_) | Trap::Channel(_))
+}
+
+#[test]
+fn test_retry() {
+    let config = RetryConfig { attempts: Some(3), backoff: Some(60), jitter: Some(0.5) };
+    let refused = Trap::Connect(String::from("Connection refused"));
+
+    assert!(config.retries(&refused, 0) && config.retries(&refused, 1));
+    assert!(!config.retries(&refused, 2));
+    assert!(!config.retries(&Trap::Auth(String::from("Permission denied")), 0));
+
+    assert_eq!(config.delay(0, 0.5), Duration::from_secs(60));
+    assert_eq!(config.delay(2, 0.5), Duration::from_secs(240));
+    assert_eq!(config.delay(0, 0.0), Duration::from_secs(30));
+    assert!(config.delay(1, 0.999) < Duration::from_secs(180));
+    assert_eq!(RetryConfig::default().delay(0, 0.5), Duration::from_secs(DEFAULT_RETRY_BACKOFF));
+}
@@ -0,0 +1,215 @@
+use chrono::{Local, NaiveDateTime};
+use serde::{Serialize, Deserialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::cancel::Cancel;
+
+/// Source served by an rsync daemon instead of over ssh, for appliances that
+/// run rsyncd but no sftp, e.g.
+///
+/// rsyncd:
+///   module: backup
+///   password_file: /etc/rensen/nas.pass
+///
+/// `source` is then a path in the module and `user` the rsyncd user.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RsyncdConfig {
+    pub module: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,              // default: 873
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>, // handed to rsync, must only be readable by rensen, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rsync: Option<String>,          // rsync binary, default: rsync
+}
+
+/// A regular file below the source, by its path relative to it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RemoteFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: u64, // unix seconds
+}
+
+/// rsync exit codes for a transfer that got through with some files left
+/// out, for errors on single files (23) or files gone meanwhile (24)
+const PARTIAL: [i32; 2] = [23, 24];
+
+impl RsyncdConfig {
+    /// rsync://$user@$identifier:$port/$module/$source/
+    pub fn url(&self, host_config: &HostConfig) -> String {
+        let source = host_config.source.to_string_lossy();
+        let source = source.trim_matches('/');
+        let user = match host_config.user.is_empty() {
+            true => String::new(),
+            false => format!("{}@", host_config.user),
+        };
+
+        match source.is_empty() {
+            true => format!("rsync://{}{}:{}/{}/", user, host_config.identifier, self.port.unwrap_or(873), self.module),
+            false => format!("rsync://{}{}:{}/{}/{}/", user, host_config.identifier, self.port.unwrap_or(873), self.module, source),
+        }
+    }
+
+    fn command(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Command {
+        let mut command = Command::new(self.rsync.as_deref().unwrap_or("rsync"));
+        command.arg(format!("--contimeout={}", host_config.connect_timeout(global_config).as_secs().max(1)));
+        if let Some(password_file) = &self.password_file {
+            command.arg("--password-file").arg(password_file);
+        }
+
+        command
+    }
+
+    /// Every regular file below the source of `host_config`
+    pub fn list(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Vec<RemoteFile>, Trap> {
+        let url = self.url(host_config);
+        let output = self.command(global_config, host_config)
+            .args(["--list-only", "--recursive", "--no-human-readable"])
+            .arg(&url)
+            .output()
+            .map_err(|err| Trap::Rsyncd(format!("Could not run rsync: {}", err)))?;
+
+        if !output.status.success() && !PARTIAL.contains(&output.status.code().unwrap_or(-1)) {
+            return Err(Trap::Rsyncd(format!("Could not list {}: {}", url, String::from_utf8_lossy(&output.stderr).trim())));
+        }
+
+        Ok(parse_list(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Fetches `files` (relative to the source) into `destination`, keeping
+    /// their mtimes. Returns those received, files that could not be read or
+    /// vanished meanwhile are left out.
+    /// rsync is killed when `cancel` is.
+    pub fn fetch(&self, global_config: &GlobalConfig, host_config: &HostConfig, files: &[PathBuf], destination: &Path, cancel: Option<&Cancel>) -> Result<Vec<RemoteFile>, Trap> {
+        if files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let url = self.url(host_config);
+        let mut child = self.command(global_config, host_config)
+            .args(["--recursive", "--times", "--from0", "--files-from=-", "--out-format=%l\t%M\t%n"])
+            .arg(&url)
+            .arg(destination)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| Trap::Rsyncd(format!("Could not run rsync: {}", err)))?;
+        if let Some(cancel) = cancel {
+            cancel.attach_process(Some(child.id()));
+        }
+
+        let mut names = Vec::new();
+        for file in files {
+            names.extend_from_slice(file.to_string_lossy().as_bytes());
+            names.push(0);
+        }
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&names)
+                .map_err(|err| Trap::Rsyncd(format!("Could not hand the files to rsync: {}", err)))?;
+        }
+
+        let output = child.wait_with_output();
+        if let Some(cancel) = cancel {
+            cancel.attach_process(None);
+        }
+        let output = output.map_err(|err| Trap::Rsyncd(format!("Could not run rsync: {}", err)))?;
+        if !output.status.success() && !PARTIAL.contains(&output.status.code().unwrap_or(-1)) {
+            return Err(Trap::Rsyncd(format!("Could not fetch from {}: {}", url, String::from_utf8_lossy(&output.stderr).trim())));
+        }
+
+        Ok(parse_received(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// `YYYY/MM/DD HH:MM:SS` or `YYYY/MM/DD-HH:MM:SS` in local time, as rsync
+/// prints them
+fn parse_time(time: &str) -> Option<u64> {
+    NaiveDateTime::parse_from_str(&time.replacen('-', " ", 1), "%Y/%m/%d %H:%M:%S").ok()
+        .and_then(|time| time.and_local_timezone(Local).earliest())
+        .and_then(|time| u64::try_from(time.timestamp()).ok())
+}
+
+/// The regular files in the output of `rsync --list-only`, e.g.
+/// `-rw-r--r--           1234 2024/05/01 14:05:00 etc/app.conf`
+pub fn parse_list(output: &str) -> Vec<RemoteFile> {
+    output.lines()
+        .filter(|line| line.starts_with('-'))
+        .filter_map(|line| {
+            let mut rest = line;
+            let mut field = || {
+                let trimmed = rest.trim_start();
+                let end = trimmed.find(' ').unwrap_or(trimmed.len());
+                let (field, tail) = trimmed.split_at(end);
+                rest = tail.get(1..).unwrap_or("");
+                field
+            };
+
+            let _mode = field();
+            let size = field().replace(',', "").parse().ok()?;
+            let date = field();
+            let time = field();
+            let mtime = parse_time(&format!("{} {}", date, time))?;
+            Some(RemoteFile { path: PathBuf::from(rest), size, mtime })
+        })
+        .collect()
+}
+
+/// The files in the output of a transfer with `--out-format=%l\t%M\t%n`,
+/// without the directories it created on the way
+pub fn parse_received(output: &str) -> Vec<RemoteFile> {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let size = fields.next()?.parse().ok()?;
+            let mtime = parse_time(fields.next()?)?;
+            let path = fields.next().filter(|path| !path.is_empty() && !path.ends_with('/'))?;
+            Some(RemoteFile { path: PathBuf::from(path), size, mtime })
+        })
+        .collect()
+}
+
+#[test]
+fn test_rsyncd() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let listed = parse_list("drwxr-xr-x          4,096 2024/05/01 14:00:00 .\n\
+                             -rw-r--r--          1,234 2024/05/01 14:05:00 etc/app.conf\n\
+                             lrwxrwxrwx             11 2024/05/01 14:05:00 current -> etc/app.conf\n\
+                             -rw-------              7 2024/05/02 08:00:00 with space.txt\n");
+    assert_eq!(listed.len(), 2);
+    assert_eq!((listed[0].path.as_path(), listed[0].size), (Path::new("etc/app.conf"), 1234));
+    assert_eq!(listed[0].mtime, parse_time("2024/05/01-14:05:00").unwrap());
+    assert_eq!(listed[1].path, PathBuf::from("with space.txt"));
+
+    // Stands in for rsync: lists two files, then fetches whatever it is handed
+    let root = std::env::temp_dir().join("rensen_test_rsyncd");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("snapshot")).unwrap();
+    let rsync = root.join("rsync");
+    fs::write(&rsync, "#!/bin/sh\n\
+        for last; do :; done\n\
+        case \"$*\" in\n\
+          *--list-only*) printf -- '-rw-r--r-- 3 2024/05/01 14:05:00 a\\n-rw-r--r-- 3 2024/05/01 14:05:00 b/c\\n' ;;\n\
+          *) tr '\\0' '\\n' | while read -r name; do mkdir -p \"$last/$(dirname \"$name\")\"; echo ok > \"$last/$name\"; printf '3\\t2024/05/01-14:05:00\\t%s\\n' \"$name\"; done; exit 24 ;;\n\
+        esac\n").unwrap();
+    fs::set_permissions(&rsync, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = RsyncdConfig { module: String::from("backup"), rsync: Some(rsync.to_string_lossy().into_owned()), ..Default::default() };
+    let host_config = HostConfig { user: String::from("nas"), identifier: String::from("nas.lan"), source: PathBuf::from("/data"), ..Default::default() };
+    assert_eq!(config.url(&host_config), "rsync://nas@nas.lan:873/backup/data/");
+
+    let global_config = GlobalConfig::default();
+    let files: Vec<PathBuf> = config.list(&global_config, &host_config).unwrap().into_iter().map(|file| file.path).collect();
+    assert_eq!(files, vec![PathBuf::from("a"), PathBuf::from("b/c")]);
+    let received = config.fetch(&global_config, &host_config, &files, &root.join("snapshot"), None).unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(fs::read_to_string(root.join("snapshot").join("b/c")).unwrap(), "ok\n");
+    let _ = fs::remove_dir_all(&root);
+}
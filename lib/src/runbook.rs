@@ -0,0 +1,461 @@
+use chrono::{Local, NaiveDate};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use ssh2::{FileStat, Session, Sftp as SftpChannel};
+
+use crate::chunks::ChunkStore;
+use crate::backup::rsync::Sftp;
+use crate::compiler::Compiler;
+use crate::unpacked::UnpackCache;
+use crate::encrypt::upload_decrypted;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::checksum::{parse_manifest, Algorithm};
+use crate::helper::quote;
+use crate::results::RestoreReport;
+use crate::traits::Restore;
+use crate::compact::snapshot_time;
+use crate::verify::snapshots;
+use crate::remote::RemoteOs;
+use crate::ownership::restores_owners;
+use crate::sparse::{copy_regions, data_regions};
+
+/// Restore runbook of a host, e.g.
+///
+/// restore:
+///   order:
+///     - /srv/app/config
+///     - /srv/app/data
+///   post:
+///     - command: chown -R app:app /srv/app/data
+///       after: /srv/app/data
+///     - command: systemctl restart app
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RestoreConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<Vec<PathBuf>>,   // source paths restored first, in this order, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<Vec<PostRestore>>, // commands run on the host, default: none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,       // where `source` is restored to on the host, default: `source`
+}
+
+/// A command run on the host during a restore
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PostRestore {
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<PathBuf>,        // run once this path of `order` is restored, default: at the end
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreStep {
+    /// Compiles the snapshot into a tree to upload from
+    Compile { snapshot: String },
+    /// Uploads a source path (and what is below it) to the host, leaving out
+    /// paths uploaded by steps of their own
+    Upload { path: PathBuf, to: PathBuf, exclude: Vec<PathBuf> },
+    /// Runs a command on the host, failing the restore if it fails
+    Run { command: String },
+    /// Hashes what was uploaded on the host and compares it with the snapshot
+    Verify,
+}
+
+impl fmt::Display for RestoreStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestoreStep::Compile { snapshot } => write!(f, "compile snapshot {}", snapshot),
+            RestoreStep::Upload { path, to, exclude } if exclude.is_empty() => write!(f, "restore {:?} to {:?}", path, to),
+            RestoreStep::Upload { path, to, exclude } => write!(f, "restore {:?} to {:?}, except {:?}", path, to, exclude),
+            RestoreStep::Run { command } => write!(f, "run `{}`", command),
+            RestoreStep::Verify => write!(f, "verify the restored files against the snapshot"),
+        }
+    }
+}
+
+/// Where `path` (below `source`) is restored to on the host
+fn target_of(host_config: &HostConfig, path: &Path) -> PathBuf {
+    let target = host_config.restore.as_ref()
+        .and_then(|restore| restore.target.clone())
+        .unwrap_or_else(|| host_config.source.clone());
+
+    match path.strip_prefix(&host_config.source) {
+        Ok(relative) if relative.as_os_str().is_empty() => target,
+        Ok(relative) => target.join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// The steps restoring `snapshot` of `host_config`: the paths of `order`
+/// first, each followed by the commands to run after it, then the rest of
+/// the source, then the remaining commands.
+pub fn restore_plan(host_config: &HostConfig, snapshot: &str) -> Result<Vec<RestoreStep>, Trap> {
+    let restore = host_config.restore.clone().unwrap_or_default();
+    let order = restore.order.unwrap_or_default();
+    let post = restore.post.unwrap_or_default();
+
+    if let Some(path) = order.iter().find(|path| !path.starts_with(&host_config.source)) {
+        return Err(Trap::Config(format!("Restore path {:?} is not within source {:?}", path, host_config.source)));
+    }
+
+    let mut steps = vec![RestoreStep::Compile { snapshot: snapshot.to_string() }];
+    for (i, path) in order.iter().enumerate() {
+        // Later paths below this one get restored in their own turn
+        let exclude = order[i + 1..].iter().filter(|other| other.starts_with(path)).cloned().collect();
+        steps.push(RestoreStep::Upload { path: path.clone(), to: target_of(host_config, path), exclude });
+
+        for post in post.iter().filter(|post| post.after.as_ref() == Some(path)) {
+            steps.push(RestoreStep::Run { command: post.command.clone() });
+        }
+    }
+
+    if !order.contains(&host_config.source) {
+        steps.push(RestoreStep::Upload {
+            path: host_config.source.clone(),
+            to: target_of(host_config, &host_config.source),
+            exclude: order.clone(),
+        });
+    }
+
+    for post in post.iter().filter(|post| post.after.as_ref().is_none_or(|after| !order.contains(after))) {
+        steps.push(RestoreStep::Run { command: post.command.clone() });
+    }
+
+    Ok(steps)
+}
+
+/// The snapshot of `host_config` restored for `at`: `latest` is the live
+/// record and a snapshot name is taken as is. Any other point in time picks
+/// the newest snapshot taken at or before it, a bare date the last one of
+/// that day.
+pub fn resolve_snapshot(global_config: &GlobalConfig, host_config: &HostConfig, at: &str) -> Result<String, Trap> {
+    let names = snapshots(global_config, host_config);
+    if at == "latest" {
+        return Ok(String::from("record"));
+    }
+    if names.iter().any(|name| name == at) {
+        return Ok(at.to_string());
+    }
+
+    let time = snapshot_time(at)
+        .or_else(|| NaiveDate::parse_from_str(at, "%Y-%m-%d").ok()
+            .and_then(|date| date.and_hms_opt(23, 59, 59))
+            .and_then(|time| time.and_local_timezone(Local).latest())
+            .map(|time| time.timestamp()))
+        .ok_or(Trap::InvalidInput(format!(
+            "Invalid snapshot `{}`, expected a snapshot name, `latest`, YYYY-MM-DD or YYYY-MM-DD-HH-MM-SS", at
+        )))?;
+
+    names.into_iter()
+        .rfind(|name| snapshot_time(name).is_some_and(|taken| taken <= time))
+        .ok_or(Trap::Restore(format!("No snapshot of `{}` was taken at or before {}", host_config.identifier, at)))
+}
+
+fn stat_of(metadata: &fs::Metadata) -> FileStat {
+    let mtime = metadata.modified().ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs());
+
+    FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(metadata.permissions().mode()),
+        atime: mtime,
+        mtime,
+    }
+}
+
+/// Gives `remote` the owner the compiled file got, which is the one it was
+/// backed up with when compiled as root. On its own, as a host user other
+/// than root can not set it and the mode and times should still be.
+fn set_owner(sftp: &SftpChannel, metadata: &fs::Metadata, remote: &Path) {
+    if !restores_owners() {
+        return;
+    }
+
+    let owner = FileStat { size: None, uid: Some(metadata.uid()), gid: Some(metadata.gid()), perm: None, atime: None, mtime: None };
+    let _ = sftp.setstat(remote, owner);
+}
+
+/// Uploads the tree at `local` to `remote`, leaving out the local paths in
+/// `exclude`. Files of hosts with `encrypt_key` are decrypted on the host as
+/// they arrive, with `decrypt`. Every file uploaded is added to `uploaded`,
+/// local path first.
+fn upload(sftp: &SftpChannel, decrypt: Option<(&Session, &Path)>, local: &Path, remote: &Path, exclude: &[PathBuf], uploaded: &mut Vec<(PathBuf, PathBuf)>) -> Result<(), Trap> {
+    if exclude.iter().any(|excluded| excluded == local) {
+        return Ok(());
+    }
+
+    let metadata = fs::symlink_metadata(local)
+        .map_err(|err| Trap::Restore(format!("Could not read {:?}: {}", local, err)))?;
+
+    if let (true, Some((sess, key))) = (metadata.is_file(), decrypt) {
+        upload_decrypted(sess, key, local, remote)
+            .map_err(|err| Trap::Restore(format!("Could not upload {:?}: {}", remote, err)))?;
+        let _ = sftp.setstat(remote, stat_of(&metadata));
+        set_owner(sftp, &metadata, remote);
+        uploaded.push((local.to_path_buf(), remote.to_path_buf()));
+        return Ok(());
+    }
+
+    if metadata.is_file() {
+        let mut source = File::open(local)
+            .map_err(|err| Trap::Restore(format!("Could not open {:?}: {}", local, err)))?;
+        let mut destination = sftp.create(remote)
+            .map_err(|err| Trap::Restore(format!("Could not create {:?} on host: {}", remote, err)))?;
+        // The holes of a sparse file are seeked over, the host leaves them holes too
+        match data_regions(&source) {
+            Ok(Some(regions)) => copy_regions(&source, &regions, metadata.len(), &mut destination),
+            _ => io::copy(&mut source, &mut destination).map(|_| ()),
+        }.map_err(|err| Trap::Restore(format!("Could not upload {:?}: {}", remote, err)))?;
+        let _ = sftp.setstat(remote, stat_of(&metadata));
+        set_owner(sftp, &metadata, remote);
+        uploaded.push((local.to_path_buf(), remote.to_path_buf()));
+        return Ok(());
+    }
+
+    // As the link it is, anything it points to on the host is not the restore's to write
+    if metadata.is_symlink() {
+        let target = fs::read_link(local)
+            .map_err(|err| Trap::Restore(format!("Could not read {:?}: {}", local, err)))?;
+        let _ = sftp.unlink(remote);
+        // Arguments as OpenSSH takes them, the link goes at `remote`
+        sftp.symlink(&target, remote)
+            .map_err(|err| Trap::Restore(format!("Could not create {:?} on host: {}", remote, err)))?;
+        return Ok(());
+    }
+
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+
+    // Fails when it exists already, which is fine
+    let _ = sftp.mkdir(remote, 0o755);
+    let mut entries: Vec<PathBuf> = fs::read_dir(local)
+        .map_err(|err| Trap::Restore(format!("Could not read {:?}: {}", local, err)))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+
+    for entry in entries {
+        let name = entry.file_name().unwrap_or_default();
+        upload(sftp, decrypt, &entry, &remote.join(name), exclude, uploaded)?;
+    }
+    let _ = sftp.setstat(remote, stat_of(&metadata));
+
+    Ok(())
+}
+
+/// Compares the files `uploaded` (local, remote) by their SHA-256, hashed
+/// on the host through `exec`. Returns the remote paths that differ and
+/// those not found.
+pub fn verify_restored<F>(exec: F, uploaded: &[(PathBuf, PathBuf)]) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Trap>
+where
+    F: Fn(&str) -> Result<String, Trap>
+{
+    let mut mismatched = Vec::new();
+    let mut missing = Vec::new();
+
+    // In batches, to stay well below the argument limit of the host
+    for batch in uploaded.chunks(100) {
+        let paths: Vec<String> = batch.iter().map(|(_, remote)| quote(remote)).collect();
+        let output = exec(&format!("sha256sum {} 2>/dev/null; true", paths.join(" ")))?;
+        let hashed: HashMap<PathBuf, String> = parse_manifest(&output).into_iter()
+            .map(|listed| (listed.file, listed.hash))
+            .collect();
+
+        for (local, remote) in batch {
+            let expected = Algorithm::Sha256.digest(local)
+                .map_err(|err| Trap::Restore(format!("Could not hash {:?}: {}", local, err)))?;
+            match hashed.get(remote) {
+                Some(hash) if *hash == expected => (),
+                Some(_) => mismatched.push(remote.clone()),
+                None => missing.push(remote.clone()),
+            }
+        }
+    }
+
+    Ok((mismatched, missing))
+}
+
+/// Carries out the steps for the host `Sftp` is connected and authenticated
+/// to, stopping at the first one that fails. Uploaded files get the mode and
+/// mtime they had at the time of the snapshot.
+impl<'a> Restore for Sftp<'a> {
+    fn restore(&self, steps: &[RestoreStep]) -> Result<RestoreReport, Trap> {
+        let global_config = self.global_config;
+        let host_config = self.host_config;
+        let sess = self.sess.as_ref()
+            .ok_or(Trap::Session(String::from("Session unavailable")))?;
+        let decrypt = host_config.encrypt_key.as_deref().map(|key| (sess, key));
+        let channel = sess
+            .sftp()
+            .map_err(|err| Trap::Session(format!("Could not init SFTP session: {}", err)))?;
+
+        let source_dir = match host_config.source.file_stem() {
+            Some(stem) => PathBuf::from(stem),
+            None => PathBuf::from(&host_config.identifier),
+        };
+
+        let mut tree: Option<PathBuf> = None;
+        let mut uploaded = Vec::new();
+        let mut report = RestoreReport::default();
+        let result = (|| {
+            for step in steps {
+                println!("{}", step);
+                match step {
+                    RestoreStep::Compile { snapshot } => {
+                        let record_path = host_config.root(global_config)
+                            .join(".records")
+                            .join(format!("{}.json", snapshot));
+
+                        let mut compiler = Compiler::from(&record_path)?;
+                        compiler.archive = false;
+                        compiler.scratch = Some(global_config.snapshots.join(".unpack"));
+                        compiler.chunks = Some(ChunkStore::new(global_config));
+                        compiler.cache = UnpackCache::new(global_config);
+                        compiler.special_files = host_config.special_files.unwrap_or_default();
+                        compiler.owner_map = host_config.owner_map.clone().unwrap_or_default();
+                        let report = compiler.compile(&global_config.snapshots);
+                        let _ = compiler.cleanup();
+                        tree = Some(report?.destination.join(&source_dir));
+                    },
+                    RestoreStep::Upload { path, to, exclude } => {
+                        let root = tree.as_ref().ok_or(Trap::Restore(String::from("Nothing compiled to restore from")))?;
+                        let local = |path: &Path| root.join(path.strip_prefix(&host_config.source).unwrap_or(path));
+                        let exclude: Vec<PathBuf> = exclude.iter().map(|path| local(path)).collect();
+                        upload(&channel, decrypt, &local(path), to, &exclude, &mut uploaded)?;
+                    },
+                    RestoreStep::Run { command } => {
+                        self.exec(command)
+                            .map_err(|err| Trap::Restore(format!("Post-restore command failed: {}", err)))?;
+                    },
+                    RestoreStep::Verify => {
+                        // What was compiled is still encrypted, the host has it decrypted
+                        if decrypt.is_some() {
+                            return Err(Trap::Restore(String::from("Restores of hosts with `encrypt_key` cannot be verified")));
+                        }
+                        // The hashes are taken with POSIX tools on the host
+                        if host_config.remote_os() == RemoteOs::Windows {
+                            return Err(Trap::Restore(String::from("Restores to Windows hosts cannot be verified")));
+                        }
+                        let (mismatched, missing) = verify_restored(|command| self.exec(command), &uploaded)?;
+                        report.verified = Some(uploaded.len() as u64);
+                        report.mismatched = mismatched;
+                        report.missing = missing;
+                    },
+                }
+            }
+            Ok(())
+        })();
+
+        // The compiled tree only served the upload
+        if let Some(root) = tree.as_ref().and_then(|root| root.parent()) {
+            let _ = fs::remove_dir_all(root);
+        }
+
+        report.uploaded = uploaded.len() as u64;
+        result.map(|_| report)
+    }
+}
+
+#[test]
+fn test_restore_plan() {
+    let host_config = HostConfig {
+        source: PathBuf::from("/srv/app"),
+        restore: Some(RestoreConfig {
+            order: Some(vec![PathBuf::from("/srv/app/data"), PathBuf::from("/srv/app/data/db")]),
+            post: Some(vec![
+                PostRestore { command: String::from("systemctl restart app"), after: None },
+                PostRestore { command: String::from("chown -R app /srv/app/data"), after: Some(PathBuf::from("/srv/app/data")) },
+            ]),
+            target: Some(PathBuf::from("/restore")),
+        }),
+        ..Default::default()
+    };
+
+    let steps = restore_plan(&host_config, "2024-01-01-00-00-00").unwrap();
+    assert_eq!(steps, vec![
+        RestoreStep::Compile { snapshot: String::from("2024-01-01-00-00-00") },
+        RestoreStep::Upload { path: "/srv/app/data".into(), to: "/restore/data".into(), exclude: vec!["/srv/app/data/db".into()] },
+        RestoreStep::Run { command: String::from("chown -R app /srv/app/data") },
+        RestoreStep::Upload { path: "/srv/app/data/db".into(), to: "/restore/data/db".into(), exclude: vec![] },
+        RestoreStep::Upload {
+            path: "/srv/app".into(),
+            to: "/restore".into(),
+            exclude: vec!["/srv/app/data".into(), "/srv/app/data/db".into()],
+        },
+        RestoreStep::Run { command: String::from("systemctl restart app") },
+    ]);
+
+    // Only paths of the source can be restored
+    let outside = HostConfig {
+        restore: Some(RestoreConfig { order: Some(vec![PathBuf::from("/etc")]), ..Default::default() }),
+        ..host_config
+    };
+    assert!(restore_plan(&outside, "latest").is_err());
+}
+
+#[test]
+fn test_resolve_snapshot() {
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_resolve"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let records = global_config.backups.join("host").join(".records");
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(&records).unwrap();
+    for name in ["2024-05-01-08-00-00", "2024-05-01-20-00-00", "2024-05-03-08-00-00", "record"] {
+        fs::write(records.join(format!("{}.json", name)), "{}").unwrap();
+    }
+
+    let resolve = |at: &str| resolve_snapshot(&global_config, &host_config, at);
+    assert_eq!(resolve("latest").unwrap(), "record");
+    assert_eq!(resolve("2024-05-03-08-00-00").unwrap(), "2024-05-03-08-00-00");
+    assert_eq!(resolve("2024-05-01-12-00-00").unwrap(), "2024-05-01-08-00-00");
+    assert_eq!(resolve("2024-05-02").unwrap(), "2024-05-01-20-00-00");
+    assert_eq!(resolve("2024-05-01").unwrap(), "2024-05-01-20-00-00");
+    assert!(matches!(resolve("2024-04-30"), Err(Trap::Restore(_))));
+    assert!(matches!(resolve("yesterday"), Err(Trap::InvalidInput(_))));
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
+
+#[test]
+fn test_verify_restored() {
+    use std::process::Command;
+
+    let root = std::env::temp_dir().join("rensen_test_verify_restored");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("compiled")).unwrap();
+    fs::create_dir_all(root.join("host")).unwrap();
+    for (name, compiled, restored) in [("same", "a", Some("a")), ("changed", "b", Some("c")), ("missing", "d", None)] {
+        fs::write(root.join("compiled").join(name), compiled).unwrap();
+        if let Some(restored) = restored {
+            fs::write(root.join("host").join(name), restored).unwrap();
+        }
+    }
+
+    // Stands in for the host
+    let exec = |command: &str| -> Result<String, Trap> {
+        let output = Command::new("sh").arg("-c").arg(command).output().unwrap();
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    let uploaded: Vec<(PathBuf, PathBuf)> = ["same", "changed", "missing"].iter()
+        .map(|name| (root.join("compiled").join(name), root.join("host").join(name)))
+        .collect();
+
+    let (mismatched, missing) = verify_restored(exec, &uploaded).unwrap();
+    if exec("command -v sha256sum").unwrap().is_empty() {
+        return;
+    }
+    assert_eq!(mismatched, vec![root.join("host").join("changed")]);
+    assert_eq!(missing, vec![root.join("host").join("missing")]);
+    let _ = fs::remove_dir_all(&root);
+}
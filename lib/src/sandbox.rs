@@ -0,0 +1,307 @@
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::codec::{predecoder, Codec};
+use crate::logging::Trap;
+use crate::utils::{demake_tar_gz, unpack_tar_gz};
+use crate::verify::{hash_entries, ArchiveProblem, HashedEntry};
+
+/// Set to the phase a re-executed rensen or rensend is to run confined
+pub const SANDBOX_ENV: &str = "RENSEN_SANDBOX";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Has archives unpacked and verified in confined subprocesses from now on,
+/// with `sandbox` in the global config
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Runs the phase of a sandboxed subprocess instead of rensen or rensend
+/// when this is one, and returns how it went. Called first thing in main.
+pub fn serve_if_requested() -> Option<process::ExitCode> {
+    let phase = std::env::var(SANDBOX_ENV).ok()?;
+    let args: Vec<PathBuf> = std::env::args_os().skip(1).map(PathBuf::from).collect();
+
+    let result = match (phase.as_str(), args.as_slice()) {
+        ("unpack", [destination, codec]) => codec_arg(codec).and_then(|codec| {
+            confine(Some(destination))?;
+            unpack_tar_gz(BufReader::new(io::stdin().lock()), destination, codec)
+        }),
+        ("hash", [codec]) => codec_arg(codec).and_then(|codec| {
+            confine(None)?;
+            let hashed = hash_entries(io::stdin().lock(), codec);
+            let json = serde_json::to_vec(&hashed)?;
+            io::stdout().lock().write_all(&json)
+        }),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown sandbox phase `{}`", phase))),
+    };
+
+    match result {
+        Ok(()) => Some(process::ExitCode::SUCCESS),
+        Err(err) => {
+            eprintln!("{}", err);
+            Some(process::ExitCode::FAILURE)
+        }
+    }
+}
+
+/// The codec a phase is handed as its last argument
+fn codec_arg(arg: &Path) -> io::Result<Codec> {
+    arg.to_str().and_then(Codec::from_name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown codec {:?}", arg)))
+}
+
+/// Unpacks `archive`, compressed with `codec`, into `destination`, in a
+/// subprocess that can only write below `destination` when the sandbox is enabled
+pub fn unpack(archive: &Path, destination: &Path, codec: Codec) -> io::Result<()> {
+    if !is_enabled() {
+        return demake_tar_gz(archive, destination, codec);
+    }
+
+    // The subprocess can not run zstd or xz, it is handed the tar instead
+    fs::create_dir_all(destination)?;
+    let (mut input, left) = predecoder(codec, fs::File::open(archive)?)?;
+    run("unpack", &[destination, Path::new(left.name())], &mut input).map(|_| ())
+}
+
+/// Size and hash of every entry of the .tar.gz, or archive compressed with
+/// `codec`, `reader` reads, hashed in a subprocess that can not open any
+/// file when the sandbox is enabled
+pub fn hash_archive(reader: &mut dyn Read, codec: Codec) -> Result<Result<Vec<HashedEntry>, ArchiveProblem>, Trap> {
+    if !is_enabled() {
+        return Ok(hash_entries(reader, codec));
+    }
+
+    let output = predecoder(codec, reader)
+        .and_then(|(mut input, left)| run("hash", &[Path::new(left.name())], &mut input))
+        .map_err(|err| Trap::Sandbox(format!("Could not hash the archive: {}", err)))?;
+    serde_json::from_slice(&output)
+        .map_err(|err| Trap::Sandbox(format!("Could not read what the sandbox hashed: {}", err)))
+}
+
+/// Runs this binary again for `phase`, with `input` on its stdin, and
+/// returns its stdout
+fn run(phase: &str, args: &[&Path], input: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let mut child = Command::new(std::env::current_exe()?)
+        .env(SANDBOX_ENV, phase)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The subprocess gives up on a broken archive before it read all of it,
+    // what it says about that counts for more than the broken pipe
+    let copied = child.stdin.take().map(|mut stdin| io::copy(input, &mut stdin));
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    if let Some(copied) = copied {
+        copied?;
+    }
+
+    Ok(output.stdout)
+}
+
+/* --------------------------------- */
+/* Landlock and seccomp, see         */
+/* landlock(7) and seccomp(2)        */
+/* --------------------------------- */
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+// The rights of ABI 1, later ones are left alone. Executing is never allowed.
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_MAKE_SYM: u64 = 1 << 12;
+const ACCESS_ALL: u64 = (1 << 13) - 1;
+
+/// What an unpack may do below its destination
+const ACCESS_UNPACK: u64 = ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_READ_DIR | ACCESS_REMOVE_DIR
+    | ACCESS_REMOVE_FILE | ACCESS_MAKE_DIR | ACCESS_MAKE_REG | ACCESS_MAKE_SYM;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Syscalls an archive parser never needs: the network, running programs,
+/// other processes and the system. They fail with EPERM.
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_bind, libc::SYS_listen,
+    libc::SYS_accept, libc::SYS_accept4, libc::SYS_execve, libc::SYS_execveat, libc::SYS_ptrace,
+    libc::SYS_process_vm_readv, libc::SYS_process_vm_writev, libc::SYS_mount, libc::SYS_umount2,
+    libc::SYS_chroot, libc::SYS_pivot_root, libc::SYS_unshare, libc::SYS_setns, libc::SYS_kexec_load,
+    libc::SYS_init_module, libc::SYS_finit_module, libc::SYS_delete_module, libc::SYS_bpf,
+    libc::SYS_keyctl, libc::SYS_add_key, libc::SYS_request_key, libc::SYS_io_uring_setup,
+    libc::SYS_reboot, libc::SYS_swapon, libc::SYS_swapoff,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Takes the calling process's right to touch any file but those below
+/// `writable`, and the syscalls in DENIED. Kernels without Landlock (before
+/// 5.13) only get the seccomp filter.
+fn confine(writable: Option<&Path>) -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    restrict_paths(writable)?;
+    filter_syscalls()
+}
+
+fn restrict_paths(writable: Option<&Path>) -> io::Result<()> {
+    let abi = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION) };
+    if abi < 1 {
+        return Ok(());
+    }
+
+    let attr = RulesetAttr { handled_access_fs: ACCESS_ALL };
+    let ruleset = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, &attr, std::mem::size_of::<RulesetAttr>(), 0) };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = ruleset as libc::c_int;
+
+    let result = (|| {
+        if let Some(writable) = writable {
+            let path = std::ffi::CString::new(writable.as_os_str().as_encoded_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let parent_fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if parent_fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let rule = PathBeneathAttr { allowed_access: ACCESS_UNPACK, parent_fd };
+            let added = unsafe { libc::syscall(libc::SYS_landlock_add_rule, ruleset, LANDLOCK_RULE_PATH_BENEATH, &rule, 0) };
+            unsafe { libc::close(parent_fd) };
+            if added != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        match unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    })();
+    unsafe { libc::close(ruleset) };
+
+    result
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter_syscalls() -> io::Result<()> {
+    let errno = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+    let load = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    let jeq = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    let ret = (libc::BPF_RET | libc::BPF_K) as u16;
+
+    // Syscall numbers of another architecture would mean something else
+    let mut program = vec![
+        stmt(load, 4), // seccomp_data.arch
+        jump(jeq, AUDIT_ARCH, 1, 0),
+        stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(load, 0), // seccomp_data.nr
+    ];
+    for syscall in DENIED {
+        program.push(jump(jeq, *syscall as u32, 0, 1));
+        program.push(stmt(ret, errno));
+    }
+    program.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+
+    let fprog = libc::sock_fprog { len: program.len() as u16, filter: program.as_mut_ptr() };
+    match unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &fprog as *const libc::sock_fprog) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn filter_syscalls() -> io::Result<()> {
+    Ok(())
+}
+
+#[test]
+fn test_sandbox() {
+    // What happened to the access the test subprocess tried once confined
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Attempts {
+        inside: bool,
+        outside: bool,
+        network: bool,
+    }
+
+    let root = std::env::temp_dir().join("rensen_test_sandbox");
+
+    // The test binary has no main to enter the sandbox from, the test is
+    // run again as the subprocess instead
+    if std::env::var(SANDBOX_ENV).as_deref() == Ok("test") {
+        confine(Some(&root.join("inside"))).unwrap();
+        let attempts = Attempts {
+            inside: fs::write(root.join("inside/file"), "1").is_ok(),
+            outside: fs::write(root.join("outside"), "1").is_ok(),
+            network: std::net::UdpSocket::bind("127.0.0.1:0").is_ok(),
+        };
+        fs::write(root.join("inside/attempts.json"), serde_json::to_vec(&attempts).unwrap()).unwrap();
+        return;
+    }
+
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("inside")).unwrap();
+    let status = Command::new(std::env::current_exe().unwrap())
+        .args(["sandbox::test_sandbox", "--exact", "--quiet"])
+        .env(SANDBOX_ENV, "test")
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Without Landlock in the kernel only the network is taken
+    let landlock = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION) } >= 1;
+    let attempts: Attempts = serde_json::from_slice(&fs::read(root.join("inside/attempts.json")).unwrap()).unwrap();
+    assert_eq!(attempts, Attempts { inside: true, outside: !landlock, network: false });
+
+    // Disabled, the work is done right here
+    enable(false);
+    let mut archive = Vec::new();
+    crate::utils::write_tar_gz(root.join("inside"), &mut archive, &Default::default()).unwrap();
+    let hashed = hash_archive(&mut archive.as_slice(), Codec::Gzip).unwrap().unwrap();
+    assert!(hashed.iter().any(|entry| entry.path.ends_with("file") && entry.size == 1));
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use cron::Schedule;
+
+use crate::config::Host;
+use crate::logging::Trap;
+
+/// Used for hosts without a `cron_schedule`, `interval` or `run_at`: every
+/// day at midnight
+pub const DEFAULT_CRON: &str = "0 0 0 * * *";
+
+/// When the backups of a host run
+#[derive(Debug, Clone)]
+pub enum HostSchedule {
+    Cron(Box<Schedule>),
+    Interval(i64),          // seconds, counted from 1970-01-01 00:00 UTC so restarts keep the same times
+    Once(DateTime<Local>),  // a single run, never again once it is over
+}
+
+impl HostSchedule {
+    /// The times it fires at after `after`
+    pub fn after<'a, T: TimeZone + 'a>(&'a self, after: &DateTime<T>) -> Box<dyn Iterator<Item = DateTime<T>> + 'a> {
+        match self {
+            HostSchedule::Cron(schedule) => Box::new(schedule.after(after)),
+            HostSchedule::Interval(seconds) => {
+                let timezone = after.timezone();
+                let first = (after.timestamp().div_euclid(*seconds) + 1) * seconds;
+                Box::new((0..).map_while(move |i| timezone.timestamp_opt(first + i * seconds, 0).single()))
+            },
+            HostSchedule::Once(run_at) => {
+                let run_at = run_at.with_timezone(&after.timezone());
+                Box::new((run_at > *after).then_some(run_at).into_iter())
+            },
+        }
+    }
+}
+
+impl fmt::Display for HostSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostSchedule::Cron(schedule) => write!(f, "{}", schedule),
+            HostSchedule::Interval(seconds) => {
+                let (count, unit) = [(86400, "d"), (3600, "h"), (60, "m")].iter()
+                    .find(|(unit, _)| seconds % unit == 0)
+                    .map(|(unit, name)| (seconds / unit, *name))
+                    .unwrap_or((*seconds, "s"));
+                write!(f, "every {}{}", count, unit)
+            },
+            HostSchedule::Once(run_at) => write!(f, "once at {}", run_at.format("%Y-%m-%dT%H:%M")),
+        }
+    }
+}
+
+/// Parses an `interval` as a count and a unit of s, m, h or d, e.g. `6h`
+/// or `90m`, into seconds. The scheduler ticks once a minute, so it has to
+/// be whole minutes.
+pub fn parse_interval(interval: &str) -> Option<i64> {
+    let interval = interval.trim();
+    let split = interval.find(|c: char| !c.is_ascii_digit())?;
+    let count: i64 = interval[..split].parse().ok()?;
+    let unit = match interval[split..].trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+
+    Some(count.checked_mul(unit)?).filter(|seconds| *seconds > 0 && seconds % 60 == 0)
+}
+
+/// Parses the schedule of `host`: its `run_at`, `interval` or
+/// `cron_schedule`, of which it may only have one, falling back to
+/// `DEFAULT_CRON`
+pub fn host_schedule(host: &Host) -> Result<HostSchedule, Trap> {
+    let config = &host.config;
+    let set = [config.cron_schedule.is_some(), config.interval.is_some(), config.run_at.is_some()];
+    if set.iter().filter(|set| **set).count() > 1 {
+        return Err(Trap::InvalidInput(format!("`{}` has more than one of cron_schedule, interval and run_at", host.hostname)));
+    }
+
+    if let Some(run_at) = &config.run_at {
+        return NaiveDateTime::parse_from_str(run_at.trim(), "%Y-%m-%dT%H:%M").ok()
+            .and_then(|run_at| Local.from_local_datetime(&run_at).earliest())
+            .map(HostSchedule::Once)
+            .ok_or(Trap::InvalidInput(format!("Invalid run_at for `{}`: `{}`, expected e.g. `2025-01-01T00:00`", host.hostname, run_at)));
+    }
+
+    if let Some(interval) = &config.interval {
+        return parse_interval(interval)
+            .map(HostSchedule::Interval)
+            .ok_or(Trap::InvalidInput(format!("Invalid interval for `{}`: `{}`, expected whole minutes as e.g. `90m` or `6h`", host.hostname, interval)));
+    }
+
+    let cron_schedule = config.cron_schedule.as_deref().unwrap_or(DEFAULT_CRON);
+    Schedule::from_str(cron_schedule).map(|schedule| HostSchedule::Cron(Box::new(schedule))).map_err(|err| {
+        Trap::InvalidInput(format!("Invalid Cron Expression for `{}`: {}", host.hostname, err))
+    })
+}
+
+/// Parses the `verify_schedule` of `host`, None if it has none
+pub fn verify_schedule(host: &Host) -> Result<Option<Schedule>, Trap> {
+    host.config.verify_schedule.as_deref()
+        .map(|cron| Schedule::from_str(cron).map_err(|err| {
+            Trap::InvalidInput(format!("Invalid verify_schedule for `{}`: {}", host.hostname, err))
+        }))
+        .transpose()
+}
+
+/// Whether a run was scheduled between `last_run` and `now`. A host that has
+/// never been backed up is always due, unless its one run is still to come.
+pub fn is_due<T: TimeZone>(schedule: &HostSchedule, last_run: Option<i64>, now: &DateTime<T>) -> bool {
+    if let HostSchedule::Once(run_at) = schedule {
+        return *run_at <= *now && last_run.is_none_or(|last_run| last_run < run_at.timestamp());
+    }
+
+    let last_run = match last_run.and_then(|last_run| Local.timestamp_opt(last_run, 0).single()) {
+        Some(last_run) => last_run,
+        None => return true,
+    };
+
+    schedule.after(&last_run)
+        .next()
+        .is_some_and(|next| next <= *now)
+}
+
+/// The next `count` times after `now` the scheduler fires for `schedule`
+pub fn preview<T: TimeZone>(schedule: &HostSchedule, now: &DateTime<T>, count: usize) -> Vec<DateTime<T>> {
+    schedule.after(now).take(count).collect()
+}
+
+#[test]
+fn test_is_due() {
+    let schedule = HostSchedule::Cron(Box::new(Schedule::from_str("0 0 * * * *").unwrap())); // hourly
+    let now = Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+    let at = |hour: u32, minute: u32| Local.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap();
+
+    assert!(is_due(&schedule, None, &now));
+    assert!(is_due(&schedule, Some(at(11, 30).timestamp()), &now));
+    assert!(!is_due(&schedule, Some(at(12, 5).timestamp()), &now));
+
+    // Every 45 minutes, at multiples of it since the epoch
+    let interval = HostSchedule::Interval(parse_interval("45m").unwrap());
+    let next = interval.after(&at(12, 5)).next().unwrap();
+    assert!(next > at(12, 5) && next <= at(12, 50) && next.timestamp() % 2700 == 0);
+    assert_eq!(interval.after(&next).next().unwrap(), next + chrono::Duration::minutes(45));
+    assert!(is_due(&interval, Some(next.timestamp() - 60), &next));
+    assert!(!is_due(&interval, Some(next.timestamp()), &next));
+    assert_eq!(interval.to_string(), "every 45m");
+
+    // A single run, not due before it, and never again after it
+    let once = HostSchedule::Once(at(13, 0));
+    assert!(!is_due(&once, None, &now));
+    assert!(is_due(&once, None, &at(13, 0)));
+    assert!(is_due(&once, Some(at(11, 0).timestamp()), &at(14, 0)));
+    assert!(!is_due(&once, Some(at(13, 0).timestamp()), &at(14, 0)));
+    assert_eq!(once.after(&now).collect::<Vec<_>>(), vec![at(13, 0)]);
+    assert_eq!(once.after(&at(13, 0)).count(), 0);
+
+    assert_eq!(parse_interval("6h"), Some(6 * 3600));
+    assert_eq!(parse_interval("90 m"), Some(90 * 60));
+    assert_eq!(parse_interval("30s"), None);
+    assert_eq!(parse_interval("0h"), None);
+    assert_eq!(parse_interval("6 hours"), None);
+}
+
+#[test]
+fn test_preview() {
+    let schedule = HostSchedule::Cron(Box::new(Schedule::from_str("0 30 2 * * Mon,Thu").unwrap()));
+    let now = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap(); // a Wednesday
+
+    let times = preview(&schedule, &now, 3);
+    assert_eq!(times, vec![
+        Local.with_ymd_and_hms(2024, 5, 2, 2, 30, 0).unwrap(),
+        Local.with_ymd_and_hms(2024, 5, 6, 2, 30, 0).unwrap(),
+        Local.with_ymd_and_hms(2024, 5, 9, 2, 30, 0).unwrap(),
+    ]);
+}
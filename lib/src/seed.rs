@@ -0,0 +1,385 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{GlobalConfig, Host, HostConfig};
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::histogram::Histogram;
+use crate::results::SeedReport;
+use crate::snapshot::FileEntry;
+use crate::traits::JsonFile;
+use crate::utils::{make_tar_gz_with, ArchiveOptions, get_datetime};
+
+/// Files exported between two saves of the manifest, at most this many are
+/// copied again after an interruption
+const SAVE_EVERY: usize = 256;
+
+/// A file on the seed media
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SeedFile {
+    pub size: u64,
+    pub mtime: u64,
+}
+
+/// What a seed on removable media holds. The files are laid out the way a
+/// snapshot is, under $media/$identifier/$snapshot/, with this manifest at
+/// $media/$identifier/seed.json.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SeedManifest {
+    pub hostname: String,
+    pub identifier: String,
+    pub source: PathBuf,
+    pub snapshot: String,                  // taken from when the export started
+    pub complete: bool,                    // set once an export ran to the end
+    pub files: BTreeMap<PathBuf, SeedFile>, // by path relative to `source`
+}
+
+impl SeedManifest {
+    pub fn path(media: &Path, host_config: &HostConfig) -> PathBuf {
+        media.join(host_config.repo_path()).join("seed.json")
+    }
+}
+
+impl JsonFile for SeedManifest {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(file_path)?);
+        serde_json::to_writer_pretty(&mut writer, &self)?;
+        writer.flush()
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(SeedManifest::default()),
+        };
+
+        let manifest: SeedManifest = serde_json::from_reader(BufReader::new(file))?;
+        Ok(manifest)
+    }
+}
+
+/// Parses a rate like `500K`, `20M` or `1G` (per second) into bytes
+pub fn parse_rate(rate: &str) -> Result<u64, Trap> {
+    let rate = rate.trim();
+    let (digits, unit) = match rate.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => rate.split_at(i),
+        None => (rate, ""),
+    };
+
+    let multiplier: u64 = match unit.to_uppercase().trim_end_matches("B") {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(Trap::InvalidInput(format!("Invalid rate `{}`, expected e.g. 500K, 20M or 1G", rate))),
+    };
+
+    digits.parse::<u64>()
+        .map(|value| value * multiplier)
+        .map_err(|err| Trap::InvalidInput(format!("Invalid rate `{}`: {}", rate, err)))
+}
+
+/// Keeps the average rate of copied bytes under `limit`, by sleeping
+pub(crate) struct Throttle {
+    limit: Option<u64>, // bytes per second
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(limit: Option<u64>) -> Self {
+        Throttle { limit, started: Instant::now(), bytes: 0 }
+    }
+
+    /// How long to wait once `bytes` more went through, to stay under the limit
+    pub(crate) fn delay(&mut self, bytes: usize) -> Option<Duration> {
+        self.bytes += bytes as u64;
+        let limit = self.limit.filter(|limit| *limit > 0)?;
+        Duration::from_secs_f64(self.bytes as f64 / limit as f64).checked_sub(self.started.elapsed())
+    }
+
+    fn consumed(&mut self, bytes: usize) {
+        if let Some(ahead) = self.delay(bytes) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+fn mtime_of(metadata: &fs::Metadata) -> u64 {
+    metadata.modified().ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs())
+        .unwrap_or(0)
+}
+
+/// Copies `source` to `destination` and gives it `mtime`, returning the bytes copied
+fn copy_file(source: &Path, destination: &Path, mtime: u64, throttle: &mut Throttle) -> io::Result<u64> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut reader = File::open(source)?;
+    let mut writer = File::create(destination)?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+        throttle.consumed(read);
+    }
+
+    writer.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+    Ok(copied)
+}
+
+/// Whether `path` already holds the copy of `file`
+fn is_copied(path: &Path, file: &SeedFile) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.len() == file.size && mtime_of(&metadata) == file.mtime)
+}
+
+/// The directory the source's files go in below a snapshot, as in backups
+fn source_dir(host_config: &HostConfig) -> PathBuf {
+    match host_config.source.file_stem() {
+        Some(stem) => PathBuf::from(stem),
+        None => PathBuf::from(&host_config.identifier),
+    }
+}
+
+/// Every regular file below `dir`, relative to `root`, sorted
+fn walk(root: &Path, dir: &Path, files: &mut Vec<PathBuf>, skipped: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(_) => {
+            skipped.push(dir.to_path_buf());
+            return;
+        },
+    };
+    entries.sort();
+
+    for path in entries {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => walk(root, &path, files, skipped),
+            Ok(metadata) if metadata.is_file() => files.push(path.strip_prefix(root).unwrap_or(&path).to_path_buf()),
+            Ok(_) => (),
+            Err(_) => skipped.push(path),
+        }
+    }
+}
+
+/// Copies the source of `host` onto `media`, to be carried to the backup
+/// server and imported there. Runs at the source site, where the host's
+/// `source` is a local path. An interrupted export picks up where it left
+/// off, files whose copy is in the manifest are not read again.
+pub fn export_seed(host: &Host, media: &Path, limit: Option<u64>) -> Result<SeedReport, Trap> {
+    let host_config = &host.config;
+    if !host_config.source.is_dir() {
+        return Err(Trap::Seed(format!("Source {:?} is not a directory here, export from the source site", host_config.source)));
+    }
+
+    let manifest_path = SeedManifest::path(media, host_config);
+    let mut manifest = SeedManifest::deserialize_json(&manifest_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", manifest_path, err)))?;
+
+    if manifest.snapshot.is_empty() {
+        manifest = SeedManifest {
+            hostname: host.hostname.clone(),
+            identifier: host_config.identifier.clone(),
+            source: host_config.source.clone(),
+            snapshot: get_datetime(),
+            ..Default::default()
+        };
+    } else if manifest.source != host_config.source {
+        return Err(Trap::Seed(format!("{:?} holds a seed of {:?}, not {:?}", manifest_path, manifest.source, host_config.source)));
+    }
+
+    let seed_root = media.join(host_config.repo_path()).join(&manifest.snapshot).join(source_dir(host_config));
+    fs::create_dir_all(&seed_root)
+        .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", seed_root, err)))?;
+
+    let mut report = SeedReport { snapshot: manifest.snapshot.clone(), ..Default::default() };
+    let mut files = Vec::new();
+    walk(&host_config.source, &host_config.source, &mut files, &mut report.skipped);
+
+    // Gone from the source since an earlier, interrupted export
+    manifest.complete = false;
+    manifest.files.retain(|path, _| files.binary_search(path).is_ok());
+
+    let mut throttle = Throttle::new(limit);
+    let mut unsaved = 0;
+    for path in files {
+        let source = host_config.source.join(&path);
+        let destination = seed_root.join(&path);
+        let metadata = match fs::metadata(&source) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                report.skipped.push(source);
+                continue;
+            },
+        };
+
+        let file = SeedFile { size: metadata.len(), mtime: mtime_of(&metadata) };
+        if manifest.files.get(&path) == Some(&file) && is_copied(&destination, &file) {
+            report.resumed += 1;
+            continue;
+        }
+
+        match copy_file(&source, &destination, file.mtime, &mut throttle) {
+            Ok(size) => {
+                manifest.files.insert(path, SeedFile { size, ..file });
+                report.files += 1;
+                report.bytes += size;
+            },
+            Err(_) => {
+                report.skipped.push(source);
+                continue;
+            },
+        }
+
+        unsaved += 1;
+        if unsaved >= SAVE_EVERY {
+            manifest.serialize_json(&manifest_path)
+                .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", manifest_path, err)))?;
+            unsaved = 0;
+        }
+    }
+
+    manifest.complete = true;
+    manifest.serialize_json(&manifest_path)
+        .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", manifest_path, err)))?;
+
+    Ok(report)
+}
+
+/// Imports the seed of `host` on `media` as its first snapshot, with a record
+/// listing every file at the mtime it had at the source, so the first run
+/// over the network only fetches what changed since the export. Refuses
+/// hosts that already have backups. An interrupted import can be run again.
+pub fn import_seed(global_config: &GlobalConfig, host: &Host, media: &Path) -> Result<SeedReport, Trap> {
+    let host_config = &host.config;
+    global_config.ensure_writable(&format!("import a seed of `{}`", host.hostname))?;
+
+    let manifest_path = SeedManifest::path(media, host_config);
+    if !manifest_path.is_file() {
+        return Err(Trap::Seed(format!("No seed of `{}` at {:?}", host.hostname, media)));
+    }
+
+    let manifest = SeedManifest::deserialize_json(&manifest_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", manifest_path, err)))?;
+
+    if !manifest.complete {
+        return Err(Trap::Seed(format!("The seed at {:?} is incomplete, run the export again first", media)));
+    }
+    if manifest.source != host_config.source {
+        return Err(Trap::Seed(format!("{:?} holds a seed of {:?}, not {:?}", manifest_path, manifest.source, host_config.source)));
+    }
+
+    let host_root = host_config.root(global_config);
+    let record_dir = host_root.join(".records");
+    let record_path = record_dir.join("record.json");
+    let existing = Record::deserialize_json(&record_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+    if !existing.snapshot.entries.is_empty() {
+        return Err(Trap::Seed(format!("`{}` already has backups, seeds are for the first one only", host.hostname)));
+    }
+
+    let snapshot_root = host_root.join(&manifest.snapshot);
+    let seed_root = media.join(host_config.repo_path()).join(&manifest.snapshot).join(source_dir(host_config));
+    let destination_root = snapshot_root.join(source_dir(host_config));
+    fs::create_dir_all(&record_dir)
+        .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", record_dir, err)))?;
+
+    let mut report = SeedReport { snapshot: manifest.snapshot.clone(), ..Default::default() };
+    let mut record = Record::new();
+    let mut throttle = Throttle::new(None);
+
+    for (path, file) in manifest.files.iter() {
+        let destination = destination_root.join(path);
+        if is_copied(&destination, file) {
+            report.resumed += 1;
+        } else {
+            let seeded = seed_root.join(path);
+            let size = copy_file(&seeded, &destination, file.mtime, &mut throttle)
+                .map_err(|err| Trap::Copy(format!("Could not import {:?}: {}", seeded, err)))?;
+            if size != file.size {
+                return Err(Trap::Seed(format!("{:?} is {} bytes on the media, the manifest says {}", seeded, size, file.size)));
+            }
+            report.files += 1;
+            report.bytes += size;
+        }
+
+        record.snapshot.entries.insert(
+            host_config.source.join(path),
+            FileEntry::from(destination, snapshot_root.clone(), file.mtime, file.size),
+        );
+        record.size += file.size;
+    }
+
+    let options = ArchiveOptions::for_host(global_config, host_config);
+    record.codec = Some(options.codec);
+    record.histogram = Some(Histogram::of(&record.snapshot, &snapshot_root));
+    make_tar_gz_with(&snapshot_root, options.codec.archive(&snapshot_root), &options)
+        .map_err(|err| Trap::FS(format!("Could not archive {:?}: {}", snapshot_root, err)))?;
+
+    // Written last, the host counts as backed up once the archive is there
+    let snapshot_record_path = record_dir.join(format!("{}.json", manifest.snapshot));
+    for path in [&snapshot_record_path, &record_path] {
+        record.serialize_json(path)
+            .map_err(|err| Trap::Serialize(format!("Could not write record {:?}: {}", path, err)))?;
+    }
+
+    Ok(report)
+}
+
+#[test]
+fn test_seed_round_trip() {
+    let root = std::env::temp_dir().join("rensen_test_seed");
+    let _ = fs::remove_dir_all(&root);
+    let source = root.join("srv");
+    let media = root.join("media");
+    fs::create_dir_all(source.join("sub")).unwrap();
+    fs::write(source.join("a"), "alpha").unwrap();
+    fs::write(source.join("sub").join("b"), "beta").unwrap();
+
+    let global_config = GlobalConfig { backups: root.join("backups"), ..Default::default() };
+    let host = Host {
+        hostname: String::from("host"),
+        config: HostConfig { identifier: String::from("host"), source: source.clone(), ..Default::default() },
+    };
+
+    let report = export_seed(&host, &media, None).unwrap();
+    assert_eq!((report.files, report.bytes, report.resumed), (2, 9, 0));
+
+    // Resumed, only the changed file is copied again
+    fs::write(source.join("a"), "alpha, changed").unwrap();
+    let report = export_seed(&host, &media, Some(1 << 30)).unwrap();
+    assert_eq!((report.files, report.resumed), (1, 1));
+
+    let report = import_seed(&global_config, &host, &media).unwrap();
+    assert_eq!(report.files, 2);
+
+    let record = Record::deserialize_json(&global_config.backups.join("host/.records/record.json")).unwrap();
+    let entry = &record.snapshot.entries[&source.join("sub").join("b")];
+    assert_eq!(entry.mtime, mtime_of(&fs::metadata(source.join("sub").join("b")).unwrap()));
+    assert_eq!(entry.file_path, entry.snapshot_path.join("srv").join("sub").join("b"));
+    assert_eq!(record.size, 18);
+    assert!(crate::record::snapshot_archive(&entry.snapshot_path).0.is_file());
+
+    // Only ever the first snapshot
+    assert!(import_seed(&global_config, &host, &media).is_err());
+
+    assert_eq!(parse_rate("20M").unwrap(), 20 << 20);
+    assert_eq!(parse_rate("500kb").unwrap(), 500 << 10);
+    assert!(parse_rate("fast").is_err());
+    let _ = fs::remove_dir_all(&root);
+}
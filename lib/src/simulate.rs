@@ -0,0 +1,148 @@
+use chrono::{DateTime, Duration, Local};
+
+use crate::breaker::Breaker;
+use crate::config::{GlobalConfig, Host};
+use crate::history::RunOutcome;
+use crate::logging::Trap;
+use crate::queue::{expected_duration, FairQueue};
+use crate::retire::Retirement;
+use crate::schedule::host_schedule;
+
+/// What became of one scheduled backup in a simulation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Simulated {
+    Queued,                 // waiting for a slot, only while simulating
+    Ran { started: DateTime<Local>, finished: DateTime<Local> },
+    Skipped(String),        // why it never ran
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedRun {
+    pub hostname: String,
+    pub due: DateTime<Local>,
+    pub outcome: Simulated,
+}
+
+/// Runs the scheduling of rensend for `hosts` in virtual time over the `days`
+/// after `start`, the way it would with the config as it is: every backup
+/// due by a `cron_schedule` is queued unless the last one of the host is
+/// still queued or running or its breaker holds it back, and started in the
+/// order of the fair queue once one of the `max_concurrent_backups` slots is
+/// free. Each backup takes as long as the latest runs of the host in `runs`.
+/// Retired hosts have none. Returns the backups in the order they were due.
+pub fn simulate(global_config: &GlobalConfig, hosts: &[&Host], runs: &[RunOutcome], start: &DateTime<Local>, days: i64) -> Result<Vec<SimulatedRun>, Trap> {
+    let slots = global_config.max_concurrent_backups.unwrap_or(usize::MAX).max(1);
+    let horizon = *start + Duration::days(days);
+
+    // In the order rensend looks at the hosts within a minute
+    let mut fires: Vec<(DateTime<Local>, usize)> = Vec::new();
+    let mut breakers = Vec::new();
+    for (index, host) in hosts.iter().enumerate() {
+        breakers.push(Breaker::load(global_config, &host.config)?);
+        if Retirement::load(global_config, &host.config)?.is_some() {
+            continue;
+        }
+
+        fires.extend(host_schedule(host)?.after(start).take_while(|due| *due < horizon).map(|due| (due, index)));
+    }
+    fires.sort();
+
+    let mut simulated: Vec<SimulatedRun> = Vec::new();
+    let mut queue: FairQueue<usize> = FairQueue::new();
+    let mut running: Vec<(DateTime<Local>, String)> = Vec::new(); // (finishes, hostname)
+    let mut fires = fires.into_iter().peekable();
+
+    loop {
+        let next_fire = fires.peek().map(|(due, _)| *due);
+        let next_finish = running.iter().map(|(finishes, _)| *finishes).min();
+        let Some(now) = next_fire.into_iter().chain(next_finish).min() else { break };
+
+        // Slots freed at the same time are free for what is due then
+        running.retain(|(finishes, _)| *finishes > now);
+
+        while let Some((_, index)) = fires.next_if(|(due, _)| *due == now) {
+            let host = hosts[index];
+            let hostname = &host.hostname;
+            let outcome = if breakers[index].holds(global_config, now.timestamp()) {
+                Simulated::Skipped(format!("held back after {} failed runs", breakers[index].failures))
+            } else if running.iter().any(|(_, running)| running == hostname) || queue.contains(hostname) {
+                Simulated::Skipped(String::from("its last backup is still queued or running"))
+            } else {
+                queue.push(hostname, expected_duration(runs, hostname), host.config.is_critical(), simulated.len());
+                Simulated::Queued
+            };
+
+            simulated.push(SimulatedRun { hostname: hostname.clone(), due: now, outcome });
+        }
+
+        while running.len() < slots {
+            let Some((hostname, index)) = queue.pop() else { break };
+            let finished = now + Duration::seconds(expected_duration(runs, &hostname) as i64);
+            simulated[index].outcome = Simulated::Ran { started: now, finished };
+            running.push((finished, hostname));
+        }
+    }
+
+    Ok(simulated)
+}
+
+/// The most backups running at once in `simulated`
+pub fn peak_concurrency(simulated: &[SimulatedRun]) -> usize {
+    let mut changes: Vec<(DateTime<Local>, i64)> = simulated.iter()
+        .filter_map(|run| match run.outcome {
+            Simulated::Ran { started, finished } => Some([(started, 1), (finished, -1)]),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    // Ends before starts at the same time, the slot is handed over
+    changes.sort();
+
+    changes.iter()
+        .scan(0, |running, (_, change)| { *running += change; Some(*running) })
+        .max()
+        .unwrap_or(0) as usize
+}
+
+#[test]
+fn test_simulate() {
+    use chrono::TimeZone;
+    use crate::config::HostConfig;
+
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_simulate"),
+        max_concurrent_backups: Some(2),
+        ..Default::default()
+    };
+    let host = |hostname: &str, cron: &str, critical: bool| Host {
+        hostname: hostname.to_string(),
+        config: HostConfig { identifier: hostname.to_string(), cron_schedule: Some(cron.to_string()), critical: Some(critical), ..Default::default() },
+    };
+    let hosts = [
+        host("web", "0 0 1 * * *", false),
+        host("db", "0 0 1 * * *", false),
+        host("mail", "0 0 1 * * *", true),
+        host("logs", "0 0 * * * *", false), // hourly, but takes two hours
+    ];
+    let runs: Vec<RunOutcome> = [("web", 600), ("db", 1800), ("mail", 300), ("logs", 7200)].iter()
+        .map(|(hostname, seconds)| RunOutcome { hostname: hostname.to_string(), started: 0, finished: *seconds, success: true, ..Default::default() })
+        .collect();
+
+    let start = Local.with_ymd_and_hms(2024, 5, 1, 0, 30, 0).unwrap();
+    let at = |hour: u32, minute: u32| Local.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap();
+    let simulated = simulate(&global_config, &hosts.iter().collect::<Vec<_>>(), &runs, &start, 1).unwrap();
+    let of = |hostname: &str| simulated.iter().filter(|run| run.hostname == hostname).collect::<Vec<_>>();
+
+    // The critical mail and web go first at 01:00, db takes the slot mail
+    // frees and logs the one of web, so its next two hours are skipped
+    assert_eq!(of("mail")[0].outcome, Simulated::Ran { started: at(1, 0), finished: at(1, 5) });
+    assert_eq!(of("web")[0].outcome, Simulated::Ran { started: at(1, 0), finished: at(1, 10) });
+    assert_eq!(of("db")[0].outcome, Simulated::Ran { started: at(1, 5), finished: at(1, 35) });
+    let logs = of("logs");
+    assert_eq!(logs[0].outcome, Simulated::Ran { started: at(1, 10), finished: at(3, 10) });
+    assert!(matches!(logs[1].outcome, Simulated::Skipped(_)) && matches!(logs[2].outcome, Simulated::Skipped(_)));
+    assert_eq!(logs[3].outcome, Simulated::Ran { started: at(4, 0), finished: at(6, 0) });
+    assert_eq!(simulated.len(), 3 + 24);
+    assert_eq!(peak_concurrency(&simulated), 2);
+    assert!(simulated.iter().all(|run| run.outcome != Simulated::Queued));
+}
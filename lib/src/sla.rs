@@ -0,0 +1,155 @@
+use serde::{Serialize, Deserialize};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use chrono::{DateTime, Local, NaiveTime, TimeZone, Duration};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::logging::Trap;
+use crate::notify::alert;
+use crate::traits::JsonFile;
+
+/// Outcome of one run measured against the host's SLA
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlaEntry {
+    pub started: i64,
+    pub deadline: i64,
+    pub finished: i64,
+    pub bytes: u64,
+    pub hit: bool,
+}
+
+/// Every SLA hit/miss recorded for a host.
+/// Stored next to the records, at `.records/sla.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SlaLedger {
+    pub entries: Vec<SlaEntry>,
+}
+
+impl SlaLedger {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        global_config.backups
+            .join(&host_config.identifier)
+            .join(".records")
+            .join("sla.json")
+    }
+
+    pub fn hits(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.hit).count()
+    }
+
+    pub fn misses(&self) -> usize {
+        self.entries.len() - self.hits()
+    }
+
+    /// Bytes transferred by the last run, used as the estimate for the next one
+    pub fn expected_bytes(&self) -> Option<u64> {
+        self.entries.last().map(|entry| entry.bytes)
+    }
+}
+
+impl JsonFile for SlaLedger {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(SlaLedger::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let ledger: SlaLedger = serde_json::from_str(&contents)?;
+        Ok(ledger)
+    }
+}
+
+/// Returns the first time matching `sla` ("HH:MM") after `start`.
+/// A run starting at 23:00 with the SLA `06:00` is due the next morning.
+pub fn deadline_after(start: DateTime<Local>, sla: &str) -> Result<DateTime<Local>, Trap> {
+    let time = NaiveTime::parse_from_str(sla.trim(), "%H:%M")
+        .map_err(|err| Trap::Config(format!("Invalid sla `{}`, expected HH:MM: {}", sla, err)))?;
+
+    let mut date = start.date_naive();
+    loop {
+        if let Some(deadline) = Local.from_local_datetime(&date.and_time(time)).earliest() {
+            if deadline > start {
+                return Ok(deadline);
+            }
+        }
+        date += Duration::days(1);
+    }
+}
+
+/// Keeps an eye on a running backup, predicting from its current
+/// throughput whether it will make its deadline.
+pub struct SlaWatch {
+    pub deadline: DateTime<Local>,
+    pub expected_bytes: Option<u64>,
+    pub escalate: bool,
+    started: Instant,
+    escalated: Cell<bool>,
+}
+
+impl SlaWatch {
+    pub fn new(deadline: DateTime<Local>, expected_bytes: Option<u64>, escalate: bool) -> Self {
+        Self {
+            deadline,
+            expected_bytes,
+            escalate,
+            started: Instant::now(),
+            escalated: Cell::new(false),
+        }
+    }
+
+    /// Predicted finishing time given `bytes_done` so far.
+    /// None if there is nothing to base the prediction on yet.
+    pub fn predicted_finish(&self, bytes_done: u64) -> Option<DateTime<Local>> {
+        let expected = self.expected_bytes?;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if bytes_done == 0 || elapsed == 0.0 {
+            return None;
+        }
+
+        let rate = bytes_done as f64 / elapsed;
+        let remaining = expected.saturating_sub(bytes_done) as f64 / rate;
+        Some(Local::now() + Duration::seconds(remaining as i64))
+    }
+
+    /// Escalates (once per run) if the run is predicted to miss its deadline
+    pub fn check(&self, global_config: &GlobalConfig, hostname: &str, bytes_done: u64) {
+        if !self.escalate || self.escalated.get() {
+            return;
+        }
+
+        if let Some(finish) = self.predicted_finish(bytes_done) {
+            if finish > self.deadline {
+                self.escalated.set(true);
+                alert(global_config, hostname, &Trap::Sla(format!(
+                    "Backup of `{}` is predicted to finish at {}, missing its deadline at {}",
+                    hostname, finish.format("%H:%M"), self.deadline.format("%H:%M")
+                )));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_deadline_after() {
+    let start = Local.with_ymd_and_hms(2024, 5, 15, 23, 0, 0).unwrap();
+    let deadline = deadline_after(start, "06:00").unwrap();
+    assert_eq!(deadline, Local.with_ymd_and_hms(2024, 5, 16, 6, 0, 0).unwrap());
+
+    let start = Local.with_ymd_and_hms(2024, 5, 15, 1, 0, 0).unwrap();
+    let deadline = deadline_after(start, "06:00").unwrap();
+    assert_eq!(deadline, Local.with_ymd_and_hms(2024, 5, 15, 6, 0, 0).unwrap());
+
+    assert!(deadline_after(start, "6 o'clock").is_err());
+}
@@ -1,19 +1,45 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeSet;
+use std::fs;
 use std::path::PathBuf;
 use serde::{Serialize, Deserialize};
 use std::cmp::Ordering;
-use std::fmt::{Display, Result, Formatter};
+use std::fmt::{self, Display, Formatter};
 use fxhash::FxHashMap;
 use std::rc::Rc;
-use std::thread;
+
+use crate::codec::Codec;
+use crate::compact::snapshot_time;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::index::{files_below, IndexedFile, SnapshotIndex};
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::special::SpecialEntry;
+use crate::traits::JsonFile;
+use crate::verify::snapshots;
 
 /// Wrapper for PathBuf holding its mtime as u64
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
     pub file_path: PathBuf, 
     pub snapshot_path: PathBuf, // root path (no extension)
     pub mtime: u64,
     pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<String>>, // digests in the chunk store of hosts with `dedup`, the snapshot holds an empty file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,        // SHA3-256 of the contents as fetched, none in records from before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample: Option<String>,      // sample_digest of the contents as fetched, with `change_detection: sampled`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,            // owner on the source, none for rsyncd sources and records from before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+}
+
+impl Default for FileEntry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FileEntry {
@@ -23,6 +49,11 @@ impl FileEntry {
             snapshot_path: PathBuf::new(),
             mtime: u64::MIN,
             size: u64::MIN,
+            chunks: None,
+            hash: None,
+            sample: None,
+            uid: None,
+            gid: None,
         }
     }
 
@@ -32,6 +63,11 @@ impl FileEntry {
             snapshot_path,
             mtime,
             size,
+            chunks: None,
+            hash: None,
+            sample: None,
+            uid: None,
+            gid: None,
         }
     }
 }
@@ -68,23 +104,32 @@ impl PartialOrd for PathPair {
 
 /// Entries containing the mtime of files.
 /// Using the source path as key, we can get data.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Snapshot {
     pub entries: FxHashMap<PathBuf, FileEntry>,
     pub deleted_entries: BTreeSet<PathPair>,
+    #[serde(default, skip_serializing_if = "FxHashMap::is_empty")]
+    pub specials: FxHashMap<PathBuf, SpecialEntry>, // symlinks, further hard links and the like, as the latest run found them
 }
 
 impl Display for Snapshot {
-    fn fmt(&self, f: &mut Formatter) -> Result {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "Snapshot: {{\n\tentries: {:?},\n\tdeleted_entries: {:?}\n\t\n}}", self.entries, self.deleted_entries)
     }
 }
 
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Snapshot {
     pub fn new() -> Self {
         Snapshot {
             entries: FxHashMap::default(),
             deleted_entries: BTreeSet::new(),
+            specials: FxHashMap::default(),
         }
     }
 
@@ -121,3 +166,115 @@ impl Snapshot {
         self.entries.get(key).map(|entry| &entry.size)
     }
 }
+
+/// What the destination holds of one snapshot of a host, as listed by
+/// Snapshot::list
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotMeta {
+    pub name: String,                // e.g. 2024-05-15-08-10-30
+    pub time: i64,                   // unix seconds it was taken at
+    pub size: u64,                   // bytes backed up
+    pub files: u64,
+    pub deleted: u64,
+    pub compacted: bool,             // only the counts are left of its record
+    pub archive: Option<PathBuf>,    // its .tar.gz or what its codec names it, none while it is a tree or kept in another store
+    pub archive_size: Option<u64>,
+    pub codec: Option<Codec>,        // its archive is compressed with, as its record says
+    #[serde(skip)]
+    root: PathBuf,
+}
+
+impl Snapshot {
+    /// The snapshots of `host_config` in the destination, oldest first. Their
+    /// sizes and counts come from the index where it is current, from the
+    /// records otherwise.
+    pub fn list(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Vec<SnapshotMeta>, Trap> {
+        let root = host_config.root(global_config);
+        let records_path = root.join(".records");
+        let index = SnapshotIndex::load(global_config, host_config);
+
+        let mut listed = Vec::new();
+        for name in snapshots(global_config, host_config) {
+            let record_path = records_path.join(format!("{}.json", name));
+            let (size, files, deleted, compacted, codec) = match index.lookup(&name, &record_path) {
+                Some(indexed) => (indexed.size, indexed.files, indexed.deleted, indexed.compacted, indexed.codec.unwrap_or_default()),
+                None => {
+                    let record = Record::deserialize_json(&record_path)
+                        .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+                    let codec = record.codec.unwrap_or_default();
+                    match record.summary {
+                        Some(summary) => (record.size, summary.files, summary.deleted, true, codec),
+                        None => (record.size, record.snapshot.entries.len() as u64, record.snapshot.deleted_entries.len() as u64, false, codec),
+                    }
+                },
+            };
+
+            let archive = codec.archive(&root.join(&name));
+            let archive_size = fs::metadata(&archive).ok().map(|metadata| metadata.len());
+            listed.push(SnapshotMeta {
+                time: snapshot_time(&name).unwrap_or_default(),
+                archive: archive_size.map(|_| archive),
+                archive_size,
+                codec: archive_size.map(|_| codec),
+                name,
+                size,
+                files,
+                deleted,
+                compacted,
+                root: root.clone(),
+            });
+        }
+
+        Ok(listed)
+    }
+}
+
+impl SnapshotMeta {
+    /// The files of the snapshot sorted by source path, each with the
+    /// snapshot it is archived in. Empty once the record is compacted.
+    pub fn files(&self) -> Result<Vec<IndexedFile>, Trap> {
+        files_below(&self.root, &self.name)
+    }
+
+    /// Where the snapshot of the host is kept in the destination
+    pub fn path(&self) -> PathBuf {
+        self.root.join(&self.name)
+    }
+
+    pub fn record_path(&self) -> PathBuf {
+        self.root.join(".records").join(format!("{}.json", self.name))
+    }
+}
+
+#[test]
+fn test_snapshot_list() {
+    let global_config = GlobalConfig { backups: std::env::temp_dir().join("rensen_test_snapshot_list"), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let root = host_config.root(&global_config);
+    let _ = fs::remove_dir_all(&global_config.backups);
+    fs::create_dir_all(root.join(".records")).unwrap();
+
+    let mut record = Record::new();
+    record.size = 12;
+    record.snapshot.entries.insert(PathBuf::from("/b"), FileEntry::from(PathBuf::new(), root.join("2024-01-01-00-00-00"), 1, 5));
+    record.snapshot.entries.insert(PathBuf::from("/a"), FileEntry::from(PathBuf::new(), root.join("2024-01-01-00-00-00"), 1, 7));
+    record.codec = Some(Codec::Zstd);
+    record.serialize_json(&root.join(".records/2024-01-02-00-00-00.json")).unwrap();
+    record.compact(0);
+    record.serialize_json(&root.join(".records/2024-01-01-00-00-00.json")).unwrap();
+    Record::new().serialize_json(&root.join(".records/record.json")).unwrap();
+    fs::write(root.join("2024-01-02-00-00-00.tar.zst"), b"archive").unwrap();
+
+    let listed = Snapshot::list(&global_config, &host_config).unwrap();
+    assert_eq!(listed.iter().map(|meta| meta.name.as_str()).collect::<Vec<_>>(), vec!["2024-01-01-00-00-00", "2024-01-02-00-00-00"]);
+    assert!(listed[0].compacted && listed[0].archive.is_none());
+    assert_eq!((listed[1].size, listed[1].files, listed[1].archive_size), (12, 2, Some(7)));
+    assert_eq!((listed[1].archive.clone(), listed[1].codec), (Some(root.join("2024-01-02-00-00-00.tar.zst")), Some(Codec::Zstd)));
+    assert_eq!(listed[1].time, snapshot_time("2024-01-02-00-00-00").unwrap());
+
+    let files = listed[1].files().unwrap();
+    assert_eq!(files.iter().map(|file| (file.path.clone(), file.size)).collect::<Vec<_>>(), vec![(PathBuf::from("/a"), 7), (PathBuf::from("/b"), 5)]);
+    assert!(listed[0].files().unwrap().is_empty());
+    assert!(listed[1].record_path().is_file());
+    let _ = fs::remove_dir_all(&global_config.backups);
+}
@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+
+use tar::{Builder, EntryType, GnuExtSparseHeader, Header};
+
+/// Blocks of zeros this large, at offsets they divide, are left as holes
+/// instead of written
+pub const HOLE_BLOCK: usize = 4096;
+
+/// The offset and length of each run of data of `file`, found with
+/// SEEK_DATA and SEEK_HOLE. None if `file` has no holes, or its file system
+/// does not tell.
+pub fn data_regions(file: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    let metadata = file.metadata()?;
+    let len = metadata.len();
+    // Holding as many blocks as it is long, there is no hole to look for
+    if metadata.blocks() * 512 >= len {
+        return Ok(None);
+    }
+
+    // Data not written out yet is a hole to SEEK_DATA on some file systems
+    file.sync_data()?;
+    let seek = |offset: u64, whence: libc::c_int| -> Option<u64> {
+        match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+            -1 => None,
+            offset => Some(offset as u64),
+        }
+    };
+
+    // Without SEEK_HOLE, or no hole before the end
+    if len == 0 || seek(0, libc::SEEK_HOLE).is_none_or(|hole| hole >= len) {
+        return Ok(None);
+    }
+
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        // ENXIO past the last data, the rest is a hole
+        let Some(data) = seek(offset, libc::SEEK_DATA) else { break };
+        let hole = seek(data, libc::SEEK_HOLE).unwrap_or(len).min(len);
+        if hole > data {
+            regions.push((data, hole - data));
+        }
+        offset = hole;
+    }
+
+    Ok(Some(regions))
+}
+
+/// Copies the `regions` of `file` to `writer`, seeking over the holes in
+/// between, so a `writer` that is a file gets them as well. `len` is the
+/// size of `file`, a hole at its end is kept with a single zero written at
+/// its last byte.
+pub fn copy_regions<W: Write + Seek>(file: &File, regions: &[(u64, u64)], len: u64, writer: &mut W) -> io::Result<()> {
+    let mut end = 0;
+    for (offset, length) in regions {
+        writer.seek(SeekFrom::Start(*offset))?;
+        io::copy(&mut Region { file, offset: *offset, remaining: *length }, writer)?;
+        end = offset + length;
+    }
+
+    if end < len {
+        writer.seek(SeekFrom::Start(len - 1))?;
+        writer.write_all(&[0])?;
+    }
+
+    Ok(())
+}
+
+/// Copies `source` to `destination`, keeping the holes of `source`
+pub fn copy_file(source: &mut File, destination: &mut File) -> io::Result<u64> {
+    let len = source.metadata()?.len();
+    match data_regions(source)? {
+        Some(regions) => copy_regions(source, &regions, len, destination).map(|_| len),
+        None => io::copy(source, destination),
+    }
+}
+
+/// Adds the file at `name` with the `regions` of `file` to `tar_builder`
+/// as a GNU sparse member, holding only the data. `header` is that of a
+/// regular file.
+pub fn append_sparse<W: Write>(tar_builder: &mut Builder<W>, header: &mut Header, name: &str, file: &File, regions: &[(u64, u64)]) -> io::Result<()> {
+    let len = header.size()?;
+    let stored: u64 = regions.iter().map(|(_, length)| length).sum();
+
+    // A hole at the end is an empty region where the file ends
+    let mut listed = regions.to_vec();
+    if listed.last().is_none_or(|(offset, length)| offset + length < len) {
+        listed.push((len, 0));
+    }
+
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(stored);
+    let gnu = header.as_gnu_mut()
+        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "sparse members need a GNU header"))?;
+    gnu.set_real_size(len);
+    for (sparse, (offset, length)) in gnu.sparse.iter_mut().zip(listed.iter()) {
+        sparse.set_offset(*offset);
+        sparse.set_length(*length);
+    }
+    gnu.set_is_extended(listed.len() > gnu.sparse.len());
+
+    // What does not fit into the header follows it, before the data
+    let mut extended = Vec::new();
+    let more: Vec<&(u64, u64)> = listed.iter().skip(gnu.sparse.len()).collect();
+    let count = more.chunks(GnuExtSparseHeader::new().sparse.len()).count();
+    for (i, group) in more.chunks(GnuExtSparseHeader::new().sparse.len()).enumerate() {
+        let mut block = GnuExtSparseHeader::new();
+        for (sparse, (offset, length)) in block.sparse.iter_mut().zip(group.iter()) {
+            sparse.set_offset(*offset);
+            sparse.set_length(*length);
+        }
+        block.set_is_extended(i + 1 < count);
+        extended.extend_from_slice(block.as_bytes());
+    }
+
+    let data = regions.iter().map(|(offset, length)| Region { file, offset: *offset, remaining: *length });
+    let reader = data.fold(Box::new(io::Cursor::new(extended)) as Box<dyn Read>, |reader, region| Box::new(reader.chain(region)));
+    tar_builder.append_data(header, name, reader)
+}
+
+/// A run of data of a file, read at its offset
+struct Region<'a> {
+    file: &'a File,
+    offset: u64,
+    remaining: u64,
+}
+
+impl Read for Region<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let wanted = buf.len().min(self.remaining as usize);
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        let read = self.file.read_at(&mut buf[..wanted], self.offset)?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file got shorter while it was read"));
+        }
+        self.offset += read as u64;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Writes to a file, leaving whole blocks of zeros out as holes. `finish`
+/// has to be called once done, for the file to get its length.
+pub struct SparseWriter<'a> {
+    file: &'a mut File,
+    pending: Vec<u8>, // written since the last whole block
+    position: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    pub fn new(file: &'a mut File) -> io::Result<Self> {
+        let position = file.stream_position()?;
+        Ok(SparseWriter { file, pending: Vec::with_capacity(HOLE_BLOCK), position })
+    }
+
+    /// Writes what is left, and ends the file where the last write did
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file.set_len(self.position)
+    }
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Blocks line up with offsets in the file, one cut short is never a hole
+        let wanted = HOLE_BLOCK - (self.position as usize + self.pending.len()) % HOLE_BLOCK;
+        let taken = wanted.min(buf.len());
+        self.pending.extend_from_slice(&buf[..taken]);
+
+        if taken == wanted {
+            match self.pending.len() == HOLE_BLOCK && self.pending.iter().all(|byte| *byte == 0) {
+                true => { self.file.seek(SeekFrom::Current(HOLE_BLOCK as i64))?; },
+                false => self.file.write_all(&self.pending)?,
+            }
+            self.position += self.pending.len() as u64;
+            self.pending.clear();
+        }
+
+        Ok(taken)
+    }
+
+    /// Writes the block started so far as it is, and a hole left last, so
+    /// the file holds all that was written, e.g. before a checkpoint
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.pending)?;
+        self.position += self.pending.len() as u64;
+        self.pending.clear();
+        if self.file.metadata()?.len() < self.position {
+            self.file.set_len(self.position)?;
+        }
+        self.file.flush()
+    }
+}
+
+#[test]
+fn test_sparse() {
+    use std::fs;
+    use tar::Archive;
+
+    let root = std::env::temp_dir().join("rensen_test_sparse");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    // 2 MiB of zeros between two bits of data, and 2 MiB of them at the end
+    let mut contents = vec![0; 1 << 22];
+    contents[..5].copy_from_slice(b"first");
+    contents[(1 << 21)..(1 << 21) + 6].copy_from_slice(b"second");
+    let path = root.join("disk.img");
+    let mut file = File::create(&path).unwrap();
+    let mut writer = SparseWriter::new(&mut file).unwrap();
+    for piece in contents.chunks(1000) {
+        writer.write_all(piece).unwrap();
+    }
+    writer.finish().unwrap();
+    drop(file);
+    assert_eq!(fs::read(&path).unwrap(), contents);
+
+    // Holes are left where the file system keeps them, tmpfs and most others do
+    let file = File::open(&path).unwrap();
+    let Some(regions) = data_regions(&file).unwrap() else { return };
+    assert!(fs::metadata(&path).unwrap().blocks() * 512 < 1 << 20);
+    assert!(regions.iter().map(|(_, length)| length).sum::<u64>() < 1 << 20);
+
+    let mut copy = File::create(root.join("copy.img")).unwrap();
+    copy_file(&mut File::open(&path).unwrap(), &mut copy).unwrap();
+    assert_eq!(fs::read(root.join("copy.img")).unwrap(), contents);
+
+    // Archived with only the data, unpacked with the holes again
+    let mut tar_builder = Builder::new(Vec::new());
+    let mut header = Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    append_sparse(&mut tar_builder, &mut header, "disk.img", &file, &regions).unwrap();
+    let tar = tar_builder.into_inner().unwrap();
+    assert!(tar.len() < 1 << 20);
+    Archive::new(tar.as_slice()).unpack(root.join("unpacked")).unwrap();
+    assert_eq!(fs::read(root.join("unpacked/disk.img")).unwrap(), contents);
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_sparse_extended() {
+    use std::fs;
+    use tar::Archive;
+
+    let root = std::env::temp_dir().join("rensen_test_sparse_extended");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    // 30 bits of data 128 KiB apart, more than the header and one extension hold
+    let mut contents = vec![0; 30 << 17];
+    for i in 0..30 {
+        contents[i << 17..(i << 17) + 9].copy_from_slice(format!("region {:02}", i).as_bytes());
+    }
+    let path = root.join("disk.img");
+    let mut file = File::create(&path).unwrap();
+    let mut writer = SparseWriter::new(&mut file).unwrap();
+    writer.write_all(&contents).unwrap();
+    writer.finish().unwrap();
+    drop(file);
+
+    let file = File::open(&path).unwrap();
+    let Some(regions) = data_regions(&file).unwrap() else { return };
+    assert_eq!(regions.len(), 30);
+
+    let mut tar_builder = Builder::new(Vec::new());
+    let mut header = Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    append_sparse(&mut tar_builder, &mut header, "disk.img", &file, &regions).unwrap();
+    let tar = tar_builder.into_inner().unwrap();
+    assert!(tar.len() < contents.len() / 4);
+
+    // The contents come back, with the holes left as holes
+    Archive::new(tar.as_slice()).unpack(root.join("unpacked")).unwrap();
+    let unpacked = root.join("unpacked/disk.img");
+    assert_eq!(fs::read(&unpacked).unwrap(), contents);
+    assert!(fs::metadata(&unpacked).unwrap().blocks() * 512 < contents.len() as u64 / 4);
+    assert_eq!(data_regions(&File::open(&unpacked).unwrap()).unwrap().map(|regions| regions.len()), Some(30));
+    let _ = fs::remove_dir_all(&root);
+}
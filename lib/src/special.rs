@@ -0,0 +1,182 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use ssh2::FileStat;
+
+/// What a file that is not backed up as a regular file or directory is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialKind {
+    Symlink,
+    Hardlink, // a further name of a regular file that is backed up under another
+    Fifo,
+    Char,     // character device
+    Block,    // block device
+    Socket,   // recorded, never made again, its server does that
+}
+
+impl SpecialKind {
+    /// The kind of the file `stat` is of, as listed without following
+    /// symlinks. None for regular files and directories.
+    pub fn of(stat: &FileStat) -> Option<SpecialKind> {
+        match stat.perm? & 0o170000 {
+            0o120000 => Some(SpecialKind::Symlink),
+            0o010000 => Some(SpecialKind::Fifo),
+            0o020000 => Some(SpecialKind::Char),
+            0o060000 => Some(SpecialKind::Block),
+            0o140000 => Some(SpecialKind::Socket),
+            _ => None,
+        }
+    }
+}
+
+/// What restores do with FIFOs and device nodes. Symlinks and hard links
+/// are always made again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialFiles {
+    #[default]
+    Skip,
+    Recreate, // device nodes need root, and their device number from the helper
+}
+
+/// A symlink, hard link, FIFO, device node or socket of the source. The
+/// snapshot holds nothing of it, restores make it again from the record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpecialEntry {
+    pub file_path: PathBuf,     // where it goes in the snapshot, as that of a FileEntry
+    pub snapshot_path: PathBuf, // root path (no extension)
+    pub kind: SpecialKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>, // a symlink points at, as read, or the source path a hard link shares its contents with
+    pub mode: u32,               // permission bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    pub mtime: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdev: Option<u64>,       // device number of a device node, none unless the helper listed it
+}
+
+/// The further hard links of each file of `links`, to the one of them
+/// that is backed up: the first by name `kept` allows, e.g. that is not
+/// excluded
+pub fn link_targets<F>(links: &[Vec<PathBuf>], kept: F) -> HashMap<PathBuf, PathBuf>
+where
+    F: Fn(&Path) -> bool
+{
+    let mut targets = HashMap::new();
+    for paths in links {
+        let kept: Vec<&PathBuf> = paths.iter().filter(|path| kept(path)).collect();
+        if let Some((first, others)) = kept.split_first() {
+            targets.extend(others.iter().map(|other| (other.to_path_buf(), first.to_path_buf())));
+        }
+    }
+
+    targets
+}
+
+/// Makes `entry` at `destination`. `linked` is where the file a hard link
+/// shares its contents with was restored to. False for what
+/// `special_files` leaves out and sockets.
+pub fn recreate(entry: &SpecialEntry, destination: &Path, linked: Option<&Path>, special_files: SpecialFiles) -> io::Result<bool> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Anything in the way that is not a directory
+    if fs::symlink_metadata(destination).is_ok_and(|metadata| !metadata.is_dir()) {
+        fs::remove_file(destination)?;
+    }
+
+    let recreated = special_files == SpecialFiles::Recreate;
+    let mode = (entry.mode & 0o7777) as libc::mode_t;
+    match entry.kind {
+        SpecialKind::Symlink => {
+            let target = entry.target.as_ref()
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "the record holds no target"))?;
+            return std::os::unix::fs::symlink(target, destination).map(|_| true);
+        },
+        SpecialKind::Hardlink => {
+            let linked = linked
+                .ok_or(io::Error::new(io::ErrorKind::NotFound, "the file it links to was not restored"))?;
+            return fs::hard_link(linked, destination).map(|_| true);
+        },
+        SpecialKind::Fifo if recreated => mknod(destination, libc::S_IFIFO | mode, 0)?,
+        SpecialKind::Char | SpecialKind::Block if recreated => {
+            let rdev = entry.rdev
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "its device number is unknown, it was backed up without the helper"))?;
+            let kind = match entry.kind {
+                SpecialKind::Char => libc::S_IFCHR,
+                _ => libc::S_IFBLK,
+            };
+            mknod(destination, kind | mode, rdev)?;
+        },
+        _ => return Ok(false),
+    }
+
+    // Not through opening it, which blocks for a FIFO
+    let path = CString::new(destination.as_os_str().as_bytes())?;
+    let times = [libc::timespec { tv_sec: entry.mtime as libc::time_t, tv_nsec: 0 }; 2];
+    unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) };
+    Ok(true)
+}
+
+fn mknod(path: &Path, mode: libc::mode_t, rdev: u64) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    match unsafe { libc::mknod(path.as_ptr(), mode, rdev as libc::dev_t) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[test]
+fn test_special() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let root = std::env::temp_dir().join("rensen_test_special");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let stat = |perm: u32| FileStat { size: None, uid: None, gid: None, perm: Some(perm), atime: None, mtime: None };
+    assert_eq!(SpecialKind::of(&stat(0o120777)), Some(SpecialKind::Symlink));
+    assert_eq!(SpecialKind::of(&stat(0o020666)), Some(SpecialKind::Char));
+    assert_eq!(SpecialKind::of(&stat(0o100644)), None);
+
+    // The first of each file that is kept is backed up, the others link to it
+    let links = vec![vec![PathBuf::from("/srv/a"), PathBuf::from("/srv/b"), PathBuf::from("/srv/c")]];
+    let targets = link_targets(&links, |path| path != Path::new("/srv/a"));
+    assert_eq!(targets, HashMap::from([(PathBuf::from("/srv/c"), PathBuf::from("/srv/b"))]));
+
+    let entry = |kind: SpecialKind, target: Option<&str>| SpecialEntry {
+        file_path: PathBuf::new(),
+        snapshot_path: PathBuf::new(),
+        kind,
+        target: target.map(PathBuf::from),
+        mode: 0o644,
+        uid: None,
+        gid: None,
+        mtime: 1_700_000_000,
+        rdev: None,
+    };
+
+    fs::write(root.join("b"), "contents").unwrap();
+    assert!(recreate(&entry(SpecialKind::Symlink, Some("b")), &root.join("link"), None, SpecialFiles::Skip).unwrap());
+    assert_eq!(fs::read_to_string(root.join("link")).unwrap(), "contents");
+    assert!(recreate(&entry(SpecialKind::Hardlink, Some("/srv/b")), &root.join("c"), Some(&root.join("b")), SpecialFiles::Skip).unwrap());
+    assert!(recreate(&entry(SpecialKind::Hardlink, Some("/srv/b")), &root.join("d"), None, SpecialFiles::Skip).is_err());
+
+    // FIFOs only with `recreate`, device nodes not without their number
+    assert!(!recreate(&entry(SpecialKind::Fifo, None), &root.join("fifo"), None, SpecialFiles::Skip).unwrap());
+    assert!(recreate(&entry(SpecialKind::Fifo, None), &root.join("fifo"), None, SpecialFiles::Recreate).unwrap());
+    assert!(fs::symlink_metadata(root.join("fifo")).unwrap().file_type().is_fifo());
+    assert!(recreate(&entry(SpecialKind::Char, None), &root.join("null"), None, SpecialFiles::Recreate).is_err());
+    assert!(!recreate(&entry(SpecialKind::Socket, None), &root.join("socket"), None, SpecialFiles::Recreate).unwrap());
+    let _ = fs::remove_dir_all(&root);
+}
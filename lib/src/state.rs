@@ -0,0 +1,78 @@
+use std::fs;
+use std::path::Path;
+
+use crate::logging::Trap;
+
+/// Directory of a snapshot the state of its host is kept in, next to the
+/// files of `source`
+pub const STATE_DIR: &str = "system-state";
+
+/// What is captured of a host with `system_state` set, by the name of the
+/// file it lands in. Each one falls back to whatever the host has installed.
+pub const STATE_COMMANDS: [(&str, &str); 4] = [
+    ("packages", "dpkg-query -W -f '${Package}\\t${Version}\\n' 2>/dev/null || rpm -qa 2>/dev/null || apk info -v 2>/dev/null"),
+    ("services", "systemctl list-unit-files --state=enabled --no-pager --no-legend 2>/dev/null || rc-update show 2>/dev/null"),
+    ("crontabs", "crontab -l 2>/dev/null; cat /etc/crontab /etc/cron.d/* 2>/dev/null; true"),
+    ("iptables", "iptables-save 2>/dev/null || nft list ruleset 2>/dev/null"),
+];
+
+/// Runs every state command through `exec` and writes its output to
+/// `directory/$name.txt`. A host without e.g. iptables has nothing to
+/// capture, so failed commands are returned to be noted rather than failing
+/// the run. Returns the number of files written alongside them.
+pub fn capture_state<F>(exec: F, directory: &Path) -> Result<(usize, Vec<String>), Trap>
+where
+    F: Fn(&str) -> Result<String, Trap>
+{
+    fs::create_dir_all(directory)
+        .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", directory, err)))?;
+
+    let mut written = 0;
+    let mut missing = Vec::new();
+    for (name, command) in STATE_COMMANDS {
+        match exec(command) {
+            Ok(output) if !output.trim().is_empty() => {
+                let file = directory.join(format!("{}.txt", name));
+                fs::write(&file, output)
+                    .map_err(|err| Trap::FS(format!("Could not write {:?}: {}", file, err)))?;
+                written += 1;
+            },
+            Ok(_) => missing.push(format!("No {} to capture", name)),
+            Err(err) => missing.push(format!("Could not capture {}: {}", name, err)),
+        }
+    }
+
+    Ok((written, missing))
+}
+
+#[test]
+fn test_capture_state() {
+    use std::process::Command;
+
+    let directory = std::env::temp_dir().join("rensen_test_state").join(STATE_DIR);
+    let _ = fs::remove_dir_all(&directory);
+
+    // Stands in for the host, which only has packages and a crontab
+    let exec = |command: &str| -> Result<String, Trap> {
+        let command = match command {
+            command if command.starts_with("dpkg-query") => "echo 'openssh-server\t1:9.2'",
+            command if command.starts_with("crontab") => "echo '0 3 * * * /usr/local/bin/rotate'",
+            command if command.starts_with("iptables") => "exit 1",
+            _ => "true",
+        };
+        let output = Command::new("sh").arg("-c").arg(command).output().unwrap();
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+            false => Err(Trap::Channel(format!("`{}` exited with {}", command, output.status))),
+        }
+    };
+
+    let (written, missing) = capture_state(exec, &directory).unwrap();
+    assert_eq!(written, 2);
+    assert_eq!(missing.len(), 2);
+    assert!(missing[0].starts_with("No services"));
+    assert_eq!(fs::read_to_string(directory.join("packages.txt")).unwrap(), "openssh-server\t1:9.2\n");
+    assert!(directory.join("crontabs.txt").is_file());
+    assert!(!directory.join("iptables.txt").exists());
+    let _ = fs::remove_dir_all(directory.parent().unwrap());
+}
@@ -0,0 +1,165 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, TimeZone};
+
+use crate::config::{GlobalConfig, HostConfig};
+use crate::history::RunOutcome;
+use crate::logging::{Trap, log_host_trap};
+use crate::schedule::HostSchedule;
+use crate::traits::JsonFile;
+
+/// How long past a scheduled run a source without a success since is
+/// overdue, unless its host has a `timeout`
+pub const OVERDUE_GRACE: i64 = 60 * 60;
+
+/// Where a source of a host stands after its latest run, rewritten by each
+/// run so it is read without going through the history.
+/// Stored at $backups/$identifier/.records/status.json, and in the
+/// namespace of each of `sources`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HostStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_attempt: Option<i64>,  // when the latest run started, unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<i64>,  // when the latest successful run started, unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>, // of the latest run, none once one succeeded
+    pub failures: u32,              // runs failed since the last success
+    pub bytes: u64,                 // transferred by the latest run
+    pub duration: i64,              // seconds the latest run took
+}
+
+impl HostStatus {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("status.json")
+    }
+
+    pub fn load(global_config: &GlobalConfig, host_config: &HostConfig) -> Result<Self, Trap> {
+        let path = Self::path(global_config, host_config);
+        HostStatus::deserialize_json(&path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", path, err)))
+    }
+
+    fn save(&self, global_config: &GlobalConfig, host_config: &HostConfig) -> Result<(), Trap> {
+        let path = Self::path(global_config, host_config);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+
+        self.serialize_json(&path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", path, err)))
+    }
+
+    /// Takes in a finished run
+    pub fn update(&mut self, outcome: &RunOutcome) {
+        self.last_attempt = Some(outcome.started);
+        self.bytes = outcome.bytes;
+        self.duration = outcome.duration();
+        match outcome.success {
+            true => {
+                self.last_success = Some(outcome.started);
+                self.last_error = None;
+                self.failures = 0;
+            },
+            false => {
+                self.last_error = Some(outcome.error.clone().unwrap_or_default());
+                self.failures += 1;
+            },
+        }
+    }
+
+    /// Updates the status of `host_config` with a finished run of it.
+    /// Failures are logged, they should never fail the run itself.
+    pub fn record(global_config: &GlobalConfig, host_config: &HostConfig, outcome: &RunOutcome) {
+        let recorded = HostStatus::load(global_config, host_config).and_then(|mut status| {
+            status.update(outcome);
+            status.save(global_config, host_config)
+        });
+        if let Err(err) = recorded {
+            log_host_trap(global_config, &outcome.hostname, &err);
+        }
+    }
+
+    /// When the run this source is overdue for was scheduled: the first
+    /// after its last success, once `grace` seconds past it went by without
+    /// another. None if it is not overdue, Some(None) if it never succeeded.
+    pub fn overdue(&self, schedule: &HostSchedule, grace: i64, now: i64) -> Option<Option<i64>> {
+        let Some(last_success) = self.last_success else { return Some(None) };
+        let last_success = Local.timestamp_opt(last_success, 0).single()?;
+        schedule.after(&last_success)
+            .next()
+            .map(|due| due.timestamp())
+            .filter(|due| due + grace <= now)
+            .map(Some)
+    }
+}
+
+impl JsonFile for HostStatus {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(HostStatus::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let status: HostStatus = serde_json::from_str(&contents)?;
+        Ok(status)
+    }
+}
+
+#[test]
+fn test_host_status() {
+    use std::str::FromStr;
+    use cron::Schedule;
+
+    let global_config = GlobalConfig {
+        backups: std::env::temp_dir().join("rensen_test_status"),
+        log: std::env::temp_dir().join("rensen_test_status.log"),
+        ..Default::default()
+    };
+    let host_config = HostConfig { identifier: String::from("db"), ..Default::default() };
+    let _ = fs::remove_dir_all(&global_config.backups);
+
+    let run = |started: i64, error: Option<&str>| RunOutcome {
+        hostname: String::from("db"),
+        started,
+        finished: started + 90,
+        success: error.is_none(),
+        error: error.map(String::from),
+        bytes: 2048,
+        ..Default::default()
+    };
+    let start = Local.with_ymd_and_hms(2024, 5, 1, 2, 0, 0).unwrap().timestamp();
+    HostStatus::record(&global_config, &host_config, &run(start, None));
+    HostStatus::record(&global_config, &host_config, &run(start + 86400, Some("Could not connect")));
+
+    let status = HostStatus::load(&global_config, &host_config).unwrap();
+    assert_eq!(status, HostStatus {
+        last_attempt: Some(start + 86400),
+        last_success: Some(start),
+        last_error: Some(String::from("Could not connect")),
+        failures: 1,
+        bytes: 2048,
+        duration: 90,
+    });
+
+    // Daily at 02:00, the run of the next day was missed once the grace is over
+    let schedule = HostSchedule::Cron(Box::new(Schedule::from_str("0 0 2 * * *").unwrap()));
+    assert_eq!(status.overdue(&schedule, OVERDUE_GRACE, start + 86400 + 1800), None);
+    assert_eq!(status.overdue(&schedule, OVERDUE_GRACE, start + 86400 + 3600), Some(Some(start + 86400)));
+    assert_eq!(HostStatus::default().overdue(&schedule, OVERDUE_GRACE, start), Some(None));
+}
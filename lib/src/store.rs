@@ -0,0 +1,205 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::logging::Trap;
+use crate::traits::Store;
+
+/// Suffix of objects of a LocalStore while they are being put
+pub const STAGING_SUFFIX: &str = ".part";
+
+/// Directory below `backups` the remote stores spool objects in
+pub const SPOOL_DIR: &str = ".records/spool";
+
+/// Files of the spool so far, which together with the pid name the next
+static SPOOLED: AtomicU64 = AtomicU64::new(0);
+
+/// Private directory for the temporary files of stores that send and fetch
+/// whole objects, e.g. a WebDAV or S3 store. Only rensen can get into it,
+/// and its files are always new ones, so nobody can have them written
+/// through a link or see what is in them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Spool {
+    pub dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(backups: &Path) -> Self {
+        Self { dir: backups.join(SPOOL_DIR) }
+    }
+
+    /// A new file of the spool named after `name`, e.g. the key of the
+    /// object it holds, which the caller removes once done with it
+    pub fn create(&self, name: &str) -> Result<(PathBuf, File), Trap> {
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700)))
+            .map_err(|err| Trap::FS(format!("Could not create spool directory {:?}: {}", self.dir, err)))?;
+
+        let name: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '-' })
+            .collect();
+        loop {
+            let path = self.dir.join(format!("{}-{}-{}", name, std::process::id(), SPOOLED.fetch_add(1, Ordering::Relaxed)));
+            match OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(Trap::FS(format!("Could not create {:?}: {}", path, err))),
+            }
+        }
+    }
+}
+
+/// Store in a directory, e.g. `backups` of the global config
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalStore {
+    pub root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf() }
+    }
+
+    /// Where `key` is kept, refusing keys that would lead out of `root`
+    pub fn path(&self, key: &Path) -> Result<PathBuf, Trap> {
+        if key.as_os_str().is_empty() || !key.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(Trap::InvalidInput(format!("Invalid key {:?} for the store at {:?}", key, self.root)));
+        }
+
+        Ok(self.root.join(key))
+    }
+
+    fn staging(&self, key: &Path) -> Result<PathBuf, Trap> {
+        Ok(PathBuf::from(format!("{}{}", self.path(key)?.display(), STAGING_SUFFIX)))
+    }
+}
+
+impl Store for LocalStore {
+    fn put(&self, key: &Path) -> Result<Box<dyn Write + '_>, Trap> {
+        let staging = self.staging(key)?;
+        if let Some(parent) = staging.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Trap::FS(format!("Could not create directory {:?}: {}", parent, err)))?;
+        }
+
+        let file = File::create(&staging)
+            .map_err(|err| Trap::FS(format!("Could not create {:?}: {}", staging, err)))?;
+        Ok(Box::new(BufWriter::new(file)))
+    }
+
+    fn finalize(&self, key: &Path) -> Result<(), Trap> {
+        let staging = self.staging(key)?;
+        let path = self.path(key)?;
+
+        // On disk before it shows up, a crash leaves the old object or the new one
+        File::open(&staging)
+            .and_then(|file| file.sync_all())
+            .and_then(|_| fs::rename(&staging, &path))
+            .map_err(|err| Trap::FS(format!("Could not finalize {:?}: {}", path, err)))
+    }
+
+    fn get(&self, key: &Path) -> Result<Box<dyn Read>, Trap> {
+        let path = self.path(key)?;
+        let file = File::open(&path)
+            .map_err(|err| Trap::FS(format!("Could not open {:?}: {}", path, err)))?;
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>, Trap> {
+        let mut keys = Vec::new();
+        let mut pending = vec![match prefix.as_os_str().is_empty() {
+            true => self.root.clone(),
+            false => self.path(prefix)?,
+        }];
+
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let path = entry.path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else if !path.to_string_lossy().ends_with(STAGING_SUFFIX) {
+                    if let Ok(key) = path.strip_prefix(&self.root) {
+                        keys.push(key.to_path_buf());
+                    }
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &Path) -> Result<(), Trap> {
+        let path = self.path(key)?;
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(Trap::FS(format!("Could not remove {:?}: {}", path, err))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_local_store() {
+    let root = std::env::temp_dir().join("rensen_test_store");
+    let _ = fs::remove_dir_all(&root);
+    let store = LocalStore::new(&root);
+    let key = Path::new("host/2024-05-01-14-05-00.tar.gz");
+
+    // Nothing shows up until it is finalized
+    let mut writer = store.put(key).unwrap();
+    writer.write_all(b"archive").unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+    assert!(store.list(Path::new("")).unwrap().is_empty());
+    assert!(store.get(key).is_err());
+
+    store.finalize(key).unwrap();
+    let mut contents = String::new();
+    store.get(key).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "archive");
+    assert_eq!(store.list(Path::new("host")).unwrap(), vec![key.to_path_buf()]);
+    assert!(store.list(Path::new("other")).unwrap().is_empty());
+
+    assert!(store.put(Path::new("../escape")).is_err());
+    assert!(store.path(Path::new("/etc/passwd")).is_err());
+
+    store.delete(key).unwrap();
+    store.delete(key).unwrap();
+    assert!(store.list(Path::new("")).unwrap().is_empty());
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_spool() {
+    let root = std::env::temp_dir().join("rensen_test_spool");
+    let _ = fs::remove_dir_all(&root);
+    let spool = Spool::new(&root);
+
+    // Two files of the same name are two files, in a directory of rensen's own
+    let (first, _) = spool.create("web01/a.tar.gz").unwrap();
+    let (second, _) = spool.create("web01/a.tar.gz").unwrap();
+    assert_ne!(first, second);
+    assert_eq!(first.parent(), Some(root.join(SPOOL_DIR).as_path()));
+    assert!(first.file_name().unwrap().to_string_lossy().starts_with("web01-a.tar.gz-"));
+    assert_eq!(fs::metadata(&spool.dir).unwrap().permissions().mode() & 0o777, 0o700);
+    assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // Nothing already there is opened, a link planted before least of all
+    fs::set_permissions(&spool.dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let target = root.join("target");
+    fs::write(&target, "untouched").unwrap();
+    SPOOLED.store(1000, Ordering::Relaxed);
+    std::os::unix::fs::symlink(&target, spool.dir.join(format!("planted-{}-1000", std::process::id()))).unwrap();
+    let (path, mut file) = spool.create("planted").unwrap();
+    file.write_all(b"spooled").unwrap();
+    assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "spooled");
+    assert_eq!(fs::metadata(&spool.dir).unwrap().permissions().mode() & 0o777, 0o700);
+    let _ = fs::remove_dir_all(&root);
+}
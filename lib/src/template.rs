@@ -0,0 +1,35 @@
+use minijinja::Environment;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+use crate::logging::Trap;
+
+/// Renders `source` (minijinja/jinja2 syntax) with `context`
+pub fn render_str<S: Serialize>(source: &str, context: S) -> Result<String, Trap> {
+    let mut env = Environment::new();
+    env.add_template("template", source)
+        .map_err(|err| Trap::Config(format!("Invalid template: {}", err)))?;
+
+    env.get_template("template")
+        .and_then(|template| template.render(context))
+        .map_err(|err| Trap::Config(format!("Could not render template: {}", err)))
+}
+
+/// Renders the template file at `path` with `context`
+pub fn render_file<S: Serialize>(path: &Path, context: S) -> Result<String, Trap> {
+    let source = fs::read_to_string(path)
+        .map_err(|err| Trap::FS(format!("Could not read template {:?}: {}", path, err)))?;
+
+    render_str(&source, context)
+}
+
+#[test]
+fn test_render_str() {
+    #[derive(Serialize)]
+    struct Context { host: &'static str, hosts: Vec<u32> }
+
+    let rendered = render_str("{{ host }}: {% for h in hosts %}{{ h }} {% endfor %}", Context { host: "king", hosts: vec![1, 2] }).unwrap();
+    assert_eq!(rendered, "king: 1 2 ");
+    assert!(render_str("{{ unclosed", ()).is_err());
+}
@@ -56,6 +56,6 @@ pub fn test_serialize_yaml() {
 #[test]
 pub fn test_deserialize_yaml() {
     let path = Path::new("tests/test.yaml");
-    let settings = Settings::deserialize_yaml(path).unwrap();
+    let _settings = Settings::deserialize_yaml(path).unwrap();
 }
 
@@ -1,6 +1,11 @@
 use crate::logging;
 use logging::Trap;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use ssh2::Session;
+use crate::inventory::Discovered;
+use crate::results::{BackupReport, RestoreReport};
+use crate::runbook::RestoreStep;
 
 pub trait YamlFile: Sized { 
     /// Wrapper for serde::yaml
@@ -17,14 +22,50 @@ pub trait JsonFile: Sized {
 }
 
 pub trait Rsync {
-    fn backup(&mut self) -> Result<(), Trap>;
+    fn backup(&mut self) -> Result<BackupReport, Trap>;
     fn auth(&mut self) -> Result<(), Trap>;
     fn connect(&mut self) -> Result<(), Trap>;
     fn copy_remote_directory(&self, remote_path: &Path, dest_path: &Path) -> Result<(), Trap>;
     fn copy_remote_file(&self, remote_path: &Path, dest_path: &Path) -> Result<(), Trap>;
 }
 
+/// Pushes a snapshot back onto the host it was taken of, following the steps
+/// of its runbook
+pub trait Restore {
+    fn restore(&self, steps: &[RestoreStep]) -> Result<RestoreReport, Trap>;
+}
+
+/// Where snapshots end up, holding objects by keys relative to its root,
+/// e.g. `myserver/2024-05-01-14-05-00.tar.gz`. Objects being put are never
+/// seen under their key until `finalize`, so a store never holds half an
+/// archive. Anything else, S3, WebDAV or a remote over sftp, implements it
+/// like LocalStore does for a directory.
+pub trait Store {
+    /// Writer for a new object at `key`, replacing any there once finalized
+    fn put(&self, key: &Path) -> Result<Box<dyn Write + '_>, Trap>;
+    /// Makes what was put at `key` visible under it, all at once
+    fn finalize(&self, key: &Path) -> Result<(), Trap>;
+    fn get(&self, key: &Path) -> Result<Box<dyn Read>, Trap>;
+    /// Keys of the finalized objects below `prefix`, sorted
+    fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>, Trap>;
+    /// Removes the object at `key`, if there is one
+    fn delete(&self, key: &Path) -> Result<(), Trap>;
+}
+
 pub trait ConvertFromPath {
     fn convert_from_path(path: &Path) -> Self;
 }
 
+
+/// Application-consistent backups: brings data on the host to a consistent
+/// state before it is copied. `thaw` is called once the copy has finished,
+/// whether it succeeded or not. Both are bounded by the session timeout.
+pub trait Quiesce {
+    fn freeze(&mut self, sess: &Session, path: &Path) -> Result<(), Trap>;
+    fn thaw(&mut self, sess: &Session, path: &Path) -> Result<(), Trap>;
+}
+
+/// Source of machines to enroll, e.g. an Ansible inventory
+pub trait Inventory {
+    fn discover(&self) -> Result<Vec<Discovered>, Trap>;
+}
@@ -0,0 +1,101 @@
+use chrono::{DateTime, Local, TimeZone, Utc, SecondsFormat};
+use chrono_tz::Tz;
+
+use crate::config::GlobalConfig;
+use crate::logging::Trap;
+
+const BYTE_UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+/// Formats sizes, durations and timestamps for user-facing output.
+///
+/// Timestamps honor `timezone` from the global config (the system's local
+/// time if unset). With `raw`, everything is printed unformatted instead:
+/// bytes as plain counts, durations in seconds and timestamps in UTC RFC3339.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Units {
+    pub tz: Option<Tz>,
+    pub raw: bool,
+}
+
+impl Units {
+    pub fn new(global_config: &GlobalConfig, raw: bool) -> Result<Self, Trap> {
+        let tz = match &global_config.timezone {
+            Some(timezone) => Some(timezone.parse::<Tz>()
+                .map_err(|err| Trap::Config(format!("Invalid timezone `{}`: {}", timezone, err)))?),
+            None => None,
+        };
+
+        Ok(Self { tz, raw })
+    }
+
+    /// e.g. `858.3 MiB`
+    pub fn bytes(&self, bytes: u64) -> String {
+        if self.raw {
+            return bytes.to_string();
+        }
+
+        let mut amount = bytes as f64;
+        let mut unit = 0;
+        while amount >= 1024.0 && unit < BYTE_UNITS.len() - 1 {
+            amount /= 1024.0;
+            unit += 1;
+        }
+
+        match unit {
+            0 => format!("{} B", bytes),
+            _ => format!("{:.1} {}", amount, BYTE_UNITS[unit]),
+        }
+    }
+
+    /// e.g. `1h 02m 03s`
+    pub fn duration(&self, secs: i64) -> String {
+        if self.raw {
+            return secs.to_string();
+        }
+
+        let sign = if secs < 0 { "-" } else { "" };
+        let secs = secs.unsigned_abs();
+        let (days, hours, minutes, seconds) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+        match (days, hours, minutes) {
+            (0, 0, 0) => format!("{}{}s", sign, seconds),
+            (0, 0, _) => format!("{}{}m {:02}s", sign, minutes, seconds),
+            (0, _, _) => format!("{}{}h {:02}m {:02}s", sign, hours, minutes, seconds),
+            _         => format!("{}{}d {:02}h {:02}m", sign, days, hours, minutes),
+        }
+    }
+
+    /// Unix seconds as `YYYY-MM-DD HH:MM:SS` in the configured timezone
+    pub fn timestamp(&self, secs: i64) -> String {
+        match Utc.timestamp_opt(secs, 0).single() {
+            Some(time) => self.datetime(&time),
+            None => secs.to_string(),
+        }
+    }
+
+    pub fn datetime<T: TimeZone>(&self, time: &DateTime<T>) -> String {
+        let format = "%Y-%m-%d %H:%M:%S";
+        if self.raw {
+            return time.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true);
+        }
+
+        match self.tz {
+            Some(tz) => time.with_timezone(&tz).format(format).to_string(),
+            None => time.with_timezone(&Local).format(format).to_string(),
+        }
+    }
+}
+
+#[test]
+fn test_units() {
+    let units = Units { tz: Some(chrono_tz::UTC), raw: false };
+    assert_eq!(units.bytes(900), "900 B");
+    assert_eq!(units.bytes(900012000), "858.3 MiB");
+    assert_eq!(units.duration(3723), "1h 02m 03s");
+    assert_eq!(units.duration(59), "59s");
+    assert_eq!(units.timestamp(0), "1970-01-01 00:00:00");
+
+    let raw = Units { raw: true, ..units };
+    assert_eq!(raw.bytes(900012000), "900012000");
+    assert_eq!(raw.timestamp(0), "1970-01-01T00:00:00Z");
+}
@@ -0,0 +1,178 @@
+use serde::{Serialize, Deserialize};
+use sha3::{Digest, Sha3_256};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::codec::Codec;
+use crate::config::GlobalConfig;
+use crate::logging::Trap;
+use crate::traits::JsonFile;
+use crate::sandbox;
+
+/// Directory below `snapshots` unpacked archives are kept in
+pub const RESTORE_CACHE_DIR: &str = ".restore-cache";
+
+/// The archive a cached tree was unpacked from, kept next to it as $key.json
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Unpacked {
+    archive: PathBuf,
+    size: u64,  // of the archive when it was unpacked
+    mtime: u64, // of the archive when it was unpacked
+    bytes: u64, // of the unpacked tree
+    used: i64,  // unix seconds of the last restore that read it
+}
+
+/// Unpacked archives kept between restores, so restoring the same snapshots
+/// again, e.g. to seed test environments, does not unpack them again. Lives
+/// at $snapshots/.restore-cache, one tree per archive, and is kept under
+/// `restore_cache` MiB by dropping the trees used the longest ago. A tree is
+/// unpacked again once its archive changed.
+#[derive(Debug, Clone)]
+pub struct UnpackCache {
+    pub root: PathBuf,
+    pub limit: u64, // bytes
+}
+
+impl UnpackCache {
+    /// None unless `restore_cache` is set
+    pub fn new(global_config: &GlobalConfig) -> Option<Self> {
+        global_config.restore_cache.map(|limit| UnpackCache {
+            root: global_config.snapshots.join(RESTORE_CACHE_DIR),
+            limit: limit * 1024 * 1024,
+        })
+    }
+
+    fn key(archive: &Path) -> String {
+        let digest = format!("{:x}", Sha3_256::digest(archive.to_string_lossy().as_bytes()));
+        digest[..32].to_string()
+    }
+
+    /// The unpacked tree of `archive`, compressed with `codec`, unpacked now
+    /// unless it is cached. Returns whether it had to be unpacked.
+    pub fn unpacked(&self, archive: &Path, codec: Codec, now: i64) -> Result<(PathBuf, bool), Trap> {
+        let metadata = fs::metadata(archive)
+            .map_err(|err| Trap::FS(format!("Could not read {:?}: {}", archive, err)))?;
+        let mtime = metadata.modified().ok()
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_secs())
+            .unwrap_or(0);
+
+        let key = UnpackCache::key(archive);
+        let tree = self.root.join(&key);
+        let note_path = self.root.join(format!("{}.json", key));
+
+        let cached = Unpacked::deserialize_json(&note_path).ok()
+            .filter(|note| note.archive == archive && note.size == metadata.len() && note.mtime == mtime && tree.is_dir());
+        let (note, fresh) = match cached {
+            Some(note) => (Unpacked { used: now, ..note }, false),
+            None => {
+                let _ = fs::remove_dir_all(&tree);
+                sandbox::unpack(archive, &tree, codec)
+                    .map_err(|err| Trap::FS(format!("Could not unpack {:?}: {}", archive, err)))?;
+                let note = Unpacked { archive: archive.to_path_buf(), size: metadata.len(), mtime, bytes: tree_size(&tree), used: now };
+                (note, true)
+            },
+        };
+
+        note.serialize_json(&note_path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", note_path, err)))?;
+        Ok((tree, fresh))
+    }
+
+    /// Drops the trees used the longest ago until the rest fit the limit,
+    /// never those in `keep`. Returns the bytes freed.
+    pub fn trim(&self, keep: &[PathBuf]) -> u64 {
+        let mut notes: Vec<(PathBuf, Unpacked)> = fs::read_dir(&self.root).into_iter().flatten()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|extension| extension.to_str()) == Some("json"))
+            .filter_map(|path| Unpacked::deserialize_json(&path).ok().map(|note| (path, note)))
+            .collect();
+        notes.sort_by_key(|(_, note)| note.used);
+
+        let mut total: u64 = notes.iter().map(|(_, note)| note.bytes).sum();
+        let mut freed = 0;
+        for (note_path, note) in notes {
+            if total <= self.limit {
+                break;
+            }
+
+            let tree = note_path.with_extension("");
+            if keep.contains(&tree) {
+                continue;
+            }
+            let _ = fs::remove_dir_all(&tree);
+            let _ = fs::remove_file(&note_path);
+            total -= note.bytes;
+            freed += note.bytes;
+        }
+
+        freed
+    }
+}
+
+/// Bytes of the regular files below `dir`
+fn tree_size(dir: &Path) -> u64 {
+    let mut size = 0;
+    let mut directories = vec![dir.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory).into_iter().flatten().filter_map(|entry| entry.ok()) {
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => directories.push(entry.path()),
+                Ok(metadata) => size += metadata.len(),
+                Err(_) => (),
+            }
+        }
+    }
+
+    size
+}
+
+impl JsonFile for Unpacked {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = File::open(file_path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let unpacked: Unpacked = serde_json::from_str(&contents)?;
+        Ok(unpacked)
+    }
+}
+
+#[test]
+fn test_unpack_cache() {
+    use crate::utils::make_tar_gz;
+
+    let root = std::env::temp_dir().join("rensen_test_unpacked");
+    let _ = fs::remove_dir_all(&root);
+    for name in ["a", "b"] {
+        fs::create_dir_all(root.join(name).join("src")).unwrap();
+        fs::write(root.join(name).join("src/file"), vec![0; 1024 * 1024]).unwrap();
+        make_tar_gz(root.join(name), root.join(format!("{}.tar.gz", name))).unwrap();
+    }
+
+    let global_config = GlobalConfig { snapshots: root.join("snapshots"), restore_cache: Some(1), ..Default::default() };
+    let cache = UnpackCache::new(&global_config).unwrap();
+    assert!(UnpackCache::new(&GlobalConfig::default()).is_none());
+
+    // Unpacked once, then read from the cache
+    let (a, fresh) = cache.unpacked(&root.join("a.tar.gz"), Codec::Gzip, 1).unwrap();
+    assert!(fresh && a.join("src/file").is_file());
+    assert_eq!(cache.unpacked(&root.join("a.tar.gz"), Codec::Gzip, 2).unwrap(), (a.clone(), false));
+
+    // The one used longest ago makes room, unless it is in use
+    let (b, _) = cache.unpacked(&root.join("b.tar.gz"), Codec::Gzip, 3).unwrap();
+    assert_eq!(cache.trim(&[a.clone(), b.clone()]), 0);
+    assert_eq!(cache.trim(std::slice::from_ref(&b)), 1024 * 1024);
+    assert!(!a.exists() && b.exists());
+    assert!(cache.unpacked(&root.join("a.tar.gz"), Codec::Gzip, 4).unwrap().1);
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,76 @@
+use serde::{Serialize, Deserialize};
+
+/// What a run cost the backup server itself, for sizing it. CPU time and
+/// I/O are those of the thread the run was on, the resident set is the peak
+/// of the whole process so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub user_ms: u64,      // CPU time in user mode
+    pub system_ms: u64,    // CPU time in the kernel
+    pub max_rss: u64,      // bytes
+    pub read_bytes: u64,   // read from disk, the page cache does not count
+    pub write_bytes: u64,  // written to disk
+}
+
+/// getrusage counts blocks of this many bytes
+const BLOCK: u64 = 512;
+
+#[cfg(target_os = "linux")]
+const WHO: libc::c_int = libc::RUSAGE_THREAD;
+#[cfg(not(target_os = "linux"))]
+const WHO: libc::c_int = libc::RUSAGE_SELF;
+
+impl ResourceUsage {
+    /// The counters of the calling thread since it started, None if the
+    /// system does not give them
+    pub fn thread() -> Option<Self> {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(WHO, &mut usage) } != 0 {
+            return None;
+        }
+
+        let ms = |time: libc::timeval| time.tv_sec as u64 * 1000 + time.tv_usec as u64 / 1000;
+        Some(ResourceUsage {
+            user_ms: ms(usage.ru_utime),
+            system_ms: ms(usage.ru_stime),
+            max_rss: usage.ru_maxrss as u64 * 1024, // KiB on Linux
+            read_bytes: usage.ru_inblock as u64 * BLOCK,
+            write_bytes: usage.ru_oublock as u64 * BLOCK,
+        })
+    }
+
+    /// What was spent between `start` and these counters
+    pub fn since(&self, start: &ResourceUsage) -> ResourceUsage {
+        ResourceUsage {
+            user_ms: self.user_ms.saturating_sub(start.user_ms),
+            system_ms: self.system_ms.saturating_sub(start.system_ms),
+            max_rss: self.max_rss,
+            read_bytes: self.read_bytes.saturating_sub(start.read_bytes),
+            write_bytes: self.write_bytes.saturating_sub(start.write_bytes),
+        }
+    }
+
+    /// CPU time in both modes, in seconds
+    pub fn cpu_seconds(&self) -> f64 {
+        (self.user_ms + self.system_ms) as f64 / 1000.0
+    }
+}
+
+#[test]
+fn test_resource_usage() {
+    let start = ResourceUsage::thread().unwrap();
+    let mut sum: u64 = 0;
+    for i in 0..5_000_000u64 {
+        sum = sum.wrapping_add(i * i);
+    }
+    std::hint::black_box(sum);
+
+    let spent = ResourceUsage::thread().unwrap().since(&start);
+    assert!(spent.max_rss > 0);
+    assert!(spent.cpu_seconds() >= 0.0);
+
+    let earlier = ResourceUsage { user_ms: 500, system_ms: 100, max_rss: 10, read_bytes: 4096, write_bytes: 0 };
+    let later = ResourceUsage { user_ms: 1700, system_ms: 400, max_rss: 20, read_bytes: 8192, write_bytes: 512 };
+    assert_eq!(later.since(&earlier), ResourceUsage { user_ms: 1200, system_ms: 300, max_rss: 20, read_bytes: 4096, write_bytes: 512 });
+    assert_eq!(later.since(&earlier).cpu_seconds(), 1.5);
+}
@@ -1,9 +1,7 @@
 use std::fs::{self, File};
-use std::io::{self, SeekFrom, BufReader, BufWriter, Read};
-use std::path::{Path, PathBuf}; use std::io::prelude::*;
-use flate2::{write::GzEncoder, read::GzDecoder};
-use flate2::Compression;
-use tar::{Builder, Archive};
+use std::io::{self, SeekFrom, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf}; use std::io::prelude::*;
+use tar::{Builder, Archive, Header, EntryType};
 use sha3::{Digest, Sha3_256};
 use std::os::unix::fs::PermissionsExt;
 use std::time::{SystemTime, Duration};
@@ -14,9 +12,12 @@ use crate::logging;
 use logging::Trap;
 
 use crate::traits::ConvertFromPath;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::codec::{encoder, decoder, Codec};
+use crate::sparse;
 
 pub fn get_datetime() -> String {
-    return offset::Local::now()
+    offset::Local::now()
         .format("%Y-%m-%d-%H-%M-%S")
         .to_string()
 }
@@ -50,9 +51,9 @@ pub fn set_metadata(file: &mut File, stat: FileStat) -> Result<(), Trap> {
     // File Times
     let default_value: u64 = 0;
 
-    let file_times = fs::FileTimes::new();
-    file_times.set_accessed(SystemTime::UNIX_EPOCH + Duration::from_secs(stat.atime.unwrap_or(default_value)));
-    file_times.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or(default_value)));
+    let file_times = fs::FileTimes::new()
+        .set_accessed(SystemTime::UNIX_EPOCH + Duration::from_secs(stat.atime.unwrap_or(default_value)))
+        .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or(default_value)));
     let _ = file.set_times(file_times);
     let _ = file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or(default_value)));
 
@@ -76,10 +77,12 @@ where
             Err(err) => return Err(Trap::FS(format!("Could not read file: {}", err)))
         }.path();
 
-        if entry.is_dir() {
+        // Not through symlinks, which may lead anywhere
+        let file_type = fs::symlink_metadata(&entry).map(|metadata| metadata.file_type());
+        if file_type.as_ref().is_ok_and(|file_type| file_type.is_dir()) {
             sum += count_files(&entry)?;
         }
-        else if entry.is_file() {
+        else if file_type.is_ok_and(|file_type| file_type.is_file()) {
             sum += 1;
         }
     }
@@ -89,10 +92,17 @@ where
 
 #[test]
 fn test_count_files() {
-    let path = "/etc/rensen";
-    let files = count_files(path).unwrap();
+    let path = std::env::temp_dir().join("rensen_test_count_files");
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(path.join("sub")).unwrap();
+    for file in ["a", "b", "sub/c"] {
+        File::create(path.join(file)).unwrap();
+    }
+
+    let files = count_files(&path).unwrap();
+    let _ = fs::remove_dir_all(&path);
 
-    assert_eq!(files, 9);
+    assert_eq!(files, 3);
 }
 
 /// Archive directory with Tarball (tar::Builder) and
@@ -105,67 +115,201 @@ where
     SRC: AsRef<Path>,
     DST: AsRef<Path>
 {
-    let source = source.as_ref();
-    let destination = destination.as_ref();
+    make_tar_gz_with(source, destination, &ArchiveOptions::default())
+}
 
-    let mut files_added = 0;
-    let file_count = count_files(source).unwrap();
-    println!("Archiving: ({}/{})", 0, file_count);
+/// How archives are written
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ArchiveOptions {
+    pub clamp_mtime: Option<u64>, // later mtimes are set to this
+    pub rsyncable: bool,          // gzip members cut at content defined boundaries
+    pub codec: Codec,
+    pub level: Option<u32>,       // default: that of the codec
+}
 
-    // Temp tar file
-    let tar_file_path = "temp.tar";
-    let tar_file = File::create(tar_file_path)?;
+impl ArchiveOptions {
+    pub fn from(global_config: &GlobalConfig) -> Self {
+        ArchiveOptions {
+            clamp_mtime: global_config.archive_mtime_clamp,
+            rsyncable: global_config.rsyncable.unwrap_or(false),
+            codec: Codec::default(),
+            level: None,
+        }
+    }
 
-    // Create a tarball
-    let mut tar_builder = Builder::new(tar_file);
-    add_dir_contents_to_tar(source, &mut tar_builder, source, &mut files_added, &file_count)?;
-    tar_builder.finish()?;
+    /// With the `compression` of `host_config`
+    pub fn for_host(global_config: &GlobalConfig, host_config: &HostConfig) -> Self {
+        ArchiveOptions {
+            codec: host_config.compression.unwrap_or_default(),
+            level: host_config.compression_level,
+            ..ArchiveOptions::from(global_config)
+        }
+    }
+}
 
-    print!("Compressing... ");
-    // Gzip compress
-    let tar_file = File::open(tar_file_path)?;
-    let gz_file = File::create(destination)?;
-    let gz_encoder = GzEncoder::new(BufWriter::new(gz_file), Compression::default());
-    io::copy(&mut BufReader::new(tar_file), &mut BufWriter::new(gz_encoder))?;
+/// Same as make_tar_gz, written according to `options`.
+///
+/// The output only depends on the content: entries are added sorted by name,
+/// headers carry nothing but path, size, mode, owner and mtime, and the gzip
+/// header has no name or timestamp. Archiving identical trees gives
+/// byte-identical archives, so replicas can be compared by hash.
+pub fn make_tar_gz_with<SRC, DST>(source: SRC, destination: DST, options: &ArchiveOptions) -> io::Result<()>
+where 
+    SRC: AsRef<Path>,
+    DST: AsRef<Path>
+{
+    let gz_file = BufWriter::new(File::create(destination.as_ref())?);
+    write_tar_gz(&source, gz_file, options)?;
 
-    // Cleanup: remove temp tar file, remove uncompressed file
+    // Cleanup: remove uncompressed file
     let _ = fs::remove_dir_all(source);
-    let _ = fs::remove_file(tar_file_path);
+    Ok(())
+}
+
+/// Same as make_tar_gz_with, written to `writer`, e.g. one of a Store.
+/// `source` is left as it is.
+pub fn write_tar_gz<SRC, W>(source: SRC, writer: W, options: &ArchiveOptions) -> io::Result<()>
+where
+    SRC: AsRef<Path>,
+    W: Write
+{
+    let source = source.as_ref();
+
+    let mut files_added = 0;
+    let file_count = count_files(source).unwrap_or(0);
+    println!("Archiving: ({}/{})", 0, file_count);
+
+    // Compressing while archiving, no temporary tar file needed
+    let level = options.level.unwrap_or(options.codec.default_level());
+    let mut tar_builder = Builder::new(encoder(options.codec, level, options.rsyncable, writer)?);
+    add_dir_contents_to_tar(source, &mut tar_builder, source, &mut files_added, &file_count, options.clamp_mtime)?;
+
+    print!("Finishing compression... ");
+    tar_builder.into_inner()?.finish()?.flush()?;
     println!("Done");
 
     Ok(())
 }
 
-/// Recurses dir and adds it to the root tar_builder.
-fn add_dir_contents_to_tar(
+/// Header holding only what is needed to restore `metadata`, of a file
+/// not followed if it is a symlink
+fn deterministic_header(metadata: &fs::Metadata, clamp_mtime: Option<u64>) -> Header {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let file_type = metadata.file_type();
+    let entry_type = match file_type {
+        _ if file_type.is_dir() => EntryType::Directory,
+        _ if file_type.is_symlink() => EntryType::Symlink,
+        _ if file_type.is_fifo() => EntryType::Fifo,
+        _ if file_type.is_char_device() => EntryType::Char,
+        _ if file_type.is_block_device() => EntryType::Block,
+        _ => EntryType::Regular,
+    };
+
+    let mtime = metadata.mtime().max(0) as u64;
+    let mut header = Header::new_gnu();
+    header.set_size(if file_type.is_file() { metadata.len() } else { 0 });
+    header.set_mode(metadata.mode() & 0o7777);
+    header.set_uid(metadata.uid() as u64);
+    header.set_gid(metadata.gid() as u64);
+    header.set_mtime(clamp_mtime.map_or(mtime, |clamp| mtime.min(clamp)));
+    header.set_entry_type(entry_type);
+    if matches!(entry_type, EntryType::Char | EntryType::Block) {
+        let _ = header.set_device_major(libc::major(metadata.rdev()));
+        let _ = header.set_device_minor(libc::minor(metadata.rdev()));
+    }
+    header
+}
+
+/// Recurses dir and adds it to the root tar_builder, in order of name.
+fn add_dir_contents_to_tar<W: Write>(
     root: &Path,
-    tar_builder: &mut Builder<File>,
+    tar_builder: &mut Builder<W>,
     dir: &Path,
     files_added: &mut i32,
-    file_count: &usize
+    file_count: &usize,
+    clamp_mtime: Option<u64>,
 ) -> io::Result<()> {
 
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        let name = path.strip_prefix(root).unwrap().to_string_lossy().into_owned();
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    paths.sort();
 
-        if path.is_dir() {
-            tar_builder.append_dir(name, &path)?;
-            add_dir_contents_to_tar(root, tar_builder, &path, files_added, file_count)?;
+    for path in paths {
+        let name = path.strip_prefix(root).unwrap().to_string_lossy().into_owned();
+        let metadata = fs::symlink_metadata(&path)?;
+        let mut header = deterministic_header(&metadata, clamp_mtime);
+
+        if metadata.is_dir() {
+            tar_builder.append_data(&mut header, format!("{}/", name), io::empty())?;
+            add_dir_contents_to_tar(root, tar_builder, &path, files_added, file_count, clamp_mtime)?;
+        } else if metadata.is_symlink() {
+            tar_builder.append_link(&mut header, name, fs::read_link(&path)?)?;
+        } else if header.entry_type() != EntryType::Regular {
+            tar_builder.append_data(&mut header, name, io::empty())?;
+        } else if !metadata.is_file() {
+            // Sockets, made again by their servers
+            continue;
         } else {
             *files_added += 1;
             clear_current_line();
             println!("Archiving: ({}/{})", files_added, file_count );
-            tar_builder.append_path_with_name(&path, name)?;
+            let file = File::open(&path)?;
+            match sparse::data_regions(&file)? {
+                Some(regions) => sparse::append_sparse(tar_builder, &mut header, &name, &file, &regions)?,
+                None => tar_builder.append_data(&mut header, name, file)?,
+            }
         }
     }
 
     Ok(())
 }
 
-// Decompresses and dearchives .tar.gz 
-pub fn demake_tar_gz<SRC, DST>(source: SRC, destination: DST) -> io::Result<()>
+#[test]
+fn test_make_tar_gz_deterministic() {
+    let root = std::env::temp_dir().join("rensen_test_tar_gz");
+    let _ = fs::remove_dir_all(&root);
+
+    // Same content, created in a different order at a different time.
+    // The last one is written rsyncable.
+    let mut archives = Vec::new();
+    let mut tars = Vec::new();
+    for (i, names) in [["a", "b", "sub/c"], ["sub/c", "b", "a"], ["b", "a", "sub/c"]].iter().enumerate() {
+        let source = root.join(format!("source{}", i));
+        fs::create_dir_all(source.join("sub")).unwrap();
+        for name in names.iter() {
+            fs::write(source.join(name), name).unwrap();
+        }
+
+        let archive = root.join(format!("{}.tar.gz", i));
+        let options = ArchiveOptions { clamp_mtime: Some(0), rsyncable: i == 2, ..Default::default() };
+        make_tar_gz_with(&source, &archive, &options).unwrap();
+        archives.push(fs::read(&archive).unwrap());
+
+        let mut tar = Vec::new();
+        decoder(Codec::Gzip, File::open(&archive).unwrap()).unwrap().read_to_end(&mut tar).unwrap();
+        tars.push(tar);
+    }
+
+    assert_eq!(archives[0], archives[1]);
+    assert_eq!(tars[0], tars[2]);
+
+    demake_tar_gz(root.join("2.tar.gz"), root.join("unpacked"), Codec::Gzip).unwrap();
+    assert_eq!(fs::read_to_string(root.join("unpacked/sub/c")).unwrap(), "sub/c");
+
+    // Any codec unpacks the same
+    let source = root.join("plain");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("a"), "a").unwrap();
+    make_tar_gz_with(&source, root.join("plain.tar"), &ArchiveOptions { codec: Codec::None, ..Default::default() }).unwrap();
+    demake_tar_gz(root.join("plain.tar"), root.join("unplain"), Codec::None).unwrap();
+    assert_eq!(fs::read_to_string(root.join("unplain/a")).unwrap(), "a");
+    let _ = fs::remove_dir_all(&root);
+}
+
+// Decompresses and dearchives .tar.gz, or an archive compressed with `codec`
+pub fn demake_tar_gz<SRC, DST>(source: SRC, destination: DST, codec: Codec) -> io::Result<()>
 where
     SRC: AsRef<Path>,
     DST: AsRef<Path>,
@@ -175,14 +319,145 @@ where
     let _ = fs::create_dir_all(destination);
 
     let gz_file = fs::File::open(source)?;
-    let gz_decoder = GzDecoder::new(BufReader::new(gz_file));
+    unpack_tar_gz(BufReader::new(gz_file), destination, codec)
+}
+
+/// Unpacks the .tar.gz `reader` reads into `destination`, failing on the
+/// first member that would end up outside of it: absolute paths, `..`, and
+/// links pointing out of it or written through. Archives compressed with
+/// another codec, or none, are unpacked with `codec`.
+pub fn unpack_tar_gz<R: Read>(reader: R, destination: &Path, codec: Codec) -> io::Result<()> {
+    let mut archive = Archive::new(decoder(codec, reader)?);
+
+    // Directories last, so unpacking their contents does not change their mtimes
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let escapes = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Archive member {:?} {}", path, reason));
+
+        if !is_relative_within(&path) {
+            return Err(escapes("is not below the destination"));
+        }
+        if let Some(target) = entry.link_name()? {
+            let within = match entry.header().entry_type() {
+                // Hard links name another member, symlinks are relative to where they are
+                EntryType::Link => is_relative_within(&target),
+                _ => is_relative_within(&path.parent().unwrap_or(Path::new("")).join(&target)),
+            };
+            if !within {
+                return Err(escapes(&format!("links to {:?}, outside of the destination", target)));
+            }
+        }
+        if !is_contained(destination, &destination.join(&path)) {
+            return Err(escapes("would be written through a symlink"));
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Directory => directories.push(entry),
+            _ => if !entry.unpack_in(destination)? {
+                return Err(escapes("is not below the destination"));
+            },
+        }
+    }
 
-    let mut archive = Archive::new(gz_decoder);
-    archive.unpack(destination)?;
+    for mut directory in directories {
+        directory.unpack_in(destination)?;
+    }
 
     Ok(())
 }
 
+/// Whether the relative `path` stays below where it starts, `..` and all
+fn is_relative_within(path: &Path) -> bool {
+    let mut depth: usize = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    true
+}
+
+/// Whether writing `path` stays below `root`: it is there by its name, and
+/// neither it nor a directory between them is a symlink already
+pub fn is_contained(root: &Path, path: &Path) -> bool {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) if relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) => relative,
+        _ => return false,
+    };
+
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return false,
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+
+    true
+}
+
+#[test]
+fn test_unpack_rejects_escapes() {
+    let root = std::env::temp_dir().join("rensen_test_unpack_escapes");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    // Names are written raw, the tar crate refuses to build such members itself
+    let member = |name: &str, kind: EntryType, link: &str, data: &[u8]| {
+        let mut header = Header::new_gnu();
+        let gnu = header.as_gnu_mut().unwrap();
+        gnu.name[..name.len()].copy_from_slice(name.as_bytes());
+        gnu.linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(kind);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        (header, data.to_vec())
+    };
+    let archive = |name: &str, members: Vec<(Header, Vec<u8>)>| {
+        let path = root.join(name);
+        let mut builder = Builder::new(flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::fast()));
+        for (header, data) in members {
+            builder.append(&header, data.as_slice()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    };
+
+    let outside = root.join("outside");
+    let cases = [
+        archive("parent.tar.gz", vec![member("../outside", EntryType::Regular, "", b"1")]),
+        archive("absolute.tar.gz", vec![member(&outside.to_string_lossy(), EntryType::Regular, "", b"1")]),
+        archive("symlink.tar.gz", vec![member("link", EntryType::Symlink, &root.to_string_lossy(), b""), member("link/outside", EntryType::Regular, "", b"1")]),
+        archive("relative.tar.gz", vec![member("link", EntryType::Symlink, "../..", b"")]),
+        archive("hardlink.tar.gz", vec![member("link", EntryType::Link, "../outside", b"")]),
+    ];
+    for case in cases.iter() {
+        let destination = root.join("unpacked");
+        let err = demake_tar_gz(case, &destination, Codec::Gzip).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", case);
+        assert!(!outside.exists());
+        let _ = fs::remove_dir_all(&destination);
+    }
+
+    // Links that stay within are kept, nothing is written through them
+    let within = archive("within.tar.gz", vec![member("dir/", EntryType::Directory, "", b""), member("dir/link", EntryType::Symlink, "../file", b""), member("file", EntryType::Regular, "", b"1")]);
+    demake_tar_gz(&within, root.join("unpacked"), Codec::Gzip).unwrap();
+    assert_eq!(fs::read_to_string(root.join("unpacked/dir/link")).unwrap(), "1");
+    assert!(!is_contained(&root.join("unpacked"), &root.join("unpacked/dir/link")));
+    assert!(!is_contained(&root.join("unpacked"), &root.join("unpacked/../outside")));
+    assert!(is_contained(&root.join("unpacked"), &root.join("unpacked/dir/new")));
+    let _ = fs::remove_dir_all(&root);
+}
+
 impl ConvertFromPath for PathBuf {
     fn convert_from_path(path: &Path) -> Self {
         path.to_path_buf()
@@ -215,19 +490,19 @@ pub fn strip_double_extension(path: &Path) -> PathBuf {
     let mut path = path.to_path_buf();
     strip_extension(&mut path);
     strip_extension(&mut path);
-    return path;
+    path
 }
 
 #[test]
 fn test_strip_tar_gz_extension() {
     let original = PathBuf::from("path/to/certain/path.tar.gz");
     let new = strip_double_extension(&original);
-    assert!(original == new,"{:?} - {:?}", original, new);
+    assert!(new == Path::new("path/to/certain/path"), "{:?} - {:?}", original, new);
 }
 
 /// Replaces the prefix part of 
 /// path1 and path2 with somehting else
-pub fn replace_common_prefix(one: &PathBuf, two: &PathBuf, replacement: &PathBuf) -> PathBuf {
+pub fn replace_common_prefix(one: &Path, two: &Path, replacement: &Path) -> PathBuf {
     let common_prefix = one.components()
         .zip(two.components())
         .take_while(|(a, b)| a == b)
@@ -240,7 +515,7 @@ pub fn replace_common_prefix(one: &PathBuf, two: &PathBuf, replacement: &PathBuf
         new_path.push(component);
     }
     
-    return new_path;
+    new_path
 }
 
 /// Wrapper for std::fs::copy which forces the write by
@@ -258,20 +533,102 @@ pub fn force_copy(source: &PathBuf, destination: &PathBuf) -> io::Result<()> {
 
     let mut source_file = File::open(source)?;
     let mut destination_file = File::create(destination)?;
-    io::copy(&mut source_file, &mut destination_file)?;
+    sparse::copy_file(&mut source_file, &mut destination_file)?;
 
     Ok(())
 }
 
 #[test]
 fn test_hash() {
-    let path = Path::new("src/hosts");
+    let path = Path::new("tests/test.yaml");
     match hash_file(path, 0) {
         Ok(hash) => println!("Hash: {}", hash),
         Err(err) => panic!("Error: {:?}", err),
     }
 }
 
+/// Hash (sha3-256) of the whole file at `path`
+pub fn digest_file(path: &Path) -> Result<String, Trap> {
+    let mut file = File::open(path).map_err(|err| {
+        Trap::FS(format!("Could not open {:?}: {}", path, err))
+    })?;
+
+    let mut sha3_256 = Sha3_256::new();
+    io::copy(&mut file, &mut sha3_256).map_err(|err| {
+        Trap::FS(format!("Could not read from {:?}: {}", path, err))
+    })?;
+
+    Ok(format!("{:x}", sha3_256.finalize()))
+}
+
+/// SHA3-256 of the whole file at `path`, as kept in records
+pub fn hash_contents(path: &Path) -> Result<String, Trap> {
+    let mut sha3_256 = Sha3_256::new();
+    File::open(path)
+        .and_then(|mut file| io::copy(&mut file, &mut sha3_256))
+        .map_err(|err| Trap::FS(format!("Could not hash {:?}: {}", path, err)))?;
+
+    Ok(format!("{:x}", sha3_256.finalize()))
+}
+
+/// SHA3-256 of the first, middle and last 1024 bytes of the `size` bytes
+/// of `reader`, the blocks hash_file would read. Tells a file that was only
+/// touched from one that changed without reading it whole.
+pub fn sample_digest<R: Read + Seek>(reader: &mut R, size: u64) -> io::Result<String> {
+    let mut sha3_256 = Sha3_256::new();
+    let mut buffer = [0; 1024];
+    for pos in [0, size / 2, size.saturating_sub(1024)] {
+        reader.seek(SeekFrom::Start(pos))?;
+        let bytes_read = reader.read(&mut buffer)?;
+        sha3_256.update(&buffer[..bytes_read]);
+    }
+
+    Ok(format!("{:x}", sha3_256.finalize()))
+}
+
+/// sample_digest of the `size` bytes of a `reader` that can not seek, e.g.
+/// the output of a command, which is read to the end for it
+pub fn sample_stream_digest<R: Read>(reader: &mut R, size: u64) -> io::Result<String> {
+    let starts = [0, size / 2, size.saturating_sub(1024)];
+    let mut samples = [Vec::new(), Vec::new(), Vec::new()];
+    let mut buffer = [0; 8192];
+    let mut offset = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        for (start, sample) in starts.iter().zip(samples.iter_mut()) {
+            let (from, to) = ((*start).max(offset), (start + 1024).min(offset + bytes_read as u64));
+            if from < to {
+                sample.extend_from_slice(&buffer[(from - offset) as usize..(to - offset) as usize]);
+            }
+        }
+        offset += bytes_read as u64;
+    }
+
+    let mut sha3_256 = Sha3_256::new();
+    samples.iter().for_each(|sample| sha3_256.update(sample));
+    Ok(format!("{:x}", sha3_256.finalize()))
+}
+
+#[test]
+fn test_sample_stream_digest() {
+    let contents: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+    for size in [0, 100, 1024, 1500, 2048, 5000] {
+        let contents = &contents[..size];
+        let sampled = sample_digest(&mut io::Cursor::new(contents), size as u64).unwrap();
+        assert_eq!(sample_stream_digest(&mut &*contents, size as u64).unwrap(), sampled);
+    }
+}
+
+/// SHA3-256 of everything read from `reader`, as hash_contents of a file
+pub fn digest_reader<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut sha3_256 = Sha3_256::new();
+    io::copy(reader, &mut sha3_256)?;
+    Ok(format!("{:x}", sha3_256.finalize()))
+}
+
 /// Read the next 1024 bytes from the 'pos'-th byte.
 pub fn hash_file(path: &Path, pos: u64) -> Result<String, Trap> {
     let mut file = File::open(path).map_err(|err| {
@@ -296,3 +653,60 @@ pub fn hash_file(path: &Path, pos: u64) -> Result<String, Trap> {
 
     Ok(format!("{:x}", sha3_256.finalize()))
 }
+
+/// Writes `path` through a temporary file next to it, synced and renamed over
+/// it, so readers and a crash midway see either the old or the new contents
+pub fn write_atomic<F>(path: &Path, write: F) -> io::Result<()>
+where F: FnOnce(&mut BufWriter<File>) -> io::Result<()> {
+    let file_name = path.file_name()
+        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a file", path)))?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let written = write(&mut writer)
+        .and_then(|_| writer.flush())
+        .and_then(|_| writer.get_ref().sync_all());
+    drop(writer);
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+
+    fs::rename(&temp_path, path)?;
+
+    // The rename is only durable once the directory is
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Uid and primary gid of the user `name`, None if there is none
+pub fn user_ids(name: &str) -> Option<(u32, u32)> {
+    let c_name = std::ffi::CString::new(name).ok()?;
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return None;
+    }
+
+    let passwd = unsafe { &*passwd };
+    Some((passwd.pw_uid, passwd.pw_gid))
+}
+
+#[test]
+fn test_write_atomic() {
+    let path = std::env::temp_dir().join("rensen_test_write_atomic.json");
+    write_atomic(&path, |writer| writer.write_all(b"old")).unwrap();
+    write_atomic(&path, |writer| writer.write_all(b"new")).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+    // A write failing midway leaves what was there
+    let failed = write_atomic(&path, |writer| {
+        writer.write_all(b"partial")?;
+        Err(io::Error::other("disk full"))
+    });
+    assert!(failed.is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert!(!path.with_file_name(".rensen_test_write_atomic.json.tmp").exists());
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,361 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sha3::{Digest, Sha3_256};
+use tar::Archive;
+
+use crate::chunks::ChunkStore;
+use crate::codec::{decoder, Codec};
+use crate::compact::snapshot_time;
+use crate::config::{GlobalConfig, HostConfig};
+use crate::lock::HostLock;
+use crate::logging::Trap;
+use crate::record::Record;
+use crate::sandbox;
+use crate::traits::JsonFile;
+
+/// Default share of a host's snapshots checked per scheduled verify
+pub const DEFAULT_VERIFY_PERCENT: u8 = 10;
+
+/// Last verification of a snapshot
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Verified {
+    pub time: i64,
+    pub ok: bool,
+}
+
+/// When each snapshot of a host was last verified, by snapshot name.
+/// Stored at $backups/$identifier/.records/verified.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VerifyState {
+    pub snapshots: BTreeMap<String, Verified>,
+}
+
+impl VerifyState {
+    pub fn path(global_config: &GlobalConfig, host_config: &HostConfig) -> PathBuf {
+        host_config.root(global_config)
+            .join(".records")
+            .join("verified.json")
+    }
+
+    /// `percent` of `snapshots` (at least one), never verified ones first and
+    /// then those verified the longest ago. Running this every night walks
+    /// through all snapshots of a host in 100 / `percent` nights.
+    pub fn pick(&self, snapshots: &[String], percent: u8) -> Vec<String> {
+        let count = (snapshots.len() * percent.min(100) as usize).div_ceil(100).max(1);
+
+        let mut snapshots = snapshots.to_vec();
+        snapshots.sort_by_key(|name| (self.snapshots.get(name).map(|verified| verified.time), name.clone()));
+        snapshots.truncate(count);
+        snapshots
+    }
+}
+
+impl JsonFile for VerifyState {
+    fn serialize_json(&self, file_path: &Path) -> std::io::Result<()> {
+        let mut file = File::create(file_path)?;
+        let json_str = serde_json::to_string_pretty(&self)?;
+        write!(file, "{}", json_str)?;
+        Ok(())
+    }
+
+    fn deserialize_json(file_path: &Path) -> std::io::Result<Self> {
+        let mut file = match File::open(file_path) {
+            Ok(v) => v,
+            Err(_) => return Ok(VerifyState::default()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let state: VerifyState = serde_json::from_str(&contents)?;
+        Ok(state)
+    }
+}
+
+/// Outcome of verifying one snapshot
+#[derive(Debug, Clone, Default)]
+pub struct VerifyResult {
+    pub snapshot: String,
+    pub files: usize,
+    pub problems: Vec<String>,
+    pub damaged: Vec<PathBuf>, // source paths of the files that are missing or do not match their hash
+}
+
+impl VerifyResult {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Reads the whole archive of a snapshot and checks that every file its
+/// record says was archived in it is there with the right size and hash, in
+/// `chunks` for files backed up with `dedup`. Records which have been
+/// compacted only get the archive itself checked, files recorded before
+/// hashes were kept only their size.
+pub fn verify_snapshot(snapshot_path: &Path, record: &Record, chunks: &ChunkStore) -> Result<VerifyResult, Trap> {
+    verify_snapshot_with(snapshot_path, record, chunks, |file| Box::new(file))
+}
+
+/// Like verify_snapshot, reading the archive through what `wrap` makes of
+/// it, e.g. to keep to a rate
+pub fn verify_snapshot_with(snapshot_path: &Path, record: &Record, chunks: &ChunkStore, wrap: impl FnOnce(File) -> Box<dyn Read>) -> Result<VerifyResult, Trap> {
+    let snapshot = snapshot_path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let codec = record.codec.unwrap_or_default();
+    let archive_path = codec.archive(snapshot_path);
+    let mut result = VerifyResult { snapshot, ..Default::default() };
+
+    let file = match File::open(&archive_path) {
+        Ok(file) => file,
+        Err(err) => {
+            result.problems.push(format!("Could not open {:?}: {}", archive_path, err));
+            return Ok(result);
+        }
+    };
+
+    let contents: HashMap<PathBuf, (u64, String)> = match sandbox::hash_archive(&mut wrap(file), codec)? {
+        Ok(hashed) => hashed.into_iter().map(|entry| (entry.path, (entry.size, entry.hash))).collect(),
+        Err(ArchiveProblem::Corrupt(err)) => {
+            result.problems.push(format!("Corrupt archive {:?}: {}", archive_path, err));
+            return Ok(result);
+        },
+        Err(ArchiveProblem::Entry(path, err)) => {
+            result.problems.push(format!("Corrupt entry {:?} in {:?}: {}", path, archive_path, err));
+            return Ok(result);
+        },
+    };
+
+    // Chunks are shared, each is read once
+    let mut chunk_sizes: HashMap<String, u64> = HashMap::new();
+    for (source, entry) in record.snapshot.entries.iter().filter(|(_, entry)| entry.snapshot_path == snapshot_path) {
+        let name = match entry.file_path.strip_prefix(snapshot_path) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        result.files += 1;
+        let problems = result.problems.len();
+
+        // Chunks are checked against their own digests as they are read
+        if let Some(digests) = &entry.chunks {
+            let mut size = 0;
+            for digest in digests {
+                match chunk_sizes.get(digest) {
+                    Some(chunk_size) => size += chunk_size,
+                    None => match chunks.read(digest, &mut std::io::sink()) {
+                        Ok(chunk_size) => {
+                            chunk_sizes.insert(digest.clone(), chunk_size);
+                            size += chunk_size;
+                        },
+                        Err(err) => result.problems.push(format!("{:?}: {}", name, err)),
+                    },
+                }
+            }
+            if size != entry.size {
+                result.problems.push(format!("{:?} is {} bytes in chunks, expected {}", name, size, entry.size));
+            }
+        } else {
+            match contents.get(name) {
+                Some((size, _)) if *size != entry.size => result.problems.push(format!("{:?} is {} bytes, expected {}", name, size, entry.size)),
+                Some((_, hash)) if entry.hash.as_ref().is_some_and(|expected| expected != hash) => {
+                    result.problems.push(format!("{:?} does not match its hash", name));
+                },
+                Some(_) => (),
+                None => result.problems.push(format!("{:?} is missing", name)),
+            }
+        }
+
+        if result.problems.len() > problems {
+            result.damaged.push(source.clone());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Size and SHA3-256 of an entry of an archive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HashedEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Why an archive could not be read to its end
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ArchiveProblem {
+    Corrupt(String),
+    Entry(PathBuf, String),
+}
+
+/// Hashes every entry of the .tar.gz, or archive compressed with `codec`,
+/// `reader` reads, reading each to the end so the gzip checksums are checked
+pub fn hash_entries(reader: impl Read, codec: Codec) -> Result<Vec<HashedEntry>, ArchiveProblem> {
+    let decoded = decoder(codec, BufReader::new(reader))
+        .map_err(|err| ArchiveProblem::Corrupt(err.to_string()))?;
+    let mut archive = Archive::new(decoded);
+    let entries = archive.entries()
+        .map_err(|err| ArchiveProblem::Corrupt(err.to_string()))?;
+
+    let mut hashed = Vec::new();
+    for entry in entries {
+        let mut entry = entry.map_err(|err| ArchiveProblem::Corrupt(err.to_string()))?;
+        let path = entry.path().map(|path| path.into_owned()).unwrap_or_default();
+        let mut sha3_256 = Sha3_256::new();
+        let size = std::io::copy(&mut entry, &mut sha3_256)
+            .map_err(|err| ArchiveProblem::Entry(path.clone(), err.to_string()))?;
+        hashed.push(HashedEntry { path, size, hash: format!("{:x}", sha3_256.finalize()) });
+    }
+
+    Ok(hashed)
+}
+
+/// Names of all snapshots of a host, oldest first
+pub fn snapshots(global_config: &GlobalConfig, host_config: &HostConfig) -> Vec<String> {
+    let records_path = host_config.root(global_config)
+        .join(".records");
+
+    let mut snapshots: Vec<String> = fs::read_dir(records_path)
+        .map(|entries| entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.path().file_stem().and_then(|stem| stem.to_str()).map(String::from))
+            .filter(|stem| snapshot_time(stem).is_some())
+            .collect())
+        .unwrap_or_default();
+
+    snapshots.sort();
+    snapshots
+}
+
+/// Verifies `percent` of the snapshots of `host_config`, see VerifyState::pick,
+/// and records the outcome. On a read-only replica the outcome is not saved.
+pub fn verify_host(global_config: &GlobalConfig, host_config: &HostConfig, percent: u8, now: i64) -> Result<Vec<VerifyResult>, Trap> {
+    let state_path = VerifyState::path(global_config, host_config);
+    let mut state = VerifyState::deserialize_json(&state_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read {:?}: {}", state_path, err)))?;
+
+    let host_root_path = host_config.root(global_config);
+    let chunks = ChunkStore::new(global_config);
+    let mut results = Vec::new();
+
+    for snapshot in state.pick(&snapshots(global_config, host_config), percent) {
+        let record_path = host_root_path.join(".records").join(format!("{}.json", snapshot));
+        let record = Record::deserialize_json(&record_path)
+            .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+
+        let result = verify_snapshot(&host_root_path.join(&snapshot), &record, &chunks)?;
+        state.snapshots.insert(snapshot, Verified { time: now, ok: result.is_ok() });
+        results.push(result);
+    }
+
+    if !global_config.is_read_only() {
+        state.serialize_json(&state_path)
+            .map_err(|err| Trap::Serialize(format!("Could not write {:?}: {}", state_path, err)))?;
+    }
+
+    Ok(results)
+}
+
+/// Drops the files of `results` that are damaged from the live record of
+/// `host_config`, so its next run fetches them again. Only files the live
+/// record still has from the damaged snapshot are dropped, newer copies are
+/// fine. Returns the source paths dropped.
+pub fn mark_for_refetch(global_config: &GlobalConfig, host_config: &HostConfig, results: &[VerifyResult]) -> Result<Vec<PathBuf>, Trap> {
+    global_config.ensure_writable("mark damaged files to be fetched again")?;
+    let _lock = HostLock::acquire(global_config, host_config)?;
+
+    let host_root_path = host_config.root(global_config);
+    let record_path = host_root_path.join(".records").join("record.json");
+    let mut record = Record::deserialize_json(&record_path)
+        .map_err(|err| Trap::Deserialize(format!("Could not read record {:?}: {}", record_path, err)))?;
+
+    let mut dropped = Vec::new();
+    for result in results {
+        let snapshot_path = host_root_path.join(&result.snapshot);
+        for source in result.damaged.iter() {
+            if record.snapshot.entries.get(source).is_some_and(|entry| entry.snapshot_path == snapshot_path) {
+                if let Some(size) = record.snapshot.size(source) {
+                    record.size = record.size.saturating_sub(*size);
+                }
+                record.snapshot.entries.remove(source);
+                dropped.push(source.clone());
+            }
+        }
+    }
+
+    if !dropped.is_empty() {
+        record.serialize_json(&record_path)
+            .map_err(|err| Trap::Serialize(format!("Could not write record {:?}: {}", record_path, err)))?;
+    }
+
+    Ok(dropped)
+}
+
+#[test]
+fn test_verify_state_pick() {
+    let snapshots: Vec<String> = (0..20).map(|i| format!("2024-01-{:02}-00-00-00", i + 1)).collect();
+    let mut state = VerifyState::default();
+
+    let first = state.pick(&snapshots, 10);
+    assert_eq!(first, vec![snapshots[0].clone(), snapshots[1].clone()]);
+    for name in first.iter() {
+        state.snapshots.insert(name.clone(), Verified { time: 1, ok: true });
+    }
+
+    assert_eq!(state.pick(&snapshots, 10), vec![snapshots[2].clone(), snapshots[3].clone()]);
+    assert_eq!(state.pick(&snapshots[..3], 1).len(), 1);
+}
+
+#[test]
+fn test_verify_snapshot() {
+    use crate::snapshot::FileEntry;
+    use crate::utils::make_tar_gz;
+
+    let root = std::env::temp_dir().join("rensen_test_verify");
+    let _ = fs::remove_dir_all(&root);
+    let snapshot_path = root.join("2024-01-01-00-00-00");
+    fs::create_dir_all(snapshot_path.join("src")).unwrap();
+    fs::write(snapshot_path.join("src/a"), "1234").unwrap();
+    make_tar_gz(&snapshot_path, root.join("2024-01-01-00-00-00.tar.gz")).unwrap();
+
+    let chunks = ChunkStore { root: root.join(".chunks") };
+    let mut record = Record::new();
+    record.snapshot.entries.insert(PathBuf::from("/src/a"), FileEntry::from(snapshot_path.join("src/a"), snapshot_path.clone(), 0, 4));
+    assert!(verify_snapshot(&snapshot_path, &record, &chunks).unwrap().is_ok());
+
+    record.snapshot.entries.insert(PathBuf::from("/src/b"), FileEntry::from(snapshot_path.join("src/b"), snapshot_path.clone(), 0, 1));
+    assert_eq!(verify_snapshot(&snapshot_path, &record, &chunks).unwrap().problems.len(), 1);
+
+    // Same size, other contents than were fetched
+    record.snapshot.entries.get_mut(Path::new("/src/a")).unwrap().hash = Some(crate::utils::hash_contents(&root.join("2024-01-01-00-00-00.tar.gz")).unwrap());
+    let result = verify_snapshot(&snapshot_path, &record, &chunks).unwrap();
+    assert!(result.problems.contains(&String::from("\"src/a\" does not match its hash")));
+    assert_eq!(result.damaged.len(), 2);
+    record.snapshot.entries.get_mut(Path::new("/src/a")).unwrap().hash = Some(format!("{:x}", Sha3_256::digest(b"1234")));
+    assert_eq!(verify_snapshot(&snapshot_path, &record, &chunks).unwrap().damaged, vec![PathBuf::from("/src/b")]);
+
+    // Read by the name and with the codec its record has
+    crate::utils::demake_tar_gz(root.join("2024-01-01-00-00-00.tar.gz"), &snapshot_path, Codec::Gzip).unwrap();
+    crate::utils::make_tar_gz_with(&snapshot_path, Codec::None.archive(&snapshot_path), &crate::utils::ArchiveOptions { codec: Codec::None, ..Default::default() }).unwrap();
+    let plain = Record { codec: Some(Codec::None), ..record.clone() };
+    assert_eq!(verify_snapshot(&snapshot_path, &plain, &chunks).unwrap().damaged, vec![PathBuf::from("/src/b")]);
+
+    // Only what the live record still has from the snapshot is fetched again
+    let global_config = GlobalConfig { backups: root.clone(), ..Default::default() };
+    let host_config = HostConfig { identifier: String::from("host"), ..Default::default() };
+    let host_snapshot = root.join("host/2024-01-01-00-00-00");
+    fs::create_dir_all(root.join("host/.records")).unwrap();
+    let mut live = Record::new();
+    live.snapshot.entries.insert(PathBuf::from("/src/a"), FileEntry::from(host_snapshot.join("src/a"), host_snapshot.clone(), 0, 4));
+    live.snapshot.entries.insert(PathBuf::from("/src/b"), FileEntry::from(root.join("host/2024-01-02-00-00-00/src/b"), root.join("host/2024-01-02-00-00-00"), 0, 1));
+    live.serialize_json(&root.join("host/.records/record.json")).unwrap();
+
+    let damaged = VerifyResult { snapshot: String::from("2024-01-01-00-00-00"), damaged: vec![PathBuf::from("/src/a"), PathBuf::from("/src/b")], ..Default::default() };
+    assert_eq!(mark_for_refetch(&global_config, &host_config, &[damaged]).unwrap(), vec![PathBuf::from("/src/a")]);
+    let live = Record::deserialize_json(&root.join("host/.records/record.json")).unwrap();
+    assert_eq!(live.snapshot.entries.keys().collect::<Vec<_>>(), vec![Path::new("/src/b")]);
+    let _ = fs::remove_dir_all(&root);
+}
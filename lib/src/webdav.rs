@@ -0,0 +1,422 @@
+use serde::{Serialize, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logging::Trap;
+use crate::store::{Spool, STAGING_SUFFIX};
+use crate::traits::Store;
+
+/// MiB per chunk of a chunked upload
+pub const DEFAULT_CHUNK_SIZE: u64 = 10;
+
+/// Times a request answered with 5xx, or not at all, is tried again
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// A WebDAV collection, e.g. a folder of a Nextcloud
+///
+/// webdav:
+///   url: https://cloud.example.com/remote.php/dav/files/backup/rensen
+///   uploads: https://cloud.example.com/remote.php/dav/uploads/backup
+///   user: backup
+///   password_file: /etc/rensen/nextcloud.pass
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WebDavConfig {
+    pub url: String,              // collection the objects go below
+    pub user: String,
+    pub password_file: PathBuf,   // holding the (app) password of `user`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploads: Option<String>,  // Nextcloud upload collection of `user`, for chunked uploads, default: one PUT per object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<u64>,  // MiB per chunk, default: 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,     // default: 3
+}
+
+/// An object being put, until it is finalized
+enum Upload {
+    Chunked { id: String, buffer: Vec<u8>, chunks: u32 },
+    Spooled { path: PathBuf, file: File },
+}
+
+/// Store on a WebDAV server, spoken to through curl. With `uploads` objects
+/// are sent in chunks as they are written and assembled by a MOVE of
+/// Nextcloud's `.file`, otherwise spooled to a file of `spool`, PUT next to
+/// their key and moved onto it. Either way they only show up once finalized.
+pub struct WebDavStore {
+    pub config: WebDavConfig,
+    pub curl: PathBuf,     // default: `curl` from $PATH
+    pub chunk_bytes: usize,
+    pub retry_delay: Duration, // doubled with every retry
+    pub spool: Spool,      // chunks, objects to PUT and ones fetched go through
+    password: String,
+    uploads: RefCell<HashMap<PathBuf, Rc<RefCell<Upload>>>>,
+}
+
+impl WebDavStore {
+    pub fn new(config: &WebDavConfig, spool: Spool) -> Result<Self, Trap> {
+        let password = fs::read_to_string(&config.password_file)
+            .map_err(|err| Trap::WebDav(format!("Could not read password file {:?}: {}", config.password_file, err)))?;
+
+        Ok(Self {
+            config: config.clone(),
+            curl: PathBuf::from("curl"),
+            chunk_bytes: (config.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE) * 1024 * 1024) as usize,
+            retry_delay: Duration::from_secs(1),
+            spool,
+            password: password.trim_end_matches(['\r', '\n']).to_string(),
+            uploads: RefCell::new(HashMap::new()),
+        })
+    }
+
+    fn url(&self, key: &Path) -> String {
+        format!("{}/{}", self.config.url.trim_end_matches('/'), encode(key))
+    }
+
+    /// Runs one request, trying it again on 5xx or when it got no answer.
+    /// Returns the status and body of the last answer.
+    fn request(&self, method: &str, url: &str, headers: &[String], upload: Option<&Path>, output: Option<&Path>) -> Result<(u16, String), Trap> {
+        let retries = self.config.retries.unwrap_or(DEFAULT_RETRIES);
+        let mut attempt = 0;
+        loop {
+            let answer = self.send(method, url, headers, upload, output);
+            let retry = match &answer {
+                Ok((status, _)) => *status >= 500,
+                Err(_) => true,
+            };
+            if !retry || attempt >= retries {
+                return answer;
+            }
+
+            thread::sleep(self.retry_delay * 2u32.pow(attempt));
+            attempt += 1;
+        }
+    }
+
+    fn send(&self, method: &str, url: &str, headers: &[String], upload: Option<&Path>, output: Option<&Path>) -> Result<(u16, String), Trap> {
+        let mut command = Command::new(&self.curl);
+        command.args(["-sS", "-K", "-", "-X", method, "-w", "\n%{http_code}"]);
+        command.arg("-o").arg(output.unwrap_or(Path::new("-")));
+        for header in headers {
+            command.arg("-H").arg(header);
+        }
+        if let Some(upload) = upload {
+            command.arg("-T").arg(upload);
+        }
+        command.arg(url);
+
+        // The credentials go in on stdin, never on the command line
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| Trap::WebDav(format!("Could not run {:?}: {}", self.curl, err)))?;
+        let credentials = format!("user = \"{}:{}\"\n", escape(&self.config.user), escape(&self.password));
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(credentials.as_bytes());
+        }
+
+        let output = child.wait_with_output()
+            .map_err(|err| Trap::WebDav(format!("Could not run {:?}: {}", self.curl, err)))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (body, status) = stdout.rsplit_once('\n').unwrap_or(("", &stdout));
+        match status.trim().parse::<u16>() {
+            Ok(status) if status > 0 => Ok((status, body.to_string())),
+            _ => Err(Trap::WebDav(format!("{} {} got no answer: {}", method, url, String::from_utf8_lossy(&output.stderr).trim()))),
+        }
+    }
+
+    /// `request`, failing on any status but `ok`
+    fn expect(&self, method: &str, url: &str, headers: &[String], upload: Option<&Path>, ok: &[u16]) -> Result<String, Trap> {
+        match self.request(method, url, headers, upload, None)? {
+            (status, body) if (200..300).contains(&status) || ok.contains(&status) => Ok(body),
+            (status, _) => Err(Trap::WebDav(format!("{} {} answered {}", method, url, status))),
+        }
+    }
+
+    /// Creates the collections of every parent of `key`, 405 being one that
+    /// is there already
+    fn make_parents(&self, key: &Path) -> Result<(), Trap> {
+        let mut parent = PathBuf::new();
+        for component in key.parent().into_iter().flat_map(|parent| parent.components()) {
+            parent.push(component);
+            self.expect("MKCOL", &self.url(&parent), &[], None, &[405])?;
+        }
+
+        Ok(())
+    }
+
+    fn upload_chunk(&self, id: &str, number: u32, buffer: &[u8], destination: &str) -> Result<(), Trap> {
+        let (spool, mut file) = self.spool.create(&format!("{}-{:05}", id, number))?;
+        if let Err(err) = file.write_all(buffer) {
+            let _ = fs::remove_file(&spool);
+            return Err(Trap::FS(format!("Could not write chunk {:?}: {}", spool, err)));
+        }
+
+        let url = format!("{}/{}/{:05}", self.uploads_url(), id, number);
+        let sent = self.expect("PUT", &url, &[format!("Destination: {}", destination)], Some(&spool), &[]);
+        let _ = fs::remove_file(&spool);
+        sent.map(|_| ())
+    }
+
+    fn uploads_url(&self) -> String {
+        self.config.uploads.as_deref().unwrap_or_default().trim_end_matches('/').to_string()
+    }
+}
+
+/// Writer of an object being put, which a WebDavStore finishes in finalize
+struct UploadWriter<'a> {
+    store: &'a WebDavStore,
+    key: PathBuf,
+    upload: Rc<RefCell<Upload>>,
+}
+
+impl Write for UploadWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut upload = self.upload.borrow_mut();
+        match &mut *upload {
+            Upload::Spooled { file, .. } => file.write(buf),
+            Upload::Chunked { id, buffer, chunks } => {
+                buffer.extend_from_slice(buf);
+                while buffer.len() >= self.store.chunk_bytes {
+                    let chunk: Vec<u8> = buffer.drain(..self.store.chunk_bytes).collect();
+                    *chunks += 1;
+                    self.store.upload_chunk(id, *chunks, &chunk, &self.store.url(&self.key))
+                        .map_err(|err| io::Error::other(err.to_string()))?;
+                }
+                Ok(buf.len())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.upload.borrow_mut() {
+            Upload::Spooled { file, .. } => file.flush(),
+            Upload::Chunked { .. } => Ok(()),
+        }
+    }
+}
+
+impl Store for WebDavStore {
+    fn put(&self, key: &Path) -> Result<Box<dyn Write + '_>, Trap> {
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or(0);
+        let name: String = key.to_string_lossy().chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '-' })
+            .collect();
+        let id = format!("rensen-{}-{}", name, stamp);
+
+        let upload = match self.config.uploads.is_some() {
+            true => {
+                self.make_parents(key)?;
+                let url = format!("{}/{}", self.uploads_url(), id);
+                self.expect("MKCOL", &url, &[format!("Destination: {}", self.url(key))], None, &[])?;
+                Upload::Chunked { id, buffer: Vec::new(), chunks: 0 }
+            },
+            false => {
+                let (path, file) = self.spool.create(&id)?;
+                Upload::Spooled { path, file }
+            },
+        };
+
+        let upload = Rc::new(RefCell::new(upload));
+        self.uploads.borrow_mut().insert(key.to_path_buf(), Rc::clone(&upload));
+        Ok(Box::new(UploadWriter { store: self, key: key.to_path_buf(), upload }))
+    }
+
+    fn finalize(&self, key: &Path) -> Result<(), Trap> {
+        let upload = self.uploads.borrow_mut().remove(key)
+            .ok_or(Trap::WebDav(format!("Nothing was put at {:?} to finalize", key)))?;
+        let destination = format!("Destination: {}", self.url(key));
+        let overwrite = String::from("Overwrite: T");
+
+        match &mut *upload.borrow_mut() {
+            Upload::Chunked { id, buffer, chunks } => {
+                if !buffer.is_empty() || *chunks == 0 {
+                    *chunks += 1;
+                    self.upload_chunk(id, *chunks, buffer, &self.url(key))?;
+                }
+
+                // Nextcloud assembles the chunks into the destination at once
+                let url = format!("{}/{}/.file", self.uploads_url(), id);
+                self.expect("MOVE", &url, &[destination, overwrite], None, &[])?;
+            },
+            Upload::Spooled { path, file } => {
+                let _ = file.flush();
+                self.make_parents(key)?;
+                let staging = PathBuf::from(format!("{}{}", key.display(), STAGING_SUFFIX));
+                let sent = self.expect("PUT", &self.url(&staging), &[], Some(path), &[])
+                    .and_then(|_| self.expect("MOVE", &self.url(&staging), &[destination, overwrite], None, &[]));
+                let _ = fs::remove_file(&path);
+                sent?;
+            },
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, key: &Path) -> Result<Box<dyn Read>, Trap> {
+        let (path, file) = self.spool.create(&format!("get-{}", key.to_string_lossy()))?;
+        let url = self.url(key);
+        let answer = self.request("GET", &url, &[], None, Some(&path));
+        let file = match answer {
+            Ok((200, _)) => Ok(file),
+            Ok((status, _)) => Err(Trap::WebDav(format!("GET {} answered {}", url, status))),
+            Err(err) => Err(err),
+        };
+
+        // Readable until closed, nothing is left behind
+        let _ = fs::remove_file(&path);
+        Ok(Box::new(file?))
+    }
+
+    fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>, Trap> {
+        let base = url_path(&self.config.url).trim_end_matches('/').to_string();
+        let mut keys = Vec::new();
+        let mut pending = vec![prefix.to_path_buf()];
+
+        while let Some(collection) = pending.pop() {
+            let url = format!("{}/", self.url(&collection).trim_end_matches('/'));
+            let body = match self.request("PROPFIND", &url, &[String::from("Depth: 1")], None, None)? {
+                (404, _) => continue,
+                (207, body) => body,
+                (status, _) => return Err(Trap::WebDav(format!("PROPFIND {} answered {}", url, status))),
+            };
+
+            for href in hrefs(&body) {
+                let Some(key) = decode(&url_path(&href)).strip_prefix(base.as_str()).map(|key| key.trim_start_matches('/').to_string()) else { continue };
+                match key.strip_suffix('/') {
+                    Some(dir) if Path::new(dir) != collection && !dir.is_empty() => pending.push(PathBuf::from(dir)),
+                    Some(_) => (),
+                    None if !key.is_empty() && !key.ends_with(STAGING_SUFFIX) => keys.push(PathBuf::from(key)),
+                    None => (),
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &Path) -> Result<(), Trap> {
+        self.expect("DELETE", &self.url(key), &[], None, &[404]).map(|_| ())
+    }
+}
+
+/// Percent-encodes `key` for a URL, keeping its slashes
+pub(crate) fn encode(key: &Path) -> String {
+    key.to_string_lossy().bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], encoded.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The path of `url`, without its scheme and host
+fn url_path(url: &str) -> String {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|start| rest[start..].to_string()).unwrap_or_default(),
+        None => url.to_string(),
+    }
+}
+
+/// The hrefs of a PROPFIND multistatus, whatever the namespace prefix
+fn hrefs(body: &str) -> Vec<String> {
+    body.split('<')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('>')?;
+            let local = name.rsplit(':').next()?;
+            (local == "href" && !name.starts_with('/')).then(|| value.trim().to_string())
+        })
+        .collect()
+}
+
+/// Quoted for a curl config file
+pub(crate) fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[test]
+fn test_webdav_store() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = std::env::temp_dir().join("rensen_test_webdav");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("password"), "s3cret\n").unwrap();
+
+    // Stands in for curl, answering 503 to the first request it gets
+    let log = root.join("requests");
+    let curl = root.join("curl");
+    fs::write(&curl, format!(
+        "#!/bin/sh\ncat > {credentials:?}\nmethod=$5\nfor url; do :; done\necho \"$method $url\" >> {log:?}\n\
+         if [ ! -e {root:?}/answered ]; then touch {root:?}/answered; printf '\\n503'; else printf '\\n201'; fi\n",
+        credentials = root.join("credentials"), log = log, root = root,
+    )).unwrap();
+    fs::set_permissions(&curl, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = WebDavConfig {
+        url: String::from("https://cloud.example.com/remote.php/dav/files/backup/rensen"),
+        uploads: Some(String::from("https://cloud.example.com/remote.php/dav/uploads/backup")),
+        user: String::from("backup"),
+        password_file: root.join("password"),
+        ..Default::default()
+    };
+    let mut store = WebDavStore::new(&config, Spool::new(&root)).unwrap();
+    store.curl = curl;
+    store.chunk_bytes = 4;
+    store.retry_delay = Duration::from_millis(10);
+
+    let key = Path::new("web 01/2024-05-01-14-05-00.tar.gz");
+    let mut writer = store.put(key).unwrap();
+    writer.write_all(b"0123456789").unwrap();
+    drop(writer);
+    store.finalize(key).unwrap();
+    assert!(store.finalize(key).is_err());
+
+    let requests = fs::read_to_string(&log).unwrap();
+    let requests: Vec<&str> = requests.lines().collect();
+    assert_eq!(requests[0], "MKCOL https://cloud.example.com/remote.php/dav/files/backup/rensen/web%2001");
+    assert_eq!(requests[0], requests[1]);
+    assert!(requests[2].starts_with("MKCOL https://cloud.example.com/remote.php/dav/uploads/backup/rensen-web-01-2024-05-01-14-05-00.tar.gz-"));
+    assert!(requests[3].ends_with("/00001") && requests[4].ends_with("/00002") && requests[5].ends_with("/00003"));
+    assert!(requests[6].starts_with("MOVE ") && requests[6].ends_with("/.file"));
+    assert_eq!(requests.len(), 7);
+    assert_eq!(fs::read_to_string(root.join("credentials")).unwrap(), "user = \"backup:s3cret\"\n");
+    assert_eq!(fs::read_dir(&store.spool.dir).unwrap().count(), 0);
+
+    let multistatus = "<?xml version=\"1.0\"?><d:multistatus xmlns:d=\"DAV:\">\
+        <d:response><d:href>/remote.php/dav/files/backup/rensen/web%2001/</d:href></d:response>\
+        <d:response><d:href>/remote.php/dav/files/backup/rensen/web%2001/a.tar.gz</d:href></d:response></d:multistatus>";
+    let base = url_path(&config.url);
+    let keys: Vec<String> = hrefs(multistatus).iter().filter_map(|href| decode(href).strip_prefix(base.as_str()).map(String::from)).collect();
+    assert_eq!(keys, ["/web 01/", "/web 01/a.tar.gz"]);
+    let _ = fs::remove_dir_all(&root);
+}
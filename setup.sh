@@ -1,6 +1,8 @@
 cp daemon/target/release/rensend /usr/bin/rensend
 cp daemon/service/rensend.service /etc/systemd/system/rensend.service
 cp ctl/target/release/rensen-ctl /usr/bin/rensen
+mkdir -p /usr/lib/rensen
+cp helper/target/release/rensen-helper /usr/lib/rensen/rensen-helper
 mkdir -p /etc/rensen
 cp assets/rensen_config.yml /etc/rensen
 cp assets/hosts.yml /etc/rensen